@@ -0,0 +1,143 @@
+use std::time::Duration;
+
+use containerd_shim_wasm::container::{Engine, Instance};
+use containerd_shim_wasm::sandbox::WasmLayer;
+use containerd_shim_wasm::testing::modules::*;
+use containerd_shim_wasm::testing::{http_helpers, WasiTest};
+use containerd_shim_wasmtime::instance::{WasiConfig, WasmtimeEngine};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use oci_spec::image::{Descriptor, MediaType};
+
+/*
+    Benchmarks of the paths of the shim around the guest, rather than of the guest itself, to
+    size the nodes running many short-lived containers or serving many requests:
+    * the creation, start and exit of a container running a module or a component,
+    * a request to a running `wasi:http/proxy` component,
+    * the precompilation of a module or a component,
+    * the piping of the stdio of a container.
+
+    Run them with
+    $ cargo bench -p containerd-shim-benchmarks --features instantiation --bench instantiation-benchmarks
+
+    The proxy benchmark listens on the port 8080 of the host.
+    The timings can be read back with `containerd_shim_benchmarks::timings` for regression
+    tracking.
+*/
+
+#[derive(Clone)]
+struct WasiTestConfig {}
+
+impl WasiConfig for WasiTestConfig {
+    fn new_config() -> wasmtime::Config {
+        let mut config = wasmtime::Config::new();
+        // Disable Wasmtime parallel compilation for the tests
+        // see https://github.com/containerd/runwasi/pull/405#issuecomment-1928468714 for details
+        config.parallel_compilation(false);
+        config.wasm_component_model(true); // enable component linking
+        config
+    }
+}
+
+type WasmtimeTestInstance = Instance<WasmtimeEngine<WasiTestConfig>>;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+const STDIN_SIZE: usize = 64 * 1024;
+
+fn run(wasmbytes: &[u8]) -> anyhow::Result<u32> {
+    let (exit_code, _, _) = WasiTest::<WasmtimeTestInstance>::builder()?
+        .with_wasm(wasmbytes)?
+        .build()?
+        .start()?
+        .wait(TIMEOUT)?;
+    Ok(exit_code)
+}
+
+fn echo(stdin: &str) -> anyhow::Result<String> {
+    let (exit_code, stdout, _) = WasiTest::<WasmtimeTestInstance>::builder()?
+        .with_wasm(ECHO)?
+        .with_stdin(stdin)?
+        .build()?
+        .start()?
+        .wait(TIMEOUT)?;
+    anyhow::ensure!(exit_code == 0, "exit code {exit_code}");
+    Ok(stdout)
+}
+
+fn instantiation(c: &mut Criterion) {
+    let mut group = c.benchmark_group("instantiation");
+    for (name, module) in [
+        ("module", HELLO_WORLD),
+        ("component", COMPONENT_HELLO_WORLD),
+    ] {
+        group.bench_function(name, |b| {
+            b.iter(|| assert_eq!(run(module.bytes).expect("running the container"), 0))
+        });
+    }
+    group.finish();
+}
+
+fn proxy_request(c: &mut Criterion) {
+    let srv = WasiTest::<WasmtimeTestInstance>::builder()
+        .and_then(|b| b.with_wasm(HELLO_WASI_HTTP))
+        .map(|b| b.with_host_network())
+        .and_then(|b| b.build())
+        .expect("building the proxy");
+    srv.start().expect("starting the proxy");
+    http_helpers::get_with_retry("http://127.0.0.1:8080/", 10, Duration::from_secs(1))
+        .expect("waiting for the proxy");
+
+    c.bench_function("proxy/request", |b| {
+        b.iter(|| {
+            let response = http_helpers::get("http://127.0.0.1:8080/").expect("sending a request");
+            assert_eq!(response.status, 200);
+        })
+    });
+
+    srv.ctrl_c()
+        .and_then(|srv| srv.wait(TIMEOUT))
+        .expect("stopping the proxy");
+}
+
+fn precompile(c: &mut Criterion) {
+    let engine = WasmtimeEngine::<WasiTestConfig>::default();
+    let mut group = c.benchmark_group("precompile");
+    for (name, module) in [
+        ("module", HELLO_WORLD),
+        ("component", COMPONENT_HELLO_WORLD),
+    ] {
+        let layer = WasmLayer {
+            config: Descriptor::new(MediaType::Other("application/wasm".into()), 0, ""),
+            layer: module.bytes.to_vec(),
+        };
+        group.throughput(Throughput::Bytes(module.bytes.len() as u64));
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                engine
+                    .precompile(std::slice::from_ref(&layer))
+                    .expect("precompiling")
+            })
+        });
+    }
+    group.finish();
+}
+
+fn stdio(c: &mut Criterion) {
+    let stdin = "x".repeat(STDIN_SIZE);
+    let mut group = c.benchmark_group("stdio");
+    group.throughput(Throughput::Bytes(STDIN_SIZE as u64));
+    group.bench_function("echo", |b| {
+        b.iter(|| {
+            let stdout = echo(&stdin).expect("running the container");
+            assert!(stdout.len() > STDIN_SIZE);
+        })
+    });
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = instantiation, proxy_request, precompile, stdio
+}
+
+criterion_main!(benches);
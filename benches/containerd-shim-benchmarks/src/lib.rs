@@ -0,0 +1,78 @@
+//! Programmatic access to the results of the benchmarks, for regression tracking.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+
+/// The mean time of the last run of each benchmark, by benchmark id, e.g. `precompile/module`.
+///
+/// `dir` is the output directory of criterion, i.e. `target/criterion` of the workspace.
+pub fn timings(dir: impl AsRef<Path>) -> Result<BTreeMap<String, Duration>> {
+    let mut timings = BTreeMap::new();
+    collect(dir.as_ref(), &mut timings)?;
+    Ok(timings)
+}
+
+fn collect(dir: &Path, timings: &mut BTreeMap<String, Duration>) -> Result<()> {
+    // Each benchmark has a `new` directory with the results of its last run
+    let new = dir.join("new");
+    if new.join("estimates.json").is_file() {
+        let (id, mean) = read(&new).with_context(|| format!("reading {}", new.display()))?;
+        timings.insert(id, mean);
+        return Ok(());
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        // The `report` directories only hold the html reports
+        if path.is_dir() && !path.ends_with("report") {
+            collect(&path, timings)?;
+        }
+    }
+    Ok(())
+}
+
+fn read(new: &Path) -> Result<(String, Duration)> {
+    let benchmark: Value = serde_json::from_slice(&fs::read(new.join("benchmark.json"))?)?;
+    let estimates: Value = serde_json::from_slice(&fs::read(new.join("estimates.json"))?)?;
+
+    let id = benchmark["full_id"]
+        .as_str()
+        .context("benchmark without id")?
+        .to_string();
+    let nanos = estimates["mean"]["point_estimate"]
+        .as_f64()
+        .context("benchmark without mean")?;
+    Ok((id, Duration::from_nanos(nanos as u64)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timings() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let new = dir.path().join("precompile").join("module").join("new");
+        fs::create_dir_all(&new)?;
+        fs::create_dir_all(dir.path().join("report"))?;
+        fs::write(
+            new.join("benchmark.json"),
+            r#"{"group_id":"precompile","function_id":"module","full_id":"precompile/module"}"#,
+        )?;
+        fs::write(
+            new.join("estimates.json"),
+            r#"{"mean":{"point_estimate":1500.0},"median":{"point_estimate":1400.0}}"#,
+        )?;
+
+        let timings = timings(dir.path())?;
+        assert_eq!(
+            timings,
+            BTreeMap::from([("precompile/module".to_string(), Duration::from_nanos(1500))])
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,154 @@
+//! Ahead-of-time compilation of the modules with `wamrc`.
+//!
+//! When `RUNWASI_WAMR_WAMRC` is set in the environment of the shim to the path of a
+//! [wamrc](https://github.com/bytecodealliance/wasm-micro-runtime/tree/main/wamr-compiler)
+//! binary, the modules are compiled to AOT files the first time they run, and the AOT files are
+//! cached in the content store next to the original layers like the precompiled modules of the
+//! other shims. On constrained devices this avoids interpreting the modules, or compiling them
+//! on every start.
+//!
+//! Execute-in-place (XIP) of the AOT files is declined, see [`ensure_no_xip`].
+
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::OnceLock;
+
+use anyhow::{ensure, Context, Result};
+
+pub const WAMRC_ENV: &str = "RUNWASI_WAMR_WAMRC";
+
+/// Requests execute-in-place (XIP) of the AOT files, which the shim refuses.
+pub const XIP_ENV: &str = "RUNWASI_WAMR_XIP";
+
+/// The magic number of the AOT files.
+const AOT_MAGIC: &[u8] = b"\0aot";
+
+/// The `wamrc` binary configured in the shim, if any.
+pub fn wamrc() -> Option<PathBuf> {
+    std::env::var_os(WAMRC_ENV)
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+}
+
+/// The cache key of the AOT files, the version of the configured `wamrc`, if any.
+/// `wamrc` only runs the first time, the result being kept for the life of the shim.
+pub fn cache_key() -> Option<String> {
+    static CACHE_KEY: OnceLock<Option<String>> = OnceLock::new();
+    CACHE_KEY
+        .get_or_init(|| {
+            let wamrc = wamrc()?;
+            version(&wamrc)
+                .inspect_err(|e| log::warn!("modules are not precompiled: {e:?}"))
+                .ok()
+        })
+        .clone()
+}
+
+/// The version of `wamrc`, used in the cache key of the AOT files.
+fn version(wamrc: &Path) -> Result<String> {
+    let output = Command::new(wamrc)
+        .arg("--version")
+        .output()
+        .with_context(|| format!("failed to run {}", wamrc.display()))?;
+    ensure!(
+        output.status.success(),
+        "{} --version failed",
+        wamrc.display()
+    );
+    let version = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    Ok(format!("{version}-{}", std::env::consts::ARCH))
+}
+
+/// Compile `module` into an AOT file for the host.
+pub fn compile(wamrc: &Path, module: &[u8]) -> Result<Vec<u8>> {
+    let dir = tempfile::tempdir()?;
+    let (input, output) = (
+        dir.path().join("module.wasm"),
+        dir.path().join("module.aot"),
+    );
+    std::fs::write(&input, module)?;
+    compile_file(wamrc, &input, &output)?;
+    Ok(std::fs::read(&output)?)
+}
+
+/// Compile the module in the file `input` into an AOT file for the host at `output`.
+pub fn compile_file(wamrc: &Path, input: &Path, output: &Path) -> Result<()> {
+    let result = Command::new(wamrc)
+        .arg("-o")
+        .arg(output)
+        .arg(input)
+        .output()
+        .with_context(|| format!("failed to run {}", wamrc.display()))?;
+    ensure!(
+        result.status.success(),
+        "wamrc failed: {}",
+        String::from_utf8_lossy(&result.stderr).trim()
+    );
+    Ok(())
+}
+
+/// Fail when `RUNWASI_WAMR_XIP` requests to execute the AOT files in place, mapped from the
+/// content store, rather than silently loading them in memory.
+/// XIP isn't supported: the WAMR SDK copies the modules into a heap buffer before loading them,
+/// and the shim links a WAMR built without XIP, so `wamrc` doesn't compile with `--xip`.
+pub fn ensure_no_xip() -> Result<()> {
+    let xip = std::env::var_os(XIP_ENV).filter(|value| !value.is_empty());
+    ensure!(
+        xip.is_none(),
+        "execute-in-place of AOT files ({XIP_ENV}) is not supported by the wamr shim"
+    );
+    Ok(())
+}
+
+/// Whether `bytes` is an AOT file rather than a wasm module.
+pub fn is_aot(bytes: &[u8]) -> bool {
+    bytes.starts_with(AOT_MAGIC)
+}
+
+/// Whether the file at `path` is an AOT file rather than a wasm module.
+pub fn is_aot_file(path: &Path) -> Result<bool> {
+    let mut magic = [0; AOT_MAGIC.len()];
+    let mut file =
+        File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    Ok(file.read_exact(&mut magic).is_ok() && is_aot(&magic))
+}
+
+#[cfg(test)]
+mod tests {
+    use serial_test::serial;
+
+    use super::*;
+
+    #[test]
+    fn test_is_aot() {
+        assert!(is_aot(b"\0aot\x03\0\0\0"));
+        assert!(!is_aot(b"\0asm\x01\0\0\0"));
+        assert!(!is_aot(b""));
+    }
+
+    #[test]
+    #[serial]
+    fn test_xip_is_declined() {
+        std::env::set_var(XIP_ENV, "1");
+        let err = ensure_no_xip().unwrap_err();
+        assert!(err.to_string().contains("not supported"), "{err}");
+
+        std::env::set_var(XIP_ENV, "");
+        assert!(ensure_no_xip().is_ok());
+        std::env::remove_var(XIP_ENV);
+        assert!(ensure_no_xip().is_ok());
+    }
+
+    #[test]
+    fn test_is_aot_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("module");
+        std::fs::write(&path, b"\0aot\x03\0\0\0")?;
+        assert!(is_aot_file(&path)?);
+        std::fs::write(&path, b"\0a")?;
+        assert!(!is_aot_file(&path)?);
+        Ok(())
+    }
+}
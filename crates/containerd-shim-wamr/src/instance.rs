@@ -1,11 +1,16 @@
 use anyhow::{Context, Result};
-use containerd_shim_wasm::container::{Engine, Entrypoint, Instance, RuntimeContext, Stdio};
+use containerd_shim_wasm::container::{
+    resolve_func, Engine, Entrypoint, Instance, RuntimeContext, Stdio, WasiDescriptor,
+};
+use containerd_shim_wasm::sandbox::{LayerFile, WasmLayer};
 use wamr_rust_sdk::function::Function;
 use wamr_rust_sdk::instance::Instance as WamrInst;
 use wamr_rust_sdk::module::Module;
 use wamr_rust_sdk::runtime::Runtime;
 use wamr_rust_sdk::wasi_context::WasiCtxBuilder;
 
+use crate::aot;
+
 pub type WamrInstance = Instance<WamrEngine>;
 
 pub struct WamrEngine {
@@ -37,8 +42,15 @@ impl Engine for WamrEngine {
     }
 
     fn run_wasi(&self, ctx: &impl RuntimeContext, stdio: Stdio) -> Result<i32> {
-        let args = ctx.args();
-        let envs = ctx.envs();
+        aot::ensure_no_xip()?;
+        let wasi = WasiDescriptor::from_ctx(ctx)?;
+        let envs: Vec<_> = wasi.envs.iter().map(|(k, v)| format!("{k}={v}")).collect();
+        // WAMR preopens the host directories at the same path in the guest
+        let preopens: Vec<_> = wasi
+            .preopens
+            .iter()
+            .map(|p| p.host.to_string_lossy().into_owned())
+            .collect();
         let Entrypoint {
             source, func, name, ..
         } = ctx.entrypoint();
@@ -59,9 +71,9 @@ impl Engine for WamrEngine {
         log::info!("Create a WASI context");
 
         let wasi_ctx = WasiCtxBuilder::new()
-            .set_pre_open_path(vec!["/"], vec![])
+            .set_pre_open_path(preopens.iter().map(String::as_str).collect(), vec![])
             .set_env_vars(envs.iter().map(String::as_str).collect())
-            .set_arguments(args.iter().map(String::as_str).collect())
+            .set_arguments(wasi.args.iter().map(String::as_str).collect())
             .build();
 
         module.set_wasi_context(wasi_ctx);
@@ -76,6 +88,9 @@ impl Engine for WamrEngine {
         log::info!("redirect stdio");
         stdio.redirect()?;
 
+        let func = resolve_func(ctx, &func, |name| {
+            Function::find_export_func(&instance, name).is_ok()
+        });
         log::info!("Running {func:?}");
         let function =
             Function::find_export_func(&instance, &func).context("Failed to find function")?;
@@ -90,4 +105,42 @@ impl Engine for WamrEngine {
 
         Ok(status)
     }
+
+    fn precompile(&self, layers: &[WasmLayer]) -> Result<Vec<Option<Vec<u8>>>> {
+        let wamrc = aot::wamrc().context("wamrc is not configured")?;
+        layers
+            .iter()
+            .map(|layer| {
+                if aot::is_aot(&layer.layer) {
+                    log::info!("Already precompiled");
+                    return Ok(None);
+                }
+                aot::compile(&wamrc, &layer.layer).map(Some)
+            })
+            .collect()
+    }
+
+    fn precompile_files(&self, layers: &[LayerFile]) -> Result<Vec<bool>> {
+        // wamrc compiles from and to files, the layers are not read in memory
+        let wamrc = aot::wamrc().context("wamrc is not configured")?;
+        layers
+            .iter()
+            .map(|layer| {
+                if aot::is_aot_file(&layer.input)? {
+                    log::info!("Already precompiled");
+                    return Ok(false);
+                }
+                aot::compile_file(&wamrc, &layer.input, &layer.output)?;
+                Ok(true)
+            })
+            .collect()
+    }
+
+    fn validate(&self, _ctx: &impl RuntimeContext) -> Result<()> {
+        aot::ensure_no_xip()
+    }
+
+    fn can_precompile(&self) -> Option<String> {
+        aot::cache_key()
+    }
 }
@@ -1,3 +1,6 @@
+#[cfg(unix)]
+mod aot;
+
 #[cfg(unix)]
 pub mod instance;
 
@@ -1,6 +1,7 @@
 use std::time::Duration;
 
 //use containerd_shim_wasm::sandbox::Instance;
+use containerd_shim_wasm::testing::conformance::Conformance;
 use containerd_shim_wasm::testing::modules::*;
 use containerd_shim_wasm::testing::WasiTest;
 use serial_test::serial;
@@ -117,3 +118,9 @@ fn test_custom_entrypoint() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[test]
+#[serial]
+fn test_conformance() -> anyhow::Result<()> {
+    Conformance::new().run::<WasiInstance>()
+}
@@ -0,0 +1,16 @@
+use std::io::Read;
+
+fn main() {
+    // Echo what the runtime passed to the module, for the conformance tests
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    println!("args: {}", args.join(" "));
+
+    let greeting = std::env::var("GREETING").unwrap_or_default();
+    println!("env: {greeting}");
+
+    let mut stdin = String::new();
+    std::io::stdin().read_to_string(&mut stdin).unwrap();
+    println!("stdin: {stdin}");
+
+    eprintln!("stderr: {greeting}");
+}
@@ -0,0 +1,63 @@
+;; A `runwasi:tcp/handler` component echoing the connections back.
+(component $echo
+  (import "wasi:io/error@0.2.0" (instance $error
+    (export "error" (type (sub resource)))
+  ))
+  (alias export $error "error" (type $error))
+  (import "wasi:io/streams@0.2.0" (instance $streams
+    (alias outer $echo $error (type $error))
+    (export "error" (type $err (eq $error)))
+    (export "input-stream" (type $in (sub resource)))
+    (export "output-stream" (type $out (sub resource)))
+    (type $stream-error (variant (case "last-operation-failed" (own $err)) (case "closed")))
+    (export "stream-error" (type $se (eq $stream-error)))
+    (type $splice-result (result u64 (error $se)))
+    (export "[method]output-stream.blocking-splice"
+      (func (param "self" (borrow $out)) (param "src" (borrow $in)) (param "len" u64) (result $splice-result)))
+  ))
+  (alias export $streams "input-stream" (type $input-stream))
+  (alias export $streams "output-stream" (type $output-stream))
+  (alias export $streams "[method]output-stream.blocking-splice" (func $blocking-splice))
+
+  (core module $memory
+    (memory (export "memory") 1)
+  )
+  (core instance $memory (instantiate $memory))
+  (alias core export $memory "memory" (core memory $mem))
+
+  (core func $splice (canon lower (func $blocking-splice) (memory $mem)))
+  (core func $drop-input (canon resource.drop $input-stream))
+  (core func $drop-output (canon resource.drop $output-stream))
+
+  (core module $handler
+    (import "host" "memory" (memory 1))
+    (import "host" "splice" (func $splice (param i32 i32 i64 i32)))
+    (import "host" "drop-input" (func $drop-input (param i32)))
+    (import "host" "drop-output" (func $drop-output (param i32)))
+    (func (export "handle") (param $input i32) (param $output i32)
+      ;; splice the input into the output until the connection is closed
+      (loop $echo
+        (call $splice (local.get $output) (local.get $input) (i64.const 4096) (i32.const 0))
+        (br_if $echo (i32.eqz (i32.load8_u (i32.const 0))))
+      )
+      (call $drop-input (local.get $input))
+      (call $drop-output (local.get $output))
+    )
+  )
+  (core instance $handler (instantiate $handler
+    (with "host" (instance
+      (export "memory" (memory $mem))
+      (export "splice" (func $splice))
+      (export "drop-input" (func $drop-input))
+      (export "drop-output" (func $drop-output))
+    ))
+  ))
+
+  (func $handle (param "input" (own $input-stream)) (param "output" (own $output-stream))
+    (canon lift (core func $handler "handle"))
+  )
+  (instance $tcp-handler
+    (export "handle" (func $handle))
+  )
+  (export "runwasi:tcp/handler@0.1.0" (instance $tcp-handler))
+)
@@ -1,9 +1,13 @@
 use std::borrow::Cow;
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
 
 use anyhow::{bail, Context};
 use oci_spec::image::Platform;
 use oci_spec::runtime::Spec;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
 
 use crate::container::path::PathResolve;
 use crate::sandbox::oci::WasmLayer;
@@ -16,6 +20,11 @@ pub trait RuntimeContext {
     // ctx.envs() returns environment variables in the format `ENV_VAR_NAME=VALUE` from the runtime spec process field.
     fn envs(&self) -> &[String];
 
+    // ctx.annotations() returns the annotations from the runtime spec, or an empty map if the spec has none.
+    fn annotations(&self) -> &HashMap<String, String> {
+        &EMPTY_MAP
+    }
+
     // ctx.entrypoint() returns a `Entrypoint` with the following fields obtained from the first argument in the OCI spec for entrypoint:
     //   - `arg0` - raw entrypoint from the OCI spec
     //   - `name` - provided as the file name of the module in the entrypoint without the extension
@@ -34,6 +43,25 @@ pub trait RuntimeContext {
     // the platform for the container using the struct defined on the OCI spec definition
     // https://github.com/opencontainers/image-spec/blob/v1.1.0-rc5/image-index.md
     fn platform(&self) -> &Platform;
+
+    // ctx.labels() returns the labels from the image config, or an empty map if the image has none.
+    fn labels(&self) -> &HashMap<String, String> {
+        &EMPTY_MAP
+    }
+
+    // ctx.exposed_ports() returns the ports exposed by the image config as declared, e.g. `8080/tcp` or `8080`,
+    // or an empty slice if the image exposes none.
+    fn exposed_ports(&self) -> &[String] {
+        &[]
+    }
+
+    // ctx.options() returns the options of the engine, deserialized into the engine's own options type.
+    // The options are read from the runtime options of containerd, e.g. the `ConfigPath` of the runtime
+    // in the CRI config, with the fields of the `runwasi.io/engine-options` annotation overriding them.
+    // Returns `T::default()` when no options are set.
+    fn options<T: DeserializeOwned + Default>(&self) -> anyhow::Result<T> {
+        Ok(T::default())
+    }
 }
 
 /// The source for a WASI module / components.
@@ -78,10 +106,15 @@ pub struct Entrypoint<'a> {
     pub source: Source<'a>,
 }
 
+static EMPTY_MAP: LazyLock<HashMap<String, String>> = LazyLock::new(HashMap::new);
+
 pub(crate) struct WasiContext<'a> {
     pub spec: &'a Spec,
     pub wasm_layers: &'a [WasmLayer],
     pub platform: &'a Platform,
+    pub labels: &'a HashMap<String, String>,
+    pub exposed_ports: &'a [String],
+    pub options: Option<&'a Value>,
 }
 
 impl RuntimeContext for WasiContext<'_> {
@@ -103,6 +136,10 @@ impl RuntimeContext for WasiContext<'_> {
             .unwrap_or_default()
     }
 
+    fn annotations(&self) -> &HashMap<String, String> {
+        self.spec.annotations().as_ref().unwrap_or(&EMPTY_MAP)
+    }
+
     fn entrypoint(&self) -> Entrypoint {
         let arg0 = self.args().first();
 
@@ -132,6 +169,21 @@ impl RuntimeContext for WasiContext<'_> {
     fn platform(&self) -> &Platform {
         self.platform
     }
+
+    fn labels(&self) -> &HashMap<String, String> {
+        self.labels
+    }
+
+    fn exposed_ports(&self) -> &[String] {
+        self.exposed_ports
+    }
+
+    fn options<T: DeserializeOwned + Default>(&self) -> anyhow::Result<T> {
+        match self.options {
+            Some(options) => T::deserialize(options).context("invalid engine options"),
+            None => Ok(T::default()),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -158,6 +210,9 @@ mod tests {
             spec: &spec,
             wasm_layers: &[],
             platform: &Platform::default(),
+            labels: &HashMap::new(),
+            exposed_ports: &[],
+            options: None,
         };
 
         let args = ctx.args();
@@ -178,6 +233,9 @@ mod tests {
             spec: &spec,
             wasm_layers: &[],
             platform: &Platform::default(),
+            labels: &HashMap::new(),
+            exposed_ports: &[],
+            options: None,
         };
 
         let args = ctx.args();
@@ -206,6 +264,9 @@ mod tests {
             spec: &spec,
             wasm_layers: &[],
             platform: &Platform::default(),
+            labels: &HashMap::new(),
+            exposed_ports: &[],
+            options: None,
         };
 
         let args = ctx.args();
@@ -228,6 +289,9 @@ mod tests {
             spec: &spec,
             wasm_layers: &[],
             platform: &Platform::default(),
+            labels: &HashMap::new(),
+            exposed_ports: &[],
+            options: None,
         };
 
         let path = ctx.entrypoint().source;
@@ -259,6 +323,9 @@ mod tests {
             spec: &spec,
             wasm_layers: &[],
             platform: &Platform::default(),
+            labels: &HashMap::new(),
+            exposed_ports: &[],
+            options: None,
         };
 
         let expected_path = PathBuf::from("hello.wat");
@@ -299,6 +366,9 @@ mod tests {
             spec: &spec,
             wasm_layers: &[],
             platform: &Platform::default(),
+            labels: &HashMap::new(),
+            exposed_ports: &[],
+            options: None,
         };
 
         let expected_path = PathBuf::from("/root/hello.wat");
@@ -339,6 +409,9 @@ mod tests {
             spec: &spec,
             wasm_layers: &[],
             platform: &Platform::default(),
+            labels: &HashMap::new(),
+            exposed_ports: &[],
+            options: None,
         };
 
         let expected_path = PathBuf::from("/root/hello.wat");
@@ -373,6 +446,9 @@ mod tests {
                 config: Descriptor::new(oci_spec::image::MediaType::Other("".to_string()), 10, ""),
             }],
             platform: &Platform::default(),
+            labels: &HashMap::new(),
+            exposed_ports: &[],
+            options: None,
         };
 
         assert!(matches!(ctx.entrypoint().source, Source::Oci(_)));
@@ -396,6 +472,9 @@ mod tests {
             spec: &spec,
             wasm_layers: &[],
             platform: &Platform::default(),
+            labels: &HashMap::new(),
+            exposed_ports: &[],
+            options: None,
         };
 
         let envs = ctx.envs();
@@ -417,6 +496,9 @@ mod tests {
             spec: &spec,
             wasm_layers: &[],
             platform: &Platform::default(),
+            labels: &HashMap::new(),
+            exposed_ports: &[],
+            options: None,
         };
 
         let envs = ctx.envs();
@@ -436,6 +518,9 @@ mod tests {
             spec: &spec,
             wasm_layers: &[],
             platform: &Platform::default(),
+            labels: &HashMap::new(),
+            exposed_ports: &[],
+            options: None,
         };
 
         let envs = ctx.envs();
@@ -443,4 +528,137 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_get_annotations() -> Result<()> {
+        let spec = SpecBuilder::default()
+            .root(RootBuilder::default().path("rootfs").build()?)
+            .process(ProcessBuilder::default().cwd("/").build()?)
+            .annotations(HashMap::from([(
+                "runwasi.io/key".to_string(),
+                "value".to_string(),
+            )]))
+            .build()?;
+
+        let ctx = WasiContext {
+            spec: &spec,
+            wasm_layers: &[],
+            platform: &Platform::default(),
+            labels: &HashMap::new(),
+            exposed_ports: &[],
+            options: None,
+        };
+
+        let annotations = ctx.annotations();
+        assert_eq!(annotations.len(), 1);
+        assert_eq!(annotations["runwasi.io/key"], "value");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_annotations_return_empty() -> Result<()> {
+        let spec = SpecBuilder::default()
+            .root(RootBuilder::default().path("rootfs").build()?)
+            .process(ProcessBuilder::default().cwd("/").build()?)
+            .build()?;
+
+        let ctx = WasiContext {
+            spec: &spec,
+            wasm_layers: &[],
+            platform: &Platform::default(),
+            labels: &HashMap::new(),
+            exposed_ports: &[],
+            options: None,
+        };
+
+        assert!(ctx.annotations().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_options() -> Result<()> {
+        #[derive(Debug, Default, PartialEq, serde::Deserialize)]
+        #[serde(default, deny_unknown_fields)]
+        struct Options {
+            cache: bool,
+            threads: Option<u32>,
+        }
+
+        let spec = SpecBuilder::default()
+            .root(RootBuilder::default().path("rootfs").build()?)
+            .process(ProcessBuilder::default().cwd("/").build()?)
+            .build()?;
+        let options = serde_json::json!({ "threads": 4 });
+
+        let ctx = WasiContext {
+            spec: &spec,
+            wasm_layers: &[],
+            platform: &Platform::default(),
+            labels: &HashMap::new(),
+            exposed_ports: &[],
+            options: Some(&options),
+        };
+        assert_eq!(
+            ctx.options::<Options>()?,
+            Options {
+                cache: false,
+                threads: Some(4),
+            }
+        );
+
+        let options = serde_json::json!({ "thread": 4 });
+        let ctx = WasiContext {
+            options: Some(&options),
+            ..ctx
+        };
+        assert!(ctx.options::<Options>().is_err());
+
+        let ctx = WasiContext {
+            options: None,
+            ..ctx
+        };
+        assert_eq!(ctx.options::<Options>()?, Options::default());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_defaults() -> Result<()> {
+        // The methods added after the first release have defaults, so that the existing
+        // implementations keep compiling
+        struct Minimal(Platform);
+
+        impl RuntimeContext for Minimal {
+            fn args(&self) -> &[String] {
+                &[]
+            }
+
+            fn envs(&self) -> &[String] {
+                &[]
+            }
+
+            fn entrypoint(&self) -> Entrypoint {
+                Entrypoint {
+                    func: "_start".into(),
+                    name: None,
+                    arg0: None,
+                    source: Source::File(PathBuf::new()),
+                }
+            }
+
+            fn platform(&self) -> &Platform {
+                &self.0
+            }
+        }
+
+        let ctx = Minimal(Platform::default());
+        assert!(ctx.annotations().is_empty());
+        assert!(ctx.labels().is_empty());
+        assert!(ctx.exposed_ports().is_empty());
+        assert_eq!(ctx.options::<HashMap<String, u32>>()?, HashMap::new());
+
+        Ok(())
+    }
 }
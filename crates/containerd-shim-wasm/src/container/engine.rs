@@ -1,11 +1,13 @@
 use std::fs::File;
+use std::future::Future;
 use std::io::Read;
 
 use anyhow::{bail, Context, Result};
+use serde_json::{Map, Value};
 
 use super::Source;
 use crate::container::{PathResolve, RuntimeContext};
-use crate::sandbox::oci::WasmLayer;
+use crate::sandbox::oci::{LayerFile, WasmLayer};
 use crate::sandbox::Stdio;
 
 pub trait Engine: Clone + Send + Sync + 'static {
@@ -13,7 +15,20 @@ pub trait Engine: Clone + Send + Sync + 'static {
     fn name() -> &'static str;
 
     /// Run a WebAssembly container
-    fn run_wasi(&self, ctx: &impl RuntimeContext, stdio: Stdio) -> Result<i32>;
+    fn run_wasi(&self, ctx: &(impl RuntimeContext + Sync), stdio: Stdio) -> Result<i32>;
+
+    /// Run a WebAssembly container asynchronously
+    /// This is what the shim calls to run the container, on a multi-threaded tokio runtime.
+    /// Engines built on an async runtime implement this method, so that they run on the runtime
+    /// of the shim rather than on one of their own.
+    /// The default implementation calls `run_wasi` in place, with `tokio::task::block_in_place`.
+    fn run_wasi_async(
+        &self,
+        ctx: &(impl RuntimeContext + Sync),
+        stdio: Stdio,
+    ) -> impl Future<Output = Result<i32>> + Send {
+        async move { tokio::task::block_in_place(|| self.run_wasi(ctx, stdio)) }
+    }
 
     /// Check that the runtime can run the container.
     /// This checks runs after the container creation and before the container starts.
@@ -44,6 +59,53 @@ pub trait Engine: Clone + Send + Sync + 'static {
         Ok(())
     }
 
+    /// Validate the runtime specific configuration of the container.
+    /// This runs at container creation, after `can_handle` succeeded, so that configuration errors
+    /// are reported before the container starts.
+    /// The default implementation accepts any configuration.
+    fn validate(&self, _ctx: &impl RuntimeContext) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called in the shim process once the container is created, before it starts.
+    /// Engines can set up the per-container resources living outside of the container here,
+    /// e.g. caches or listeners. An error fails the creation of the container.
+    /// The default implementation does nothing.
+    fn on_create(&self, _ctx: &impl RuntimeContext) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called in the shim process right before the container starts.
+    /// An error fails the start of the container.
+    /// The default implementation does nothing.
+    fn on_start(&self, _ctx: &impl RuntimeContext) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called in the shim process when the container exits with `exit_code`, before the exit is
+    /// reported to containerd.
+    /// An error is logged and doesn't change the exit code.
+    /// The default implementation does nothing.
+    fn on_exit(&self, _ctx: &impl RuntimeContext, _exit_code: u32) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called in the shim process when the container is deleted, to clean up what `on_create`
+    /// set up.
+    /// An error is logged and doesn't prevent the deletion.
+    /// The default implementation does nothing.
+    fn on_delete(&self, _ctx: &impl RuntimeContext) -> Result<()> {
+        Ok(())
+    }
+
+    /// The signal asking the process of the container to dump the linear memories of its guest,
+    /// when the engine supports memory dumps for the container.
+    /// The shim sends it to serve the memory dumps requested with the `Update` ttrpc call.
+    /// The default implementation doesn't support memory dumps.
+    fn memory_dump_signal(&self, _ctx: &impl RuntimeContext) -> Option<i32> {
+        None
+    }
+
     /// Return the supported OCI layer types
     /// This is used to filter only layers that are supported by the runtime.
     /// The default implementation returns the OCI layer type 'application/vnd.bytecodealliance.wasm.component.layer.v0+wasm'
@@ -67,6 +129,39 @@ pub trait Engine: Clone + Send + Sync + 'static {
         bail!("precompile not supported");
     }
 
+    /// Precompile_files is how the shim precompiles the layers: they are streamed from the containerd content store into
+    /// the `input` files, and the compiled layers are streamed back from the `output` files, so that the shim doesn't hold them in memory.
+    /// The runtime is expected to return whether each layer passed in was compiled into its `output` file, `false` where `precompile` returns `None`.
+    /// The default implementation reads the layers in memory and calls `precompile`, runtimes whose compiler can read or write files should override it.
+    fn precompile_files(&self, layers: &[LayerFile]) -> Result<Vec<bool>> {
+        let inputs = layers
+            .iter()
+            .map(|layer| {
+                let bytes = std::fs::read(&layer.input)
+                    .with_context(|| format!("failed to read {}", layer.input.display()))?;
+                Ok(WasmLayer {
+                    config: layer.config.clone(),
+                    layer: bytes,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let compiled_layers = self.precompile(&inputs)?;
+        drop(inputs);
+
+        layers
+            .iter()
+            .zip(compiled_layers)
+            .map(|(layer, compiled_layer)| {
+                let Some(compiled_layer) = compiled_layer else {
+                    return Ok(false);
+                };
+                std::fs::write(&layer.output, compiled_layer)
+                    .with_context(|| format!("failed to write {}", layer.output.display()))?;
+                Ok(true)
+            })
+            .collect()
+    }
+
     /// Can_precompile lets the shim know if the runtime supports precompilation.
     /// When it returns Some(unique_string) the `unique_string` will be used as a cache key for the precompiled module.
     ///
@@ -81,4 +176,20 @@ pub trait Engine: Clone + Send + Sync + 'static {
     fn can_precompile(&self) -> Option<String> {
         None
     }
+
+    /// Details of the engine for the `--info` report of the shim, e.g. the version of the runtime
+    /// it embeds, its enabled features, or the WASI interfaces it supports.
+    /// The name of the engine and its precompile key are always reported.
+    /// The default implementation reports nothing else.
+    fn info(&self) -> Map<String, Value> {
+        Map::new()
+    }
+}
+
+/// The `--info` report of `engine`.
+pub(crate) fn engine_info<E: Engine>(engine: &E) -> Value {
+    let mut info = engine.info();
+    info.insert("name".into(), E::name().into());
+    info.insert("precompile_key".into(), engine.can_precompile().into());
+    Value::Object(info)
 }
@@ -0,0 +1,54 @@
+//! Fallbacks of the function called in core modules.
+//!
+//! The toolchains don't agree on the name of the function to export: when a module doesn't
+//! export the requested function (`_start` by default), the shims try the functions listed in
+//! the `runwasi.io/fallback-functions` annotation in order instead, e.g. `main,run`.
+
+use crate::container::RuntimeContext;
+
+pub const FALLBACK_FUNCTIONS_ANNOTATION: &str = "runwasi.io/fallback-functions";
+
+/// The function to call in a module: `func` if the module exports it, or else the first of the
+/// fallbacks of the container which the module exports.
+///
+/// `func` is returned when none of them is exported, for the engine to report it missing.
+pub fn resolve_func(
+    ctx: &impl RuntimeContext,
+    func: &str,
+    mut is_exported: impl FnMut(&str) -> bool,
+) -> String {
+    if is_exported(func) {
+        return func.to_string();
+    }
+    let fallbacks = ctx
+        .annotations()
+        .get(FALLBACK_FUNCTIONS_ANNOTATION)
+        .map(|v| parse_fallbacks(v))
+        .unwrap_or_default();
+    match fallbacks.into_iter().find(|name| is_exported(name)) {
+        Some(name) => {
+            tracing::info!("module does not export {func:?}, calling {name:?} instead");
+            name.to_string()
+        }
+        None => func.to_string(),
+    }
+}
+
+fn parse_fallbacks(value: &str) -> Vec<&str> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_fallbacks() {
+        assert_eq!(parse_fallbacks(" main, run,,"), ["main", "run"]);
+        assert!(parse_fallbacks("").is_empty());
+    }
+}
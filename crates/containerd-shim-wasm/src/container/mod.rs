@@ -12,14 +12,24 @@
 
 mod context;
 mod engine;
+pub mod fallback;
 mod path;
+mod phase;
+pub mod priority;
+pub mod socket_policy;
+mod wasi;
 mod wasm;
 
 pub(crate) use context::WasiContext;
 pub use context::{Entrypoint, RuntimeContext, Source};
+pub(crate) use engine::engine_info;
 pub use engine::Engine;
+pub use fallback::resolve_func;
 pub use instance::Instance;
 pub use path::PathResolve;
+pub(crate) use phase::set_reporter;
+pub use phase::{report_phase, Phase};
+pub use wasi::{parse_envs, Preopen, WasiDescriptor, VAR_ANNOTATION_PREFIX};
 pub use wasm::WasmBinaryType;
 
 pub use crate::sandbox::stdio::Stdio;
@@ -0,0 +1,93 @@
+#![cfg_attr(windows, allow(dead_code))] // the phases are only reported by the unix containers
+
+use std::fmt;
+use std::sync::OnceLock;
+
+/// A phase of the lifecycle of a container, finer grained than the task events.
+///
+/// The phases are published by the shim on the event bus of containerd, with the
+/// `/runwasi/tasks/phase` topic, e.g. to measure cold starts or to tell when a server is ready.
+/// Phases may be skipped, e.g. an engine that doesn't serve requests never reports
+/// [`Phase::Serving`] or [`Phase::Draining`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Phase {
+    /// The wasm modules are being compiled.
+    Compiling = 1,
+    /// The wasm modules are compiled.
+    Compiled = 2,
+    /// The guest is being instantiated.
+    Instantiating = 3,
+    /// The guest is running: listening for requests for a server, or running its entrypoint.
+    Serving = 4,
+    /// The guest was asked to shut down, and finishes its in-flight work.
+    Draining = 5,
+}
+
+impl Phase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Phase::Compiling => "compiling",
+            Phase::Compiled => "compiled",
+            Phase::Instantiating => "instantiating",
+            Phase::Serving => "serving",
+            Phase::Draining => "draining",
+        }
+    }
+
+    pub(crate) fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            1 => Some(Phase::Compiling),
+            2 => Some(Phase::Compiled),
+            3 => Some(Phase::Instantiating),
+            4 => Some(Phase::Serving),
+            5 => Some(Phase::Draining),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+type Reporter = Box<dyn Fn(Phase) + Send + Sync>;
+
+static REPORTER: OnceLock<Reporter> = OnceLock::new();
+
+/// Report that the container entered `phase`.
+///
+/// Engines call this from [`Engine::run_wasi`](crate::container::Engine::run_wasi), it does
+/// nothing outside of a container.
+pub fn report_phase(phase: Phase) {
+    tracing::debug!("container phase: {phase}");
+    if let Some(reporter) = REPORTER.get() {
+        reporter(phase);
+    }
+}
+
+/// Set where the phases of the container are reported, once the container process is running.
+pub(crate) fn set_reporter(reporter: impl Fn(Phase) + Send + Sync + 'static) {
+    let _ = REPORTER.set(Box::new(reporter));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_u32() {
+        for phase in [
+            Phase::Compiling,
+            Phase::Compiled,
+            Phase::Instantiating,
+            Phase::Serving,
+            Phase::Draining,
+        ] {
+            assert_eq!(Phase::from_u32(phase as u32), Some(phase));
+        }
+        assert_eq!(Phase::from_u32(0), None);
+    }
+}
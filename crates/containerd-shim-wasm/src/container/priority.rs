@@ -0,0 +1,92 @@
+//! Priority of the containers.
+//!
+//! The `runwasi.io/priority` annotation, `low`, `normal` (the default) or `high`, sets the nice
+//! value of the process running the container. Engines running several guests in the same
+//! process, e.g. one per HTTP request, also let the guests of higher priority run for longer
+//! before yielding to the others, so that latency-critical guests are not starved by batch ones.
+
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+
+use crate::container::RuntimeContext;
+
+pub const PRIORITY_ANNOTATION: &str = "runwasi.io/priority";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl FromStr for Priority {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim() {
+            "low" => Ok(Self::Low),
+            "normal" => Ok(Self::Normal),
+            "high" => Ok(Self::High),
+            s => bail!("invalid priority {s:?}, expected one of low, normal, high"),
+        }
+    }
+}
+
+impl Priority {
+    /// The priority selected by the container annotations.
+    pub fn from_ctx(ctx: &impl RuntimeContext) -> Result<Self> {
+        ctx.annotations()
+            .get(PRIORITY_ANNOTATION)
+            .map(|v| v.parse())
+            .transpose()
+            .context(PRIORITY_ANNOTATION)
+            .map(Option::unwrap_or_default)
+    }
+
+    /// The nice value of the process running the container.
+    pub fn nice(self) -> i32 {
+        match self {
+            Self::Low => 10,
+            Self::Normal => 0,
+            Self::High => -5,
+        }
+    }
+
+    /// The time slices a guest runs before yielding to the other guests of the process.
+    pub fn time_slices(self) -> u64 {
+        match self {
+            Self::Low => 1,
+            Self::Normal => 2,
+            Self::High => 8,
+        }
+    }
+
+    /// Set the nice value of the current process.
+    #[cfg(unix)]
+    #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+    pub(crate) fn renice(self) -> Result<()> {
+        if self == Self::Normal {
+            return Ok(());
+        }
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, self.nice()) } != 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("failed to set the nice value to {}", self.nice()));
+        }
+        tracing::info!("running with priority {self:?}, nice value {}", self.nice());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("high".parse::<Priority>().unwrap(), Priority::High);
+        assert_eq!(" low ".parse::<Priority>().unwrap(), Priority::Low);
+        assert!("urgent".parse::<Priority>().is_err());
+    }
+}
@@ -0,0 +1,190 @@
+//! Policy applied to the sockets opened by the guest.
+//!
+//! TCP sockets are unrestricted. UDP binds can be restricted to a set of local ports and
+//! addresses with the following annotations:
+//! * `runwasi.io/udp-allowed-ports`: comma separated list of ports or port ranges, e.g. `53,8000-8100`.
+//! * `runwasi.io/udp-allowed-addresses`: comma separated list of local IP addresses, e.g. `0.0.0.0,::`.
+//!
+//! Binding to port `0` (an ephemeral port) is always allowed by the port policy, as that is
+//! what UDP clients do before sending their first datagram.
+
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+
+use crate::container::RuntimeContext;
+
+pub const UDP_ALLOWED_PORTS_ANNOTATION: &str = "runwasi.io/udp-allowed-ports";
+pub const UDP_ALLOWED_ADDRESSES_ANNOTATION: &str = "runwasi.io/udp-allowed-addresses";
+
+/// The use of a socket address by the guest, which each engine maps from its own WASI
+/// implementation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SocketUse {
+    TcpBind,
+    TcpConnect,
+    UdpBind,
+    UdpConnect,
+    UdpOutgoingDatagram,
+}
+
+/// Counters for the UDP activity of the guest, which the engines serve as metrics.
+///
+/// The binds, sent datagrams and denied operations are counted by [`SocketPolicy::check`], the
+/// received datagrams by the engines.
+#[derive(Default, Debug)]
+pub struct UdpStats {
+    pub binds: AtomicU64,
+    pub datagrams_sent: AtomicU64,
+    pub datagrams_received: AtomicU64,
+    pub denied: AtomicU64,
+}
+
+#[derive(Default, Clone)]
+pub struct SocketPolicy {
+    udp_ports: Option<Vec<RangeInclusive<u16>>>,
+    udp_addresses: Option<Vec<IpAddr>>,
+    udp_stats: Arc<UdpStats>,
+}
+
+impl SocketPolicy {
+    pub fn from_ctx(ctx: &impl RuntimeContext) -> Result<Self> {
+        let annotations = ctx.annotations();
+
+        let udp_ports = annotations
+            .get(UDP_ALLOWED_PORTS_ANNOTATION)
+            .map(|v| parse_port_ranges(v))
+            .transpose()
+            .context(UDP_ALLOWED_PORTS_ANNOTATION)?;
+
+        let udp_addresses = annotations
+            .get(UDP_ALLOWED_ADDRESSES_ANNOTATION)
+            .map(|v| parse_addresses(v))
+            .transpose()
+            .context(UDP_ALLOWED_ADDRESSES_ANNOTATION)?;
+
+        Ok(Self {
+            udp_ports,
+            udp_addresses,
+            udp_stats: Default::default(),
+        })
+    }
+
+    pub fn udp_stats(&self) -> Arc<UdpStats> {
+        self.udp_stats.clone()
+    }
+
+    /// Check whether the guest may use `addr` for the given operation.
+    pub fn check(&self, addr: SocketAddr, addr_use: SocketUse) -> bool {
+        let allowed = match addr_use {
+            SocketUse::TcpBind | SocketUse::TcpConnect => return true,
+            SocketUse::UdpBind => {
+                let allowed = self.allows_udp_bind(addr);
+                if allowed {
+                    self.udp_stats.binds.fetch_add(1, Ordering::Relaxed);
+                }
+                allowed
+            }
+            SocketUse::UdpConnect => true,
+            SocketUse::UdpOutgoingDatagram => {
+                self.udp_stats
+                    .datagrams_sent
+                    .fetch_add(1, Ordering::Relaxed);
+                true
+            }
+        };
+
+        if !allowed {
+            tracing::warn!("denied udp bind to {addr}");
+            self.udp_stats.denied.fetch_add(1, Ordering::Relaxed);
+        }
+
+        allowed
+    }
+
+    fn allows_udp_bind(&self, addr: SocketAddr) -> bool {
+        let port_allowed = match &self.udp_ports {
+            None => true,
+            Some(_) if addr.port() == 0 => true,
+            Some(ports) => ports.iter().any(|r| r.contains(&addr.port())),
+        };
+
+        let address_allowed = match &self.udp_addresses {
+            None => true,
+            Some(addresses) => addresses.contains(&addr.ip()),
+        };
+
+        port_allowed && address_allowed
+    }
+}
+
+fn parse_port_ranges(value: &str) -> Result<Vec<RangeInclusive<u16>>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(|v| -> Result<RangeInclusive<u16>> {
+            let range = match v.split_once('-') {
+                Some((start, end)) => start.trim().parse()?..=end.trim().parse()?,
+                None => {
+                    let port = v.parse()?;
+                    port..=port
+                }
+            };
+            anyhow::ensure!(!range.is_empty(), "invalid port range {v:?}");
+            Ok(range)
+        })
+        .collect()
+}
+
+fn parse_addresses(value: &str) -> Result<Vec<IpAddr>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(|v| v.parse().with_context(|| format!("invalid address {v:?}")))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(ports: Option<&str>, addresses: Option<&str>) -> SocketPolicy {
+        SocketPolicy {
+            udp_ports: ports.map(|p| parse_port_ranges(p).unwrap()),
+            udp_addresses: addresses.map(|a| parse_addresses(a).unwrap()),
+            udp_stats: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_parse_port_ranges() -> Result<()> {
+        assert_eq!(
+            parse_port_ranges("53, 8000-8100")?,
+            vec![53..=53, 8000..=8100]
+        );
+        assert!(parse_port_ranges("8100-8000").is_err());
+        assert!(parse_port_ranges("http").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_udp_bind_policy() {
+        let p = policy(Some("53"), Some("127.0.0.1"));
+        assert!(p.check("127.0.0.1:53".parse().unwrap(), SocketUse::UdpBind));
+        assert!(p.check("127.0.0.1:0".parse().unwrap(), SocketUse::UdpBind));
+        assert!(!p.check("127.0.0.1:54".parse().unwrap(), SocketUse::UdpBind));
+        assert!(!p.check("0.0.0.0:53".parse().unwrap(), SocketUse::UdpBind));
+        assert!(p.check("0.0.0.0:80".parse().unwrap(), SocketUse::TcpBind));
+
+        assert_eq!(p.udp_stats.binds.load(Ordering::Relaxed), 2);
+        assert_eq!(p.udp_stats.denied.load(Ordering::Relaxed), 2);
+
+        let p = policy(None, None);
+        assert!(p.check("0.0.0.0:5353".parse().unwrap(), SocketUse::UdpBind));
+    }
+}
@@ -1,4 +1,8 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
 use anyhow::bail;
+use containerd_shim_wasm_test_modules::HELLO_WORLD;
 
 use crate::container::{Engine, RuntimeContext, Stdio};
 use crate::sys::container::instance::Instance;
@@ -36,3 +40,57 @@ fn test_validation_error() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+static HOOKS: Mutex<Vec<&str>> = Mutex::new(vec![]);
+
+#[derive(Clone, Default)]
+struct EngineWithHooks;
+
+impl Engine for EngineWithHooks {
+    fn name() -> &'static str {
+        "wasi_instance"
+    }
+    fn can_handle(&self, _ctx: &impl RuntimeContext) -> anyhow::Result<()> {
+        Ok(())
+    }
+    fn run_wasi(&self, _ctx: &impl RuntimeContext, _stdio: Stdio) -> anyhow::Result<i32> {
+        Ok(42)
+    }
+    fn on_create(&self, _ctx: &impl RuntimeContext) -> anyhow::Result<()> {
+        HOOKS.lock().unwrap().push("create");
+        Ok(())
+    }
+    fn on_start(&self, _ctx: &impl RuntimeContext) -> anyhow::Result<()> {
+        HOOKS.lock().unwrap().push("start");
+        Ok(())
+    }
+    fn on_exit(&self, _ctx: &impl RuntimeContext, exit_code: u32) -> anyhow::Result<()> {
+        assert_eq!(exit_code, 42);
+        HOOKS.lock().unwrap().push("exit");
+        Ok(())
+    }
+    fn on_delete(&self, _ctx: &impl RuntimeContext) -> anyhow::Result<()> {
+        HOOKS.lock().unwrap().push("delete");
+        Ok(())
+    }
+}
+
+#[test]
+#[cfg(unix)] // not yet implemented on Windows
+fn test_lifecycle_hooks() -> anyhow::Result<()> {
+    let test = WasiTest::<Instance<EngineWithHooks>>::builder()?
+        .with_wasm(HELLO_WORLD)?
+        .build()?;
+    assert_eq!(*HOOKS.lock().unwrap(), ["create"]);
+
+    let (exit_code, _, _) = test.start()?.wait(Duration::from_secs(10))?;
+    assert_eq!(exit_code, 42);
+    test.delete()?;
+
+    assert_eq!(
+        *HOOKS.lock().unwrap(),
+        ["create", "start", "exit", "delete"]
+    );
+
+    Ok(())
+}
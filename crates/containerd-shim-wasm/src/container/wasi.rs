@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use anyhow::Result;
+
+use crate::container::socket_policy::SocketPolicy;
+use crate::container::RuntimeContext;
+
+/// The prefix of the annotations defining variables for the templates in the args and envs,
+/// e.g. `runwasi.io/var.PORT: "8080"`.
+pub const VAR_ANNOTATION_PREFIX: &str = "runwasi.io/var.";
+
+/// A directory of the host preopened in the guest.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Preopen {
+    pub host: PathBuf,
+    pub guest: String,
+}
+
+/// The WASI context of a container, independent of the engine running it.
+///
+/// The policies of the shim are resolved from the container once here, and each engine maps
+/// the descriptor to its own WASI APIs. Engines which can't intercept the sockets of the guest
+/// ignore the socket policy.
+///
+/// The `${NAME}` templates in the args and the values of the envs are replaced by the variable
+/// `NAME`: an env of the container, or else the value of the `runwasi.io/var.NAME` annotation.
+/// Templates of undefined variables are kept as they are, and `$${` escapes a template.
+#[derive(Clone)]
+pub struct WasiDescriptor {
+    /// The arguments of the guest, starting with the entrypoint.
+    pub args: Vec<String>,
+    /// The environment variables of the guest.
+    pub envs: Vec<(String, String)>,
+    pub preopens: Vec<Preopen>,
+    pub socket_policy: SocketPolicy,
+}
+
+impl WasiDescriptor {
+    pub fn from_ctx(ctx: &impl RuntimeContext) -> Result<Self> {
+        let envs = parse_envs(ctx.envs());
+        let mut vars: HashMap<&str, &str> = ctx
+            .annotations()
+            .iter()
+            .filter_map(|(key, value)| Some((key.strip_prefix(VAR_ANNOTATION_PREFIX)?, &**value)))
+            .collect();
+        vars.extend(
+            envs.iter()
+                .map(|(key, value)| (key.as_str(), value.as_str())),
+        );
+        let template = |value: &str| expand(value, |name| vars.get(name).copied());
+
+        Ok(Self {
+            args: ctx.args().iter().map(|arg| template(arg)).collect(),
+            envs: envs
+                .iter()
+                .map(|(key, value)| (key.clone(), template(value)))
+                .collect(),
+            // TODO: make this more configurable (e.g. allow the user to specify the
+            // preopened directories and their permissions)
+            // https://github.com/containerd/runwasi/issues/413
+            preopens: vec![Preopen {
+                host: "/".into(),
+                guest: "/".into(),
+            }],
+            socket_policy: SocketPolicy::from_ctx(ctx)?,
+        })
+    }
+}
+
+/// Split the `NAME=VALUE` environment variables of the spec, dropping the ones without a name.
+pub fn parse_envs(envs: &[String]) -> Vec<(String, String)> {
+    envs.iter()
+        .map(|v| v.split_once('=').unwrap_or((v.as_str(), "")))
+        .filter(|(key, _)| !key.is_empty())
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+/// Replace the `${NAME}` templates in `value` with the variables defined by `var`.
+fn expand<'a>(value: &str, var: impl Fn(&str) -> Option<&'a str>) -> String {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            expanded.push_str(&rest[..start]);
+            expanded.push('{');
+            rest = &rest[start + 2..];
+            continue;
+        }
+        expanded.push_str(&rest[..start]);
+        let template = &rest[start..];
+        match template
+            .find('}')
+            .and_then(|end| Some((var(&template[2..end])?, end)))
+        {
+            Some((value, end)) => {
+                expanded.push_str(value);
+                rest = &template[end + 1..];
+            }
+            None => {
+                expanded.push_str("${");
+                rest = &template[2..];
+            }
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_envs() {
+        let envs = ["A=1", "B=x=y", "C", "=D"].map(String::from);
+        assert_eq!(
+            parse_envs(&envs),
+            [("A", "1"), ("B", "x=y"), ("C", "")].map(|(k, v)| (k.to_string(), v.to_string()))
+        );
+    }
+
+    #[test]
+    fn test_expand() {
+        let var = |name: &str| match name {
+            "POD_IP" => Some("10.0.0.1"),
+            "PORT" => Some("8080"),
+            _ => None,
+        };
+        assert_eq!(
+            expand("--addr=${POD_IP}:${PORT}", var),
+            "--addr=10.0.0.1:8080"
+        );
+        assert_eq!(expand("${MISSING} ${PORT", var), "${MISSING} ${PORT");
+        assert_eq!(expand("$${PORT} $$", var), "${PORT} $$");
+        assert_eq!(expand("plain", var), "plain");
+    }
+}
@@ -1,10 +1,14 @@
 use std::path::PathBuf;
+use std::sync::OnceLock;
 
 use containerd_shim::{parse, run, Config};
+use serde_json::{json, Value};
 
+#[cfg(unix)]
+use crate::sandbox::shim::takeover;
 #[cfg(feature = "opentelemetry")]
 use crate::sandbox::shim::{otel_traces_enabled, OtlpConfig};
-use crate::sandbox::{Instance, ShimCli};
+use crate::sandbox::{validate, Instance, ShimCli};
 
 pub mod r#impl {
     pub use git_version::git_version;
@@ -32,6 +36,43 @@ macro_rules! revision {
     };
 }
 
+/// The name, version and revision of the running shim.
+static SHIM_INFO: OnceLock<Value> = OnceLock::new();
+
+/// The machine-readable report of the shim and of its engine, printed by `--info` and attached to
+/// the processes listed by the `Pids` ttrpc requests as [`INFO_TYPE_URL`], e.g.:
+///
+/// ```json
+/// {
+///   "runtime": "wasmtime",
+///   "version": "0.5.0",
+///   "revision": "v0.5.0-12-g0123456789abcde",
+///   "engine": {
+///     "name": "wasmtime",
+///     "precompile_key": "1234567890",
+///     "wasmtime": "27.0.0",
+///     "features": ["crypto"],
+///     "interfaces": ["wasi:cli/command@0.2.0", "wasi:http/proxy@0.2.0"]
+///   }
+/// }
+/// ```
+///
+/// [`INFO_TYPE_URL`]: super::shim::INFO_TYPE_URL
+pub fn info<I: Instance>(engine: &I::Engine) -> Value {
+    let mut info = SHIM_INFO.get().cloned().unwrap_or_else(|| json!({}));
+    info["engine"] = I::info(engine);
+    info
+}
+
+/// The version of the running shim, the `version` of the response to the `Connect` ttrpc request.
+pub fn version() -> String {
+    SHIM_INFO
+        .get()
+        .and_then(|info| info["version"].as_str())
+        .unwrap_or_default()
+        .to_string()
+}
+
 /// Main entry point for the shim.
 ///
 /// If the `opentelemetry` feature is enabled, this function will start the shim with OpenTelemetry tracing.
@@ -92,20 +133,82 @@ fn shim_main_inner<'a, I>(
     }
     let os_args: Vec<_> = std::env::args_os().collect();
 
+    // `--info` is not a flag of containerd, it is handled before parsing them
+    let print_info = os_args.iter().any(|arg| arg == "--info");
+    let os_args: Vec<_> = os_args.into_iter().filter(|arg| arg != "--info").collect();
+
+    // `--validate [bundle]` checks a bundle without running it, see `sandbox::validate`
+    if let Some(pos) = os_args.iter().position(|arg| arg == "--validate") {
+        let bundle = os_args
+            .get(pos + 1)
+            .map_or_else(|| PathBuf::from("."), PathBuf::from);
+        let bundle = bundle.canonicalize().unwrap_or(bundle);
+        let findings = I::validate(&I::Engine::default(), &bundle);
+        let report = validate::report(&bundle, &findings);
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+        std::process::exit(if report["valid"] == true { 0 } else { 1 });
+    }
+
+    // `--takeover <pid>` upgrades the running shim `pid` to this binary, see `shim::takeover`
+    #[cfg(unix)]
+    if let Some(pos) = os_args.iter().position(|arg| arg == "--takeover") {
+        let Some(pid) = os_args
+            .get(pos + 1)
+            .and_then(|pid| pid.to_str()?.parse().ok())
+        else {
+            eprintln!("usage: {} --takeover <pid>", os_args[0].to_string_lossy());
+            std::process::exit(2);
+        };
+        match takeover::take_over(pid) {
+            Ok(pid) => {
+                println!("{pid}");
+                std::process::exit(0);
+            }
+            Err(err) => {
+                eprintln!("failed to take over shim {pid}: {err:#}");
+                std::process::exit(1);
+            }
+        }
+    }
+
     let flags = parse(&os_args[1..]).unwrap();
+
+    // the shim serving the tasks hands its sockets over to the new shim on live upgrades
+    #[cfg(unix)]
+    if flags.action.is_empty() {
+        if let Err(err) = takeover::setup() {
+            eprintln!("failed to set up the live upgrade of the shim: {err:#}");
+            std::process::exit(1);
+        }
+    }
     let argv0 = PathBuf::from(&os_args[0]);
     let argv0 = argv0.file_stem().unwrap_or_default().to_string_lossy();
 
+    let revision = revision.into();
+    SHIM_INFO.get_or_init(|| {
+        json!({
+            "runtime": name,
+            "version": version,
+            "revision": revision,
+        })
+    });
+
     if flags.version {
         println!("{argv0}:");
         println!("  Runtime: {name}");
         println!("  Version: {version}");
-        println!("  Revision: {}", revision.into().unwrap_or("<none>"));
+        println!("  Revision: {}", revision.unwrap_or("<none>"));
         println!();
 
         std::process::exit(0);
     }
 
+    if print_info {
+        let info = info::<I>(&I::Engine::default());
+        println!("{}", serde_json::to_string_pretty(&info).unwrap());
+        std::process::exit(0);
+    }
+
     let shim_version = shim_version.into().unwrap_or("v1");
 
     let lower_name = name.to_lowercase();
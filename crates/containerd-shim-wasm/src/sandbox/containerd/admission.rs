@@ -0,0 +1,163 @@
+//! Admission of the images before their layers are run.
+//!
+//! Operators verify the provenance of the images they run, e.g. check their attestations or that
+//! they come from an allowlisted registry, before the shim reads and compiles the bytes of their
+//! layers. When `RUNWASI_ADMISSION_HOOK` is set in the environment of the shim to the path of an
+//! executable, the executable is run at the creation of every container with a wasm image, with a
+//! JSON request on its stdin:
+//!
+//! ```json
+//! {
+//!   "container": "<container id>",
+//!   "image": "docker.io/library/app:latest",
+//!   "image_digest": "sha256:...",
+//!   "layers": [{ "mediaType": "application/wasm", "digest": "sha256:...", "size": 1234 }]
+//! }
+//! ```
+//!
+//! The container is admitted if the hook exits successfully, and else its creation fails with
+//! the stderr of the hook. A hook still running after `RUNWASI_ADMISSION_HOOK_TIMEOUT` seconds
+//! (default: 10) is killed and the container rejected.
+
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use oci_spec::image::Descriptor;
+use serde_json::json;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::sandbox::error::Error as ShimError;
+
+const ADMISSION_HOOK_ENV: &str = "RUNWASI_ADMISSION_HOOK";
+const ADMISSION_HOOK_TIMEOUT_ENV: &str = "RUNWASI_ADMISSION_HOOK_TIMEOUT";
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub(crate) struct AdmissionHook {
+    path: PathBuf,
+    timeout: Duration,
+}
+
+impl AdmissionHook {
+    /// The admission hook configured by the environment of the shim, if any.
+    pub fn from_env() -> Result<Option<Self>> {
+        let Some(path) = std::env::var_os(ADMISSION_HOOK_ENV).filter(|path| !path.is_empty())
+        else {
+            return Ok(None);
+        };
+        let timeout = match std::env::var(ADMISSION_HOOK_TIMEOUT_ENV) {
+            Ok(secs) => Duration::from_secs(
+                secs.trim()
+                    .parse()
+                    .with_context(|| format!("invalid {ADMISSION_HOOK_TIMEOUT_ENV}"))?,
+            ),
+            Err(_) => DEFAULT_TIMEOUT,
+        };
+        Ok(Some(Self {
+            path: path.into(),
+            timeout,
+        }))
+    }
+
+    /// Ask the hook to admit the `layers` of `image` for `container`, failing with
+    /// [`ShimError::PermissionDenied`] if it rejects them.
+    #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+    pub async fn admit(
+        &self,
+        container: &str,
+        image: &str,
+        image_digest: &str,
+        layers: &[&Descriptor],
+    ) -> Result<(), ShimError> {
+        let request = json!({
+            "container": container,
+            "image": image,
+            "image_digest": image_digest,
+            "layers": layers,
+        });
+        match self.run(serde_json::to_vec(&request)?).await {
+            Ok(()) => {
+                tracing::info!("image {image} admitted by {:?}", self.path);
+                Ok(())
+            }
+            Err(e) => Err(ShimError::PermissionDenied(format!(
+                "image {image} rejected by the admission hook: {e:#}"
+            ))),
+        }
+    }
+
+    async fn run(&self, request: Vec<u8>) -> Result<()> {
+        let mut child = Command::new(&self.path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            // The hook is killed when it times out
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("failed to run {:?}", self.path))?;
+
+        // A hook which doesn't read its request must not block the shim
+        let mut stdin = child.stdin.take().context("no stdin")?;
+        tokio::spawn(async move { stdin.write_all(&request).await });
+
+        let Ok(output) = tokio::time::timeout(self.timeout, child.wait_with_output()).await else {
+            anyhow::bail!("timed out after {:?}", self.timeout);
+        };
+        let output = output?;
+        anyhow::ensure!(
+            output.status.success(),
+            "{}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use oci_spec::image::MediaType;
+
+    use super::*;
+
+    fn hook(script: &str, timeout: Duration) -> (tempfile::TempDir, AdmissionHook) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hook");
+        std::fs::write(&path, format!("#!/bin/sh\n{script}\n")).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        (dir, AdmissionHook { path, timeout })
+    }
+
+    async fn admit(hook: &AdmissionHook, layer: &Descriptor) -> Result<(), ShimError> {
+        hook.admit("c1", "app:latest", "sha256:abcd", &[layer])
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_admit() {
+        let layer = Descriptor::new(
+            MediaType::Other("application/wasm".to_string()),
+            1234,
+            "sha256:1234",
+        );
+
+        let (_dir, allow) = hook(
+            r#"req=$(cat); echo "$req" | grep -q '"image":"app:latest"' && echo "$req" | grep -q '"size":1234'"#,
+            DEFAULT_TIMEOUT,
+        );
+        admit(&allow, &layer).await.unwrap();
+
+        let (_dir, deny) = hook("echo 'untrusted registry' >&2; exit 1", DEFAULT_TIMEOUT);
+        let err = admit(&deny, &layer).await.unwrap_err();
+        assert!(matches!(err, ShimError::PermissionDenied(_)));
+        assert!(err.to_string().contains("untrusted registry"), "{err}");
+
+        let (_dir, slow) = hook("exec sleep 10", Duration::from_millis(100));
+        let err = admit(&slow, &layer).await.unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{err}");
+    }
+}
@@ -1,6 +1,7 @@
 #![cfg(unix)]
 
 use std::collections::HashMap;
+use std::io::{Cursor, SeekFrom};
 use std::path::Path;
 
 use containerd_client;
@@ -18,15 +19,18 @@ use containerd_client::tonic::Streaming;
 use containerd_client::{tonic, with_namespace};
 use futures::TryStreamExt;
 use oci_spec::image::{Arch, ImageManifest, MediaType, Platform};
-use sha256::digest;
+use sha256::{digest, try_digest};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWriteExt};
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Code, Request};
 
+use super::admission::AdmissionHook;
 use super::lease::LeaseGuard;
+use super::scheduler::{lock_layers, PrecompileQueue, PrecompileSlot};
 use crate::container::Engine;
 use crate::sandbox::error::{Error as ShimError, Result};
-use crate::sandbox::oci::{self, WasmLayer};
+use crate::sandbox::oci::{self, LayerFile, WasmLayer};
 use crate::with_lease;
 
 static PRECOMPILE_PREFIX: &str = "runwasi.io/precompiled";
@@ -75,6 +79,16 @@ impl Client {
     // wrapper around read that will read the entire content file
     #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
     async fn read_content(&self, digest: impl ToString) -> Result<Vec<u8>> {
+        self.read_content_with_capacity(digest, 0).await
+    }
+
+    // reads the content into a buffer of `capacity` bytes, so that large layers are not copied while the buffer grows
+    #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+    async fn read_content_with_capacity(
+        &self,
+        digest: impl ToString,
+        capacity: usize,
+    ) -> Result<Vec<u8>> {
         let req = ReadContentRequest {
             digest: digest.to_string(),
             ..Default::default()
@@ -85,12 +99,39 @@ impl Client {
             .await
             .map_err(|err| ShimError::Containerd(err.to_string()))?
             .into_inner()
-            .map_ok(|msg| msg.data)
-            .try_concat()
+            .try_fold(Vec::with_capacity(capacity), |mut data, msg| async move {
+                data.extend_from_slice(&msg.data);
+                Ok(data)
+            })
             .await
             .map_err(|err| ShimError::Containerd(err.to_string()))
     }
 
+    // streams the content into the file at `path`, so that large layers are not held in memory
+    #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+    async fn read_content_to_file(&self, digest: impl ToString, path: &Path) -> Result<()> {
+        let req = ReadContentRequest {
+            digest: digest.to_string(),
+            ..Default::default()
+        };
+        let req = with_namespace!(req, self.namespace);
+        let mut stream = ContentClient::new(self.inner.clone())
+            .read(req)
+            .await
+            .map_err(|err| ShimError::Containerd(err.to_string()))?
+            .into_inner();
+        let mut file = tokio::fs::File::create(path).await?;
+        while let Some(msg) = stream
+            .message()
+            .await
+            .map_err(|err| ShimError::Containerd(err.to_string()))?
+        {
+            file.write_all(&msg.data).await?;
+        }
+        file.flush().await?;
+        Ok(())
+    }
+
     // used in tests to clean up content
     #[allow(dead_code)]
     #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
@@ -136,14 +177,45 @@ impl Client {
         ))
     }
 
+    // used in tests to save content from memory
+    #[allow(dead_code)]
     #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
     async fn save_content(
         &self,
-        data: Vec<u8>,
+        data: &[u8],
+        unique_id: &str,
+        labels: HashMap<String, String>,
+    ) -> Result<WriteContent> {
+        let expected = format!("sha256:{}", digest(data));
+        let len = data.len() as i64;
+        self.write_content(Cursor::new(data), len, expected, unique_id, labels)
+            .await
+    }
+
+    // saves the content of the file at `path`, streaming it into the content store in chunks
+    #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+    async fn save_file(
+        &self,
+        path: &Path,
+        unique_id: &str,
+        labels: HashMap<String, String>,
+    ) -> Result<WriteContent> {
+        let expected = format!("sha256:{}", try_digest(path)?);
+        let file = tokio::fs::File::open(path).await?;
+        let len = file.metadata().await?.len() as i64;
+        self.write_content(file, len, expected, unique_id, labels)
+            .await
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+    async fn write_content(
+        &self,
+        mut content: impl AsyncRead + AsyncSeek + Unpin,
+        len: i64,
+        expected: String,
         unique_id: &str,
         labels: HashMap<String, String>,
     ) -> Result<WriteContent> {
-        let expected = format!("sha256:{}", digest(data.clone()));
         let reference = format!("precompile-{}", unique_id);
         let lease = self.lease(reference.clone()).await?;
 
@@ -151,8 +223,7 @@ impl Client {
             // create a channel to feed the stream; only sending one message at a time so we can set this to one
             let (tx, rx) = mpsc::channel(1);
 
-            let len = data.len() as i64;
-            log::debug!("Writing {} bytes to content store", len);
+            tracing::debug!("Writing {} bytes to content store", len);
             let mut client = ContentClient::new(self.inner.clone());
 
             // Send write request with Stat action to containerd to let it know that we are going to write content
@@ -173,7 +244,7 @@ impl Client {
             let mut response_stream = match client.write(request_stream).await {
                 Ok(response_stream) => response_stream.into_inner(),
                 Err(e) if e.code() == Code::AlreadyExists => {
-                    log::info!("content already exists {}", expected.clone().to_string());
+                    tracing::info!("content already exists {}", expected.clone().to_string());
                     break 'digest expected;
                 }
                 Err(e) => return Err(ShimError::Containerd(e.to_string())),
@@ -190,7 +261,7 @@ impl Client {
                         expected
                     ))
                 })?;
-            log::debug!(
+            tracing::debug!(
                 "Starting to write content for layer {} with current status response {:?}",
                 expected,
                 response
@@ -198,21 +269,23 @@ impl Client {
 
             // Separate the content into chunks and send a write request for each chunk.
             let mut offset = response.offset;
+            content.seek(SeekFrom::Start(offset as u64)).await?;
             while offset < len {
                 let end = (offset + MAX_WRITE_CHUNK_SIZE_BYTES).min(len);
-                let chunk = &data[offset as usize..end as usize];
+                let mut chunk = vec![0; (end - offset) as usize];
+                content.read_exact(&mut chunk).await?;
 
                 let write_request = WriteContentRequest {
                     action: WriteAction::Write.into(),
                     // Ignore size verification of each chunk
                     total: 0,
                     offset,
-                    data: chunk.to_vec(),
+                    data: chunk,
                     ..Default::default()
                 };
                 let response =
                     send_message(write_request, &mut response_stream, &tx, &expected).await?;
-                log::debug!(
+                tracing::debug!(
                     "Writing content for layer {} at offset {} got response: {:?}",
                     expected,
                     offset,
@@ -233,7 +306,7 @@ impl Client {
             };
             let response =
                 send_message(commit_request, &mut response_stream, &tx, &expected).await?;
-            log::info!(
+            tracing::info!(
                 "Validating final response after writing content for layer {}: {:?}",
                 expected,
                 response
@@ -375,7 +448,7 @@ impl Client {
         &self,
         containerd_id: impl ToString,
         engine: &T,
-    ) -> Result<(Vec<oci::WasmLayer>, Platform)> {
+    ) -> Result<(Vec<oci::WasmLayer>, Platform, ImageMetadata)> {
         let container = self.get_container(containerd_id.to_string()).await?;
         let (manifest, image_digest) = self.get_image_manifest_and_digest(&container.image).await?;
 
@@ -383,14 +456,15 @@ impl Client {
         let image_config = self.read_content(image_config_descriptor.digest()).await?;
         let image_config = image_config.as_slice();
 
-        // the only part we care about here is the platform values
+        // the only parts we care about here are the platform, the labels and the exposed ports
         let platform: Platform = serde_json::from_slice(image_config)?;
+        let metadata = ImageMetadata::parse(image_config);
         let Arch::Wasm = platform.architecture() else {
-            log::info!("manifest is not in WASM OCI image format");
-            return Ok((vec![], platform));
+            tracing::info!("manifest is not in WASM OCI image format");
+            return Ok((vec![], platform, metadata));
         };
 
-        log::info!("found manifest with WASM OCI image format");
+        tracing::info!("found manifest with WASM OCI image format");
         // This label is unique across runtimes and version of the shim running
         // a precompiled component/module will not work across different runtimes or versions
         let (can_precompile, precompile_id) = match engine.can_precompile() {
@@ -401,34 +475,108 @@ impl Client {
         let image_info = self.get_info(&image_digest).await?;
         let mut needs_precompile =
             can_precompile && !image_info.labels.contains_key(&precompile_id);
-        let configs = manifest
+        let configs: Vec<_> = manifest
             .layers()
             .iter()
-            .filter(|x| is_wasm_layer(x.media_type(), T::supported_layers_types()));
+            .filter(|x| is_wasm_layer(x.media_type(), T::supported_layers_types()))
+            .collect();
+
+        // The layers are admitted before their bytes are read
+        if let Some(hook) = AdmissionHook::from_env()? {
+            hook.admit(
+                &containerd_id.to_string(),
+                &container.image,
+                &image_digest,
+                &configs,
+            )
+            .await?;
+        }
+
+        // Held until the compiled layers are saved, so that the other containers of the sandbox
+        // find the layers they share with this image compiled
+        let _layer_locks = if needs_precompile {
+            lock_layers(configs.iter().map(|config| config.digest().to_string())).await
+        } else {
+            vec![]
+        };
+
+        // Held until the compiled layers are saved, so that the shims waiting to precompile the
+        // same image find them
+        let _slot = if needs_precompile {
+            wait_precompile_slot(&container.image).await
+        } else {
+            None
+        };
+        if _slot.is_some() {
+            // The image may have been precompiled by another shim while waiting
+            let image_info = self.get_info(&image_digest).await?;
+            needs_precompile = !image_info.labels.contains_key(&precompile_id);
+        }
 
-        let mut layers = vec![];
-        for original_config in configs {
+        // The compiled layers are read first, as a missing one marks the image for recompilation
+        let mut precompiled = Vec::with_capacity(configs.len());
+        for original_config in &configs {
             let layer = self
-                .read_wasm_layer(
+                .read_precompiled_layer(
                     original_config,
                     can_precompile,
                     &precompile_id,
                     &mut needs_precompile,
                 )
                 .await?;
-            layers.push(layer);
+            precompiled.push(layer);
+        }
+
+        if precompiled.is_empty() {
+            tracing::info!("no WASM layers found in OCI image");
+            return Ok((vec![], platform, metadata));
         }
 
-        if layers.is_empty() {
-            log::info!("no WASM layers found in OCI image");
-            return Ok((vec![], platform));
+        // Only the layers without compiled content are compiled, the others may be shared with
+        // images compiled before. They are streamed into files for the compiler rather than read
+        // in memory.
+        let spool = if needs_precompile {
+            Some(tempfile::tempdir()?)
+        } else {
+            None
+        };
+        let mut layers = Vec::with_capacity(configs.len());
+        let mut pending = vec![];
+        for (i, (original_config, layer)) in configs.into_iter().zip(precompiled).enumerate() {
+            let layer = match (layer, &spool) {
+                (Some(layer), _) => layer,
+                (None, Some(spool)) => {
+                    let input = spool.path().join(format!("{i}.wasm"));
+                    self.read_content_to_file(original_config.digest(), &input)
+                        .await?;
+                    pending.push((
+                        i,
+                        LayerFile {
+                            config: original_config.clone(),
+                            input,
+                            output: spool.path().join(format!("{i}.compiled")),
+                        },
+                    ));
+                    WasmLayer {
+                        config: original_config.clone(),
+                        layer: vec![],
+                    }
+                }
+                (None, None) => self.read_original_layer(original_config).await?,
+            };
+            layers.push(layer);
         }
 
-        if needs_precompile {
-            log::info!("precompiling layers for image: {}", container.image);
-            let compiled_layers = match engine.precompile(&layers) {
+        if !pending.is_empty() {
+            tracing::info!(
+                "precompiling {} layers for image: {}",
+                pending.len(),
+                container.image
+            );
+            let (pending, files): (Vec<_>, Vec<_>) = pending.into_iter().unzip();
+            let compiled_layers = match engine.precompile_files(&files) {
                 Ok(compiled_layers) => {
-                    if compiled_layers.len() != layers.len() {
+                    if compiled_layers.len() != files.len() {
                         return Err(ShimError::FailedPrecondition(
                             "precompile returned wrong number of layers".to_string(),
                         ));
@@ -436,30 +584,31 @@ impl Client {
                     compiled_layers
                 }
                 Err(e) => {
-                    log::error!("precompilation failed: {}", e);
-                    return Ok((layers, platform));
+                    tracing::error!("precompilation failed: {}", e);
+                    for (i, file) in pending.into_iter().zip(files) {
+                        layers[i].layer = tokio::fs::read(&file.input).await?;
+                    }
+                    return Ok((layers, platform, metadata));
                 }
             };
 
-            let mut layers_for_runtime = Vec::with_capacity(compiled_layers.len());
-            for (i, compiled_layer) in compiled_layers.iter().enumerate() {
-                if compiled_layer.is_none() {
-                    log::debug!("no compiled layer using original");
-                    layers_for_runtime.push(layers[i].clone());
+            let compiled_layers = pending.into_iter().zip(files).zip(compiled_layers);
+            for ((i, file), compiled) in compiled_layers {
+                if !compiled {
+                    tracing::debug!("no compiled layer using original");
+                    layers[i].layer = tokio::fs::read(&file.input).await?;
                     continue;
                 }
 
-                let compiled_layer = compiled_layer.as_ref().unwrap();
-                let original_config = &layers[i].config;
+                let original_config = file.config;
                 let labels = HashMap::from([(
                     format!("{precompile_id}/original"),
                     original_config.digest().to_string(),
                 )]);
-                let precompiled_content = self
-                    .save_content(compiled_layer.clone(), &precompile_id, labels)
-                    .await?;
+                let precompiled_content =
+                    self.save_file(&file.output, &precompile_id, labels).await?;
 
-                log::debug!(
+                tracing::debug!(
                     "updating original layer {} with compiled layer {}",
                     original_config.digest(),
                     precompiled_content.digest
@@ -481,7 +630,7 @@ impl Client {
                 // We tell containerd to not garbage collect the new content until this image is removed from the system
                 // this ensures that we keep the content around after the lease is dropped
                 // We also save the precompiled flag here since the image labels can be mutated containerd, for example if the image is pulled twice
-                log::debug!(
+                tracing::debug!(
                     "updating image content with precompile digest to avoid garbage collection"
                 );
                 let mut image_content = self.get_info(&image_digest).await?;
@@ -494,66 +643,113 @@ impl Client {
                     .insert(precompile_id.clone(), "true".to_string());
                 self.update_info(image_content).await?;
 
-                layers_for_runtime.push(WasmLayer {
-                    config: original_config.clone(),
-                    layer: compiled_layer.clone(),
-                });
+                // The compiled layer is read back to run it
+                layers[i].layer = tokio::fs::read(&file.output).await?;
 
                 let _ = precompiled_content.lease.release().await;
             }
-            return Ok((layers_for_runtime, platform));
+            return Ok((layers, platform, metadata));
         };
 
-        log::info!("using OCI layers");
-        Ok((layers, platform))
+        tracing::info!("using OCI layers");
+        Ok((layers, platform, metadata))
     }
 
+    // reads the compiled content of the layer, if it has any for the engine
     #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
-    async fn read_wasm_layer(
+    async fn read_precompiled_layer(
         &self,
         original_config: &oci_spec::image::Descriptor,
         can_precompile: bool,
         precompile_id: &String,
         needs_precompile: &mut bool,
-    ) -> std::prelude::v1::Result<WasmLayer, ShimError> {
-        let mut digest_to_load = original_config.digest().clone();
-        if can_precompile {
-            let info = self.get_info(&digest_to_load).await?;
-            if let Some(label) = info.labels.get(precompile_id) {
-                // Safe to unwrap here since we already checked for the label's existence
-                digest_to_load.clone_from(label);
-                log::info!(
-                    "layer {} has pre-compiled content: {} ",
-                    info.digest,
-                    &digest_to_load
-                );
-            }
+    ) -> Result<Option<WasmLayer>> {
+        if !can_precompile {
+            return Ok(None);
         }
-        log::debug!("loading digest: {} ", &digest_to_load);
-        let res = self
-            .read_content(&digest_to_load)
-            .await
-            .map(|module| WasmLayer {
+        let info = self.get_info(original_config.digest()).await?;
+        let Some(digest_to_load) = info.labels.get(precompile_id) else {
+            return Ok(None);
+        };
+        tracing::info!(
+            "layer {} has pre-compiled content: {} ",
+            info.digest,
+            digest_to_load
+        );
+        tracing::debug!("loading digest: {} ", digest_to_load);
+        match self.read_content(digest_to_load).await {
+            Ok(module) => Ok(Some(WasmLayer {
                 config: original_config.clone(),
                 layer: module,
-            });
-
-        match res {
-            Ok(res) => Ok(res),
-            Err(err) if digest_to_load == *original_config.digest() => Err(err),
+            })),
             Err(err) => {
-                log::error!("failed to load precompiled layer: {err}");
-                log::error!("falling back to original layer and marking for recompile");
+                tracing::error!("failed to load precompiled layer: {err}");
+                tracing::error!("falling back to original layer and marking for recompile");
                 *needs_precompile = can_precompile; // only mark for recompile if engine is capable
-                self.read_content(original_config.digest())
-                    .await
-                    .map(|module| WasmLayer {
-                        config: original_config.clone(),
-                        layer: module,
-                    })
+                Ok(None)
             }
         }
     }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+    async fn read_original_layer(
+        &self,
+        original_config: &oci_spec::image::Descriptor,
+    ) -> Result<WasmLayer> {
+        tracing::debug!("loading digest: {} ", original_config.digest());
+        // The size of the original layer is known from the manifest
+        let module = self
+            .read_content_with_capacity(original_config.digest(), original_config.size() as usize)
+            .await?;
+        Ok(WasmLayer {
+            config: original_config.clone(),
+            layer: module,
+        })
+    }
+}
+
+#[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+async fn wait_precompile_slot(image: &str) -> Option<PrecompileSlot> {
+    let queue = PrecompileQueue::from_env()
+        .inspect_err(|e| tracing::warn!("precompile queue is disabled: {e:?}"))
+        .ok()??;
+    queue
+        .acquire(image)
+        .await
+        .inspect_err(|e| tracing::warn!("failed to wait in the precompile queue: {e:?}"))
+        .ok()
+}
+
+/// The parts of the image config passed to the runtimes.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ImageMetadata {
+    /// The labels of the image.
+    pub labels: HashMap<String, String>,
+    /// The ports exposed by the image, as declared, e.g. `8080/tcp` or `8080`.
+    pub exposed_ports: Vec<String>,
+}
+
+impl ImageMetadata {
+    // The metadata is informative only, so a malformed image config is treated as having none.
+    fn parse(image_config: &[u8]) -> Self {
+        let Ok(config) = serde_json::from_slice::<serde_json::Value>(image_config) else {
+            return Self::default();
+        };
+        let config = config.get("config");
+        let labels = config
+            .and_then(|config| config.get("Labels"))
+            .and_then(|labels| serde_json::from_value(labels.clone()).ok())
+            .unwrap_or_default();
+        let mut exposed_ports: Vec<String> = config
+            .and_then(|config| config.get("ExposedPorts")?.as_object())
+            .map(|ports| ports.keys().cloned().collect())
+            .unwrap_or_default();
+        exposed_ports.sort();
+        Self {
+            labels,
+            exposed_ports,
+        }
+    }
 }
 
 fn precompile_label(name: &str, version: &str) -> String {
@@ -562,7 +758,7 @@ fn precompile_label(name: &str, version: &str) -> String {
 
 fn is_wasm_layer(media_type: &MediaType, supported_layer_types: &[&str]) -> bool {
     let supported = supported_layer_types.contains(&media_type.to_string().as_str());
-    log::debug!(
+    tracing::debug!(
         "layer type {} is supported: {}",
         media_type.to_string().as_str(),
         supported
@@ -606,6 +802,25 @@ mod tests {
     use crate::testing::oci_helpers::ImageContent;
     use crate::testing::{oci_helpers, TEST_NAMESPACE};
 
+    #[test]
+    fn test_image_metadata() {
+        let config = br#"{
+            "architecture": "wasm",
+            "os": "wasip1",
+            "config": {
+                "Labels": {"org.opencontainers.image.title": "hello"},
+                "ExposedPorts": {"9090/udp": {}, "8080/tcp": {}}
+            }
+        }"#;
+        let metadata = ImageMetadata::parse(config);
+        assert_eq!(metadata.labels["org.opencontainers.image.title"], "hello");
+        assert_eq!(metadata.exposed_ports, ["8080/tcp", "9090/udp"]);
+
+        let metadata = ImageMetadata::parse(br#"{"architecture": "wasm", "os": "wasip1"}"#);
+        assert_eq!(metadata, ImageMetadata::default());
+        assert_eq!(ImageMetadata::parse(b"not json"), ImageMetadata::default());
+    }
+
     #[tokio::test(flavor = "current_thread")]
     async fn test_save_content() {
         let path = PathBuf::from("/run/containerd/containerd.sock");
@@ -618,7 +833,7 @@ mod tests {
 
         let label = HashMap::from([(precompile_label("test", "hasdfh"), "original".to_string())]);
         let returned = client
-            .save_content(data, "test", label.clone())
+            .save_content(&data, "test", label.clone())
             .await
             .unwrap();
         assert_eq!(expected, returned.digest.clone());
@@ -627,7 +842,7 @@ mod tests {
         assert_eq!(data, b"hello world");
 
         client
-            .save_content(data.clone(), "test", label.clone())
+            .save_content(&data, "test", label.clone())
             .await
             .expect_err("Should not be able to save when lease is open");
 
@@ -637,7 +852,7 @@ mod tests {
 
         // a second call should be successful since it already exists
         let returned = client
-            .save_content(data, "test", label.clone())
+            .save_content(&data, "test", label.clone())
             .await
             .unwrap();
         assert_eq!(expected, returned.digest);
@@ -662,7 +877,7 @@ mod tests {
         let (_, container_name, _cleanup) = generate_test_container(None, &[&fake_bytes]);
         let engine = FakePrecomiplerEngine::new(None);
 
-        let (layers, _) = client.load_modules(container_name, &engine).await.unwrap();
+        let (layers, _, _) = client.load_modules(container_name, &engine).await.unwrap();
         assert_eq!(layers.len(), 1);
         assert_eq!(layers[0].layer, fake_bytes.bytes);
         assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 0);
@@ -683,11 +898,11 @@ mod tests {
         let mut engine = FakePrecomiplerEngine::new(Some(()));
         engine.add_precompiled_bits(fake_bytes.bytes.clone(), &fake_precompiled_bytes);
 
-        let (_, _) = client.load_modules(&container_name, &engine).await.unwrap();
+        let (_, _, _) = client.load_modules(&container_name, &engine).await.unwrap();
         assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 1);
 
         // Even on second calls should only pre-compile once
-        let (layers, _) = client.load_modules(&container_name, &engine).await.unwrap();
+        let (layers, _, _) = client.load_modules(&container_name, &engine).await.unwrap();
         assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 1);
         assert_eq!(layers.len(), 1);
         assert_eq!(layers[0].layer, fake_precompiled_bytes.bytes);
@@ -708,11 +923,11 @@ mod tests {
         let mut engine = FakePrecomiplerEngine::new(Some(()));
         engine.add_precompiled_bits(fake_bytes.bytes.clone(), &fake_precompiled_bytes);
 
-        let (_, _) = client.load_modules(&container_name, &engine).await.unwrap();
+        let (_, _, _) = client.load_modules(&container_name, &engine).await.unwrap();
         assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 1);
 
         engine.precompile_id = Some("new_version".to_string());
-        let (_, _) = client.load_modules(&container_name, &engine).await.unwrap();
+        let (_, _, _) = client.load_modules(&container_name, &engine).await.unwrap();
         assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 2);
     }
 
@@ -735,7 +950,7 @@ mod tests {
             engine.can_precompile().unwrap().as_str(),
         );
 
-        let (layers, _) = client.load_modules(container_name, &engine).await.unwrap();
+        let (layers, _, _) = client.load_modules(container_name, &engine).await.unwrap();
         assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 1);
         assert_eq!(layers.len(), 1);
         assert_eq!(layers[0].layer, fake_precompiled_bytes.bytes);
@@ -771,7 +986,7 @@ mod tests {
         let mut engine = FakePrecomiplerEngine::new(Some(()));
         engine.add_precompiled_bits(fake_bytes.bytes.clone(), &fake_precompiled_bytes);
 
-        let (layers, _) = client.load_modules(container_name, &engine).await.unwrap();
+        let (layers, _, _) = client.load_modules(container_name, &engine).await.unwrap();
 
         assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 1);
         assert_eq!(engine.layers_compiled_per_call.load(Ordering::SeqCst), 1);
@@ -795,7 +1010,7 @@ mod tests {
         let mut engine = FakePrecomiplerEngine::new(Some(()));
         engine.add_precompiled_bits(fake_bytes.bytes.clone(), &fake_precompiled_bytes);
 
-        let (layers, _) = client.load_modules(container_name, &engine).await.unwrap();
+        let (layers, _, _) = client.load_modules(container_name, &engine).await.unwrap();
         assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 1);
         assert_eq!(layers.len(), 1);
         assert_eq!(layers[0].layer, fake_precompiled_bytes.bytes);
@@ -821,7 +1036,7 @@ mod tests {
         // and then check that the layers don't need to be recompiled
         oci_helpers::wait_for_content_removal(&image_sha).unwrap();
 
-        let (layers, _) = client.load_modules(container_name2, &engine).await.unwrap();
+        let (layers, _, _) = client.load_modules(container_name2, &engine).await.unwrap();
         assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 2);
         assert_eq!(layers.len(), 2);
         assert_eq!(engine.layers_compiled_per_call.load(Ordering::SeqCst), 1);
@@ -842,7 +1057,7 @@ mod tests {
         let mut engine = FakePrecomiplerEngine::new(Some(()));
         engine.add_precompiled_bits(fake_bytes.bytes.clone(), &fake_precompiled_bytes);
 
-        let (layers, _) = client.load_modules(container_name, &engine).await.unwrap();
+        let (layers, _, _) = client.load_modules(container_name, &engine).await.unwrap();
         assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 1);
         assert_eq!(layers.len(), 1);
         assert_eq!(layers[0].layer, fake_precompiled_bytes.bytes);
@@ -853,7 +1068,7 @@ mod tests {
         let fake_precompiled_bytes2 = generate_content("precompiled2", WASM_LAYER_MEDIA_TYPE);
         engine.add_precompiled_bits(fake_bytes2.bytes.clone(), &fake_precompiled_bytes2);
 
-        let (layers, _) = client.load_modules(container_name2, &engine).await.unwrap();
+        let (layers, _, _) = client.load_modules(container_name2, &engine).await.unwrap();
         assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 2);
         assert_eq!(layers.len(), 2);
         assert_eq!(engine.layers_compiled_per_call.load(Ordering::SeqCst), 1);
@@ -885,7 +1100,7 @@ mod tests {
             engine.can_precompile().unwrap().as_str(),
         );
 
-        let (layers, _) = client.load_modules(container_name, &engine).await.unwrap();
+        let (layers, _, _) = client.load_modules(container_name, &engine).await.unwrap();
         assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 1);
         assert_eq!(engine.layers_compiled_per_call.load(Ordering::SeqCst), 2);
 
@@ -1028,7 +1243,7 @@ mod tests {
 
                 // load the "precompiled" layer that was stored as precompiled for this layer
                 self.precompiled_layers.iter().all(|x| {
-                    log::warn!("layer: {:?}", x.0);
+                    tracing::warn!("layer: {:?}", x.0);
                     true
                 });
                 let precompiled = self.precompiled_layers[&key].clone();
@@ -61,6 +61,7 @@ impl LeaseGuard {
 }
 
 impl LeaseGuardInner {
+    #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
     async fn release(mut self) -> anyhow::Result<()> {
         self.client
             .delete(self.req)
@@ -76,8 +77,8 @@ impl Drop for LeaseGuard {
         let inner = self.inner.take().unwrap();
         tokio::spawn(async move {
             match inner.release().await {
-                Ok(()) => log::info!("removed lease"),
-                Err(err) => log::warn!("error removing lease: {err}"),
+                Ok(()) => tracing::info!("removed lease"),
+                Err(err) => tracing::warn!("error removing lease: {err}"),
             }
         });
     }
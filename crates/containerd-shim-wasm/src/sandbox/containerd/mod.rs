@@ -1,6 +1,8 @@
 #![cfg(unix)]
 
+mod admission;
 mod client;
 mod lease;
+mod scheduler;
 
-pub(crate) use client::Client;
+pub(crate) use client::{Client, ImageMetadata};
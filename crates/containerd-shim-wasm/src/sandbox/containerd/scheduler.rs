@@ -0,0 +1,290 @@
+//! Node-wide scheduling of precompilations.
+//!
+//! Every container is started by its own shim process, so when many images are pulled at once,
+//! e.g. during a rollout, every shim would precompile its image at the same time and oversubscribe
+//! the CPUs of the node. When `RUNWASI_PRECOMPILE_CONCURRENCY` is set, the shims coordinate
+//! through lock files instead: at most that many precompilations run at once on the node, e.g. `1`
+//! as the compilers already use all the CPUs, and the others wait in FIFO order and log their
+//! position. The coordination is disabled when it is unset or `0`.
+//!
+//! The lock files live in `RUNWASI_PRECOMPILE_LOCK_DIR` (default: `/run/runwasi/precompile`).
+//! The locks are released by the kernel when a shim exits, so a crashed shim doesn't block the
+//! queue.
+//!
+//! The containers of a pod sandbox are served by the same shim, which also locks the layers it
+//! precompiles, so that the layers shared by the images of the sandbox are compiled once.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+const CONCURRENCY_ENV: &str = "RUNWASI_PRECOMPILE_CONCURRENCY";
+const LOCK_DIR_ENV: &str = "RUNWASI_PRECOMPILE_LOCK_DIR";
+const DEFAULT_LOCK_DIR: &str = "/run/runwasi/precompile";
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+pub(crate) struct PrecompileQueue {
+    dir: PathBuf,
+    slots: usize,
+}
+
+/// Permission to precompile, released when dropped.
+pub(crate) struct PrecompileSlot {
+    _lock: File,
+}
+
+/// A place in the queue, held by a locked file so that it is released if the shim dies.
+struct Ticket {
+    path: PathBuf,
+    _lock: File,
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl PrecompileQueue {
+    /// The queue configured by the environment of the shim, `None` when disabled.
+    pub fn from_env() -> Result<Option<Self>> {
+        let Ok(slots) = std::env::var(CONCURRENCY_ENV) else {
+            return Ok(None);
+        };
+        let slots = slots
+            .trim()
+            .parse()
+            .with_context(|| format!("invalid {CONCURRENCY_ENV}"))?;
+        if slots == 0 {
+            return Ok(None);
+        }
+        let dir = std::env::var_os(LOCK_DIR_ENV)
+            .map(PathBuf::from)
+            .unwrap_or_else(|| DEFAULT_LOCK_DIR.into());
+        Ok(Some(Self { dir, slots }))
+    }
+
+    /// Wait for a slot to precompile `image`.
+    #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+    pub async fn acquire(&self, image: &str) -> Result<PrecompileSlot> {
+        let queue_dir = self.dir.join("queue");
+        std::fs::create_dir_all(&queue_dir)
+            .with_context(|| format!("failed to create {queue_dir:?}"))?;
+        let ticket = self.take_ticket(&queue_dir)?;
+
+        let mut last_position = None;
+        loop {
+            let position = self.position(&queue_dir, &ticket)?;
+            // Only the first waiters compete for the slots, so that the queue is served in order
+            if position < self.slots {
+                if let Some(slot) = self.try_lock_slot()? {
+                    if last_position.is_some() {
+                        tracing::info!("precompiling {image} after waiting in the node queue");
+                    }
+                    return Ok(slot);
+                }
+            }
+            if last_position != Some(position) {
+                tracing::info!(
+                    "waiting to precompile {image}: position {} in the node queue",
+                    position + 1
+                );
+                last_position = Some(position);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    fn take_ticket(&self, queue_dir: &Path) -> Result<Ticket> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let name = format!("{nanos:032}-{}", std::process::id());
+        // The ticket is locked before it's published in the queue, so that the other shims never
+        // see it unlocked and remove it
+        let tmp = self.dir.join(format!(".{name}.tmp"));
+        let file = File::create(&tmp).with_context(|| format!("failed to create {tmp:?}"))?;
+        let path = queue_dir.join(name);
+        let published = lock(&file, libc::LOCK_EX).and_then(|_| Ok(std::fs::rename(&tmp, &path)?));
+        if let Err(e) = published {
+            let _ = std::fs::remove_file(&tmp);
+            return Err(e.context(format!("failed to create {path:?}")));
+        }
+        Ok(Ticket { path, _lock: file })
+    }
+
+    /// Number of live tickets ahead of `ticket`. The tickets of dead shims are removed.
+    fn position(&self, queue_dir: &Path, ticket: &Ticket) -> Result<usize> {
+        let own = ticket.path.file_name().unwrap_or_default();
+        let mut position = 0;
+        for entry in std::fs::read_dir(queue_dir)? {
+            let entry = entry?;
+            if entry.file_name().as_os_str() >= own {
+                continue;
+            }
+            let Ok(file) = File::open(entry.path()) else {
+                continue;
+            };
+            if lock(&file, libc::LOCK_SH | libc::LOCK_NB)? {
+                // Nobody holds the ticket anymore
+                let _ = std::fs::remove_file(entry.path());
+            } else {
+                position += 1;
+            }
+        }
+        Ok(position)
+    }
+
+    fn try_lock_slot(&self) -> Result<Option<PrecompileSlot>> {
+        for i in 0..self.slots {
+            let path = self.dir.join(format!("slot-{i}.lock"));
+            let file = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)
+                .with_context(|| format!("failed to open {path:?}"))?;
+            if lock(&file, libc::LOCK_EX | libc::LOCK_NB)? {
+                return Ok(Some(PrecompileSlot { _lock: file }));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// The layers being precompiled by the shim, by digest.
+static LAYER_LOCKS: LazyLock<Mutex<HashMap<String, Weak<AsyncMutex<()>>>>> =
+    LazyLock::new(Default::default);
+
+/// Wait until no other container of the sandbox is precompiling one of the layers `digests`.
+#[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+pub(crate) async fn lock_layers(
+    digests: impl IntoIterator<Item = String>,
+) -> Vec<OwnedMutexGuard<()>> {
+    let mut digests: Vec<_> = digests.into_iter().collect();
+    // Locked in the same order by all the containers, as their images may share several layers
+    digests.sort();
+    digests.dedup();
+
+    let locks: Vec<_> = {
+        let mut layer_locks = LAYER_LOCKS.lock().unwrap();
+        layer_locks.retain(|_, lock| lock.strong_count() > 0);
+        digests
+            .into_iter()
+            .map(|digest| {
+                if let Some(lock) = layer_locks.get(&digest).and_then(Weak::upgrade) {
+                    return lock;
+                }
+                let lock = Arc::new(AsyncMutex::new(()));
+                layer_locks.insert(digest, Arc::downgrade(&lock));
+                lock
+            })
+            .collect()
+    };
+
+    let mut guards = Vec::with_capacity(locks.len());
+    for lock in locks {
+        guards.push(lock.lock_owned().await);
+    }
+    guards
+}
+
+/// Lock `file`, returns false if it would block.
+fn lock(file: &File, operation: libc::c_int) -> Result<bool> {
+    if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    match err.kind() {
+        io::ErrorKind::WouldBlock => Ok(false),
+        _ => Err(err.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_acquire() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let queue = PrecompileQueue {
+            dir: dir.path().to_path_buf(),
+            slots: 1,
+        };
+
+        let slot = queue.acquire("first").await?;
+
+        let dir_path = dir.path().to_path_buf();
+        let waiter = tokio::spawn(async move {
+            let queue = PrecompileQueue {
+                dir: dir_path,
+                slots: 1,
+            };
+            queue.acquire("second").await.map(drop)
+        });
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(!waiter.is_finished());
+        drop(slot);
+        tokio::time::timeout(Duration::from_secs(5), waiter).await???;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_lock_layers() {
+        let guards = lock_layers(["sha256:a".to_string(), "sha256:b".to_string()]).await;
+
+        // A container sharing a layer waits
+        let shared = tokio::spawn(lock_layers(["sha256:b".to_string()]));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!shared.is_finished());
+
+        // Other layers are not locked
+        lock_layers(["sha256:c".to_string()]).await;
+
+        drop(guards);
+        drop(shared.await.unwrap());
+
+        // The locks are removed once released
+        lock_layers([]).await;
+        assert!(LAYER_LOCKS.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_position() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let queue = PrecompileQueue {
+            dir: dir.path().to_path_buf(),
+            slots: 1,
+        };
+        let queue_dir = dir.path().join("queue");
+        std::fs::create_dir_all(&queue_dir)?;
+
+        // A ticket left behind by a dead shim
+        let stale = queue_dir.join(format!("{:032}-1", 0));
+        File::create(&stale)?;
+
+        let first = queue.take_ticket(&queue_dir)?;
+        let second = queue.take_ticket(&queue_dir)?;
+        assert_eq!(std::fs::read_dir(&queue_dir)?.count(), 3);
+        assert_eq!(queue.position(&queue_dir, &first)?, 0);
+        assert!(!stale.exists());
+        assert_eq!(queue.position(&queue_dir, &second)?, 1);
+
+        drop(first);
+        assert_eq!(queue.position(&queue_dir, &second)?, 0);
+
+        Ok(())
+    }
+}
@@ -35,6 +35,9 @@ pub enum Error {
     /// The operation was rejected because the system is not in a state required for the operation's
     #[error("{0}")]
     FailedPrecondition(String),
+    /// The operation was rejected by a policy of the node
+    #[error("permission denied: {0}")]
+    PermissionDenied(String),
     /// Error while parsing JSON
     #[error("{0}")]
     Json(#[from] serde_json::Error),
@@ -76,6 +79,9 @@ impl From<Error> for ttrpc::Error {
             Error::FailedPrecondition(ref s) => {
                 ttrpc::Error::RpcStatus(ttrpc::get_status(ttrpc::Code::FAILED_PRECONDITION, s))
             }
+            Error::PermissionDenied(ref s) => {
+                ttrpc::Error::RpcStatus(ttrpc::get_status(ttrpc::Code::PERMISSION_DENIED, s))
+            }
             Error::Oci(ref _s) => {
                 ttrpc::Error::RpcStatus(ttrpc::get_status(ttrpc::Code::UNKNOWN, e.to_string()))
             }
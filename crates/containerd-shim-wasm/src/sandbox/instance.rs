@@ -1,11 +1,20 @@
 //! Abstractions for running/managing a wasm/wasi instance.
 
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::Duration;
 
 use chrono::{DateTime, Utc};
+use containerd_shim::Error as ShimError;
+use serde_json::Value;
 
 use super::error::Error;
+use super::sync::WaitableCell;
+use super::validate::Finding;
+use crate::container::Phase;
+
+/// Called with the lifecycle phases of a container, and when they were entered.
+pub type PhaseListener = Arc<dyn Fn(Phase, DateTime<Utc>) + Send + Sync>;
 
 /// Generic options builder for creating a wasm instance.
 /// This is passed to the `Instance::new` method.
@@ -26,6 +35,10 @@ pub struct InstanceConfig<Engine: Send + Sync + Clone> {
     namespace: String,
     // /// GRPC address back to main containerd
     containerd_address: String,
+    /// Engine options from the runtime options of the task.
+    engine_options: Option<Value>,
+    /// Listener of the lifecycle phases of the container.
+    phase_listener: Option<PhaseListener>,
 }
 
 impl<Engine: Send + Sync + Clone> InstanceConfig<Engine> {
@@ -44,6 +57,8 @@ impl<Engine: Send + Sync + Clone> InstanceConfig<Engine> {
             stdout: PathBuf::default(),
             stderr: PathBuf::default(),
             bundle: PathBuf::default(),
+            engine_options: None,
+            phase_listener: None,
         }
     }
 
@@ -91,6 +106,31 @@ impl<Engine: Send + Sync + Clone> InstanceConfig<Engine> {
         &self.bundle
     }
 
+    /// set the engine options from the runtime options of the task
+    pub fn set_engine_options(&mut self, options: Option<Value>) -> &mut Self {
+        self.engine_options = options;
+        self
+    }
+
+    /// get the engine options from the runtime options of the task
+    pub fn get_engine_options(&self) -> Option<&Value> {
+        self.engine_options.as_ref()
+    }
+
+    /// set the listener of the lifecycle phases of the instance
+    pub fn set_phase_listener(
+        &mut self,
+        listener: impl Fn(Phase, DateTime<Utc>) + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.phase_listener = Some(Arc::new(listener));
+        self
+    }
+
+    /// get the listener of the lifecycle phases of the instance
+    pub fn get_phase_listener(&self) -> Option<PhaseListener> {
+        self.phase_listener.clone()
+    }
+
     /// get the wasm engine for the instance
     pub fn get_engine(&self) -> Engine {
         self.engine.clone()
@@ -132,6 +172,27 @@ pub trait Instance: 'static {
     /// This is called after the instance has exited.
     fn delete(&self) -> Result<(), Error>;
 
+    /// Write a snapshot of the linear memories of the running instance, for offline analysis.
+    /// The default implementation doesn't support memory dumps.
+    fn dump_memory(&self) -> Result<(), Error> {
+        Err(ShimError::Unimplemented("memory dumps are not supported".to_string()).into())
+    }
+
+    /// Adopt the running instance `id`, started by a previous shim which hands its tasks over to
+    /// this one, see `docs/shim-live-upgrade.md`. The previous shim, the parent of the instance,
+    /// reports its exit code in `exit`.
+    /// The default implementation doesn't support live upgrades.
+    fn adopt(
+        _id: String,
+        _cfg: &InstanceConfig<Self::Engine>,
+        _exit: WaitableCell<(u32, DateTime<Utc>)>,
+    ) -> Result<Self, Error>
+    where
+        Self: Sized,
+    {
+        Err(ShimError::Unimplemented("live upgrade is not supported".to_string()).into())
+    }
+
     /// Waits for the instance to finish and returns its exit code
     /// This is a blocking call.
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), parent = tracing::Span::current(), level = "Info"))]
@@ -143,4 +204,19 @@ pub trait Instance: 'static {
     /// Returns None if the timeout is reached before the instance has finished.
     /// This is a blocking call.
     fn wait_timeout(&self, t: impl Into<Option<Duration>>) -> Option<(u32, DateTime<Utc>)>;
+
+    /// Details of `engine` for the `--info` report of the shim.
+    /// The default implementation reports nothing.
+    fn info(_engine: &Self::Engine) -> Value {
+        Value::Null
+    }
+
+    /// Check the bundle at `bundle` for the `--validate` report of the shim, without running it.
+    /// The default implementation reports that the validation is not supported.
+    fn validate(_engine: &Self::Engine, _bundle: &Path) -> Vec<Finding> {
+        vec![Finding::error(
+            "bundle",
+            "validation is not supported by this shim",
+        )]
+    }
 }
@@ -1,12 +1,21 @@
 //! Common utilities for the containerd shims.
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
 
+use protobuf::well_known_types::any::Any;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 use super::Error;
 
+/// Annotation overriding the engine options of a container, as a JSON object.
+pub const ENGINE_OPTIONS_ANNOTATION: &str = "runwasi.io/engine-options";
+
+/// The type of the runtime options containerd passes to the shims other than runc.
+const RUNTIME_OPTIONS_TYPE: &str = "runtimeoptions.v1.Options";
+
 #[derive(Serialize, Deserialize)]
 struct Options {
     root: Option<PathBuf>,
@@ -27,10 +36,120 @@ pub fn determine_rootdir(
         .root
         .unwrap_or_else(|| rootdir.as_ref().to_owned())
         .join(namespace);
-    log::info!("container runtime root path is {path:?}");
+    tracing::info!("container runtime root path is {path:?}");
     Ok(path)
 }
 
+/// The engine options in the runtime options of the task, if any.
+///
+/// containerd passes the `options` of the runtime in its CRI config, or `ctr run --runtime-config-path`,
+/// as a `runtimeoptions.v1.Options` message, whose `ConfigPath` is the path of a JSON file with the
+/// engine options, or whose `ConfigBody` is the JSON itself.
+pub(crate) fn engine_options_from_runtime(options: &Any) -> Result<Option<Value>, Error> {
+    if !options.type_url.ends_with(RUNTIME_OPTIONS_TYPE) {
+        if !options.type_url.is_empty() {
+            tracing::debug!("ignoring runtime options of type {}", options.type_url);
+        }
+        return Ok(None);
+    }
+    let (config_path, config_body) = decode_runtime_options(&options.value)
+        .map_err(|e| Error::InvalidArgument(format!("invalid runtime options: {e}")))?;
+    let options = if !config_body.is_empty() {
+        serde_json::from_slice(&config_body)?
+    } else if !config_path.is_empty() {
+        let file = File::open(&config_path).map_err(|e| {
+            Error::InvalidArgument(format!("failed to open engine options {config_path}: {e}"))
+        })?;
+        serde_json::from_reader(file).map_err(|e| {
+            Error::InvalidArgument(format!("invalid engine options in {config_path}: {e}"))
+        })?
+    } else {
+        return Ok(None);
+    };
+    Ok(Some(options))
+}
+
+/// The options of the engine for a container: the runtime options, with the fields of the
+/// `runwasi.io/engine-options` annotation overriding theirs.
+pub(crate) fn merge_engine_options(
+    runtime: Option<&Value>,
+    annotations: Option<&HashMap<String, String>>,
+) -> Result<Option<Value>, Error> {
+    let Some(overrides) = annotations.and_then(|a| a.get(ENGINE_OPTIONS_ANNOTATION)) else {
+        return Ok(runtime.cloned());
+    };
+    let overrides: Value = serde_json::from_str(overrides).map_err(|e| {
+        Error::InvalidArgument(format!(
+            "invalid {ENGINE_OPTIONS_ANNOTATION} annotation: {e}"
+        ))
+    })?;
+    match (runtime, overrides) {
+        (Some(Value::Object(runtime)), Value::Object(overrides)) => {
+            let mut options = runtime.clone();
+            options.extend(overrides);
+            Ok(Some(Value::Object(options)))
+        }
+        (_, overrides) => Ok(Some(overrides)),
+    }
+}
+
+/// Decode the `config_path` and `config_body` fields of a `runtimeoptions.v1.Options` message.
+/// The message is not part of the shim protos, and is simple enough to be decoded by hand.
+fn decode_runtime_options(mut buf: &[u8]) -> Result<(String, Vec<u8>), &'static str> {
+    fn varint(buf: &mut &[u8]) -> Result<u64, &'static str> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = buf.split_first().ok_or("truncated varint")?;
+            *buf = rest;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("invalid varint")
+    }
+    fn take<'a>(buf: &mut &'a [u8], len: u64) -> Result<&'a [u8], &'static str> {
+        let len = usize::try_from(len).map_err(|_| "invalid length")?;
+        if len > buf.len() {
+            return Err("truncated field");
+        }
+        let (field, rest) = buf.split_at(len);
+        *buf = rest;
+        Ok(field)
+    }
+
+    let (mut config_path, mut config_body) = (String::new(), vec![]);
+    while !buf.is_empty() {
+        let key = varint(&mut buf)?;
+        match (key >> 3, key & 7) {
+            (2, 2) => {
+                let len = varint(&mut buf)?;
+                config_path = String::from_utf8(take(&mut buf, len)?.to_vec())
+                    .map_err(|_| "invalid config_path")?;
+            }
+            (3, 2) => {
+                let len = varint(&mut buf)?;
+                config_body = take(&mut buf, len)?.to_vec();
+            }
+            (_, 0) => {
+                varint(&mut buf)?;
+            }
+            (_, 1) => {
+                take(&mut buf, 8)?;
+            }
+            (_, 2) => {
+                let len = varint(&mut buf)?;
+                take(&mut buf, len)?;
+            }
+            (_, 5) => {
+                take(&mut buf, 4)?;
+            }
+            _ => return Err("unsupported wire type"),
+        }
+    }
+    Ok((config_path, config_body))
+}
+
 #[cfg(unix)]
 #[cfg(test)]
 mod tests {
@@ -67,6 +186,62 @@ mod tests {
         );
         Ok(())
     }
+
+    fn runtime_options(fields: &[(u8, &[u8])]) -> Any {
+        let mut options = Any::new();
+        options.type_url = format!("types.containerd.io/{RUNTIME_OPTIONS_TYPE}");
+        for (field, value) in fields {
+            options.value.push(field << 3 | 2);
+            options.value.push(value.len() as u8);
+            options.value.extend_from_slice(value);
+        }
+        options
+    }
+
+    #[test]
+    fn test_engine_options_from_runtime() -> Result<(), Error> {
+        let dir = tempdir()?;
+        let path = dir.path().join("engine.json");
+        std::fs::write(&path, r#"{ "from": "path" }"#)?;
+        let path = path.to_string_lossy();
+
+        let options = runtime_options(&[(1, b"ignored"), (2, path.as_bytes())]);
+        let options = engine_options_from_runtime(&options)?;
+        assert_eq!(options, Some(serde_json::json!({ "from": "path" })));
+
+        let options = runtime_options(&[(2, path.as_bytes()), (3, br#"{ "from": "body" }"#)]);
+        let options = engine_options_from_runtime(&options)?;
+        assert_eq!(options, Some(serde_json::json!({ "from": "body" })));
+
+        assert_eq!(engine_options_from_runtime(&runtime_options(&[]))?, None);
+        assert_eq!(engine_options_from_runtime(&Any::new())?, None);
+
+        let mut truncated = runtime_options(&[(3, b"{}")]);
+        truncated.value.pop();
+        assert!(engine_options_from_runtime(&truncated).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_engine_options() -> Result<(), Error> {
+        let runtime = serde_json::json!({ "a": 1, "b": 2 });
+        let annotations = HashMap::from([(
+            ENGINE_OPTIONS_ANNOTATION.to_string(),
+            r#"{ "b": 3, "c": 4 }"#.to_string(),
+        )]);
+
+        let options = merge_engine_options(Some(&runtime), Some(&annotations))?;
+        assert_eq!(options, Some(serde_json::json!({ "a": 1, "b": 3, "c": 4 })));
+        let options = merge_engine_options(Some(&runtime), None)?;
+        assert_eq!(options, Some(runtime));
+        assert_eq!(merge_engine_options(None, None)?, None);
+
+        let annotations = HashMap::from([(ENGINE_OPTIONS_ANNOTATION.to_string(), "{".to_string())]);
+        assert!(merge_engine_options(None, Some(&annotations)).is_err());
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -7,6 +7,7 @@ pub mod instance_utils;
 pub mod shim;
 pub mod stdio;
 pub mod sync;
+pub mod validate;
 
 pub use error::{Error, Result};
 pub use instance::{Instance, InstanceConfig};
@@ -15,6 +16,6 @@ pub use stdio::Stdio;
 
 pub(crate) mod containerd;
 pub(crate) mod oci;
-pub use oci::WasmLayer;
+pub use oci::{LayerFile, WasmLayer};
 
 pub(crate) mod async_utils;
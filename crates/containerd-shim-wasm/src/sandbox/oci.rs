@@ -4,6 +4,7 @@ use std::collections::HashMap;
 use std::io::{ErrorKind, Write};
 #[cfg(unix)]
 use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
 use std::process;
 
 use anyhow::Context;
@@ -17,6 +18,17 @@ pub struct WasmLayer {
     pub layer: Vec<u8>,
 }
 
+/// A layer to compile from a file into another, see
+/// [`Engine::precompile_files`](crate::container::Engine::precompile_files).
+#[derive(Clone, Debug)]
+pub struct LayerFile {
+    pub config: Descriptor,
+    /// The file the layer is streamed into from the content store.
+    pub input: PathBuf,
+    /// The file the compiled layer is written to, streamed back into the content store.
+    pub output: PathBuf,
+}
+
 fn parse_env(envs: &[String]) -> HashMap<String, String> {
     // make NAME=VALUE to HashMap<NAME, VALUE>.
     envs.iter()
@@ -44,7 +56,7 @@ pub(crate) fn setup_prestart_hooks(hooks: &Option<oci_spec::runtime::Hooks>) ->
             // doesn't include arg0. So we have to make the split arg0 from the
             // rest of args.
             if let Some((arg0, args)) = hook.args().as_ref().and_then(|a| a.split_first()) {
-                log::debug!("run_hooks arg0: {:?}, args: {:?}", arg0, args);
+                tracing::debug!("run_hooks arg0: {:?}, args: {:?}", arg0, args);
 
                 #[cfg(unix)]
                 {
@@ -69,7 +81,7 @@ pub(crate) fn setup_prestart_hooks(hooks: &Option<oci_spec::runtime::Hooks>) ->
             } else {
                 HashMap::new()
             };
-            log::debug!("run_hooks envs: {:?}", envs);
+            tracing::debug!("run_hooks envs: {:?}", envs);
 
             let mut hook_process = hook_command
                 .env_clear()
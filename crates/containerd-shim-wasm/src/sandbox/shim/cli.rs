@@ -86,13 +86,19 @@ where
         let events = RemoteEventSender::new(&self.namespace, publisher);
         let exit = self.exit.clone();
         let engine = self.engine.clone();
-        Local::<I>::new(
+        let local = Local::<I>::new(
             engine,
             events,
             exit,
             &self.namespace,
             &self.containerd_address,
-        )
+        );
+        #[cfg(unix)]
+        if let Err(err) = local.live_upgrade() {
+            tracing::error!("failed to take over the instances of the previous shim: {err:#}");
+            std::process::exit(1);
+        }
+        local
     }
 
     #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
@@ -1,13 +1,53 @@
 use std::sync::Arc;
 
-use chrono::{DateTime, TimeZone};
+use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
 use containerd_shim::event::Event;
 use containerd_shim::publisher::RemotePublisher;
-use log::warn;
+use protobuf::well_known_types::struct_::{Struct, Value};
 use protobuf::well_known_types::timestamp::Timestamp;
+use protobuf::MessageDyn;
+use tracing::warn;
+
+use crate::container::Phase;
+
+/// Topic of the lifecycle phases of the containers.
+pub(super) const PHASE_TOPIC: &str = "/runwasi/tasks/phase";
 
 pub trait EventSender: Clone + Send + Sync + 'static {
-    fn send(&self, event: impl Event);
+    fn send(&self, event: impl Event) {
+        let topic = event.topic();
+        self.publish(&topic, Box::new(event));
+    }
+
+    fn publish(&self, topic: &str, event: Box<dyn MessageDyn>);
+
+    /// Publish that the container `id` entered `phase`.
+    ///
+    /// The phases have no message type in the containerd API, they are published as a
+    /// `google.protobuf.Struct` with the `container_id`, `phase` and `timestamp` fields.
+    fn send_phase(&self, id: &str, phase: Phase, timestamp: DateTime<Utc>) {
+        self.publish(PHASE_TOPIC, Box::new(phase_event(id, phase, timestamp)));
+    }
+}
+
+fn phase_event(id: &str, phase: Phase, timestamp: DateTime<Utc>) -> Struct {
+    let string = |value: String| {
+        let mut v = Value::new();
+        v.set_string_value(value);
+        v
+    };
+    let mut event = Struct::new();
+    event
+        .fields
+        .insert("container_id".into(), string(id.into()));
+    event
+        .fields
+        .insert("phase".into(), string(phase.to_string()));
+    event.fields.insert(
+        "timestamp".into(),
+        string(timestamp.to_rfc3339_opts(SecondsFormat::Nanos, true)),
+    );
+    event
 }
 
 #[derive(Clone)]
@@ -33,14 +73,16 @@ impl RemoteEventSender {
 }
 
 impl EventSender for RemoteEventSender {
-    fn send(&self, event: impl Event) {
-        let topic = event.topic();
-        let event = Box::new(event);
+    fn publish(&self, topic: &str, event: Box<dyn MessageDyn>) {
+        // the events are published by the new shim once the old one handed over its instances
+        #[cfg(unix)]
+        if super::takeover::is_handed_over() {
+            return;
+        }
         let publisher = &self.inner.publisher;
-        if let Err(err) =
-            publisher.publish(Default::default(), &topic, &self.inner.namespace, event)
+        if let Err(err) = publisher.publish(Default::default(), topic, &self.inner.namespace, event)
         {
-            warn!("failed to publish event, topic: {}: {}", &topic, err)
+            warn!("failed to publish event, topic: {}: {}", topic, err)
         }
     }
 }
@@ -58,3 +100,20 @@ impl<Tz: TimeZone> ToTimestamp for DateTime<Tz> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_phase_event() {
+        let timestamp = DateTime::from_timestamp(1_700_000_000, 5).unwrap();
+        let event = phase_event("foo", Phase::Serving, timestamp);
+        assert_eq!(event.fields["container_id"].string_value(), "foo");
+        assert_eq!(event.fields["phase"].string_value(), "serving");
+        assert_eq!(
+            event.fields["timestamp"].string_value(),
+            "2023-11-14T22:13:20.000000005Z"
+        );
+    }
+}
@@ -3,8 +3,12 @@ use std::time::Duration;
 
 use chrono::{DateTime, Utc};
 
+#[cfg(unix)]
+use crate::sandbox::shim::takeover::{ExitCode, InstanceState};
 use crate::sandbox::shim::task_state::TaskState;
-use crate::sandbox::{Instance, InstanceConfig, Result};
+#[cfg(unix)]
+use crate::sandbox::sync::WaitableCell;
+use crate::sandbox::{Error, Instance, InstanceConfig, Result};
 
 pub(super) struct InstanceData<T: Instance> {
     pub instance: T,
@@ -26,6 +30,45 @@ impl<T: Instance> InstanceData<T> {
         })
     }
 
+    /// Adopt the instance handed over by a previous shim, which reports its exit in `exit`.
+    #[cfg(unix)]
+    #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+    pub fn adopt(
+        state: &InstanceState,
+        cfg: InstanceConfig<T::Engine>,
+        exit: WaitableCell<ExitCode>,
+    ) -> Result<Self> {
+        let instance = T::adopt(state.id.clone(), &cfg, exit)?;
+        Ok(Self {
+            instance,
+            cfg,
+            pid: OnceLock::from(state.pid),
+            state: RwLock::new(TaskState::Started),
+        })
+    }
+
+    /// The state of the instance, to hand it over to a new shim.
+    /// The instances being created, started or deleted can't be handed over.
+    #[cfg(unix)]
+    #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+    pub fn snapshot(&self, id: &str) -> anyhow::Result<InstanceState> {
+        let state = *self.state.read().unwrap();
+        let pid = match (state, self.pid()) {
+            (TaskState::Started | TaskState::Exited, Some(pid)) => pid,
+            _ => anyhow::bail!("instance {id} is {state:?}"),
+        };
+        Ok(InstanceState {
+            id: id.to_string(),
+            bundle: self.cfg.get_bundle().to_path_buf(),
+            stdin: self.cfg.get_stdin().to_path_buf(),
+            stdout: self.cfg.get_stdout().to_path_buf(),
+            stderr: self.cfg.get_stderr().to_path_buf(),
+            engine_options: self.cfg.get_engine_options().cloned(),
+            pid,
+            exit: self.instance.wait_timeout(Duration::ZERO).map(Into::into),
+        })
+    }
+
     #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
     pub fn pid(&self) -> Option<u32> {
         self.pid.get().copied()
@@ -64,6 +107,14 @@ impl<T: Instance> InstanceData<T> {
         self.instance.kill(signal)
     }
 
+    #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+    pub fn dump_memory(&self) -> Result<()> {
+        if self.pid().is_none() {
+            return Err(Error::FailedPrecondition("task is not running".to_string()));
+        }
+        self.instance.dump_memory()
+    }
+
     #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
     pub fn delete(&self) -> Result<()> {
         let mut s = self.state.write().unwrap();
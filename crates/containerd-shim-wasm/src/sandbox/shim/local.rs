@@ -9,28 +9,43 @@ use std::time::Duration;
 use anyhow::Context as AnyhowContext;
 use containerd_shim::api::{
     ConnectRequest, ConnectResponse, CreateTaskRequest, CreateTaskResponse, DeleteRequest, Empty,
-    KillRequest, ShutdownRequest, StartRequest, StartResponse, StateRequest, StateResponse,
-    StatsRequest, StatsResponse, WaitRequest, WaitResponse,
+    KillRequest, PidsRequest, PidsResponse, ShutdownRequest, StartRequest, StartResponse,
+    StateRequest, StateResponse, StatsRequest, StatsResponse, WaitRequest, WaitResponse,
 };
 use containerd_shim::error::Error as ShimError;
 use containerd_shim::protos::events::task::{TaskCreate, TaskDelete, TaskExit, TaskIO, TaskStart};
+use containerd_shim::protos::shim::shim::UpdateTaskRequest;
 use containerd_shim::protos::shim::shim_ttrpc::Task;
-use containerd_shim::protos::types::task::Status;
+use containerd_shim::protos::types::task::{ProcessInfo, Status};
 use containerd_shim::util::IntoOption;
 use containerd_shim::{DeleteResponse, ExitSignal, TtrpcContext, TtrpcResult};
-use log::debug;
 use oci_spec::runtime::Spec;
+use protobuf::well_known_types::any::Any;
+use tracing::debug;
 
 use crate::sandbox::instance::{Instance, InstanceConfig};
+use crate::sandbox::instance_utils::engine_options_from_runtime;
 use crate::sandbox::shim::events::{EventSender, RemoteEventSender, ToTimestamp};
 use crate::sandbox::shim::instance_data::InstanceData;
-use crate::sandbox::{oci, Error, Result};
+#[cfg(unix)]
+use crate::sandbox::shim::takeover::{self, ExitCode, InstanceState, Tasks};
+#[cfg(unix)]
+use crate::sandbox::sync::WaitableCell;
+use crate::sandbox::{cli, oci, Error, Result};
 use crate::sys::metrics::get_metrics;
 
 #[cfg(test)]
 mod tests;
 
-type LocalInstances<T> = RwLock<HashMap<String, Arc<InstanceData<T>>>>;
+/// The annotation of the `Update` requests asking for a dump of the linear memories of the task,
+/// see [`Instance::dump_memory`].
+pub const MEMORY_DUMP_ANNOTATION: &str = "runwasi.io/debug-memory-dump";
+
+/// The `type_url` of the `info` of the processes listed by the `Pids` requests, the JSON report of
+/// [`cli::info`] about the shim and its engine.
+pub const INFO_TYPE_URL: &str = "runwasi.io/info";
+
+type LocalInstances<T> = Arc<RwLock<HashMap<String, Arc<InstanceData<T>>>>>;
 
 /// Local implements the Task service for a containerd shim.
 /// It defers all task operations to the `Instance` implementation.
@@ -53,7 +68,7 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
         namespace: impl AsRef<str>,
         containerd_address: impl AsRef<str>,
     ) -> Self {
-        let instances = RwLock::default();
+        let instances = Arc::default();
         let namespace = namespace.as_ref().to_string();
         let containerd_address = containerd_address.as_ref().to_string();
         Self {
@@ -92,6 +107,75 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
     }
 }
 
+#[cfg(unix)]
+impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
+    /// Adopt the instances handed over by the previous shim, if any, and serve the takeover
+    /// requests of the next ones, for the live upgrade of the shim.
+    #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+    pub(super) fn live_upgrade(&self) -> anyhow::Result<()> {
+        let mut exits = HashMap::new();
+        for state in takeover::inherited_instances() {
+            let exit = WaitableCell::new();
+            self.adopt(&state, exit.clone())
+                .with_context(|| format!("failed to adopt instance {}", state.id))?;
+            exits.insert(state.id, exit);
+        }
+        takeover::commit(exits)?;
+
+        if let Err(err) = takeover::listen(self.instances.clone()) {
+            tracing::error!("live upgrade of the shim is disabled: {err:#}");
+        }
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+    fn adopt(&self, state: &InstanceState, exit: WaitableCell<ExitCode>) -> Result<()> {
+        if let Some(exited) = state.exit {
+            let _ = exit.set(ExitCode::from(exited));
+        }
+
+        let events = self.events.clone();
+        let id = state.id.clone();
+        let mut cfg = self.instance_config();
+        cfg.set_bundle(&state.bundle)
+            .set_stdin(&state.stdin)
+            .set_stdout(&state.stdout)
+            .set_stderr(&state.stderr)
+            .set_engine_options(state.engine_options.clone())
+            .set_phase_listener(move |phase, timestamp| events.send_phase(&id, phase, timestamp));
+
+        let instance = Arc::new(InstanceData::adopt(state, cfg, exit)?);
+        self.instances
+            .write()
+            .unwrap()
+            .insert(state.id.clone(), instance.clone());
+
+        // the exit of an instance that exited before the handover was already published
+        if state.exit.is_none() {
+            self.publish_exit(state.id.clone(), instance, state.pid)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+impl<T: Instance + Send + Sync> Tasks for LocalInstances<T> {
+    #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+    fn snapshot(&self) -> anyhow::Result<Vec<InstanceState>> {
+        let instances = self.read().unwrap();
+        instances
+            .iter()
+            .map(|(id, instance)| instance.snapshot(id))
+            .collect()
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+    fn wait(&self, id: &str) -> Option<ExitCode> {
+        let instance = self.read().unwrap().get(id).cloned()?;
+        Some(instance.wait())
+    }
+}
+
 // These are the same functions as in Task, but without the TtrcpContext, which is useful for testing
 impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
     #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
@@ -140,11 +224,21 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
             }
         }
 
+        let engine_options = match req.options.as_ref() {
+            Some(options) => engine_options_from_runtime(options)?,
+            None => None,
+        };
+
+        let events = self.events.clone();
+        let id = req.id.clone();
+
         let mut cfg = self.instance_config();
         cfg.set_bundle(&req.bundle)
             .set_stdin(&req.stdin)
             .set_stdout(&req.stdout)
-            .set_stderr(&req.stderr);
+            .set_stderr(&req.stderr)
+            .set_engine_options(engine_options)
+            .set_phase_listener(move |phase, timestamp| events.send_phase(&id, phase, timestamp));
 
         // Check if this is a cri container
         let instance = InstanceData::new(req.id(), cfg)?;
@@ -195,9 +289,20 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
             ..Default::default()
         });
 
-        let events = self.events.clone();
+        self.publish_exit(req.id().to_string(), i, pid)?;
+
+        debug!("started: {:?}", req);
 
-        let id = req.id().to_string();
+        Ok(StartResponse {
+            pid,
+            ..Default::default()
+        })
+    }
+
+    /// Publish the exit of the instance `id` once it exits.
+    #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+    fn publish_exit(&self, id: String, i: Arc<InstanceData<T>>, pid: u32) -> Result<()> {
+        let events = self.events.clone();
 
         thread::Builder::new()
             .name(format!("{id}-wait"))
@@ -214,13 +319,7 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
             })
             .context("could not spawn thread to wait exit")
             .map_err(Error::from)?;
-
-        debug!("started: {:?}", req);
-
-        Ok(StartResponse {
-            pid,
-            ..Default::default()
-        })
+        Ok(())
     }
 
     #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
@@ -232,6 +331,17 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
         Ok(Empty::new())
     }
 
+    /// The `Update` requests only serve the memory dumps, the resources of the tasks can't be
+    /// updated.
+    #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+    fn task_update(&self, req: UpdateTaskRequest) -> Result<Empty> {
+        if !req.annotations.contains_key(MEMORY_DUMP_ANNOTATION) {
+            return Err(ShimError::Unimplemented("update is not supported".to_string()).into());
+        }
+        self.get_instance(req.id())?.dump_memory()?;
+        Ok(Empty::new())
+    }
+
     #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
     fn task_delete(&self, req: DeleteRequest) -> Result<DeleteResponse> {
         if !req.exec_id().is_empty() {
@@ -327,6 +437,25 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
             ..Default::default()
         })
     }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+    fn task_pids(&self, req: PidsRequest) -> Result<PidsResponse> {
+        let i = self.get_instance(req.id())?;
+        let info = Any {
+            type_url: INFO_TYPE_URL.to_string(),
+            value: serde_json::to_vec(&cli::info::<T>(&self.engine))?,
+            ..Default::default()
+        };
+
+        Ok(PidsResponse {
+            processes: vec![ProcessInfo {
+                pid: i.pid().unwrap_or_default(),
+                info: Some(info).into(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        })
+    }
 }
 
 impl<T: Instance + Sync + Send, E: EventSender> Task for Local<T, E> {
@@ -348,6 +477,12 @@ impl<T: Instance + Sync + Send, E: EventSender> Task for Local<T, E> {
         Ok(self.task_kill(req)?)
     }
 
+    #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+    fn update(&self, _: &TtrpcContext, req: UpdateTaskRequest) -> TtrpcResult<Empty> {
+        debug!("update: {:?}", req);
+        Ok(self.task_update(req)?)
+    }
+
     #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
     fn delete(&self, _: &TtrpcContext, req: DeleteRequest) -> TtrpcResult<DeleteResponse> {
         debug!("delete: {:?}", req);
@@ -393,6 +528,7 @@ impl<T: Instance + Sync + Send, E: EventSender> Task for Local<T, E> {
         Ok(ConnectResponse {
             shim_pid,
             task_pid,
+            version: cli::version(),
             ..Default::default()
         })
     }
@@ -417,4 +553,10 @@ impl<T: Instance + Sync + Send, E: EventSender> Task for Local<T, E> {
         debug!("stats: {:?}", req);
         Ok(self.task_stats(req)?)
     }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+    fn pids(&self, _ctx: &TtrpcContext, req: PidsRequest) -> TtrpcResult<PidsResponse> {
+        debug!("pids: {:?}", req);
+        Ok(self.task_pids(req)?)
+    }
 }
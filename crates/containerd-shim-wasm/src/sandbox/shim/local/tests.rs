@@ -6,7 +6,6 @@ use std::time::Duration;
 use anyhow::Context;
 use chrono::{DateTime, Utc};
 use containerd_shim::api::Status;
-use containerd_shim::event::Event;
 use protobuf::MessageDyn;
 use serde_json as json;
 use tempfile::tempdir;
@@ -55,8 +54,8 @@ impl<T: Instance + Send + Sync, E: EventSender> LocalWithDestructor<T, E> {
 }
 
 impl EventSender for Sender<(String, Box<dyn MessageDyn>)> {
-    fn send(&self, event: impl Event) {
-        let _ = self.send((event.topic(), Box::new(event)));
+    fn publish(&self, topic: &str, event: Box<dyn MessageDyn>) {
+        let _ = self.send((topic.to_string(), event));
     }
 }
 
@@ -130,6 +129,115 @@ fn test_delete_after_create() {
         .unwrap();
 }
 
+#[test]
+fn test_memory_dump_request() -> Result<()> {
+    let dir = tempdir()?;
+    let id = "test-memory-dump-request";
+    create_bundle(dir.path(), None)?;
+
+    let (tx, _rx) = channel();
+    let local = Arc::new(Local::<InstanceStub, _>::new(
+        (),
+        tx,
+        Arc::new(ExitSignal::default()),
+        "test_namespace",
+        "/test/address",
+    ));
+    let mut _wrapped = LocalWithDestructor::new(local.clone());
+
+    local.task_create(CreateTaskRequest {
+        id: id.to_string(),
+        bundle: dir.path().to_str().unwrap().to_string(),
+        ..Default::default()
+    })?;
+
+    let dump = UpdateTaskRequest {
+        id: id.to_string(),
+        annotations: [(MEMORY_DUMP_ANNOTATION.to_string(), String::new())].into(),
+        ..Default::default()
+    };
+    // The task must be running
+    let err = local.task_update(dump.clone()).unwrap_err();
+    assert!(matches!(err, Error::FailedPrecondition(_)), "{err:?}");
+
+    local.task_start(StartRequest {
+        id: id.to_string(),
+        ..Default::default()
+    })?;
+
+    // The instances must support memory dumps
+    let err = local.task_update(dump).unwrap_err();
+    assert!(
+        matches!(err, Error::Shim(ShimError::Unimplemented(_))),
+        "{err:?}"
+    );
+
+    // The resources of the tasks can't be updated
+    let err = local
+        .task_update(UpdateTaskRequest {
+            id: id.to_string(),
+            ..Default::default()
+        })
+        .unwrap_err();
+    assert!(
+        matches!(err, Error::Shim(ShimError::Unimplemented(_))),
+        "{err:?}"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_pids_request() -> Result<()> {
+    let dir = tempdir()?;
+    let id = "test-pids-request";
+    create_bundle(dir.path(), None)?;
+
+    let (tx, _rx) = channel();
+    let local = Arc::new(Local::<InstanceStub, _>::new(
+        (),
+        tx,
+        Arc::new(ExitSignal::default()),
+        "test_namespace",
+        "/test/address",
+    ));
+    let mut _wrapped = LocalWithDestructor::new(local.clone());
+
+    local.task_create(CreateTaskRequest {
+        id: id.to_string(),
+        bundle: dir.path().to_str().unwrap().to_string(),
+        ..Default::default()
+    })?;
+    local.task_start(StartRequest {
+        id: id.to_string(),
+        ..Default::default()
+    })?;
+
+    let res = local.task_pids(PidsRequest {
+        id: id.to_string(),
+        ..Default::default()
+    })?;
+    assert_eq!(res.processes.len(), 1);
+
+    let process = &res.processes[0];
+    assert_eq!(process.pid, std::process::id());
+    assert_eq!(process.info.type_url, INFO_TYPE_URL);
+
+    // The report includes the details of the engine, which the stub doesn't have
+    let info: json::Value = json::from_slice(&process.info.value)?;
+    assert_eq!(info["engine"], json::Value::Null);
+
+    let err = local
+        .task_pids(PidsRequest {
+            id: "missing".to_string(),
+            ..Default::default()
+        })
+        .unwrap_err();
+    assert!(matches!(err, Error::NotFound(_)), "{err:?}");
+
+    Ok(())
+}
+
 #[test]
 fn test_cri_task() -> Result<()> {
     // Currently the relationship between the "base" container and the "instances" are pretty weak.
@@ -8,8 +8,11 @@ mod instance_data;
 mod local;
 #[cfg(feature = "opentelemetry")]
 mod otel;
+#[cfg(unix)]
+pub(crate) mod takeover;
 mod task_state;
 
 pub use cli::Cli;
+pub use local::{INFO_TYPE_URL, MEMORY_DUMP_ANNOTATION};
 #[cfg(feature = "opentelemetry")]
 pub use otel::{traces_enabled as otel_traces_enabled, Config as OtlpConfig};
@@ -0,0 +1,799 @@
+//! Live upgrade of the shim: a new shim binary takes the tasks of a running shim over, without
+//! restarting its containers, see `docs/shim-live-upgrade.md`.
+//!
+//! With `RUNWASI_LIVE_UPGRADE` set in its environment, a shim relays the connections of containerd
+//! to its ttrpc server, see [`relay`], and listens for takeover requests on
+//! `/run/runwasi/shims/<pid>.sock`. Running `<new shim binary> --takeover <pid>`:
+//! 1. asks the shim `<pid>` for its tasks. It pauses its relay between two frames, once the
+//!    requests in flight are answered, and hands over the state of its instances and its command
+//!    line, with the listener and the connections of containerd passed as `SCM_RIGHTS`,
+//! 2. starts the new shim with the same command line, inheriting those fds and the takeover socket,
+//! 3. the new shim adopts the instances, and tells the old one it is ready. Once the old shim
+//!    commits, the new one relays the connections, replaying the requests not answered yet. The old
+//!    shim stops publishing events, and only lives on as the parent of the containers, to report
+//!    their exits to the new shim.
+
+mod relay;
+
+use std::collections::HashMap;
+use std::fs::{self, DirBuilder};
+use std::io::{self, ErrorKind, IoSlice, IoSliceMut, Read, Write};
+use std::net::Shutdown;
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use std::{env, thread};
+
+use anyhow::{bail, ensure, Context, Result};
+use chrono::{DateTime, Utc};
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::sys::socket::{
+    getsockopt, recvmsg, sendmsg, sockopt, ControlMessage, ControlMessageOwned, MsgFlags,
+};
+use nix::unistd::{dup2, geteuid, setsid};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use self::relay::{Frame, Relay};
+use crate::sandbox::sync::WaitableCell;
+
+/// Enables the live upgrade of the shims started with it in their environment.
+pub const LIVE_UPGRADE_ENV: &str = "RUNWASI_LIVE_UPGRADE";
+/// The fds of the takeover socket and of the `--takeover` process, in the environment of a new shim.
+const TAKEOVER_ENV: &str = "RUNWASI_TAKEOVER";
+/// The directory of the sockets of the shims, only accessible to their user.
+const SHIMS_DIR: &str = "/run/runwasi/shims";
+/// The version of the takeover protocol, both shims must speak the same.
+const PROTOCOL_VERSION: u32 = 1;
+/// How long the requests in flight are given to be answered before a handover.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the new shim is given to adopt the instances.
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+/// The fd of the listener served by the ttrpc server of containerd-shim.
+const TTRPC_LISTENER_FD: RawFd = 3;
+/// The lowest fd inherited by the new shim, so that its fd 3 stays free.
+const MIN_INHERITED_FD: RawFd = 10;
+/// The most fds handed over: the listener and the connections of containerd.
+const MAX_FDS: usize = 64;
+/// The largest message of the protocol.
+const MAX_MESSAGE_LEN: usize = 16 << 20;
+
+/// The exit code and time of an instance.
+pub(super) type ExitCode = (u32, DateTime<Utc>);
+
+/// The relay of the connections of containerd, once live upgrades are enabled.
+static RELAY: OnceLock<Arc<Relay>> = OnceLock::new();
+/// The state handed over by the previous shim, until its instances are adopted.
+static INHERITED: Mutex<Option<Inherited>> = Mutex::new(None);
+/// Set once the tasks are handed over, the new shim then publishes their events.
+static HANDED_OVER: AtomicBool = AtomicBool::new(false);
+
+/// The tasks of a shim, as handed over to a new shim.
+pub(super) trait Tasks: Send + Sync + 'static {
+    /// The state of the instances, failing if one of them can't be handed over in its state.
+    fn snapshot(&self) -> Result<Vec<InstanceState>>;
+
+    /// Wait for the exit of the instance `id`.
+    fn wait(&self, id: &str) -> Option<ExitCode>;
+}
+
+/// The state of an instance, handed over to the new shim.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(super) struct InstanceState {
+    pub id: String,
+    pub bundle: PathBuf,
+    pub stdin: PathBuf,
+    pub stdout: PathBuf,
+    pub stderr: PathBuf,
+    pub engine_options: Option<Value>,
+    pub pid: u32,
+    /// The exit of the instance, if it already exited.
+    pub exit: Option<ExitStatus>,
+}
+
+/// The exit of an instance.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub(super) struct ExitStatus {
+    pub status: u32,
+    /// In nanoseconds since the epoch.
+    pub exited_at: i64,
+}
+
+impl From<ExitCode> for ExitStatus {
+    fn from((status, exited_at): ExitCode) -> Self {
+        let exited_at = exited_at.timestamp_nanos_opt().unwrap_or_default();
+        Self { status, exited_at }
+    }
+}
+
+impl From<ExitStatus> for ExitCode {
+    fn from(exit: ExitStatus) -> Self {
+        (exit.status, DateTime::from_timestamp_nanos(exit.exited_at))
+    }
+}
+
+/// The state of the old shim, handed over to the new one.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Handoff {
+    version: u32,
+    /// The arguments of the shim, without its binary.
+    args: Vec<String>,
+    env: Vec<(String, String)>,
+    cwd: PathBuf,
+    instances: Vec<InstanceState>,
+    /// The requests not answered yet, for each connection of containerd.
+    connections: Vec<Vec<Vec<u8>>>,
+    /// The fds of the listener and of the connections in the new shim, set by `--takeover`.
+    #[serde(default)]
+    fds: Vec<RawFd>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct ExitReport {
+    id: String,
+    #[serde(flatten)]
+    exit: ExitStatus,
+}
+
+/// The messages of the takeover protocol.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Message {
+    /// Sent by `--takeover` to ask for the tasks of the old shim.
+    Takeover { version: u32 },
+    /// The state of the old shim, sent with the fds of its listener and connections.
+    Handoff(Handoff),
+    /// Sent by the old shim when it can't hand its tasks over.
+    Refused(String),
+    /// Sent by the new shim once it adopted the instances.
+    Ready,
+    /// Sent by the old shim once it no longer serves containerd.
+    Committed,
+    /// Sent by the old shim when an instance exits.
+    Exit(ExitReport),
+}
+
+/// Send `message` on `stream`, with `fds`.
+fn send(stream: &UnixStream, message: &Message, fds: &[RawFd]) -> io::Result<()> {
+    let body = serde_json::to_vec(message)?;
+    let len = (body.len() as u64).to_be_bytes();
+    let rights = [ControlMessage::ScmRights(fds)];
+    let cmsgs = if fds.is_empty() { &[][..] } else { &rights[..] };
+    let sent = sendmsg::<()>(
+        stream.as_raw_fd(),
+        &[IoSlice::new(&len)],
+        cmsgs,
+        MsgFlags::empty(),
+        None,
+    )?;
+    let mut stream = stream;
+    stream.write_all(&len[sent..])?;
+    stream.write_all(&body)
+}
+
+/// Receive a message from `stream`, with the fds sent along.
+fn recv(stream: &UnixStream) -> io::Result<(Message, Vec<OwnedFd>)> {
+    let mut len = [0u8; 8];
+    let mut fds = vec![];
+    let mut cmsg = nix::cmsg_space!([RawFd; MAX_FDS]);
+    let received = {
+        let mut iov = [IoSliceMut::new(&mut len)];
+        let msg = recvmsg::<()>(
+            stream.as_raw_fd(),
+            &mut iov,
+            Some(&mut cmsg),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        )?;
+        for cmsg in msg.cmsgs()? {
+            if let ControlMessageOwned::ScmRights(received) = cmsg {
+                // SAFETY: the fds were just received, nothing else owns them
+                fds.extend(
+                    received
+                        .into_iter()
+                        .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }),
+                );
+            }
+        }
+        msg.bytes
+    };
+    if received == 0 {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+
+    let mut stream = stream;
+    stream.read_exact(&mut len[received..])?;
+    let len = u64::from_be_bytes(len) as usize;
+    if len > MAX_MESSAGE_LEN {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("message of {len} bytes is too large"),
+        ));
+    }
+    let mut body = vec![0; len];
+    stream.read_exact(&mut body)?;
+    Ok((serde_json::from_slice(&body)?, fds))
+}
+
+/// The directory of the sockets of the shims, created if needed.
+fn shims_dir() -> io::Result<PathBuf> {
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(SHIMS_DIR)?;
+    Ok(PathBuf::from(SHIMS_DIR))
+}
+
+/// The takeover socket of the shim `pid`.
+fn takeover_socket(pid: u32) -> PathBuf {
+    PathBuf::from(SHIMS_DIR).join(format!("{pid}.sock"))
+}
+
+/// Set `FD_CLOEXEC` on `fd`, which this process owns from now on.
+fn owned(fd: RawFd) -> Result<OwnedFd> {
+    fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).with_context(|| format!("invalid fd {fd}"))?;
+    // SAFETY: the fd was inherited for this process to own it
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Duplicate `fd` without `FD_CLOEXEC`, above the fds a shim uses, to be inherited by a new shim.
+fn inheritable(fd: impl AsFd) -> Result<OwnedFd> {
+    let fd = fcntl(fd.as_fd().as_raw_fd(), FcntlArg::F_DUPFD(MIN_INHERITED_FD))?;
+    // SAFETY: the fd was just duplicated
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Serve an internal listener on fd 3, for the ttrpc server of containerd-shim, returning its path.
+fn serve_internal_listener() -> Result<(UnixListener, PathBuf)> {
+    let path = shims_dir()?.join(format!("{}.ttrpc", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)
+        .with_context(|| format!("failed to listen on {}", path.display()))?;
+    Ok((listener, path))
+}
+
+fn install_internal_listener(listener: UnixListener) -> Result<()> {
+    dup2(listener.as_raw_fd(), TTRPC_LISTENER_FD)?;
+    fcntl(TTRPC_LISTENER_FD, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+    Ok(())
+}
+
+/// Prepare the live upgrade of the shim, before containerd-shim serves its ttrpc listener on fd 3:
+/// the listener of containerd is moved aside, and relayed to an internal listener on fd 3.
+#[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+pub(crate) fn setup() -> Result<()> {
+    if let Some(fds) = env::var_os(TAKEOVER_ENV) {
+        env::remove_var(TAKEOVER_ENV);
+        return inherit(&fds.to_string_lossy());
+    }
+    if env::var_os(LIVE_UPGRADE_ENV).is_none() {
+        return Ok(());
+    }
+
+    let listener = fcntl(
+        TTRPC_LISTENER_FD,
+        FcntlArg::F_DUPFD_CLOEXEC(MIN_INHERITED_FD),
+    )
+    .context("the shim has no ttrpc listener")?;
+    // SAFETY: the fd was just duplicated
+    let listener = UnixListener::from(unsafe { OwnedFd::from_raw_fd(listener) });
+    let (internal, path) = serve_internal_listener()?;
+    let relay = Relay::start(listener, &path, vec![])?;
+    install_internal_listener(internal)?;
+    let _ = RELAY.set(relay);
+    Ok(())
+}
+
+/// The state handed over by the previous shim.
+struct Inherited {
+    handoff: Handoff,
+    /// The connection to the previous shim.
+    takeover: UnixStream,
+    /// The connection to `--takeover`, waiting for the new shim to be ready.
+    spawner: UnixStream,
+    listener: UnixListener,
+    connections: Vec<UnixStream>,
+    internal: PathBuf,
+}
+
+/// Read the state handed over to this shim, started by `--takeover`.
+#[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+fn inherit(fds: &str) -> Result<()> {
+    let (takeover, spawner) = fds
+        .split_once(',')
+        .with_context(|| format!("invalid {TAKEOVER_ENV}: {fds:?}"))?;
+    let takeover = UnixStream::from(owned(takeover.parse()?)?);
+    let spawner = UnixStream::from(owned(spawner.parse()?)?);
+
+    let mut handoff = vec![];
+    (&spawner).read_to_end(&mut handoff)?;
+    let handoff: Handoff = serde_json::from_slice(&handoff)?;
+    ensure!(
+        handoff.fds.len() == handoff.connections.len() + 1,
+        "expected {} fds, got {}",
+        handoff.connections.len() + 1,
+        handoff.fds.len()
+    );
+    let mut fds = handoff.fds.iter().map(|&fd| owned(fd));
+    let listener = UnixListener::from(fds.next().unwrap()?);
+    let connections = fds
+        .map(|fd| fd.map(UnixStream::from))
+        .collect::<Result<_>>()?;
+
+    let (internal, path) = serve_internal_listener()?;
+    install_internal_listener(internal)?;
+    *INHERITED.lock().unwrap() = Some(Inherited {
+        handoff,
+        takeover,
+        spawner,
+        listener,
+        connections,
+        internal: path,
+    });
+    Ok(())
+}
+
+/// The instances handed over by the previous shim, to adopt before [`commit`].
+pub(super) fn inherited_instances() -> Vec<InstanceState> {
+    let inherited = INHERITED.lock().unwrap();
+    inherited
+        .as_ref()
+        .map(|inherited| inherited.handoff.instances.clone())
+        .unwrap_or_default()
+}
+
+/// Take the connections of containerd over from the previous shim, once its instances are adopted.
+/// The exits reported by the previous shim are set in `exits`.
+#[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+pub(super) fn commit(exits: HashMap<String, WaitableCell<ExitCode>>) -> Result<()> {
+    let Some(inherited) = INHERITED.lock().unwrap().take() else {
+        return Ok(());
+    };
+    let Inherited {
+        handoff,
+        takeover,
+        spawner,
+        listener,
+        connections,
+        internal,
+    } = inherited;
+
+    send(&takeover, &Message::Ready, &[])?;
+    match recv(&takeover)?.0 {
+        Message::Committed => {}
+        Message::Refused(reason) => bail!("the previous shim aborted the takeover: {reason}"),
+        message => bail!("unexpected message from the previous shim: {message:?}"),
+    }
+
+    let pending = handoff
+        .connections
+        .into_iter()
+        .map(|frames| frames.into_iter().map(Frame).collect());
+    let relay = Relay::start(
+        listener,
+        internal,
+        connections.into_iter().zip(pending).collect(),
+    )?;
+    let _ = RELAY.set(relay);
+
+    thread::Builder::new()
+        .name("takeover-exits".into())
+        .spawn(move || receive_exits(takeover, exits))?;
+    let _ = (&spawner).write_all(&[1]);
+    tracing::info!("took {} instance(s) over", handoff.instances.len());
+    Ok(())
+}
+
+/// Set the exits reported by the previous shim, the parent of the containers.
+#[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+fn receive_exits(takeover: UnixStream, exits: HashMap<String, WaitableCell<ExitCode>>) {
+    loop {
+        match recv(&takeover) {
+            Ok((Message::Exit(report), _)) => {
+                if let Some(exit) = exits.get(&report.id) {
+                    let _ = exit.set(ExitCode::from(report.exit));
+                }
+            }
+            Ok((message, _)) => {
+                tracing::warn!("unexpected message from the previous shim: {message:?}")
+            }
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+            Err(err) => {
+                tracing::warn!("failed to receive the exits of the previous shim: {err}");
+                break;
+            }
+        }
+    }
+    // the previous shim exits after the last exit, any other exit is lost
+    for (id, exit) in exits {
+        if exit.set((137, Utc::now())).is_ok() {
+            tracing::warn!("the exit of {id} was not reported by the previous shim");
+        }
+    }
+}
+
+/// Whether the tasks were handed over to a new shim.
+pub(super) fn is_handed_over() -> bool {
+    HANDED_OVER.load(Ordering::Acquire)
+}
+
+/// Serve the takeover requests of new shims, once live upgrades are enabled.
+#[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+pub(super) fn listen(tasks: impl Tasks) -> Result<()> {
+    let Some(relay) = RELAY.get() else {
+        return Ok(());
+    };
+    shims_dir()?;
+    let path = takeover_socket(std::process::id());
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)
+        .with_context(|| format!("failed to listen on {}", path.display()))?;
+
+    thread::Builder::new()
+        .name("takeover".into())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        tracing::warn!("failed to accept a takeover request: {err}");
+                        continue;
+                    }
+                };
+                match hand_over(&stream, relay, &tasks) {
+                    Ok(running) => {
+                        let _ = fs::remove_file(&path);
+                        report_exits(stream, &tasks, running);
+                        tracing::info!("the tasks were taken over by a new shim, exiting");
+                        std::process::exit(0);
+                    }
+                    Err(err) => {
+                        tracing::warn!("refused to hand the tasks over: {err:#}");
+                        let _ = send(&stream, &Message::Refused(format!("{err:#}")), &[]);
+                    }
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// Hand the tasks over on `stream`, returning the instances still running.
+#[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+fn hand_over(stream: &UnixStream, relay: &Arc<Relay>, tasks: &impl Tasks) -> Result<Vec<String>> {
+    let peer = getsockopt(stream, sockopt::PeerCredentials)?;
+    ensure!(
+        peer.uid() == geteuid().as_raw(),
+        "takeover requested by uid {}",
+        peer.uid()
+    );
+    stream.set_read_timeout(Some(READY_TIMEOUT))?;
+    match recv(stream)?.0 {
+        Message::Takeover { version } => ensure!(
+            version == PROTOCOL_VERSION,
+            "unsupported takeover protocol version {version}"
+        ),
+        message => bail!("unexpected message: {message:?}"),
+    }
+
+    relay.pause(DRAIN_TIMEOUT)?;
+    let running = send_handoff(stream, relay, tasks).and_then(|running| {
+        match recv(stream)?.0 {
+            Message::Ready => {}
+            message => bail!("unexpected message from the new shim: {message:?}"),
+        }
+        send(stream, &Message::Committed, &[])?;
+        Ok(running)
+    });
+    let running = match running {
+        Ok(running) => running,
+        Err(err) => {
+            relay.resume()?;
+            return Err(err);
+        }
+    };
+
+    HANDED_OVER.store(true, Ordering::Release);
+    relay.release();
+    stream.set_read_timeout(None)?;
+    Ok(running)
+}
+
+#[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+fn send_handoff(stream: &UnixStream, relay: &Relay, tasks: &impl Tasks) -> Result<Vec<String>> {
+    let instances = tasks.snapshot()?;
+    let (listener, connections) = relay.handed_over()?;
+    let mut fds = vec![listener.as_raw_fd()];
+    fds.extend(connections.iter().map(|(client, _)| client.as_raw_fd()));
+    ensure!(fds.len() <= MAX_FDS, "too many connections to hand over");
+
+    let running = instances
+        .iter()
+        .filter(|instance| instance.exit.is_none())
+        .map(|instance| instance.id.clone())
+        .collect();
+    let handoff = Handoff {
+        version: PROTOCOL_VERSION,
+        args: env::args().skip(1).collect(),
+        env: env::vars().collect(),
+        cwd: env::current_dir()?,
+        instances,
+        connections: connections
+            .iter()
+            .map(|(_, pending)| pending.iter().map(|frame| frame.0.clone()).collect())
+            .collect(),
+        fds: vec![],
+    };
+    send(stream, &Message::Handoff(handoff), &fds)?;
+    Ok(running)
+}
+
+/// Report the exits of the `running` instances to the new shim, as the parent of the containers.
+#[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+fn report_exits(stream: UnixStream, tasks: &impl Tasks, running: Vec<String>) {
+    let stream = Mutex::new(stream);
+    thread::scope(|scope| {
+        for id in running {
+            let stream = &stream;
+            scope.spawn(move || {
+                let exit = tasks.wait(&id).unwrap_or_else(|| (137, Utc::now()));
+                let report = ExitReport {
+                    id,
+                    exit: exit.into(),
+                };
+                let stream = stream.lock().unwrap();
+                if let Err(err) = send(&stream, &Message::Exit(report), &[]) {
+                    tracing::warn!("failed to report an exit to the new shim: {err}");
+                }
+            });
+        }
+    });
+}
+
+/// Take the tasks of the running shim `pid` over, with a new shim running the current binary.
+/// Returns the pid of the new shim, once it serves containerd.
+#[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+pub(crate) fn take_over(pid: u32) -> Result<u32> {
+    let path = takeover_socket(pid);
+    let stream = UnixStream::connect(&path).with_context(|| {
+        format!(
+            "failed to connect to {}, is the shim running with {LIVE_UPGRADE_ENV}?",
+            path.display()
+        )
+    })?;
+    send(
+        &stream,
+        &Message::Takeover {
+            version: PROTOCOL_VERSION,
+        },
+        &[],
+    )?;
+    let (mut handoff, fds) = match recv(&stream)? {
+        (Message::Handoff(handoff), fds) => (handoff, fds),
+        (Message::Refused(reason), _) => bail!("the shim refused the takeover: {reason}"),
+        (message, _) => bail!("unexpected message: {message:?}"),
+    };
+    ensure!(
+        handoff.version == PROTOCOL_VERSION,
+        "unsupported takeover protocol version {}",
+        handoff.version
+    );
+    ensure!(
+        fds.len() == handoff.connections.len() + 1,
+        "expected {} fds, got {}",
+        handoff.connections.len() + 1,
+        fds.len()
+    );
+
+    let inherited = fds.iter().map(inheritable).collect::<Result<Vec<_>>>()?;
+    handoff.fds = inherited.iter().map(AsRawFd::as_raw_fd).collect();
+    let takeover = inheritable(&stream)?;
+    let (spawner, child) = UnixStream::pair()?;
+    let child = inheritable(&child)?;
+
+    let mut command = Command::new(env::current_exe()?);
+    command
+        .args(&handoff.args)
+        .env_clear()
+        .envs(handoff.env.iter().cloned())
+        .env(
+            TAKEOVER_ENV,
+            format!("{},{}", takeover.as_raw_fd(), child.as_raw_fd()),
+        )
+        .current_dir(&handoff.cwd)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    // SAFETY: setsid is async-signal-safe
+    unsafe {
+        command.pre_exec(|| setsid().map(drop).map_err(io::Error::from));
+    }
+    let mut shim = command.spawn().context("failed to start the new shim")?;
+    drop((inherited, takeover, child));
+
+    (&spawner).write_all(&serde_json::to_vec(&handoff)?)?;
+    spawner.shutdown(Shutdown::Write)?;
+    spawner.set_read_timeout(Some(READY_TIMEOUT))?;
+    let mut ready = [0];
+    match (&spawner).read(&mut ready) {
+        Ok(1) => Ok(shim.id()),
+        _ => {
+            let _ = shim.kill();
+            bail!("the new shim failed to take the tasks over, see its logs")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_messages() -> Result<()> {
+        let (left, right) = UnixStream::pair()?;
+        let exit = ExitStatus {
+            status: 3,
+            exited_at: 1_700_000_000_000_000_005,
+        };
+        let handoff = Handoff {
+            version: PROTOCOL_VERSION,
+            args: vec!["-id".into(), "foo".into()],
+            env: vec![("RUNWASI_LIVE_UPGRADE".into(), "1".into())],
+            cwd: "/run/containerd/bundle/foo".into(),
+            instances: vec![InstanceState {
+                id: "foo".into(),
+                bundle: "/run/containerd/bundle/foo".into(),
+                stdin: "".into(),
+                stdout: "/run/foo/stdout".into(),
+                stderr: "/run/foo/stderr".into(),
+                engine_options: Some(serde_json::json!({"fuel": 1})),
+                pid: 42,
+                exit: Some(exit),
+            }],
+            connections: vec![vec![vec![0, 0, 0, 0, 0, 0, 0, 1, 1, 0]]],
+            fds: vec![],
+        };
+
+        // the fds are received as new fds to the same files
+        let dir = tempdir()?;
+        File::create(dir.path().join("file"))?.write_all(b"hello")?;
+        let file = File::open(dir.path().join("file"))?;
+        send(
+            &left,
+            &Message::Handoff(handoff.clone()),
+            &[file.as_raw_fd()],
+        )?;
+        send(
+            &left,
+            &Message::Exit(ExitReport {
+                id: "foo".into(),
+                exit,
+            }),
+            &[],
+        )?;
+        drop(left);
+
+        let (message, fds) = recv(&right)?;
+        assert_eq!(message, Message::Handoff(handoff));
+        assert_eq!(fds.len(), 1);
+        assert_ne!(fds[0].as_raw_fd(), file.as_raw_fd());
+        let mut content = String::new();
+        File::from(fds.into_iter().next().unwrap()).read_to_string(&mut content)?;
+        assert_eq!(content, "hello");
+
+        let (message, fds) = recv(&right)?;
+        assert_eq!(
+            message,
+            Message::Exit(ExitReport {
+                id: "foo".into(),
+                exit
+            })
+        );
+        assert!(fds.is_empty());
+        let err = recv(&right).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        Ok(())
+    }
+
+    struct FakeTasks(Vec<InstanceState>);
+
+    impl Tasks for FakeTasks {
+        fn snapshot(&self) -> Result<Vec<InstanceState>> {
+            ensure!(!self.0.is_empty(), "foo is being created");
+            Ok(self.0.clone())
+        }
+
+        fn wait(&self, _id: &str) -> Option<ExitCode> {
+            Some((0, DateTime::from_timestamp(1_700_000_000, 0).unwrap()))
+        }
+    }
+
+    #[test]
+    fn test_hand_over() -> Result<()> {
+        let dir = tempdir()?;
+        let (public, internal) = (dir.path().join("public"), dir.path().join("internal"));
+        let _server = UnixListener::bind(&internal)?;
+        let relay = Relay::start(UnixListener::bind(&public)?, &internal, vec![])?;
+        let _client = UnixStream::connect(&public)?;
+        let instance = InstanceState {
+            id: "foo".into(),
+            bundle: "/run/containerd/bundle/foo".into(),
+            stdin: "".into(),
+            stdout: "".into(),
+            stderr: "".into(),
+            engine_options: None,
+            pid: 42,
+            exit: None,
+        };
+        let takeover = Message::Takeover {
+            version: PROTOCOL_VERSION,
+        };
+
+        // an instance being created can't be handed over, the relay resumes
+        let (old, new) = UnixStream::pair()?;
+        send(&new, &takeover, &[])?;
+        assert!(hand_over(&old, &relay, &FakeTasks(vec![])).is_err());
+        assert!(!is_handed_over());
+
+        let (old, new) = UnixStream::pair()?;
+        let tasks = FakeTasks(vec![instance.clone()]);
+        send(&new, &takeover, &[])?;
+        thread::scope(|scope| -> Result<()> {
+            let old = scope.spawn(|| hand_over(&old, &relay, &tasks));
+            let (message, fds) = recv(&new)?;
+            let Message::Handoff(handoff) = message else {
+                bail!("unexpected message {message:?}");
+            };
+            assert_eq!(handoff.instances, vec![instance]);
+            assert_eq!(handoff.connections, vec![Vec::<Vec<u8>>::new()]);
+            // the listener and the connection of the client
+            assert_eq!(fds.len(), 2);
+            send(&new, &Message::Ready, &[])?;
+            assert_eq!(recv(&new)?.0, Message::Committed);
+            assert_eq!(old.join().unwrap()?, vec!["foo".to_string()]);
+            Ok(())
+        })?;
+        assert!(is_handed_over());
+        Ok(())
+    }
+
+    #[test]
+    fn test_exit_status() {
+        let exited_at = DateTime::from_timestamp(1_700_000_000, 5).unwrap();
+        let exit = ExitStatus::from((137, exited_at));
+        assert_eq!(exit.exited_at, 1_700_000_000_000_000_005);
+        assert_eq!(ExitCode::from(exit), (137, exited_at));
+    }
+
+    #[test]
+    fn test_receive_exits() -> Result<()> {
+        let (old, new) = UnixStream::pair()?;
+        let exited_at = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let (foo, bar) = (WaitableCell::new(), WaitableCell::new());
+        let exits = HashMap::from([
+            ("foo".to_string(), foo.clone()),
+            ("bar".to_string(), bar.clone()),
+        ]);
+
+        let report = ExitReport {
+            id: "foo".into(),
+            exit: (3, exited_at).into(),
+        };
+        send(&old, &Message::Exit(report), &[])?;
+        drop(old);
+        receive_exits(new, exits);
+
+        assert_eq!(foo.wait(), &(3, exited_at));
+        // the exits not reported are lost with the previous shim
+        assert_eq!(bar.wait().0, 137);
+        Ok(())
+    }
+}
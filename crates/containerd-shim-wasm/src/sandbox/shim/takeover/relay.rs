@@ -0,0 +1,569 @@
+//! Relay of the connections of containerd to the ttrpc server of the shim.
+//!
+//! With live upgrades enabled, the ttrpc server of the shim doesn't serve the listener created by
+//! containerd, but an internal one. The relay accepts the connections of containerd, and copies
+//! the ttrpc frames between them and the server. It knows the boundaries of the frames, and the
+//! requests not answered yet, so that it can be paused between two frames and its connections
+//! handed over to a new shim process, which replays the unanswered requests to its own server.
+
+use std::collections::BTreeMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::mem;
+use std::net::Shutdown;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Length of the header of a ttrpc frame: its length, stream id, message type and flags.
+const HEADER_LEN: usize = 10;
+/// Message type of the frames of requests.
+const MESSAGE_TYPE_REQUEST: u8 = 1;
+/// Message type of the frames of responses.
+const MESSAGE_TYPE_RESPONSE: u8 = 2;
+/// The largest frame accepted by ttrpc.
+const MAX_FRAME_LEN: usize = 4 << 20;
+/// How often the idle relay checks whether it is paused.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// The only requests that may stay unanswered while the relay is paused. `Wait` blocks until the
+/// task exits and has no side effect, so it is replayed to the new shim.
+const WAIT_METHOD: &str = "Wait";
+
+/// A ttrpc frame, with its header.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(super) struct Frame(pub Vec<u8>);
+
+impl Frame {
+    fn stream_id(&self) -> u32 {
+        u32::from_be_bytes(self.0[4..8].try_into().unwrap())
+    }
+
+    fn message_type(&self) -> u8 {
+        self.0[8]
+    }
+
+    /// The method of the request in this frame, e.g. `Wait`.
+    fn method(&self) -> Option<String> {
+        request_method(&self.0[HEADER_LEN..]).ok().flatten()
+    }
+}
+
+/// The next frame of a connection.
+#[derive(Debug, PartialEq)]
+enum Next {
+    Frame(Frame),
+    /// The relay was paused before the first byte of a frame.
+    Paused,
+    /// The connection was closed between two frames.
+    Closed,
+}
+
+/// Read the next frame of `stream`, whose reads time out, unless `paused` is set before its first
+/// byte is read. Once started, a frame is always read to its end.
+fn next_frame(mut stream: impl Read, paused: &AtomicBool) -> io::Result<Next> {
+    let mut header = [0; HEADER_LEN];
+    let mut filled = 0;
+    while filled < HEADER_LEN {
+        if filled == 0 && paused.load(Ordering::Acquire) {
+            return Ok(Next::Paused);
+        }
+        match stream.read(&mut header[filled..]) {
+            Ok(0) if filled == 0 => return Ok(Next::Closed),
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(n) => filled += n,
+            Err(err) if is_timeout(&err) => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    let len = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("frame of {len} bytes exceeds the ttrpc limit"),
+        ));
+    }
+    let mut frame = header.to_vec();
+    frame.resize(HEADER_LEN + len, 0);
+    while filled < frame.len() {
+        match stream.read(&mut frame[filled..]) {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(n) => filled += n,
+            Err(err) if is_timeout(&err) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(Next::Frame(Frame(frame)))
+}
+
+fn is_timeout(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+    )
+}
+
+/// Decode the `method` field of a ttrpc `Request` message.
+/// The message is not part of the shim protos, and is simple enough to be decoded by hand.
+fn request_method(mut buf: &[u8]) -> Result<Option<String>, &'static str> {
+    fn varint(buf: &mut &[u8]) -> Result<u64, &'static str> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = buf.split_first().ok_or("truncated varint")?;
+            *buf = rest;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("invalid varint")
+    }
+    fn take<'a>(buf: &mut &'a [u8], len: u64) -> Result<&'a [u8], &'static str> {
+        let len = usize::try_from(len).map_err(|_| "invalid length")?;
+        if len > buf.len() {
+            return Err("truncated field");
+        }
+        let (field, rest) = buf.split_at(len);
+        *buf = rest;
+        Ok(field)
+    }
+
+    while !buf.is_empty() {
+        let key = varint(&mut buf)?;
+        match (key >> 3, key & 7) {
+            (2, 2) => {
+                let len = varint(&mut buf)?;
+                let method = String::from_utf8(take(&mut buf, len)?.to_vec())
+                    .map_err(|_| "invalid method")?;
+                return Ok(Some(method));
+            }
+            (_, 0) => {
+                varint(&mut buf)?;
+            }
+            (_, 1) => {
+                take(&mut buf, 8)?;
+            }
+            (_, 2) => {
+                let len = varint(&mut buf)?;
+                take(&mut buf, len)?;
+            }
+            (_, 5) => {
+                take(&mut buf, 4)?;
+            }
+            _ => return Err("unsupported wire type"),
+        }
+    }
+    Ok(None)
+}
+
+/// A connection of containerd, relayed to a connection to the ttrpc server of the shim.
+struct Connection {
+    client: UnixStream,
+    server: UnixStream,
+    /// The requests relayed to the server and not answered yet, by stream id.
+    pending: Mutex<BTreeMap<u32, Frame>>,
+    closed: AtomicBool,
+}
+
+impl Connection {
+    fn new(client: UnixStream, server: &Path) -> io::Result<Self> {
+        let server = UnixStream::connect(server)?;
+        client.set_nonblocking(false)?;
+        client.set_read_timeout(Some(POLL_INTERVAL))?;
+        server.set_read_timeout(Some(POLL_INTERVAL))?;
+        Ok(Self {
+            client,
+            server,
+            pending: Mutex::default(),
+            closed: AtomicBool::new(false),
+        })
+    }
+
+    /// Close both ends, once one of them is closed.
+    /// The client is only shut down here: it may be shared with a new shim once handed over.
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        let _ = self.client.shutdown(Shutdown::Both);
+        let _ = self.server.shutdown(Shutdown::Both);
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    /// Send `frame` to the server, tracking it until answered if it is a request.
+    fn request(&self, frame: Frame) -> io::Result<()> {
+        if frame.message_type() == MESSAGE_TYPE_REQUEST {
+            let mut pending = self.pending.lock().unwrap();
+            pending.insert(frame.stream_id(), frame.clone());
+        }
+        (&self.server).write_all(&frame.0)
+    }
+
+    /// Relay the frames of containerd to the server, until paused or closed.
+    fn relay_requests(&self, paused: &AtomicBool) -> io::Result<()> {
+        loop {
+            match next_frame(&self.client, paused)? {
+                Next::Frame(frame) => self.request(frame)?,
+                Next::Paused => return Ok(()),
+                Next::Closed => {
+                    self.close();
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Relay the frames of the server to containerd, until paused or closed.
+    fn relay_responses(&self, paused: &AtomicBool) -> io::Result<()> {
+        loop {
+            match next_frame(&self.server, paused)? {
+                Next::Frame(frame) => {
+                    if frame.message_type() == MESSAGE_TYPE_RESPONSE {
+                        self.pending.lock().unwrap().remove(&frame.stream_id());
+                    }
+                    (&self.client).write_all(&frame.0)?;
+                }
+                Next::Paused => return Ok(()),
+                Next::Closed => {
+                    self.close();
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Whether the requests not answered yet can be replayed to a new shim.
+    fn is_idle(&self) -> bool {
+        let pending = self.pending.lock().unwrap();
+        pending
+            .values()
+            .all(|frame| frame.method().as_deref() == Some(WAIT_METHOD))
+    }
+
+    /// The requests not answered yet, in the order they were sent.
+    fn pending(&self) -> Vec<Frame> {
+        self.pending.lock().unwrap().values().cloned().collect()
+    }
+}
+
+#[derive(Default)]
+struct Threads {
+    accept: Option<JoinHandle<()>>,
+    requests: Vec<JoinHandle<()>>,
+    responses: Vec<JoinHandle<()>>,
+}
+
+/// A connection handed over to a new shim: the connection of containerd, and its requests not
+/// answered yet.
+pub(super) type HandedConnection = (UnixStream, Vec<Frame>);
+
+/// Relay of the connections of containerd on `listener` to the ttrpc server listening on `server`.
+pub(super) struct Relay {
+    listener: UnixListener,
+    server: PathBuf,
+    connections: Mutex<Vec<Arc<Connection>>>,
+    requests_paused: AtomicBool,
+    responses_paused: AtomicBool,
+    threads: Mutex<Threads>,
+}
+
+impl Relay {
+    /// Start relaying the connections accepted on `listener`, and the connections `inherited`
+    /// from a previous shim, whose unanswered requests are replayed first.
+    #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+    pub(super) fn start(
+        listener: UnixListener,
+        server: impl AsRef<Path>,
+        inherited: Vec<HandedConnection>,
+    ) -> io::Result<Arc<Self>> {
+        listener.set_nonblocking(true)?;
+        let server = server.as_ref().to_path_buf();
+        let mut connections = vec![];
+        for (client, pending) in inherited {
+            let connection = Connection::new(client, &server)?;
+            for frame in pending {
+                connection.request(frame)?;
+            }
+            connections.push(Arc::new(connection));
+        }
+        let relay = Arc::new(Self {
+            listener,
+            server,
+            connections: Mutex::new(connections),
+            requests_paused: AtomicBool::new(false),
+            responses_paused: AtomicBool::new(false),
+            threads: Mutex::default(),
+        });
+        relay.resume()?;
+        Ok(relay)
+    }
+
+    /// Relay the connections again after `pause`.
+    #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+    pub(super) fn resume(self: &Arc<Self>) -> io::Result<()> {
+        self.requests_paused.store(false, Ordering::Release);
+        self.responses_paused.store(false, Ordering::Release);
+
+        let mut connections = self.connections.lock().unwrap();
+        connections.retain(|connection| !connection.is_closed());
+        for connection in connections.iter() {
+            self.spawn_pumps(connection.clone())?;
+        }
+
+        let relay = self.clone();
+        let accept = thread::Builder::new()
+            .name("relay-accept".into())
+            .spawn(move || relay.accept())?;
+        self.threads.lock().unwrap().accept = Some(accept);
+        Ok(())
+    }
+
+    fn accept(self: Arc<Self>) {
+        while !self.requests_paused.load(Ordering::Acquire) {
+            match self.listener.accept() {
+                Ok((client, _)) => {
+                    let connection = match Connection::new(client, &self.server) {
+                        Ok(connection) => Arc::new(connection),
+                        Err(err) => {
+                            tracing::error!("failed to relay a connection of containerd: {err}");
+                            continue;
+                        }
+                    };
+                    if let Err(err) = self.spawn_pumps(connection.clone()) {
+                        tracing::error!("failed to relay a connection of containerd: {err}");
+                        continue;
+                    }
+                    self.connections.lock().unwrap().push(connection);
+                }
+                Err(err) if is_timeout(&err) => thread::sleep(POLL_INTERVAL),
+                Err(err) => {
+                    tracing::error!("failed to accept a connection of containerd: {err}");
+                    thread::sleep(POLL_INTERVAL);
+                }
+            }
+        }
+    }
+
+    fn spawn_pumps(self: &Arc<Self>, connection: Arc<Connection>) -> io::Result<()> {
+        let (relay, requests) = (self.clone(), connection.clone());
+        let requests = thread::Builder::new()
+            .name("relay-requests".into())
+            .spawn(move || {
+                if let Err(err) = requests.relay_requests(&relay.requests_paused) {
+                    tracing::warn!("relay of the requests of containerd failed: {err}");
+                    requests.close();
+                }
+            })?;
+        let (relay, responses) = (self.clone(), connection);
+        let responses = thread::Builder::new()
+            .name("relay-responses".into())
+            .spawn(move || {
+                if let Err(err) = responses.relay_responses(&relay.responses_paused) {
+                    tracing::warn!("relay of the responses to containerd failed: {err}");
+                    responses.close();
+                }
+            })?;
+        let mut threads = self.threads.lock().unwrap();
+        threads.requests.push(requests);
+        threads.responses.push(responses);
+        Ok(())
+    }
+
+    /// Stop relaying the requests of containerd, then give the requests in flight up to `timeout`
+    /// to be answered. Fails, relaying again, if requests other than `Wait` are still in flight.
+    #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+    pub(super) fn pause(self: &Arc<Self>, timeout: Duration) -> io::Result<()> {
+        self.requests_paused.store(true, Ordering::Release);
+        // join the accept thread first, it may add connections
+        let accept = self.threads.lock().unwrap().accept.take();
+        if let Some(accept) = accept {
+            let _ = accept.join();
+        }
+        let requests = mem::take(&mut self.threads.lock().unwrap().requests);
+        for thread in requests {
+            let _ = thread.join();
+        }
+
+        let deadline = Instant::now() + timeout;
+        while !self.is_idle() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        self.responses_paused.store(true, Ordering::Release);
+        let responses = mem::take(&mut self.threads.lock().unwrap().responses);
+        for thread in responses {
+            let _ = thread.join();
+        }
+
+        if !self.is_idle() {
+            self.resume()?;
+            return Err(io::Error::new(
+                ErrorKind::TimedOut,
+                "requests of containerd are still in flight",
+            ));
+        }
+        Ok(())
+    }
+
+    fn is_idle(&self) -> bool {
+        let connections = self.connections.lock().unwrap();
+        connections
+            .iter()
+            .all(|connection| connection.is_closed() || connection.is_idle())
+    }
+
+    /// The listener and the open connections of the paused relay, to hand them over.
+    #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+    pub(super) fn handed_over(&self) -> io::Result<(UnixListener, Vec<HandedConnection>)> {
+        let listener = self.listener.try_clone()?;
+        let connections = self.connections.lock().unwrap();
+        let connections = connections
+            .iter()
+            .filter(|connection| !connection.is_closed())
+            .map(|connection| Ok((connection.client.try_clone()?, connection.pending())))
+            .collect::<io::Result<_>>()?;
+        Ok((listener, connections))
+    }
+
+    /// Drop the connections of the paused relay, once handed over.
+    /// Only the connections to the server are shut down, the new shim serves the clients.
+    #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+    pub(super) fn release(&self) {
+        for connection in self.connections.lock().unwrap().drain(..) {
+            let _ = connection.server.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn frame(stream_id: u32, message_type: u8, body: &[u8]) -> Frame {
+        let mut frame = (body.len() as u32).to_be_bytes().to_vec();
+        frame.extend(stream_id.to_be_bytes());
+        frame.extend([message_type, 0]);
+        frame.extend(body);
+        Frame(frame)
+    }
+
+    /// A ttrpc `Request` message of the task service.
+    fn request(method: &str) -> Vec<u8> {
+        let service = b"containerd.task.v2.Task";
+        let mut body = vec![0x0a, service.len() as u8];
+        body.extend(service);
+        body.extend([0x12, method.len() as u8]);
+        body.extend(method.as_bytes());
+        body.extend([0x1a, 2, 0x0a, 0]);
+        body
+    }
+
+    /// A reader returning at most one byte per read, and timing out every other read.
+    struct Trickle(Cursor<Vec<u8>>, bool);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.1 = !self.1;
+            if self.1 {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            let len = buf.len().min(1);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn test_next_frame() -> io::Result<()> {
+        let (first, second) = (frame(1, 1, b"hello"), frame(3, 1, b""));
+        let bytes = [first.0.clone(), second.0.clone()].concat();
+        let mut stream = Trickle(Cursor::new(bytes), false);
+
+        let paused = AtomicBool::new(false);
+        assert_eq!(next_frame(&mut stream, &paused)?, Next::Frame(first));
+        paused.store(true, Ordering::Release);
+        assert_eq!(next_frame(&mut stream, &paused)?, Next::Paused);
+        paused.store(false, Ordering::Release);
+        assert_eq!(next_frame(&mut stream, &paused)?, Next::Frame(second));
+        assert_eq!(next_frame(&mut stream, &paused)?, Next::Closed);
+
+        let truncated = frame(1, 1, b"hello").0[..12].to_vec();
+        let err = next_frame(Cursor::new(truncated), &paused).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+
+        let mut too_large = frame(1, 1, b"").0;
+        too_large[..4].copy_from_slice(&(MAX_FRAME_LEN as u32 + 1).to_be_bytes());
+        let err = next_frame(Cursor::new(too_large), &paused).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        Ok(())
+    }
+
+    #[test]
+    fn test_request_method() {
+        let wait = frame(1, MESSAGE_TYPE_REQUEST, &request("Wait"));
+        assert_eq!(wait.method().as_deref(), Some("Wait"));
+        assert_eq!(wait.stream_id(), 1);
+        assert_eq!(wait.message_type(), MESSAGE_TYPE_REQUEST);
+
+        assert_eq!(request_method(&[]), Ok(None));
+        assert!(request_method(&[0x12, 10, b'W']).is_err());
+    }
+
+    #[test]
+    fn test_relay_hand_over() -> io::Result<()> {
+        let dir = tempdir()?;
+        let (public, internal) = (dir.path().join("public"), dir.path().join("internal"));
+        let server = UnixListener::bind(&internal)?;
+        let relay = Relay::start(UnixListener::bind(&public)?, &internal, vec![])?;
+
+        // a request answered by the server is forwarded both ways, and no longer pending
+        let mut client = UnixStream::connect(&public)?;
+        let (mut upstream, _) = server.accept()?;
+        let start = frame(1, MESSAGE_TYPE_REQUEST, &request("Start"));
+        client.write_all(&start.0)?;
+        let paused = AtomicBool::new(false);
+        assert_eq!(next_frame(&mut upstream, &paused)?, Next::Frame(start));
+        let response = frame(1, MESSAGE_TYPE_RESPONSE, b"ok");
+        upstream.write_all(&response.0)?;
+        assert_eq!(next_frame(&mut client, &paused)?, Next::Frame(response));
+
+        // a pending `Start` blocks the pause, a pending `Wait` doesn't
+        let start = frame(3, MESSAGE_TYPE_REQUEST, &request("Start"));
+        client.write_all(&start.0)?;
+        assert_eq!(next_frame(&mut upstream, &paused)?, Next::Frame(start));
+        let err = relay.pause(Duration::from_millis(100)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        upstream.write_all(&frame(3, MESSAGE_TYPE_RESPONSE, b"ok").0)?;
+        next_frame(&mut client, &paused)?;
+
+        let wait = frame(5, MESSAGE_TYPE_REQUEST, &request("Wait"));
+        client.write_all(&wait.0)?;
+        assert_eq!(
+            next_frame(&mut upstream, &paused)?,
+            Next::Frame(wait.clone())
+        );
+        relay.pause(Duration::from_secs(1))?;
+
+        let (listener, connections) = relay.handed_over()?;
+        assert_eq!(connections.len(), 1);
+        assert_eq!(connections[0].1, vec![wait.clone()]);
+        relay.release();
+
+        // a new relay replays the pending `Wait` to its server
+        let new_internal = dir.path().join("new-internal");
+        let new_server = UnixListener::bind(&new_internal)?;
+        let _relay = Relay::start(listener, &new_internal, connections)?;
+        let (mut upstream, _) = new_server.accept()?;
+        assert_eq!(next_frame(&mut upstream, &paused)?, Next::Frame(wait));
+        let response = frame(5, MESSAGE_TYPE_RESPONSE, b"exited");
+        upstream.write_all(&response.0)?;
+        assert_eq!(next_frame(&mut client, &paused)?, Next::Frame(response));
+        Ok(())
+    }
+}
@@ -0,0 +1,76 @@
+//! Validation of a bundle without running it, for the `--validate` mode of the shims.
+//!
+//! The misconfigurations of a container otherwise surface when containerd creates or starts it,
+//! often as an error of the engine far from its cause. `containerd-shim-<runtime>-v1 --validate
+//! [bundle]` checks the `config.json` of the bundle (default: the current directory) and prints a
+//! machine-readable report of the findings, e.g.:
+//!
+//! ```json
+//! {
+//!   "bundle": "/run/containerd/io.containerd.runtime.v2.task/default/hello",
+//!   "valid": false,
+//!   "findings": [
+//!     {
+//!       "severity": "error",
+//!       "field": "process.args[0]",
+//!       "message": "/hello.wasm is neither a wasm module nor a wat file: expected `(`"
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! Errors fail the creation or the start of the container. Warnings point at settings that are
+//! ignored, or at checks that can't be made from the bundle alone, e.g. the wasm layers of the
+//! image are only known to containerd. The shim exits with `1` when the bundle has errors.
+
+use std::fmt::Display;
+use std::path::Path;
+
+use serde::Serialize;
+use serde_json::{json, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// The container can't run.
+    Error,
+    /// The container runs, but likely not as intended.
+    Warning,
+}
+
+/// A finding of the validation of a bundle.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Finding {
+    pub severity: Severity,
+    /// The field of the spec the finding is about, e.g. `process.args[0]`.
+    pub field: String,
+    pub message: String,
+}
+
+impl Finding {
+    pub fn error(field: impl Into<String>, message: impl Display) -> Self {
+        Self {
+            severity: Severity::Error,
+            field: field.into(),
+            message: message.to_string(),
+        }
+    }
+
+    pub fn warning(field: impl Into<String>, message: impl Display) -> Self {
+        Self {
+            severity: Severity::Warning,
+            field: field.into(),
+            message: message.to_string(),
+        }
+    }
+}
+
+/// The report printed by `--validate`.
+pub fn report(bundle: &Path, findings: &[Finding]) -> Value {
+    let valid = findings.iter().all(|f| f.severity != Severity::Error);
+    json!({
+        "bundle": bundle,
+        "valid": valid,
+        "findings": findings,
+    })
+}
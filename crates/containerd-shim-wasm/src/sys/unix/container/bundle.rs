@@ -0,0 +1,112 @@
+//! Bundle of the containers whose spec is changed by the shim.
+//!
+//! libcontainer reads the spec of a container from the `config.json` of its bundle. When the shim
+//! changes the spec, e.g. to mount the scratch directory or the rootfs overlay, the changed spec is
+//! saved in a bundle of its own, in the root directory of the shim, rather than in the bundle of
+//! the caller, which is left untouched. The bundle is removed with the container.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use oci_spec::runtime::{RootBuilder, Spec};
+
+use crate::sandbox::Error;
+
+/// A bundle of the shim, removed when dropped.
+pub(crate) struct PrivateBundle {
+    dir: PathBuf,
+}
+
+impl PrivateBundle {
+    /// Save `spec`, read from `bundle` and changed by the shim, as the spec of the container `id`.
+    #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+    pub(crate) fn save(
+        spec: &Spec,
+        bundle: &Path,
+        rootdir: &Path,
+        id: &str,
+    ) -> Result<Self, Error> {
+        let dir = bundle_dir(rootdir, id);
+        fs::create_dir_all(&dir)?;
+        let private = Self { dir };
+
+        // The root of the container is relative to the bundle of the caller
+        let mut spec = spec.clone();
+        if let Some(root) = spec
+            .root()
+            .as_ref()
+            .filter(|root| root.path().is_relative())
+        {
+            let root = RootBuilder::default()
+                .path(bundle.join(root.path()))
+                .readonly(root.readonly().unwrap_or(false))
+                .build()?;
+            spec.set_root(Some(root));
+        }
+        spec.save(private.dir.join("config.json"))?;
+        Ok(private)
+    }
+
+    /// The bundle saved for the container `id` by a previous shim, which handed the container
+    /// over to this one.
+    #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+    pub(crate) fn adopt(rootdir: &Path, id: &str) -> Option<Self> {
+        let dir = bundle_dir(rootdir, id);
+        dir.join("config.json").exists().then_some(Self { dir })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.dir
+    }
+}
+
+impl Drop for PrivateBundle {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_dir_all(&self.dir) {
+            tracing::warn!("failed to remove {}: {err}", self.dir.display());
+        }
+    }
+}
+
+fn bundle_dir(rootdir: &Path, id: &str) -> PathBuf {
+    rootdir.join("bundles").join(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use oci_spec::runtime::SpecBuilder;
+
+    use super::*;
+
+    #[test]
+    fn test_save() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (bundle, rootdir) = (dir.path().join("bundle"), dir.path().join("root"));
+        fs::create_dir_all(&bundle)?;
+        let spec = SpecBuilder::default()
+            .root(RootBuilder::default().path("rootfs").build()?)
+            .build()?;
+        spec.save(bundle.join("config.json"))?;
+
+        let private = PrivateBundle::save(&spec, &bundle, &rootdir, "test")?;
+        let saved = Spec::load(private.path().join("config.json"))?;
+        assert_eq!(
+            saved.root().as_ref().unwrap().path(),
+            &bundle.join("rootfs")
+        );
+
+        // The bundle of the caller is left untouched
+        assert_eq!(Spec::load(bundle.join("config.json"))?, spec);
+
+        // The bundle is handed over to the next shim, and removed with the container
+        let path = private.path().to_path_buf();
+        std::mem::forget(private);
+        assert!(PrivateBundle::adopt(&rootdir, "other").is_none());
+        let adopted = PrivateBundle::adopt(&rootdir, "test").unwrap();
+        assert_eq!(adopted.path(), path);
+        drop(adopted);
+        assert!(!path.exists());
+
+        Ok(())
+    }
+}
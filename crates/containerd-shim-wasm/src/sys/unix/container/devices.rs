@@ -0,0 +1,221 @@
+//! The devices passed to a container, e.g. GPUs used by the wasi-nn backends.
+//!
+//! The devices of `linux.devices` are created in the container by libcontainer, where the engine
+//! and its wasi-nn backends open them like any other process. Like runc, the devices are also
+//! allowed in the devices cgroup of the container, so that they can be opened. A device missing
+//! from the node would only surface when the backend fails to find it, so the devices are checked
+//! when the container is created instead.
+//!
+//! [CDI](https://github.com/cncf-tags/container-device-interface) devices requested with
+//! annotations are resolved into `linux.devices` by containerd, when CDI is enabled.
+
+use std::path::Path;
+
+use oci_spec::runtime::{LinuxDeviceCgroupBuilder, LinuxDeviceType, Spec};
+
+use crate::sandbox::Error;
+
+const CDI_ANNOTATION_PREFIX: &str = "cdi.k8s.io/";
+
+/// Check that the devices of the container are present on the node.
+#[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+pub(crate) fn check_devices(spec: &Spec) -> Result<(), Error> {
+    check_devices_in(spec, Path::new("/sys/dev"))
+}
+
+fn check_devices_in(spec: &Spec, sys_dev: &Path) -> Result<(), Error> {
+    let devices = spec
+        .linux()
+        .as_ref()
+        .and_then(|linux| linux.devices().as_deref())
+        .unwrap_or_default();
+
+    for device in devices {
+        let class = match device.typ() {
+            LinuxDeviceType::C | LinuxDeviceType::U => "char",
+            LinuxDeviceType::B => "block",
+            // FIFOs don't need a device on the node
+            _ => continue,
+        };
+        let (major, minor) = (device.major(), device.minor());
+        if !sys_dev
+            .join(class)
+            .join(format!("{major}:{minor}"))
+            .exists()
+        {
+            return Err(Error::FailedPrecondition(format!(
+                "device {} ({class} {major}:{minor}) is not present on the node",
+                device.path().display()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// A warning if `spec` requests CDI devices but has no device.
+///
+/// CDI devices may only inject mounts or environment variables, so this is not an error, but
+/// usually means that CDI is not enabled in containerd.
+pub(crate) fn uninjected_cdi_devices(spec: &Spec) -> Option<String> {
+    let has_devices = spec
+        .linux()
+        .as_ref()
+        .and_then(|linux| linux.devices().as_ref())
+        .is_some_and(|devices| !devices.is_empty());
+    if has_devices {
+        return None;
+    }
+    let (key, names) = spec
+        .annotations()
+        .iter()
+        .flatten()
+        .find(|(key, _)| key.starts_with(CDI_ANNOTATION_PREFIX))?;
+    Some(format!(
+        "CDI devices {names} requested by annotation {key} injected no device in the \
+         container, is CDI enabled in containerd (enable_cdi = true)?"
+    ))
+}
+
+/// Allow the devices of `spec` in its devices cgroup, unless they already are.
+/// Returns whether the spec was changed.
+#[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+pub(crate) fn allow_devices(spec: &mut Spec) -> Result<bool, Error> {
+    let Some(linux) = spec.linux_mut() else {
+        return Ok(false);
+    };
+    let devices = linux.devices().clone().unwrap_or_default();
+    let resources = linux.resources_mut().get_or_insert_with(Default::default);
+    let mut rules = resources.devices().clone().unwrap_or_default();
+
+    let mut changed = false;
+    for device in devices {
+        let typ = match device.typ() {
+            LinuxDeviceType::C | LinuxDeviceType::U => LinuxDeviceType::C,
+            LinuxDeviceType::B => LinuxDeviceType::B,
+            _ => continue,
+        };
+        let (major, minor) = (device.major(), device.minor());
+        if rules.iter().any(|rule| {
+            rule.allow()
+                && rule.typ() == Some(typ)
+                && rule.major() == Some(major)
+                && rule.minor() == Some(minor)
+        }) {
+            continue;
+        }
+        rules.push(
+            LinuxDeviceCgroupBuilder::default()
+                .allow(true)
+                .typ(typ)
+                .major(major)
+                .minor(minor)
+                .access("rwm")
+                .build()?,
+        );
+        changed = true;
+    }
+
+    if changed {
+        resources.set_devices(Some(rules));
+    }
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use oci_spec::runtime::{LinuxBuilder, LinuxDeviceBuilder, SpecBuilder};
+
+    use super::*;
+
+    fn spec_with_device(typ: LinuxDeviceType, major: i64, minor: i64) -> anyhow::Result<Spec> {
+        let device = LinuxDeviceBuilder::default()
+            .path("/dev/dri/renderD128")
+            .typ(typ)
+            .major(major)
+            .minor(minor)
+            .build()?;
+        Ok(SpecBuilder::default()
+            .linux(LinuxBuilder::default().devices(vec![device]).build()?)
+            .build()?)
+    }
+
+    #[test]
+    fn test_check_devices() -> anyhow::Result<()> {
+        let sys_dev = tempfile::tempdir()?;
+        std::fs::create_dir_all(sys_dev.path().join("char/226:128"))?;
+
+        let spec = spec_with_device(LinuxDeviceType::C, 226, 128)?;
+        check_devices_in(&spec, sys_dev.path())?;
+
+        let spec = spec_with_device(LinuxDeviceType::C, 226, 129)?;
+        let err = check_devices_in(&spec, sys_dev.path()).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("/dev/dri/renderD128 (char 226:129)"));
+
+        let spec = spec_with_device(LinuxDeviceType::B, 226, 128)?;
+        assert!(check_devices_in(&spec, sys_dev.path()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_cdi_devices() -> anyhow::Result<()> {
+        let sys_dev = tempfile::tempdir()?;
+        let spec = SpecBuilder::default()
+            .annotations(HashMap::from([(
+                "cdi.k8s.io/gpu".to_string(),
+                "vendor.com/gpu=0".to_string(),
+            )]))
+            .linux(LinuxBuilder::default().devices(vec![]).build()?)
+            .build()?;
+
+        check_devices_in(&spec, sys_dev.path())?;
+        let warning = uninjected_cdi_devices(&spec).unwrap();
+        assert!(warning.contains("vendor.com/gpu=0"));
+
+        let spec = SpecBuilder::default()
+            .linux(LinuxBuilder::default().devices(vec![]).build()?)
+            .build()?;
+        check_devices_in(&spec, sys_dev.path())?;
+        assert_eq!(uninjected_cdi_devices(&spec), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_allow_devices() -> anyhow::Result<()> {
+        let rules = |spec: &Spec| {
+            spec.linux()
+                .as_ref()
+                .and_then(|linux| linux.resources().as_ref())
+                .and_then(|resources| resources.devices().clone())
+                .unwrap_or_default()
+        };
+
+        // The default rules deny all devices
+        let mut spec = spec_with_device(LinuxDeviceType::C, 226, 128)?;
+        let denied = rules(&spec).len();
+        assert!(allow_devices(&mut spec)?);
+
+        let allowed = rules(&spec);
+        assert_eq!(allowed.len(), denied + 1);
+        let rule = allowed.last().unwrap();
+        assert!(rule.allow());
+        assert_eq!(rule.typ(), Some(LinuxDeviceType::C));
+        assert_eq!((rule.major(), rule.minor()), (Some(226), Some(128)));
+        assert_eq!(rule.access().as_deref(), Some("rwm"));
+
+        // already allowed
+        assert!(!allow_devices(&mut spec)?);
+        assert_eq!(rules(&spec).len(), denied + 1);
+
+        let mut spec = SpecBuilder::default().build()?;
+        spec.set_linux(None);
+        assert!(!allow_devices(&mut spec)?);
+
+        Ok(())
+    }
+}
@@ -4,6 +4,7 @@ use std::fs::File;
 use std::io::Read;
 use std::os::unix::prelude::PermissionsExt;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use anyhow::{bail, Context, Result};
 use libcontainer::workload::default::DefaultExecutor;
@@ -13,14 +14,22 @@ use libcontainer::workload::{
 };
 use oci_spec::image::Platform;
 use oci_spec::runtime::Spec;
+use serde_json::Value;
 
-use crate::container::{Engine, PathResolve, RuntimeContext, Source, Stdio, WasiContext};
+use crate::container::priority::Priority;
+use crate::container::{
+    set_reporter, Engine, PathResolve, RuntimeContext, Source, Stdio, WasiContext,
+};
+use crate::sandbox::containerd::ImageMetadata;
 use crate::sandbox::oci::WasmLayer;
+use crate::sys::container::pause::{is_pause_container, pause};
+use crate::sys::container::phase::PhaseLog;
 
 #[derive(Clone)]
 enum InnerExecutor {
     Wasm,
     Linux,
+    Pause,
     CantHandle,
 }
 
@@ -29,8 +38,11 @@ pub(crate) struct Executor<E: Engine> {
     engine: E,
     stdio: Stdio,
     inner: OnceCell<InnerExecutor>,
-    wasm_layers: Vec<WasmLayer>,
+    wasm_layers: Arc<[WasmLayer]>,
     platform: Platform,
+    image: ImageMetadata,
+    options: Option<Value>,
+    phases: Arc<PhaseLog>,
 }
 
 impl<E: Engine> LibcontainerExecutor for Executor<E> {
@@ -39,7 +51,11 @@ impl<E: Engine> LibcontainerExecutor for Executor<E> {
         // We can handle linux container. We delegate wasm container to the engine.
         match self.inner(spec) {
             InnerExecutor::CantHandle => Err(ExecutorValidationError::CantHandle(E::name())),
-            _ => Ok(()),
+            InnerExecutor::Wasm => self.engine.validate(&self.ctx(spec)).map_err(|err| {
+                tracing::error!("invalid wasm container configuration: {err:#}");
+                ExecutorValidationError::ArgValidationError(format!("{err:#}"))
+            }),
+            InnerExecutor::Linux | InnerExecutor::Pause => Ok(()),
         }
     }
 
@@ -50,16 +66,33 @@ impl<E: Engine> LibcontainerExecutor for Executor<E> {
         match self.inner(spec) {
             InnerExecutor::CantHandle => Err(LibcontainerExecutorError::CantHandle(E::name())),
             InnerExecutor::Linux => {
-                log::info!("executing linux container");
+                tracing::info!("executing linux container");
                 self.stdio.take().redirect().unwrap();
                 DefaultExecutor {}.exec(spec)
             }
+            InnerExecutor::Pause => {
+                tracing::info!("executing built-in pause container");
+                pause()
+            }
             InnerExecutor::Wasm => {
-                log::info!("calling start function");
-                match self.engine.run_wasi(&self.ctx(spec), self.stdio.take()) {
+                tracing::info!("calling start function");
+                let phases = self.phases.clone();
+                set_reporter(move |phase| phases.push(phase));
+                let ctx = self.ctx(spec);
+                if let Err(err) = Priority::from_ctx(&ctx).and_then(Priority::renice) {
+                    tracing::warn!("failed to set the priority of the container: {err:#}");
+                }
+                let status = tokio::runtime::Builder::new_multi_thread()
+                    .enable_all()
+                    .build()
+                    .map_err(anyhow::Error::from)
+                    .and_then(|runtime| {
+                        runtime.block_on(self.engine.run_wasi_async(&ctx, self.stdio.take()))
+                    });
+                match status {
                     Ok(code) => std::process::exit(code),
                     Err(err) => {
-                        log::info!("error running start function: {err}");
+                        tracing::error!("error running start function: {err:#}");
                         std::process::exit(137)
                     }
                 };
@@ -69,8 +102,8 @@ impl<E: Engine> LibcontainerExecutor for Executor<E> {
 
     // This is an no-op for the Wasm `Executor`. Instead of youki's libcontainer setting the envs
     // in the shim process, the shim will manage the envs itself. The expectation is that the shim will
-    // call `RuntimeContext::envs()` to get the container's envs and set them in the `Engine::run_wasi`
-    // function. This way, the shim can decide how to pass the envs to the WASI context.
+    // call `RuntimeContext::envs()` to get the container's envs and set them in the
+    // `Engine::run_wasi_async` function. This way, the shim can decide how to pass the envs to the WASI context.
     //
     // See the following issues for more context:
     // https://github.com/containerd/runwasi/issues/619
@@ -84,13 +117,24 @@ impl<E: Engine> LibcontainerExecutor for Executor<E> {
 }
 
 impl<E: Engine> Executor<E> {
-    pub fn new(engine: E, stdio: Stdio, wasm_layers: Vec<WasmLayer>, platform: Platform) -> Self {
+    pub fn new(
+        engine: E,
+        stdio: Stdio,
+        wasm_layers: Arc<[WasmLayer]>,
+        platform: Platform,
+        image: ImageMetadata,
+        options: Option<Value>,
+        phases: Arc<PhaseLog>,
+    ) -> Self {
         Self {
             engine,
             stdio,
             inner: Default::default(),
             wasm_layers,
             platform,
+            image,
+            options,
+            phases,
         }
     }
 
@@ -101,21 +145,27 @@ impl<E: Engine> Executor<E> {
             spec,
             wasm_layers,
             platform,
+            labels: &self.image.labels,
+            exposed_ports: &self.image.exposed_ports,
+            options: self.options.as_ref(),
         }
     }
 
     fn inner(&self, spec: &Spec) -> &InnerExecutor {
         self.inner.get_or_init(|| {
+            if is_pause_container(spec.annotations().as_ref()) {
+                return InnerExecutor::Pause;
+            }
             let ctx = &self.ctx(spec);
             match is_linux_container(ctx) {
                 Ok(_) => InnerExecutor::Linux,
                 Err(err) => {
-                    log::debug!("error checking if linux container: {err}. Fallback to wasm container");
+                    tracing::debug!("error checking if linux container: {err}. Fallback to wasm container");
                     match self.engine.can_handle(ctx) {
                         Ok(_) => InnerExecutor::Wasm,
                         Err(err) => {
                             // log an error and return
-                            log::error!("error checking if wasm container: {err}. Note: arg0 must be a path to a Wasm file");
+                            tracing::error!("error checking if wasm container: {err}. Note: arg0 must be a path to a Wasm file");
                             InnerExecutor::CantHandle
                         }
                     }
@@ -1,6 +1,5 @@
-use std::marker::PhantomData;
 use std::path::Path;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 
@@ -14,23 +13,66 @@ use nix::errno::Errno;
 use nix::sys::wait::{waitid, Id as WaitID, WaitPidFlag, WaitStatus};
 use nix::unistd::Pid;
 use oci_spec::image::Platform;
+use oci_spec::runtime::Spec;
+use serde_json::Value;
 
-use crate::container::Engine;
+use crate::container::{engine_info, Engine, Phase, WasiContext};
 use crate::sandbox::async_utils::AmbientRuntime as _;
-use crate::sandbox::instance_utils::determine_rootdir;
+use crate::sandbox::containerd::ImageMetadata;
+use crate::sandbox::instance::PhaseListener;
+use crate::sandbox::instance_utils::{determine_rootdir, merge_engine_options};
+use crate::sandbox::oci::WasmLayer;
 use crate::sandbox::sync::WaitableCell;
+use crate::sandbox::validate::Finding;
 use crate::sandbox::{
     containerd, Error as SandboxError, Instance as SandboxInstance, InstanceConfig, Stdio,
 };
+use crate::sys::container::bundle::PrivateBundle;
+use crate::sys::container::devices::{allow_devices, check_devices, uninjected_cdi_devices};
 use crate::sys::container::executor::Executor;
+use crate::sys::container::overlay::RootfsOverlay;
+use crate::sys::container::pause::is_pause_container;
+use crate::sys::container::phase::PhaseLog;
+use crate::sys::container::scratch::add_scratch_mount;
+use crate::sys::container::validate::validate_bundle;
 
 static DEFAULT_CONTAINER_ROOT_DIR: &str = "/run/containerd";
 
+/// How often the phases reported by the container are polled.
+const PHASE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
 pub struct Instance<E: Engine> {
     exit_code: WaitableCell<(u32, DateTime<Utc>)>,
     container: Mutex<Container>,
     id: String,
-    _phantom: PhantomData<E>,
+    engine: E,
+    hooks: Arc<HookContext>,
+    phases: Arc<PhaseLog>,
+    phase_listener: Option<PhaseListener>,
+    _overlay: Option<RootfsOverlay>,
+    _bundle: Option<PrivateBundle>,
+}
+
+/// The container as seen by the lifecycle hooks of the engine.
+struct HookContext {
+    spec: Spec,
+    wasm_layers: Arc<[WasmLayer]>,
+    platform: Platform,
+    image: ImageMetadata,
+    options: Option<Value>,
+}
+
+impl HookContext {
+    fn ctx(&self) -> WasiContext<'_> {
+        WasiContext {
+            spec: &self.spec,
+            wasm_layers: &self.wasm_layers,
+            platform: &self.platform,
+            labels: &self.image.labels,
+            exposed_ports: &self.image.exposed_ports,
+            options: self.options.as_ref(),
+        }
+    }
 }
 
 impl<E: Engine> SandboxInstance for Instance<E> {
@@ -45,28 +87,156 @@ impl<E: Engine> SandboxInstance for Instance<E> {
         let rootdir = Path::new(DEFAULT_CONTAINER_ROOT_DIR).join(E::name());
         let rootdir = determine_rootdir(&bundle, &namespace, rootdir)?;
         let stdio = Stdio::init_from_cfg(cfg)?;
+        let phase_listener = cfg.get_phase_listener();
+        let report = |phase| {
+            if let Some(listener) = &phase_listener {
+                listener(phase, Utc::now());
+            }
+        };
+
+        let mut spec = Spec::load(bundle.join("config.json"))?;
 
         // check if container is OCI image with wasm layers and attempt to read the module
-        let (modules, platform) = containerd::Client::connect(cfg.get_containerd_address().as_str(), &namespace).block_on()?
-            .load_modules(&id, &engine)
-            .block_on()
-            .unwrap_or_else(|e| {
-                log::warn!("Error obtaining wasm layers for container {id}.  Will attempt to use files inside container image. Error: {e}");
-                (vec![], Platform::default())
-            });
+        let mut overlay = None;
+        let mut scratch = false;
+        let mut devices = false;
+        let (modules, platform, image) = if is_pause_container(spec.annotations().as_ref()) {
+            tracing::info!("running {id} as a built-in pause container");
+            (vec![], Platform::default(), Default::default())
+        } else {
+            check_devices(&spec)?;
+            if let Some(warning) = uninjected_cdi_devices(&spec) {
+                tracing::warn!("{warning}");
+            }
+            devices = allow_devices(&mut spec)?;
+            overlay = RootfsOverlay::mount(&mut spec, &bundle)?;
+            report(Phase::Compiling);
+            let (modules, platform, image) = containerd::Client::connect(cfg.get_containerd_address().as_str(), &namespace).block_on()?
+                .load_modules(&id, &engine)
+                .block_on()
+                .or_else(|e| match e {
+                    // A rejected image must not run from the files of its image either
+                    SandboxError::PermissionDenied(_) => Err(e),
+                    e => {
+                        tracing::warn!("Error obtaining wasm layers for container {id}.  Will attempt to use files inside container image. Error: {e}");
+                        Ok((vec![], Platform::default(), Default::default()))
+                    }
+                })?;
+            if !modules.is_empty() {
+                report(Phase::Compiled);
+            }
+            // The scratch directory would hide the /tmp of the Linux containers
+            scratch = !modules.is_empty() && add_scratch_mount(&mut spec)?;
+            (modules, platform, image)
+        };
+
+        // libcontainer reads the root, mounts and devices cgroup of the container from its bundle
+        let private_bundle = if scratch || devices || overlay.is_some() {
+            Some(PrivateBundle::save(&spec, &bundle, &rootdir, &id)?)
+        } else {
+            None
+        };
+        let phases = Arc::new(PhaseLog::new()?);
 
-        let container = ContainerBuilder::new(id.clone(), SyscallType::Linux)
-            .with_executor(Executor::new(engine, stdio, modules, platform))
+        let options = merge_engine_options(cfg.get_engine_options(), spec.annotations().as_ref())?;
+        let hooks = Arc::new(HookContext {
+            spec,
+            wasm_layers: modules.into(),
+            platform,
+            image,
+            options,
+        });
+
+        let executor = Executor::new(
+            engine.clone(),
+            stdio,
+            hooks.wasm_layers.clone(),
+            hooks.platform.clone(),
+            hooks.image.clone(),
+            hooks.options.clone(),
+            phases.clone(),
+        );
+        let mut container = ContainerBuilder::new(id.clone(), SyscallType::Linux)
+            .with_executor(executor)
             .with_root_path(rootdir.clone())?
-            .as_init(&bundle)
+            .as_init(
+                private_bundle
+                    .as_ref()
+                    .map_or(&*bundle, PrivateBundle::path),
+            )
             .with_systemd(false)
             .build()?;
 
+        if let Err(err) = engine.on_create(&hooks.ctx()) {
+            let _ = container.delete(true);
+            return Err(err.into());
+        }
+
         Ok(Self {
             id,
             exit_code: WaitableCell::new(),
             container: Mutex::new(container),
-            _phantom: Default::default(),
+            engine,
+            hooks,
+            phases,
+            phase_listener,
+            _overlay: overlay,
+            _bundle: private_bundle,
+        })
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+    fn adopt(
+        id: String,
+        cfg: &InstanceConfig<Self::Engine>,
+        exit: WaitableCell<(u32, DateTime<Utc>)>,
+    ) -> Result<Self, SandboxError> {
+        let engine = cfg.get_engine();
+        let bundle = cfg.get_bundle().to_path_buf();
+        let rootdir = Path::new(DEFAULT_CONTAINER_ROOT_DIR).join(E::name());
+        let rootdir = determine_rootdir(&bundle, &cfg.get_namespace(), rootdir)?;
+        let container = Container::load(rootdir.join(&id))?;
+        tracing::info!("adopting instance {id} from the previous shim");
+
+        // the modules were already loaded by the previous shim, in the container process
+        let private_bundle = PrivateBundle::adopt(&rootdir, &id);
+        let spec = match &private_bundle {
+            Some(private_bundle) => Spec::load(private_bundle.path().join("config.json"))?,
+            None => Spec::load(bundle.join("config.json"))?,
+        };
+        let overlay = RootfsOverlay::adopt(&spec, &bundle)?;
+        let options = merge_engine_options(cfg.get_engine_options(), spec.annotations().as_ref())?;
+        let hooks = Arc::new(HookContext {
+            spec,
+            wasm_layers: Arc::new([]),
+            platform: Platform::default(),
+            image: Default::default(),
+            options,
+        });
+
+        // the container is a child of the previous shim, which reports its exit
+        let exit_code = WaitableCell::new();
+        thread::spawn({
+            let (engine, hooks, exit_code) = (engine.clone(), hooks.clone(), exit_code.clone());
+            move || {
+                let (status, timestamp) = *exit.wait();
+                if let Err(err) = engine.on_exit(&hooks.ctx(), status) {
+                    tracing::error!("exit hook failed: {err:?}");
+                }
+                let _ = exit_code.set((status, timestamp));
+            }
+        });
+
+        Ok(Self {
+            id,
+            exit_code,
+            container: Mutex::new(container),
+            engine,
+            hooks,
+            phases: Arc::new(PhaseLog::new()?),
+            phase_listener: cfg.get_phase_listener(),
+            _overlay: overlay,
+            _bundle: private_bundle,
         })
     }
 
@@ -75,16 +245,34 @@ impl<E: Engine> SandboxInstance for Instance<E> {
     /// Nothing internally should be using this ID, but it is returned to containerd where a user may want to use it.
     #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
     fn start(&self) -> Result<u32, SandboxError> {
-        log::info!("starting instance: {}", self.id);
+        tracing::info!("starting instance: {}", self.id);
         // make sure we have an exit code by the time we finish (even if there's a panic)
         let guard = self.exit_code.set_guard_with(|| (137, Utc::now()));
 
         let mut container = self.container.lock().expect("Poisoned mutex");
         let pid = container.pid().context("failed to get pid")?.as_raw();
 
+        self.engine.on_start(&self.hooks.ctx())?;
         container.start()?;
 
+        if let Some(listener) = self.phase_listener.clone() {
+            let (phases, exit_code) = (self.phases.clone(), self.exit_code.clone());
+            thread::spawn(move || {
+                let mut cursor = 0;
+                loop {
+                    let exited = exit_code.wait_timeout(PHASE_POLL_INTERVAL).is_some();
+                    for (phase, timestamp) in phases.read(&mut cursor) {
+                        listener(phase, timestamp);
+                    }
+                    if exited {
+                        break;
+                    }
+                }
+            });
+        }
+
         let exit_code = self.exit_code.clone();
+        let (engine, hooks) = (self.engine.clone(), self.hooks.clone());
         thread::spawn(move || {
             // move the exit code guard into this thread
             let _guard = guard;
@@ -94,14 +282,17 @@ impl<E: Engine> SandboxInstance for Instance<E> {
                 Ok(WaitStatus::Signaled(_, sig, _)) => sig as i32,
                 Ok(_) => 0,
                 Err(Errno::ECHILD) => {
-                    log::info!("no child process");
+                    tracing::info!("no child process");
                     0
                 }
                 Err(e) => {
-                    log::error!("waitpid failed: {e}");
+                    tracing::error!("waitpid failed: {e}");
                     137
                 }
             } as u32;
+            if let Err(err) = engine.on_exit(&hooks.ctx(), status) {
+                tracing::error!("exit hook failed: {err:?}");
+            }
             let _ = exit_code.set((status, Utc::now()));
         });
 
@@ -111,7 +302,7 @@ impl<E: Engine> SandboxInstance for Instance<E> {
     /// Send a signal to the instance
     #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
     fn kill(&self, signal: u32) -> Result<(), SandboxError> {
-        log::info!("sending signal {signal} to instance: {}", self.id);
+        tracing::info!("sending signal {signal} to instance: {}", self.id);
         let signal = Signal::try_from(signal as i32).map_err(|err| {
             SandboxError::InvalidArgument(format!("invalid signal number: {}", err))
         })?;
@@ -124,15 +315,41 @@ impl<E: Engine> SandboxInstance for Instance<E> {
         Ok(())
     }
 
+    /// Ask the container process to dump the linear memories of its guest
+    #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+    fn dump_memory(&self) -> Result<(), SandboxError> {
+        // The default action of the signals is to terminate the process, only send it when the
+        // engine handles it
+        let Some(signal) = self.engine.memory_dump_signal(&self.hooks.ctx()) else {
+            return Err(SandboxError::FailedPrecondition(
+                "memory dumps are not enabled for this container".to_string(),
+            ));
+        };
+        tracing::info!("requesting a memory dump of instance: {}", self.id);
+        let signal = Signal::try_from(signal).map_err(|err| {
+            SandboxError::InvalidArgument(format!("invalid signal number: {}", err))
+        })?;
+
+        self.container
+            .lock()
+            .expect("Poisoned mutex")
+            .kill(signal, false)?;
+
+        Ok(())
+    }
+
     /// Delete any reference to the instance
     /// This is called after the instance has exited.
     #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
     fn delete(&self) -> Result<(), SandboxError> {
-        log::info!("deleting instance: {}", self.id);
+        tracing::info!("deleting instance: {}", self.id);
         self.container
             .lock()
             .expect("Poisoned mutex")
             .delete(true)?;
+        if let Err(err) = self.engine.on_delete(&self.hooks.ctx()) {
+            tracing::error!("delete hook failed: {err:?}");
+        }
         Ok(())
     }
 
@@ -143,4 +360,12 @@ impl<E: Engine> SandboxInstance for Instance<E> {
     fn wait_timeout(&self, t: impl Into<Option<Duration>>) -> Option<(u32, DateTime<Utc>)> {
         self.exit_code.wait_timeout(t).copied()
     }
+
+    fn info(engine: &E) -> Value {
+        engine_info(engine)
+    }
+
+    fn validate(engine: &E, bundle: &Path) -> Vec<Finding> {
+        validate_bundle(engine, bundle)
+    }
 }
@@ -1,2 +1,9 @@
+mod bundle;
+mod devices;
 mod executor;
 pub mod instance;
+mod overlay;
+mod pause;
+mod phase;
+mod scratch;
+mod validate;
@@ -0,0 +1,173 @@
+//! Copy-on-write overlay of the rootfs of the containers.
+//!
+//! The rootfs of a container is usually a snapshot shared with the other containers of the same
+//! image, and the engines preopen it with write permissions. With the `runwasi.io/rootfs-overlay`
+//! annotation set to `true`, the rootfs becomes the read-only lower directory of an overlay, whose
+//! upper directory is private to the container. The guest sees the union of both: it can write
+//! anywhere in its root, while the snapshot is never modified.
+//!
+//! The upper directory lives in the bundle of the container, and is removed with the overlay when
+//! the container is deleted, making the writes ephemeral.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use oci_spec::runtime::{RootBuilder, Spec};
+
+use crate::sandbox::Error;
+
+const ROOTFS_OVERLAY_ANNOTATION: &str = "runwasi.io/rootfs-overlay";
+
+/// An overlay mounted on top of the rootfs of a container, unmounted when dropped.
+pub(crate) struct RootfsOverlay {
+    dir: PathBuf,
+    merged: PathBuf,
+}
+
+impl RootfsOverlay {
+    /// Mount the overlay of the rootfs of `spec`, if its annotations ask for it, and make it the
+    /// root of the container instead.
+    #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+    pub(crate) fn mount(spec: &mut Spec, bundle: &Path) -> Result<Option<Self>, Error> {
+        if !is_enabled(spec)? {
+            return Ok(None);
+        }
+        let root = spec
+            .root()
+            .as_ref()
+            .ok_or_else(|| Error::InvalidArgument("the spec has no root".into()))?;
+        let lower = bundle.join(root.path());
+
+        let dir = bundle.join("overlay");
+        let (upper, work, merged) = (dir.join("upper"), dir.join("work"), dir.join("merged"));
+        for dir in [&upper, &work, &merged] {
+            fs::create_dir_all(dir)?;
+        }
+
+        let options = mount_options(&lower, &upper, &work)?;
+        mount(
+            Some("overlay"),
+            &merged,
+            Some("overlay"),
+            MsFlags::empty(),
+            Some(options.as_str()),
+        )?;
+        let overlay = Self { dir, merged };
+
+        // The writes go to the upper directory, the lower one is never written to
+        spec.set_root(Some(
+            RootBuilder::default()
+                .path(&overlay.merged)
+                .readonly(false)
+                .build()?,
+        ));
+        Ok(Some(overlay))
+    }
+}
+
+impl RootfsOverlay {
+    /// The overlay mounted for the container of `spec` by a previous shim, which handed the
+    /// container over to this one.
+    #[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+    pub(crate) fn adopt(spec: &Spec, bundle: &Path) -> Result<Option<Self>, Error> {
+        let dir = bundle.join("overlay");
+        let merged = dir.join("merged");
+        if !is_enabled(spec)? || !merged.exists() {
+            return Ok(None);
+        }
+        Ok(Some(Self { dir, merged }))
+    }
+}
+
+impl Drop for RootfsOverlay {
+    fn drop(&mut self) {
+        if let Err(err) = umount2(&self.merged, MntFlags::MNT_DETACH) {
+            tracing::warn!("failed to unmount {}: {err}", self.merged.display());
+        }
+        if let Err(err) = fs::remove_dir_all(&self.dir) {
+            tracing::warn!("failed to remove {}: {err}", self.dir.display());
+        }
+    }
+}
+
+pub(crate) fn is_enabled(spec: &Spec) -> Result<bool, Error> {
+    let Some(value) = spec
+        .annotations()
+        .as_ref()
+        .and_then(|annotations| annotations.get(ROOTFS_OVERLAY_ANNOTATION))
+    else {
+        return Ok(false);
+    };
+    value.trim().parse().map_err(|err| {
+        Error::InvalidArgument(format!(
+            "invalid {ROOTFS_OVERLAY_ANNOTATION} {value:?}: {err}"
+        ))
+    })
+}
+
+fn mount_options(lower: &Path, upper: &Path, work: &Path) -> Result<String, Error> {
+    let mut options = vec![];
+    for (name, dir) in [("lowerdir", lower), ("upperdir", upper), ("workdir", work)] {
+        let dir = dir.to_str().unwrap_or_default();
+        // The separators of the mount options can't be escaped
+        if dir.is_empty() || dir.contains([',', ':']) {
+            return Err(Error::InvalidArgument(format!(
+                "unsupported overlay {name} {dir:?}"
+            )));
+        }
+        options.push(format!("{name}={dir}"));
+    }
+    Ok(options.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use oci_spec::runtime::SpecBuilder;
+
+    use super::*;
+
+    #[test]
+    fn test_is_enabled() -> anyhow::Result<()> {
+        let spec = |value: &str| {
+            SpecBuilder::default()
+                .annotations(HashMap::from([(
+                    ROOTFS_OVERLAY_ANNOTATION.into(),
+                    value.into(),
+                )]))
+                .build()
+        };
+        assert!(is_enabled(&spec("true")?)?);
+        assert!(!is_enabled(&spec("false")?)?);
+        assert!(is_enabled(&spec("yes")?).is_err());
+        assert!(!is_enabled(&SpecBuilder::default().build()?)?);
+
+        let mut spec = SpecBuilder::default().build()?;
+        assert!(RootfsOverlay::mount(&mut spec, Path::new("/bundle"))?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mount_options() {
+        let options = mount_options(
+            Path::new("/bundle/rootfs"),
+            Path::new("/bundle/overlay/upper"),
+            Path::new("/bundle/overlay/work"),
+        )
+        .unwrap();
+        assert_eq!(
+            options,
+            "lowerdir=/bundle/rootfs,upperdir=/bundle/overlay/upper,workdir=/bundle/overlay/work"
+        );
+
+        assert!(mount_options(
+            Path::new("/bundle/a:b"),
+            Path::new("/upper"),
+            Path::new("/work")
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,96 @@
+//! Built-in implementation of the CRI pause container.
+//!
+//! The first container of a pod sandbox only holds the namespaces of the pod. Clusters usually
+//! run it from a pause image, but with a wasm runtime class the image may not be a runnable Linux
+//! image, e.g. when the cluster doesn't override the pause image of the runtime. The shim runs the
+//! sandbox containers itself instead, without any wasm involved.
+
+use std::collections::HashMap;
+
+use nix::errno::Errno;
+use nix::sys::signal::{SigSet, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::Pid;
+
+const CONTAINER_TYPE_ANNOTATIONS: &[&str] = &[
+    // containerd
+    "io.kubernetes.cri.container-type",
+    // CRI-O
+    "io.kubernetes.cri-o.ContainerType",
+];
+const SANDBOX_CONTAINER_TYPE: &str = "sandbox";
+
+/// Whether the container with `annotations` is the pause container of a CRI pod sandbox.
+pub(crate) fn is_pause_container(annotations: Option<&HashMap<String, String>>) -> bool {
+    let Some(annotations) = annotations else {
+        return false;
+    };
+    CONTAINER_TYPE_ANNOTATIONS
+        .iter()
+        .any(|key| annotations.get(*key).map(String::as_str) == Some(SANDBOX_CONTAINER_TYPE))
+}
+
+/// Do what the pause binary of Kubernetes does: reap the zombies of the pod, as the init process
+/// of its PID namespace when it's shared, and exit on `SIGINT` or `SIGTERM`.
+pub(crate) fn pause() -> ! {
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGINT);
+    signals.add(Signal::SIGTERM);
+    signals.add(Signal::SIGCHLD);
+    if let Err(err) = signals.thread_block() {
+        tracing::error!("failed to block the signals of the pause container: {err}");
+        std::process::exit(1);
+    }
+
+    loop {
+        match signals.wait() {
+            Ok(Signal::SIGCHLD) => reap(),
+            Ok(signal) => {
+                tracing::info!("pause container shutting down on {signal}");
+                std::process::exit(0);
+            }
+            Err(err) => {
+                tracing::error!("failed to wait for signals in the pause container: {err}");
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+fn reap() {
+    loop {
+        match waitpid(Pid::from_raw(-1), Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::StillAlive) | Err(Errno::ECHILD) => return,
+            Ok(_) => continue,
+            Err(err) => {
+                tracing::warn!("failed to reap children in the pause container: {err}");
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_pause_container() {
+        let annotations = |key: &str, value: &str| HashMap::from([(key.into(), value.into())]);
+
+        assert!(is_pause_container(Some(&annotations(
+            "io.kubernetes.cri.container-type",
+            "sandbox"
+        ))));
+        assert!(is_pause_container(Some(&annotations(
+            "io.kubernetes.cri-o.ContainerType",
+            "sandbox"
+        ))));
+        assert!(!is_pause_container(Some(&annotations(
+            "io.kubernetes.cri.container-type",
+            "container"
+        ))));
+        assert!(!is_pause_container(Some(&HashMap::new())));
+        assert!(!is_pause_container(None));
+    }
+}
@@ -0,0 +1,142 @@
+//! Phases reported by the container process to the shim.
+//!
+//! The container process is forked from the shim, and can't reach containerd once it runs in the
+//! rootfs of the container. The phases it reports are written to memory shared across the fork,
+//! which the shim polls and publishes.
+
+use std::io;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicI64, AtomicU32, AtomicUsize, Ordering};
+
+use chrono::{DateTime, Utc};
+
+use crate::container::Phase;
+
+/// Maximum number of phases reported by a container, the next ones are dropped.
+const CAPACITY: usize = 64;
+
+#[repr(C)]
+struct Entry {
+    /// The phase, 0 until the entry is written.
+    phase: AtomicU32,
+    /// When the phase was reported, in nanoseconds since the epoch.
+    nanos: AtomicI64,
+}
+
+#[repr(C)]
+struct Shared {
+    reserved: AtomicUsize,
+    entries: [Entry; CAPACITY],
+}
+
+pub(crate) struct PhaseLog {
+    shared: NonNull<Shared>,
+}
+
+// The memory is only accessed through atomics
+unsafe impl Send for PhaseLog {}
+unsafe impl Sync for PhaseLog {}
+
+impl PhaseLog {
+    pub fn new() -> io::Result<Self> {
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                size_of::<Shared>(),
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        // Anonymous mappings are zeroed, which is a valid empty log
+        let shared = NonNull::new(ptr.cast()).ok_or_else(io::Error::last_os_error)?;
+        Ok(Self { shared })
+    }
+
+    fn shared(&self) -> &Shared {
+        unsafe { self.shared.as_ref() }
+    }
+
+    /// Append `phase` to the log, called in the container process.
+    pub fn push(&self, phase: Phase) {
+        let shared = self.shared();
+        let index = shared.reserved.fetch_add(1, Ordering::Relaxed);
+        let Some(entry) = shared.entries.get(index) else {
+            return;
+        };
+        let nanos = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+        entry.nanos.store(nanos, Ordering::Relaxed);
+        entry.phase.store(phase as u32, Ordering::Release);
+    }
+
+    /// The phases appended since `cursor`, which is advanced past them.
+    pub fn read(&self, cursor: &mut usize) -> Vec<(Phase, DateTime<Utc>)> {
+        let mut phases = vec![];
+        while let Some(entry) = self.shared().entries.get(*cursor) {
+            let phase = entry.phase.load(Ordering::Acquire);
+            if phase == 0 {
+                // Not written yet
+                break;
+            }
+            *cursor += 1;
+            let Some(phase) = Phase::from_u32(phase) else {
+                continue;
+            };
+            let timestamp = DateTime::from_timestamp_nanos(entry.nanos.load(Ordering::Relaxed));
+            phases.push((phase, timestamp));
+        }
+        phases
+    }
+}
+
+impl Drop for PhaseLog {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.shared.as_ptr().cast(), size_of::<Shared>());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::sys::wait::{waitpid, WaitStatus};
+    use nix::unistd::{fork, ForkResult};
+
+    use super::*;
+
+    #[test]
+    fn test_shared_across_fork() -> anyhow::Result<()> {
+        let log = PhaseLog::new()?;
+        let mut cursor = 0;
+        log.push(Phase::Compiling);
+
+        match unsafe { fork() }? {
+            ForkResult::Child => {
+                log.push(Phase::Instantiating);
+                log.push(Phase::Serving);
+                unsafe { libc::_exit(0) };
+            }
+            ForkResult::Parent { child } => {
+                assert_eq!(waitpid(child, None)?, WaitStatus::Exited(child, 0));
+            }
+        }
+
+        let phases: Vec<_> = log.read(&mut cursor).into_iter().map(|(p, _)| p).collect();
+        assert_eq!(
+            phases,
+            [Phase::Compiling, Phase::Instantiating, Phase::Serving]
+        );
+        assert!(log.read(&mut cursor).is_empty());
+
+        for _ in 0..CAPACITY {
+            log.push(Phase::Draining);
+        }
+        assert_eq!(log.read(&mut cursor).len(), CAPACITY - 3);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,176 @@
+//! Writable scratch directory of the containers at `/tmp`.
+//!
+//! Many language runtimes compiled to wasm expect a writable temporary directory, which the
+//! images of wasm containers, often built `FROM scratch`, don't have. With the `runwasi.io/scratch`
+//! annotation set to `true`, and unless the spec already mounts something at `/tmp`, e.g. an
+//! `emptyDir` volume, a tmpfs is added to the mounts of the container. It is mounted in the mount
+//! namespace of the container, and goes away with it.
+//!
+//! Only the containers of images with wasm layers get the directory: it would hide the `/tmp` of
+//! the image of the Linux containers.
+//!
+//! The engines preopen the root of the container, which makes the directory available to the
+//! guest. The tmpfs counts against the memory limit of the container, and its size is:
+//!
+//! * the `runwasi.io/scratch-size` annotation, in bytes, where `0` disables the directory,
+//! * otherwise half of the memory limit of the container, if any,
+//! * otherwise the default of tmpfs, half of the memory of the node.
+
+use oci_spec::runtime::{MountBuilder, Spec};
+
+use crate::sandbox::Error;
+
+const SCRATCH_ANNOTATION: &str = "runwasi.io/scratch";
+const SCRATCH_SIZE_ANNOTATION: &str = "runwasi.io/scratch-size";
+
+const SCRATCH_DIR: &str = "/tmp";
+
+/// Add the scratch directory to the mounts of `spec`, if its annotations ask for it.
+/// Returns whether the spec was changed.
+#[cfg_attr(feature = "tracing", tracing::instrument(parent = tracing::Span::current(), skip_all, level = "Info"))]
+pub(crate) fn add_scratch_mount(spec: &mut Spec) -> Result<bool, Error> {
+    let annotation = |name: &str| {
+        spec.annotations()
+            .as_ref()
+            .and_then(|annotations| annotations.get(name))
+    };
+    let enabled = match annotation(SCRATCH_ANNOTATION) {
+        Some(value) => value.trim().parse().map_err(|err| {
+            Error::InvalidArgument(format!("invalid {SCRATCH_ANNOTATION} {value:?}: {err}"))
+        })?,
+        None => false,
+    };
+    if !enabled {
+        return Ok(false);
+    }
+
+    let mut mounts = spec.mounts().clone().unwrap_or_default();
+    if mounts
+        .iter()
+        .any(|mount| mount.destination().as_os_str() == SCRATCH_DIR)
+    {
+        return Ok(false);
+    }
+
+    let size = match annotation(SCRATCH_SIZE_ANNOTATION) {
+        Some(size) => Some(size.trim().parse::<u64>().map_err(|err| {
+            Error::InvalidArgument(format!("invalid {SCRATCH_SIZE_ANNOTATION} {size:?}: {err}"))
+        })?),
+        None => memory_limit(spec).map(|limit| limit / 2),
+    };
+    if size == Some(0) {
+        return Ok(false);
+    }
+
+    let mut options = vec!["nosuid".to_string(), "nodev".into(), "mode=1777".into()];
+    options.extend(size.map(|size| format!("size={size}")));
+    mounts.push(
+        MountBuilder::default()
+            .destination(SCRATCH_DIR)
+            .typ("tmpfs")
+            .source("tmpfs")
+            .options(options)
+            .build()?,
+    );
+    spec.set_mounts(Some(mounts));
+    Ok(true)
+}
+
+fn memory_limit(spec: &Spec) -> Option<u64> {
+    let limit = spec
+        .linux()
+        .as_ref()?
+        .resources()
+        .as_ref()?
+        .memory()
+        .as_ref()?
+        .limit()?;
+    u64::try_from(limit).ok().filter(|limit| *limit > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use oci_spec::runtime::{LinuxBuilder, LinuxMemoryBuilder, LinuxResourcesBuilder, SpecBuilder};
+
+    use super::*;
+
+    fn scratch_options(spec: &Spec) -> Option<Vec<String>> {
+        let mount = spec
+            .mounts()
+            .iter()
+            .flatten()
+            .find(|mount| mount.destination().as_os_str() == SCRATCH_DIR)?;
+        assert_eq!(mount.typ().as_deref(), Some("tmpfs"));
+        mount.options().clone()
+    }
+
+    fn annotations(size: Option<&str>) -> HashMap<String, String> {
+        let mut annotations = HashMap::from([(SCRATCH_ANNOTATION.into(), "true".into())]);
+        annotations.extend(size.map(|size| (SCRATCH_SIZE_ANNOTATION.into(), size.into())));
+        annotations
+    }
+
+    #[test]
+    fn test_add_scratch_mount() -> anyhow::Result<()> {
+        // The directory is opt-in
+        let mut spec = SpecBuilder::default().mounts(vec![]).build()?;
+        assert!(!add_scratch_mount(&mut spec)?);
+        assert!(scratch_options(&spec).is_none());
+
+        let mut spec = SpecBuilder::default()
+            .mounts(vec![])
+            .annotations(annotations(None))
+            .build()?;
+        assert!(add_scratch_mount(&mut spec)?);
+        let options = scratch_options(&spec).unwrap();
+        assert!(options.contains(&"mode=1777".to_string()));
+        assert!(!options.iter().any(|option| option.starts_with("size=")));
+
+        // An existing mount is kept
+        assert!(!add_scratch_mount(&mut spec)?);
+        assert_eq!(spec.mounts().as_ref().unwrap().len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_scratch_size() -> anyhow::Result<()> {
+        let memory = LinuxMemoryBuilder::default().limit(256 << 20).build()?;
+        let resources = LinuxResourcesBuilder::default().memory(memory).build()?;
+        let linux = LinuxBuilder::default().resources(resources).build()?;
+
+        let mut spec = SpecBuilder::default()
+            .mounts(vec![])
+            .linux(linux.clone())
+            .annotations(annotations(None))
+            .build()?;
+        add_scratch_mount(&mut spec)?;
+        let options = scratch_options(&spec).unwrap();
+        assert!(options.contains(&format!("size={}", 128 << 20)));
+
+        let mut spec = SpecBuilder::default()
+            .mounts(vec![])
+            .linux(linux)
+            .annotations(annotations(Some("1048576")))
+            .build()?;
+        add_scratch_mount(&mut spec)?;
+        let options = scratch_options(&spec).unwrap();
+        assert!(options.contains(&"size=1048576".to_string()));
+
+        let mut spec = SpecBuilder::default()
+            .mounts(vec![])
+            .annotations(annotations(Some("0")))
+            .build()?;
+        assert!(!add_scratch_mount(&mut spec)?);
+        assert!(scratch_options(&spec).is_none());
+
+        let mut spec = SpecBuilder::default()
+            .annotations(annotations(Some("big")))
+            .build()?;
+        assert!(add_scratch_mount(&mut spec).is_err());
+
+        Ok(())
+    }
+}
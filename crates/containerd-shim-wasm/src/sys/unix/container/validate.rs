@@ -0,0 +1,264 @@
+//! Checks of a bundle for the `--validate` mode of the shims, see [`crate::sandbox::validate`].
+//!
+//! The spec is checked like the shim does when creating the container: the devices, the
+//! annotations of the shim, the entrypoint, resolved in the rootfs as it would be in the
+//! container, and the configuration of the engine, with the engine options of the annotations.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use oci_spec::image::Platform;
+use oci_spec::runtime::Spec;
+
+use crate::container::{Engine, WasiContext};
+use crate::sandbox::instance_utils::merge_engine_options;
+use crate::sandbox::validate::Finding;
+use crate::sys::container::devices::{check_devices, uninjected_cdi_devices};
+use crate::sys::container::overlay;
+use crate::sys::container::pause::is_pause_container;
+use crate::sys::container::scratch::add_scratch_mount;
+
+/// Check the bundle at `bundle` for `engine`.
+pub(crate) fn validate_bundle<E: Engine>(engine: &E, bundle: &Path) -> Vec<Finding> {
+    let spec = match Spec::load(bundle.join("config.json")) {
+        Ok(spec) => spec,
+        Err(err) => return vec![Finding::error("config.json", err)],
+    };
+    if is_pause_container(spec.annotations().as_ref()) {
+        return vec![];
+    }
+
+    let mut findings = check_spec(&spec);
+    if let Err(err) = check_devices(&spec) {
+        findings.push(Finding::error("linux.devices", err));
+    }
+    if let Some(warning) = uninjected_cdi_devices(&spec) {
+        findings.push(Finding::warning("annotations", warning));
+    }
+    if let Err(err) = add_scratch_mount(&mut spec.clone()) {
+        findings.push(Finding::error("annotations", err));
+    }
+    if let Err(err) = overlay::is_enabled(&spec) {
+        findings.push(Finding::error("annotations", err));
+    }
+    let options = match merge_engine_options(None, spec.annotations().as_ref()) {
+        Ok(options) => options,
+        Err(err) => {
+            findings.push(Finding::error("annotations", err));
+            None
+        }
+    };
+
+    let Some(arg0) = spec
+        .process()
+        .as_ref()
+        .and_then(|p| p.args().as_ref())
+        .and_then(|args| args.first())
+    else {
+        return findings;
+    };
+    let rootfs = match spec.root() {
+        Some(root) => bundle.join(root.path()),
+        None => return findings,
+    };
+    let (path, _) = arg0.split_once('#').unwrap_or((arg0, ""));
+    let Some(module) = resolve_entrypoint(&spec, &rootfs, Path::new(path)) else {
+        findings.push(Finding::warning(
+            "process.args[0]",
+            format!(
+                "{path} not found in the rootfs, the module must be provided by the wasm layers \
+                 of the image"
+            ),
+        ));
+        return findings;
+    };
+    match check_module(&module) {
+        Ok(true) => {}
+        Ok(false) => {
+            findings.push(Finding::warning(
+                "process.args[0]",
+                format!("{path} is a Linux executable, the container runs as a Linux container"),
+            ));
+            return findings;
+        }
+        Err(err) => {
+            findings.push(Finding::error(
+                "process.args[0]",
+                format!("{path} is neither a wasm module nor a wat file: {err:#}"),
+            ));
+        }
+    }
+
+    let ctx = WasiContext {
+        spec: &spec,
+        wasm_layers: &[],
+        platform: &Platform::default(),
+        labels: &Default::default(),
+        exposed_ports: &[],
+        options: options.as_ref(),
+    };
+    if let Err(err) = engine.validate(&ctx) {
+        findings.push(Finding::error("engine", format!("{err:#}")));
+    }
+    findings
+}
+
+/// Check the fields of the spec the shim doesn't support.
+fn check_spec(spec: &Spec) -> Vec<Finding> {
+    let mut findings = vec![];
+    match spec.process() {
+        Some(process) => {
+            if process.args().as_deref().unwrap_or_default().is_empty() {
+                findings.push(Finding::error(
+                    "process.args",
+                    "no entrypoint, the first argument must be the path of the wasm module, \
+                     optionally followed by `#<function>`",
+                ));
+            }
+            if process.terminal() == Some(true) {
+                findings.push(Finding::error(
+                    "process.terminal",
+                    "terminal is not supported",
+                ));
+            }
+        }
+        None => findings.push(Finding::error("process", "no process to run")),
+    }
+    if spec.root().is_none() {
+        findings.push(Finding::error("root", "rootfs is not set in runtime spec"));
+    }
+    for (field, set) in [
+        ("solaris", spec.solaris().is_some()),
+        ("windows", spec.windows().is_some()),
+        ("vm", spec.vm().is_some()),
+    ] {
+        if set {
+            findings.push(Finding::warning(field, "ignored on Linux"));
+        }
+    }
+    findings
+}
+
+/// The file of the entrypoint `path` in `rootfs`, resolved as in the container: relative to the
+/// working directory of the process, or, for a bare file name, in its `PATH` and then in its
+/// working directory.
+fn resolve_entrypoint(spec: &Spec, rootfs: &Path, path: &Path) -> Option<PathBuf> {
+    let process = spec.process().as_ref()?;
+    let cwd = process.cwd();
+    let in_rootfs = |path: &Path| rootfs.join(path.strip_prefix("/").unwrap_or(path));
+
+    if path.components().count() > 1 {
+        let path = in_rootfs(&cwd.join(path));
+        return path.is_file().then_some(path);
+    }
+    let dirs: Vec<_> = process
+        .env()
+        .iter()
+        .flatten()
+        .find_map(|env| env.strip_prefix("PATH="))
+        .map(|paths| std::env::split_paths(paths).collect())
+        .unwrap_or_default();
+    dirs.iter()
+        .chain([cwd])
+        .map(|dir| in_rootfs(&dir.join(path)))
+        .find(|path| path.is_file())
+}
+
+/// Whether `path` is a wasm module or a wat file, rather than a Linux executable.
+fn check_module(path: &Path) -> anyhow::Result<bool> {
+    let mut buffer = [0; 4];
+    File::open(path)?.read_exact(&mut buffer)?;
+    match buffer {
+        [0x00, 0x61, 0x73, 0x6d] => Ok(true),  // wasm magic number
+        [0x7f, 0x45, 0x4c, 0x46] => Ok(false), // ELF magic number
+        [0x23, 0x21, ..] => Ok(false),         // shebang
+        _ => {
+            wat::parse_file(path)?;
+            Ok(true)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use oci_spec::runtime::{ProcessBuilder, RootBuilder, SpecBuilder};
+
+    use super::*;
+    use crate::container::RuntimeContext;
+    use crate::sandbox::validate::Severity;
+    use crate::sandbox::Stdio;
+
+    #[derive(Clone, Default)]
+    struct TestEngine;
+
+    impl Engine for TestEngine {
+        fn name() -> &'static str {
+            "test"
+        }
+
+        fn run_wasi(&self, _ctx: &impl RuntimeContext, _stdio: Stdio) -> anyhow::Result<i32> {
+            Ok(0)
+        }
+
+        fn validate(&self, ctx: &impl RuntimeContext) -> anyhow::Result<()> {
+            anyhow::ensure!(ctx.entrypoint().func != "invalid", "invalid function");
+            Ok(())
+        }
+    }
+
+    fn write_bundle(bundle: &Path, args: &[&str], env: &[&str]) -> anyhow::Result<()> {
+        let spec = SpecBuilder::default()
+            .root(RootBuilder::default().path("rootfs").build()?)
+            .process(
+                ProcessBuilder::default()
+                    .cwd("/")
+                    .args(args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>())
+                    .env(env.iter().map(|env| env.to_string()).collect::<Vec<_>>())
+                    .build()?,
+            )
+            .build()?;
+        spec.save(bundle.join("config.json"))?;
+        std::fs::create_dir_all(bundle.join("rootfs/app"))?;
+        std::fs::write(bundle.join("rootfs/app/hello.wasm"), b"\0asm\x01\0\0\0")?;
+        std::fs::write(bundle.join("rootfs/app/hello.txt"), "hello")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_bundle() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let bundle = dir.path();
+
+        write_bundle(bundle, &["/app/hello.wasm#run"], &[])?;
+        assert_eq!(validate_bundle(&TestEngine, bundle), vec![]);
+
+        write_bundle(bundle, &["hello.wasm"], &["PATH=/bin:/app"])?;
+        assert_eq!(validate_bundle(&TestEngine, bundle), vec![]);
+
+        write_bundle(bundle, &["/app/hello.wasm#invalid"], &[])?;
+        let findings = validate_bundle(&TestEngine, bundle);
+        assert_eq!(findings, vec![Finding::error("engine", "invalid function")]);
+
+        write_bundle(bundle, &["/app/hello.txt"], &[])?;
+        let findings = validate_bundle(&TestEngine, bundle);
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].severity, Severity::Error);
+        assert_eq!(findings[0].field, "process.args[0]");
+
+        write_bundle(bundle, &["/app/missing.wasm"], &[])?;
+        let findings = validate_bundle(&TestEngine, bundle);
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].severity, Severity::Warning);
+
+        write_bundle(bundle, &[], &[])?;
+        let findings = validate_bundle(&TestEngine, bundle);
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].field, "process.args");
+
+        std::fs::write(bundle.join("config.json"), "{")?;
+        let findings = validate_bundle(&TestEngine, bundle);
+        assert_eq!(findings[0].field, "config.json");
+        Ok(())
+    }
+}
@@ -1,4 +1,26 @@
-//! Testing utilities used across different modules
+//! Testing utilities used across different modules, and by downstream engines.
+//!
+//! Enable the `testing` feature to write integration tests running a wasm module or component with
+//! an [`Instance`], e.g.:
+//!
+//! ```ignore
+//! use containerd_shim_wasm::testing::{http_helpers, WasiTest};
+//!
+//! let srv = WasiTest::<MyInstance>::builder()?
+//!     .with_wasm(std::fs::read("my-component.wasm")?)?
+//!     .with_env("GREETING", "hello")
+//!     .with_annotation("runwasi.io/http-backlog", "10")
+//!     .with_host_network()
+//!     .build()?;
+//!
+//! srv.start()?;
+//! let response = http_helpers::get_with_retry("http://127.0.0.1:8080/", 10, Duration::from_secs(1))?;
+//! assert_eq!(response.status, 200);
+//!
+//! let (stdout, _stderr) = srv.ctrl_c()?.wait_for_exit_code(Duration::from_secs(5), 0)?;
+//! ```
+//!
+//! The tests run the instance as a container, and must run serially.
 
 use std::collections::HashMap;
 use std::fs::{self, create_dir, read, read_to_string, write, File};
@@ -21,6 +43,9 @@ use oci_spec::runtime::{
 
 use crate::sandbox::{Instance, InstanceConfig};
 
+#[cfg(feature = "conformance")]
+pub mod conformance;
+
 pub const TEST_NAMESPACE: &str = "runwasi-test";
 pub const SIGKILL: u32 = 9;
 
@@ -30,6 +55,9 @@ where
 {
     container_name: String,
     start_fn: String,
+    args: Vec<String>,
+    env: Vec<String>,
+    annotations: HashMap<String, String>,
     namespaces: Vec<LinuxNamespace>,
     tempdir: tempfile::TempDir,
     _phantom: PhantomData<WasiInstance>,
@@ -53,7 +81,7 @@ where
         // --show-output before running test
         let _ = env_logger::try_init();
 
-        log::info!("creating new wasi test");
+        tracing::info!("creating new wasi test");
 
         let tempdir = tempfile::tempdir()?;
         let dir = tempdir.path();
@@ -72,6 +100,9 @@ where
             tempdir,
             container_name: "test".to_string(),
             start_fn: "".to_string(),
+            args: vec![],
+            env: vec![],
+            annotations: HashMap::new(),
             namespaces: get_default_namespaces(),
             _phantom: Default::default(),
         }
@@ -99,10 +130,29 @@ where
         self
     }
 
+    /// Add arguments after the entrypoint.
+    pub fn with_args(mut self, args: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    /// Set the environment variable `key` of the container.
+    pub fn with_env(mut self, key: impl AsRef<str>, value: impl AsRef<str>) -> Self {
+        self.env
+            .push(format!("{}={}", key.as_ref(), value.as_ref()));
+        self
+    }
+
+    /// Set the annotation `key` of the container.
+    pub fn with_annotation(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.annotations.insert(key.into(), value.into());
+        self
+    }
+
     pub fn with_wasm(self, wasmbytes: impl AsRef<[u8]>) -> Result<Self> {
         let dir = self.tempdir.path();
 
-        log::info!(
+        tracing::info!(
             "setting wasi test wasm file [u8; {}]",
             wasmbytes.as_ref().len()
         );
@@ -116,7 +166,7 @@ where
     pub fn with_stdin(self, stdin: impl AsRef<[u8]>) -> Result<Self> {
         let dir = self.tempdir.path();
 
-        log::info!("setting wasi test stdin to [u8; {}]", stdin.as_ref().len());
+        tracing::info!("setting wasi test stdin to [u8; {}]", stdin.as_ref().len());
 
         write(dir.join("stdin"), stdin)?;
 
@@ -128,7 +178,7 @@ where
 
         let dir = self.tempdir.path();
 
-        log::info!("setting wasi test stdout to {:?}", stdout);
+        tracing::info!("setting wasi test stdout to {:?}", stdout);
 
         std::fs::remove_file(dir.join("stdout"))?;
         symlink(stdout, dir.join("stdout"))?;
@@ -141,7 +191,7 @@ where
 
         let dir = self.tempdir.path();
 
-        log::info!("setting wasi test stderr to {:?}", stderr);
+        tracing::info!("setting wasi test stderr to {:?}", stderr);
 
         std::fs::remove_file(dir.join("stderr"))?;
         symlink(stderr, dir.join("stderr"))?;
@@ -186,7 +236,7 @@ where
         let tempdir = self.tempdir;
         let dir = tempdir.path();
 
-        log::info!("setting wasi test start_fn to {}", self.start_fn);
+        tracing::info!("setting wasi test start_fn to {}", self.start_fn);
 
         let entrypoint = match self.start_fn.as_str() {
             "" => "/hello.wasm".to_string(),
@@ -195,6 +245,7 @@ where
 
         let spec = SpecBuilder::default()
             .root(RootBuilder::default().path("rootfs").build()?)
+            .annotations(self.annotations)
             .linux(
                 LinuxBuilder::default()
                     .namespaces(self.namespaces)
@@ -203,14 +254,15 @@ where
             .process(
                 ProcessBuilder::default()
                     .cwd("/")
-                    .args([entrypoint])
+                    .args([vec![entrypoint], self.args].concat())
+                    .env(self.env)
                     .build()?,
             )
             .build()?;
 
         spec.save(dir.join("config.json"))?;
 
-        log::info!("building wasi test: {}", dir.display());
+        tracing::info!("building wasi test: {}", dir.display());
 
         let mut cfg = InstanceConfig::new(
             WasiInstance::Engine::default(),
@@ -240,39 +292,39 @@ where
     }
 
     pub fn start(&self) -> Result<&Self> {
-        log::info!("starting wasi test");
+        tracing::info!("starting wasi test");
         let pid = self.instance.start()?;
-        log::info!("wasi test pid {pid}");
+        tracing::info!("wasi test pid {pid}");
 
         Ok(self)
     }
 
     pub fn delete(&self) -> Result<&Self> {
-        log::info!("deleting wasi test");
+        tracing::info!("deleting wasi test");
         self.instance.delete()?;
         Ok(self)
     }
 
     pub fn ctrl_c(&self) -> Result<&Self> {
-        log::info!("sending SIGINT");
+        tracing::info!("sending SIGINT");
         self.instance.kill(SIGINT as u32)?;
         Ok(self)
     }
 
     pub fn terminate(&self) -> Result<&Self> {
-        log::info!("sending SIGTERM");
+        tracing::info!("sending SIGTERM");
         self.instance.kill(SIGTERM as u32)?;
         Ok(self)
     }
 
     pub fn kill(&self) -> Result<&Self> {
-        log::info!("sending SIGKILL");
+        tracing::info!("sending SIGKILL");
         self.instance.kill(SIGKILL as u32)?;
         Ok(self)
     }
 
     pub fn wait(&self, timeout: Duration) -> Result<(u32, String, String)> {
-        log::info!("waiting wasi test");
+        tracing::info!("waiting wasi test");
         let (status, _) = match self.instance.wait_timeout(timeout) {
             Some(res) => res,
             None => {
@@ -286,11 +338,20 @@ where
 
         self.instance.delete()?;
 
-        log::info!("wasi test status is {status}");
+        tracing::info!("wasi test status is {status}");
 
         Ok((status, stdout, stderr))
     }
 
+    /// Wait for the instance to exit with `expected` exit code, returning its stdout and stderr.
+    pub fn wait_for_exit_code(&self, timeout: Duration, expected: u32) -> Result<(String, String)> {
+        let (status, stdout, stderr) = self.wait(timeout)?;
+        if status != expected {
+            bail!("exit code {status}, expected {expected}\nstdout: {stdout}\nstderr: {stderr}");
+        }
+        Ok((stdout, stderr))
+    }
+
     pub fn root(&self) -> &Path {
         self.tempdir.path()
     }
@@ -312,6 +373,109 @@ where
     }
 }
 
+pub mod http_helpers {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::time::Duration;
+
+    use anyhow::{bail, Context, Result};
+
+    /// A response of the server under test.
+    #[derive(Debug)]
+    pub struct Response {
+        pub status: u16,
+        pub headers: Vec<(String, String)>,
+        pub body: String,
+    }
+
+    impl Response {
+        pub fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.as_str())
+        }
+    }
+
+    /// Send a `GET` request to `url`, e.g. `http://127.0.0.1:8080/hello`.
+    ///
+    /// The request is sent with HTTP/1.0, so that the body of the response is delimited by the end
+    /// of the connection.
+    pub fn get(url: &str) -> Result<Response> {
+        let rest = url
+            .strip_prefix("http://")
+            .with_context(|| format!("unsupported url {url:?}"))?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+
+        let mut stream = TcpStream::connect(authority)?;
+        stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+        write!(stream, "GET {path} HTTP/1.0\r\nHost: {authority}\r\n\r\n")?;
+
+        let mut response = vec![];
+        stream.read_to_end(&mut response)?;
+        parse(&String::from_utf8_lossy(&response))
+    }
+
+    /// Like [`get`], retrying up to `attempts` times every `backoff` while the server starts.
+    pub fn get_with_retry(url: &str, attempts: u32, backoff: Duration) -> Result<Response> {
+        let mut attempt = 1;
+        loop {
+            match get(url) {
+                Ok(response) => return Ok(response),
+                Err(e) if attempt >= attempts => {
+                    return Err(
+                        e.context(format!("server did not respond after {attempts} attempts"))
+                    )
+                }
+                Err(_) => {
+                    std::thread::sleep(backoff);
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    fn parse(response: &str) -> Result<Response> {
+        let Some((head, body)) = response.split_once("\r\n\r\n") else {
+            bail!("incomplete response {response:?}");
+        };
+        let mut lines = head.split("\r\n");
+        let status = lines
+            .next()
+            .and_then(|line| line.split(' ').nth(1))
+            .and_then(|status| status.parse().ok())
+            .with_context(|| format!("invalid status line in {head:?}"))?;
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect();
+        Ok(Response {
+            status,
+            headers,
+            body: body.to_string(),
+        })
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_parse() -> Result<()> {
+            let response = parse("HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\nhello\n")?;
+            assert_eq!(response.status, 200);
+            assert_eq!(response.header("content-type"), Some("text/plain"));
+            assert_eq!(response.body, "hello\n");
+
+            assert!(parse("HTTP/1.1 200 OK\r\n").is_err());
+            Ok(())
+        }
+    }
+}
+
 pub mod oci_helpers {
     use std::fs::{write, File};
     use std::process::{Command, Stdio};
@@ -330,14 +494,14 @@ pub mod oci_helpers {
 
     impl Drop for OCICleanup {
         fn drop(&mut self) {
-            log::debug!("dropping OCIGuard");
+            tracing::debug!("dropping OCIGuard");
             clean_container(self.container_name.clone()).unwrap();
             clean_image(self.image_name.clone()).unwrap();
         }
     }
 
     pub fn clean_container(container_name: String) -> Result<()> {
-        log::debug!("deleting container '{}'", container_name);
+        tracing::debug!("deleting container '{}'", container_name);
         let success = Command::new("ctr")
             .arg("-n")
             .arg(TEST_NAMESPACE)
@@ -435,7 +599,7 @@ pub mod oci_helpers {
             Err(_) => return Ok(()), // doesn't exist
         };
 
-        log::debug!("deleting image '{}'", image_name);
+        tracing::debug!("deleting image '{}'", image_name);
         let success = Command::new("ctr")
             .arg("-n")
             .arg(TEST_NAMESPACE)
@@ -460,7 +624,7 @@ pub mod oci_helpers {
     pub fn wait_for_content_removal(content_sha: &str) -> Result<(), anyhow::Error> {
         let start = Instant::now();
         let timeout = Duration::from_secs(60);
-        log::info!("waiting for content to be removed: {}", &content_sha);
+        tracing::info!("waiting for content to be removed: {}", &content_sha);
         loop {
             let output = Command::new("ctr")
                 .arg("-n")
@@ -475,7 +639,7 @@ pub mod oci_helpers {
             }
 
             if start.elapsed() > timeout {
-                log::warn!("didn't clean content fully");
+                tracing::warn!("didn't clean content fully");
                 break;
             }
         }
@@ -483,7 +647,7 @@ pub mod oci_helpers {
     }
 
     fn get_image_sha(image_name: &str) -> Result<String> {
-        log::info!("getting image sha for '{}'", image_name);
+        tracing::info!("getting image sha for '{}'", image_name);
         let mut grep = Command::new("grep")
             .arg(image_name)
             .stdout(Stdio::piped())
@@ -500,14 +664,14 @@ pub mod oci_helpers {
 
         let output = grep.wait_with_output()?;
         let stdout = String::from_utf8(output.stdout)?;
-        log::warn!("stdout: {}", stdout);
+        tracing::warn!("stdout: {}", stdout);
 
         let parts: Vec<&str> = stdout.trim().split(' ').collect();
         if parts.len() < 3 {
             bail!("failed to get image sha");
         }
         let sha = parts[2];
-        log::warn!("sha: {}", sha);
+        tracing::warn!("sha: {}", sha);
         Ok(sha.to_string())
     }
 
@@ -529,7 +693,7 @@ pub mod oci_helpers {
 
         let output = grep.wait_with_output()?;
         let stdout = String::from_utf8(output.stdout)?;
-        log::debug!("stdout: {}", stdout);
+        tracing::debug!("stdout: {}", stdout);
         let label: Vec<&str> = stdout.split('=').collect();
 
         Ok((
@@ -572,7 +736,7 @@ pub mod oci_helpers {
 
         let stdout = String::from_utf8(output.stdout)?;
 
-        log::debug!("stdout: {}", stdout);
+        tracing::debug!("stdout: {}", stdout);
 
         let label: Vec<&str> = stdout.split('=').collect();
 
@@ -583,7 +747,7 @@ pub mod oci_helpers {
     }
 
     pub fn remove_content(digest: String) -> Result<()> {
-        log::debug!("cleaning content '{}'", digest);
+        tracing::debug!("cleaning content '{}'", digest);
         let success = Command::new("ctr")
             .arg("-n")
             .arg(TEST_NAMESPACE)
@@ -0,0 +1,216 @@
+//! Conformance test suite of the engines.
+//!
+//! Enable the `conformance` feature to check that an [`Engine`](crate::container::Engine) runs
+//! the same binaries as the shims of this repository, with the same observable behavior: stdio,
+//! arguments, environment, exit codes and traps, and optionally components and the
+//! `wasi:http/proxy` world, e.g.:
+//!
+//! ```ignore
+//! use containerd_shim_wasm::testing::conformance::Conformance;
+//!
+//! #[test]
+//! #[serial]
+//! fn test_conformance() -> anyhow::Result<()> {
+//!     Conformance::new().with_components().run::<MyInstance>()
+//! }
+//! ```
+//!
+//! All the cases run, and the error lists the ones which failed. Like the other tests running
+//! an instance, the suite must run serially.
+
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Result};
+
+use super::modules::*;
+use super::{http_helpers, WasiTest};
+use crate::sandbox::Instance;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A case of the suite.
+type Case = (&'static str, fn() -> Result<()>);
+
+/// The conformance test suite, checking wasm modules by default.
+#[derive(Clone, Copy, Default)]
+pub struct Conformance {
+    components: bool,
+    http_proxy: bool,
+}
+
+impl Conformance {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also check that the engine runs `wasi:cli/command` components.
+    pub fn with_components(mut self) -> Self {
+        self.components = true;
+        self
+    }
+
+    /// Also check that the engine serves `wasi:http/proxy` components on the port 8080 of the
+    /// host.
+    pub fn with_http_proxy(mut self) -> Self {
+        self.http_proxy = true;
+        self
+    }
+
+    /// Run the cases of the suite against the engine of `WasiInstance`.
+    pub fn run<WasiInstance: Instance>(self) -> Result<()>
+    where
+        WasiInstance::Engine: Default + Send + Sync + Clone,
+    {
+        let modules: [Case; 5] = [
+            ("p1 module", hello_world::<WasiInstance>),
+            ("stdio, args and env", stdio_args_env::<WasiInstance>),
+            ("exit code", exit_code::<WasiInstance>),
+            ("trap", trap::<WasiInstance>),
+            ("custom entrypoint", custom_entrypoint::<WasiInstance>),
+        ];
+        let mut cases = Vec::from(modules);
+        if self.components {
+            cases.push(("p2 command", p2_command::<WasiInstance>));
+        }
+        if self.http_proxy {
+            cases.push(("http proxy", http_proxy::<WasiInstance>));
+        }
+
+        let mut failures = vec![];
+        for (name, case) in cases {
+            tracing::info!("conformance: {name}");
+            if let Err(err) = case() {
+                tracing::error!("conformance: {name} failed: {err:?}");
+                failures.push(format!("{name}: {err:#}"));
+            }
+        }
+        if !failures.is_empty() {
+            bail!("conformance failures:\n{}", failures.join("\n"));
+        }
+        Ok(())
+    }
+}
+
+fn hello_world<WasiInstance: Instance>() -> Result<()>
+where
+    WasiInstance::Engine: Default + Send + Sync + Clone,
+{
+    let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(HELLO_WORLD)?
+        .build()?
+        .start()?
+        .wait(TIMEOUT)?;
+
+    ensure!(exit_code == 0, "exit code {exit_code}");
+    ensure!(stdout == "hello world\n", "stdout {stdout:?}");
+    Ok(())
+}
+
+fn stdio_args_env<WasiInstance: Instance>() -> Result<()>
+where
+    WasiInstance::Engine: Default + Send + Sync + Clone,
+{
+    let (exit_code, stdout, stderr) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(ECHO)?
+        .with_args(["a", "b"])
+        .with_env("GREETING", "hello")
+        .with_stdin("input")?
+        .build()?
+        .start()?
+        .wait(TIMEOUT)?;
+
+    ensure!(exit_code == 0, "exit code {exit_code}");
+    ensure!(
+        stdout == "args: a b\nenv: hello\nstdin: input\n",
+        "stdout {stdout:?}"
+    );
+    ensure!(stderr == "stderr: hello\n", "stderr {stderr:?}");
+    Ok(())
+}
+
+fn exit_code<WasiInstance: Instance>() -> Result<()>
+where
+    WasiInstance::Engine: Default + Send + Sync + Clone,
+{
+    let (exit_code, _, _) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(EXIT_CODE)?
+        .build()?
+        .start()?
+        .wait(TIMEOUT)?;
+
+    ensure!(exit_code == 42, "exit code {exit_code}");
+    Ok(())
+}
+
+fn trap<WasiInstance: Instance>() -> Result<()>
+where
+    WasiInstance::Engine: Default + Send + Sync + Clone,
+{
+    let (exit_code, _, _) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(UNREACHABLE)?
+        .build()?
+        .start()?
+        .wait(TIMEOUT)?;
+
+    ensure!(exit_code != 0, "a trap exited successfully");
+    Ok(())
+}
+
+fn custom_entrypoint<WasiInstance: Instance>() -> Result<()>
+where
+    WasiInstance::Engine: Default + Send + Sync + Clone,
+{
+    let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
+        .with_start_fn("foo")
+        .with_wasm(CUSTOM_ENTRYPOINT)?
+        .build()?
+        .start()?
+        .wait(TIMEOUT)?;
+
+    ensure!(exit_code == 0, "exit code {exit_code}");
+    ensure!(stdout == "hello world\n", "stdout {stdout:?}");
+    Ok(())
+}
+
+fn p2_command<WasiInstance: Instance>() -> Result<()>
+where
+    WasiInstance::Engine: Default + Send + Sync + Clone,
+{
+    let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(COMPONENT_HELLO_WORLD)?
+        .build()?
+        .start()?
+        .wait(TIMEOUT)?;
+
+    ensure!(exit_code == 0, "exit code {exit_code}");
+    ensure!(stdout == "Hello, world!\n", "stdout {stdout:?}");
+    Ok(())
+}
+
+fn http_proxy<WasiInstance: Instance>() -> Result<()>
+where
+    WasiInstance::Engine: Default + Send + Sync + Clone,
+{
+    let srv = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(HELLO_WASI_HTTP)?
+        .with_host_network()
+        .build()?;
+
+    srv.start()?;
+    let response =
+        http_helpers::get_with_retry("http://127.0.0.1:8080/", 10, Duration::from_secs(1));
+
+    // Stop the server whatever the response
+    let stopped = srv.ctrl_c().and_then(|srv| srv.wait(TIMEOUT));
+
+    let response = response?;
+    ensure!(response.status == 200, "status {}", response.status);
+    ensure!(
+        response.body == "Hello, this is your first wasi:http/proxy world!\n",
+        "body {:?}",
+        response.body
+    );
+    let (exit_code, _, _) = stopped?;
+    ensure!(exit_code == 0, "exit code {exit_code} after ctrl-c");
+    Ok(())
+}
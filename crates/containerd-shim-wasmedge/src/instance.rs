@@ -1,37 +1,64 @@
 use anyhow::{Context, Result};
-use containerd_shim_wasm::container::{Engine, Entrypoint, Instance, RuntimeContext, Stdio};
-use wasmedge_sdk::config::{ConfigBuilder, HostRegistrationConfigOptions};
+use containerd_shim_wasm::container::{
+    resolve_func, Engine, Entrypoint, Instance, RuntimeContext, Stdio, WasiDescriptor,
+};
+use wasmedge_sdk::config::{
+    ConfigBuilder, HostRegistrationConfigOptions, RuntimeConfigOptions, StatisticsConfigOptions,
+};
 use wasmedge_sdk::plugin::PluginManager;
-use wasmedge_sdk::VmBuilder;
+use wasmedge_sdk::{Vm, VmBuilder};
+
+use crate::options::WasmEdgeOptions;
 
 pub type WasmEdgeInstance = Instance<WasmEdgeEngine>;
 
 #[derive(Clone)]
 pub struct WasmEdgeEngine {
-    vm: wasmedge_sdk::Vm,
+    vm: Vm,
 }
 
 impl Default for WasmEdgeEngine {
     fn default() -> Self {
-        let host_options = HostRegistrationConfigOptions::default();
-        let host_options = host_options.wasi(true);
-        let config = ConfigBuilder::default()
-            .with_host_registration_config(host_options)
-            .build()
-            .unwrap();
-        let vm = VmBuilder::new().with_config(config).build().unwrap();
+        let vm = build_vm(&WasmEdgeOptions::default()).unwrap();
         Self { vm }
     }
 }
 
+/// A VM with WASI, limited by `options`.
+fn build_vm(options: &WasmEdgeOptions) -> Result<Vm> {
+    let host_options = HostRegistrationConfigOptions::default();
+    let host_options = host_options.wasi(true);
+    let mut config = ConfigBuilder::default().with_host_registration_config(host_options);
+    if let Some(pages) = options.max_memory_pages() {
+        config =
+            config.with_runtime_config(RuntimeConfigOptions::default().max_memory_pages(pages));
+    }
+    if let Some(fuel) = options.fuel {
+        // Every instruction costs 1 by default, like the fuel of wasmtime
+        let statistics = StatisticsConfigOptions::default()
+            .count_instructions(true)
+            .measure_cost(true)
+            .set_cost_limit(fuel);
+        config = config.with_statistics_config(statistics);
+    }
+    let vm = VmBuilder::new().with_config(config.build()?).build()?;
+    Ok(vm)
+}
+
 impl Engine for WasmEdgeEngine {
     fn name() -> &'static str {
         "wasmedge"
     }
 
     fn run_wasi(&self, ctx: &impl RuntimeContext, stdio: Stdio) -> Result<i32> {
-        let args = ctx.args();
-        let envs = ctx.envs();
+        let wasi = WasiDescriptor::from_ctx(ctx)?;
+        let envs: Vec<_> = wasi.envs.iter().map(|(k, v)| format!("{k}={v}")).collect();
+        // The preopens of WasmEdge are `guest:host`
+        let preopens: Vec<_> = wasi
+            .preopens
+            .iter()
+            .map(|p| format!("{}:{}", p.guest, p.host.display()))
+            .collect();
         let Entrypoint {
             source,
             func,
@@ -39,13 +66,19 @@ impl Engine for WasmEdgeEngine {
             name,
         } = ctx.entrypoint();
 
-        let mut vm = self.vm.clone();
+        let options: WasmEdgeOptions = ctx.options()?;
+        let mut vm = if options.is_limited() {
+            log::info!("limiting the VM: {options:?}");
+            build_vm(&options)?
+        } else {
+            self.vm.clone()
+        };
         vm.wasi_module_mut()
             .context("Not found wasi module")?
             .initialize(
-                Some(args.iter().map(String::as_str).collect()),
+                Some(wasi.args.iter().map(String::as_str).collect()),
                 Some(envs.iter().map(String::as_str).collect()),
-                Some(vec!["/:/"]),
+                Some(preopens.iter().map(String::as_str).collect()),
             );
 
         let mod_name = name.unwrap_or_else(|| "main".to_string());
@@ -58,6 +91,9 @@ impl Engine for WasmEdgeEngine {
             .register_module_from_bytes(&mod_name, wasm_bytes)
             .context("registering module")?;
 
+        let module = vm.named_module(&mod_name)?;
+        let func = resolve_func(ctx, &func, |name| module.func(name).is_ok());
+
         stdio.redirect()?;
 
         log::debug!("running with method {func:?}");
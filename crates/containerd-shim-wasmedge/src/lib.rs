@@ -1,4 +1,5 @@
 pub mod instance;
+pub mod options;
 
 pub use instance::WasmEdgeInstance;
 
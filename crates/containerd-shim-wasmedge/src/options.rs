@@ -0,0 +1,78 @@
+//! Options of the wasmedge engine, from the runtime options of containerd.
+//!
+//! The options are a JSON object, read from the file set as `ConfigPath` in the options of the
+//! runtime, and overridden by the `runwasi.io/engine-options` annotation of a container:
+//!
+//! ```json
+//! { "max_memory_size": 33554432, "fuel": 100000000 }
+//! ```
+//!
+//! * `max_memory_size`: maximum size in bytes of each linear memory, rounded down to wasm pages.
+//! * `fuel`: number of instructions the guest may execute before it traps.
+//!
+//! The fields have the same meaning as in the resource profiles of the wasmtime shim, so that
+//! the guests are limited alike whichever shim runs them. The memory and CPU of the container
+//! itself are limited by its cgroup, with any shim.
+
+use serde::Deserialize;
+
+/// Size of a wasm page.
+const PAGE_SIZE: u64 = 64 * 1024;
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WasmEdgeOptions {
+    /// Maximum size in bytes of each linear memory.
+    pub max_memory_size: Option<u64>,
+    /// Number of instructions the guest may execute.
+    pub fuel: Option<u64>,
+}
+
+impl WasmEdgeOptions {
+    /// Whether the options limit the guest, which needs a VM of its own.
+    pub fn is_limited(&self) -> bool {
+        self.max_memory_size.is_some() || self.fuel.is_some()
+    }
+
+    /// The maximum number of pages of each linear memory.
+    pub fn max_memory_pages(&self) -> Option<u32> {
+        let pages = self.max_memory_size? / PAGE_SIZE;
+        Some(pages.clamp(1, u32::MAX.into()) as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deserialize() -> anyhow::Result<()> {
+        let options: WasmEdgeOptions =
+            serde_json::from_str(r#"{ "max_memory_size": 1048576, "fuel": 1000 }"#)?;
+        assert!(options.is_limited());
+        assert_eq!(options.max_memory_pages(), Some(16));
+        assert_eq!(options.fuel, Some(1000));
+
+        let options: WasmEdgeOptions = serde_json::from_str("{}")?;
+        assert!(!options.is_limited());
+        assert_eq!(options.max_memory_pages(), None);
+
+        assert!(serde_json::from_str::<WasmEdgeOptions>(r#"{ "memory": 1 }"#).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_memory_pages() {
+        let pages = |size| {
+            WasmEdgeOptions {
+                max_memory_size: Some(size),
+                fuel: None,
+            }
+            .max_memory_pages()
+        };
+        assert_eq!(pages(PAGE_SIZE * 3 + 1), Some(3));
+        assert_eq!(pages(1), Some(1));
+        assert_eq!(pages(u64::MAX), Some(u32::MAX));
+    }
+}
@@ -1,6 +1,7 @@
 use std::time::Duration;
 
 //use containerd_shim_wasm::sandbox::Instance;
+use containerd_shim_wasm::testing::conformance::Conformance;
 use containerd_shim_wasm::testing::modules::*;
 use containerd_shim_wasm::testing::WasiTest;
 use serial_test::serial;
@@ -153,3 +154,9 @@ fn check_dynamic_linking() {
     let current_exe = std::env::current_exe().unwrap().canonicalize().unwrap();
     assert!(wasmedge_path != current_exe);
 }
+
+#[test]
+#[serial]
+fn test_conformance() -> anyhow::Result<()> {
+    Conformance::new().run::<WasiInstance>()
+}
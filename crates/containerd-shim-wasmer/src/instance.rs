@@ -1,5 +1,8 @@
-use anyhow::Result;
-use containerd_shim_wasm::container::{Engine, Entrypoint, Instance, RuntimeContext, Stdio};
+use anyhow::{bail, Result};
+use containerd_shim_wasm::container::{
+    resolve_func, Engine, Entrypoint, Instance, RuntimeContext, Stdio, WasiDescriptor,
+    WasmBinaryType,
+};
 use tokio::runtime::Handle;
 use wasmer::{Module, Store};
 use wasmer_wasix::virtual_fs::host_fs::FileSystem;
@@ -18,15 +21,12 @@ impl Engine for WasmerEngine {
     }
 
     fn run_wasi(&self, ctx: &impl RuntimeContext, stdio: Stdio) -> Result<i32> {
-        let args = ctx.args();
-        let envs = ctx
-            .envs()
-            .iter()
-            .map(|v| match v.split_once('=') {
-                None => (v.to_string(), String::new()),
-                Some((key, value)) => (key.to_string(), value.to_string()),
-            })
-            .collect::<Vec<_>>();
+        let WasiDescriptor {
+            args,
+            envs,
+            preopens,
+            ..
+        } = WasiDescriptor::from_ctx(ctx)?;
         let Entrypoint {
             source,
             func,
@@ -40,6 +40,9 @@ impl Engine for WasmerEngine {
         let mut store = Store::new(self.engine.clone());
 
         let wasm_bytes = source.as_bytes()?;
+        if let Some(WasmBinaryType::Component) = WasmBinaryType::from_bytes(&wasm_bytes) {
+            bail!("wasmer does not support the component model, use the wasmtime shim instead");
+        }
         let module = Module::from_binary(&store, &wasm_bytes)?;
 
         let runtime = tokio::runtime::Builder::new_multi_thread()
@@ -49,16 +52,38 @@ impl Engine for WasmerEngine {
 
         log::info!("Creating `WasiEnv`...: args {args:?}, envs: {envs:?}");
         let fs = FileSystem::new(Handle::current(), "/")?;
-        let (instance, wasi_env) = WasiEnv::builder(mod_name)
+        let mut builder = WasiEnv::builder(mod_name)
             .args(&args[1..])
             .envs(envs)
-            .fs(Box::new(fs))
-            .preopen_dir("/")?
-            .instantiate(module, &mut store)?;
+            .fs(Box::new(fs));
+        // The host filesystem of wasmer is rooted at `/`, so the guest paths are the host ones
+        for preopen in preopens {
+            builder = builder.preopen_dir(preopen.host)?;
+        }
+
+        if cfg!(feature = "wasix") && func == "_start" && wasmer_wasix::is_wasix_module(&module) {
+            // The WASIX runner spawns the threads of the guest and handles its forks
+            log::info!("redirect stdio");
+            stdio.redirect()?;
+
+            log::info!("Running WASIX module");
+            return match builder.run_with_store(module, &mut store) {
+                Ok(()) => Ok(0),
+                Err(err) => match err.as_exit_code() {
+                    Some(code) => Ok(code.raw()),
+                    None => Err(err.into()),
+                },
+            };
+        }
+
+        let (instance, wasi_env) = builder.instantiate(module, &mut store)?;
 
         log::info!("redirect stdio");
         stdio.redirect()?;
 
+        let func = resolve_func(ctx, &func, |name| {
+            instance.exports.get_function(name).is_ok()
+        });
         log::info!("Running {func:?}");
         let start = instance.exports.get_function(&func)?;
         wasi_env.data(&store).thread.set_status_running();
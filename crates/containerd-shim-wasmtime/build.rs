@@ -0,0 +1,98 @@
+use std::env;
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+
+/// A `[[package]]` entry of a `Cargo.lock`.
+#[derive(Default)]
+struct Package<'a> {
+    name: &'a str,
+    version: &'a str,
+    dependencies: Vec<&'a str>,
+}
+
+fn main() -> Result<()> {
+    // The lockfile of the workspace, or of the package when it is installed on its own
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR")?);
+    let lockfile = manifest_dir
+        .ancestors()
+        .map(|dir| dir.join("Cargo.lock"))
+        .find(|path| path.is_file())
+        .context("failed to find the Cargo.lock of the build")?;
+    println!("cargo:rerun-if-changed={}", lockfile.display());
+
+    let lock = std::fs::read_to_string(&lockfile)
+        .with_context(|| format!("failed to read {}", lockfile.display()))?;
+    let version = wasmtime_version(&lock)?;
+    println!("cargo:rustc-env=WASMTIME_VERSION={version}");
+
+    Ok(())
+}
+
+/// The version of `wasmtime` the shim package is resolved against in `lock`.
+fn wasmtime_version(lock: &str) -> Result<&str> {
+    let packages = packages(lock);
+
+    let name = env::var("CARGO_PKG_NAME")?;
+    let version = env::var("CARGO_PKG_VERSION")?;
+    let Some(shim) = packages
+        .iter()
+        .find(|p| p.name == name && p.version == version)
+    else {
+        bail!("{name} {version} is not in the Cargo.lock");
+    };
+
+    // The dependency only has a version when several versions of the crate are locked
+    let Some(dependency) = shim
+        .dependencies
+        .iter()
+        .find(|dep| dep.split(' ').next() == Some("wasmtime"))
+    else {
+        bail!("{name} doesn't depend on wasmtime in the Cargo.lock");
+    };
+    if let Some(version) = dependency.split(' ').nth(1) {
+        return Ok(version);
+    }
+
+    match packages.iter().find(|p| p.name == "wasmtime") {
+        Some(wasmtime) => Ok(wasmtime.version),
+        None => bail!("wasmtime is not in the Cargo.lock"),
+    }
+}
+
+fn packages(lock: &str) -> Vec<Package<'_>> {
+    lock.split("[[package]]")
+        .skip(1)
+        .map(|entry| {
+            let mut package = Package::default();
+            let mut dependencies = false;
+            for line in entry.lines().map(str::trim) {
+                if dependencies {
+                    match line {
+                        "]" => dependencies = false,
+                        dep => package
+                            .dependencies
+                            .push(dep.trim_end_matches(',').trim_matches('"')),
+                    }
+                } else if let Some(name) = field(line, "name") {
+                    package.name = name;
+                } else if let Some(version) = field(line, "version") {
+                    package.version = version;
+                } else if line == "dependencies = [" {
+                    dependencies = true;
+                }
+            }
+            package
+        })
+        .collect()
+}
+
+/// The value of the `key = "value"` line.
+fn field<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    line.strip_prefix(key)?
+        .trim_start()
+        .strip_prefix('=')?
+        .trim()
+        .strip_prefix('"')?
+        .strip_suffix('"')
+}
@@ -0,0 +1,314 @@
+//! Audit log of the filesystem and network operations of the guest.
+//!
+//! Before granting a third-party component broader capabilities, it helps to know what it does
+//! with the ones it has. With the `runwasi.io/audit` annotation set to `true`, the shim logs a
+//! JSON record for:
+//! * every file or directory opened by a component outside of the paths listed in the
+//!   `runwasi.io/audit-allowed-paths` annotation, e.g. `/app,/etc/ssl`,
+//! * every TCP connection and UDP association attempted by the guest, with its destination,
+//!   and whether the socket policy allowed it,
+//! * every outgoing HTTP request of a component, with its destination.
+//!
+//! The records are rate-limited to 100 per second, a warning telling how many were dropped.
+//! Wasm modules open their files through the WASI preview 1 implementation of wasmtime, which
+//! can't be audited.
+//!
+//! To help writing minimal capability policies, the `runwasi.io/usage-report` annotation set to
+//! `true` logs a summary of what the guest used when it exits instead: the paths it opened, the
+//! hosts it contacted, and the interfaces it imports.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use containerd_shim_wasm::container::RuntimeContext;
+use hyper::Uri;
+use serde_json::{json, Value};
+use wasmtime::component::Component;
+use wasmtime::Engine;
+use wasmtime_wasi::SocketAddrUse;
+
+pub const AUDIT_ANNOTATION: &str = "runwasi.io/audit";
+pub const AUDIT_ALLOWED_PATHS_ANNOTATION: &str = "runwasi.io/audit-allowed-paths";
+pub const USAGE_REPORT_ANNOTATION: &str = "runwasi.io/usage-report";
+
+const MAX_RECORDS: u32 = 100;
+/// The paths in the usage report, beyond which it is truncated.
+const MAX_PATHS: usize = 1000;
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Fixed window rate limit of the records.
+struct Limiter {
+    start: Instant,
+    records: u32,
+    dropped: u64,
+}
+
+impl Limiter {
+    /// Whether a record may be logged at `now`, and how many were dropped in the previous
+    /// window if it just ended.
+    fn admit(&mut self, now: Instant) -> (bool, u64) {
+        let mut dropped = 0;
+        if now.duration_since(self.start) >= WINDOW {
+            dropped = std::mem::take(&mut self.dropped);
+            self.start = now;
+            self.records = 0;
+        }
+        if self.records >= MAX_RECORDS {
+            self.dropped += 1;
+            return (false, dropped);
+        }
+        self.records += 1;
+        (true, dropped)
+    }
+}
+
+/// What the guest used, for the usage report.
+#[derive(Default)]
+struct Usage {
+    /// The paths opened, and whether for writing.
+    paths: BTreeMap<PathBuf, bool>,
+    truncated: bool,
+    hosts: BTreeSet<String>,
+    interfaces: BTreeSet<String>,
+}
+
+impl Usage {
+    fn open(&mut self, path: &Path, write: bool) {
+        if let Some(written) = self.paths.get_mut(path) {
+            *written |= write;
+        } else if self.paths.len() < MAX_PATHS {
+            self.paths.insert(path.to_path_buf(), write);
+        } else {
+            self.truncated = true;
+        }
+    }
+
+    fn report(&self) -> Value {
+        let paths: BTreeMap<_, _> = self
+            .paths
+            .iter()
+            .map(|(path, write)| (path.display().to_string(), if *write { "rw" } else { "r" }))
+            .collect();
+        json!({
+            "paths": paths,
+            "truncated": self.truncated,
+            "hosts": self.hosts,
+            "interfaces": self.interfaces,
+        })
+    }
+}
+
+/// The audit log and the usage report of a container.
+pub struct Audit {
+    log: bool,
+    allowed_paths: Vec<PathBuf>,
+    limiter: Mutex<Limiter>,
+    usage: Option<Mutex<Usage>>,
+}
+
+impl Drop for Audit {
+    // The audit is dropped together with the guest contexts, i.e., when the guest exits.
+    fn drop(&mut self) {
+        if let Some(usage) = &self.usage {
+            tracing::info!("usage: {}", usage.lock().unwrap().report());
+        }
+    }
+}
+
+impl Audit {
+    /// Audit the operations on the files outside of `allowed_paths`, and report the usage of
+    /// the guest if `usage_report`.
+    pub fn new(log: bool, allowed_paths: Vec<PathBuf>, usage_report: bool) -> Self {
+        Self {
+            log,
+            allowed_paths,
+            limiter: Mutex::new(Limiter {
+                start: Instant::now(),
+                records: 0,
+                dropped: 0,
+            }),
+            usage: usage_report.then(Default::default),
+        }
+    }
+
+    /// The audit of the container, if its annotations enable the audit log or the usage report.
+    pub fn from_ctx(ctx: &impl RuntimeContext) -> Result<Option<Arc<Self>>> {
+        let annotations = ctx.annotations();
+        let enabled = |annotation: &str| {
+            annotations
+                .get(annotation)
+                .map(|v| v.trim().parse())
+                .transpose()
+                .with_context(|| format!("invalid {annotation}"))
+                .map(Option::unwrap_or_default)
+        };
+        let log = enabled(AUDIT_ANNOTATION)?;
+        let usage_report = enabled(USAGE_REPORT_ANNOTATION)?;
+        if !log && !usage_report {
+            return Ok(None);
+        }
+
+        let allowed_paths = annotations
+            .get(AUDIT_ALLOWED_PATHS_ANNOTATION)
+            .map(|v| parse_paths(v))
+            .transpose()
+            .context(AUDIT_ALLOWED_PATHS_ANNOTATION)?
+            .unwrap_or_default();
+        Ok(Some(Arc::new(Self::new(log, allowed_paths, usage_report))))
+    }
+
+    fn is_allowed(&self, path: &Path) -> bool {
+        self.allowed_paths.iter().any(|dir| path.starts_with(dir))
+    }
+
+    /// Audit the opening of the guest `path`, for writing if `write`.
+    pub fn open(&self, path: &Path, write: bool, ok: bool) {
+        if let (Some(usage), true) = (&self.usage, ok) {
+            usage.lock().unwrap().open(path, write);
+        }
+        if !self.log || self.is_allowed(path) {
+            return;
+        }
+        self.record(json!({
+            "event": "open",
+            "path": path.display().to_string(),
+            "write": write,
+            "ok": ok,
+        }));
+    }
+
+    /// Audit a connection attempt of the guest to `addr`.
+    pub fn connect(&self, addr: SocketAddr, addr_use: SocketAddrUse, allowed: bool) {
+        let protocol = match addr_use {
+            SocketAddrUse::TcpConnect => "tcp",
+            SocketAddrUse::UdpConnect => "udp",
+            _ => return,
+        };
+        self.used_host(format!("{protocol}:{addr}"));
+        self.record(json!({
+            "event": "connect",
+            "protocol": protocol,
+            "destination": addr.to_string(),
+            "allowed": allowed,
+        }));
+    }
+
+    /// Audit an outgoing HTTP request of the guest.
+    pub fn http_request(&self, method: &str, uri: &Uri) {
+        if let Some(authority) = uri.authority() {
+            self.used_host(format!("http:{authority}"));
+        }
+        self.record(json!({
+            "event": "http-request",
+            "method": method,
+            "destination": uri.to_string(),
+        }));
+    }
+
+    /// Report the interfaces, or the functions of a module, imported by the guest.
+    pub fn imports(&self, imports: impl IntoIterator<Item = String>) {
+        if let Some(usage) = &self.usage {
+            usage.lock().unwrap().interfaces.extend(imports);
+        }
+    }
+
+    fn used_host(&self, host: String) {
+        if let Some(usage) = &self.usage {
+            usage.lock().unwrap().hosts.insert(host);
+        }
+    }
+
+    fn record(&self, record: Value) {
+        if !self.log {
+            return;
+        }
+        let (admitted, dropped) = self.limiter.lock().unwrap().admit(Instant::now());
+        if dropped > 0 {
+            tracing::warn!("audit: {dropped} records dropped by the rate limit");
+        }
+        if admitted {
+            tracing::info!("audit: {record}");
+        }
+    }
+}
+
+/// The interfaces imported by `component`.
+pub(crate) fn component_imports(engine: &Engine, component: &Component) -> Vec<String> {
+    let imports = component.component_type().imports(engine);
+    imports.map(|(name, _)| name.to_string()).collect()
+}
+
+fn parse_paths(value: &str) -> Result<Vec<PathBuf>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|path| !path.is_empty())
+        .map(|path| {
+            let path = PathBuf::from(path);
+            anyhow::ensure!(path.is_absolute(), "{path:?} is not absolute");
+            Ok(path)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowed_paths() {
+        let paths = parse_paths("/app, /etc/ssl,").unwrap();
+        let audit = Audit::new(true, paths, false);
+        assert!(audit.is_allowed(Path::new("/app")));
+        assert!(audit.is_allowed(Path::new("/etc/ssl/certs/ca.pem")));
+        assert!(!audit.is_allowed(Path::new("/etc/passwd")));
+        assert!(!audit.is_allowed(Path::new("/application")));
+
+        assert!(parse_paths("app").is_err());
+    }
+
+    #[test]
+    fn test_usage_report() {
+        let audit = Audit::new(false, vec![], true);
+        audit.open(Path::new("/etc/app.toml"), false, true);
+        audit.open(Path::new("/data/out"), false, true);
+        audit.open(Path::new("/data/out"), true, true);
+        audit.open(Path::new("/missing"), false, false);
+        audit.http_request("GET", &"https://example.com/a?b".parse().unwrap());
+        audit.imports(["wasi:cli/environment@0.2.0".to_string()]);
+
+        let report = audit.usage.as_ref().unwrap().lock().unwrap().report();
+        assert_eq!(
+            report,
+            json!({
+                "paths": {"/data/out": "rw", "/etc/app.toml": "r"},
+                "truncated": false,
+                "hosts": ["http:example.com"],
+                "interfaces": ["wasi:cli/environment@0.2.0"],
+            })
+        );
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let start = Instant::now();
+        let mut limiter = Limiter {
+            start,
+            records: 0,
+            dropped: 0,
+        };
+        for _ in 0..MAX_RECORDS {
+            assert_eq!(limiter.admit(start), (true, 0));
+        }
+        assert_eq!(limiter.admit(start), (false, 0));
+        assert_eq!(limiter.admit(start + WINDOW / 2), (false, 0));
+
+        // The next window reports the dropped records
+        assert_eq!(limiter.admit(start + WINDOW), (true, 2));
+        assert_eq!(limiter.admit(start + WINDOW), (true, 0));
+    }
+}
@@ -0,0 +1,165 @@
+//! Fault injection for resilience testing.
+//!
+//! Faults are only injected by shims built with the `chaos` feature, and are enabled with
+//! environment variables of the container:
+//! * `RUNWASI_CHAOS_COMPILE_FAILURE`: when `true`, loading the module or component fails.
+//! * `RUNWASI_CHAOS_INSTANTIATION_DELAY_MS`: delay of every instantiation of the component for
+//!   an HTTP request or a TCP connection.
+//! * `RUNWASI_CHAOS_TRAP_EVERY`: every n-th HTTP request fails as if the guest trapped.
+//! * `RUNWASI_CHAOS_NETWORK_ERROR_PERCENT`: percentage of the outgoing connections of the guest
+//!   that are refused.
+//!
+//! Shims built without the feature ignore these variables, so that they can't be used to disrupt
+//! a production workload.
+
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Result};
+use containerd_shim_wasm::container::RuntimeContext;
+
+use crate::instance::envs_from_ctx;
+
+const COMPILE_FAILURE_ENV: &str = "RUNWASI_CHAOS_COMPILE_FAILURE";
+const INSTANTIATION_DELAY_ENV: &str = "RUNWASI_CHAOS_INSTANTIATION_DELAY_MS";
+const TRAP_EVERY_ENV: &str = "RUNWASI_CHAOS_TRAP_EVERY";
+const NETWORK_ERROR_PERCENT_ENV: &str = "RUNWASI_CHAOS_NETWORK_ERROR_PERCENT";
+
+#[derive(Debug, Default)]
+pub struct Chaos {
+    compile_failure: bool,
+    instantiation_delay: Option<Duration>,
+    trap_every: Option<u64>,
+    network_error_percent: u64,
+    requests: AtomicU64,
+    connections: AtomicU64,
+}
+
+impl Chaos {
+    /// Read the faults to inject in the container.
+    pub fn from_ctx(ctx: &impl RuntimeContext) -> Result<Arc<Self>> {
+        if !cfg!(feature = "chaos") {
+            return Ok(Arc::default());
+        }
+        let envs = envs_from_ctx(ctx);
+        let chaos = Self::from_env(envs.iter().map(|(k, v)| (k.as_str(), v.as_str())))?;
+        if chaos.is_enabled() {
+            tracing::warn!("injecting faults: {chaos:?}");
+        }
+        Ok(Arc::new(chaos))
+    }
+
+    fn from_env<'a>(envs: impl IntoIterator<Item = (&'a str, &'a str)>) -> Result<Self> {
+        let mut chaos = Self::default();
+        for (key, value) in envs {
+            match key {
+                COMPILE_FAILURE_ENV => chaos.compile_failure = parse(key, value)?,
+                INSTANTIATION_DELAY_ENV => {
+                    chaos.instantiation_delay = Some(Duration::from_millis(parse(key, value)?))
+                }
+                TRAP_EVERY_ENV => {
+                    let every = parse(key, value)?;
+                    ensure!(every > 0, "{key} must be positive");
+                    chaos.trap_every = Some(every);
+                }
+                NETWORK_ERROR_PERCENT_ENV => {
+                    let percent = parse(key, value)?;
+                    ensure!(percent <= 100, "{key} must be at most 100");
+                    chaos.network_error_percent = percent;
+                }
+                _ => {}
+            }
+        }
+        Ok(chaos)
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.compile_failure
+            || self.instantiation_delay.is_some()
+            || self.trap_every.is_some()
+            || self.network_error_percent > 0
+    }
+
+    /// Fail the compilation, if enabled.
+    pub fn compile(&self) -> Result<()> {
+        if self.compile_failure {
+            bail!("injected compilation failure");
+        }
+        Ok(())
+    }
+
+    /// Delay the instantiation, if enabled.
+    pub async fn instantiate(&self) {
+        if let Some(delay) = self.instantiation_delay {
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Fail the request, if it's the n-th one.
+    pub fn request(&self) -> Result<()> {
+        let Some(every) = self.trap_every else {
+            return Ok(());
+        };
+        let seq = self.requests.fetch_add(1, Ordering::Relaxed) + 1;
+        if seq % every == 0 {
+            bail!("injected trap on request {seq}");
+        }
+        Ok(())
+    }
+
+    /// Whether outgoing connections are refused at all.
+    pub fn refuses_connections(&self) -> bool {
+        self.network_error_percent > 0
+    }
+
+    /// Whether the next outgoing connection is refused.
+    pub fn refuse_connection(&self) -> bool {
+        if !self.refuses_connections() {
+            return false;
+        }
+        let seq = self.connections.fetch_add(1, Ordering::Relaxed);
+        seq % 100 < self.network_error_percent
+    }
+}
+
+fn parse<T>(key: &str, value: &str) -> Result<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match value.trim().parse() {
+        Ok(value) => Ok(value),
+        Err(e) => bail!("invalid {key} {value:?}: {e}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_env() -> Result<()> {
+        let chaos = Chaos::from_env([
+            (TRAP_EVERY_ENV, "3"),
+            (NETWORK_ERROR_PERCENT_ENV, "50"),
+            ("OTHER", "value"),
+        ])?;
+        assert!(chaos.is_enabled());
+        assert!(chaos.compile().is_ok());
+
+        let traps: Vec<_> = (0..6).map(|_| chaos.request().is_err()).collect();
+        assert_eq!(traps, [false, false, true, false, false, true]);
+
+        let refused = (0..100).filter(|_| chaos.refuse_connection()).count();
+        assert_eq!(refused, 50);
+
+        assert!(!Chaos::from_env([])?.is_enabled());
+        assert!(Chaos::from_env([(TRAP_EVERY_ENV, "0")]).is_err());
+        assert!(Chaos::from_env([(COMPILE_FAILURE_ENV, "yes")]).is_err());
+
+        Ok(())
+    }
+}
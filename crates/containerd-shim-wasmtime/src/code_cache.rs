@@ -0,0 +1,319 @@
+//! Compiled code shared by the containers of a node.
+//!
+//! When `RUNWASI_WASMTIME_CODE_CACHE_DIR` is set in the environment of the shim, the compiled code
+//! of OCI layers is stored in that directory, content-addressed by the precompilation hash of the
+//! engine and the digest of the layer, e.g.:
+//!
+//! ```text
+//! <dir>/<compatibility hash>/sha256-<layer digest>.cwasm
+//! <dir>/<compatibility hash>/sha256-<layer digest>.hmac
+//! ```
+//!
+//! The containers map the files read-only instead of loading a private copy of the code, so the
+//! containers of the same image share the code pages.
+//!
+//! The files are loaded as native code, so no container may write to them:
+//! * The directory must be owned by the user of the shim and only accessible to it, and must not
+//!   be mounted in the containers. The shim creates it with these permissions, and refuses to use
+//!   it otherwise.
+//! * The shim opens the directory when it starts, before creating the containers. The container
+//!   processes reach it through the inherited descriptor, which their guests can't use.
+//! * Every file comes with an HMAC-SHA256 of the compatibility hash, the digest of the layer and
+//!   the code, keyed by a secret in `<dir>/key`. The code is only loaded when its HMAC matches, and
+//!   from the very file that was authenticated.
+//!
+//! When the cache is not available, e.g. to containers running as another user than the shim, the
+//! code is loaded as usual.
+
+use std::fs::{DirBuilder, File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock};
+
+use anyhow::{anyhow, ensure, Context, Result};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+pub const CODE_CACHE_DIR_ENV: &str = "RUNWASI_WASMTIME_CODE_CACHE_DIR";
+
+/// Length of the secret key of the HMACs, in bytes.
+const KEY_LEN: usize = 32;
+
+static CODE_CACHE: LazyLock<Option<Arc<CodeCache>>> = LazyLock::new(|| {
+    let dir = std::env::var_os(CODE_CACHE_DIR_ENV)?;
+    match CodeCache::open(Path::new(&dir)) {
+        Ok(cache) => Some(Arc::new(cache)),
+        Err(e) => {
+            tracing::warn!("code cache {dir:?} is not available: {e:?}");
+            None
+        }
+    }
+});
+
+pub(crate) struct CodeCache {
+    /// The directory of the cache, inherited by the container processes.
+    dir: File,
+    key: [u8; KEY_LEN],
+}
+
+impl CodeCache {
+    /// The code cache of the shim, if any.
+    ///
+    /// The cache is opened by the first call, which must happen in the shim, before creating the
+    /// containers: their processes don't have access to the directory.
+    pub fn shared() -> Option<Arc<Self>> {
+        CODE_CACHE.clone()
+    }
+
+    /// Open the cache in `dir`, creating it if needed.
+    fn open(dir: &Path) -> Result<Self> {
+        DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+        let dir = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECTORY)
+            .open(dir)?;
+
+        let metadata = dir.metadata()?;
+        // SAFETY: geteuid always succeeds
+        let euid = unsafe { libc::geteuid() };
+        ensure!(
+            metadata.uid() == euid,
+            "the directory must be owned by the user of the shim"
+        );
+        ensure!(
+            metadata.mode() & 0o077 == 0,
+            "the directory must only be accessible to its owner"
+        );
+
+        let mut cache = Self {
+            dir,
+            key: [0; KEY_LEN],
+        };
+        cache.key = cache.load_key()?;
+        Ok(cache)
+    }
+
+    /// Path of `name` in the directory of the cache, through the inherited descriptor.
+    fn path(&self, name: impl AsRef<Path>) -> PathBuf {
+        fd_path(&self.dir).join(name)
+    }
+
+    /// The secret key of the HMACs, generated by the first shim using the cache.
+    fn load_key(&self) -> Result<[u8; KEY_LEN]> {
+        let path = self.path("key");
+        if !path.exists() {
+            let mut key = [0; KEY_LEN];
+            File::open("/dev/urandom")?.read_exact(&mut key)?;
+            let tmp = self.path(format!("key.{}.tmp", std::process::id()));
+            write_private(&tmp, &key)?;
+            // Only the first of the shims starting together creates the key
+            let linked = std::fs::hard_link(&tmp, &path);
+            let _ = std::fs::remove_file(&tmp);
+            match linked {
+                Err(e) if e.kind() != ErrorKind::AlreadyExists => return Err(e.into()),
+                _ => {}
+            }
+        }
+
+        let key = std::fs::read(&path)?;
+        key.try_into().map_err(|_| anyhow!("invalid key {path:?}"))
+    }
+
+    /// The entry of the code of the layer with `digest`, compiled by an engine with
+    /// compatibility hash `compat`.
+    pub fn entry(self: &Arc<Self>, compat: &str, digest: &str) -> Entry {
+        Entry {
+            cache: self.clone(),
+            compat: compat.to_string(),
+            digest: digest.to_string(),
+        }
+    }
+}
+
+/// The code of a layer in the cache.
+#[derive(Clone)]
+pub(crate) struct Entry {
+    cache: Arc<CodeCache>,
+    compat: String,
+    digest: String,
+}
+
+impl Entry {
+    /// Path of the file of the entry with `extension`.
+    fn path(&self, extension: &str) -> PathBuf {
+        let name = self.digest.replace([':', '/'], "-");
+        self.cache
+            .path(&self.compat)
+            .join(format!("{name}.{extension}"))
+    }
+
+    fn hmac(&self, code: &[u8]) -> Hmac<Sha256> {
+        let mut hmac =
+            Hmac::<Sha256>::new_from_slice(&self.cache.key).expect("HMAC takes keys of any size");
+        for field in [self.compat.as_bytes(), self.digest.as_bytes()] {
+            hmac.update(&(field.len() as u64).to_le_bytes());
+            hmac.update(field);
+        }
+        hmac.update(code);
+        hmac
+    }
+
+    /// The cached code, if any and authentic.
+    pub fn get(&self) -> Result<Option<CachedCode>> {
+        let path = self.path("cwasm");
+        let mut file = match File::open(&path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            file => file?,
+        };
+        let tag = match std::fs::read(self.path("hmac")) {
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            tag => tag?,
+        };
+
+        let mut code = vec![];
+        file.read_to_end(&mut code)?;
+        self.hmac(&code)
+            .verify_slice(&tag)
+            .map_err(|_| anyhow!("cached code {path:?} is not authentic"))?;
+        Ok(Some(CachedCode { file }))
+    }
+
+    /// Store `code` in the cache.
+    pub fn put(&self, code: &[u8]) -> Result<()> {
+        let path = self.path("cwasm");
+        let dir = path.parent().context("no parent directory")?;
+        DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+
+        let tag = self.hmac(code).finalize().into_bytes();
+        write_atomic(&path, code).with_context(|| format!("failed to write {path:?}"))?;
+        write_atomic(&self.path("hmac"), &tag)?;
+        tracing::info!("cached code {path:?}");
+        Ok(())
+    }
+
+    /// The cached code, compiling it with `compile` unless another container already did.
+    pub fn get_or_compile(&self, compile: impl FnOnce() -> Result<Vec<u8>>) -> Result<CachedCode> {
+        match self.get() {
+            Ok(Some(code)) => {
+                tracing::info!("using cached code {:?}", self.path("cwasm"));
+                return Ok(code);
+            }
+            Ok(None) => {}
+            Err(e) => tracing::warn!("compiling the code again: {e:?}"),
+        }
+
+        self.put(&compile()?)?;
+        self.get()?.context("cached code disappeared")
+    }
+}
+
+/// Authenticated code of the cache.
+pub(crate) struct CachedCode {
+    file: File,
+}
+
+impl CachedCode {
+    /// Path of the authenticated file, which stays the same while `self` is alive.
+    pub fn path(&self) -> PathBuf {
+        fd_path(&self.file)
+    }
+}
+
+fn fd_path(file: &File) -> PathBuf {
+    PathBuf::from(format!("/proc/self/fd/{}", file.as_raw_fd()))
+}
+
+/// Create `path` with `contents`, only accessible to its owner.
+fn write_private(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(contents)?;
+    Ok(())
+}
+
+/// Write `path` so that concurrent readers either see the whole file or no file.
+fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+    let _ = std::fs::remove_file(&tmp);
+    let written = write_private(&tmp, contents).and_then(|()| Ok(std::fs::rename(&tmp, path)?));
+    if written.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    written
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    #[test]
+    fn test_get_or_compile() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let cache = Arc::new(CodeCache::open(&dir.path().join("cache"))?);
+
+        let entry = cache.entry("1234", "sha256:abcd");
+        let code = entry.get_or_compile(|| Ok(b"code".to_vec()))?;
+        assert_eq!(std::fs::read(code.path())?, b"code");
+        assert!(dir.path().join("cache/1234/sha256-abcd.cwasm").exists());
+
+        // The second container doesn't compile again
+        let cached = entry.get_or_compile(|| unreachable!())?;
+        assert_eq!(std::fs::read(cached.path())?, b"code");
+
+        // Engines with another compatibility hash don't share the code
+        let other = cache.entry("5678", "sha256:abcd");
+        let code = other.get_or_compile(|| Ok(b"other".to_vec()))?;
+        assert_eq!(std::fs::read(code.path())?, b"other");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tampered_code() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let cache = Arc::new(CodeCache::open(&dir.path().join("cache"))?);
+        let entry = cache.entry("1234", "sha256:abcd");
+        entry.put(b"code")?;
+
+        // Code planted without the key is rejected
+        std::fs::write(dir.path().join("cache/1234/sha256-abcd.cwasm"), b"evil")?;
+        assert!(entry.get().is_err());
+        let code = entry.get_or_compile(|| Ok(b"code".to_vec()))?;
+        assert_eq!(std::fs::read(code.path())?, b"code");
+
+        // The code of another layer is rejected too
+        let other = cache.entry("1234", "sha256:ef01");
+        std::fs::copy(
+            dir.path().join("cache/1234/sha256-abcd.cwasm"),
+            dir.path().join("cache/1234/sha256-ef01.cwasm"),
+        )?;
+        std::fs::copy(
+            dir.path().join("cache/1234/sha256-abcd.hmac"),
+            dir.path().join("cache/1234/sha256-ef01.hmac"),
+        )?;
+        assert!(other.get().is_err());
+
+        // Another shim uses the same key
+        let reopened = Arc::new(CodeCache::open(&dir.path().join("cache"))?);
+        assert!(reopened.entry("1234", "sha256:abcd").get()?.is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_shared_directory() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let shared = dir.path().join("cache");
+        std::fs::create_dir(&shared)?;
+        std::fs::set_permissions(&shared, std::fs::Permissions::from_mode(0o755))?;
+        assert!(CodeCache::open(&shared).is_err());
+        Ok(())
+    }
+}
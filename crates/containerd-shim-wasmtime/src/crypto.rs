@@ -0,0 +1,1339 @@
+//! The [wasi-crypto](https://github.com/WebAssembly/wasi-crypto) proposal for wasm modules, with
+//! the `crypto` feature.
+//!
+//! Modules import the `wasi_ephemeral_crypto_*` host functions instead of bundling their own
+//! crypto in wasm, which is slower and lacks the CPU extensions used by the host. The functions
+//! follow the witx definitions of the proposal, for the hashes, MACs and signatures:
+//!
+//! * `wasi_ephemeral_crypto_common`: options and array outputs,
+//! * `wasi_ephemeral_crypto_asymmetric_common`: key pairs, public keys and secret keys,
+//! * `wasi_ephemeral_crypto_signatures`: signature and verification states,
+//! * `wasi_ephemeral_crypto_symmetric`: hash and MAC states, keys and tags.
+//!
+//! The key exchange, the symmetric encryption and the managed keys are not implemented. Key pairs
+//! and secret keys are imported and exported as PKCS#8 documents. Public keys use the raw encodings
+//! of wasi-crypto: uncompressed SEC1 points for ECDSA, 32 bytes for Ed25519 and PKCS#1
+//! `RSAPublicKey` documents of 2048 to 8192 bits for RSA. Signatures use the raw encoding, e.g.
+//! fixed-size `r || s` for ECDSA.
+//!
+//! All the algorithms are available by default. The `runwasi.io/crypto-algorithms` annotation
+//! restricts a container to a comma separated list of algorithms, e.g. `SHA-256,Ed25519`.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use containerd_shim_wasm::container::RuntimeContext;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{
+    self, EcdsaKeyPair, EcdsaSigningAlgorithm, Ed25519KeyPair, KeyPair, RsaEncoding, RsaKeyPair,
+    UnparsedPublicKey,
+};
+use ring::{digest, hmac};
+use wasmtime::{Caller, Extern, Linker};
+
+use crate::linker::ModuleCtx;
+
+pub const CRYPTO_ALGORITHMS_ANNOTATION: &str = "runwasi.io/crypto-algorithms";
+
+const COMMON: &str = "wasi_ephemeral_crypto_common";
+const ASYMMETRIC_COMMON: &str = "wasi_ephemeral_crypto_asymmetric_common";
+const SIGNATURES: &str = "wasi_ephemeral_crypto_signatures";
+const SYMMETRIC: &str = "wasi_ephemeral_crypto_symmetric";
+
+/// Maximum number of open handles of a module.
+const MAX_HANDLES: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Sha256,
+    Sha512,
+    Sha512_256,
+    HmacSha256,
+    HmacSha512,
+    EcdsaP256Sha256,
+    EcdsaP384Sha384,
+    Ed25519,
+    RsaPkcs1Sha256,
+    RsaPkcs1Sha512,
+    RsaPssSha256,
+    RsaPssSha512,
+}
+
+const ALGORITHMS: &[(&str, Algorithm)] = &[
+    ("SHA-256", Algorithm::Sha256),
+    ("SHA-512", Algorithm::Sha512),
+    ("SHA-512/256", Algorithm::Sha512_256),
+    ("HMAC/SHA-256", Algorithm::HmacSha256),
+    ("HMAC/SHA-512", Algorithm::HmacSha512),
+    ("ECDSA_P256_SHA256", Algorithm::EcdsaP256Sha256),
+    ("ECDSA_P384_SHA384", Algorithm::EcdsaP384Sha384),
+    ("Ed25519", Algorithm::Ed25519),
+    ("RSA_PKCS1_2048_SHA256", Algorithm::RsaPkcs1Sha256),
+    ("RSA_PKCS1_2048_SHA512", Algorithm::RsaPkcs1Sha512),
+    ("RSA_PSS_2048_SHA256", Algorithm::RsaPssSha256),
+    ("RSA_PSS_2048_SHA512", Algorithm::RsaPssSha512),
+];
+
+impl Algorithm {
+    pub fn from_name(name: &str) -> Option<Self> {
+        ALGORITHMS
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, algorithm)| *algorithm)
+    }
+
+    fn digest(&self) -> Option<&'static digest::Algorithm> {
+        match self {
+            Algorithm::Sha256 => Some(&digest::SHA256),
+            Algorithm::Sha512 => Some(&digest::SHA512),
+            Algorithm::Sha512_256 => Some(&digest::SHA512_256),
+            _ => None,
+        }
+    }
+
+    fn hmac(&self) -> Option<hmac::Algorithm> {
+        match self {
+            Algorithm::HmacSha256 => Some(hmac::HMAC_SHA256),
+            Algorithm::HmacSha512 => Some(hmac::HMAC_SHA512),
+            _ => None,
+        }
+    }
+
+    fn verification(&self) -> Option<&'static dyn signature::VerificationAlgorithm> {
+        match self {
+            Algorithm::EcdsaP256Sha256 => Some(&signature::ECDSA_P256_SHA256_FIXED),
+            Algorithm::EcdsaP384Sha384 => Some(&signature::ECDSA_P384_SHA384_FIXED),
+            Algorithm::Ed25519 => Some(&signature::ED25519),
+            Algorithm::RsaPkcs1Sha256 => Some(&signature::RSA_PKCS1_2048_8192_SHA256),
+            Algorithm::RsaPkcs1Sha512 => Some(&signature::RSA_PKCS1_2048_8192_SHA512),
+            Algorithm::RsaPssSha256 => Some(&signature::RSA_PSS_2048_8192_SHA256),
+            Algorithm::RsaPssSha512 => Some(&signature::RSA_PSS_2048_8192_SHA512),
+            _ => None,
+        }
+    }
+}
+
+/// The `crypto_errno` of wasi-crypto.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum CryptoErrno {
+    GuestError = 1,
+    NotImplemented = 2,
+    UnsupportedFeature = 3,
+    ProhibitedOperation = 4,
+    UnsupportedEncoding = 5,
+    UnsupportedAlgorithm = 6,
+    UnsupportedOption = 7,
+    InvalidKey = 8,
+    InvalidLength = 9,
+    VerificationFailed = 10,
+    RngError = 11,
+    AlgorithmFailure = 12,
+    InvalidHandle = 15,
+    Overflow = 16,
+    TooManyHandles = 18,
+    KeyNotSupported = 19,
+    KeyRequired = 20,
+    InvalidTag = 21,
+    InvalidOperation = 22,
+    IncompatibleKeys = 29,
+}
+
+type CryptoResult<T> = Result<T, CryptoErrno>;
+
+// The `algorithm_type` of wasi-crypto.
+const ALGORITHM_TYPE_SIGNATURES: i32 = 0;
+const ALGORITHM_TYPE_SYMMETRIC: i32 = 1;
+
+// The `keypair_encoding`, `publickey_encoding`, `secretkey_encoding` and `signature_encoding` of
+// wasi-crypto.
+const ENCODING_RAW: i32 = 0;
+const ENCODING_PKCS8: i32 = 1;
+const ENCODING_SEC: i32 = 3;
+
+/// The crypto of a container, restricted to the allowed algorithms.
+pub struct Crypto {
+    allowed: Vec<Algorithm>,
+    rng: SystemRandom,
+}
+
+impl Default for Crypto {
+    fn default() -> Self {
+        Self::new(ALGORITHMS.iter().map(|(_, algorithm)| *algorithm).collect())
+    }
+}
+
+impl Crypto {
+    pub fn new(allowed: Vec<Algorithm>) -> Self {
+        Self {
+            allowed,
+            rng: SystemRandom::new(),
+        }
+    }
+
+    /// The crypto allowed by the container annotations.
+    pub fn from_ctx(ctx: &impl RuntimeContext) -> Result<Arc<Self>> {
+        let Some(value) = ctx.annotations().get(CRYPTO_ALGORITHMS_ANNOTATION) else {
+            return Ok(Default::default());
+        };
+        Ok(Arc::new(Self::new(parse_algorithms(value)?)))
+    }
+
+    fn algorithm(&self, name: &str) -> CryptoResult<Algorithm> {
+        match Algorithm::from_name(name) {
+            Some(algorithm) if self.allowed.contains(&algorithm) => Ok(algorithm),
+            Some(_) => Err(CryptoErrno::ProhibitedOperation),
+            None => Err(CryptoErrno::UnsupportedAlgorithm),
+        }
+    }
+
+    /// A signature algorithm allowed for the container.
+    fn signature_algorithm(&self, algorithm_type: i32, name: &str) -> CryptoResult<Algorithm> {
+        if algorithm_type != ALGORITHM_TYPE_SIGNATURES {
+            return Err(CryptoErrno::UnsupportedFeature);
+        }
+        let algorithm = self.algorithm(name)?;
+        match algorithm.verification() {
+            Some(_) => Ok(algorithm),
+            None => Err(CryptoErrno::UnsupportedAlgorithm),
+        }
+    }
+
+    /// Generate a PKCS#8 key pair.
+    fn generate(&self, algorithm: Algorithm) -> CryptoResult<Vec<u8>> {
+        let pkcs8 = match algorithm {
+            Algorithm::EcdsaP256Sha256 => {
+                EcdsaKeyPair::generate_pkcs8(&signature::ECDSA_P256_SHA256_FIXED_SIGNING, &self.rng)
+            }
+            Algorithm::EcdsaP384Sha384 => {
+                EcdsaKeyPair::generate_pkcs8(&signature::ECDSA_P384_SHA384_FIXED_SIGNING, &self.rng)
+            }
+            Algorithm::Ed25519 => Ed25519KeyPair::generate_pkcs8(&self.rng),
+            // ring can't generate RSA keys
+            _ => return Err(CryptoErrno::UnsupportedFeature),
+        };
+        Ok(pkcs8.map_err(|_| CryptoErrno::RngError)?.as_ref().to_vec())
+    }
+
+    /// The public key of the PKCS#8 key pair `pkcs8`.
+    fn public_key(&self, algorithm: Algorithm, pkcs8: &[u8]) -> CryptoResult<Vec<u8>> {
+        let invalid_key = |_| CryptoErrno::InvalidKey;
+        let public_key = match algorithm {
+            Algorithm::EcdsaP256Sha256 | Algorithm::EcdsaP384Sha384 => {
+                let alg = ecdsa_signing(algorithm);
+                let key_pair =
+                    EcdsaKeyPair::from_pkcs8(alg, pkcs8, &self.rng).map_err(invalid_key)?;
+                key_pair.public_key().as_ref().to_vec()
+            }
+            Algorithm::Ed25519 => {
+                let key_pair =
+                    Ed25519KeyPair::from_pkcs8_maybe_unchecked(pkcs8).map_err(invalid_key)?;
+                key_pair.public_key().as_ref().to_vec()
+            }
+            _ => {
+                let key_pair = RsaKeyPair::from_pkcs8(pkcs8).map_err(invalid_key)?;
+                key_pair.public_key().as_ref().to_vec()
+            }
+        };
+        Ok(public_key)
+    }
+
+    /// Sign `data` with the PKCS#8 key pair `pkcs8`.
+    fn sign(&self, algorithm: Algorithm, pkcs8: &[u8], data: &[u8]) -> CryptoResult<Vec<u8>> {
+        let invalid_key = |_| CryptoErrno::InvalidKey;
+        let failed = |_| CryptoErrno::AlgorithmFailure;
+
+        let rsa = |padding: &'static dyn RsaEncoding| -> CryptoResult<Vec<u8>> {
+            let key_pair = RsaKeyPair::from_pkcs8(pkcs8).map_err(invalid_key)?;
+            let mut signature = vec![0; key_pair.public().modulus_len()];
+            key_pair
+                .sign(padding, &self.rng, data, &mut signature)
+                .map_err(failed)?;
+            Ok(signature)
+        };
+
+        match algorithm {
+            Algorithm::EcdsaP256Sha256 | Algorithm::EcdsaP384Sha384 => {
+                let alg = ecdsa_signing(algorithm);
+                let key_pair =
+                    EcdsaKeyPair::from_pkcs8(alg, pkcs8, &self.rng).map_err(invalid_key)?;
+                let signature = key_pair.sign(&self.rng, data).map_err(failed)?;
+                Ok(signature.as_ref().to_vec())
+            }
+            Algorithm::Ed25519 => {
+                let key_pair =
+                    Ed25519KeyPair::from_pkcs8_maybe_unchecked(pkcs8).map_err(invalid_key)?;
+                Ok(key_pair.sign(data).as_ref().to_vec())
+            }
+            Algorithm::RsaPkcs1Sha256 => rsa(&signature::RSA_PKCS1_SHA256),
+            Algorithm::RsaPkcs1Sha512 => rsa(&signature::RSA_PKCS1_SHA512),
+            Algorithm::RsaPssSha256 => rsa(&signature::RSA_PSS_SHA256),
+            Algorithm::RsaPssSha512 => rsa(&signature::RSA_PSS_SHA512),
+            _ => Err(CryptoErrno::InvalidOperation),
+        }
+    }
+
+    /// Verify the `signature` of `data` with `public_key`.
+    fn verify(
+        &self,
+        algorithm: Algorithm,
+        public_key: &[u8],
+        data: &[u8],
+        signature: &[u8],
+    ) -> CryptoResult<()> {
+        let verification = algorithm
+            .verification()
+            .ok_or(CryptoErrno::InvalidOperation)?;
+        UnparsedPublicKey::new(verification, public_key)
+            .verify(data, signature)
+            .map_err(|_| CryptoErrno::VerificationFailed)
+    }
+}
+
+fn ecdsa_signing(algorithm: Algorithm) -> &'static EcdsaSigningAlgorithm {
+    match algorithm {
+        Algorithm::EcdsaP384Sha384 => &signature::ECDSA_P384_SHA384_FIXED_SIGNING,
+        _ => &signature::ECDSA_P256_SHA256_FIXED_SIGNING,
+    }
+}
+
+/// Parse a `name[,name]` list of algorithms.
+pub(crate) fn parse_algorithms(value: &str) -> Result<Vec<Algorithm>> {
+    let mut algorithms = vec![];
+    for name in value.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+        let Some(algorithm) = Algorithm::from_name(name) else {
+            bail!("unsupported crypto algorithm {name:?}");
+        };
+        algorithms.push(algorithm);
+    }
+    Ok(algorithms)
+}
+
+#[derive(Clone)]
+enum SymmetricState {
+    Hash(digest::Context),
+    Mac(hmac::Context),
+}
+
+/// The objects referenced by the handles of a module.
+enum Object {
+    Options,
+    ArrayOutput(Vec<u8>),
+    KeyPair(Algorithm, Vec<u8>),
+    PublicKey(Algorithm, Vec<u8>),
+    SecretKey(Algorithm, Vec<u8>),
+    Signature(Algorithm, Vec<u8>),
+    SignatureState(Algorithm, Vec<u8>, Vec<u8>),
+    VerificationState(Algorithm, Vec<u8>, Vec<u8>),
+    SymmetricKey(Algorithm, Vec<u8>),
+    SymmetricState(Algorithm, Box<SymmetricState>),
+    SymmetricTag(Vec<u8>),
+}
+
+/// The wasi-crypto state of a module: the crypto of its container, and its open handles.
+pub(crate) struct WasiCrypto {
+    crypto: Arc<Crypto>,
+    objects: HashMap<u32, Object>,
+    next_handle: u32,
+}
+
+impl WasiCrypto {
+    pub fn new(crypto: Arc<Crypto>) -> Self {
+        Self {
+            crypto,
+            objects: HashMap::new(),
+            next_handle: 1,
+        }
+    }
+
+    fn open(&mut self, object: Object) -> CryptoResult<u32> {
+        if self.objects.len() >= MAX_HANDLES {
+            return Err(CryptoErrno::TooManyHandles);
+        }
+        while self.objects.contains_key(&self.next_handle) {
+            self.next_handle = self.next_handle.wrapping_add(1);
+        }
+        let handle = self.next_handle;
+        self.next_handle = self.next_handle.wrapping_add(1);
+        self.objects.insert(handle, object);
+        Ok(handle)
+    }
+
+    fn get(&mut self, handle: u32) -> CryptoResult<&mut Object> {
+        self.objects
+            .get_mut(&handle)
+            .ok_or(CryptoErrno::InvalidHandle)
+    }
+
+    /// Close `handle`, if it references an object matching `is`.
+    fn close(&mut self, handle: u32, is: fn(&Object) -> bool) -> CryptoResult<Object> {
+        if !is(self.get(handle)?) {
+            return Err(CryptoErrno::InvalidHandle);
+        }
+        Ok(self.objects.remove(&handle).expect("handle is open"))
+    }
+
+    /// The options of `handle`, which must be `None` or options.
+    fn options(&mut self, handle: Option<u32>) -> CryptoResult<()> {
+        match handle.map(|handle| self.get(handle)).transpose()? {
+            None | Some(Object::Options) => Ok(()),
+            Some(_) => Err(CryptoErrno::InvalidHandle),
+        }
+    }
+
+    fn key_pair(&mut self, handle: u32) -> CryptoResult<(Algorithm, Vec<u8>)> {
+        match self.get(handle)? {
+            Object::KeyPair(algorithm, pkcs8) => Ok((*algorithm, pkcs8.clone())),
+            _ => Err(CryptoErrno::InvalidHandle),
+        }
+    }
+
+    fn public_key(&mut self, handle: u32) -> CryptoResult<(Algorithm, Vec<u8>)> {
+        match self.get(handle)? {
+            Object::PublicKey(algorithm, raw) => Ok((*algorithm, raw.clone())),
+            _ => Err(CryptoErrno::InvalidHandle),
+        }
+    }
+
+    fn secret_key(&mut self, handle: u32) -> CryptoResult<(Algorithm, Vec<u8>)> {
+        match self.get(handle)? {
+            Object::SecretKey(algorithm, pkcs8) => Ok((*algorithm, pkcs8.clone())),
+            _ => Err(CryptoErrno::InvalidHandle),
+        }
+    }
+
+    fn keypair_import(
+        &mut self,
+        algorithm_type: i32,
+        algorithm: &str,
+        encoded: &[u8],
+        encoding: i32,
+    ) -> CryptoResult<u32> {
+        let algorithm = self.crypto.signature_algorithm(algorithm_type, algorithm)?;
+        if encoding != ENCODING_PKCS8 {
+            return Err(CryptoErrno::UnsupportedEncoding);
+        }
+        // Parsing the key pair validates it
+        self.crypto.public_key(algorithm, encoded)?;
+        self.open(Object::KeyPair(algorithm, encoded.to_vec()))
+    }
+
+    fn keypair_from_pk_and_sk(&mut self, public_key: u32, secret_key: u32) -> CryptoResult<u32> {
+        let (algorithm, raw) = self.public_key(public_key)?;
+        let (secret_algorithm, pkcs8) = self.secret_key(secret_key)?;
+        if algorithm != secret_algorithm || self.crypto.public_key(algorithm, &pkcs8)? != raw {
+            return Err(CryptoErrno::IncompatibleKeys);
+        }
+        self.open(Object::KeyPair(algorithm, pkcs8))
+    }
+
+    fn publickey_import(
+        &mut self,
+        algorithm_type: i32,
+        algorithm: &str,
+        encoded: &[u8],
+        encoding: i32,
+    ) -> CryptoResult<u32> {
+        let algorithm = self.crypto.signature_algorithm(algorithm_type, algorithm)?;
+        let ecdsa = matches!(
+            algorithm,
+            Algorithm::EcdsaP256Sha256 | Algorithm::EcdsaP384Sha384
+        );
+        match encoding {
+            ENCODING_RAW => {}
+            ENCODING_SEC if ecdsa => {}
+            _ => return Err(CryptoErrno::UnsupportedEncoding),
+        }
+        self.open(Object::PublicKey(algorithm, encoded.to_vec()))
+    }
+
+    fn secretkey_import(
+        &mut self,
+        algorithm_type: i32,
+        algorithm: &str,
+        encoded: &[u8],
+        encoding: i32,
+    ) -> CryptoResult<u32> {
+        let algorithm = self.crypto.signature_algorithm(algorithm_type, algorithm)?;
+        if encoding != ENCODING_PKCS8 {
+            return Err(CryptoErrno::UnsupportedEncoding);
+        }
+        self.crypto.public_key(algorithm, encoded)?;
+        self.open(Object::SecretKey(algorithm, encoded.to_vec()))
+    }
+
+    /// Export the key pair, public key, secret key or signature `handle` with `encoding`.
+    fn export(&mut self, handle: u32, encoding: i32) -> CryptoResult<u32> {
+        let encoded = match self.get(handle)? {
+            Object::KeyPair(_, pkcs8) | Object::SecretKey(_, pkcs8)
+                if encoding == ENCODING_PKCS8 =>
+            {
+                pkcs8.clone()
+            }
+            Object::PublicKey(algorithm, raw) => {
+                let ecdsa = matches!(
+                    algorithm,
+                    Algorithm::EcdsaP256Sha256 | Algorithm::EcdsaP384Sha384
+                );
+                match encoding {
+                    ENCODING_RAW => raw.clone(),
+                    ENCODING_SEC if ecdsa => raw.clone(),
+                    _ => return Err(CryptoErrno::UnsupportedEncoding),
+                }
+            }
+            Object::Signature(_, raw) if encoding == ENCODING_RAW => raw.clone(),
+            Object::KeyPair(..) | Object::SecretKey(..) | Object::Signature(..) => {
+                return Err(CryptoErrno::UnsupportedEncoding)
+            }
+            _ => return Err(CryptoErrno::InvalidHandle),
+        };
+        self.open(Object::ArrayOutput(encoded))
+    }
+
+    /// The contents of the array output `handle`, which is closed.
+    /// Signatures are also read as array outputs, in their raw encoding.
+    fn array_output_pull(&mut self, handle: u32, buf_len: usize) -> CryptoResult<Vec<u8>> {
+        let len = self.array_output_len(handle)?;
+        if buf_len < len {
+            return Err(CryptoErrno::Overflow);
+        }
+        match self.objects.remove(&handle) {
+            Some(Object::ArrayOutput(data) | Object::Signature(_, data)) => Ok(data),
+            _ => unreachable!("array output checked"),
+        }
+    }
+
+    fn array_output_len(&mut self, handle: u32) -> CryptoResult<usize> {
+        match self.get(handle)? {
+            Object::ArrayOutput(data) | Object::Signature(_, data) => Ok(data.len()),
+            _ => Err(CryptoErrno::InvalidHandle),
+        }
+    }
+
+    fn signature_state_sign(&mut self, handle: u32) -> CryptoResult<u32> {
+        let (algorithm, signature) = match self.objects.get(&handle) {
+            Some(Object::SignatureState(algorithm, pkcs8, data)) => {
+                (*algorithm, self.crypto.sign(*algorithm, pkcs8, data)?)
+            }
+            _ => return Err(CryptoErrno::InvalidHandle),
+        };
+        self.open(Object::Signature(algorithm, signature))
+    }
+
+    fn signature_verification_state_verify(
+        &mut self,
+        handle: u32,
+        signature: u32,
+    ) -> CryptoResult<()> {
+        let (signature_algorithm, signature) = match self.get(signature)? {
+            Object::Signature(algorithm, raw) => (*algorithm, raw.clone()),
+            _ => return Err(CryptoErrno::InvalidHandle),
+        };
+        match self.objects.get(&handle) {
+            Some(Object::VerificationState(algorithm, _, _))
+                if *algorithm != signature_algorithm =>
+            {
+                Err(CryptoErrno::IncompatibleKeys)
+            }
+            Some(Object::VerificationState(algorithm, public_key, data)) => {
+                self.crypto.verify(*algorithm, public_key, data, &signature)
+            }
+            _ => Err(CryptoErrno::InvalidHandle),
+        }
+    }
+
+    /// Absorb `data` in the signature, verification or symmetric state `handle`.
+    fn update(&mut self, handle: u32, input: &[u8]) -> CryptoResult<()> {
+        match self.get(handle)? {
+            Object::SignatureState(_, _, data) | Object::VerificationState(_, _, data) => {
+                data.extend_from_slice(input)
+            }
+            Object::SymmetricState(_, state) => match &mut **state {
+                SymmetricState::Hash(ctx) => ctx.update(input),
+                SymmetricState::Mac(ctx) => ctx.update(input),
+            },
+            _ => return Err(CryptoErrno::InvalidHandle),
+        }
+        Ok(())
+    }
+
+    fn symmetric_key_generate(&mut self, algorithm: &str) -> CryptoResult<u32> {
+        let algorithm = self.crypto.algorithm(algorithm)?;
+        let hmac = algorithm.hmac().ok_or(CryptoErrno::KeyNotSupported)?;
+        let mut key = vec![0; hmac.digest_algorithm().output_len()];
+        self.crypto
+            .rng
+            .fill(&mut key)
+            .map_err(|_| CryptoErrno::RngError)?;
+        self.open(Object::SymmetricKey(algorithm, key))
+    }
+
+    fn symmetric_key_import(&mut self, algorithm: &str, raw: &[u8]) -> CryptoResult<u32> {
+        let algorithm = self.crypto.algorithm(algorithm)?;
+        if algorithm.hmac().is_none() {
+            return Err(CryptoErrno::KeyNotSupported);
+        }
+        self.open(Object::SymmetricKey(algorithm, raw.to_vec()))
+    }
+
+    fn symmetric_key_export(&mut self, handle: u32) -> CryptoResult<u32> {
+        let raw = match self.get(handle)? {
+            Object::SymmetricKey(_, raw) => raw.clone(),
+            _ => return Err(CryptoErrno::InvalidHandle),
+        };
+        self.open(Object::ArrayOutput(raw))
+    }
+
+    fn symmetric_state_open(&mut self, algorithm: &str, key: Option<u32>) -> CryptoResult<u32> {
+        let algorithm = self.crypto.algorithm(algorithm)?;
+        let key = match key.map(|key| self.get(key)).transpose()? {
+            Some(Object::SymmetricKey(key_algorithm, raw)) if *key_algorithm == algorithm => {
+                Some(raw.clone())
+            }
+            Some(Object::SymmetricKey(..)) => return Err(CryptoErrno::IncompatibleKeys),
+            Some(_) => return Err(CryptoErrno::InvalidHandle),
+            None => None,
+        };
+        let state = match (algorithm.digest(), algorithm.hmac(), key) {
+            (Some(_), _, Some(_)) => return Err(CryptoErrno::KeyNotSupported),
+            (Some(digest), _, None) => SymmetricState::Hash(digest::Context::new(digest)),
+            (_, Some(hmac), Some(key)) => {
+                SymmetricState::Mac(hmac::Context::with_key(&hmac::Key::new(hmac, &key)))
+            }
+            (_, Some(_), None) => return Err(CryptoErrno::KeyRequired),
+            (None, None, _) => return Err(CryptoErrno::UnsupportedAlgorithm),
+        };
+        self.open(Object::SymmetricState(algorithm, Box::new(state)))
+    }
+
+    fn symmetric_state_clone(&mut self, handle: u32) -> CryptoResult<u32> {
+        let (algorithm, state) = self.symmetric_state(handle)?;
+        let state = Box::new(state.clone());
+        self.open(Object::SymmetricState(algorithm, state))
+    }
+
+    fn symmetric_state(&mut self, handle: u32) -> CryptoResult<(Algorithm, &SymmetricState)> {
+        match self.get(handle)? {
+            Object::SymmetricState(algorithm, state) => Ok((*algorithm, state)),
+            _ => Err(CryptoErrno::InvalidHandle),
+        }
+    }
+
+    /// The hash of the symmetric state `handle`, truncated to `len` bytes.
+    fn symmetric_state_squeeze(&mut self, handle: u32, len: usize) -> CryptoResult<Vec<u8>> {
+        let SymmetricState::Hash(ctx) = self.symmetric_state(handle)?.1 else {
+            return Err(CryptoErrno::InvalidOperation);
+        };
+        let hash = ctx.clone().finish();
+        match hash.as_ref().get(..len) {
+            Some(hash) => Ok(hash.to_vec()),
+            None => Err(CryptoErrno::InvalidLength),
+        }
+    }
+
+    fn symmetric_state_squeeze_tag(&mut self, handle: u32) -> CryptoResult<u32> {
+        let SymmetricState::Mac(ctx) = self.symmetric_state(handle)?.1 else {
+            return Err(CryptoErrno::InvalidOperation);
+        };
+        let tag = ctx.clone().sign();
+        self.open(Object::SymmetricTag(tag.as_ref().to_vec()))
+    }
+
+    fn symmetric_state_max_tag_len(&mut self, handle: u32) -> CryptoResult<usize> {
+        match self.symmetric_state(handle)? {
+            (algorithm, SymmetricState::Mac(_)) => {
+                let hmac = algorithm.hmac().expect("MAC algorithm");
+                Ok(hmac.digest_algorithm().output_len())
+            }
+            _ => Err(CryptoErrno::InvalidOperation),
+        }
+    }
+
+    fn symmetric_tag(&mut self, handle: u32) -> CryptoResult<&[u8]> {
+        match self.get(handle)? {
+            Object::SymmetricTag(tag) => Ok(tag),
+            _ => Err(CryptoErrno::InvalidHandle),
+        }
+    }
+
+    /// The contents of the tag `handle`, which is closed.
+    fn symmetric_tag_pull(&mut self, handle: u32, buf_len: usize) -> CryptoResult<Vec<u8>> {
+        if buf_len < self.symmetric_tag(handle)?.len() {
+            return Err(CryptoErrno::Overflow);
+        }
+        match self.objects.remove(&handle) {
+            Some(Object::SymmetricTag(tag)) => Ok(tag),
+            _ => unreachable!("tag checked"),
+        }
+    }
+
+    fn symmetric_tag_verify(&mut self, handle: u32, expected: &[u8]) -> CryptoResult<()> {
+        let tag = self.symmetric_tag(handle)?;
+        // Compare in constant time
+        let diff = tag
+            .iter()
+            .zip(expected)
+            .fold(0, |diff, (a, b)| diff | (a ^ b));
+        if tag.len() != expected.len() || diff != 0 {
+            return Err(CryptoErrno::InvalidTag);
+        }
+        Ok(())
+    }
+}
+
+/// The memory of the calling module, in which the arguments and results are passed.
+struct GuestMemory<'a>(&'a mut [u8]);
+
+impl GuestMemory<'_> {
+    fn slice(&self, ptr: i32, len: i32) -> CryptoResult<&[u8]> {
+        let start = ptr as u32 as usize;
+        let end = start
+            .checked_add(len as u32 as usize)
+            .ok_or(CryptoErrno::GuestError)?;
+        self.0.get(start..end).ok_or(CryptoErrno::GuestError)
+    }
+
+    fn str(&self, ptr: i32, len: i32) -> CryptoResult<&str> {
+        std::str::from_utf8(self.slice(ptr, len)?).map_err(|_| CryptoErrno::GuestError)
+    }
+
+    /// Read an optional handle, a union of `some` (tag 0) and `none`, with the handle at offset 4.
+    fn opt_handle(&self, ptr: i32) -> CryptoResult<Option<u32>> {
+        let union = self.slice(ptr, 8)?;
+        match union[0] {
+            0 => Ok(Some(u32::from_le_bytes(union[4..8].try_into().unwrap()))),
+            1 => Ok(None),
+            _ => Err(CryptoErrno::GuestError),
+        }
+    }
+
+    fn write(&mut self, ptr: i32, data: &[u8]) -> CryptoResult<()> {
+        let start = ptr as u32 as usize;
+        let end = start
+            .checked_add(data.len())
+            .ok_or(CryptoErrno::GuestError)?;
+        self.0
+            .get_mut(start..end)
+            .ok_or(CryptoErrno::GuestError)?
+            .copy_from_slice(data);
+        Ok(())
+    }
+
+    fn write_u32(&mut self, ptr: i32, value: u32) -> CryptoResult<()> {
+        self.write(ptr, &value.to_le_bytes())
+    }
+
+    fn write_len(&mut self, ptr: i32, len: usize) -> CryptoResult<()> {
+        let len = u32::try_from(len).map_err(|_| CryptoErrno::Overflow)?;
+        self.write_u32(ptr, len)
+    }
+}
+
+/// Call `f` with the memory and the wasi-crypto state of the module, and return its `crypto_errno`.
+fn call(
+    mut caller: Caller<'_, ModuleCtx>,
+    f: impl FnOnce(&mut GuestMemory, &mut WasiCrypto) -> CryptoResult<()>,
+) -> i32 {
+    let Some(Extern::Memory(memory)) = caller.get_export("memory") else {
+        return CryptoErrno::GuestError as i32;
+    };
+    let (memory, ctx) = memory.data_and_store_mut(&mut caller);
+    match f(&mut GuestMemory(memory), &mut ctx.crypto) {
+        Ok(()) => 0,
+        Err(errno) => errno as i32,
+    }
+}
+
+type Ctx<'a> = Caller<'a, ModuleCtx>;
+
+/// Add the wasi-crypto modules to the linker.
+pub(crate) fn add_to_linker(linker: &mut Linker<ModuleCtx>) -> Result<()> {
+    add_common_to_linker(linker)?;
+    add_asymmetric_common_to_linker(linker)?;
+    add_signatures_to_linker(linker)?;
+    add_symmetric_to_linker(linker)
+}
+
+fn add_common_to_linker(linker: &mut Linker<ModuleCtx>) -> Result<()> {
+    linker.func_wrap(COMMON, "options_open", |c: Ctx, typ: i32, out: i32| {
+        call(c, |mem, crypto| {
+            if !matches!(typ, ALGORITHM_TYPE_SIGNATURES | ALGORITHM_TYPE_SYMMETRIC) {
+                return Err(CryptoErrno::UnsupportedFeature);
+            }
+            let handle = crypto.open(Object::Options)?;
+            mem.write_u32(out, handle)
+        })
+    })?;
+    linker.func_wrap(COMMON, "options_close", |c: Ctx, handle: i32| {
+        call(c, |_, crypto| {
+            crypto.close(handle as u32, |o| matches!(o, Object::Options))?;
+            Ok(())
+        })
+    })?;
+    // No option is supported
+    linker.func_wrap(
+        COMMON,
+        "options_set",
+        |c: Ctx, handle: i32, _: i32, _: i32, _: i32, _: i32| {
+            call(c, |_, crypto| {
+                crypto.options(Some(handle as u32))?;
+                Err(CryptoErrno::UnsupportedOption)
+            })
+        },
+    )?;
+    linker.func_wrap(
+        COMMON,
+        "options_set_u64",
+        |c: Ctx, handle: i32, _: i32, _: i32, _: i64| {
+            call(c, |_, crypto| {
+                crypto.options(Some(handle as u32))?;
+                Err(CryptoErrno::UnsupportedOption)
+            })
+        },
+    )?;
+    linker.func_wrap(
+        COMMON,
+        "options_set_guest_buffer",
+        |c: Ctx, handle: i32, _: i32, _: i32, _: i32, _: i32| {
+            call(c, |_, crypto| {
+                crypto.options(Some(handle as u32))?;
+                Err(CryptoErrno::UnsupportedOption)
+            })
+        },
+    )?;
+    linker.func_wrap(
+        COMMON,
+        "array_output_len",
+        |c: Ctx, handle: i32, out: i32| {
+            call(c, |mem, crypto| {
+                let len = crypto.array_output_len(handle as u32)?;
+                mem.write_len(out, len)
+            })
+        },
+    )?;
+    linker.func_wrap(
+        COMMON,
+        "array_output_pull",
+        |c: Ctx, handle: i32, buf: i32, buf_len: i32, out: i32| {
+            call(c, |mem, crypto| {
+                mem.slice(buf, buf_len)?;
+                let data = crypto.array_output_pull(handle as u32, buf_len as u32 as usize)?;
+                mem.write(buf, &data)?;
+                mem.write_len(out, data.len())
+            })
+        },
+    )?;
+    Ok(())
+}
+
+fn add_asymmetric_common_to_linker(linker: &mut Linker<ModuleCtx>) -> Result<()> {
+    linker.func_wrap(
+        ASYMMETRIC_COMMON,
+        "keypair_generate",
+        |c: Ctx, typ: i32, alg: i32, alg_len: i32, options: i32, out: i32| {
+            call(c, |mem, crypto| {
+                crypto.options(mem.opt_handle(options)?)?;
+                let algorithm = crypto
+                    .crypto
+                    .signature_algorithm(typ, mem.str(alg, alg_len)?)?;
+                let pkcs8 = crypto.crypto.generate(algorithm)?;
+                let handle = crypto.open(Object::KeyPair(algorithm, pkcs8))?;
+                mem.write_u32(out, handle)
+            })
+        },
+    )?;
+    linker.func_wrap(
+        ASYMMETRIC_COMMON,
+        "keypair_import",
+        |c: Ctx,
+         typ: i32,
+         alg: i32,
+         alg_len: i32,
+         encoded: i32,
+         len: i32,
+         encoding: i32,
+         out: i32| {
+            call(c, |mem, crypto| {
+                let (alg, encoded) = (mem.str(alg, alg_len)?, mem.slice(encoded, len)?);
+                let handle = crypto.keypair_import(typ, alg, encoded, encoding)?;
+                mem.write_u32(out, handle)
+            })
+        },
+    )?;
+    linker.func_wrap(
+        ASYMMETRIC_COMMON,
+        "keypair_from_pk_and_sk",
+        |c: Ctx, public_key: i32, secret_key: i32, out: i32| {
+            call(c, |mem, crypto| {
+                let handle = crypto.keypair_from_pk_and_sk(public_key as u32, secret_key as u32)?;
+                mem.write_u32(out, handle)
+            })
+        },
+    )?;
+    linker.func_wrap(
+        ASYMMETRIC_COMMON,
+        "keypair_publickey",
+        |c: Ctx, handle: i32, out: i32| {
+            call(c, |mem, crypto| {
+                let (algorithm, pkcs8) = crypto.key_pair(handle as u32)?;
+                let raw = crypto.crypto.public_key(algorithm, &pkcs8)?;
+                let handle = crypto.open(Object::PublicKey(algorithm, raw))?;
+                mem.write_u32(out, handle)
+            })
+        },
+    )?;
+    linker.func_wrap(
+        ASYMMETRIC_COMMON,
+        "keypair_secretkey",
+        |c: Ctx, handle: i32, out: i32| {
+            call(c, |mem, crypto| {
+                let (algorithm, pkcs8) = crypto.key_pair(handle as u32)?;
+                let handle = crypto.open(Object::SecretKey(algorithm, pkcs8))?;
+                mem.write_u32(out, handle)
+            })
+        },
+    )?;
+    linker.func_wrap(
+        ASYMMETRIC_COMMON,
+        "publickey_import",
+        |c: Ctx,
+         typ: i32,
+         alg: i32,
+         alg_len: i32,
+         encoded: i32,
+         len: i32,
+         encoding: i32,
+         out: i32| {
+            call(c, |mem, crypto| {
+                let (alg, encoded) = (mem.str(alg, alg_len)?, mem.slice(encoded, len)?);
+                let handle = crypto.publickey_import(typ, alg, encoded, encoding)?;
+                mem.write_u32(out, handle)
+            })
+        },
+    )?;
+    linker.func_wrap(
+        ASYMMETRIC_COMMON,
+        "publickey_verify",
+        |c: Ctx, handle: i32| {
+            call(c, |_, crypto| {
+                crypto.public_key(handle as u32)?;
+                Ok(())
+            })
+        },
+    )?;
+    linker.func_wrap(
+        ASYMMETRIC_COMMON,
+        "publickey_from_secretkey",
+        |c: Ctx, handle: i32, out: i32| {
+            call(c, |mem, crypto| {
+                let (algorithm, pkcs8) = crypto.secret_key(handle as u32)?;
+                let raw = crypto.crypto.public_key(algorithm, &pkcs8)?;
+                let handle = crypto.open(Object::PublicKey(algorithm, raw))?;
+                mem.write_u32(out, handle)
+            })
+        },
+    )?;
+    linker.func_wrap(
+        ASYMMETRIC_COMMON,
+        "secretkey_import",
+        |c: Ctx,
+         typ: i32,
+         alg: i32,
+         alg_len: i32,
+         encoded: i32,
+         len: i32,
+         encoding: i32,
+         out: i32| {
+            call(c, |mem, crypto| {
+                let (alg, encoded) = (mem.str(alg, alg_len)?, mem.slice(encoded, len)?);
+                let handle = crypto.secretkey_import(typ, alg, encoded, encoding)?;
+                mem.write_u32(out, handle)
+            })
+        },
+    )?;
+    for name in ["keypair_export", "publickey_export", "secretkey_export"] {
+        linker.func_wrap(
+            ASYMMETRIC_COMMON,
+            name,
+            |c: Ctx, handle: i32, encoding: i32, out: i32| {
+                call(c, |mem, crypto| {
+                    let handle = crypto.export(handle as u32, encoding)?;
+                    mem.write_u32(out, handle)
+                })
+            },
+        )?;
+    }
+    add_close_to_linker(linker, ASYMMETRIC_COMMON, "keypair_close", |o| {
+        matches!(o, Object::KeyPair(..))
+    })?;
+    add_close_to_linker(linker, ASYMMETRIC_COMMON, "publickey_close", |o| {
+        matches!(o, Object::PublicKey(..))
+    })?;
+    add_close_to_linker(linker, ASYMMETRIC_COMMON, "secretkey_close", |o| {
+        matches!(o, Object::SecretKey(..))
+    })
+}
+
+fn add_signatures_to_linker(linker: &mut Linker<ModuleCtx>) -> Result<()> {
+    linker.func_wrap(
+        SIGNATURES,
+        "signature_export",
+        |c: Ctx, handle: i32, encoding: i32, out: i32| {
+            call(c, |mem, crypto| {
+                let handle = crypto.export(handle as u32, encoding)?;
+                mem.write_u32(out, handle)
+            })
+        },
+    )?;
+    linker.func_wrap(
+        SIGNATURES,
+        "signature_import",
+        |c: Ctx, alg: i32, alg_len: i32, encoded: i32, len: i32, encoding: i32, out: i32| {
+            call(c, |mem, crypto| {
+                let algorithm = mem.str(alg, alg_len)?;
+                let algorithm = crypto
+                    .crypto
+                    .signature_algorithm(ALGORITHM_TYPE_SIGNATURES, algorithm)?;
+                if encoding != ENCODING_RAW {
+                    return Err(CryptoErrno::UnsupportedEncoding);
+                }
+                let raw = mem.slice(encoded, len)?.to_vec();
+                let handle = crypto.open(Object::Signature(algorithm, raw))?;
+                mem.write_u32(out, handle)
+            })
+        },
+    )?;
+    linker.func_wrap(
+        SIGNATURES,
+        "signature_state_open",
+        |c: Ctx, key_pair: i32, out: i32| {
+            call(c, |mem, crypto| {
+                let (algorithm, pkcs8) = crypto.key_pair(key_pair as u32)?;
+                let handle = crypto.open(Object::SignatureState(algorithm, pkcs8, vec![]))?;
+                mem.write_u32(out, handle)
+            })
+        },
+    )?;
+    linker.func_wrap(
+        SIGNATURES,
+        "signature_state_sign",
+        |c: Ctx, handle: i32, out: i32| {
+            call(c, |mem, crypto| {
+                let handle = crypto.signature_state_sign(handle as u32)?;
+                mem.write_u32(out, handle)
+            })
+        },
+    )?;
+    linker.func_wrap(
+        SIGNATURES,
+        "signature_verification_state_open",
+        |c: Ctx, public_key: i32, out: i32| {
+            call(c, |mem, crypto| {
+                let (algorithm, raw) = crypto.public_key(public_key as u32)?;
+                let handle = crypto.open(Object::VerificationState(algorithm, raw, vec![]))?;
+                mem.write_u32(out, handle)
+            })
+        },
+    )?;
+    linker.func_wrap(
+        SIGNATURES,
+        "signature_verification_state_verify",
+        |c: Ctx, handle: i32, signature: i32| {
+            call(c, |_, crypto| {
+                crypto.signature_verification_state_verify(handle as u32, signature as u32)
+            })
+        },
+    )?;
+    for name in [
+        "signature_state_update",
+        "signature_verification_state_update",
+    ] {
+        add_update_to_linker(linker, SIGNATURES, name)?;
+    }
+    add_close_to_linker(linker, SIGNATURES, "signature_state_close", |o| {
+        matches!(o, Object::SignatureState(..))
+    })?;
+    add_close_to_linker(
+        linker,
+        SIGNATURES,
+        "signature_verification_state_close",
+        |o| matches!(o, Object::VerificationState(..)),
+    )?;
+    add_close_to_linker(linker, SIGNATURES, "signature_close", |o| {
+        matches!(o, Object::Signature(..))
+    })
+}
+
+fn add_symmetric_to_linker(linker: &mut Linker<ModuleCtx>) -> Result<()> {
+    linker.func_wrap(
+        SYMMETRIC,
+        "symmetric_key_generate",
+        |c: Ctx, alg: i32, alg_len: i32, options: i32, out: i32| {
+            call(c, |mem, crypto| {
+                crypto.options(mem.opt_handle(options)?)?;
+                let handle = crypto.symmetric_key_generate(mem.str(alg, alg_len)?)?;
+                mem.write_u32(out, handle)
+            })
+        },
+    )?;
+    linker.func_wrap(
+        SYMMETRIC,
+        "symmetric_key_import",
+        |c: Ctx, alg: i32, alg_len: i32, raw: i32, raw_len: i32, out: i32| {
+            call(c, |mem, crypto| {
+                let (alg, raw) = (mem.str(alg, alg_len)?, mem.slice(raw, raw_len)?);
+                let handle = crypto.symmetric_key_import(alg, raw)?;
+                mem.write_u32(out, handle)
+            })
+        },
+    )?;
+    linker.func_wrap(
+        SYMMETRIC,
+        "symmetric_key_export",
+        |c: Ctx, handle: i32, out: i32| {
+            call(c, |mem, crypto| {
+                let handle = crypto.symmetric_key_export(handle as u32)?;
+                mem.write_u32(out, handle)
+            })
+        },
+    )?;
+    linker.func_wrap(
+        SYMMETRIC,
+        "symmetric_state_open",
+        |c: Ctx, alg: i32, alg_len: i32, key: i32, options: i32, out: i32| {
+            call(c, |mem, crypto| {
+                crypto.options(mem.opt_handle(options)?)?;
+                let key = mem.opt_handle(key)?;
+                let handle = crypto.symmetric_state_open(mem.str(alg, alg_len)?, key)?;
+                mem.write_u32(out, handle)
+            })
+        },
+    )?;
+    linker.func_wrap(
+        SYMMETRIC,
+        "symmetric_state_clone",
+        |c: Ctx, handle: i32, out: i32| {
+            call(c, |mem, crypto| {
+                let handle = crypto.symmetric_state_clone(handle as u32)?;
+                mem.write_u32(out, handle)
+            })
+        },
+    )?;
+    add_update_to_linker(linker, SYMMETRIC, "symmetric_state_absorb")?;
+    linker.func_wrap(
+        SYMMETRIC,
+        "symmetric_state_squeeze",
+        |c: Ctx, handle: i32, out: i32, out_len: i32| {
+            call(c, |mem, crypto| {
+                mem.slice(out, out_len)?;
+                let hash =
+                    crypto.symmetric_state_squeeze(handle as u32, out_len as u32 as usize)?;
+                mem.write(out, &hash)
+            })
+        },
+    )?;
+    linker.func_wrap(
+        SYMMETRIC,
+        "symmetric_state_squeeze_tag",
+        |c: Ctx, handle: i32, out: i32| {
+            call(c, |mem, crypto| {
+                let handle = crypto.symmetric_state_squeeze_tag(handle as u32)?;
+                mem.write_u32(out, handle)
+            })
+        },
+    )?;
+    linker.func_wrap(
+        SYMMETRIC,
+        "symmetric_state_max_tag_len",
+        |c: Ctx, handle: i32, out: i32| {
+            call(c, |mem, crypto| {
+                let len = crypto.symmetric_state_max_tag_len(handle as u32)?;
+                mem.write_len(out, len)
+            })
+        },
+    )?;
+    linker.func_wrap(
+        SYMMETRIC,
+        "symmetric_tag_len",
+        |c: Ctx, handle: i32, out: i32| {
+            call(c, |mem, crypto| {
+                let len = crypto.symmetric_tag(handle as u32)?.len();
+                mem.write_len(out, len)
+            })
+        },
+    )?;
+    linker.func_wrap(
+        SYMMETRIC,
+        "symmetric_tag_pull",
+        |c: Ctx, handle: i32, buf: i32, buf_len: i32, out: i32| {
+            call(c, |mem, crypto| {
+                mem.slice(buf, buf_len)?;
+                let tag = crypto.symmetric_tag_pull(handle as u32, buf_len as u32 as usize)?;
+                mem.write(buf, &tag)?;
+                mem.write_len(out, tag.len())
+            })
+        },
+    )?;
+    linker.func_wrap(
+        SYMMETRIC,
+        "symmetric_tag_verify",
+        |c: Ctx, handle: i32, expected: i32, expected_len: i32| {
+            call(c, |mem, crypto| {
+                crypto.symmetric_tag_verify(handle as u32, mem.slice(expected, expected_len)?)
+            })
+        },
+    )?;
+    add_close_to_linker(linker, SYMMETRIC, "symmetric_key_close", |o| {
+        matches!(o, Object::SymmetricKey(..))
+    })?;
+    add_close_to_linker(linker, SYMMETRIC, "symmetric_state_close", |o| {
+        matches!(o, Object::SymmetricState(..))
+    })?;
+    add_close_to_linker(linker, SYMMETRIC, "symmetric_tag_close", |o| {
+        matches!(o, Object::SymmetricTag(..))
+    })
+}
+
+/// Add the `name(handle, data, data_len)` function absorbing data in a state.
+fn add_update_to_linker(linker: &mut Linker<ModuleCtx>, module: &str, name: &str) -> Result<()> {
+    linker.func_wrap(module, name, |c: Ctx, handle: i32, data: i32, len: i32| {
+        call(c, |mem, crypto| {
+            crypto.update(handle as u32, mem.slice(data, len)?)
+        })
+    })?;
+    Ok(())
+}
+
+/// Add the `name(handle)` function closing the objects matching `is`.
+fn add_close_to_linker(
+    linker: &mut Linker<ModuleCtx>,
+    module: &str,
+    name: &str,
+    is: fn(&Object) -> bool,
+) -> Result<()> {
+    linker.func_wrap(module, name, move |c: Ctx, handle: i32| {
+        call(c, |_, crypto| {
+            crypto.close(handle as u32, is)?;
+            Ok(())
+        })
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_algorithms() -> Result<()> {
+        assert_eq!(
+            parse_algorithms("SHA-256, Ed25519")?,
+            vec![Algorithm::Sha256, Algorithm::Ed25519]
+        );
+        assert!(parse_algorithms("")?.is_empty());
+        assert!(parse_algorithms("MD5").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_hash_and_mac() -> CryptoResult<()> {
+        let mut crypto = WasiCrypto::new(Default::default());
+
+        let state = crypto.symmetric_state_open("SHA-256", None)?;
+        crypto.update(state, b"abc")?;
+        let hash = crypto.symmetric_state_squeeze(state, 32)?;
+        assert_eq!(hash[..4], [0xba, 0x78, 0x16, 0xbf]);
+        assert_eq!(
+            crypto.symmetric_state_squeeze(state, 33),
+            Err(CryptoErrno::InvalidLength)
+        );
+        assert_eq!(
+            crypto.symmetric_state_open("MD5", None),
+            Err(CryptoErrno::UnsupportedAlgorithm)
+        );
+
+        let key = crypto.symmetric_key_import("HMAC/SHA-256", b"key")?;
+        assert_eq!(
+            crypto.symmetric_state_open("HMAC/SHA-256", None),
+            Err(CryptoErrno::KeyRequired)
+        );
+        let state = crypto.symmetric_state_open("HMAC/SHA-256", Some(key))?;
+        crypto.update(state, b"abc")?;
+        let tag = crypto.symmetric_state_squeeze_tag(state)?;
+        let raw = crypto.symmetric_tag(tag)?.to_vec();
+        assert_eq!(raw.len(), 32);
+        crypto.symmetric_tag_verify(tag, &raw)?;
+        assert_eq!(
+            crypto.symmetric_tag_verify(tag, &raw[1..]),
+            Err(CryptoErrno::InvalidTag)
+        );
+        assert_eq!(
+            crypto.symmetric_tag_pull(tag, 16),
+            Err(CryptoErrno::Overflow)
+        );
+        assert_eq!(crypto.symmetric_tag_pull(tag, 64)?, raw);
+        assert_eq!(
+            crypto.symmetric_tag(tag).err(),
+            Some(CryptoErrno::InvalidHandle)
+        );
+
+        let mut crypto = WasiCrypto::new(Arc::new(Crypto::new(vec![Algorithm::Sha512])));
+        assert_eq!(
+            crypto.symmetric_state_open("SHA-256", None),
+            Err(CryptoErrno::ProhibitedOperation)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_sign_and_verify() -> CryptoResult<()> {
+        let mut crypto = WasiCrypto::new(Default::default());
+
+        for algorithm in ["Ed25519", "ECDSA_P256_SHA256"] {
+            let algorithm = crypto.crypto.algorithm(algorithm)?;
+            let pkcs8 = crypto.crypto.generate(algorithm)?;
+            let key_pair = crypto.open(Object::KeyPair(algorithm, pkcs8))?;
+
+            let (_, pkcs8) = crypto.key_pair(key_pair)?;
+            let state = crypto.open(Object::SignatureState(algorithm, pkcs8.clone(), vec![]))?;
+            crypto.update(state, b"data")?;
+            let signature = crypto.signature_state_sign(state)?;
+
+            let public_key = crypto.crypto.public_key(algorithm, &pkcs8)?;
+            let state = crypto.open(Object::VerificationState(
+                algorithm,
+                public_key.clone(),
+                vec![],
+            ))?;
+            crypto.update(state, b"data")?;
+            crypto.signature_verification_state_verify(state, signature)?;
+
+            let state = crypto.open(Object::VerificationState(algorithm, public_key, vec![]))?;
+            crypto.update(state, b"other")?;
+            assert_eq!(
+                crypto.signature_verification_state_verify(state, signature),
+                Err(CryptoErrno::VerificationFailed)
+            );
+
+            // The signature is exported in its raw encoding
+            let exported = crypto.export(signature, ENCODING_RAW)?;
+            assert!(crypto.array_output_len(exported)? > 0);
+        }
+
+        assert_eq!(
+            crypto.keypair_import(
+                ALGORITHM_TYPE_SIGNATURES,
+                "Ed25519",
+                b"not a key",
+                ENCODING_PKCS8
+            ),
+            Err(CryptoErrno::InvalidKey)
+        );
+        assert_eq!(
+            crypto.keypair_import(ALGORITHM_TYPE_SIGNATURES, "SHA-256", b"", ENCODING_PKCS8),
+            Err(CryptoErrno::UnsupportedAlgorithm)
+        );
+        Ok(())
+    }
+}
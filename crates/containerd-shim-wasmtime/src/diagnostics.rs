@@ -0,0 +1,253 @@
+//! Diagnostic bundles written when the guest traps.
+//!
+//! When the `runwasi.io/debug-trap-dir` annotation is set to a directory of the container, every
+//! trap of the guest writes a JSON bundle to that directory, named
+//! `trap-<timestamp in ms>-<pid>.json`, with:
+//! * the error and the wasm backtrace of the trap,
+//! * the arguments and environment of the container, with the values of the variables whose name
+//!   contains `SECRET`, `TOKEN`, `PASSWORD` or `KEY` redacted,
+//! * the method, URI and headers of the HTTP request being handled, if any, with the values of the
+//!   credential headers redacted,
+//! * the fuel consumed by the store, when fuel is metered,
+//! * the last 16 KiB written by the guest to stderr.
+//!
+//! Mounting the log directory of the container at that path, e.g. with a `hostPath` volume, puts
+//! the bundles next to the container logs.
+
+use std::collections::VecDeque;
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use containerd_shim_wasm::container::RuntimeContext;
+use hyper::header::{self, HeaderMap};
+use serde::Serialize;
+use tokio::io::AsyncWrite;
+use wasmtime::{Trap, WasmBacktrace};
+use wasmtime_wasi::pipe::AsyncWriteStream;
+use wasmtime_wasi::{HostOutputStream, StdoutStream};
+
+use crate::instance::envs_from_ctx;
+
+pub const TRAP_DIR_ANNOTATION: &str = "runwasi.io/debug-trap-dir";
+
+/// Size of the tail of stderr kept for the bundles.
+const STDERR_TAIL_SIZE: usize = 16 * 1024;
+
+// Matches the write budget wasmtime uses for its own stdio streams.
+const WRITE_BUDGET: usize = 1024 * 1024;
+
+const REDACTED: &str = "[redacted]";
+
+/// The last bytes written by the guest to stderr. The container runs in its own process.
+static STDERR_TAIL: LazyLock<Mutex<VecDeque<u8>>> = LazyLock::new(Default::default);
+
+/// The HTTP request handled when the guest trapped.
+#[derive(Debug, Serialize)]
+pub struct RequestSnapshot {
+    pub id: u64,
+    pub method: String,
+    pub uri: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl RequestSnapshot {
+    pub fn new<B>(id: u64, req: &hyper::Request<B>) -> Self {
+        Self {
+            id,
+            method: req.method().to_string(),
+            uri: req.uri().to_string(),
+            headers: redact_headers(req.headers()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Bundle<'a> {
+    timestamp_ms: u64,
+    error: String,
+    trap: String,
+    backtrace: Option<String>,
+    args: &'a [String],
+    env: &'a [(String, String)],
+    request: Option<RequestSnapshot>,
+    fuel_consumed: Option<u64>,
+    stderr: String,
+}
+
+/// What the bundles are made of, besides the trap.
+pub(crate) struct TrapContext {
+    dir: PathBuf,
+    args: Vec<String>,
+    env: Vec<(String, String)>,
+}
+
+impl TrapContext {
+    /// The trap context of the container, `None` when bundles are not enabled.
+    pub fn from_ctx(ctx: &impl RuntimeContext) -> Option<Arc<Self>> {
+        let dir = ctx.annotations().get(TRAP_DIR_ANNOTATION)?;
+        let env = envs_from_ctx(ctx)
+            .into_iter()
+            .map(|(name, value)| {
+                let value = if is_secret(&name) {
+                    REDACTED.to_string()
+                } else {
+                    value
+                };
+                (name, value)
+            })
+            .collect();
+        Some(Arc::new(Self {
+            dir: dir.into(),
+            args: ctx.args().to_vec(),
+            env,
+        }))
+    }
+
+    /// Write a bundle if `error` is a trap.
+    pub fn capture(
+        &self,
+        error: &anyhow::Error,
+        request: Option<RequestSnapshot>,
+        fuel_consumed: Option<u64>,
+    ) {
+        let Some(trap) = error.downcast_ref::<Trap>() else {
+            return;
+        };
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let bundle = Bundle {
+            timestamp_ms,
+            error: format!("{error:#}"),
+            trap: trap.to_string(),
+            backtrace: error
+                .downcast_ref::<WasmBacktrace>()
+                .map(ToString::to_string),
+            args: &self.args,
+            env: &self.env,
+            request,
+            fuel_consumed,
+            stderr: stderr_tail(),
+        };
+
+        let path = self
+            .dir
+            .join(format!("trap-{timestamp_ms}-{}.json", std::process::id()));
+        let result = std::fs::create_dir_all(&self.dir)
+            .and_then(|_| std::fs::write(&path, serde_json::to_vec_pretty(&bundle)?));
+        match result {
+            Ok(()) => tracing::info!("wrote trap diagnostics to {path:?}"),
+            Err(e) => tracing::warn!("failed to write trap diagnostics to {path:?}: {e}"),
+        }
+    }
+
+    /// The stderr of the guest, keeping its tail for the bundles.
+    pub fn stderr(&self) -> impl StdoutStream {
+        TailedStderr
+    }
+}
+
+fn is_secret(name: &str) -> bool {
+    let name = name.to_ascii_uppercase();
+    ["SECRET", "TOKEN", "PASSWORD", "KEY"]
+        .iter()
+        .any(|word| name.contains(word))
+}
+
+fn redact_headers(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = match *name {
+                header::AUTHORIZATION
+                | header::PROXY_AUTHORIZATION
+                | header::COOKIE
+                | header::SET_COOKIE => REDACTED.to_string(),
+                _ => String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            };
+            (name.to_string(), value)
+        })
+        .collect()
+}
+
+fn stderr_tail() -> String {
+    let tail = STDERR_TAIL.lock().unwrap();
+    let (a, b) = tail.as_slices();
+    String::from_utf8_lossy(&[a, b].concat()).into_owned()
+}
+
+fn append_stderr_tail(data: &[u8]) {
+    let mut tail = STDERR_TAIL.lock().unwrap();
+    let data = &data[data.len().saturating_sub(STDERR_TAIL_SIZE)..];
+    let overflow = (tail.len() + data.len()).saturating_sub(STDERR_TAIL_SIZE);
+    tail.drain(..overflow);
+    tail.extend(data);
+}
+
+/// The stderr of the process, i.e. of the container once redirected.
+struct TailedStderr;
+
+impl StdoutStream for TailedStderr {
+    fn stream(&self) -> Box<dyn HostOutputStream> {
+        Box::new(AsyncWriteStream::new(
+            WRITE_BUDGET,
+            TailWriter(tokio::io::stderr()),
+        ))
+    }
+
+    fn isatty(&self) -> bool {
+        false
+    }
+}
+
+struct TailWriter(tokio::io::Stderr);
+
+impl AsyncWrite for TailWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let written = ready!(Pin::new(&mut self.0).poll_write(cx, buf))?;
+        append_stderr_tail(&buf[..written]);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stderr_tail() {
+        append_stderr_tail(&vec![b'a'; STDERR_TAIL_SIZE - 2]);
+        append_stderr_tail(b"bcde");
+        let tail = stderr_tail();
+        assert_eq!(tail.len(), STDERR_TAIL_SIZE);
+        assert!(tail.starts_with("aa"));
+        assert!(tail.ends_with("abcde"));
+
+        append_stderr_tail(&vec![b'z'; STDERR_TAIL_SIZE + 1]);
+        assert_eq!(stderr_tail(), "z".repeat(STDERR_TAIL_SIZE));
+    }
+
+    #[test]
+    fn test_is_secret() {
+        assert!(is_secret("API_KEY"));
+        assert!(is_secret("github_token"));
+        assert!(!is_secret("PATH"));
+    }
+}
@@ -0,0 +1,138 @@
+//! Epoch ticker shared by the engines of the shim.
+//!
+//! Engines are created with epoch interruption, and stores yield to the async executor every time
+//! the epoch of their engine is incremented. Rather than each feature running its own timer, a
+//! single thread increments the epoch of the engines with live [`EpochRegistration`]s, at the
+//! tightest interval requested. The thread is paused while there is no registration, so idle
+//! containers don't wake up.
+
+use std::sync::{Condvar, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use wasmtime::Engine;
+
+static TICKER: LazyLock<Ticker> = LazyLock::new(Ticker::default);
+
+/// Increment the epoch of `engine` at least every `interval`, until the registration is dropped.
+pub fn register(engine: &Engine, interval: Duration) -> EpochRegistration {
+    TICKER.register(engine, interval)
+}
+
+/// Keeps the epoch of an engine ticking.
+pub struct EpochRegistration {
+    id: u64,
+}
+
+impl Drop for EpochRegistration {
+    fn drop(&mut self) {
+        TICKER.unregister(self.id);
+    }
+}
+
+#[derive(Default)]
+struct Ticker {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    next_id: u64,
+    registrations: Vec<(u64, Engine, Duration)>,
+    /// The process running the ticker thread. The thread doesn't survive a fork.
+    thread_pid: Option<u32>,
+}
+
+impl State {
+    /// The tightest interval requested, `None` when idle.
+    fn interval(&self) -> Option<Duration> {
+        self.registrations.iter().map(|(_, _, i)| *i).min()
+    }
+
+    /// The distinct engines with a registration.
+    fn engines(&self) -> Vec<Engine> {
+        let mut engines: Vec<Engine> = vec![];
+        for (_, engine, _) in &self.registrations {
+            if !engines.iter().any(|e| Engine::same(e, engine)) {
+                engines.push(engine.clone());
+            }
+        }
+        engines
+    }
+}
+
+impl Ticker {
+    fn register(&'static self, engine: &Engine, interval: Duration) -> EpochRegistration {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.registrations.push((id, engine.clone(), interval));
+
+        let pid = std::process::id();
+        if state.thread_pid != Some(pid) {
+            state.thread_pid = Some(pid);
+            std::thread::Builder::new()
+                .name("epoch-ticker".into())
+                .spawn(|| self.run())
+                .expect("failed to spawn the epoch ticker");
+        }
+
+        self.changed.notify_one();
+        EpochRegistration { id }
+    }
+
+    fn unregister(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.registrations.retain(|(i, _, _)| *i != id);
+        self.changed.notify_one();
+    }
+
+    fn run(&self) {
+        let mut state = self.state.lock().unwrap();
+        let mut last_tick = Instant::now();
+        loop {
+            let Some(interval) = state.interval() else {
+                // Paused until the next registration
+                state = self.changed.wait(state).unwrap();
+                last_tick = Instant::now();
+                continue;
+            };
+
+            let elapsed = last_tick.elapsed();
+            if elapsed < interval {
+                // Woken up early when the registrations change, e.g. for a tighter interval
+                state = self
+                    .changed
+                    .wait_timeout(state, interval - elapsed)
+                    .unwrap()
+                    .0;
+                continue;
+            }
+
+            for engine in state.engines() {
+                engine.increment_epoch();
+            }
+            last_tick = Instant::now();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state() {
+        let (a, b) = (Engine::default(), Engine::default());
+        let mut state = State::default();
+        assert_eq!(state.interval(), None);
+
+        state.registrations = vec![
+            (0, a.clone(), Duration::from_millis(100)),
+            (1, a.clone(), Duration::from_millis(10)),
+            (2, b.clone(), Duration::from_secs(1)),
+        ];
+        assert_eq!(state.interval(), Some(Duration::from_millis(10)));
+        assert_eq!(state.engines().len(), 2);
+    }
+}
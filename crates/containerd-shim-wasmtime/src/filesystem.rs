@@ -0,0 +1,409 @@
+//! The `wasi:filesystem/types` and `wasi:filesystem/preopens` interfaces of the components.
+//!
+//! The interfaces are implemented by wasmtime, but the shim needs to see the operations of the
+//! guest on its preopened directories to enforce the [write quotas](crate::write_quota) and to
+//! write the [audit log](crate::audit). The implementation of wasmtime is wrapped, and replaces it
+//! in the linkers.
+
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Result;
+use wasmtime::component::{Linker, Resource};
+use wasmtime_wasi::bindings::filesystem::preopens;
+use wasmtime_wasi::bindings::filesystem::types::{
+    self, ErrorCode, HostDescriptor, HostDirectoryEntryStream,
+};
+use wasmtime_wasi::bindings::io::streams::{InputStream, OutputStream};
+use wasmtime_wasi::{async_trait, FsError, FsResult, WasiImpl};
+
+use crate::audit::Audit;
+use crate::instance::WasiPreview2Ctx;
+use crate::write_quota::{QuotaStream, WriteQuota};
+
+/// Replace the `wasi:filesystem/types` and `wasi:filesystem/preopens` interfaces of the linker
+/// with [`Filesystem`].
+pub(crate) fn add_to_linker(linker: &mut Linker<WasiPreview2Ctx>) -> Result<()> {
+    linker.allow_shadowing(true);
+    let res = types::add_to_linker_get_host(linker, filesystem)
+        .and_then(|()| preopens::add_to_linker_get_host(linker, filesystem));
+    linker.allow_shadowing(false);
+    res
+}
+
+fn filesystem(ctx: &mut WasiPreview2Ctx) -> Filesystem<'_> {
+    let audit = ctx.audit.clone();
+    Filesystem {
+        wasi: WasiImpl(ctx),
+        audit,
+    }
+}
+
+/// The `wasi:filesystem` implementation of wasmtime, with the writes accounted for and the opens
+/// audited.
+struct Filesystem<'a> {
+    wasi: WasiImpl<&'a mut WasiPreview2Ctx>,
+    audit: Option<Arc<Audit>>,
+}
+
+impl Filesystem<'_> {
+    /// The write quota of the descriptor `fd`, the one of the guest path it was opened at.
+    fn quota(&self, fd: &Resource<types::Descriptor>) -> Option<Arc<WriteQuota>> {
+        self.wasi.0.descriptor_quotas.get(&fd.rep()).cloned()
+    }
+
+    /// Account for the writes to the output stream `stream`, within `quota`.
+    fn wrap_stream(
+        &mut self,
+        stream: Resource<OutputStream>,
+        quota: Option<Arc<WriteQuota>>,
+    ) -> FsResult<Resource<OutputStream>> {
+        let Some(quota) = quota else {
+            return Ok(stream);
+        };
+        let table = &mut self.wasi.0.resource_table;
+        let inner = table.delete(stream)?;
+        let stream: OutputStream = Box::new(QuotaStream::new(inner, quota));
+        Ok(table.push(stream)?)
+    }
+
+    /// The guest path of the directory `fd`, the preopened root unless opened by the guest.
+    fn dir_path(&self, fd: &Resource<types::Descriptor>) -> PathBuf {
+        let paths = &self.wasi.0.descriptor_paths;
+        paths.get(&fd.rep()).cloned().unwrap_or_else(|| "/".into())
+    }
+}
+
+impl preopens::Host for Filesystem<'_> {
+    fn get_directories(&mut self) -> Result<Vec<(Resource<types::Descriptor>, String)>> {
+        let directories = preopens::Host::get_directories(&mut self.wasi)?;
+        if let Some(quotas) = self.wasi.0.write_quotas.clone() {
+            for (fd, guest) in &directories {
+                if let Some(quota) = quotas.quota(Path::new(guest)) {
+                    self.wasi.0.descriptor_quotas.insert(fd.rep(), quota);
+                }
+            }
+        }
+        Ok(directories)
+    }
+}
+
+#[async_trait]
+impl<'a> types::Host for Filesystem<'a> {
+    fn convert_error_code(&mut self, err: FsError) -> Result<ErrorCode> {
+        types::Host::convert_error_code(&mut self.wasi, err)
+    }
+
+    fn filesystem_error_code(&mut self, err: Resource<anyhow::Error>) -> Result<Option<ErrorCode>> {
+        types::Host::filesystem_error_code(&mut self.wasi, err)
+    }
+}
+
+#[async_trait]
+impl<'a> HostDescriptor for Filesystem<'a> {
+    async fn write(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        buf: Vec<u8>,
+        offset: types::Filesize,
+    ) -> FsResult<types::Filesize> {
+        let Some(quota) = self.quota(&fd) else {
+            return self.wasi.write(fd, buf, offset).await;
+        };
+        let len = buf.len() as u64;
+        quota.reserve(len)?;
+        let res = self.wasi.write(fd, buf, offset).await;
+        quota.release(len - res.as_ref().map_or(0, |written| (*written).min(len)));
+        res
+    }
+
+    fn write_via_stream(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        offset: types::Filesize,
+    ) -> FsResult<Resource<OutputStream>> {
+        let quota = self.quota(&fd);
+        let stream = self.wasi.write_via_stream(fd, offset)?;
+        self.wrap_stream(stream, quota)
+    }
+
+    fn append_via_stream(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+    ) -> FsResult<Resource<OutputStream>> {
+        let quota = self.quota(&fd);
+        let stream = self.wasi.append_via_stream(fd)?;
+        self.wrap_stream(stream, quota)
+    }
+
+    fn read_via_stream(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        offset: types::Filesize,
+    ) -> FsResult<Resource<InputStream>> {
+        self.wasi.read_via_stream(fd, offset)
+    }
+
+    async fn advise(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        offset: types::Filesize,
+        len: types::Filesize,
+        advice: types::Advice,
+    ) -> FsResult<()> {
+        self.wasi.advise(fd, offset, len, advice).await
+    }
+
+    async fn sync_data(&mut self, fd: Resource<types::Descriptor>) -> FsResult<()> {
+        self.wasi.sync_data(fd).await
+    }
+
+    async fn get_flags(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+    ) -> FsResult<types::DescriptorFlags> {
+        self.wasi.get_flags(fd).await
+    }
+
+    async fn get_type(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+    ) -> FsResult<types::DescriptorType> {
+        self.wasi.get_type(fd).await
+    }
+
+    async fn set_size(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        size: types::Filesize,
+    ) -> FsResult<()> {
+        self.wasi.set_size(fd, size).await
+    }
+
+    async fn set_times(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        atim: types::NewTimestamp,
+        mtim: types::NewTimestamp,
+    ) -> FsResult<()> {
+        self.wasi.set_times(fd, atim, mtim).await
+    }
+
+    async fn read(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        len: types::Filesize,
+        offset: types::Filesize,
+    ) -> FsResult<(Vec<u8>, bool)> {
+        self.wasi.read(fd, len, offset).await
+    }
+
+    async fn read_directory(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+    ) -> FsResult<Resource<types::DirectoryEntryStream>> {
+        self.wasi.read_directory(fd).await
+    }
+
+    async fn sync(&mut self, fd: Resource<types::Descriptor>) -> FsResult<()> {
+        self.wasi.sync(fd).await
+    }
+
+    async fn create_directory_at(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        path: String,
+    ) -> FsResult<()> {
+        self.wasi.create_directory_at(fd, path).await
+    }
+
+    async fn stat(&mut self, fd: Resource<types::Descriptor>) -> FsResult<types::DescriptorStat> {
+        self.wasi.stat(fd).await
+    }
+
+    async fn stat_at(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        path_flags: types::PathFlags,
+        path: String,
+    ) -> FsResult<types::DescriptorStat> {
+        self.wasi.stat_at(fd, path_flags, path).await
+    }
+
+    async fn set_times_at(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        path_flags: types::PathFlags,
+        path: String,
+        atim: types::NewTimestamp,
+        mtim: types::NewTimestamp,
+    ) -> FsResult<()> {
+        self.wasi
+            .set_times_at(fd, path_flags, path, atim, mtim)
+            .await
+    }
+
+    async fn link_at(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        old_path_flags: types::PathFlags,
+        old_path: String,
+        new_descriptor: Resource<types::Descriptor>,
+        new_path: String,
+    ) -> FsResult<()> {
+        self.wasi
+            .link_at(fd, old_path_flags, old_path, new_descriptor, new_path)
+            .await
+    }
+
+    async fn open_at(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        path_flags: types::PathFlags,
+        path: String,
+        oflags: types::OpenFlags,
+        flags: types::DescriptorFlags,
+    ) -> FsResult<Resource<types::Descriptor>> {
+        let quotas = self.wasi.0.write_quotas.clone();
+        let guest_path = (self.audit.is_some() || quotas.is_some())
+            .then(|| normalize(&self.dir_path(&fd).join(&path)));
+        // The descriptor takes the quota of its guest path
+        let quota = quotas
+            .zip(guest_path.as_ref())
+            .and_then(|(q, p)| q.quota(p));
+        let write = flags.contains(types::DescriptorFlags::WRITE)
+            || oflags.intersects(types::OpenFlags::CREATE | types::OpenFlags::TRUNCATE);
+
+        let res = self.wasi.open_at(fd, path_flags, path, oflags, flags).await;
+        if let (Some(audit), Some(guest_path)) = (&self.audit, &guest_path) {
+            audit.open(guest_path, write, res.is_ok());
+        }
+        if let Ok(fd) = &res {
+            if let Some(quota) = quota {
+                self.wasi.0.descriptor_quotas.insert(fd.rep(), quota);
+            }
+            if let Some(guest_path) = guest_path {
+                self.wasi.0.descriptor_paths.insert(fd.rep(), guest_path);
+            }
+        }
+        res
+    }
+
+    fn drop(&mut self, fd: Resource<types::Descriptor>) -> Result<()> {
+        self.wasi.0.descriptor_paths.remove(&fd.rep());
+        self.wasi.0.descriptor_quotas.remove(&fd.rep());
+        HostDescriptor::drop(&mut self.wasi, fd)
+    }
+
+    async fn readlink_at(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        path: String,
+    ) -> FsResult<String> {
+        self.wasi.readlink_at(fd, path).await
+    }
+
+    async fn remove_directory_at(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        path: String,
+    ) -> FsResult<()> {
+        self.wasi.remove_directory_at(fd, path).await
+    }
+
+    async fn rename_at(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        old_path: String,
+        new_fd: Resource<types::Descriptor>,
+        new_path: String,
+    ) -> FsResult<()> {
+        self.wasi.rename_at(fd, old_path, new_fd, new_path).await
+    }
+
+    async fn symlink_at(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        src_path: String,
+        dest_path: String,
+    ) -> FsResult<()> {
+        self.wasi.symlink_at(fd, src_path, dest_path).await
+    }
+
+    async fn unlink_file_at(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        path: String,
+    ) -> FsResult<()> {
+        self.wasi.unlink_file_at(fd, path).await
+    }
+
+    async fn is_same_object(
+        &mut self,
+        a: Resource<types::Descriptor>,
+        b: Resource<types::Descriptor>,
+    ) -> Result<bool> {
+        self.wasi.is_same_object(a, b).await
+    }
+
+    async fn metadata_hash(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+    ) -> FsResult<types::MetadataHashValue> {
+        self.wasi.metadata_hash(fd).await
+    }
+
+    async fn metadata_hash_at(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        path_flags: types::PathFlags,
+        path: String,
+    ) -> FsResult<types::MetadataHashValue> {
+        self.wasi.metadata_hash_at(fd, path_flags, path).await
+    }
+}
+
+#[async_trait]
+impl<'a> HostDirectoryEntryStream for Filesystem<'a> {
+    async fn read_directory_entry(
+        &mut self,
+        stream: Resource<types::DirectoryEntryStream>,
+    ) -> FsResult<Option<types::DirectoryEntry>> {
+        self.wasi.read_directory_entry(stream).await
+    }
+
+    fn drop(&mut self, stream: Resource<types::DirectoryEntryStream>) -> Result<()> {
+        HostDirectoryEntryStream::drop(&mut self.wasi, stream)
+    }
+}
+
+/// Resolve the `.` and `..` components of the absolute `path`, without following symlinks.
+pub(crate) fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::from("/");
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::Normal(name) => normalized.push(name),
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize(Path::new("/")), Path::new("/"));
+        assert_eq!(
+            normalize(Path::new("/etc/./ssl/../passwd")),
+            Path::new("/etc/passwd")
+        );
+        assert_eq!(normalize(Path::new("/../../etc")), Path::new("/etc"));
+        assert_eq!(normalize(Path::new("/app/data/")), Path::new("/app/data"));
+    }
+}
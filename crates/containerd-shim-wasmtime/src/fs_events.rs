@@ -0,0 +1,477 @@
+//! Change events of the directories of the container, handed to the guest.
+//!
+//! WASI has no way to watch files, so a guest reloading its configuration, e.g. from a mounted
+//! ConfigMap, has to poll it. The directories declared with the `runwasi.io/fs-events`
+//! annotation, as a comma separated list of absolute paths, are watched by the shim instead, and
+//! the guest reads their changes through the `runwasi:fs/events` host interface.
+//!
+//! The directories are watched with inotify, and are polled every second when inotify is not
+//! available, e.g. when the instances of inotify of the node are exhausted. Only the entries
+//! directly in the directories are watched, not their subdirectories.
+//!
+//! Every change has a sequence number, and the guest reads the changes since a cursor, so that
+//! the instances of a `wasi:http/proxy` component, created for each request, see the same changes.
+
+use std::collections::{HashMap, VecDeque};
+use std::ffi::{CString, OsStr, OsString};
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Result};
+use containerd_shim_wasm::container::RuntimeContext;
+use tokio::io::unix::AsyncFd;
+use tokio::sync::Notify;
+use wasmtime::component::Linker;
+use wasmtime::StoreContextMut;
+
+use crate::instance::WasiPreview2Ctx;
+
+pub const FS_EVENTS_ANNOTATION: &str = "runwasi.io/fs-events";
+
+pub(crate) const INTERFACE: &str = "runwasi:fs/events@0.1.0";
+
+/// Number of changes kept for the guest, older ones are replaced by an overflow event.
+const CAPACITY: usize = 1024;
+
+/// Interval of the polling of the directories that can't be watched with inotify.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The changes returned to the guest: the next cursor, and `(path, kind)` pairs.
+type Changes = (u64, Vec<(String, String)>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    /// An entry was created or moved into the directory, e.g. the `..data` symlink swapped when a
+    /// ConfigMap is updated.
+    Created,
+    /// A file was written and closed.
+    Modified,
+    /// An entry was removed or moved out of the directory.
+    Removed,
+    /// Changes were lost, the guest should read the directories again.
+    Overflow,
+}
+
+impl EventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::Created => "created",
+            EventKind::Modified => "modified",
+            EventKind::Removed => "removed",
+            EventKind::Overflow => "overflow",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    /// The changed path, empty for [`EventKind::Overflow`].
+    pub path: PathBuf,
+    pub kind: EventKind,
+}
+
+#[derive(Default)]
+struct Log {
+    /// The last changes, the first one with sequence number `next - events.len()`.
+    events: VecDeque<Event>,
+    next: u64,
+}
+
+/// The changes of the watched directories of a container.
+#[derive(Default)]
+pub struct FsEvents {
+    log: Mutex<Log>,
+    notify: Notify,
+}
+
+impl FsEvents {
+    /// Start watching the directories declared in the container annotations, if any.
+    ///
+    /// This must be called from within a tokio runtime.
+    pub fn watch(ctx: &impl RuntimeContext) -> Result<Option<Arc<Self>>> {
+        let Some(value) = ctx.annotations().get(FS_EVENTS_ANNOTATION) else {
+            return Ok(None);
+        };
+        let dirs = parse_dirs(value)?;
+        if dirs.is_empty() {
+            return Ok(None);
+        }
+
+        let events = Arc::new(Self::default());
+        let watched = Arc::downgrade(&events);
+        match Inotify::new(&dirs) {
+            Ok(inotify) => {
+                tracing::info!("watching {dirs:?} with inotify");
+                tokio::spawn(inotify.run(watched));
+            }
+            Err(err) => {
+                tracing::warn!("inotify is not available ({err}), polling {dirs:?}");
+                tokio::spawn(poll(dirs, watched));
+            }
+        }
+        Ok(Some(events))
+    }
+
+    fn push(&self, path: PathBuf, kind: EventKind) {
+        tracing::debug!("{} {path:?}", kind.as_str());
+        let mut log = self.log.lock().unwrap();
+        if log.events.len() == CAPACITY {
+            log.events.pop_front();
+        }
+        log.events.push_back(Event { path, kind });
+        log.next += 1;
+        drop(log);
+        self.notify.notify_waiters();
+    }
+
+    /// The changes since `cursor`, and the cursor of the next changes.
+    ///
+    /// A cursor older than the changes that are kept is answered with an overflow event.
+    pub fn since(&self, cursor: u64) -> (u64, Vec<Event>) {
+        let log = self.log.lock().unwrap();
+        let first = log.next - log.events.len() as u64;
+        let mut events = vec![];
+        if cursor < first {
+            events.push(Event {
+                path: PathBuf::new(),
+                kind: EventKind::Overflow,
+            });
+        }
+        let skip = cursor.saturating_sub(first).min(log.events.len() as u64);
+        events.extend(log.events.iter().skip(skip as usize).cloned());
+        (log.next, events)
+    }
+
+    /// Wait up to `timeout` for changes since `cursor`.
+    pub async fn wait(&self, cursor: u64, timeout: Duration) -> (u64, Vec<Event>) {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let notified = self.notify.notified();
+            let changes = self.since(cursor);
+            if !changes.1.is_empty() {
+                return changes;
+            }
+            if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                return changes;
+            }
+        }
+    }
+}
+
+/// Parse a `/path[,/path]` declaration of watched directories.
+pub(crate) fn parse_dirs(value: &str) -> Result<Vec<PathBuf>> {
+    let mut dirs: Vec<PathBuf> = vec![];
+    for dir in value.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+        let dir = PathBuf::from(dir);
+        if !dir.is_absolute() {
+            bail!("invalid watched directory {dir:?}, expected an absolute path");
+        }
+        if !dirs.contains(&dir) {
+            dirs.push(dir);
+        }
+    }
+    Ok(dirs)
+}
+
+struct Inotify {
+    fd: AsyncFd<OwnedFd>,
+    dirs: HashMap<i32, PathBuf>,
+}
+
+impl Inotify {
+    fn new(dirs: &[PathBuf]) -> io::Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mask = libc::IN_CREATE
+            | libc::IN_MOVED_TO
+            | libc::IN_CLOSE_WRITE
+            | libc::IN_DELETE
+            | libc::IN_MOVED_FROM
+            | libc::IN_DELETE_SELF;
+        let mut watches = HashMap::new();
+        for dir in dirs {
+            let path = CString::new(dir.as_os_str().as_bytes())?;
+            let wd = unsafe { libc::inotify_add_watch(fd.as_raw_fd(), path.as_ptr(), mask) };
+            if wd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            watches.insert(wd, dir.clone());
+        }
+
+        Ok(Self {
+            fd: AsyncFd::new(fd)?,
+            dirs: watches,
+        })
+    }
+
+    async fn run(self, events: Weak<FsEvents>) {
+        let mut buf = vec![0u8; 4096];
+        loop {
+            let read = match self.read(&mut buf).await {
+                Ok(read) => read,
+                Err(err) => {
+                    tracing::warn!("failed to read inotify events: {err}");
+                    return;
+                }
+            };
+            let Some(events) = events.upgrade() else {
+                return;
+            };
+            for (wd, mask, name) in parse_inotify(&buf[..read]) {
+                let kind = if mask & libc::IN_Q_OVERFLOW != 0 {
+                    events.push(PathBuf::new(), EventKind::Overflow);
+                    continue;
+                } else if mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 {
+                    EventKind::Created
+                } else if mask & libc::IN_CLOSE_WRITE != 0 {
+                    EventKind::Modified
+                } else if mask & (libc::IN_DELETE | libc::IN_MOVED_FROM | libc::IN_DELETE_SELF) != 0
+                {
+                    EventKind::Removed
+                } else {
+                    continue;
+                };
+                let Some(dir) = self.dirs.get(&wd) else {
+                    continue;
+                };
+                events.push(dir.join(name), kind);
+            }
+        }
+    }
+
+    async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.fd.readable().await?;
+            let read = guard.try_io(|fd| {
+                let n = unsafe { libc::read(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+                if n < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(n as usize)
+            });
+            match read {
+                Ok(result) => return result,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+/// Parse the `(wd, mask, name)` of the inotify events in `buf`.
+fn parse_inotify(buf: &[u8]) -> Vec<(i32, u32, &OsStr)> {
+    const HEADER: usize = size_of::<libc::inotify_event>();
+
+    let mut events = vec![];
+    let mut offset = 0;
+    while offset + HEADER <= buf.len() {
+        let event: libc::inotify_event =
+            unsafe { std::ptr::read_unaligned(buf[offset..].as_ptr().cast()) };
+        let start = offset + HEADER;
+        let end = (start + event.len as usize).min(buf.len());
+        // The name is padded with NULs
+        let name = buf[start..end]
+            .split(|b| *b == 0)
+            .next()
+            .unwrap_or_default();
+        events.push((event.wd, event.mask, OsStr::from_bytes(name)));
+        offset = end;
+    }
+    events
+}
+
+type Snapshot = HashMap<OsString, (Option<SystemTime>, u64)>;
+
+/// Poll `dirs` for changes, until `events` is dropped.
+async fn poll(dirs: Vec<PathBuf>, events: Weak<FsEvents>) {
+    let mut snapshots: Vec<_> = dirs.iter().map(|dir| snapshot(dir)).collect();
+    loop {
+        tokio::time::sleep(POLL_INTERVAL).await;
+        let Some(events) = events.upgrade() else {
+            return;
+        };
+        for (dir, old) in dirs.iter().zip(snapshots.iter_mut()) {
+            let new = snapshot(dir);
+            for (name, kind) in diff(old, &new) {
+                events.push(dir.join(name), kind);
+            }
+            *old = new;
+        }
+    }
+}
+
+fn snapshot(dir: &Path) -> Snapshot {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Snapshot::default();
+    };
+    entries
+        .flatten()
+        .map(|entry| {
+            // Symlinks are not followed, e.g. swapping the `..data` symlink of a ConfigMap
+            // changes its own modification time
+            let meta = entry.path().symlink_metadata().ok();
+            let mtime = meta.as_ref().and_then(|meta| meta.modified().ok());
+            (
+                entry.file_name(),
+                (mtime, meta.map_or(0, |meta| meta.len())),
+            )
+        })
+        .collect()
+}
+
+/// The changes from the `old` to the `new` snapshot of a directory, sorted by name.
+fn diff(old: &Snapshot, new: &Snapshot) -> Vec<(OsString, EventKind)> {
+    let mut changes: Vec<_> = new
+        .iter()
+        .filter_map(|(name, entry)| match old.get(name) {
+            None => Some((name.clone(), EventKind::Created)),
+            Some(old) if old != entry => Some((name.clone(), EventKind::Modified)),
+            Some(_) => None,
+        })
+        .chain(
+            old.keys()
+                .filter(|name| !new.contains_key(*name))
+                .map(|name| (name.clone(), EventKind::Removed)),
+        )
+        .collect();
+    changes.sort_by(|(a, _), (b, _)| a.cmp(b));
+    changes
+}
+
+fn to_guest((next, events): (u64, Vec<Event>)) -> Changes {
+    let events = events
+        .into_iter()
+        .map(|event| {
+            let path = event.path.to_string_lossy().into_owned();
+            (path, event.kind.as_str().to_string())
+        })
+        .collect();
+    (next, events)
+}
+
+/// Add the `runwasi:fs/events` interface to the linker.
+///
+/// The interface exports two functions, returning the next cursor and `(path, kind)` pairs:
+/// `poll: func(cursor: u64) -> tuple<u64, list<tuple<string, string>>>`
+/// `wait: func(cursor: u64, timeout-ms: u64) -> tuple<u64, list<tuple<string, string>>>`
+/// Without watched directories, no change is ever returned.
+pub(crate) fn add_to_linker(linker: &mut Linker<WasiPreview2Ctx>) -> Result<()> {
+    let mut instance = linker.instance(INTERFACE)?;
+    instance.func_wrap(
+        "poll",
+        |store: StoreContextMut<'_, WasiPreview2Ctx>, (cursor,): (u64,)| -> Result<(Changes,)> {
+            let changes = match &store.data().fs_events {
+                Some(events) => events.since(cursor),
+                None => (cursor, vec![]),
+            };
+            Ok((to_guest(changes),))
+        },
+    )?;
+    instance.func_wrap_async(
+        "wait",
+        |store: StoreContextMut<'_, WasiPreview2Ctx>, (cursor, timeout_ms): (u64, u64)| {
+            let events = store.data().fs_events.clone();
+            Box::new(async move {
+                let timeout = Duration::from_millis(timeout_ms);
+                let changes = match events {
+                    Some(events) => events.wait(cursor, timeout).await,
+                    None => {
+                        tokio::time::sleep(timeout).await;
+                        (cursor, vec![])
+                    }
+                };
+                Ok((to_guest(changes),))
+            })
+        },
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_dirs() -> Result<()> {
+        assert_eq!(
+            parse_dirs("/config, /secrets,/config")?,
+            vec![PathBuf::from("/config"), PathBuf::from("/secrets")]
+        );
+        assert!(parse_dirs("")?.is_empty());
+        assert!(parse_dirs("config").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_since() {
+        let events = FsEvents::default();
+        assert_eq!(events.since(0), (0, vec![]));
+
+        events.push("/config/a".into(), EventKind::Created);
+        events.push("/config/a".into(), EventKind::Modified);
+        let (next, changes) = events.since(0);
+        assert_eq!(next, 2);
+        assert_eq!(changes.len(), 2);
+        assert_eq!(events.since(1).1[0].kind, EventKind::Modified);
+        assert!(events.since(2).1.is_empty());
+
+        for _ in 0..CAPACITY {
+            events.push("/config/b".into(), EventKind::Modified);
+        }
+        let (next, changes) = events.since(0);
+        assert_eq!(next, CAPACITY as u64 + 2);
+        assert_eq!(changes[0].kind, EventKind::Overflow);
+        assert_eq!(changes.len(), CAPACITY + 1);
+    }
+
+    #[test]
+    fn test_diff() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("a"), "a")?;
+        std::fs::write(dir.path().join("b"), "b")?;
+        let old = snapshot(dir.path());
+
+        std::fs::write(dir.path().join("a"), "aa")?;
+        std::fs::remove_file(dir.path().join("b"))?;
+        std::fs::write(dir.path().join("c"), "c")?;
+        let new = snapshot(dir.path());
+
+        assert_eq!(
+            diff(&old, &new),
+            vec![
+                ("a".into(), EventKind::Modified),
+                ("b".into(), EventKind::Removed),
+                ("c".into(), EventKind::Created),
+            ]
+        );
+        assert!(diff(&new, &new).is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_inotify() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let events = Arc::new(FsEvents::default());
+        let inotify = Inotify::new(&[dir.path().to_path_buf()])?;
+        tokio::spawn(inotify.run(Arc::downgrade(&events)));
+
+        std::fs::write(dir.path().join("config.json"), "{}")?;
+        let (_, changes) = events.wait(0, Duration::from_secs(5)).await;
+        assert_eq!(
+            changes[0],
+            Event {
+                path: dir.path().join("config.json"),
+                kind: EventKind::Created,
+            }
+        );
+        Ok(())
+    }
+}
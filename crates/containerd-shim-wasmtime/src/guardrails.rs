@@ -0,0 +1,187 @@
+//! Guardrails on the resources of the shim process.
+//!
+//! The guests of a container share its process: when they grow past its cgroup limit, the kernel
+//! kills the process with all of them. The caps are set with environment variables of the shim:
+//! * `RUNWASI_WASMTIME_MEMORY_CAP`: bytes of resident memory of the process.
+//! * `RUNWASI_WASMTIME_CPU_CAP`: CPU cores used by the process, e.g. `1.5`.
+//!
+//! When the process uses more than 90% of a cap, it sheds load: the HTTP proxy answers the new
+//! requests with `503 Service Unavailable`, and the TCP handler closes the new connections,
+//! instead of instantiating more guests. The in-flight requests are not interrupted. The memory
+//! is sampled at most every 100ms, and the CPU usage is averaged over one second.
+//!
+//! The memory is only sampled on Linux, and the CPU usage on Unix: elsewhere, the process is never
+//! considered close to its caps.
+
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, ensure, Result};
+
+pub const MEMORY_CAP_ENV: &str = "RUNWASI_WASMTIME_MEMORY_CAP";
+pub const CPU_CAP_ENV: &str = "RUNWASI_WASMTIME_CPU_CAP";
+
+/// Share of the caps above which the load is shed.
+const SHED_RATIO: f64 = 0.9;
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+const CPU_WINDOW: Duration = Duration::from_secs(1);
+
+static GUARDRAILS: LazyLock<Option<Guardrails>> = LazyLock::new(|| {
+    // Invalid caps are reported when the container is created
+    Guardrails::from_env().ok().flatten()
+});
+
+/// Whether the process is close to its caps, and new work should be rejected.
+pub fn should_shed() -> bool {
+    GUARDRAILS.as_ref().is_some_and(Guardrails::should_shed)
+}
+
+pub struct Guardrails {
+    memory_cap: Option<u64>,
+    cpu_cap: Option<f64>,
+    state: Mutex<State>,
+}
+
+struct State {
+    sampled_at: Option<Instant>,
+    shedding: bool,
+    cpu_window_start: (Instant, Duration),
+    cpu_usage: f64,
+}
+
+impl Guardrails {
+    pub fn new(memory_cap: Option<u64>, cpu_cap: Option<f64>) -> Self {
+        Self {
+            memory_cap,
+            cpu_cap,
+            state: Mutex::new(State {
+                sampled_at: None,
+                shedding: false,
+                cpu_window_start: (Instant::now(), cpu_time()),
+                cpu_usage: 0.0,
+            }),
+        }
+    }
+
+    /// The guardrails of the shim, if any cap is set.
+    pub fn from_env() -> Result<Option<Self>> {
+        let memory_cap = parse::<u64>(MEMORY_CAP_ENV)?;
+        let cpu_cap = parse::<f64>(CPU_CAP_ENV)?;
+        if let Some(cores) = cpu_cap {
+            ensure!(cores > 0.0, "{CPU_CAP_ENV} must be positive");
+        }
+        if memory_cap.is_none() && cpu_cap.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(Self::new(memory_cap, cpu_cap)))
+    }
+
+    fn should_shed(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        if state
+            .sampled_at
+            .is_some_and(|at| now.duration_since(at) < SAMPLE_INTERVAL)
+        {
+            return state.shedding;
+        }
+        state.sampled_at = Some(now);
+
+        let (start, start_cpu) = state.cpu_window_start;
+        if now.duration_since(start) >= CPU_WINDOW {
+            let cpu = cpu_time();
+            state.cpu_usage =
+                (cpu - start_cpu).as_secs_f64() / now.duration_since(start).as_secs_f64();
+            state.cpu_window_start = (now, cpu);
+        }
+
+        let shedding = self.over_caps(resident_memory(), state.cpu_usage);
+        if shedding != state.shedding {
+            if shedding {
+                tracing::warn!("close to the resource caps of the shim, shedding load");
+            } else {
+                tracing::info!("back under the resource caps of the shim");
+            }
+        }
+        state.shedding = shedding;
+        shedding
+    }
+
+    /// Whether `memory` bytes of resident memory, or `cpu` cores, are close to the caps.
+    fn over_caps(&self, memory: Option<u64>, cpu: f64) -> bool {
+        let memory = match (self.memory_cap, memory) {
+            (Some(cap), Some(memory)) => memory as f64 > cap as f64 * SHED_RATIO,
+            _ => false,
+        };
+        let cpu = self.cpu_cap.is_some_and(|cap| cpu > cap * SHED_RATIO);
+        memory || cpu
+    }
+}
+
+fn parse<T: std::str::FromStr>(name: &str) -> Result<Option<T>> {
+    let Ok(value) = std::env::var(name) else {
+        return Ok(None);
+    };
+    match value.trim().parse() {
+        Ok(value) => Ok(Some(value)),
+        Err(_) => bail!("invalid {name} {value:?}"),
+    }
+}
+
+/// The resident memory of the process, where it is known.
+#[cfg(target_os = "linux")]
+fn resident_memory() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Some(pages * u64::try_from(page_size).ok()?)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn resident_memory() -> Option<u64> {
+    None
+}
+
+/// The CPU time of the process.
+#[cfg(unix)]
+fn cpu_time() -> Duration {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::zeroed();
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) } != 0 {
+        return Duration::ZERO;
+    }
+    let usage = unsafe { usage.assume_init() };
+    let time = |t: libc::timeval| Duration::new(t.tv_sec as u64, t.tv_usec as u32 * 1000);
+    time(usage.ru_utime) + time(usage.ru_stime)
+}
+
+#[cfg(not(unix))]
+fn cpu_time() -> Duration {
+    Duration::ZERO
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_over_caps() {
+        let guardrails = Guardrails::new(Some(1000), Some(2.0));
+        assert!(!guardrails.over_caps(Some(900), 1.8));
+        assert!(guardrails.over_caps(Some(901), 0.0));
+        assert!(guardrails.over_caps(None, 1.9));
+        assert!(!guardrails.over_caps(None, 0.0));
+
+        let memory_only = Guardrails::new(Some(1000), None);
+        assert!(!memory_only.over_caps(Some(10), 64.0));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_samples() {
+        assert!(resident_memory().is_some_and(|memory| memory > 0));
+        let guardrails = Guardrails::new(Some(u64::MAX), None);
+        assert!(!guardrails.should_shed());
+        let tight = Guardrails::new(Some(1), None);
+        assert!(tight.should_shed());
+    }
+}
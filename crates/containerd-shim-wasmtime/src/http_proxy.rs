@@ -1,34 +1,129 @@
+//! HTTP proxy serving `wasi:http/proxy` components.
+//!
+//! The proxy is started by the shim for components exporting `wasi:http/incoming-handler`, but it
+//! can also be embedded outside of containerd: build a [`ProxyHandler`] from a [`ProxyConfig`] and
+//! pass it to [`serve`]. Cross-cutting features, e.g. authentication, are added as [`Middleware`]
+//! registered with [`ProxyHandler::with_middleware`].
+
 // Heavily inspired by wasmtime serve command:
 // https://github.com/bytecodealliance/wasmtime/blob/main/src/commands/serve.rs
 
-use std::collections::HashMap;
+mod acceptor;
+mod access_log;
+mod admin;
+mod body_limit;
+mod cache;
+mod concurrency;
+mod config;
+mod connection_env;
+mod cors;
+mod forwarded;
+mod grpc;
+mod guest_metrics;
+mod header_env;
+mod instance_pool;
+mod jwt;
+mod listen;
+mod middleware;
+mod mirror;
+mod normalize;
+mod probes;
+mod read_rate;
+mod recorder;
+mod route_limits;
+mod scaler;
+mod slots;
+mod static_files;
+mod streaming;
+
+use std::future::Future;
 use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 
-use anyhow::{bail, Result};
-use containerd_shim_wasm::container::RuntimeContext;
-use hyper::server::conn::http1;
+use anyhow::{anyhow, bail, Result};
+use containerd_shim_wasm::container::priority::Priority;
+use containerd_shim_wasm::container::{report_phase, Phase, RuntimeContext};
+use http_body_util::{BodyExt, Empty};
+use hyper::body::Body;
+use hyper::header::{self, HeaderValue};
+use hyper::server::conn::{http1, http2};
+use hyper::StatusCode;
+#[cfg(unix)]
+use tokio::net::UnixListener;
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+use tokio::time::Instant;
 use tokio_util::sync::CancellationToken;
 use tokio_util::task::TaskTracker;
+use tracing::Instrument;
 use wasmtime::component::ResourceTable;
 use wasmtime::Store;
-use wasmtime_wasi_http::bindings::http::types::Scheme;
-use wasmtime_wasi_http::bindings::ProxyPre;
+use wasmtime_wasi_http::bindings::{Proxy, ProxyPre};
 use wasmtime_wasi_http::body::HyperOutgoingBody;
 use wasmtime_wasi_http::io::TokioIo;
 use wasmtime_wasi_http::{WasiHttpCtx, WasiHttpView};
 
-use crate::instance::{envs_from_ctx, WasiPreview2Ctx};
+pub use self::acceptor::TlsConfig;
+use self::acceptor::{Acceptor, Stream};
+pub use self::access_log::AccessLog;
+use self::access_log::RequestId;
+use self::admin::{serve_admin, Admin};
+use self::body_limit::{BodyLimit, BodyTooLarge};
+pub use self::cache::{CacheStats, ResponseCache, ResponseCacheConfig};
+use self::concurrency::{ConcurrencyLimit, InFlight};
+pub use self::config::{ComponentRoute, ProxyConfig};
+use self::connection_env::connection_envs;
+pub use self::cors::Cors;
+pub use self::forwarded::{ClientAddr, Forwarded};
+use self::grpc::GrpcServices;
+pub use self::guest_metrics::GuestMetrics;
+pub use self::header_env::HeaderEnv;
+use self::instance_pool::InstancePool;
+pub use self::instance_pool::InstancePoolConfig;
+pub use self::jwt::{JwksSource, JwtAuth, JwtConfig};
+pub use self::listen::{ListenAddr, ListenScheme};
+pub use self::middleware::Middleware;
+use self::mirror::{GuestRequest, Mirror, MAX_MIRROR_BODY_SIZE};
+pub use self::normalize::Normalize;
+pub use self::read_rate::ReadRate;
+use self::read_rate::{MinReadRate, ReadWatch};
+use self::recorder::Recorder;
+pub use self::recorder::{Exchange, RecorderConfig};
+use self::route_limits::Routes;
+pub use self::route_limits::{RouteLimit, RouteLimits};
+use self::scaler::{serve_scaler, Scaler};
+use self::slots::Slots;
+use self::static_files::StaticFiles;
+pub use self::streaming::Streaming;
+use crate::audit::{component_imports, Audit};
+use crate::chaos::Chaos;
+use crate::diagnostics::{RequestSnapshot, TrapContext};
+#[cfg(unix)]
+use crate::fs_events::FsEvents;
+use crate::instance::{envs_from_ctx, new_store, WasiPreview2Ctx};
+use crate::kv_cache::KvCache;
+use crate::outgoing::Outgoing;
+use crate::profile::ResourceProfile;
+#[cfg(unix)]
+use crate::reload::{self, LiveConfig};
+use crate::timezone::TimeZone;
+use crate::{epoch, guardrails, quarantine};
 
 const DEFAULT_ADDR: SocketAddr =
     SocketAddr::new(IpAddr::V4(std::net::Ipv4Addr::new(0, 0, 0, 0)), 8080);
 
-const DEFAULT_BACKLOG: u32 = 100;
+pub(crate) const DEFAULT_BACKLOG: u32 = 100;
+
+/// Interval of the time slices of the in-flight guests, which yield after a number of slices
+/// depending on their priority, e.g. every 10ms with the normal priority, so that a busy guest
+/// doesn't starve the others.
+const PREEMPTION_INTERVAL: Duration = Duration::from_millis(5);
 
-type Request = hyper::Request<hyper::body::Incoming>;
+/// A request received by the proxy.
+pub type Request = hyper::Request<hyper::body::Incoming>;
 
 fn is_connection_error(e: &std::io::Error) -> bool {
     matches!(
@@ -39,10 +134,36 @@ fn is_connection_error(e: &std::io::Error) -> bool {
     )
 }
 
+/// Bind a listener to `addr`, with room for `backlog` pending connections.
+pub fn bind_listener(addr: SocketAddr, backlog: u32) -> Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => tokio::net::TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => tokio::net::TcpSocket::new_v6()?,
+    };
+
+    // Conditionally enable `SO_REUSEADDR` depending on the current
+    // platform. On Unix we want this to be able to rebind an address in
+    // the `TIME_WAIT` state which can happen then a server is killed with
+    // active TCP connections and then restarted. On Windows though if
+    // `SO_REUSEADDR` is specified then it enables multiple applications to
+    // bind the port at the same time which is not something we want. Hence
+    // this is conditionally set based on the platform (and deviates from
+    // Tokio's default from always-on).
+    socket.set_reuseaddr(!cfg!(windows))?;
+    socket.bind(addr)?;
+
+    Ok(socket.listen(backlog)?)
+}
+
 // [From axum](https://github.com/tokio-rs/axum/blob/280d16a61059f57230819a79b15aa12a263e8cca/axum/src/serve.rs#L425)
-async fn tcp_accept(listener: &TcpListener) -> Option<TcpStream> {
-    match listener.accept().await {
-        Ok((stream, _addr)) => Some(stream),
+pub(crate) async fn tcp_accept(listener: &TcpListener) -> Option<TcpStream> {
+    accepted(listener.accept().await.map(|(stream, _addr)| stream)).await
+}
+
+/// The accepted connection, or `None` after an error accepting it.
+async fn accepted<T>(result: std::io::Result<T>) -> Option<T> {
+    match result {
+        Ok(stream) => Some(stream),
         Err(e) => {
             if is_connection_error(&e) {
                 return None;
@@ -57,60 +178,369 @@ async fn tcp_accept(listener: &TcpListener) -> Option<TcpStream> {
             // > to accept the connection again. If this option is `true`, the error
             // > will be logged at the `error` level, since it is still a big deal,
             // > and then the listener will sleep for 1 second.
-            log::error!("accept error: {e}");
+            tracing::error!("accept error: {e}");
             tokio::time::sleep(Duration::from_secs(1)).await;
             None
         }
     }
 }
 
+/// A listener of the proxy, on TCP or on a Unix domain socket.
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Self::Tcp(listener)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixListener> for Listener {
+    fn from(listener: UnixListener) -> Self {
+        Self::Unix(listener)
+    }
+}
+
+impl Listener {
+    /// Bind a listener to `addr`, with room for `backlog` pending connections on TCP.
+    pub fn bind(addr: &ListenAddr, backlog: u32) -> Result<Self> {
+        let path = match addr {
+            ListenAddr::Tcp(addr, _) => return Ok(bind_listener(*addr, backlog)?.into()),
+            ListenAddr::Unix(path) => path,
+        };
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::FileTypeExt;
+
+            use anyhow::Context;
+
+            // The socket of a previous run of the container is in the way
+            if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+                std::fs::remove_file(path)?;
+            }
+            let listener = UnixListener::bind(path)
+                .with_context(|| format!("failed to bind unix socket {path:?}"))?;
+            Ok(listener.into())
+        }
+        #[cfg(not(unix))]
+        bail!("unix socket {path:?} is not supported on this platform")
+    }
+
+    /// The stream of the next connection, with the address of its client on TCP.
+    async fn accept(&self) -> Option<(Box<dyn Stream>, Option<ClientAddr>)> {
+        match self {
+            Self::Tcp(listener) => {
+                let stream = tcp_accept(listener).await?;
+                // Small writes, e.g. Server-Sent Events, are sent right away
+                if let Err(e) = stream.set_nodelay(true) {
+                    tracing::debug!("failed to set TCP_NODELAY: {e}");
+                }
+                let client = stream.peer_addr().ok().map(ClientAddr);
+                Some((Box::new(stream), client))
+            }
+            #[cfg(unix)]
+            Self::Unix(listener) => {
+                let (stream, _addr) = accepted(listener.accept().await).await?;
+                Some((Box::new(stream), None))
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Listener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "http://{addr}/"),
+                Err(_) => write!(f, "tcp socket"),
+            },
+            #[cfg(unix)]
+            Self::Unix(listener) => {
+                let addr = listener.local_addr().ok();
+                match addr.as_ref().and_then(|addr| addr.as_pathname()) {
+                    Some(path) => write!(f, "unix://{}", path.display()),
+                    None => write!(f, "unix socket"),
+                }
+            }
+        }
+    }
+}
+
+/// The listeners of the proxy, with the scheme of the requests of each, if set.
+pub struct Listeners(Vec<(Listener, Option<ListenScheme>)>);
+
+impl Listeners {
+    /// Bind the listeners of `config`.
+    pub fn bind(config: &ProxyConfig) -> Result<Self> {
+        let listeners = config
+            .listen
+            .iter()
+            .map(|addr| Ok((Listener::bind(addr, config.backlog)?, addr.scheme())))
+            .collect::<Result<_>>()?;
+        Ok(Self(listeners))
+    }
+
+    /// Also listen on `listener`, whose requests are passed with `scheme`, if set.
+    pub fn with(mut self, listener: impl Into<Listener>, scheme: Option<ListenScheme>) -> Self {
+        self.0.push((listener.into(), scheme));
+        self
+    }
+}
+
+impl From<Listener> for Listeners {
+    fn from(listener: Listener) -> Self {
+        Self(vec![(listener, None)])
+    }
+}
+
+impl From<TcpListener> for Listeners {
+    fn from(listener: TcpListener) -> Self {
+        Listener::from(listener).into()
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixListener> for Listeners {
+    fn from(listener: UnixListener) -> Self {
+        Listener::from(listener).into()
+    }
+}
+
+impl std::fmt::Display for Listeners {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, (listener, _)) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{listener}")?;
+        }
+        Ok(())
+    }
+}
+
 pub(crate) async fn serve_conn(
     ctx: &impl RuntimeContext,
     instance: ProxyPre<WasiPreview2Ctx>,
+    optimized: Option<oneshot::Receiver<Result<ProxyPre<WasiPreview2Ctx>>>>,
     cancel: CancellationToken,
 ) -> Result<()> {
-    let mut env = envs_from_ctx(ctx).into_iter().collect::<HashMap<_, _>>();
-
-    // Consume env variables for Proxy server settings before passing it to handler
-    let addr = env
-        .remove("WASMTIME_HTTP_PROXY_SOCKET_ADDR")
-        .and_then(|v| v.parse().ok())
-        .unwrap_or(DEFAULT_ADDR);
-    let backlog = env
-        .remove("WASMTIME_HTTP_BACKLOG")
-        .and_then(|v| v.parse().ok())
-        .unwrap_or(DEFAULT_BACKLOG);
+    let config = ProxyConfig::from_ctx(ctx)?;
 
-    let socket = match addr {
-        SocketAddr::V4(_) => tokio::net::TcpSocket::new_v4()?,
-        SocketAddr::V6(_) => tokio::net::TcpSocket::new_v6()?,
-    };
+    // Proxy server settings are not passed to the guest
+    let env = envs_from_ctx(ctx)
+        .into_iter()
+        .filter(|(key, _)| !config::is_setting(key))
+        .collect();
 
-    // Conditionally enable `SO_REUSEADDR` depending on the current
-    // platform. On Unix we want this to be able to rebind an address in
-    // the `TIME_WAIT` state which can happen then a server is killed with
-    // active TCP connections and then restarted. On Windows though if
-    // `SO_REUSEADDR` is specified then it enables multiple applications to
-    // bind the port at the same time which is not something we want. Hence
-    // this is conditionally set based on the platform (and deviates from
-    // Tokio's default from always-on).
-    socket.set_reuseaddr(!cfg!(windows))?;
-    socket.bind(addr)?;
+    let outgoing = Outgoing::from_ctx(ctx)?;
+    let listeners = Listeners::bind(&config)?;
+    let admin_addr = config.admin_socket_addr;
+    let (scaler_addr, scaler_target) = (config.scaler_socket_addr, config.scaler_target);
+    let jwt = config.jwt.clone();
+    let response_cache = config.response_cache.clone();
+    let streaming = config.streaming.clone();
+    let tls = config.tls.clone();
+    let audit = Audit::from_ctx(ctx)?;
+    if let Some(audit) = &audit {
+        let component = instance.instance_pre().component();
+        audit.imports(component_imports(instance.engine(), component));
+    }
+    let mut handler = ProxyHandler::new(instance, config, env, outgoing.clone())?
+        .with_trap_context(TrapContext::from_ctx(ctx))
+        .with_timezone(TimeZone::from_ctx(ctx))
+        .with_kv_cache(KvCache::from_ctx(ctx)?)
+        .with_audit(audit)
+        .with_priority(Priority::from_ctx(ctx)?);
+    #[cfg(unix)]
+    {
+        handler = handler.with_fs_events(FsEvents::watch(ctx)?);
+    }
+
+    if let Some(jwt) = jwt {
+        let jwt = Arc::new(JwtAuth::new(jwt, &outgoing).await?);
+        let (auth, cancel) = (jwt.clone(), cancel.clone());
+        handler
+            .tracker
+            .spawn(async move { auth.refresh(&outgoing, cancel).await });
+        handler = handler.with_middleware(jwt);
+    }
+
+    // Cached responses are only served to authorized requests
+    if let Some(response_cache) = response_cache {
+        handler = handler.with_middleware(ResponseCache::new(response_cache));
+    }
 
-    let listener = socket.listen(backlog)?;
-    let tracker = TaskTracker::new();
+    // Streamed responses are marked as such before reaching the cache
+    handler = handler.with_middleware(streaming);
 
-    log::info!("Serving HTTP on http://{}/", listener.local_addr()?);
+    let handler = Arc::new(handler);
 
-    let env = env.into_iter().collect();
-    let handler = Arc::new(ProxyHandler::new(instance, env, tracker.clone()));
+    tracing::info!("Serving HTTP on {listeners}");
+    quarantine::instantiated();
+    report_phase(Phase::Serving);
+
+    if let Some(optimized) = optimized {
+        let (h, cancel) = (handler.clone(), cancel.clone());
+        handler.tracker.spawn(async move {
+            tokio::select! {
+                instance = optimized => match instance {
+                    Ok(Ok(instance)) => {
+                        tracing::info!("optimized code is ready");
+                        h.upgrade(instance);
+                    }
+                    Ok(Err(e)) => tracing::warn!("failed to compile optimized code: {e:?}"),
+                    Err(_) => {}
+                },
+                _ = cancel.cancelled() => {}
+            }
+        });
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = reload::config_file(ctx) {
+        let (h, cancel) = (handler.clone(), cancel.clone());
+        handler.tracker.spawn(async move {
+            reload::watch(path, |config| h.reconfigure(config), cancel).await
+        });
+    }
+
+    #[cfg(unix)]
+    if let Some(tls) = tls {
+        let (h, cancel) = (handler.clone(), cancel.clone());
+        handler.tracker.spawn(async move {
+            let paths = [tls.cert.clone(), tls.key.clone()];
+            let reload = || match h.acceptor.reload(&tls) {
+                Ok(()) => tracing::debug!("read the TLS certificates {:?}", tls.cert),
+                Err(e) => tracing::warn!("keeping the current TLS certificates: {e:#}"),
+            };
+            reload::watch_files(&paths, reload, cancel).await
+        });
+    }
+
+    if let Some(concurrency) = handler.concurrency.clone() {
+        let cancel = cancel.clone();
+        handler
+            .tracker
+            .spawn(async move { concurrency.report(cancel).await });
+    }
+
+    if let Some(pool) = handler.pool.clone() {
+        let (h, cancel) = (handler.clone(), cancel.clone());
+        handler
+            .tracker
+            .spawn(async move { pool.fill(&h, cancel).await });
+    }
+
+    if let Some(addr) = admin_addr {
+        let admin = Arc::new(Admin {
+            handler: handler.clone(),
+            slots: handler.slots.clone(),
+            recorder: handler.recorder.clone(),
+            concurrency: handler.concurrency.clone(),
+        });
+        let cancel = cancel.clone();
+        handler.tracker.spawn(async move {
+            if let Err(e) = serve_admin(addr, admin, cancel).await {
+                tracing::error!("admin endpoint error: {e:?}");
+            }
+        });
+    }
+
+    if let Some(addr) = scaler_addr {
+        let scaler = Arc::new(Scaler::new(
+            handler.in_flight.clone(),
+            handler.concurrency.clone(),
+            scaler_target,
+        ));
+        let cancel = cancel.clone();
+        handler.tracker.spawn(async move {
+            if let Err(e) = serve_scaler(addr, scaler, cancel).await {
+                tracing::error!("scaler endpoint error: {e:?}");
+            }
+        });
+    }
+
+    serve(listeners, handler, cancel).await
+}
+
+/// Serve HTTP/1.1 connections accepted on `listeners` with `handler`, until `cancel` is
+/// cancelled.
+///
+/// Clients can also speak HTTP/2 over plain TCP (h2c) with prior knowledge, e.g. gRPC clients:
+/// the connections starting with the HTTP/2 preface are served with HTTP/2. The upgrade of
+/// HTTP/1.1 connections to h2c is not supported, as it is deprecated by RFC 9113. TLS is
+/// terminated, or sniffed next to plaintext HTTP, as configured by the [`ProxyConfig`], except on
+/// the listeners whose scheme is `http`.
+///
+/// Once cancelled, no new connection is accepted, the open connections are shut down gracefully,
+/// with a GOAWAY frame or a `Connection: close` header, and the function returns when the
+/// in-flight requests, and the background tasks of the handler, have completed. The requests
+/// still running after the drain timeout of the [`ProxyConfig`] are aborted.
+pub async fn serve(
+    listeners: impl Into<Listeners>,
+    handler: Arc<ProxyHandler>,
+    cancel: CancellationToken,
+) -> Result<()> {
+    serve_with(listeners, handler, cancel, ProxyHandler::handle_request).await
+}
+
+/// Like [`serve`], but requests are passed to `service` rather than to
+/// [`ProxyHandler::handle_request`].
+///
+/// This is the hook to wrap the whole handler, e.g.:
+///
+/// ```ignore
+/// serve_with(listeners, handler, cancel, |handler, req| async move {
+///     if req.headers().contains_key("x-blocked") {
+///         return forbidden();
+///     }
+///     handler.handle_request(req).await
+/// })
+/// .await?;
+/// ```
+pub async fn serve_with<S, F>(
+    listeners: impl Into<Listeners>,
+    handler: Arc<ProxyHandler>,
+    cancel: CancellationToken,
+    service: S,
+) -> Result<()>
+where
+    S: Fn(Arc<ProxyHandler>, Request) -> F + Clone + Send + 'static,
+    F: Future<Output = Result<hyper::Response<HyperOutgoingBody>>> + Send + 'static,
+{
+    let tracker = handler.tracker.clone();
+
+    // Each listener accepts its connections in a task of its own
+    let (conns, mut accepted) = tokio::sync::mpsc::channel(1);
+    for (listener, scheme) in listeners.into().0 {
+        let (conns, cancel) = (conns.clone(), cancel.clone());
+        tracker.spawn(async move {
+            loop {
+                let conn = tokio::select! {
+                    conn = listener.accept() => conn,
+                    _ = cancel.cancelled() => break,
+                };
+                let Some((stream, client)) = conn else {
+                    continue;
+                };
+                if conns.send((stream, client, scheme)).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    drop(conns);
 
     loop {
-        let stream = tokio::select! {
-            conn = tcp_accept(&listener) => {
+        let (stream, client, scheme) = tokio::select! {
+            conn = accepted.recv() => {
                 match conn {
                     Some(conn) => conn,
-                    None => continue,
+                    None => break,
                 }
             }
             _ = cancel.cancelled() => {
@@ -118,99 +548,685 @@ pub(crate) async fn serve_conn(
             }
         };
 
-        let stream = TokioIo::new(stream);
         let h = handler.clone();
+        let service = service.clone();
+        let executor = TrackedExecutor(tracker.clone());
+        let cancel = cancel.clone();
 
-        tracker.spawn(async {
-            if let Err(e) = http1::Builder::new()
-                .keep_alive(true)
-                .serve_connection(
-                    stream,
-                    hyper::service::service_fn(move |req| h.clone().handle_request(req)),
-                )
-                .await
-            {
-                log::error!("error: {e:?}");
+        tracker.spawn(async move {
+            let conn = tokio::select! {
+                conn = h.acceptor.accept(stream, scheme == Some(ListenScheme::Http)) => conn,
+                _ = cancel.cancelled() => None,
+            };
+            let Some(conn) = conn else {
+                return;
+            };
+            let abort = h.abort.clone();
+            let title_case_headers = h.title_case_headers;
+            let tls = conn.tls.clone();
+            let (stream, watch) = match h.read_rate {
+                Some(rate) if !conn.h2 => {
+                    let (stream, watch) = MinReadRate::new(conn.stream, rate);
+                    (Box::new(stream) as Box<dyn Stream>, Some(watch))
+                }
+                _ => (conn.stream, None),
+            };
+            let service = hyper::service::service_fn(move |mut req: Request| {
+                if let Some(client) = client {
+                    req.extensions_mut().insert(client);
+                }
+                if let Some(tls) = &tls {
+                    req.extensions_mut().insert(tls.clone());
+                }
+                if let Some(scheme) = scheme {
+                    req.extensions_mut().insert(scheme);
+                }
+                if let Some(watch) = &watch {
+                    if req.body().is_end_stream() {
+                        watch.pause();
+                    }
+                    req.extensions_mut().insert(watch.clone());
+                }
+                let entry = h.access_log.map(|log| log.start(&req));
+                let resp = service(h.clone(), req);
+                let watch = watch.clone();
+                async move {
+                    let resp = match resp.await {
+                        Ok(resp) => resp,
+                        Err(e) => {
+                            if let Some(entry) = entry {
+                                entry.failed();
+                            }
+                            return Err(e);
+                        }
+                    };
+                    let resp = match entry {
+                        Some(entry) => entry.response(resp).map(BodyExt::boxed),
+                        None => resp,
+                    };
+                    anyhow::Ok(match watch {
+                        Some(watch) => resp.map(|body| watch.response_body(body).boxed()),
+                        None => resp,
+                    })
+                }
+            });
+            let result = if conn.h2 {
+                let conn =
+                    http2::Builder::new(executor).serve_connection(TokioIo::new(stream), service);
+                drain(conn, |conn| conn.graceful_shutdown(), &cancel, &abort).await
+            } else {
+                let conn = http1::Builder::new()
+                    .keep_alive(true)
+                    .title_case_headers(title_case_headers)
+                    .serve_connection(TokioIo::new(stream), service);
+                drain(conn, |conn| conn.graceful_shutdown(), &cancel, &abort).await
+            };
+            if let Err(e) = result {
+                tracing::error!("error: {e:?}");
             }
         });
     }
 
+    // Unblock the accept tasks sending a connection no one will serve
+    drop(accepted);
     tracker.close();
-    tracker.wait().await;
+    let drain_timeout = *handler.drain_timeout.read().unwrap();
+    match drain_timeout {
+        Some(timeout) => {
+            if tokio::time::timeout(timeout, tracker.wait()).await.is_err() {
+                tracing::warn!(
+                    "aborting {} tasks still running after {timeout:?}",
+                    tracker.len()
+                );
+                handler.abort.cancel();
+                tracker.wait().await;
+            }
+        }
+        None => tracker.wait().await,
+    }
 
     Ok(())
 }
 
-struct ProxyHandler {
-    instance_pre: ProxyPre<WasiPreview2Ctx>,
+/// Serve `conn` until `cancel` is cancelled, then shut it down with `shutdown` and serve its
+/// in-flight requests until they complete, or until `abort` is cancelled.
+async fn drain<C>(
+    conn: C,
+    shutdown: impl FnOnce(Pin<&mut C>),
+    cancel: &CancellationToken,
+    abort: &CancellationToken,
+) -> hyper::Result<()>
+where
+    C: Future<Output = hyper::Result<()>>,
+{
+    tokio::pin!(conn);
+    tokio::select! {
+        result = conn.as_mut() => return result,
+        _ = cancel.cancelled() => shutdown(conn.as_mut()),
+    }
+    tokio::select! {
+        result = conn => result,
+        _ = abort.cancelled() => Ok(()),
+    }
+}
+
+/// Executor of the HTTP/2 streams, which are awaited when the server stops.
+#[derive(Clone)]
+struct TrackedExecutor(TaskTracker);
+
+impl<F> hyper::rt::Executor<F> for TrackedExecutor
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    fn execute(&self, fut: F) {
+        self.0.spawn(fut);
+    }
+}
+
+/// Serves the requests with a `wasi:http/proxy` component, instantiated for each request.
+///
+/// The features of the [`ProxyConfig`] that apply to requests (CORS, static files, mirroring,
+/// ...) are handled by the handler, while listening is left to [`serve`] or to the embedder.
+pub struct ProxyHandler {
+    instance_pre: RwLock<ProxyPre<WasiPreview2Ctx>>,
     next_id: AtomicU64,
     env: Vec<(String, String)>,
+    header_env: HeaderEnv,
+    connection_env: bool,
+    request_id_env: bool,
+    normalize: Normalize,
+    title_case_headers: bool,
+    read_rate: Option<ReadRate>,
+    forwarded: Forwarded,
+    middleware: Vec<Box<dyn Middleware>>,
+    static_files: Option<StaticFiles>,
+    grpc: GrpcServices,
+    mirror: Option<Mirror>,
+    outgoing: Arc<Outgoing>,
+    timezone: Arc<TimeZone>,
+    kv_cache: Arc<KvCache>,
+    audit: Option<Arc<Audit>>,
+    slots: Option<Arc<Slots>>,
+    recorder: Option<Arc<Recorder>>,
+    trap_ctx: Option<Arc<TrapContext>>,
+    #[cfg(unix)]
+    fs_events: Option<Arc<FsEvents>>,
+    profile: ResourceProfile,
+    priority: Priority,
+    chaos: Arc<Chaos>,
+    concurrency: Option<Arc<ConcurrencyLimit>>,
+    pool: Option<Arc<InstancePool>>,
+    in_flight: Arc<AtomicU64>,
+    acceptor: Acceptor,
+    drain_timeout: RwLock<Option<Duration>>,
+    request_timeout: RwLock<Option<Duration>>,
+    max_request_body_size: Option<u64>,
+    access_log: Option<AccessLog>,
+    routes: Routes,
+    probe_paths: bool,
+    guest_metrics: Option<GuestMetrics>,
+    /// Cancelled when the drain timeout expires, to abort the requests still running.
+    abort: CancellationToken,
     tracker: TaskTracker,
 }
 
 impl ProxyHandler {
-    fn new(
+    /// Create a handler serving the requests with `instance_pre`.
+    ///
+    /// `env` is the environment of the guest, and `outgoing` handles the outgoing requests of the
+    /// guest, e.g. [`Outgoing::direct`]. The components referenced by `config` are loaded with
+    /// the engine of `instance_pre`.
+    pub fn new(
         instance_pre: ProxyPre<WasiPreview2Ctx>,
+        config: ProxyConfig,
         env: Vec<(String, String)>,
-        tracker: TaskTracker,
-    ) -> Self {
-        ProxyHandler {
-            instance_pre,
-            env,
-            tracker,
+        outgoing: Arc<Outgoing>,
+    ) -> Result<Self> {
+        let engine = instance_pre.engine();
+        let grpc = GrpcServices::new(
+            config.grpc_health,
+            config.grpc_reflection.as_deref(),
+            config.max_request_body_size,
+        )?;
+        let static_files = config
+            .static_dir
+            .as_deref()
+            .map(|dir| StaticFiles::new(dir, config.dynamic_prefixes))
+            .transpose()?;
+        let mirror = config
+            .mirror
+            .as_ref()
+            .map(|route| Mirror::new(route, engine))
+            .transpose()?;
+        let slots = config
+            .green
+            .as_ref()
+            .map(|route| Slots::new(route, engine))
+            .transpose()?
+            .map(Arc::new);
+        let acceptor = Acceptor::new(config.tls.as_ref(), config.sniff_protocol)?;
+
+        Ok(Self {
+            instance_pre: RwLock::new(instance_pre),
             next_id: AtomicU64::from(0),
+            env,
+            header_env: config.header_env,
+            connection_env: config.connection_env,
+            request_id_env: config.request_id_env,
+            normalize: config.normalize,
+            title_case_headers: config.title_case_headers,
+            read_rate: config.read_rate,
+            forwarded: config.forwarded,
+            middleware: config
+                .cors
+                .map(|cors| Box::new(cors) as Box<dyn Middleware>)
+                .into_iter()
+                .collect(),
+            static_files,
+            grpc,
+            mirror,
+            outgoing,
+            timezone: Default::default(),
+            kv_cache: Default::default(),
+            audit: None,
+            slots,
+            recorder: config
+                .recorder
+                .map(|config| Arc::new(Recorder::new(config))),
+            trap_ctx: None,
+            #[cfg(unix)]
+            fs_events: None,
+            concurrency: config
+                .max_concurrency
+                .or(config.profile.max_concurrency)
+                .map(|limit| Arc::new(ConcurrencyLimit::new(limit, config.max_queue))),
+            pool: config
+                .instance_pool
+                .map(|pool| Arc::new(InstancePool::new(pool, config.profile.clone()))),
+            in_flight: Arc::default(),
+            acceptor,
+            drain_timeout: RwLock::new(config.drain_timeout),
+            request_timeout: RwLock::new(config.request_timeout),
+            max_request_body_size: config.max_request_body_size,
+            access_log: config.access_log,
+            routes: Routes::new(&config.route_limits, config.max_queue),
+            probe_paths: config.probe_paths,
+            guest_metrics: config.guest_metrics,
+            abort: CancellationToken::new(),
+            profile: config.profile,
+            priority: Priority::default(),
+            chaos: config.chaos,
+            tracker: TaskTracker::new(),
+        })
+    }
+
+    /// Register `middleware` to run around the guest, after the middleware already registered.
+    pub fn with_middleware(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// Write a diagnostic bundle when the guest traps.
+    pub(crate) fn with_trap_context(mut self, trap_ctx: Option<Arc<TrapContext>>) -> Self {
+        self.trap_ctx = trap_ctx;
+        self
+    }
+
+    /// Use `timezone` as the time zone of the guest, UTC by default.
+    pub(crate) fn with_timezone(mut self, timezone: Arc<TimeZone>) -> Self {
+        self.timezone = timezone;
+        self
+    }
+
+    /// Share `kv_cache` between the requests, instead of a default sized cache.
+    pub(crate) fn with_kv_cache(mut self, kv_cache: Arc<KvCache>) -> Self {
+        self.kv_cache = kv_cache;
+        self
+    }
+
+    /// Audit the outgoing requests of the guest.
+    pub(crate) fn with_audit(mut self, audit: Option<Arc<Audit>>) -> Self {
+        self.audit = audit;
+        self
+    }
+
+    /// Let the guests run for longer before yielding if `priority` is high, or shorter if low.
+    pub(crate) fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Hand the changes of the watched directories of the container to the guest.
+    #[cfg(unix)]
+    pub(crate) fn with_fs_events(mut self, fs_events: Option<Arc<FsEvents>>) -> Self {
+        self.fs_events = fs_events;
+        self
+    }
+
+    /// Apply the settings of `config` that can be changed while serving.
+    #[cfg(unix)]
+    pub(crate) fn reconfigure(&self, config: &LiveConfig) {
+        if let Some(level) = config.log_level {
+            log::set_max_level(level);
+        }
+        if let Some(limit) = config.max_concurrency {
+            match &self.concurrency {
+                Some(concurrency) => concurrency.resize(limit),
+                None => tracing::warn!("concurrency is not limited, ignoring max_concurrency"),
+            }
+        }
+        if let Some(weight) = config.green_weight {
+            match &self.slots {
+                Some(slots) => slots.set_green_weight(weight),
+                None => tracing::warn!("no green component, ignoring green_weight"),
+            }
+        }
+        // The in-flight requests keep the timeouts they started with
+        let timeout = |secs| (secs > 0).then(|| Duration::from_secs(secs));
+        if let Some(secs) = config.request_timeout {
+            *self.request_timeout.write().unwrap() = timeout(secs);
+        }
+        if let Some(secs) = config.drain_timeout {
+            *self.drain_timeout.write().unwrap() = timeout(secs);
         }
+        if let Some(size) = config.instance_pool_size {
+            match &self.pool {
+                Some(pool) => pool.resize(size),
+                None => tracing::warn!("no instance pool, ignoring instance_pool_size"),
+            }
+        }
+    }
+
+    /// Serve the next requests with `instance_pre`, e.g. once the optimized code of the component
+    /// is compiled. The in-flight requests complete with the previous component.
+    pub fn upgrade(&self, instance_pre: ProxyPre<WasiPreview2Ctx>) {
+        let mut current = self.instance_pre.write().unwrap();
+        *current = instance_pre;
+        if let Some(pool) = &self.pool {
+            pool.clear();
+        }
+    }
+
+    /// Check that a store of the current component can be instantiated.
+    async fn ready(&self) -> Result<()> {
+        self.instantiate().await?;
+        Ok(())
     }
 
-    fn wasi_store_for_request(&self, req_id: u64) -> Store<WasiPreview2Ctx> {
-        let engine = self.instance_pre.engine();
+    /// Instantiate the current component in a store not bound to a request.
+    async fn instantiate(&self) -> Result<(Store<WasiPreview2Ctx>, Proxy)> {
+        let instance_pre = self.instance_pre.read().unwrap().clone();
+        let mut store = self.wasi_store(instance_pre.engine(), None)?;
+        let proxy = instance_pre.instantiate_async(&mut store).await?;
+        Ok((store, proxy))
+    }
+
+    /// A store with the environment of `request`, if any.
+    fn wasi_store(
+        &self,
+        engine: &wasmtime::Engine,
+        request: Option<(u64, &GuestRequest)>,
+    ) -> Result<Store<WasiPreview2Ctx>> {
         let mut builder = wasmtime_wasi::WasiCtxBuilder::new();
 
         builder.envs(&self.env);
-        builder.env("REQUEST_ID", req_id.to_string());
+        if let Some(trap_ctx) = &self.trap_ctx {
+            builder.stderr(trap_ctx.stderr());
+        }
+        if let Some((req_id, req)) = request {
+            if self.request_id_env {
+                builder.env("REQUEST_ID", req_id.to_string());
+            }
+            if self.connection_env {
+                builder.envs(&connection_envs(req, &self.forwarded));
+            }
+            for (key, value) in self.header_env.envs(req.headers()) {
+                builder.env(key, value);
+            }
+        }
 
         let ctx = WasiPreview2Ctx {
             wasi_ctx: builder.build(),
             wasi_http: WasiHttpCtx::new(),
             resource_table: ResourceTable::default(),
+            #[cfg(unix)]
+            unix_sockets: Default::default(),
+            #[cfg(unix)]
+            fs_events: self.fs_events.clone(),
+            outgoing: self.outgoing.clone(),
+            timezone: self.timezone.clone(),
+            kv_cache: self.kv_cache.clone(),
+            write_quotas: None,
+            audit: self.audit.clone(),
+            udp_stats: Default::default(),
+            descriptor_paths: Default::default(),
+            descriptor_quotas: Default::default(),
+            limits: self.profile.store_limits(),
+            priority: self.priority,
+            extensions: Default::default(),
         };
 
-        Store::new(engine, ctx)
+        new_store(engine, ctx, &self.profile)
     }
 
-    async fn handle_request(
+    /// Handle a request, calling the guest unless the request is answered by the proxy itself,
+    /// e.g. a CORS preflight or a static file.
+    #[tracing::instrument(
+        name = "http_request",
+        skip_all,
+        level = "info",
+        fields(method = %req.method(), uri = %req.uri())
+    )]
+    pub async fn handle_request(
         self: Arc<Self>,
         req: Request,
     ) -> Result<hyper::Response<HyperOutgoingBody>> {
-        let (sender, receiver) = tokio::sync::oneshot::channel();
+        let (mut parts, body) = req.into_parts();
+        self.normalize.apply(&mut parts.uri, &mut parts.headers)?;
+        if self.probe_paths {
+            if let Some((status, text)) = probes::answer(&self, parts.uri.path()).await {
+                return Ok(probes::response(status, text));
+            }
+        }
+        let req = Request::from_parts(parts, body);
+
+        middleware::run(&self.middleware, req, |req| self.clone().dispatch(req)).await
+    }
+
+    async fn dispatch(self: Arc<Self>, req: Request) -> Result<hyper::Response<HyperOutgoingBody>> {
+        if self.grpc.handles(&req) {
+            return Ok(self.grpc.serve(req, &self.tracker));
+        }
+
+        // The metrics of the guest are only served on the admin endpoint
+        if let Some(guest_metrics) = &self.guest_metrics {
+            if guest_metrics.is_scrape_path(req.uri().path()) {
+                return Ok(not_found());
+            }
+        }
+
+        // Static files are served within the middleware, so that they get the same CORS headers as
+        // the responses of the guest
+        let req = match &self.static_files {
+            Some(static_files) => match static_files.serve(req).await {
+                Ok(resp) => return Ok(resp),
+                Err(req) => req,
+            },
+            None => req,
+        };
+
+        if guardrails::should_shed() {
+            return Ok(service_unavailable());
+        }
 
         let req_id = self.next_req_id();
 
-        log::trace!(
+        tracing::trace!(
             "Request {req_id} handling {} to {}",
             req.method(),
             req.uri()
         );
 
-        let mut store = self.wasi_store_for_request(req_id);
+        let req = req.map(BodyExt::boxed);
+        let route = self.routes.find(req.uri().path());
+        let max_request_body_size = route
+            .and_then(|route| route.limit.max_request_body_size)
+            .or(self.max_request_body_size);
+        let req = match max_request_body_size {
+            Some(max) => match body_limit::limit(req, max) {
+                Some(req) => req,
+                None => return Ok(payload_too_large()),
+            },
+            None => req,
+        };
+        let body_limit = req.extensions().get::<BodyLimit>().cloned();
+        let req = match req.extensions().get::<ReadWatch>().cloned() {
+            Some(watch) => req.map(|body| watch.request_body(body).boxed()),
+            None => req,
+        };
+        let (req, recording) = match &self.recorder {
+            Some(recorder) => {
+                let (req, recording) = recorder.start(req_id, req);
+                (req, Some(recording))
+            }
+            None => (req, None),
+        };
+        let sampled = self
+            .mirror
+            .as_ref()
+            .and_then(|mirror| Some((mirror, mirror.sample()?)));
+        let (req, shadow) = match sampled {
+            Some((mirror, permit)) => {
+                let (req, shadow) = tokio::select! {
+                    split = mirror::split(req, MAX_MIRROR_BODY_SIZE) => split?,
+                    _ = body_limit_exceeded(body_limit.as_ref()) => return Ok(payload_too_large()),
+                };
+                if shadow.is_none() {
+                    mirror.skip();
+                }
+                (req, shadow.map(|shadow| (shadow, permit)))
+            }
+            None => (req, None),
+        };
 
-        let req = store.data_mut().new_incoming_request(Scheme::Http, req)?;
-        let out = store.data_mut().new_response_outparam(sender)?;
-        let proxy = self.instance_pre.instantiate_async(&mut store).await?;
-
-        let task = self.tracker.spawn(async move {
-            if let Err(e) = proxy
-                .wasi_http_incoming_handler()
-                .call_handle(store, req, out)
-                .await
-            {
-                log::error!("[{req_id}] :: {:#?}", e);
+        // The pooled instances are instances of the blue component
+        let (instance_pre, pool) = match &self.slots {
+            Some(slots) if slots.route_green() => {
+                tracing::trace!("Request {req_id} routed to green slot");
+                (slots.green.clone(), None)
+            }
+            _ => (
+                self.instance_pre.read().unwrap().clone(),
+                self.pool.as_ref(),
+            ),
+        };
+
+        let mut resp = match self
+            .call_guest(&instance_pre, pool, req_id, req, false)
+            .await
+        {
+            Ok(resp) => resp,
+            Err(e) => {
+                if let Some(recording) = recording {
+                    recording.error(&e);
+                }
                 return Err(e);
             }
+        };
+        if let Some(recording) = recording {
+            recording.response(&mut resp);
+        }
+        resp.extensions_mut().insert(RequestId(req_id));
+
+        if let Some((shadow, permit)) = shadow {
+            let status = resp.status();
+            let h = self.clone();
+            self.tracker.spawn(async move {
+                let _permit = permit;
+                let mirror = h.mirror.as_ref().expect("mirroring enabled");
+                let result = h
+                    .call_guest(&mirror.instance_pre, None, req_id, shadow, true)
+                    .await;
+                let result = match result {
+                    // Drain the body so that the shadow component can complete the response.
+                    Ok(resp) => {
+                        let status = resp.status();
+                        resp.into_body()
+                            .collect()
+                            .await
+                            .map(|_| status)
+                            .map_err(anyhow::Error::from)
+                    }
+                    Err(e) => Err(e),
+                };
+                mirror.record(status, result);
+            });
+        }
+
+        Ok(resp)
+    }
 
-            Ok(())
+    /// Call the guest with `req`.
+    ///
+    /// Shadow requests, i.e. `mirrored`, are bounded by the budget of the mirror instead: they
+    /// don't take the concurrency permits, and aren't counted in the requests in flight.
+    #[tracing::instrument(skip_all, level = "debug", fields(req_id = req_id))]
+    async fn call_guest(
+        &self,
+        instance_pre: &ProxyPre<WasiPreview2Ctx>,
+        pool: Option<&Arc<InstancePool>>,
+        req_id: u64,
+        mut req: GuestRequest,
+        mirrored: bool,
+    ) -> Result<hyper::Response<HyperOutgoingBody>> {
+        // The slot of the route is taken first, so that its queue doesn't hold the other slots
+        let route = self.routes.find(req.uri().path());
+        let route_permit = match route.and_then(|route| route.concurrency.as_ref()) {
+            Some(concurrency) if !mirrored => match concurrency.acquire().await? {
+                Some(permit) => Some(permit),
+                None => return Ok(service_unavailable()),
+            },
+            _ => None,
+        };
+        // The permit is held until the guest completes, including streaming the response body
+        let permit = match &self.concurrency {
+            Some(concurrency) if !mirrored => match concurrency.acquire().await? {
+                Some(permit) => Some(permit),
+                None => return Ok(service_unavailable()),
+            },
+            _ => None,
+        };
+        let in_flight = (!mirrored).then(|| InFlight::enter(&self.in_flight));
+        let deadline = route
+            .and_then(|route| route.limit.request_timeout)
+            .or(*self.request_timeout.read().unwrap())
+            .map(|timeout| Instant::now() + timeout);
+        let body_limit = req.extensions().get::<BodyLimit>().cloned();
+
+        self.chaos.request()?;
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+
+        // Waiting for a stateful worker counts in the timeout of the request
+        let pooled = match pool {
+            Some(pool) => tokio::select! {
+                pooled = pool.take() => pooled,
+                _ = sleep_until(deadline) => return Ok(gateway_timeout()),
+            },
+            None => None,
+        };
+        let (mut store, proxy, lease) = match pooled {
+            Some((store, proxy, lease)) => (store, proxy, Some(lease)),
+            None => {
+                let mut store = self.wasi_store(instance_pre.engine(), Some((req_id, &req)))?;
+                self.chaos.instantiate().await;
+                let proxy = instance_pre.instantiate_async(&mut store).await?;
+                (store, proxy, None)
+            }
+        };
+        let on_trap = self.trap_ctx.clone().map(|trap_ctx| {
+            let request = RequestSnapshot::new(req_id, &req);
+            (trap_ctx, self.profile.clone(), request)
         });
 
+        let scheme = self.forwarded.apply(&mut req);
+        let req = store.data_mut().new_incoming_request(scheme, req)?;
+        let out = store.data_mut().new_response_outparam(sender)?;
+        let preemption = epoch::register(instance_pre.engine(), PREEMPTION_INTERVAL);
+        let abort = self.abort.clone();
+
+        // The guest runs in its own task, within the span of the request
+        let task = self.tracker.spawn(
+            async move {
+                let _permit = permit;
+                let _route_permit = route_permit;
+                let _in_flight = in_flight;
+                let _preemption = preemption;
+                let mut store = store;
+                let result = tokio::select! {
+                    result = proxy
+                        .wasi_http_incoming_handler()
+                        .call_handle(&mut store, req, out) => result,
+                    _ = abort.cancelled() => Err(anyhow!("aborted after the drain timeout")),
+                    _ = sleep_until(deadline) => Err(DeadlineExceeded.into()),
+                    _ = body_limit_exceeded(body_limit.as_ref()) => Err(BodyTooLarge.into()),
+                };
+                if let Err(e) = result {
+                    tracing::error!("[{req_id}] :: {:#?}", e);
+                    if let Some((trap_ctx, profile, request)) = on_trap {
+                        trap_ctx.capture(&e, Some(request), profile.fuel_consumed(&store));
+                    }
+                    return Err(e);
+                }
+                if let Some(lease) = lease {
+                    lease.release(store, proxy);
+                }
+
+                Ok(())
+            }
+            .in_current_span(),
+        );
+
         match receiver.await {
             Ok(Ok(resp)) => Ok(resp),
             Ok(Err(e)) => Err(e.into()),
@@ -228,6 +1244,12 @@ impl ProxyHandler {
                     }
                     Err(e) => e.into(),
                 };
+                if e.is::<DeadlineExceeded>() {
+                    return Ok(gateway_timeout());
+                }
+                if e.is::<BodyTooLarge>() {
+                    return Ok(payload_too_large());
+                }
 
                 bail!("guest never invoked `response-outparam::set` method: {e:?}")
             }
@@ -238,3 +1260,61 @@ impl ProxyHandler {
         self.next_id.fetch_add(1, Ordering::Relaxed)
     }
 }
+
+/// The guest exceeded the request timeout, and was cancelled.
+#[derive(Debug)]
+struct DeadlineExceeded;
+
+impl std::fmt::Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("the request exceeded its timeout")
+    }
+}
+
+impl std::error::Error for DeadlineExceeded {}
+
+/// Sleep until `deadline`, or forever without a deadline.
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Wait until the request body exceeds its maximum size, or forever without a limit.
+async fn body_limit_exceeded(limit: Option<&BodyLimit>) {
+    match limit {
+        Some(limit) => limit.exceeded().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The response to the requests to the paths not served by the proxy.
+fn not_found() -> hyper::Response<HyperOutgoingBody> {
+    let mut resp = hyper::Response::new(Empty::new().map_err(|e| match e {}).boxed());
+    *resp.status_mut() = StatusCode::NOT_FOUND;
+    resp
+}
+
+/// The response to the requests whose body exceeds the maximum size.
+fn payload_too_large() -> hyper::Response<HyperOutgoingBody> {
+    let mut resp = hyper::Response::new(Empty::new().map_err(|e| match e {}).boxed());
+    *resp.status_mut() = StatusCode::PAYLOAD_TOO_LARGE;
+    resp
+}
+
+/// The response to the requests whose guest exceeded the request timeout.
+fn gateway_timeout() -> hyper::Response<HyperOutgoingBody> {
+    let mut resp = hyper::Response::new(Empty::new().map_err(|e| match e {}).boxed());
+    *resp.status_mut() = StatusCode::GATEWAY_TIMEOUT;
+    resp
+}
+
+/// The response to the requests rejected to shed load.
+fn service_unavailable() -> hyper::Response<HyperOutgoingBody> {
+    let mut resp = hyper::Response::new(Empty::new().map_err(|e| match e {}).boxed());
+    *resp.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    resp.headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from_static("1"));
+    resp
+}
@@ -0,0 +1,315 @@
+//! Protocols of the connections accepted by the proxy.
+//!
+//! The proxy serves HTTP/1.1, and HTTP/2 over plain TCP (h2c) to the clients starting their
+//! connections with the HTTP/2 preface. With `WASMTIME_HTTP_TLS_CERT` and `WASMTIME_HTTP_TLS_KEY`
+//! set to PEM files, it terminates TLS instead, HTTP/2 or HTTP/1.1 being negotiated with ALPN.
+//! The files are read again when they change, e.g. when cert-manager rotates the certificate of
+//! a mounted Secret, or when the task receives `SIGHUP`: the new connections are served with the
+//! new certificate, while the open ones are kept.
+//!
+//! With `WASMTIME_HTTP_SNIFF_PROTOCOL` set to `true`, the first bytes of every connection are
+//! inspected to serve TLS and plaintext HTTP on the same port, e.g. for single-port edge deployments.
+//! The connections starting with a TLS handshake are served with TLS, the ones starting with an
+//! HTTP/1.1 request line or the HTTP/2 preface in plaintext, and the others are closed.
+
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context as TaskContext, Poll};
+
+use anyhow::{ensure, Context, Result};
+use bytes::{Buf, Bytes};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+use super::config::Settings;
+use super::forwarded::Tls;
+
+/// The preface starting the HTTP/2 connections.
+const H2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// The type of the TLS records carrying a handshake, starting the TLS connections.
+const TLS_HANDSHAKE: u8 = 0x16;
+
+/// The longest method of an HTTP/1.1 request line recognized when sniffing, e.g. `OPTIONS`.
+const MAX_METHOD_LEN: usize = 16;
+
+/// The certificate chain and private key the proxy terminates TLS with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsConfig {
+    /// Path of the PEM certificate chain.
+    pub cert: PathBuf,
+    /// Path of the PEM private key.
+    pub key: PathBuf,
+}
+
+impl TlsConfig {
+    pub(crate) fn from_settings(settings: &Settings) -> Result<Option<Self>> {
+        match (settings.get("tls-cert"), settings.get("tls-key")) {
+            (Some(cert), Some(key)) => Ok(Some(Self {
+                cert: cert.into(),
+                key: key.into(),
+            })),
+            (None, None) => Ok(None),
+            _ => anyhow::bail!(
+                "{} and {} must be set together",
+                settings.source("tls-cert"),
+                settings.source("tls-key")
+            ),
+        }
+    }
+
+    fn server_config(&self) -> Result<ServerConfig> {
+        let certs = CertificateDer::pem_file_iter(&self.cert)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .with_context(|| format!("failed to read certificates {:?}", self.cert))?;
+        ensure!(!certs.is_empty(), "no certificate in {:?}", self.cert);
+        let key = PrivateKeyDer::from_pem_file(&self.key)
+            .with_context(|| format!("failed to read private key {:?}", self.key))?;
+
+        let mut config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .context("invalid certificate or private key")?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(config)
+    }
+}
+
+/// The protocol a connection starts with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Protocol {
+    Tls,
+    Http1,
+    H2,
+    Unknown,
+}
+
+/// The protocol starting with `buf`, or `None` if more bytes are needed to tell.
+fn detect(buf: &[u8]) -> Option<Protocol> {
+    match buf {
+        [] => Some(Protocol::Unknown),
+        // A handshake record of TLS 1.0 and later, whose version is 3.x
+        [TLS_HANDSHAKE] => None,
+        [TLS_HANDSHAKE, 3, ..] => Some(Protocol::Tls),
+        _ if buf.len() >= H2_PREFACE.len() && buf.starts_with(H2_PREFACE) => Some(Protocol::H2),
+        _ if H2_PREFACE.starts_with(buf) => None,
+        _ => {
+            // A request line starts with a method token and a space
+            let method = buf.iter().take_while(|b| b.is_ascii_uppercase()).count();
+            match buf.get(method) {
+                Some(b' ') if method > 0 => Some(Protocol::Http1),
+                None if method < MAX_METHOD_LEN => None,
+                _ => Some(Protocol::Unknown),
+            }
+        }
+    }
+}
+
+/// The protocol of the connection of `stream`, read from its first bytes, which are read again
+/// from the returned stream.
+async fn sniff(mut stream: Box<dyn Stream>) -> (Protocol, Box<dyn Stream>) {
+    let mut buf = [0; H2_PREFACE.len()];
+    let mut len = 0;
+    let protocol = loop {
+        match stream.read(&mut buf[len..]).await {
+            Ok(0) | Err(_) => break Protocol::Unknown,
+            Ok(n) => len += n,
+        }
+        match detect(&buf[..len]) {
+            Some(protocol) => break protocol,
+            None if len == buf.len() => break Protocol::Unknown,
+            None => {}
+        }
+    };
+    let prefix = Bytes::copy_from_slice(&buf[..len]);
+    (protocol, Box::new(Rewind { prefix, stream }))
+}
+
+/// A stream whose first bytes, read to sniff its protocol, are read again.
+struct Rewind {
+    prefix: Bytes,
+    stream: Box<dyn Stream>,
+}
+
+impl AsyncRead for Rewind {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.prefix.has_remaining() {
+            let n = self.prefix.len().min(buf.remaining());
+            buf.put_slice(&self.prefix[..n]);
+            self.prefix.advance(n);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Rewind {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// The stream of a connection, over TCP or a Unix domain socket, and TLS or not.
+pub(crate) trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// An accepted connection.
+pub(crate) struct Connection {
+    pub stream: Box<dyn Stream>,
+    /// Whether the connection is served with HTTP/2.
+    pub h2: bool,
+    /// The TLS session of the connection, if it is over TLS.
+    pub tls: Option<Tls>,
+}
+
+impl Connection {
+    fn plain(stream: Box<dyn Stream>, h2: bool) -> Self {
+        Self {
+            stream,
+            h2,
+            tls: None,
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Acceptor {
+    tls: Option<RwLock<TlsAcceptor>>,
+    sniff: bool,
+}
+
+impl Acceptor {
+    /// Terminate TLS with `tls`, if any, and peek at the first bytes of the connections if
+    /// `sniff` is set.
+    pub fn new(tls: Option<&TlsConfig>, sniff: bool) -> Result<Self> {
+        let tls = tls
+            .map(|tls| tls.server_config())
+            .transpose()?
+            .map(|config| RwLock::new(TlsAcceptor::from(Arc::new(config))));
+        Ok(Self { tls, sniff })
+    }
+
+    /// Read the certificate chain and private key of `tls` again, for the next connections.
+    #[cfg(unix)]
+    pub fn reload(&self, tls: &TlsConfig) -> Result<()> {
+        let Some(acceptor) = &self.tls else {
+            return Ok(());
+        };
+        let config = tls.server_config()?;
+        *acceptor.write().unwrap() = TlsAcceptor::from(Arc::new(config));
+        Ok(())
+    }
+
+    /// The acceptor of the next TLS connections.
+    fn tls_acceptor(&self) -> Option<TlsAcceptor> {
+        self.tls.as_ref().map(|tls| tls.read().unwrap().clone())
+    }
+
+    /// The connection of `stream`, or `None` if it is rejected. TLS isn't terminated on the
+    /// `plaintext` connections.
+    pub async fn accept(&self, stream: Box<dyn Stream>, plaintext: bool) -> Option<Connection> {
+        let tls = self.tls_acceptor().filter(|_| !plaintext);
+        let (protocol, stream) = match (&tls, self.sniff) {
+            (Some(_), false) => (Protocol::Tls, stream),
+            _ => sniff(stream).await,
+        };
+        match (protocol, tls) {
+            (Protocol::Tls, Some(tls)) => match tls.accept(stream).await {
+                Ok(stream) => {
+                    let session = stream.get_ref().1;
+                    let h2 = session.alpn_protocol() == Some(b"h2");
+                    let tls = Tls {
+                        server_name: session.server_name().map(Arc::from),
+                        alpn_protocol: session
+                            .alpn_protocol()
+                            .map(|protocol| String::from_utf8_lossy(protocol).into()),
+                    };
+                    Some(Connection {
+                        stream: Box::new(stream),
+                        h2,
+                        tls: Some(tls),
+                    })
+                }
+                Err(e) => {
+                    tracing::debug!("TLS handshake failed: {e}");
+                    None
+                }
+            },
+            (Protocol::H2, _) => Some(Connection::plain(stream, true)),
+            (Protocol::Http1, _) => Some(Connection::plain(stream, false)),
+            // Without sniffing, hyper answers whatever the client sent
+            _ if !self.sniff => Some(Connection::plain(stream, false)),
+            (protocol, _) => {
+                tracing::debug!("rejecting a connection starting with {protocol:?}");
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(detect(b"GET / HTTP/1.1\r\n"), Some(Protocol::Http1));
+        assert_eq!(detect(b"OPTIONS * HTTP/1.1"), Some(Protocol::Http1));
+        assert_eq!(detect(b"DEL"), None);
+        assert_eq!(detect(H2_PREFACE), Some(Protocol::H2));
+        assert_eq!(detect(&H2_PREFACE[..10]), None);
+        assert_eq!(detect(b"PRI * HTTP/1.1\r\n\r\n"), Some(Protocol::Http1));
+        assert_eq!(detect(&[TLS_HANDSHAKE, 3, 1, 0, 200]), Some(Protocol::Tls));
+        assert_eq!(detect(&[TLS_HANDSHAKE]), None);
+        assert_eq!(detect(b"SSH-2.0-OpenSSH_9.6\r\n"), Some(Protocol::Unknown));
+        assert_eq!(detect(b"get / HTTP/1.1"), Some(Protocol::Unknown));
+        assert_eq!(detect(b""), Some(Protocol::Unknown));
+    }
+
+    #[test]
+    fn test_tls_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let tls = TlsConfig {
+            cert: dir.path().join("cert.pem"),
+            key: dir.path().join("key.pem"),
+        };
+        std::fs::write(&tls.cert, "not a certificate").unwrap();
+        std::fs::write(&tls.key, "").unwrap();
+        let err = Acceptor::new(Some(&tls), true).err().unwrap();
+        assert!(err.to_string().contains("no certificate"), "{err:#}");
+    }
+}
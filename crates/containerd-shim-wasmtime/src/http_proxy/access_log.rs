@@ -0,0 +1,198 @@
+//! Access log of the proxied requests.
+//!
+//! With `WASMTIME_HTTP_ACCESS_LOG` set to `stdout` or `stderr`, the proxy writes a JSON line per
+//! request to that stream of the container, where the log pipelines already collecting the output
+//! of the containers capture it, e.g.
+//! `{"req_id":42,"method":"GET","path":"/","status":200,"latency_ms":12.5,"bytes":512}`.
+//!
+//! The line is written once the response body is sent, or dropped, so that the latency covers
+//! streaming the response. The requests answered by the proxy itself, e.g. static files, have no
+//! `req_id`, and the ones failing without a response no `status`.
+
+use std::io::Write;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use anyhow::bail;
+use bytes::Buf;
+use hyper::body::{Body, Frame, SizeHint};
+use hyper::{Method, StatusCode};
+use serde_json::json;
+
+/// The ID of the guest call of a request, in the extensions of its response.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct RequestId(pub u64);
+
+/// The container stream the access log is written to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessLog {
+    Stdout,
+    Stderr,
+}
+
+impl FromStr for AccessLog {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "stdout" => Ok(Self::Stdout),
+            "stderr" => Ok(Self::Stderr),
+            _ => bail!("expected stdout or stderr"),
+        }
+    }
+}
+
+impl AccessLog {
+    /// Start the entry of `req`, received now.
+    pub(crate) fn start<B>(self, req: &hyper::Request<B>) -> Entry {
+        Entry {
+            log: self,
+            method: req.method().clone(),
+            path: req.uri().path().to_string(),
+            start: Instant::now(),
+            req_id: None,
+            status: None,
+            bytes: 0,
+        }
+    }
+
+    fn write(self, line: &str) {
+        // A single write, so that the lines of concurrent requests don't interleave
+        let line = format!("{line}\n");
+        let result = match self {
+            Self::Stdout => std::io::stdout().lock().write_all(line.as_bytes()),
+            Self::Stderr => std::io::stderr().lock().write_all(line.as_bytes()),
+        };
+        if let Err(e) = result {
+            tracing::debug!("failed to write the access log: {e}");
+        }
+    }
+}
+
+/// The access log entry of a request in flight.
+pub(crate) struct Entry {
+    log: AccessLog,
+    method: Method,
+    path: String,
+    start: Instant,
+    req_id: Option<u64>,
+    status: Option<StatusCode>,
+    bytes: u64,
+}
+
+impl Entry {
+    /// Write the entry of a request failing without a response.
+    pub fn failed(self) {
+        self.log.write(&self.line());
+    }
+
+    /// `resp` with its body writing the entry once it is sent, or dropped.
+    pub fn response<B>(mut self, resp: hyper::Response<B>) -> hyper::Response<Logged<B>> {
+        self.req_id = resp
+            .extensions()
+            .get::<RequestId>()
+            .map(|RequestId(id)| *id);
+        self.status = Some(resp.status());
+        resp.map(|body| Logged {
+            body,
+            entry: Some(self),
+        })
+    }
+
+    fn line(&self) -> String {
+        json!({
+            "req_id": self.req_id,
+            "method": self.method.as_str(),
+            "path": self.path,
+            "status": self.status.map(|status| status.as_u16()),
+            "latency_ms": self.start.elapsed().as_secs_f64() * 1000.0,
+            "bytes": self.bytes,
+        })
+        .to_string()
+    }
+}
+
+/// A response body writing the access log entry of its request once it ends, or is dropped.
+pub(crate) struct Logged<B> {
+    body: B,
+    entry: Option<Entry>,
+}
+
+impl<B> Logged<B> {
+    fn end(&mut self) {
+        if let Some(entry) = self.entry.take() {
+            entry.log.write(&entry.line());
+        }
+    }
+}
+
+impl<B> Drop for Logged<B> {
+    fn drop(&mut self) {
+        self.end();
+    }
+}
+
+impl<B: Body + Unpin> Body for Logged<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let frame = std::task::ready!(Pin::new(&mut self.body).poll_frame(cx));
+        if let (Some(Ok(frame)), Some(entry)) = (&frame, &mut self.entry) {
+            entry.bytes += frame.data_ref().map_or(0, |data| data.remaining() as u64);
+        }
+        if !matches!(frame, Some(Ok(_))) || self.body.is_end_stream() {
+            self.end();
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use bytes::Bytes;
+    use http_body_util::{BodyExt, StreamBody};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_entry() -> anyhow::Result<()> {
+        assert_eq!("stderr".parse::<AccessLog>()?, AccessLog::Stderr);
+        assert!("syslog".parse::<AccessLog>().is_err());
+
+        let req = hyper::Request::post("/api/users?token=secret").body(())?;
+        let entry = AccessLog::Stdout.start(&req);
+        let chunks =
+            ["created", " user"].map(|chunk| Ok::<_, Infallible>(Frame::data(Bytes::from(chunk))));
+        let mut resp = hyper::Response::new(StreamBody::new(tokio_stream::iter(chunks)));
+        *resp.status_mut() = StatusCode::CREATED;
+        resp.extensions_mut().insert(RequestId(7));
+
+        let mut body = entry.response(resp).into_body();
+        body.frame().await;
+        // Taken before the body ends, so that it isn't written
+        let entry = body.entry.take().unwrap();
+        let line: serde_json::Value = serde_json::from_str(&entry.line())?;
+        assert_eq!(line["req_id"], 7);
+        assert_eq!(line["method"], "POST");
+        assert_eq!(line["path"], "/api/users");
+        assert_eq!(line["status"], 201);
+        assert_eq!(line["bytes"], 7);
+        Ok(())
+    }
+}
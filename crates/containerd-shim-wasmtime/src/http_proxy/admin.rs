@@ -0,0 +1,125 @@
+//! Admin endpoint of the proxy.
+//!
+//! The endpoint is served on `WASMTIME_HTTP_ADMIN_SOCKET_ADDR`:
+//! * `/healthz` and `/readyz` answer the probes of the proxy, see [`probes`].
+//! * `/slots` adjusts the weight of the green component, see [`Slots`].
+//! * `/recordings` downloads the exchanges of the guest, see [`Recorder`].
+//! * `/metrics` serves the metrics of the container, see [`metrics`](crate::metrics), the metrics
+//!   of the queue of the requests, when their concurrency is limited, see [`ConcurrencyLimit`],
+//!   and the metrics of the guest, see [`guest_metrics`].
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::Result;
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::server::conn::http1;
+use hyper::StatusCode;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use wasmtime_wasi_http::io::TokioIo;
+
+use super::concurrency::ConcurrencyLimit;
+use super::recorder::Recorder;
+use super::slots::Slots;
+use super::{
+    bind_listener, guest_metrics, probes, tcp_accept, ProxyHandler, Request, DEFAULT_BACKLOG,
+};
+
+pub(crate) struct Admin {
+    pub handler: Arc<ProxyHandler>,
+    pub slots: Option<Arc<Slots>>,
+    pub recorder: Option<Arc<Recorder>>,
+    pub concurrency: Option<Arc<ConcurrencyLimit>>,
+}
+
+impl Admin {
+    async fn handle_request(&self, req: Request) -> hyper::Response<Full<Bytes>> {
+        let path = req.uri().path();
+        let probe = probes::answer(&self.handler, path).await;
+        let (status, body) = match (probe, &self.slots, &self.recorder) {
+            (Some(probe), _, _) => probe,
+            (_, Some(slots), _) if path.starts_with("/slots") => {
+                slots.handle_admin_request(req).await
+            }
+            (_, _, Some(recorder)) if path == "/recordings" => {
+                recorder.handle_admin_request(req.method())
+            }
+            _ if path == "/metrics" => (StatusCode::OK, self.metrics().await),
+            _ => (StatusCode::NOT_FOUND, String::new()),
+        };
+
+        let mut resp = hyper::Response::new(Full::new(Bytes::from(body)));
+        *resp.status_mut() = status;
+        resp
+    }
+
+    /// The metrics of the container and of the queue of the requests, followed by the metrics of
+    /// the guest.
+    async fn metrics(&self) -> String {
+        let mut metrics = crate::metrics::render();
+        if let Some(concurrency) = &self.concurrency {
+            metrics.push_str(&concurrency.metrics());
+        }
+        if let Some(source) = &self.handler.guest_metrics {
+            let guest = match source.scrape(&self.handler).await {
+                Ok(guest) => Some(guest),
+                Err(e) => {
+                    tracing::warn!("failed to read the metrics of the guest: {e:#}");
+                    None
+                }
+            };
+            guest_metrics::merge(&mut metrics, guest.as_deref());
+        }
+        metrics
+    }
+}
+
+/// Serve the admin endpoint until `cancel` is triggered.
+pub(crate) async fn serve_admin(
+    addr: SocketAddr,
+    admin: Arc<Admin>,
+    cancel: CancellationToken,
+) -> Result<()> {
+    let listener = bind_listener(addr, DEFAULT_BACKLOG)?;
+    let tracker = TaskTracker::new();
+
+    tracing::info!(
+        "Serving admin endpoint on http://{}/",
+        listener.local_addr()?
+    );
+
+    loop {
+        let stream = tokio::select! {
+            conn = tcp_accept(&listener) => {
+                match conn {
+                    Some(conn) => conn,
+                    None => continue,
+                }
+            }
+            _ = cancel.cancelled() => {
+                break;
+            }
+        };
+
+        let admin = admin.clone();
+        tracker.spawn(async move {
+            let service = hyper::service::service_fn(move |req| {
+                let admin = admin.clone();
+                async move { anyhow::Ok(admin.handle_request(req).await) }
+            });
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                tracing::error!("admin error: {e:?}");
+            }
+        });
+    }
+
+    tracker.close();
+    tracker.wait().await;
+
+    Ok(())
+}
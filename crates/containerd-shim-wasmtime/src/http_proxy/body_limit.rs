@@ -0,0 +1,150 @@
+//! Maximum size of the request bodies.
+//!
+//! With `WASMTIME_HTTP_MAX_REQUEST_BODY_SIZE` set to a number of bytes, the requests with a larger
+//! `Content-Length` are answered with `413 Content Too Large` without reaching the guest. The
+//! bodies of unknown length, e.g. chunked, are counted as the guest reads them: once past the
+//! limit, the guest is cancelled and the request answered with 413, unless the guest already
+//! responded, in which case its response is cut.
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use http_body_util::BodyExt;
+use hyper::body::{Body, Frame, SizeHint};
+use tokio_util::sync::CancellationToken;
+
+use super::mirror::GuestRequest;
+
+/// Triggered when the body of a request exceeds the maximum size, in the extensions of the
+/// request.
+#[derive(Clone, Default)]
+pub(crate) struct BodyLimit(CancellationToken);
+
+impl BodyLimit {
+    /// Wait until the body exceeds the maximum size.
+    pub async fn exceeded(&self) {
+        self.0.cancelled().await
+    }
+}
+
+/// The guest was cancelled as the body of its request exceeded the maximum size.
+#[derive(Debug)]
+pub(crate) struct BodyTooLarge;
+
+impl std::fmt::Display for BodyTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("the request body exceeded the maximum size")
+    }
+}
+
+impl std::error::Error for BodyTooLarge {}
+
+/// `req` with its body limited to `max` bytes, or `None` if its length is known to be larger.
+pub(crate) fn limit(mut req: GuestRequest, max: u64) -> Option<GuestRequest> {
+    let size_hint = req.body().size_hint();
+    if size_hint.lower() > max {
+        return None;
+    }
+    // The bodies of a known length are checked by hyper
+    if size_hint.upper().is_some_and(|upper| upper <= max) {
+        return Some(req);
+    }
+
+    let limit = BodyLimit::default();
+    req.extensions_mut().insert(limit.clone());
+    Some(req.map(|body| {
+        Limited {
+            body,
+            remaining: max,
+            limit,
+        }
+        .boxed()
+    }))
+}
+
+/// A body stalling once more than `remaining` bytes are read, after triggering its limit.
+struct Limited<B> {
+    body: B,
+    remaining: u64,
+    limit: BodyLimit,
+}
+
+impl<B: Body<Data = Bytes> + Unpin> Body for Limited<B> {
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, B::Error>>> {
+        // The guest reading the body is being cancelled
+        if self.limit.0.is_cancelled() {
+            return Poll::Pending;
+        }
+        let frame = std::task::ready!(Pin::new(&mut self.body).poll_frame(cx));
+        if let Some(Ok(frame)) = &frame {
+            let len = frame.data_ref().map_or(0, |data| data.len() as u64);
+            if len > self.remaining {
+                self.limit.0.cancel();
+                return Poll::Pending;
+            }
+            self.remaining -= len;
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use http_body_util::{Full, StreamBody};
+
+    use super::*;
+
+    fn request<B>(body: B) -> GuestRequest
+    where
+        B: Body<Data = Bytes, Error = Infallible> + Send + Sync + 'static,
+    {
+        hyper::Request::new(body.map_err(|e| match e {}).boxed())
+    }
+
+    fn chunked(chunks: &[&'static str]) -> GuestRequest {
+        let frames: Vec<Result<_, Infallible>> = chunks
+            .iter()
+            .map(|chunk| Ok(Frame::data(Bytes::from_static(chunk.as_bytes()))))
+            .collect();
+        request(StreamBody::new(tokio_stream::iter(frames)))
+    }
+
+    #[tokio::test]
+    async fn test_limit() -> Result<()> {
+        // Known lengths are checked upfront
+        assert!(limit(request(Full::new(Bytes::from("too large"))), 4).is_none());
+        let req = limit(request(Full::new(Bytes::from("body"))), 4).unwrap();
+        assert!(req.extensions().get::<BodyLimit>().is_none());
+
+        let req = limit(chunked(&["bo", "dy"]), 4).unwrap();
+        assert_eq!(req.into_body().collect().await?.to_bytes(), "body");
+
+        let req = limit(chunked(&["too", " large"]), 4).unwrap();
+        let limit = req.extensions().get::<BodyLimit>().unwrap().clone();
+        let read = tokio::time::timeout(Duration::from_secs(1), req.into_body().collect());
+        tokio::select! {
+            _ = limit.exceeded() => {}
+            _ = read => panic!("the body exceeding the limit was read"),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,582 @@
+//! In-memory response cache in front of the guest.
+//!
+//! The cache is enabled by setting `WASMTIME_HTTP_RESPONSE_CACHE_SIZE` to its size in bytes.
+//! Responses to `GET` and `HEAD` requests are cached when the guest allows it with a
+//! `Cache-Control: max-age` or `s-maxage` directive, and are served from the cache until they
+//! expire, without invoking the guest. The cache is keyed by method, host and path, and by the
+//! values of the request headers listed in the `Vary` header of the response.
+//! * `WASMTIME_HTTP_RESPONSE_CACHE_MAX_ENTRY_SIZE`: maximum size in bytes of a cached response
+//!   body (default: 1 MiB).
+//!
+//! Responses with `no-store`, `no-cache` or `private` directives, with a `Set-Cookie` header, or
+//! with `Vary: *` are never cached. Responses to requests with an `Authorization` header are
+//! only cached with a `public` or `s-maxage` directive. When the cache is full, the least recently
+//! used responses are evicted.
+
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use bytes::{Bytes, BytesMut};
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::body::{Body, Frame, SizeHint};
+use hyper::header::{self, HeaderMap, HeaderName, HeaderValue};
+use hyper::http::request::Parts;
+use hyper::{Method, StatusCode, Uri};
+use wasmtime_wasi_http::bindings::http::types::ErrorCode;
+use wasmtime_wasi_http::body::HyperOutgoingBody;
+
+use super::config::Settings;
+use super::middleware::Middleware;
+use super::Request;
+use crate::metrics::{self, write_metric, MetricsSource};
+
+const DEFAULT_MAX_ENTRY_SIZE: usize = 1024 * 1024;
+
+/// Configuration of the response cache.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResponseCacheConfig {
+    /// Total size of the cached responses, in bytes.
+    pub size: usize,
+    /// Maximum size of a cached response body, in bytes.
+    pub max_entry_size: usize,
+}
+
+impl ResponseCacheConfig {
+    /// Read the response cache settings.
+    /// Returns `None` if the cache is not enabled.
+    pub(crate) fn from_settings(settings: &Settings) -> Result<Option<Self>> {
+        let max_entry_size = settings
+            .parse("response-cache-max-entry-size")?
+            .unwrap_or(DEFAULT_MAX_ENTRY_SIZE);
+        let size = settings.parse("response-cache-size")?;
+        Ok(size.filter(|size| *size > 0).map(|size| Self {
+            size,
+            max_entry_size,
+        }))
+    }
+}
+
+/// Counters for the response cache, served as the `runwasi_response_cache_*` metrics.
+#[derive(Default, Debug)]
+pub struct CacheStats {
+    pub hits: AtomicU64,
+    pub misses: AtomicU64,
+    pub stores: AtomicU64,
+    pub evictions: AtomicU64,
+}
+
+impl MetricsSource for CacheStats {
+    fn write_metrics(&self, metrics: &mut String) {
+        let counters = [
+            ("hits", "Responses served from the cache.", &self.hits),
+            (
+                "misses",
+                "Cacheable requests not found in the cache.",
+                &self.misses,
+            ),
+            ("stores", "Responses stored in the cache.", &self.stores),
+            (
+                "evictions",
+                "Responses evicted from the cache.",
+                &self.evictions,
+            ),
+        ];
+        for (name, help, counter) in counters {
+            let value = counter.load(Ordering::Relaxed) as f64;
+            let name = format!("runwasi_response_cache_{name}_total");
+            write_metric(metrics, &name, "counter", help, value);
+        }
+    }
+}
+
+type Key = (Method, String);
+
+struct Entry {
+    // Request header values the response varies on
+    vary: Vec<(HeaderName, Option<HeaderValue>)>,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    stored: Instant,
+    expires: Instant,
+    last_used: u64,
+}
+
+impl Entry {
+    fn size(&self) -> usize {
+        let headers: usize = self
+            .headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum();
+        self.body.len() + headers
+    }
+
+    fn matches(&self, headers: &HeaderMap) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| headers.get(name) == value.as_ref())
+    }
+}
+
+#[derive(Default)]
+struct Entries {
+    entries: HashMap<Key, Vec<Entry>>,
+    size: usize,
+    tick: u64,
+}
+
+struct Shared {
+    config: ResponseCacheConfig,
+    entries: Mutex<Entries>,
+    stats: Arc<CacheStats>,
+}
+
+/// Middleware serving cached responses of the guest.
+pub struct ResponseCache {
+    shared: Arc<Shared>,
+}
+
+impl ResponseCache {
+    pub fn new(config: ResponseCacheConfig) -> Self {
+        tracing::info!("caching up to {} bytes of responses", config.size);
+        let stats = Arc::default();
+        metrics::register(&stats);
+        Self {
+            shared: Arc::new(Shared {
+                config,
+                entries: Default::default(),
+                stats,
+            }),
+        }
+    }
+
+    pub fn stats(&self) -> &CacheStats {
+        &self.shared.stats
+    }
+
+    /// The cached response to a request, if any.
+    fn lookup(
+        &self,
+        method: &Method,
+        uri: &Uri,
+        headers: &HeaderMap,
+    ) -> Option<hyper::Response<HyperOutgoingBody>> {
+        let key = cache_key(method, uri, headers)?;
+        if has_directive(headers, "no-cache") || has_directive(headers, "no-store") {
+            return None;
+        }
+
+        let now = Instant::now();
+        let mut entries = self.shared.entries.lock().unwrap();
+        entries.tick += 1;
+        let tick = entries.tick;
+
+        let found = entries.entries.get_mut(&key).and_then(|variants| {
+            let entry = variants
+                .iter_mut()
+                .find(|entry| entry.expires > now && entry.matches(headers))?;
+            entry.last_used = tick;
+
+            let mut resp = hyper::Response::new(
+                Full::new(entry.body.clone())
+                    .map_err(|e| match e {})
+                    .boxed(),
+            );
+            *resp.status_mut() = entry.status;
+            *resp.headers_mut() = entry.headers.clone();
+            let age = now.duration_since(entry.stored).as_secs();
+            resp.headers_mut()
+                .insert(header::AGE, HeaderValue::from(age));
+            Some(resp)
+        });
+
+        let counter = match found {
+            Some(_) => &self.shared.stats.hits,
+            None => &self.shared.stats.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        found
+    }
+
+    /// Wrap the body of `resp` to store it once complete, if it can be cached.
+    fn store(&self, req: &Parts, resp: &mut hyper::Response<HyperOutgoingBody>) {
+        let Some(key) = cache_key(&req.method, &req.uri, &req.headers) else {
+            return;
+        };
+        let authorized = req.headers.contains_key(header::AUTHORIZATION);
+        let Some(ttl) = freshness(resp.status(), resp.headers(), authorized) else {
+            return;
+        };
+        let Some(vary) = vary(resp.headers(), &req.headers) else {
+            return;
+        };
+
+        let now = Instant::now();
+        let pending = Entry {
+            vary,
+            status: resp.status(),
+            headers: resp.headers().clone(),
+            body: Bytes::new(),
+            stored: now,
+            expires: now + ttl,
+            last_used: 0,
+        };
+
+        // Empty bodies are not polled
+        if resp.body().is_end_stream() {
+            self.shared.insert(key, pending);
+            return;
+        }
+
+        let inner = std::mem::replace(
+            resp.body_mut(),
+            Empty::new().map_err(|e| match e {}).boxed(),
+        );
+        *resp.body_mut() = CachingBody {
+            inner,
+            buf: BytesMut::new(),
+            pending: Some((key, pending)),
+            shared: self.shared.clone(),
+        }
+        .boxed();
+    }
+}
+
+impl Shared {
+    fn insert(&self, key: Key, mut entry: Entry) {
+        let size = entry.size();
+        if size > self.config.size {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.tick += 1;
+        entry.last_used = entries.tick;
+
+        // Replace the variant of the same request, if any
+        let variants = entries.entries.entry(key).or_default();
+        let mut freed = 0;
+        variants.retain(|old| {
+            let replaced = old.vary == entry.vary;
+            if replaced {
+                freed += old.size();
+            }
+            !replaced
+        });
+        variants.push(entry);
+        entries.size = entries.size + size - freed;
+
+        while entries.size > self.config.size {
+            let Some((key, index, size)) = entries
+                .entries
+                .iter()
+                .flat_map(|(key, variants)| {
+                    variants
+                        .iter()
+                        .enumerate()
+                        .map(move |(index, entry)| (key, index, entry))
+                })
+                .min_by_key(|(_, _, entry)| entry.last_used)
+                .map(|(key, index, entry)| (key.clone(), index, entry.size()))
+            else {
+                break;
+            };
+
+            let variants = entries.entries.get_mut(&key).expect("evicted key exists");
+            variants.swap_remove(index);
+            if variants.is_empty() {
+                entries.entries.remove(&key);
+            }
+            entries.size -= size;
+            self.stats.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.stats.stores.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Middleware for ResponseCache {
+    fn on_request(&self, req: &mut Request) -> Result<Option<hyper::Response<HyperOutgoingBody>>> {
+        Ok(self.lookup(req.method(), req.uri(), req.headers()))
+    }
+
+    fn on_response(
+        &self,
+        req: &Parts,
+        resp: &mut hyper::Response<HyperOutgoingBody>,
+    ) -> Result<()> {
+        self.store(req, resp);
+        Ok(())
+    }
+}
+
+/// Body storing the response in the cache once it has been read entirely.
+struct CachingBody {
+    inner: HyperOutgoingBody,
+    buf: BytesMut,
+    pending: Option<(Key, Entry)>,
+    shared: Arc<Shared>,
+}
+
+impl CachingBody {
+    fn complete(&mut self) {
+        if let Some((key, mut entry)) = self.pending.take() {
+            entry.body = std::mem::take(&mut self.buf).freeze();
+            self.shared.insert(key, entry);
+        }
+    }
+}
+
+impl Body for CachingBody {
+    type Data = Bytes;
+    type Error = ErrorCode;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, ErrorCode>>> {
+        let this = &mut *self;
+        let frame = ready!(Pin::new(&mut this.inner).poll_frame(cx));
+
+        match &frame {
+            Some(Ok(frame)) => {
+                if let Some(data) = frame.data_ref() {
+                    if this.buf.len() + data.len() > this.shared.config.max_entry_size {
+                        this.pending = None;
+                    } else if this.pending.is_some() {
+                        this.buf.extend_from_slice(data);
+                    }
+                }
+                // The body may not be polled again once it reports its end
+                if this.inner.is_end_stream() {
+                    this.complete();
+                }
+            }
+            Some(Err(_)) => this.pending = None,
+            None => this.complete(),
+        }
+
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+fn cache_key(method: &Method, uri: &Uri, headers: &HeaderMap) -> Option<Key> {
+    if method != Method::GET && method != Method::HEAD {
+        return None;
+    }
+    let host = uri
+        .authority()
+        .map(|authority| authority.as_str())
+        .or_else(|| headers.get(header::HOST)?.to_str().ok())
+        .unwrap_or_default();
+    let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    Some((method.clone(), format!("{host}{path}")))
+}
+
+fn directives(headers: &HeaderMap) -> impl Iterator<Item = (String, Option<&str>)> {
+    headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|directive| match directive.split_once('=') {
+            Some((name, arg)) => (
+                name.trim().to_ascii_lowercase(),
+                Some(arg.trim().trim_matches('"')),
+            ),
+            None => (directive.trim().to_ascii_lowercase(), None),
+        })
+}
+
+fn has_directive(headers: &HeaderMap, name: &str) -> bool {
+    directives(headers).any(|(directive, _)| directive == name)
+}
+
+/// How long a response can be served from the cache, if it can be cached.
+fn freshness(status: StatusCode, headers: &HeaderMap, authorized: bool) -> Option<Duration> {
+    // Status codes cacheable by default (RFC 9110, section 15.1)
+    if !matches!(
+        status.as_u16(),
+        200 | 203 | 204 | 300 | 301 | 404 | 405 | 410 | 414 | 501
+    ) {
+        return None;
+    }
+    if headers.contains_key(header::SET_COOKIE) {
+        return None;
+    }
+
+    let (mut max_age, mut s_maxage, mut public) = (None, None, false);
+    for (directive, arg) in directives(headers) {
+        match directive.as_str() {
+            "no-store" | "no-cache" | "private" => return None,
+            "public" => public = true,
+            "max-age" => max_age = arg.and_then(|a| a.parse().ok()),
+            "s-maxage" => s_maxage = arg.and_then(|a| a.parse().ok()),
+            _ => {}
+        }
+    }
+
+    if authorized && !public && s_maxage.is_none() {
+        return None;
+    }
+    s_maxage
+        .or(max_age)
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs)
+}
+
+/// The request header values the response varies on, or `None` for `Vary: *`.
+fn vary(
+    resp_headers: &HeaderMap,
+    req_headers: &HeaderMap,
+) -> Option<Vec<(HeaderName, Option<HeaderValue>)>> {
+    let mut vary = vec![];
+    for value in resp_headers.get_all(header::VARY) {
+        for name in value.to_str().ok()?.split(',').map(str::trim) {
+            if name == "*" {
+                return None;
+            }
+            let Ok(name) = HeaderName::try_from(name) else {
+                continue;
+            };
+            let value = req_headers.get(&name).cloned();
+            vary.push((name, value));
+        }
+    }
+    Some(vary)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn cache(size: usize) -> ResponseCache {
+        ResponseCache::new(ResponseCacheConfig {
+            size,
+            max_entry_size: DEFAULT_MAX_ENTRY_SIZE,
+        })
+    }
+
+    fn store(cache: &ResponseCache, path: &str, varies: Option<(&str, &str)>, body: &'static str) {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("max-age=60"),
+        );
+        let mut vary_value = vec![];
+        if let Some((name, value)) = varies {
+            headers.insert(header::VARY, HeaderValue::from_str(name).unwrap());
+            vary_value.push((
+                HeaderName::from_str(name).unwrap(),
+                Some(HeaderValue::from_str(value).unwrap()),
+            ));
+        }
+        let now = Instant::now();
+        let entry = Entry {
+            vary: vary_value,
+            status: StatusCode::OK,
+            headers,
+            body: Bytes::from_static(body.as_bytes()),
+            stored: now,
+            expires: now + Duration::from_secs(60),
+            last_used: 0,
+        };
+        cache
+            .shared
+            .insert((Method::GET, format!("example.com{path}")), entry);
+    }
+
+    fn lookup(cache: &ResponseCache, path: &str, headers: &[(&str, &str)]) -> bool {
+        let uri = format!("http://example.com{path}").parse().unwrap();
+        let mut req_headers = HeaderMap::new();
+        for (name, value) in headers {
+            req_headers.insert(
+                HeaderName::from_str(name).unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        cache.lookup(&Method::GET, &uri, &req_headers).is_some()
+    }
+
+    #[test]
+    fn test_freshness() {
+        let headers = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::CACHE_CONTROL, HeaderValue::from_static(value));
+            headers
+        };
+        let ok = StatusCode::OK;
+
+        assert_eq!(
+            freshness(ok, &headers("public, max-age=60"), false),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            freshness(ok, &headers("max-age=60, s-maxage=10"), false),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(freshness(ok, &headers("max-age=60, private"), false), None);
+        assert_eq!(freshness(ok, &headers("no-store"), false), None);
+        assert_eq!(freshness(ok, &headers("max-age=60"), true), None);
+        assert_eq!(freshness(ok, &HeaderMap::new(), false), None);
+        assert_eq!(
+            freshness(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &headers("max-age=60"),
+                false
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_lookup() {
+        let cache = cache(1024);
+        store(&cache, "/a", None, "a");
+        store(&cache, "/b", Some(("accept-language", "fr")), "b");
+
+        assert!(lookup(&cache, "/a", &[]));
+        assert!(!lookup(&cache, "/a?page=2", &[]));
+        assert!(lookup(&cache, "/b", &[("accept-language", "fr")]));
+        assert!(!lookup(&cache, "/b", &[("accept-language", "en")]));
+        assert!(!lookup(&cache, "/a", &[("cache-control", "no-cache")]));
+    }
+
+    #[test]
+    fn test_eviction() {
+        // Each entry is 24 bytes with its headers
+        let cache = cache(60);
+        store(&cache, "/a", None, "a");
+        store(&cache, "/b", None, "b");
+        assert!(lookup(&cache, "/a", &[]));
+        store(&cache, "/c", None, "c");
+
+        // `/b` is the least recently used entry
+        assert!(lookup(&cache, "/a", &[]));
+        assert!(!lookup(&cache, "/b", &[]));
+        assert!(lookup(&cache, "/c", &[]));
+
+        let mut metrics = String::new();
+        cache.stats().write_metrics(&mut metrics);
+        assert!(metrics.contains("runwasi_response_cache_hits_total 3\n"));
+        assert!(metrics.contains("runwasi_response_cache_misses_total 1\n"));
+        assert!(metrics.contains("runwasi_response_cache_stores_total 3\n"));
+        assert!(metrics.contains("runwasi_response_cache_evictions_total 1\n"));
+    }
+}
@@ -0,0 +1,274 @@
+//! Limit of the requests handled concurrently by the guest.
+//!
+//! The limit is `WASMTIME_HTTP_MAX_CONCURRENCY`, or else the `max_concurrency` of the resource
+//! profile, and bounds the stores instantiated at once, so that a spike of traffic can't exhaust
+//! the memory of the shim.
+//! The requests beyond the limit wait in a queue, up to `WASMTIME_HTTP_MAX_QUEUE` requests when
+//! set, the others being rejected with `503 Service Unavailable`. The depth of the queue, the wait
+//! time, and the rejections are the saturation signal of the service: they are logged every
+//! minute, and served as Prometheus metrics on `/metrics` of the admin endpoint, e.g. for an
+//! autoscaler.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::CancellationToken;
+
+/// Interval of the summaries of the queue.
+const SUMMARY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Counters of the queue of the requests.
+#[derive(Default, Debug)]
+pub struct QueueStats {
+    /// Requests waiting for a slot.
+    pub waiting: AtomicU64,
+    /// Requests handled by the guest.
+    pub admitted: AtomicU64,
+    /// Requests rejected because the queue was full.
+    pub rejected: AtomicU64,
+    /// Total time the admitted requests waited for a slot, in microseconds.
+    pub wait_micros: AtomicU64,
+}
+
+/// A request waiting for a slot, until dropped.
+struct Waiting<'a>(&'a AtomicU64);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A request handled by the guest, counted in flight until dropped.
+pub(crate) struct InFlight(Arc<AtomicU64>);
+
+impl InFlight {
+    pub fn enter(counter: &Arc<AtomicU64>) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+        Self(counter.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub(crate) struct ConcurrencyLimit {
+    semaphore: Arc<Semaphore>,
+    limit: Mutex<usize>,
+    max_queue: Option<usize>,
+    stats: QueueStats,
+}
+
+impl ConcurrencyLimit {
+    pub fn new(limit: usize, max_queue: Option<usize>) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(limit)),
+            limit: Mutex::new(limit),
+            max_queue,
+            stats: QueueStats::default(),
+        }
+    }
+
+    /// Wait for a request slot, held until the permit is dropped, or `None` if the queue is
+    /// full.
+    pub async fn acquire(&self) -> Result<Option<OwnedSemaphorePermit>> {
+        let stats = &self.stats;
+        if let Ok(permit) = self.semaphore.clone().try_acquire_owned() {
+            stats.admitted.fetch_add(1, Ordering::Relaxed);
+            return Ok(Some(permit));
+        }
+
+        let waiting = stats.waiting.fetch_add(1, Ordering::Relaxed);
+        let _waiting = Waiting(&stats.waiting);
+        if self.max_queue.is_some_and(|max| waiting >= max as u64) {
+            stats.rejected.fetch_add(1, Ordering::Relaxed);
+            return Ok(None);
+        }
+
+        let start = Instant::now();
+        let permit = self.semaphore.clone().acquire_owned().await?;
+        let waited = start.elapsed().as_micros() as u64;
+        stats.wait_micros.fetch_add(waited, Ordering::Relaxed);
+        stats.admitted.fetch_add(1, Ordering::Relaxed);
+        Ok(Some(permit))
+    }
+
+    /// The requests waiting for a slot.
+    pub fn queue_depth(&self) -> u64 {
+        self.stats.waiting.load(Ordering::Relaxed)
+    }
+
+    /// Change the limit. Lowering it doesn't interrupt the in-flight requests, the limit is
+    /// reached as they complete.
+    pub fn resize(&self, new_limit: usize) {
+        let mut limit = self.limit.lock().unwrap();
+        if new_limit > *limit {
+            self.semaphore.add_permits(new_limit - *limit);
+        } else if new_limit < *limit {
+            let excess = *limit - new_limit;
+            // The permits in use are forgotten once released
+            let in_use = excess - self.semaphore.forget_permits(excess);
+            if in_use > 0 {
+                let semaphore = self.semaphore.clone();
+                tokio::spawn(async move {
+                    if let Ok(permits) = semaphore.acquire_many_owned(in_use as u32).await {
+                        permits.forget();
+                    }
+                });
+            }
+        }
+        tracing::info!("handling up to {new_limit} requests concurrently");
+        *limit = new_limit;
+    }
+
+    /// The metrics of the queue, in the Prometheus text format.
+    pub fn metrics(&self) -> String {
+        let stats = &self.stats;
+        let limit = *self.limit.lock().unwrap();
+        let in_flight = limit.saturating_sub(self.semaphore.available_permits());
+        let mut metrics = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: f64| {
+            let _ = writeln!(metrics, "# HELP {name} {help}");
+            let _ = writeln!(metrics, "# TYPE {name} {kind}");
+            let _ = writeln!(metrics, "{name} {value}");
+        };
+        metric(
+            "runwasi_http_concurrency_limit",
+            "gauge",
+            "Maximum number of requests handled concurrently.",
+            limit as f64,
+        );
+        metric(
+            "runwasi_http_in_flight_requests",
+            "gauge",
+            "Requests handled by the guest.",
+            in_flight as f64,
+        );
+        metric(
+            "runwasi_http_queue_depth",
+            "gauge",
+            "Requests waiting for a slot.",
+            stats.waiting.load(Ordering::Relaxed) as f64,
+        );
+        metric(
+            "runwasi_http_admitted_requests_total",
+            "counter",
+            "Requests admitted to the guest.",
+            stats.admitted.load(Ordering::Relaxed) as f64,
+        );
+        metric(
+            "runwasi_http_rejected_requests_total",
+            "counter",
+            "Requests rejected because the queue was full.",
+            stats.rejected.load(Ordering::Relaxed) as f64,
+        );
+        metric(
+            "runwasi_http_queue_wait_seconds_total",
+            "counter",
+            "Total time the admitted requests waited for a slot.",
+            stats.wait_micros.load(Ordering::Relaxed) as f64 / 1e6,
+        );
+        metrics
+    }
+
+    /// Log a summary of the queue every minute with requests, until `cancel` is cancelled.
+    pub async fn report(&self, cancel: CancellationToken) {
+        let mut interval = tokio::time::interval(SUMMARY_INTERVAL);
+        interval.tick().await;
+        let mut last = (0, 0, 0);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = cancel.cancelled() => break,
+            }
+            let stats = &self.stats;
+            let now = (
+                stats.admitted.load(Ordering::Relaxed),
+                stats.rejected.load(Ordering::Relaxed),
+                stats.wait_micros.load(Ordering::Relaxed),
+            );
+            let (admitted, rejected, wait_micros) =
+                (now.0 - last.0, now.1 - last.1, now.2 - last.2);
+            last = now;
+            if admitted == 0 && rejected == 0 {
+                continue;
+            }
+            let waiting = stats.waiting.load(Ordering::Relaxed);
+            let avg_wait = Duration::from_micros(wait_micros / admitted.max(1));
+            if rejected > 0 {
+                tracing::warn!(
+                    "request queue saturated: {rejected} requests rejected, {admitted} admitted waiting {avg_wait:?} on average, {waiting} waiting"
+                );
+            } else {
+                tracing::info!(
+                    "request queue: {admitted} requests admitted waiting {avg_wait:?} on average, {waiting} waiting"
+                );
+            }
+        }
+    }
+
+    #[cfg(test)]
+    fn available(&self) -> usize {
+        self.semaphore.available_permits()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_resize() -> Result<()> {
+        let limit = ConcurrencyLimit::new(4, None);
+        let a = limit.acquire().await?;
+        let b = limit.acquire().await?;
+        assert_eq!(limit.available(), 2);
+
+        limit.resize(8);
+        assert_eq!(limit.available(), 6);
+
+        // The two permits in use are forgotten once released
+        limit.resize(1);
+        assert_eq!(limit.available(), 0);
+        drop((a, b));
+        tokio::task::yield_now().await;
+        assert_eq!(limit.available(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_queue() -> Result<()> {
+        let limit = Arc::new(ConcurrencyLimit::new(1, Some(1)));
+        let permit = limit.acquire().await?.unwrap();
+
+        let waiter = tokio::spawn({
+            let limit = limit.clone();
+            async move { limit.acquire().await.map(|permit| permit.is_some()) }
+        });
+        while limit.stats.waiting.load(Ordering::Relaxed) == 0 {
+            tokio::task::yield_now().await;
+        }
+        // The queue is full
+        assert!(limit.acquire().await?.is_none());
+        assert!(limit.metrics().contains("runwasi_http_queue_depth 1\n"));
+
+        drop(permit);
+        assert!(waiter.await??);
+        let metrics = limit.metrics();
+        assert!(
+            metrics.contains("runwasi_http_queue_depth 0\n"),
+            "{metrics}"
+        );
+        assert!(metrics.contains("runwasi_http_admitted_requests_total 2\n"));
+        assert!(metrics.contains("runwasi_http_rejected_requests_total 1\n"));
+        Ok(())
+    }
+}
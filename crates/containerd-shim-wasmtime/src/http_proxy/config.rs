@@ -0,0 +1,650 @@
+//! Typed configuration of the HTTP proxy.
+//!
+//! Each setting is identified by a key, e.g. `cors-max-age`, and is looked up in order in:
+//! 1. the container annotations, as `runwasi.io/http-<key>`, e.g. `runwasi.io/http-cors-max-age`,
+//! 2. the image labels, with the same name as the annotation,
+//! 3. the `http` engine options, by key, see [`WasmtimeOptions`],
+//! 4. the environment of the shim, as `WASMTIME_HTTP_<KEY>`, e.g. `WASMTIME_HTTP_CORS_MAX_AGE`,
+//! 5. the environment of the container, with the same name as in the shim environment.
+//!
+//! The settings are validated when the container is created, so that a typo surfaces as a create
+//! error rather than a misbehaving proxy. Settings found in the container environment are not
+//! passed to the guest.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Context, Result};
+use containerd_shim_wasm::container::RuntimeContext;
+
+use super::acceptor::TlsConfig;
+use super::access_log::AccessLog;
+use super::cache::ResponseCacheConfig;
+use super::cors::Cors;
+use super::forwarded::Forwarded;
+use super::guest_metrics::GuestMetrics;
+use super::header_env::HeaderEnv;
+use super::instance_pool::InstancePoolConfig;
+use super::jwt::JwtConfig;
+use super::listen::ListenAddr;
+use super::normalize::Normalize;
+use super::read_rate::ReadRate;
+use super::recorder::RecorderConfig;
+use super::route_limits::RouteLimits;
+use super::scaler::DEFAULT_TARGET as DEFAULT_SCALER_TARGET;
+use super::streaming::Streaming;
+use super::{DEFAULT_ADDR, DEFAULT_BACKLOG};
+use crate::chaos::Chaos;
+use crate::instance::envs_from_ctx;
+use crate::options::WasmtimeOptions;
+use crate::profile::ResourceProfile;
+
+const ANNOTATION_PREFIX: &str = "runwasi.io/http-";
+const ENV_PREFIX: &str = "WASMTIME_HTTP_";
+
+/// The keys of all the settings.
+const KEYS: &[&str] = &[
+    "proxy-socket-addr",
+    "backlog",
+    "drain-timeout",
+    "request-timeout",
+    "access-log",
+    "route-limits",
+    "max-request-body-size",
+    "min-read-rate",
+    "read-grace-period",
+    "admin-socket-addr",
+    "header-env",
+    "connection-env",
+    "request-id-env",
+    "normalize-path",
+    "lowercase-host",
+    "default-host",
+    "title-case-headers",
+    "cors-allowed-origins",
+    "cors-allowed-methods",
+    "cors-allowed-headers",
+    "cors-max-age",
+    "static-dir",
+    "dynamic-prefixes",
+    "mirror-component",
+    "mirror-percent",
+    "green-component",
+    "green-weight",
+    "grpc-health",
+    "probe-paths",
+    "guest-metrics",
+    "grpc-reflection",
+    "jwt-jwks",
+    "jwt-issuer",
+    "jwt-audience",
+    "jwt-claim-headers",
+    "response-cache-size",
+    "response-cache-max-entry-size",
+    "streaming-content-types",
+    "tiered-start",
+    "instance-pool-size",
+    "instance-pool-max-uses",
+    "stateful-workers",
+    "record-size",
+    "record-max-body-size",
+    "record-redact-headers",
+    "trusted-proxies",
+    "max-concurrency",
+    "max-queue",
+    "scaler-socket-addr",
+    "scaler-target",
+    "tls-cert",
+    "tls-key",
+    "sniff-protocol",
+];
+
+/// A component requests are routed to, in addition to the component of the container.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ComponentRoute {
+    /// Path of the component in the container.
+    pub path: PathBuf,
+    /// Percentage of the requests routed to the component.
+    pub percent: u64,
+}
+
+/// Configuration of the HTTP proxy serving a `wasi:http/proxy` component.
+pub struct ProxyConfig {
+    /// Addresses the proxy listens on (default: the TCP port exposed by the image, if it exposes
+    /// a single one, or else `0.0.0.0:8080`).
+    pub listen: Vec<ListenAddr>,
+    /// Size of the listen backlog (default: 100).
+    pub backlog: u32,
+    /// Time the in-flight requests are given to complete on shutdown, after which they are
+    /// aborted (default: unbounded).
+    pub drain_timeout: Option<Duration>,
+    /// Time the guest is given to handle each request, including streaming its response, after
+    /// which it is cancelled (default: unbounded).
+    pub request_timeout: Option<Duration>,
+    /// Maximum size in bytes of the request bodies, beyond which the requests are answered with
+    /// `413 Content Too Large` (default: unbounded).
+    pub max_request_body_size: Option<u64>,
+    /// Container stream a line is written to for every request, disabled when `None`.
+    pub access_log: Option<AccessLog>,
+    /// Request timeout, body size and concurrency overridden for some path prefixes.
+    pub route_limits: RouteLimits,
+    /// Minimum rate the requests are received at over HTTP/1.1, disabled when `None`.
+    pub read_rate: Option<ReadRate>,
+    /// Address of the admin endpoint.
+    pub admin_socket_addr: Option<SocketAddr>,
+    /// Request headers mapped to environment variables of the guest.
+    pub header_env: HeaderEnv,
+    /// Whether the connection metadata of the requests is passed to the guest in its environment.
+    pub connection_env: bool,
+    /// Whether the id of the requests is passed to the guest in its `REQUEST_ID` variable.
+    pub request_id_env: bool,
+    /// Normalization of the requests before they reach the guest.
+    pub normalize: Normalize,
+    /// Whether the headers of the HTTP/1 responses are written in Title-Case, e.g. `Content-Type`.
+    pub title_case_headers: bool,
+    /// CORS handling, disabled when `None`.
+    pub cors: Option<Cors>,
+    /// Directory of the container static files are served from.
+    pub static_dir: Option<PathBuf>,
+    /// Path prefixes that are always handled by the guest when serving static files.
+    pub dynamic_prefixes: Vec<String>,
+    /// Shadow component a share of the requests is mirrored to.
+    pub mirror: Option<ComponentRoute>,
+    /// Green component a share of the requests is routed to.
+    pub green: Option<ComponentRoute>,
+    /// Whether the gRPC health service is answered by the proxy.
+    pub grpc_health: bool,
+    /// Whether `/healthz` and `/readyz` are answered by the proxy, in front of the guest.
+    pub probe_paths: bool,
+    /// Where the metrics of the guest served on the admin endpoint are read from, not served
+    /// when `None`.
+    pub guest_metrics: Option<GuestMetrics>,
+    /// Path of the `FileDescriptorSet` the gRPC reflection service is answered from.
+    pub grpc_reflection: Option<PathBuf>,
+    /// JWT validation, disabled when `None`.
+    pub jwt: Option<JwtConfig>,
+    /// Response caching, disabled when `None`.
+    pub response_cache: Option<ResponseCacheConfig>,
+    /// Content types of the streamed responses, e.g. Server-Sent Events.
+    pub streaming: Streaming,
+    /// Resource limits of the guest, and concurrency of the requests.
+    pub profile: ResourceProfile,
+    /// Requests handled concurrently by the guest, overriding the limit of the profile.
+    pub max_concurrency: Option<usize>,
+    /// Requests waiting for a slot when their concurrency is limited, beyond which they are
+    /// rejected (default: unbounded).
+    pub max_queue: Option<usize>,
+    /// Whether the component starts with baseline code while optimized code is compiled.
+    pub tiered_start: bool,
+    /// Instances of the guest instantiated ahead of the requests, or stateful workers, disabled
+    /// when `None`.
+    pub instance_pool: Option<InstancePoolConfig>,
+    /// Recording of the requests handled by the guest, disabled when `None`.
+    pub recorder: Option<RecorderConfig>,
+    /// Faults injected in the requests, see [`Chaos`].
+    pub chaos: Arc<Chaos>,
+    /// Proxies trusted to forward the scheme and authority of the requests.
+    pub forwarded: Forwarded,
+    /// Address of the KEDA external scaler, not served when `None`.
+    pub scaler_socket_addr: Option<SocketAddr>,
+    /// Requests per replica KEDA scales the workload to (default: 100).
+    pub scaler_target: u64,
+    /// TLS termination of the connections, disabled when `None`.
+    pub tls: Option<TlsConfig>,
+    /// Whether the first bytes of the connections are peeked to serve TLS and plaintext HTTP on
+    /// the same port.
+    pub sniff_protocol: bool,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            listen: vec![ListenAddr::Tcp(DEFAULT_ADDR, None)],
+            backlog: DEFAULT_BACKLOG,
+            drain_timeout: None,
+            request_timeout: None,
+            max_request_body_size: None,
+            access_log: None,
+            route_limits: RouteLimits::default(),
+            read_rate: None,
+            admin_socket_addr: None,
+            header_env: HeaderEnv::default(),
+            connection_env: false,
+            request_id_env: true,
+            normalize: Normalize::default(),
+            title_case_headers: false,
+            cors: None,
+            static_dir: None,
+            dynamic_prefixes: vec![],
+            mirror: None,
+            green: None,
+            grpc_health: false,
+            probe_paths: false,
+            guest_metrics: None,
+            grpc_reflection: None,
+            jwt: None,
+            response_cache: None,
+            streaming: Streaming::default(),
+            profile: ResourceProfile::default(),
+            max_concurrency: None,
+            max_queue: None,
+            tiered_start: false,
+            instance_pool: None,
+            recorder: None,
+            chaos: Arc::default(),
+            forwarded: Forwarded::default(),
+            scaler_socket_addr: None,
+            scaler_target: DEFAULT_SCALER_TARGET,
+            tls: None,
+            sniff_protocol: false,
+        }
+    }
+}
+
+impl ProxyConfig {
+    /// Read and validate the proxy configuration of the container.
+    pub fn from_ctx(ctx: &impl RuntimeContext) -> Result<Self> {
+        let options: WasmtimeOptions = ctx.options()?;
+        let settings = Settings {
+            annotations: ctx.annotations().clone(),
+            labels: ctx.labels().clone(),
+            exposed_ports: ctx.exposed_ports().to_vec(),
+            options: options.http,
+            shim_env: std::env::vars().collect(),
+            env: envs_from_ctx(ctx).into_iter().collect(),
+        };
+        Ok(Self {
+            profile: ResourceProfile::from_ctx(ctx)?,
+            chaos: Chaos::from_ctx(ctx)?,
+            ..Self::from_settings(&settings)?
+        })
+    }
+
+    fn from_settings(settings: &Settings) -> Result<Self> {
+        if let Some(key) = settings
+            .options
+            .keys()
+            .find(|key| !KEYS.contains(&key.as_str()))
+        {
+            bail!("unknown http engine option {key:?}");
+        }
+
+        let route = |component: &str, percent_key: &str, default_percent| -> Result<_> {
+            let percent = settings.parse(percent_key)?.unwrap_or(default_percent);
+            ensure!(
+                percent <= 100,
+                "{} must be at most 100",
+                settings.source(percent_key)
+            );
+            Ok(settings.get(component).map(|path| ComponentRoute {
+                path: path.into(),
+                percent,
+            }))
+        };
+
+        let listen = match settings.with_source("proxy-socket-addr", ListenAddr::parse_list)? {
+            Some(listen) => listen,
+            None => {
+                let addr = settings.exposed_addr().unwrap_or(DEFAULT_ADDR);
+                vec![ListenAddr::Tcp(addr, None)]
+            }
+        };
+
+        let scaler_target = settings
+            .parse("scaler-target")?
+            .unwrap_or(DEFAULT_SCALER_TARGET);
+        ensure!(
+            scaler_target > 0,
+            "{} must be positive",
+            settings.source("scaler-target")
+        );
+
+        let max_concurrency = settings.parse("max-concurrency")?;
+        ensure!(
+            max_concurrency != Some(0),
+            "{} must be positive",
+            settings.source("max-concurrency")
+        );
+
+        // The environment of the pooled instances, and of the stateful workers, is set before
+        // their requests are received
+        let instance_pool = InstancePoolConfig::from_settings(settings)?;
+        let header_env = HeaderEnv::from_settings(settings)?;
+        let connection_env = settings.flag("connection-env")?;
+        ensure!(
+            instance_pool.is_none() || (header_env.is_empty() && !connection_env),
+            "pooled instances can't be combined with the header or connection environment of the \
+             guest"
+        );
+        let request_id_env = settings.parse("request-id-env")?;
+        ensure!(
+            instance_pool.is_none() || request_id_env != Some(true),
+            "pooled instances can't be combined with {}",
+            settings.source("request-id-env")
+        );
+        let request_id_env = request_id_env.unwrap_or(instance_pool.is_none());
+
+        Ok(Self {
+            listen,
+            backlog: settings.parse("backlog")?.unwrap_or(DEFAULT_BACKLOG),
+            drain_timeout: settings.parse("drain-timeout")?.map(Duration::from_secs),
+            request_timeout: settings.parse("request-timeout")?.map(Duration::from_secs),
+            max_request_body_size: settings.parse("max-request-body-size")?,
+            access_log: settings.parse("access-log")?,
+            route_limits: RouteLimits::from_settings(settings)?,
+            read_rate: ReadRate::from_settings(settings)?,
+            admin_socket_addr: settings.parse("admin-socket-addr")?,
+            header_env,
+            connection_env,
+            request_id_env,
+            normalize: Normalize::from_settings(settings)?,
+            title_case_headers: settings.flag("title-case-headers")?,
+            cors: Cors::from_settings(settings)?,
+            static_dir: settings.get("static-dir").map(PathBuf::from),
+            dynamic_prefixes: settings
+                .get("dynamic-prefixes")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|p| !p.is_empty())
+                .map(String::from)
+                .collect(),
+            mirror: route("mirror-component", "mirror-percent", 100)?,
+            green: route("green-component", "green-weight", 0)?,
+            grpc_health: settings.flag("grpc-health")?,
+            probe_paths: settings.flag("probe-paths")?,
+            guest_metrics: settings.parse("guest-metrics")?,
+            grpc_reflection: settings.get("grpc-reflection").map(PathBuf::from),
+            jwt: JwtConfig::from_settings(settings)?,
+            response_cache: ResponseCacheConfig::from_settings(settings)?,
+            streaming: Streaming::from_settings(settings)?,
+            profile: ResourceProfile::default(),
+            max_concurrency,
+            max_queue: settings.parse("max-queue")?,
+            tiered_start: settings.flag("tiered-start")?,
+            instance_pool,
+            recorder: RecorderConfig::from_settings(settings)?,
+            chaos: Arc::default(),
+            forwarded: Forwarded::from_settings(settings)?,
+            scaler_socket_addr: settings.parse("scaler-socket-addr")?,
+            scaler_target,
+            tls: TlsConfig::from_settings(settings)?,
+            sniff_protocol: settings.flag("sniff-protocol")?,
+        })
+    }
+}
+
+/// Whether the container environment variable `name` is a proxy setting.
+pub(crate) fn is_setting(name: &str) -> bool {
+    KEYS.iter().any(|key| env_name(key) == name)
+}
+
+fn env_name(key: &str) -> String {
+    format!("{ENV_PREFIX}{}", key.to_ascii_uppercase().replace('-', "_"))
+}
+
+/// The sources of the proxy settings.
+#[derive(Default)]
+pub(crate) struct Settings {
+    annotations: HashMap<String, String>,
+    labels: HashMap<String, String>,
+    /// The ports exposed by the image, e.g. `8080/tcp`.
+    exposed_ports: Vec<String>,
+    options: HashMap<String, String>,
+    shim_env: HashMap<String, String>,
+    env: HashMap<String, String>,
+}
+
+impl Settings {
+    fn lookup(&self, key: &str) -> Option<(String, &str)> {
+        debug_assert!(KEYS.contains(&key), "unknown proxy setting {key:?}");
+        let annotation = format!("{ANNOTATION_PREFIX}{key}");
+        let env = env_name(key);
+
+        if let Some(value) = self.annotations.get(&annotation) {
+            return Some((format!("annotation {annotation}"), value));
+        }
+        if let Some(value) = self.labels.get(&annotation) {
+            return Some((format!("label {annotation}"), value));
+        }
+        if let Some(value) = self.options.get(key) {
+            return Some((format!("http engine option {key}"), value));
+        }
+        if let Some(value) = self.shim_env.get(&env) {
+            return Some((format!("shim environment variable {env}"), value));
+        }
+        let value = self.env.get(&env)?;
+        Some((format!("environment variable {env}"), value))
+    }
+
+    /// The value of the setting `key`, if set.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.lookup(key).map(|(_, value)| value)
+    }
+
+    /// A description of where the setting `key` was read from, for error messages.
+    pub fn source(&self, key: &str) -> String {
+        match self.lookup(key) {
+            Some((source, _)) => source,
+            None => env_name(key),
+        }
+    }
+
+    pub fn parse<T>(&self, key: &str) -> Result<Option<T>>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        let Some((source, value)) = self.lookup(key) else {
+            return Ok(None);
+        };
+        match value.trim().parse() {
+            Ok(value) => Ok(Some(value)),
+            Err(e) => bail!("invalid {source} {value:?}: {e}"),
+        }
+    }
+
+    pub fn flag(&self, key: &str) -> Result<bool> {
+        Ok(self.parse(key)?.unwrap_or(false))
+    }
+
+    /// Like `get`, with the source of the setting as error context.
+    pub fn with_source<T>(
+        &self,
+        key: &str,
+        f: impl FnOnce(&str) -> Result<T>,
+    ) -> Result<Option<T>> {
+        let Some((source, value)) = self.lookup(key) else {
+            return Ok(None);
+        };
+        f(value)
+            .map(Some)
+            .with_context(|| format!("invalid {source}"))
+    }
+
+    /// The address of the TCP port exposed by the image, if it exposes a single one. The ports of
+    /// other protocols, and the port ranges, are ignored.
+    fn exposed_addr(&self) -> Option<SocketAddr> {
+        let mut ports = self.exposed_ports.iter().filter_map(|port| {
+            let (port, protocol) = port.split_once('/').unwrap_or((port, "tcp"));
+            if !protocol.eq_ignore_ascii_case("tcp") {
+                return None;
+            }
+            port.parse().ok()
+        });
+        match (ports.next(), ports.next()) {
+            (Some(port), None) => Some(SocketAddr::new(DEFAULT_ADDR.ip(), port)),
+            _ => None,
+        }
+    }
+
+    /// Settings read from the container environment only.
+    #[cfg(test)]
+    pub fn from_env<'a>(env: impl IntoIterator<Item = (&'a str, &'a str)>) -> Self {
+        Self {
+            env: env
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_precedence() -> Result<()> {
+        let settings = Settings {
+            annotations: HashMap::from([("runwasi.io/http-backlog".into(), "10".into())]),
+            labels: HashMap::from([
+                ("runwasi.io/http-backlog".into(), "20".into()),
+                ("runwasi.io/http-green-weight".into(), "5".into()),
+            ]),
+            options: HashMap::from([
+                ("backlog".into(), "40".into()),
+                ("mirror-component".into(), "/mirror.wasm".into()),
+            ]),
+            ..Settings::from_env([
+                ("WASMTIME_HTTP_BACKLOG", "30"),
+                ("WASMTIME_HTTP_GREEN_WEIGHT", "50"),
+                ("WASMTIME_HTTP_GREEN_COMPONENT", "/green.wasm"),
+            ])
+        };
+
+        let config = ProxyConfig::from_settings(&settings)?;
+        assert_eq!(config.backlog, 10);
+        assert_eq!(config.listen, vec![ListenAddr::Tcp(DEFAULT_ADDR, None)]);
+        assert_eq!(
+            config.green,
+            Some(ComponentRoute {
+                path: "/green.wasm".into(),
+                percent: 5,
+            })
+        );
+        assert_eq!(
+            config.mirror,
+            Some(ComponentRoute {
+                path: "/mirror.wasm".into(),
+                percent: 100,
+            })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validation() {
+        let err = |key, value| {
+            let settings = Settings::from_env([(key, value)]);
+            format!("{:#}", ProxyConfig::from_settings(&settings).err().unwrap())
+        };
+
+        assert!(err("WASMTIME_HTTP_BACKLOG", "many")
+            .contains("environment variable WASMTIME_HTTP_BACKLOG"));
+        assert!(err("WASMTIME_HTTP_DRAIN_TIMEOUT", "30s").contains("WASMTIME_HTTP_DRAIN_TIMEOUT"));
+        assert!(err("WASMTIME_HTTP_MAX_REQUEST_BODY_SIZE", "1MB")
+            .contains("WASMTIME_HTTP_MAX_REQUEST_BODY_SIZE"));
+        assert!(err("WASMTIME_HTTP_ACCESS_LOG", "syslog").contains("expected stdout or stderr"));
+        assert!(err("WASMTIME_HTTP_ROUTE_LIMITS", "/upload:timeout=1")
+            .contains("WASMTIME_HTTP_ROUTE_LIMITS"));
+        assert!(err("WASMTIME_HTTP_DEFAULT_HOST", "a b").contains("WASMTIME_HTTP_DEFAULT_HOST"));
+        assert!(err("WASMTIME_HTTP_GRPC_HEALTH", "yes").contains("WASMTIME_HTTP_GRPC_HEALTH"));
+        assert!(err("WASMTIME_HTTP_GUEST_METRICS", "metrics.prom").contains("file:<path>"));
+        assert!(err("WASMTIME_HTTP_MIRROR_PERCENT", "150").contains("at most 100"));
+        assert!(err("WASMTIME_HTTP_SCALER_TARGET", "0").contains("must be positive"));
+        assert!(err("WASMTIME_HTTP_MAX_CONCURRENCY", "0").contains("must be positive"));
+        assert!(err("WASMTIME_HTTP_MIN_READ_RATE", "0").contains("must be positive"));
+        assert!(err("WASMTIME_HTTP_TLS_CERT", "/cert.pem").contains("must be set together"));
+        assert!(err("WASMTIME_HTTP_CORS_ALLOWED_ORIGINS", "a\nb")
+            .contains("WASMTIME_HTTP_CORS_ALLOWED_ORIGINS"));
+
+        let settings = Settings::from_env([
+            ("WASMTIME_HTTP_INSTANCE_POOL_SIZE", "4"),
+            ("WASMTIME_HTTP_CONNECTION_ENV", "true"),
+        ]);
+        let err = ProxyConfig::from_settings(&settings).err().unwrap();
+        assert!(err.to_string().contains("can't be combined"));
+
+        let settings = Settings::from_env([
+            ("WASMTIME_HTTP_INSTANCE_POOL_SIZE", "4"),
+            ("WASMTIME_HTTP_REQUEST_ID_ENV", "true"),
+        ]);
+        let err = ProxyConfig::from_settings(&settings).err().unwrap();
+        assert!(err.to_string().contains("WASMTIME_HTTP_REQUEST_ID_ENV"));
+        let settings = Settings::from_env([("WASMTIME_HTTP_INSTANCE_POOL_SIZE", "4")]);
+        let config = ProxyConfig::from_settings(&settings).unwrap();
+        assert!(!config.request_id_env);
+
+        let settings = Settings {
+            options: HashMap::from([("backlg".into(), "10".into())]),
+            ..Default::default()
+        };
+        let err = ProxyConfig::from_settings(&settings).err().unwrap();
+        assert!(err.to_string().contains("\"backlg\""));
+    }
+
+    #[test]
+    fn test_unix_socket() -> Result<()> {
+        let settings = Settings::from_env([(
+            "WASMTIME_HTTP_PROXY_SOCKET_ADDR",
+            "unix:///run/proxy/http.sock",
+        )]);
+        let config = ProxyConfig::from_settings(&settings)?;
+        assert_eq!(
+            config.listen,
+            vec![ListenAddr::Unix("/run/proxy/http.sock".into())]
+        );
+
+        let settings = Settings::from_env([("WASMTIME_HTTP_PROXY_SOCKET_ADDR", "unix://")]);
+        assert!(ProxyConfig::from_settings(&settings).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_exposed_ports() -> Result<()> {
+        let socket_addr = |ports: &[&str]| -> Result<SocketAddr> {
+            let settings = Settings {
+                exposed_ports: ports.iter().map(|p| p.to_string()).collect(),
+                ..Default::default()
+            };
+            let listen = ProxyConfig::from_settings(&settings)?.listen;
+            match listen.as_slice() {
+                [ListenAddr::Tcp(addr, None)] => Ok(*addr),
+                _ => bail!("unexpected listeners {listen:?}"),
+            }
+        };
+        assert_eq!(socket_addr(&[])?, DEFAULT_ADDR);
+        assert_eq!(socket_addr(&["3000/tcp"])?, "0.0.0.0:3000".parse()?);
+        assert_eq!(socket_addr(&["53/udp", "3000"])?, "0.0.0.0:3000".parse()?);
+        assert_eq!(socket_addr(&["3000/tcp", "9090/tcp"])?, DEFAULT_ADDR);
+        assert_eq!(socket_addr(&["8000-8010/tcp"])?, DEFAULT_ADDR);
+
+        // An explicit address wins over the exposed port
+        let settings = Settings {
+            exposed_ports: vec!["3000/tcp".into()],
+            ..Settings::from_env([("WASMTIME_HTTP_PROXY_SOCKET_ADDR", "127.0.0.1:8081")])
+        };
+        let config = ProxyConfig::from_settings(&settings)?;
+        assert_eq!(
+            config.listen,
+            vec![ListenAddr::Tcp("127.0.0.1:8081".parse()?, None)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_setting() {
+        assert!(is_setting("WASMTIME_HTTP_PROXY_SOCKET_ADDR"));
+        assert!(is_setting("WASMTIME_HTTP_CORS_MAX_AGE"));
+        assert!(!is_setting("WASMTIME_HTTP_UNKNOWN"));
+        assert!(!is_setting("HTTP_PROXY"));
+    }
+}
@@ -0,0 +1,76 @@
+//! Connection metadata of the requests, passed to the guest.
+//!
+//! `wasi:http` requests carry no transport details, which some components need, e.g. to log or
+//! locate their clients. With `WASMTIME_HTTP_CONNECTION_ENV` set to `true`, every request sets
+//! per-request environment variables of the guest, named after CGI:
+//! * `REMOTE_ADDR`: the IP address of the client, taken from `X-Forwarded-For` behind trusted
+//!   proxies,
+//! * `REMOTE_PORT`: the port of the client, when it is connected to the proxy directly,
+//! * `SERVER_PROTOCOL`: the HTTP version of the request, e.g. `HTTP/1.1` or `HTTP/2.0`,
+//! * `HTTPS`: `on` for the requests received over TLS,
+//! * `TLS_SERVER_NAME`: the server name indicated by the client (SNI),
+//! * `TLS_ALPN_PROTOCOL`: the protocol negotiated with ALPN, e.g. `h2`.
+//!
+//! The clients connected over a Unix domain socket have no address.
+
+use super::forwarded::{ClientAddr, Forwarded, Tls};
+
+/// The environment variables describing the connection of `req`.
+pub(crate) fn connection_envs<B>(
+    req: &hyper::Request<B>,
+    forwarded: &Forwarded,
+) -> Vec<(&'static str, String)> {
+    let mut envs = vec![("SERVER_PROTOCOL", format!("{:?}", req.version()))];
+
+    if let Some(ClientAddr(peer)) = req.extensions().get::<ClientAddr>() {
+        let client = forwarded.client_ip(peer.ip(), req.headers());
+        envs.push(("REMOTE_ADDR", client.to_string()));
+        if client == peer.ip() {
+            envs.push(("REMOTE_PORT", peer.port().to_string()));
+        }
+    }
+
+    if let Some(tls) = req.extensions().get::<Tls>() {
+        envs.push(("HTTPS", "on".into()));
+        if let Some(server_name) = &tls.server_name {
+            envs.push(("TLS_SERVER_NAME", server_name.to_string()));
+        }
+        if let Some(alpn_protocol) = &tls.alpn_protocol {
+            envs.push(("TLS_ALPN_PROTOCOL", alpn_protocol.to_string()));
+        }
+    }
+    envs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_envs() {
+        let mut req = hyper::Request::builder()
+            .version(hyper::Version::HTTP_2)
+            .body(())
+            .unwrap();
+        req.extensions_mut()
+            .insert(ClientAddr("192.168.0.7:41000".parse().unwrap()));
+        req.extensions_mut().insert(Tls {
+            server_name: Some("app.example.com".into()),
+            alpn_protocol: Some("h2".into()),
+        });
+
+        let envs = connection_envs(&req, &Forwarded::default());
+        assert_eq!(
+            envs,
+            [
+                ("SERVER_PROTOCOL", "HTTP/2.0"),
+                ("REMOTE_ADDR", "192.168.0.7"),
+                ("REMOTE_PORT", "41000"),
+                ("HTTPS", "on"),
+                ("TLS_SERVER_NAME", "app.example.com"),
+                ("TLS_ALPN_PROTOCOL", "h2"),
+            ]
+            .map(|(key, value)| (key, value.to_string()))
+        );
+    }
+}
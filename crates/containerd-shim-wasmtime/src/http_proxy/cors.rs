@@ -0,0 +1,212 @@
+//! CORS handling at the proxy layer.
+//!
+//! CORS is enabled by setting `WASMTIME_HTTP_CORS_ALLOWED_ORIGINS` to a comma separated list of
+//! origins, or `*` to allow any origin. Preflight requests are answered by the shim without
+//! invoking the guest, and responses of the guest to allowed origins get the
+//! `Access-Control-Allow-Origin` header if the guest didn't set it.
+//!
+//! The preflight response is further configured with:
+//! * `WASMTIME_HTTP_CORS_ALLOWED_METHODS`: comma separated list of methods
+//!   (default: `GET,HEAD,POST,PUT,PATCH,DELETE`).
+//! * `WASMTIME_HTTP_CORS_ALLOWED_HEADERS`: comma separated list of headers, or `*`. When unset,
+//!   the headers requested by the client are allowed.
+//! * `WASMTIME_HTTP_CORS_MAX_AGE`: number of seconds the preflight response can be cached.
+
+use anyhow::{Context, Result};
+use http_body_util::{BodyExt, Empty};
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Method, StatusCode};
+use wasmtime_wasi_http::body::HyperOutgoingBody;
+
+use super::config::Settings;
+use super::Request;
+
+const DEFAULT_METHODS: &str = "GET,HEAD,POST,PUT,PATCH,DELETE";
+
+pub struct Cors {
+    origins: AllowedOrigins,
+    methods: HeaderValue,
+    headers: Option<HeaderValue>,
+    max_age: Option<HeaderValue>,
+}
+
+enum AllowedOrigins {
+    Any,
+    List(Vec<HeaderValue>),
+}
+
+impl Cors {
+    /// Read the CORS settings.
+    /// Returns `None` if CORS is not enabled.
+    pub(crate) fn from_settings(settings: &Settings) -> Result<Option<Self>> {
+        let origins = settings.with_source("cors-allowed-origins", |origins| {
+            Ok(match origins.trim() {
+                "*" => AllowedOrigins::Any,
+                origins => AllowedOrigins::List(
+                    split_list(origins)
+                        .map(HeaderValue::try_from)
+                        .collect::<Result<_, _>>()?,
+                ),
+            })
+        })?;
+
+        let methods = settings.with_source("cors-allowed-methods", |methods| {
+            for method in split_list(methods) {
+                method
+                    .parse::<Method>()
+                    .with_context(|| format!("invalid method {method:?}"))?;
+            }
+            join_list(methods)
+        })?;
+
+        let headers = settings.with_source("cors-allowed-headers", join_list)?;
+        let max_age = settings
+            .parse::<u64>("cors-max-age")?
+            .map(HeaderValue::from);
+
+        let Some(origins) = origins else {
+            return Ok(None);
+        };
+
+        Ok(Some(Self {
+            origins,
+            methods: methods.unwrap_or(HeaderValue::from_static(DEFAULT_METHODS)),
+            headers,
+            max_age,
+        }))
+    }
+
+    fn allow_origin(&self, origin: Option<&HeaderValue>) -> Option<HeaderValue> {
+        let origin = origin?;
+        match &self.origins {
+            AllowedOrigins::Any => Some(HeaderValue::from_static("*")),
+            AllowedOrigins::List(origins) => origins.contains(origin).then(|| origin.clone()),
+        }
+    }
+
+    pub fn is_preflight(req: &Request) -> bool {
+        req.method() == Method::OPTIONS
+            && req.headers().contains_key(header::ORIGIN)
+            && req
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+    }
+
+    /// Answer a preflight request.
+    /// Preflights from disallowed origins get a response without any CORS header,
+    /// which the browser treats as a failure.
+    pub fn preflight(&self, req_headers: &HeaderMap) -> hyper::Response<HyperOutgoingBody> {
+        let mut resp = hyper::Response::new(Empty::new().map_err(|e| match e {}).boxed());
+        *resp.status_mut() = StatusCode::NO_CONTENT;
+
+        let headers = resp.headers_mut();
+        headers.insert(header::VARY, HeaderValue::from_static("origin"));
+
+        let Some(origin) = self.allow_origin(req_headers.get(header::ORIGIN)) else {
+            return resp;
+        };
+
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, self.methods.clone());
+
+        let allow_headers = self.headers.clone().or_else(|| {
+            req_headers
+                .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+                .cloned()
+        });
+        if let Some(allow_headers) = allow_headers {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, allow_headers);
+        }
+        if let Some(max_age) = &self.max_age {
+            headers.insert(header::ACCESS_CONTROL_MAX_AGE, max_age.clone());
+        }
+
+        resp
+    }
+
+    /// Add the CORS headers to a response of the guest to a request from `origin`.
+    pub fn apply(
+        &self,
+        origin: Option<&HeaderValue>,
+        resp: &mut hyper::Response<HyperOutgoingBody>,
+    ) {
+        let Some(origin) = self.allow_origin(origin) else {
+            return;
+        };
+
+        let headers = resp.headers_mut();
+        if !headers.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN) {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+            headers.append(header::VARY, HeaderValue::from_static("origin"));
+        }
+    }
+}
+
+fn split_list(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').map(str::trim).filter(|v| !v.is_empty())
+}
+
+fn join_list(value: &str) -> Result<HeaderValue> {
+    Ok(split_list(value).collect::<Vec<_>>().join(",").try_into()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_origins(origins: &str) -> Cors {
+        let settings = Settings::from_env([
+            ("WASMTIME_HTTP_CORS_ALLOWED_ORIGINS", origins),
+            ("WASMTIME_HTTP_CORS_MAX_AGE", "600"),
+        ]);
+        Cors::from_settings(&settings).unwrap().unwrap()
+    }
+
+    fn preflight_headers(origin: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ORIGIN, HeaderValue::from_static(origin));
+        headers.insert(
+            header::ACCESS_CONTROL_REQUEST_METHOD,
+            HeaderValue::from_static("PUT"),
+        );
+        headers.insert(
+            header::ACCESS_CONTROL_REQUEST_HEADERS,
+            HeaderValue::from_static("x-token"),
+        );
+        headers
+    }
+
+    #[test]
+    fn test_preflight() {
+        let cors = with_origins("https://a.example, https://b.example");
+
+        let resp = cors.preflight(&preflight_headers("https://b.example"));
+        let headers = resp.headers();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://b.example"
+        );
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_METHODS],
+            DEFAULT_METHODS
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_HEADERS], "x-token");
+        assert_eq!(headers[header::ACCESS_CONTROL_MAX_AGE], "600");
+
+        let resp = cors.preflight(&preflight_headers("https://c.example"));
+        assert!(!resp
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        let resp = with_origins("*").preflight(&preflight_headers("https://c.example"));
+        assert_eq!(resp.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+    }
+
+    #[test]
+    fn test_disabled_by_default() -> Result<()> {
+        let settings = Settings::from_env([("WASMTIME_HTTP_CORS_MAX_AGE", "600")]);
+        assert!(Cors::from_settings(&settings)?.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,275 @@
+//! Scheme and authority of the requests forwarded by trusted proxies.
+//!
+//! Behind an ingress, the requests reach the proxy over plain HTTP with the authority of the pod,
+//! so the components would build wrong absolute URLs and redirects. With
+//! `WASMTIME_HTTP_TRUSTED_PROXIES` set to a comma separated list of CIDRs, e.g.
+//! `10.0.0.0/8,127.0.0.1`, the `X-Forwarded-Proto` and `X-Forwarded-Host` headers of the requests
+//! of those clients set the scheme and the authority of the requests passed to the guest, and
+//! `X-Forwarded-For` the address of their client. The headers of the other clients are ignored,
+//! as anyone could set them.
+
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{ensure, Context, Result};
+use hyper::http::uri::{self, Authority, PathAndQuery};
+use hyper::{HeaderMap, Uri};
+use wasmtime_wasi_http::bindings::http::types::Scheme;
+
+use super::config::Settings;
+use super::listen::ListenScheme;
+
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// The address of the client of a connection, in the extensions of its requests.
+#[derive(Clone, Copy, Debug)]
+pub struct ClientAddr(pub SocketAddr);
+
+/// The TLS session of the requests received over TLS, terminated by the proxy.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Tls {
+    /// The server name indicated by the client.
+    pub server_name: Option<Arc<str>>,
+    /// The protocol negotiated with ALPN.
+    pub alpn_protocol: Option<Arc<str>>,
+}
+
+/// A range of IP addresses, e.g. `10.0.0.0/8`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>()?, Some(prefix.parse::<u8>()?)),
+            None => (s.parse()?, None),
+        };
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(bits);
+        ensure!(prefix <= bits, "invalid prefix length {prefix}");
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl Cidr {
+    fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match (self.addr, ip) {
+            (IpAddr::V4(_), IpAddr::V6(ip)) => ip.to_ipv4_mapped().map_or(ip.into(), IpAddr::V4),
+            _ => ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct Forwarded {
+    trusted_proxies: Vec<Cidr>,
+}
+
+impl Forwarded {
+    /// Read the trusted proxies.
+    pub(crate) fn from_settings(settings: &Settings) -> Result<Self> {
+        let trusted_proxies = settings
+            .with_source("trusted-proxies", parse_cidrs)?
+            .unwrap_or_default();
+        Ok(Self { trusted_proxies })
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// The IP address of the client of a request from `peer`: the peer itself, or behind trusted
+    /// proxies, the last address of `X-Forwarded-For` which isn't a trusted proxy.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let hops: Vec<&str> = headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect();
+
+        // Every proxy appends the address of its own client
+        let mut client = peer;
+        for hop in hops.iter().rev() {
+            if !self.is_trusted(client) {
+                break;
+            }
+            let Some(ip) = parse_hop(hop) else {
+                break;
+            };
+            client = ip;
+        }
+        client
+    }
+
+    /// The scheme of `req`, whose URI is given the authority forwarded by a trusted proxy.
+    pub fn apply<B>(&self, req: &mut hyper::Request<B>) -> Scheme {
+        // The scheme of the listener, if set, or else whether TLS was terminated
+        let received = match req.extensions().get::<ListenScheme>() {
+            Some(ListenScheme::Https) => Scheme::Https,
+            Some(ListenScheme::Http) => Scheme::Http,
+            None if req.extensions().get::<Tls>().is_some() => Scheme::Https,
+            None => Scheme::Http,
+        };
+        let trusted = req
+            .extensions()
+            .get::<ClientAddr>()
+            .is_some_and(|ClientAddr(addr)| self.is_trusted(addr.ip()));
+        if !trusted {
+            return received;
+        }
+
+        // The first value was set by the proxy closest to the client
+        let header = |name| {
+            let value = req.headers().get(name)?.to_str().ok()?;
+            Some(value.split(',').next()?.trim()).filter(|value| !value.is_empty())
+        };
+        let scheme = match header(X_FORWARDED_PROTO) {
+            Some(proto) if proto.eq_ignore_ascii_case("https") => Scheme::Https,
+            Some(proto) if !proto.eq_ignore_ascii_case("http") => Scheme::Other(proto.into()),
+            Some(_) => Scheme::Http,
+            None => received,
+        };
+        let Some(host) = header(X_FORWARDED_HOST).and_then(|host| host.parse::<Authority>().ok())
+        else {
+            return scheme;
+        };
+
+        let mut parts = std::mem::take(req.uri_mut()).into_parts();
+        parts.scheme = Some(match scheme {
+            Scheme::Https => uri::Scheme::HTTPS,
+            _ => uri::Scheme::HTTP,
+        });
+        parts.authority = Some(host);
+        if parts.path_and_query.is_none() {
+            parts.path_and_query = Some(PathAndQuery::from_static("/"));
+        }
+        *req.uri_mut() = Uri::from_parts(parts).expect("valid absolute URI");
+        scheme
+    }
+}
+
+/// The IP address of a hop of `X-Forwarded-For`, with or without its port.
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    hop.parse()
+        .ok()
+        .or_else(|| hop.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+fn parse_cidrs(value: &str) -> Result<Vec<Cidr>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|cidr| !cidr.is_empty())
+        .map(|cidr| {
+            cidr.parse()
+                .with_context(|| format!("invalid CIDR {cidr:?}"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cidr() -> Result<()> {
+        let cidr: Cidr = "10.0.0.0/8".parse()?;
+        assert!(cidr.contains("10.1.2.3".parse()?));
+        assert!(cidr.contains("::ffff:10.1.2.3".parse()?));
+        assert!(!cidr.contains("11.0.0.1".parse()?));
+
+        let cidr: Cidr = "fd00::/8".parse()?;
+        assert!(cidr.contains("fd12::1".parse()?));
+        assert!(!cidr.contains("fe80::1".parse()?));
+
+        let cidr: Cidr = "127.0.0.1".parse()?;
+        assert!(cidr.contains("127.0.0.1".parse()?));
+        assert!(!cidr.contains("127.0.0.2".parse()?));
+        assert!("0.0.0.0/0".parse::<Cidr>()?.contains("1.2.3.4".parse()?));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!(parse_cidrs("10.0.0.0/8,nope").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply() -> Result<()> {
+        let forwarded = Forwarded {
+            trusted_proxies: parse_cidrs("10.0.0.0/8")?,
+        };
+        let request = |client: &str| {
+            let mut req = hyper::Request::builder()
+                .uri("/login?next=1")
+                .header(X_FORWARDED_PROTO, "https")
+                .header(X_FORWARDED_HOST, "app.example.com, ingress.local")
+                .body(())
+                .unwrap();
+            req.extensions_mut()
+                .insert(ClientAddr(client.parse().unwrap()));
+            req
+        };
+
+        let mut req = request("10.0.0.1:4000");
+        assert!(matches!(forwarded.apply(&mut req), Scheme::Https));
+        assert_eq!(req.uri(), "https://app.example.com/login?next=1");
+
+        let mut req = request("192.168.0.1:4000");
+        assert!(matches!(forwarded.apply(&mut req), Scheme::Http));
+        assert_eq!(req.uri(), "/login?next=1");
+
+        req.extensions_mut().insert(Tls::default());
+        assert!(matches!(forwarded.apply(&mut req), Scheme::Https));
+
+        // TLS terminated upstream of an `https://` listener
+        let mut req = request("192.168.0.1:4000");
+        req.extensions_mut().insert(ListenScheme::Https);
+        assert!(matches!(forwarded.apply(&mut req), Scheme::Https));
+
+        // Trusted proxies still win
+        let mut req = request("10.0.0.1:4000");
+        req.extensions_mut().insert(ListenScheme::Http);
+        assert!(matches!(forwarded.apply(&mut req), Scheme::Https));
+        Ok(())
+    }
+
+    #[test]
+    fn test_client_ip() -> Result<()> {
+        let forwarded = Forwarded {
+            trusted_proxies: parse_cidrs("10.0.0.0/8")?,
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(X_FORWARDED_FOR, "6.6.6.6, 1.2.3.4:5678, 10.0.0.2".parse()?);
+
+        let client =
+            |peer: &str, headers: &HeaderMap| forwarded.client_ip(peer.parse().unwrap(), headers);
+        assert_eq!(client("10.0.0.1", &headers), "1.2.3.4".parse::<IpAddr>()?);
+        // The headers of the other clients are ignored
+        assert_eq!(client("5.5.5.5", &headers), "5.5.5.5".parse::<IpAddr>()?);
+        assert_eq!(
+            client("10.0.0.1", &HeaderMap::new()),
+            "10.0.0.1".parse::<IpAddr>()?
+        );
+        Ok(())
+    }
+}
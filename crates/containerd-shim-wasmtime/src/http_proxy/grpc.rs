@@ -0,0 +1,567 @@
+//! gRPC health and reflection services answered by the shim on behalf of the guest.
+//!
+//! Meshes and load balancers commonly probe `grpc.health.v1.Health`, and tools like `grpcurl`
+//! rely on server reflection, but guests rarely implement either. When enabled, the proxy
+//! answers these calls directly instead of forwarding them to the component:
+//! * `WASMTIME_HTTP_GRPC_HEALTH`: when `true`, answer `grpc.health.v1.Health/Check` and
+//!   `grpc.health.v1.Health/Watch` with `SERVING`.
+//! * `WASMTIME_HTTP_GRPC_REFLECTION`: path to a binary `FileDescriptorSet` (as produced by
+//!   `protoc --descriptor_set_out`) describing the services of the guest. When set, answer
+//!   `grpc.reflection.v1.ServerReflection` and `grpc.reflection.v1alpha.ServerReflection`.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use bytes::{Buf, Bytes, BytesMut};
+use http_body_util::{BodyExt, StreamBody};
+use hyper::body::Frame;
+use prost::Message;
+use prost_types::{DescriptorProto, FileDescriptorProto};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::task::TaskTracker;
+use wasmtime_wasi_http::bindings::http::types::ErrorCode;
+use wasmtime_wasi_http::body::HyperOutgoingBody;
+
+use super::Request;
+
+const HEALTH_CHECK: &str = "/grpc.health.v1.Health/Check";
+const HEALTH_WATCH: &str = "/grpc.health.v1.Health/Watch";
+const REFLECTION_V1: &str = "/grpc.reflection.v1.ServerReflection/ServerReflectionInfo";
+const REFLECTION_V1ALPHA: &str = "/grpc.reflection.v1alpha.ServerReflection/ServerReflectionInfo";
+
+const HEALTH_SERVICE: &str = "grpc.health.v1.Health";
+const REFLECTION_SERVICES: [&str; 2] = [
+    "grpc.reflection.v1.ServerReflection",
+    "grpc.reflection.v1alpha.ServerReflection",
+];
+
+// `grpc.health.v1.HealthCheckResponse.ServingStatus.SERVING`
+const SERVING: i32 = 1;
+
+/// Maximum size of the messages of the clients, unless the request bodies are limited, as the
+/// default of the gRPC servers.
+pub(super) const DEFAULT_MAX_MESSAGE_SIZE: usize = 4 << 20;
+
+// gRPC status codes
+pub(super) const GRPC_OK: u32 = 0;
+const GRPC_NOT_FOUND: u32 = 5;
+pub(super) const GRPC_RESOURCE_EXHAUSTED: u32 = 8;
+pub(super) const GRPC_UNIMPLEMENTED: u32 = 12;
+
+#[derive(Clone, PartialEq, Message)]
+struct HealthCheckResponse {
+    #[prost(int32, tag = "1")]
+    status: i32,
+}
+
+/// The descriptors are kept serialized, as they are sent back as is.
+#[derive(Clone, PartialEq, Message)]
+struct FileDescriptorSet {
+    #[prost(bytes = "bytes", repeated, tag = "1")]
+    file: Vec<Bytes>,
+}
+
+// The messages of `grpc.reflection.v1.ServerReflection`, the same as in `v1alpha`
+
+#[derive(Clone, PartialEq, Message)]
+struct ServerReflectionRequest {
+    #[prost(string, tag = "1")]
+    host: String,
+    #[prost(oneof = "MessageRequest", tags = "3, 4, 5, 6, 7")]
+    message_request: Option<MessageRequest>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+enum MessageRequest {
+    #[prost(string, tag = "3")]
+    FileByFilename(String),
+    #[prost(string, tag = "4")]
+    FileContainingSymbol(String),
+    #[prost(message, tag = "5")]
+    FileContainingExtension(ExtensionRequest),
+    #[prost(string, tag = "6")]
+    AllExtensionNumbersOfType(String),
+    #[prost(string, tag = "7")]
+    ListServices(String),
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ExtensionRequest {
+    #[prost(string, tag = "1")]
+    containing_type: String,
+    #[prost(int32, tag = "2")]
+    extension_number: i32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ServerReflectionResponse {
+    #[prost(string, tag = "1")]
+    valid_host: String,
+    #[prost(message, optional, tag = "2")]
+    original_request: Option<ServerReflectionRequest>,
+    #[prost(oneof = "MessageResponse", tags = "4, 6, 7")]
+    message_response: Option<MessageResponse>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+enum MessageResponse {
+    #[prost(message, tag = "4")]
+    FileDescriptor(FileDescriptorResponse),
+    #[prost(message, tag = "6")]
+    ListServices(ListServiceResponse),
+    #[prost(message, tag = "7")]
+    Error(ErrorResponse),
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct FileDescriptorResponse {
+    #[prost(bytes = "bytes", repeated, tag = "1")]
+    file_descriptor_proto: Vec<Bytes>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ListServiceResponse {
+    #[prost(message, repeated, tag = "1")]
+    service: Vec<ServiceResponse>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ServiceResponse {
+    #[prost(string, tag = "1")]
+    name: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ErrorResponse {
+    #[prost(int32, tag = "1")]
+    error_code: i32,
+    #[prost(string, tag = "2")]
+    error_message: String,
+}
+
+#[derive(Clone)]
+pub(crate) struct GrpcServices {
+    health: bool,
+    reflection: Option<Arc<Reflection>>,
+    max_message_size: usize,
+}
+
+impl GrpcServices {
+    /// Answer the health service if `health` is set, and the reflection service from the
+    /// `FileDescriptorSet` at `reflection`, to messages of at most `max_message_size` bytes.
+    pub fn new(
+        health: bool,
+        reflection: Option<&Path>,
+        max_message_size: Option<u64>,
+    ) -> Result<Self> {
+        let reflection = reflection
+            .map(|path| -> Result<_> {
+                let set = std::fs::read(path)
+                    .with_context(|| format!("failed to read descriptor set {path:?}"))?;
+                Ok(Arc::new(Reflection::new(Bytes::from(set))?))
+            })
+            .transpose()?;
+        let max_message_size = max_message_size.map_or(DEFAULT_MAX_MESSAGE_SIZE, |max| {
+            usize::try_from(max).unwrap_or(usize::MAX)
+        });
+
+        Ok(Self {
+            health,
+            reflection,
+            max_message_size,
+        })
+    }
+
+    /// Whether this request is answered by the shim rather than the guest.
+    pub fn handles(&self, req: &Request) -> bool {
+        let is_grpc = req
+            .headers()
+            .get(hyper::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.starts_with("application/grpc"));
+
+        is_grpc
+            && match req.uri().path() {
+                HEALTH_CHECK | HEALTH_WATCH => self.health,
+                REFLECTION_V1 | REFLECTION_V1ALPHA => self.reflection.is_some(),
+                _ => false,
+            }
+    }
+
+    /// Answer `req`, streaming the response from a task of `tracker`.
+    pub fn serve(&self, req: Request, tracker: &TaskTracker) -> hyper::Response<HyperOutgoingBody> {
+        let (tx, rx) = mpsc::channel(16);
+        let path = req.uri().path().to_string();
+        let mut body = req.into_body();
+        let this = self.clone();
+
+        tracker.spawn(async move {
+            let mut reader = MessageReader::new(this.max_message_size);
+            let mut status = GRPC_OK;
+
+            'frames: while let Some(Ok(frame)) = body.frame().await {
+                let Ok(data) = frame.into_data() else {
+                    continue;
+                };
+                reader.push(&data);
+
+                loop {
+                    let msg = match reader.next_message() {
+                        Ok(Some(msg)) => msg,
+                        Ok(None) => break,
+                        Err(e) => {
+                            tracing::debug!("invalid gRPC request to {path}: {e}");
+                            status = GRPC_RESOURCE_EXHAUSTED;
+                            break 'frames;
+                        }
+                    };
+                    match path.as_str() {
+                        HEALTH_CHECK => {
+                            let _ = tx.send(Ok(Frame::data(encode_health()))).await;
+                            break 'frames;
+                        }
+                        HEALTH_WATCH => {
+                            let _ = tx.send(Ok(Frame::data(encode_health()))).await;
+                            // Watch is a server streaming call, keep it open until the client goes away.
+                            tx.closed().await;
+                            return;
+                        }
+                        _ => {
+                            let reflection =
+                                this.reflection.as_deref().expect("reflection enabled");
+                            match reflection.respond(&msg, this.health) {
+                                Some(resp) => {
+                                    let _ = tx.send(Ok(Frame::data(frame_message(&resp)))).await;
+                                }
+                                None => {
+                                    status = GRPC_UNIMPLEMENTED;
+                                    break 'frames;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+
+            let _ = tx.send(Ok(Frame::trailers(grpc_trailers(status)))).await;
+        });
+
+        let body = StreamBody::new(ReceiverStream::<Result<Frame<Bytes>, ErrorCode>>::new(rx));
+        hyper::Response::builder()
+            .header(hyper::header::CONTENT_TYPE, "application/grpc")
+            .body(body.boxed())
+            .expect("valid response")
+    }
+}
+
+pub(super) fn grpc_trailers(status: u32) -> hyper::HeaderMap {
+    let mut trailers = hyper::HeaderMap::new();
+    trailers.insert("grpc-status", status.into());
+    trailers
+}
+
+fn encode_health() -> Bytes {
+    let resp = HealthCheckResponse { status: SERVING };
+    frame_message(&resp.encode_to_vec())
+}
+
+/// Prefix a message with the gRPC length-prefixed message header.
+pub(super) fn frame_message(msg: &[u8]) -> Bytes {
+    let mut framed = BytesMut::with_capacity(msg.len() + 5);
+    framed.extend_from_slice(&[0]);
+    framed.extend_from_slice(&(msg.len() as u32).to_be_bytes());
+    framed.extend_from_slice(msg);
+    framed.freeze()
+}
+
+/// Splits a stream of bytes into gRPC messages.
+///
+/// Messages larger than the maximum size are rejected as soon as their header is read, so the
+/// buffer never holds more than one message and the frame being pushed.
+pub(super) struct MessageReader {
+    buf: BytesMut,
+    max_message_size: usize,
+}
+
+impl MessageReader {
+    pub fn new(max_message_size: usize) -> Self {
+        Self {
+            buf: BytesMut::new(),
+            max_message_size,
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    pub fn next_message(&mut self) -> Result<Option<Bytes>> {
+        if self.buf.len() < 5 {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes(self.buf[1..5].try_into().unwrap()) as usize;
+        if len > self.max_message_size {
+            bail!(
+                "message of {len} bytes larger than the maximum of {} bytes",
+                self.max_message_size
+            );
+        }
+        if self.buf.len() < 5 + len {
+            return Ok(None);
+        }
+        self.buf.advance(5);
+        Ok(Some(self.buf.split_to(len).freeze()))
+    }
+}
+
+/// The descriptors of the guest services, used to answer reflection requests.
+struct Reflection {
+    // file name -> serialized `FileDescriptorProto`
+    files: Vec<(String, Bytes)>,
+    // fully qualified symbol -> index in `files`
+    symbols: HashMap<String, usize>,
+    services: Vec<String>,
+}
+
+impl Reflection {
+    fn new(descriptor_set: Bytes) -> Result<Self> {
+        let mut files = vec![];
+        let mut symbols = HashMap::new();
+        let mut services = vec![];
+
+        for file in FileDescriptorSet::decode(descriptor_set)?.file {
+            let index = files.len();
+            let descriptor = FileDescriptorProto::decode(file.clone())?;
+            let prefix = match descriptor.package() {
+                "" => String::new(),
+                package => format!("{package}."),
+            };
+
+            for message in &descriptor.message_type {
+                let symbol = format!("{prefix}{}", message.name());
+                collect_nested(message, &symbol, index, &mut symbols);
+                symbols.insert(symbol, index);
+            }
+            for enum_type in &descriptor.enum_type {
+                symbols.insert(format!("{prefix}{}", enum_type.name()), index);
+            }
+            for service in &descriptor.service {
+                let symbol = format!("{prefix}{}", service.name());
+                for method in &service.method {
+                    symbols.insert(format!("{symbol}.{}", method.name()), index);
+                }
+                symbols.insert(symbol.clone(), index);
+                services.push(symbol);
+            }
+
+            files.push((descriptor.name().to_string(), file));
+        }
+
+        Ok(Self {
+            files,
+            symbols,
+            services,
+        })
+    }
+
+    /// Build a `ServerReflectionResponse` for a `ServerReflectionRequest`.
+    /// Returns `None` if the request kind is not supported.
+    fn respond(&self, req: &[u8], health: bool) -> Option<Vec<u8>> {
+        let req = ServerReflectionRequest::decode(req).ok()?;
+        let file = |file: &Bytes| {
+            MessageResponse::FileDescriptor(FileDescriptorResponse {
+                file_descriptor_proto: vec![file.clone()],
+            })
+        };
+        let not_found = |symbol: &str| {
+            MessageResponse::Error(ErrorResponse {
+                error_code: GRPC_NOT_FOUND as i32,
+                error_message: format!("{symbol} not found"),
+            })
+        };
+
+        let resp = match req.message_request.as_ref()? {
+            MessageRequest::FileByFilename(name) => {
+                match self.files.iter().find(|(file, _)| file == name) {
+                    Some((_, descriptor)) => file(descriptor),
+                    None => not_found(name),
+                }
+            }
+            MessageRequest::FileContainingSymbol(symbol) => match self.symbols.get(symbol) {
+                Some(index) => file(&self.files[*index].1),
+                None => not_found(symbol),
+            },
+            MessageRequest::ListServices(_) => {
+                let builtin = REFLECTION_SERVICES
+                    .iter()
+                    .chain(health.then_some(&HEALTH_SERVICE));
+                let service = self
+                    .services
+                    .iter()
+                    .map(String::as_str)
+                    .chain(builtin.copied())
+                    .map(|name| ServiceResponse {
+                        name: name.to_string(),
+                    })
+                    .collect();
+                MessageResponse::ListServices(ListServiceResponse { service })
+            }
+            MessageRequest::FileContainingExtension(_)
+            | MessageRequest::AllExtensionNumbersOfType(_) => return None,
+        };
+
+        let resp = ServerReflectionResponse {
+            valid_host: req.host.clone(),
+            original_request: Some(req),
+            message_response: Some(resp),
+        };
+        Some(resp.encode_to_vec())
+    }
+}
+
+fn collect_nested(
+    message: &DescriptorProto,
+    symbol: &str,
+    index: usize,
+    symbols: &mut HashMap<String, usize>,
+) {
+    for nested in &message.nested_type {
+        let nested_symbol = format!("{symbol}.{}", nested.name());
+        collect_nested(nested, &nested_symbol, index, symbols);
+        symbols.insert(nested_symbol, index);
+    }
+    for enum_type in &message.enum_type {
+        symbols.insert(format!("{symbol}.{}", enum_type.name()), index);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost_types::{MethodDescriptorProto, ServiceDescriptorProto};
+
+    use super::*;
+
+    fn descriptor_set() -> Bytes {
+        let file = FileDescriptorProto {
+            name: Some("helloworld.proto".into()),
+            package: Some("helloworld".into()),
+            message_type: vec![DescriptorProto {
+                name: Some("HelloRequest".into()),
+                ..Default::default()
+            }],
+            service: vec![ServiceDescriptorProto {
+                name: Some("Greeter".into()),
+                method: vec![MethodDescriptorProto {
+                    name: Some("SayHello".into()),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let set = FileDescriptorSet {
+            file: vec![file.encode_to_vec().into()],
+        };
+        set.encode_to_vec().into()
+    }
+
+    #[test]
+    fn test_message_reader() -> Result<()> {
+        let mut reader = MessageReader::new(8);
+        let framed = frame_message(b"hello");
+        reader.push(&framed[..3]);
+        assert!(reader.next_message()?.is_none());
+        reader.push(&framed[3..]);
+        assert_eq!(reader.next_message()?.unwrap(), "hello");
+        assert!(reader.next_message()?.is_none());
+
+        // Larger messages are rejected before they are buffered
+        reader.push(&frame_message(b"hello world")[..5]);
+        assert!(reader.next_message().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_reflection_symbols() -> Result<()> {
+        let reflection = Reflection::new(descriptor_set())?;
+        assert_eq!(reflection.services, vec!["helloworld.Greeter"]);
+        assert_eq!(reflection.symbols["helloworld.Greeter"], 0);
+        assert_eq!(reflection.symbols["helloworld.Greeter.SayHello"], 0);
+        assert_eq!(reflection.symbols["helloworld.HelloRequest"], 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reflection_list_services() -> Result<()> {
+        let reflection = Reflection::new(descriptor_set())?;
+
+        let req = ServerReflectionRequest {
+            host: String::new(),
+            message_request: Some(MessageRequest::ListServices("*".into())),
+        };
+        let resp = reflection.respond(&req.encode_to_vec(), true).unwrap();
+        let resp = ServerReflectionResponse::decode(resp.as_slice())?;
+        assert_eq!(resp.original_request, Some(req));
+        let Some(MessageResponse::ListServices(list)) = resp.message_response else {
+            panic!("unexpected response {resp:?}");
+        };
+        let services = list
+            .service
+            .into_iter()
+            .map(|service| service.name)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            services,
+            vec![
+                "helloworld.Greeter",
+                "grpc.reflection.v1.ServerReflection",
+                "grpc.reflection.v1alpha.ServerReflection",
+                "grpc.health.v1.Health",
+            ]
+        );
+
+        // unsupported requests are rejected
+        let req = ServerReflectionRequest {
+            host: String::new(),
+            message_request: Some(MessageRequest::AllExtensionNumbersOfType(
+                "helloworld.HelloRequest".into(),
+            )),
+        };
+        assert!(reflection.respond(&req.encode_to_vec(), true).is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reflection_file_containing_symbol() -> Result<()> {
+        let reflection = Reflection::new(descriptor_set())?;
+        let respond = |symbol: &str| -> Result<MessageResponse> {
+            let req = ServerReflectionRequest {
+                host: String::new(),
+                message_request: Some(MessageRequest::FileContainingSymbol(symbol.into())),
+            };
+            let resp = reflection.respond(&req.encode_to_vec(), true).unwrap();
+            Ok(ServerReflectionResponse::decode(resp.as_slice())?
+                .message_response
+                .unwrap())
+        };
+
+        let MessageResponse::FileDescriptor(file) = respond("helloworld.Greeter")? else {
+            panic!("file not found");
+        };
+        assert_eq!(
+            file.file_descriptor_proto,
+            vec![reflection.files[0].1.clone()]
+        );
+        assert!(matches!(
+            respond("helloworld.Missing")?,
+            MessageResponse::Error(ErrorResponse { error_code: 5, .. })
+        ));
+
+        Ok(())
+    }
+}
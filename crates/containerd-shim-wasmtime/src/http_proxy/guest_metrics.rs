@@ -0,0 +1,228 @@
+//! Metrics of the guest, served with the metrics of the proxy.
+//!
+//! A component can't open a port of its own for Prometheus to scrape. With
+//! `WASMTIME_HTTP_GUEST_METRICS` set, the metrics of the guest, in the Prometheus text format, are
+//! read on every scrape of `/metrics` on the admin endpoint and appended to the metrics of the
+//! proxy. They are read either:
+//! * from a file of the container the guest writes them to, with `file:<path>`, e.g.
+//!   `file:/tmp/metrics.prom`,
+//! * or from the guest itself, with the path it serves them on, e.g. `/internal/metrics`. The
+//!   requests of the clients to that path are answered with `404 Not Found` by the proxy, so that
+//!   the metrics are only exposed on the admin endpoint.
+//!
+//! The lines that aren't valid metrics, and the metrics named like the ones of the proxy, i.e.
+//! starting with `runwasi_`, are dropped. `runwasi_guest_metrics_up` tells whether the metrics of
+//! the guest could be read.
+
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Context, Result};
+use http_body_util::{BodyExt, Empty};
+use hyper::header::{self, HeaderValue};
+
+use super::mirror::GuestRequest;
+use super::ProxyHandler;
+
+/// Time the guest is given to answer a scrape of its metrics.
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Prefix of the metrics of the proxy.
+const HOST_PREFIX: &str = "runwasi_";
+
+/// Where the metrics of the guest are read from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GuestMetrics {
+    /// A file of the container written by the guest.
+    File(PathBuf),
+    /// A path served by the guest.
+    Path(String),
+}
+
+impl FromStr for GuestMetrics {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(path) = s.strip_prefix("file:") {
+            ensure!(!path.is_empty(), "empty metrics file");
+            return Ok(Self::File(path.into()));
+        }
+        if !s.starts_with('/') {
+            bail!("expected `file:<path>`, or a path of the guest starting with `/`");
+        }
+        Ok(Self::Path(s.to_string()))
+    }
+}
+
+impl GuestMetrics {
+    /// Whether the requests to `path` are for the metrics served by the guest.
+    pub(crate) fn is_scrape_path(&self, path: &str) -> bool {
+        matches!(self, Self::Path(metrics) if metrics == path)
+    }
+
+    /// Read the metrics of the guest, in the Prometheus text format.
+    pub(crate) async fn scrape(&self, handler: &ProxyHandler) -> Result<String> {
+        match self {
+            Self::File(path) => tokio::fs::read_to_string(path)
+                .await
+                .with_context(|| format!("failed to read {path:?}")),
+            Self::Path(path) => {
+                let scrape = scrape_guest(handler, path);
+                tokio::time::timeout(SCRAPE_TIMEOUT, scrape)
+                    .await
+                    .context("the guest didn't answer in time")?
+            }
+        }
+    }
+}
+
+/// Call the guest with a request for its metrics at `path`.
+async fn scrape_guest(handler: &ProxyHandler, path: &str) -> Result<String> {
+    let mut req: GuestRequest = hyper::Request::get(format!("http://localhost{path}"))
+        .body(Empty::new().map_err(|e| match e {}).boxed())?;
+    let headers = req.headers_mut();
+    headers.insert(header::HOST, HeaderValue::from_static("localhost"));
+    headers.insert(header::ACCEPT, HeaderValue::from_static("text/plain"));
+
+    let instance_pre = handler.instance_pre.read().unwrap().clone();
+    let resp = handler
+        .call_guest(
+            &instance_pre,
+            handler.pool.as_ref(),
+            handler.next_req_id(),
+            req,
+            false,
+        )
+        .await?;
+    let status = resp.status();
+    ensure!(status.is_success(), "the guest answered {status}");
+    let body = resp.into_body().collect().await?.to_bytes();
+    Ok(String::from_utf8(body.to_vec())?)
+}
+
+/// Append the metrics of the guest, or `None` if they couldn't be read, to `metrics`.
+pub(crate) fn merge(metrics: &mut String, guest: Option<&str>) {
+    let _ = writeln!(
+        metrics,
+        "# HELP runwasi_guest_metrics_up Whether the metrics of the guest could be read."
+    );
+    let _ = writeln!(metrics, "# TYPE runwasi_guest_metrics_up gauge");
+    let _ = writeln!(
+        metrics,
+        "runwasi_guest_metrics_up {}",
+        guest.is_some() as u8
+    );
+
+    let mut dropped = 0;
+    for line in guest.unwrap_or_default().lines().map(str::trim) {
+        if line.is_empty() {
+            continue;
+        }
+        if is_valid(line) {
+            metrics.push_str(line);
+            metrics.push('\n');
+        } else {
+            dropped += 1;
+        }
+    }
+    if dropped > 0 {
+        tracing::debug!("dropped {dropped} invalid lines of the guest metrics");
+    }
+}
+
+/// Whether `line` is a comment, or a sample of a metric of the guest.
+fn is_valid(line: &str) -> bool {
+    let name = match line.strip_prefix('#') {
+        // The descriptors of a metric
+        Some(comment) => match comment.split_whitespace().collect::<Vec<_>>()[..] {
+            ["HELP" | "TYPE", name, ..] => name,
+            ["HELP" | "TYPE", ..] => return false,
+            _ => return true,
+        },
+        None => {
+            let end = line.find(['{', ' ', '\t']).unwrap_or(line.len());
+            let rest = match line[end..].strip_prefix('{') {
+                Some(labels) => match labels.rsplit_once('}') {
+                    Some((_, rest)) => rest,
+                    None => return false,
+                },
+                None => &line[end..],
+            };
+            // The value, and the optional timestamp
+            let valid = match rest.split_whitespace().collect::<Vec<_>>()[..] {
+                [value] => value.parse::<f64>().is_ok(),
+                [value, timestamp] => {
+                    value.parse::<f64>().is_ok() && timestamp.parse::<i64>().is_ok()
+                }
+                _ => false,
+            };
+            if !valid {
+                return false;
+            }
+            &line[..end]
+        }
+    };
+    is_metric_name(name) && !name.starts_with(HOST_PREFIX)
+}
+
+/// Whether `name` is a valid Prometheus metric name.
+fn is_metric_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == ':')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guest_metrics() -> Result<()> {
+        assert_eq!(
+            "file:/tmp/metrics.prom".parse::<GuestMetrics>()?,
+            GuestMetrics::File("/tmp/metrics.prom".into())
+        );
+        let metrics: GuestMetrics = "/internal/metrics".parse()?;
+        assert!(metrics.is_scrape_path("/internal/metrics"));
+        assert!(!metrics.is_scrape_path("/"));
+        assert!("metrics.prom".parse::<GuestMetrics>().is_err());
+        assert!("file:".parse::<GuestMetrics>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge() {
+        let guest = "\
+            # HELP orders_total Orders placed.\n\
+            # TYPE orders_total counter\n\
+            orders_total{region=\"eu\"} 42\n\
+            \n\
+            runwasi_http_queue_depth 1000\n\
+            orders in progress 3\n\
+            cart_size\n\
+            # a comment\n\
+            latency_seconds_sum 1.5 1700000000000\n";
+        let mut metrics = "runwasi_http_queue_depth 0\n".to_string();
+        merge(&mut metrics, Some(guest));
+        assert_eq!(
+            metrics,
+            "runwasi_http_queue_depth 0\n\
+             # HELP runwasi_guest_metrics_up Whether the metrics of the guest could be read.\n\
+             # TYPE runwasi_guest_metrics_up gauge\n\
+             runwasi_guest_metrics_up 1\n\
+             # HELP orders_total Orders placed.\n\
+             # TYPE orders_total counter\n\
+             orders_total{region=\"eu\"} 42\n\
+             # a comment\n\
+             latency_seconds_sum 1.5 1700000000000\n"
+        );
+
+        let mut metrics = String::new();
+        merge(&mut metrics, None);
+        assert!(metrics.ends_with("runwasi_guest_metrics_up 0\n"));
+    }
+}
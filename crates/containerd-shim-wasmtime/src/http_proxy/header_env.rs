@@ -0,0 +1,79 @@
+//! Map inbound request headers into per-request environment variables of the guest.
+//!
+//! Rules are configured with `WASMTIME_HTTP_HEADER_ENV` as a comma separated list of
+//! `header=ENV_VAR` pairs, e.g. `x-tenant-id=TENANT_ID,accept-language=LOCALE`.
+//! Headers missing from the request, or with a value that is not valid UTF-8, are not set.
+
+use anyhow::{bail, Context, Result};
+use hyper::header::{HeaderMap, HeaderName};
+
+use super::config::Settings;
+
+#[derive(Default)]
+pub struct HeaderEnv {
+    rules: Vec<(HeaderName, String)>,
+}
+
+impl HeaderEnv {
+    /// Read the header mapping rules.
+    pub(crate) fn from_settings(settings: &Settings) -> Result<Self> {
+        Ok(settings
+            .with_source("header-env", Self::parse)?
+            .unwrap_or_default())
+    }
+
+    /// Parse a comma separated list of `header=ENV_VAR` rules.
+    pub fn parse(value: &str) -> Result<Self> {
+        let mut rules = vec![];
+        for rule in value.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+            let Some((header, var)) = rule.split_once('=') else {
+                bail!("invalid header mapping {rule:?}, expected `header=ENV_VAR`");
+            };
+            let header = HeaderName::try_from(header.trim())
+                .with_context(|| format!("invalid header name in {rule:?}"))?;
+            let var = var.trim();
+            if var.is_empty() || var.contains('=') {
+                bail!("invalid environment variable name in {rule:?}");
+            }
+            rules.push((header, var.to_string()));
+        }
+        Ok(Self { rules })
+    }
+
+    /// Whether no header is mapped.
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// The environment variables derived from the request headers.
+    pub fn envs<'a>(
+        &'a self,
+        headers: &'a HeaderMap,
+    ) -> impl Iterator<Item = (&'a str, &'a str)> + 'a {
+        self.rules.iter().filter_map(|(header, var)| {
+            let value = headers.get(header)?.to_str().ok()?;
+            Some((var.as_str(), value))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_env() -> Result<()> {
+        let rules = HeaderEnv::parse("X-Tenant-Id=TENANT_ID, accept-language=LOCALE")?;
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-tenant-id", "acme".parse()?);
+        let envs = rules.envs(&headers).collect::<Vec<_>>();
+        assert_eq!(envs, vec![("TENANT_ID", "acme")]);
+
+        assert!(HeaderEnv::parse("x-tenant-id").is_err());
+        assert!(HeaderEnv::parse("x tenant=TENANT").is_err());
+        assert!(HeaderEnv::parse("x-tenant-id=").is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,328 @@
+//! Pool of pre-instantiated instances of the guest.
+//!
+//! Instantiating the component is most of the latency of the requests to a small component. With
+//! `WASMTIME_HTTP_INSTANCE_POOL_SIZE` set, that many instances are instantiated ahead of the
+//! requests, and a request takes one from the pool instead of instantiating its own. Once its
+//! request is handled, the instance goes back to the pool, until it has handled
+//! `WASMTIME_HTTP_INSTANCE_POOL_MAX_USES` requests (default: 100), after which it is discarded so
+//! that the state leaked by the guest across requests stays bounded. The instances of the guests
+//! that fail, or are cancelled, are discarded too, and the pool is refilled in the background.
+//!
+//! Components keeping in-memory state across requests, e.g. a cache, instead set
+//! `WASMTIME_HTTP_STATEFUL_WORKERS` to the number of instances, e.g. `1` to handle the requests
+//! sequentially with a single instance. The workers are never recycled, and the requests wait for
+//! an idle worker rather than instantiating their own. A worker that fails is replaced, losing its
+//! state.
+//!
+//! The environment of a pooled instance is set when it is instantiated, so the pool can't be
+//! combined with the per-request environment of the guest: `REQUEST_ID` is not set by default, and
+//! the pool is rejected with `WASMTIME_HTTP_REQUEST_ID_ENV`, `WASMTIME_HTTP_HEADER_ENV` or
+//! `WASMTIME_HTTP_CONNECTION_ENV`. The requests routed to the green or mirror components are
+//! always handled by instances of their own.
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{ensure, Result};
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+use wasmtime::Store;
+use wasmtime_wasi_http::bindings::Proxy;
+
+use super::config::Settings;
+use super::ProxyHandler;
+use crate::instance::WasiPreview2Ctx;
+use crate::profile::ResourceProfile;
+
+const DEFAULT_MAX_USES: u64 = 100;
+
+/// Delay before instantiating the guest again after a failure.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Configuration of the pool of instances.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstancePoolConfig {
+    /// Instances kept instantiated ahead of the requests, or the number of stateful workers.
+    pub size: usize,
+    /// Requests handled by an instance before it is discarded, unbounded for stateful workers.
+    pub max_uses: Option<u64>,
+    /// Whether the instances are stateful workers, the requests waiting for an idle one instead
+    /// of instantiating their own.
+    pub stateful: bool,
+}
+
+impl InstancePoolConfig {
+    /// Read the configuration of the pool, `None` when disabled.
+    pub(crate) fn from_settings(settings: &Settings) -> Result<Option<Self>> {
+        if let Some(workers) = settings.parse("stateful-workers")? {
+            ensure!(
+                settings.get("instance-pool-size").is_none(),
+                "{} can't be combined with {}",
+                settings.source("stateful-workers"),
+                settings.source("instance-pool-size")
+            );
+            ensure!(
+                workers > 0,
+                "{} must be positive",
+                settings.source("stateful-workers")
+            );
+            return Ok(Some(Self {
+                size: workers,
+                max_uses: None,
+                stateful: true,
+            }));
+        }
+
+        let Some(size) = settings.parse("instance-pool-size")? else {
+            return Ok(None);
+        };
+        let max_uses = settings
+            .parse("instance-pool-max-uses")?
+            .unwrap_or(DEFAULT_MAX_USES);
+        ensure!(
+            max_uses > 0,
+            "{} must be positive",
+            settings.source("instance-pool-max-uses")
+        );
+        Ok((size > 0).then_some(Self {
+            size,
+            max_uses: Some(max_uses),
+            stateful: false,
+        }))
+    }
+}
+
+/// An idle instance of the guest, with its store.
+struct Instance {
+    store: Store<WasiPreview2Ctx>,
+    proxy: Proxy,
+    uses: u64,
+    generation: u64,
+}
+
+pub(crate) struct InstancePool {
+    config: InstancePoolConfig,
+    /// The size of the pool, initially the one of `config`.
+    size: AtomicUsize,
+    profile: ResourceProfile,
+    idle: Mutex<Vec<Instance>>,
+    /// Instances idle or handling a request.
+    live: AtomicUsize,
+    /// Incremented when the component is upgraded, to discard the instances of the previous one.
+    generation: AtomicU64,
+    refill: Notify,
+    /// Notified when an instance becomes idle.
+    idled: Notify,
+}
+
+/// A pooled instance handling a request, to be released back to its pool.
+pub(crate) struct Lease {
+    pool: Arc<InstancePool>,
+    uses: u64,
+    generation: u64,
+    /// Whether the instance went back to the pool.
+    kept: bool,
+}
+
+impl InstancePool {
+    pub fn new(config: InstancePoolConfig, profile: ResourceProfile) -> Self {
+        Self {
+            size: AtomicUsize::new(config.size),
+            config,
+            profile,
+            idle: Mutex::default(),
+            live: AtomicUsize::new(0),
+            generation: AtomicU64::new(0),
+            refill: Notify::new(),
+            idled: Notify::new(),
+        }
+    }
+
+    /// Take an idle instance, with the lease releasing it. Without an idle instance, wait for one
+    /// with stateful workers, or else return `None`.
+    pub async fn take(self: &Arc<Self>) -> Option<(Store<WasiPreview2Ctx>, Proxy, Lease)> {
+        let instance = loop {
+            let instance = self.idle.lock().unwrap().pop();
+            self.refill.notify_one();
+            match instance {
+                Some(instance) => break instance,
+                None if self.config.stateful => self.idled.notified().await,
+                None => return None,
+            }
+        };
+        let Instance {
+            store,
+            proxy,
+            uses,
+            generation,
+        } = instance;
+        let lease = Lease {
+            pool: self.clone(),
+            uses,
+            generation,
+            kept: false,
+        };
+        Some((store, proxy, lease))
+    }
+
+    /// Discard the idle instances, and the ones in use once released, after the component
+    /// changed.
+    pub fn clear(&self) {
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        let discarded = std::mem::take(&mut *self.idle.lock().unwrap());
+        self.live.fetch_sub(discarded.len(), Ordering::AcqRel);
+        self.refill.notify_one();
+    }
+
+    /// Whether the pool is missing instances: idle ones, or stateful workers.
+    fn is_missing(&self) -> bool {
+        let count = if self.config.stateful {
+            self.live.load(Ordering::Acquire)
+        } else {
+            self.idle.lock().unwrap().len()
+        };
+        count < self.size.load(Ordering::Acquire)
+    }
+
+    /// Change the size of the pool. The instances in excess are discarded once idle.
+    pub fn resize(&self, size: usize) {
+        self.size.store(size, Ordering::Release);
+        let discarded = {
+            let mut idle = self.idle.lock().unwrap();
+            let excess = idle.len().saturating_sub(size);
+            idle.drain(..excess).count()
+        };
+        self.live.fetch_sub(discarded, Ordering::AcqRel);
+        self.refill.notify_one();
+    }
+
+    /// Keep the pool of `handler` filled until `cancel` is triggered.
+    pub async fn fill(&self, handler: &ProxyHandler, cancel: CancellationToken) {
+        loop {
+            while self.is_missing() {
+                let generation = self.generation.load(Ordering::Acquire);
+                let instantiated = tokio::select! {
+                    instance = handler.instantiate() => instance,
+                    _ = cancel.cancelled() => return,
+                };
+                let (store, proxy) = match instantiated {
+                    Ok(instance) => instance,
+                    Err(e) => {
+                        tracing::warn!("failed to pre-instantiate the guest: {e:#}");
+                        tokio::select! {
+                            _ = tokio::time::sleep(RETRY_DELAY) => continue,
+                            _ = cancel.cancelled() => return,
+                        }
+                    }
+                };
+                // Instantiated from the component before an upgrade
+                if generation != self.generation.load(Ordering::Acquire) {
+                    continue;
+                }
+                self.live.fetch_add(1, Ordering::AcqRel);
+                self.idle.lock().unwrap().push(Instance {
+                    store,
+                    proxy,
+                    uses: 0,
+                    generation,
+                });
+                self.idled.notify_one();
+            }
+            tokio::select! {
+                _ = self.refill.notified() => {}
+                _ = cancel.cancelled() => return,
+            }
+        }
+    }
+}
+
+impl Lease {
+    /// Put the instance back in the pool after it handled a request successfully, unless it is
+    /// worn out.
+    pub fn release(mut self, mut store: Store<WasiPreview2Ctx>, proxy: Proxy) {
+        let pool = self.pool.clone();
+        let uses = self.uses + 1;
+        if pool.config.max_uses.is_some_and(|max| uses >= max)
+            || self.generation != pool.generation.load(Ordering::Acquire)
+        {
+            tracing::trace!("discarding an instance after {uses} requests");
+            return;
+        }
+        // Refuel the store for the next request
+        if let Err(e) = pool.profile.limit_store(&mut store, |ctx| &mut ctx.limits) {
+            tracing::debug!("discarding an instance: {e}");
+            return;
+        }
+        let mut idle = pool.idle.lock().unwrap();
+        let size = pool.size.load(Ordering::Acquire);
+        // The stateful workers in excess after the pool shrank are discarded
+        let kept = if pool.config.stateful {
+            pool.live.load(Ordering::Acquire) <= size
+        } else {
+            idle.len() < size
+        };
+        if kept {
+            idle.push(Instance {
+                store,
+                proxy,
+                uses,
+                generation: self.generation,
+            });
+            self.kept = true;
+            pool.idled.notify_one();
+        }
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        if !self.kept {
+            self.pool.live.fetch_sub(1, Ordering::AcqRel);
+            self.pool.refill.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_instance_pool_config() -> Result<()> {
+        let config = |vars: &[(&str, &str)]| {
+            InstancePoolConfig::from_settings(&Settings::from_env(vars.iter().copied()))
+        };
+        assert_eq!(config(&[])?, None);
+        assert_eq!(config(&[("WASMTIME_HTTP_INSTANCE_POOL_SIZE", "0")])?, None);
+        assert_eq!(
+            config(&[("WASMTIME_HTTP_INSTANCE_POOL_SIZE", "4")])?,
+            Some(InstancePoolConfig {
+                size: 4,
+                max_uses: Some(DEFAULT_MAX_USES),
+                stateful: false,
+            })
+        );
+        assert!(config(&[
+            ("WASMTIME_HTTP_INSTANCE_POOL_SIZE", "4"),
+            ("WASMTIME_HTTP_INSTANCE_POOL_MAX_USES", "0"),
+        ])
+        .is_err());
+
+        assert_eq!(
+            config(&[("WASMTIME_HTTP_STATEFUL_WORKERS", "1")])?,
+            Some(InstancePoolConfig {
+                size: 1,
+                max_uses: None,
+                stateful: true,
+            })
+        );
+        assert!(config(&[("WASMTIME_HTTP_STATEFUL_WORKERS", "0")]).is_err());
+        assert!(config(&[
+            ("WASMTIME_HTTP_STATEFUL_WORKERS", "1"),
+            ("WASMTIME_HTTP_INSTANCE_POOL_SIZE", "4"),
+        ])
+        .is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,420 @@
+//! JWT validation at the proxy layer.
+//!
+//! JWT validation is enabled by setting `WASMTIME_HTTP_JWT_JWKS`, or `WASMTIME_HTTP_JWT_ISSUER`
+//! alone to discover the keys of an OpenID Connect issuer. Requests without a valid bearer token
+//! are rejected with `401 Unauthorized` without invoking the guest.
+//! * `WASMTIME_HTTP_JWT_JWKS`: path of a JWKS file in the container, or `http(s)` URL of the JWKS.
+//!   Keys fetched from a URL are refreshed every 5 minutes.
+//! * `WASMTIME_HTTP_JWT_ISSUER`: expected `iss` claim. When no JWKS is set, the keys are read from
+//!   the `jwks_uri` of `<issuer>/.well-known/openid-configuration`.
+//! * `WASMTIME_HTTP_JWT_AUDIENCE`: comma separated list of accepted `aud` claims.
+//! * `WASMTIME_HTTP_JWT_CLAIM_HEADERS`: comma separated list of `claim=header` rules forwarding
+//!   the claims of the token to the guest as request headers, e.g. `sub=x-user-id`. These headers
+//!   are always removed from the incoming requests, so that the guest can trust them.
+//!
+//! The tokens must be signed with the algorithm of their key, its `alg`, or when the key has none,
+//! with an algorithm of its key type, e.g. `RS256` for an RSA key.
+
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Context, Result};
+use http_body_util::{BodyExt, Empty};
+use hyper::header::{self, HeaderMap, HeaderName, HeaderValue};
+use hyper::{StatusCode, Uri};
+use jsonwebtoken::jwk::{AlgorithmParameters, EllipticCurve, Jwk, JwkSet};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde_json::{Map, Value};
+use tokio_util::sync::CancellationToken;
+use wasmtime_wasi_http::body::HyperOutgoingBody;
+
+use super::config::Settings;
+use super::middleware::Middleware;
+use super::Request;
+use crate::outgoing::Outgoing;
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Where the signing keys are read from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum JwksSource {
+    /// A JWKS file in the container.
+    File(PathBuf),
+    /// A JWKS served over HTTP.
+    Url(Uri),
+    /// The JWKS of an OpenID Connect issuer.
+    Discovery(String),
+}
+
+impl JwksSource {
+    fn parse(value: &str) -> Result<Self> {
+        let value = value.trim();
+        if value.starts_with("http://") || value.starts_with("https://") {
+            Ok(Self::Url(value.parse()?))
+        } else {
+            Ok(Self::File(value.into()))
+        }
+    }
+}
+
+/// Configuration of the JWT validation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JwtConfig {
+    pub keys: JwksSource,
+    pub issuer: Option<String>,
+    pub audience: Vec<String>,
+    /// Claims forwarded to the guest, and the request header they are forwarded as.
+    pub claim_headers: Vec<(String, HeaderName)>,
+}
+
+impl JwtConfig {
+    /// Read the JWT settings.
+    /// Returns `None` if JWT validation is not enabled.
+    pub(crate) fn from_settings(settings: &Settings) -> Result<Option<Self>> {
+        let issuer = settings
+            .get("jwt-issuer")
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .map(String::from);
+
+        let keys = match (
+            settings.with_source("jwt-jwks", JwksSource::parse)?,
+            &issuer,
+        ) {
+            (Some(keys), _) => keys,
+            (None, Some(issuer)) => JwksSource::Discovery(issuer.clone()),
+            (None, None) => return Ok(None),
+        };
+
+        let audience = split_list(settings.get("jwt-audience").unwrap_or_default())
+            .map(String::from)
+            .collect();
+
+        let claim_headers = settings
+            .with_source("jwt-claim-headers", parse_claim_headers)?
+            .unwrap_or_default();
+
+        Ok(Some(Self {
+            keys,
+            issuer,
+            audience,
+            claim_headers,
+        }))
+    }
+}
+
+fn parse_claim_headers(value: &str) -> Result<Vec<(String, HeaderName)>> {
+    let mut rules = vec![];
+    for rule in split_list(value) {
+        let Some((claim, header)) = rule.split_once('=') else {
+            bail!("invalid rule {rule:?}, expected claim=header");
+        };
+        let header = HeaderName::try_from(header.trim())
+            .with_context(|| format!("invalid header name {header:?}"))?;
+        rules.push((claim.trim().to_string(), header));
+    }
+    Ok(rules)
+}
+
+fn split_list(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').map(str::trim).filter(|v| !v.is_empty())
+}
+
+/// Middleware rejecting the requests without a valid bearer token.
+pub struct JwtAuth {
+    config: JwtConfig,
+    keys: RwLock<Arc<JwkSet>>,
+}
+
+impl JwtAuth {
+    /// Load the signing keys, fetching them with `outgoing` if they are served over HTTP.
+    pub async fn new(config: JwtConfig, outgoing: &Outgoing) -> Result<Self> {
+        let keys = fetch_keys(&config.keys, outgoing).await?;
+        tracing::info!("validating JWTs with {} keys", keys.keys.len());
+        Ok(Self {
+            config,
+            keys: RwLock::new(Arc::new(keys)),
+        })
+    }
+
+    /// Refresh the keys served over HTTP periodically, until `cancel` is cancelled.
+    pub async fn refresh(&self, outgoing: &Outgoing, cancel: CancellationToken) {
+        if let JwksSource::File(_) = self.config.keys {
+            return;
+        }
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(REFRESH_INTERVAL) => {}
+                _ = cancel.cancelled() => return,
+            }
+            match fetch_keys(&self.config.keys, outgoing).await {
+                Ok(keys) => *self.keys.write().unwrap() = Arc::new(keys),
+                Err(e) => tracing::warn!("failed to refresh the JWT keys: {e:#}"),
+            }
+        }
+    }
+
+    /// Validate `token`, and return its claims.
+    fn validate(&self, token: &str) -> Result<Map<String, Value>> {
+        let header = jsonwebtoken::decode_header(token)?;
+        let keys = self.keys.read().unwrap().clone();
+        let jwk = match &header.kid {
+            Some(kid) => keys.find(kid),
+            // Tokens without key id are only accepted with a single key
+            None if keys.keys.len() == 1 => keys.keys.first(),
+            None => None,
+        }
+        .context("unknown signing key")?;
+
+        // The algorithm is the one of the key, never the one the token claims
+        let alg = key_algorithm(jwk, header.alg)?;
+        let mut validation = Validation::new(alg);
+        validation.algorithms = vec![alg];
+        if let Some(issuer) = &self.config.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        if self.config.audience.is_empty() {
+            validation.validate_aud = false;
+        } else {
+            validation.set_audience(&self.config.audience);
+        }
+
+        let key = DecodingKey::from_jwk(jwk)?;
+        Ok(jsonwebtoken::decode(token, &key, &validation)?.claims)
+    }
+
+    /// Check the bearer token of a request with `headers`, and forward its claims.
+    /// Returns the response rejecting the request if the token is missing or invalid.
+    fn authorize(&self, headers: &mut HeaderMap) -> Option<hyper::Response<HyperOutgoingBody>> {
+        for (_, header) in &self.config.claim_headers {
+            headers.remove(header);
+        }
+
+        let Some(token) = bearer_token(headers) else {
+            return Some(unauthorized("Bearer"));
+        };
+        let claims = match self.validate(token) {
+            Ok(claims) => claims,
+            Err(e) => {
+                tracing::debug!("rejected JWT: {e:#}");
+                return Some(unauthorized("Bearer error=\"invalid_token\""));
+            }
+        };
+
+        for (claim, header) in &self.config.claim_headers {
+            let value = match claims.get(claim) {
+                None | Some(Value::Null) => continue,
+                Some(Value::String(value)) => HeaderValue::try_from(value.as_str()),
+                Some(value) => HeaderValue::try_from(value.to_string()),
+            };
+            match value {
+                Ok(value) => {
+                    headers.insert(header.clone(), value);
+                }
+                Err(_) => tracing::debug!("claim {claim:?} is not a valid header value"),
+            }
+        }
+
+        None
+    }
+}
+
+impl Middleware for JwtAuth {
+    fn on_request(&self, req: &mut Request) -> Result<Option<hyper::Response<HyperOutgoingBody>>> {
+        Ok(self.authorize(req.headers_mut()))
+    }
+}
+
+/// The algorithm of the tokens signed with `jwk`, whose header claims `token_alg`.
+fn key_algorithm(jwk: &Jwk, token_alg: Algorithm) -> Result<Algorithm> {
+    let Some(key_alg) = jwk.common.key_algorithm else {
+        use Algorithm::*;
+        let fits = match &jwk.algorithm {
+            AlgorithmParameters::OctetKey(_) => matches!(token_alg, HS256 | HS384 | HS512),
+            AlgorithmParameters::RSA(_) => {
+                matches!(token_alg, RS256 | RS384 | RS512 | PS256 | PS384 | PS512)
+            }
+            AlgorithmParameters::EllipticCurve(params) => matches!(
+                (&params.curve, token_alg),
+                (EllipticCurve::P256, ES256) | (EllipticCurve::P384, ES384)
+            ),
+            AlgorithmParameters::OctetKeyPair(_) => token_alg == EdDSA,
+        };
+        ensure!(fits, "the key can't verify {token_alg:?} tokens");
+        return Ok(token_alg);
+    };
+    let alg = Algorithm::from_str(&key_alg.to_string())
+        .with_context(|| format!("unsupported key algorithm {key_alg}"))?;
+    ensure!(
+        alg == token_alg,
+        "the token is signed with {token_alg:?}, but its key with {alg:?}"
+    );
+    Ok(alg)
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then(|| token.trim())
+}
+
+fn unauthorized(challenge: &'static str) -> hyper::Response<HyperOutgoingBody> {
+    let mut resp = hyper::Response::new(Empty::new().map_err(|e| match e {}).boxed());
+    *resp.status_mut() = StatusCode::UNAUTHORIZED;
+    resp.headers_mut().insert(
+        header::WWW_AUTHENTICATE,
+        HeaderValue::from_static(challenge),
+    );
+    resp
+}
+
+async fn fetch_keys(source: &JwksSource, outgoing: &Outgoing) -> Result<JwkSet> {
+    let jwks = match source {
+        JwksSource::File(path) => std::fs::read(path)
+            .with_context(|| format!("failed to read JWKS {path:?}"))?
+            .into(),
+        JwksSource::Url(uri) => outgoing.get(uri).await?,
+        JwksSource::Discovery(issuer) => {
+            let uri: Uri = format!(
+                "{}/.well-known/openid-configuration",
+                issuer.trim_end_matches('/')
+            )
+            .parse()
+            .with_context(|| format!("invalid issuer {issuer:?}"))?;
+            let config: Value = serde_json::from_slice(&outgoing.get(&uri).await?)
+                .with_context(|| format!("invalid OpenID configuration {uri}"))?;
+            let jwks_uri = config["jwks_uri"]
+                .as_str()
+                .with_context(|| format!("no jwks_uri in {uri}"))?;
+            outgoing.get(&jwks_uri.parse()?).await?
+        }
+    };
+    serde_json::from_slice(&jwks).context("invalid JWKS")
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+
+    use super::*;
+
+    const SECRET: &[u8] = b"runwasi-test-secret";
+
+    fn jwt_auth(config: JwtConfig) -> JwtAuth {
+        // base64url of SECRET
+        let keys = json!({
+            "keys": [
+                { "kty": "oct", "kid": "test", "alg": "HS256", "k": "cnVud2FzaS10ZXN0LXNlY3JldA" },
+                { "kty": "oct", "kid": "no-alg", "k": "cnVud2FzaS10ZXN0LXNlY3JldA" },
+            ]
+        });
+        JwtAuth {
+            config,
+            keys: RwLock::new(Arc::new(serde_json::from_value(keys).unwrap())),
+        }
+    }
+
+    fn token(claims: Value) -> String {
+        signed_token("test", Algorithm::HS256, claims)
+    }
+
+    fn signed_token(kid: &str, alg: Algorithm, claims: Value) -> String {
+        let header = Header {
+            kid: Some(kid.into()),
+            ..Header::new(alg)
+        };
+        jsonwebtoken::encode(&header, &claims, &EncodingKey::from_secret(SECRET)).unwrap()
+    }
+
+    fn headers(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            format!("Bearer {token}").parse().unwrap(),
+        );
+        headers.insert("x-user-id", HeaderValue::from_static("spoofed"));
+        headers
+    }
+
+    #[test]
+    fn test_from_settings() -> Result<()> {
+        let settings = Settings::from_env([
+            ("WASMTIME_HTTP_JWT_ISSUER", "https://issuer.example"),
+            ("WASMTIME_HTTP_JWT_AUDIENCE", "api, web"),
+            ("WASMTIME_HTTP_JWT_CLAIM_HEADERS", "sub=x-user-id"),
+        ]);
+        let config = JwtConfig::from_settings(&settings)?.unwrap();
+        assert_eq!(
+            config.keys,
+            JwksSource::Discovery("https://issuer.example".into())
+        );
+        assert_eq!(config.audience, vec!["api", "web"]);
+        assert_eq!(
+            config.claim_headers,
+            vec![("sub".into(), HeaderName::from_static("x-user-id"))]
+        );
+
+        let settings = Settings::from_env([("WASMTIME_HTTP_JWT_JWKS", "/etc/jwks.json")]);
+        let config = JwtConfig::from_settings(&settings)?.unwrap();
+        assert_eq!(config.keys, JwksSource::File("/etc/jwks.json".into()));
+
+        assert!(JwtConfig::from_settings(&Settings::default())?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_authorize() {
+        let exp = jsonwebtoken::get_current_timestamp() + 60;
+        let auth = jwt_auth(JwtConfig {
+            keys: JwksSource::File("/etc/jwks.json".into()),
+            issuer: Some("https://issuer.example".into()),
+            audience: vec![],
+            claim_headers: vec![("sub".into(), HeaderName::from_static("x-user-id"))],
+        });
+
+        let valid = token(json!({ "iss": "https://issuer.example", "sub": "alice", "exp": exp }));
+        let mut valid_headers = headers(&valid);
+        assert!(auth.authorize(&mut valid_headers).is_none());
+        assert_eq!(valid_headers["x-user-id"], "alice");
+
+        let other_issuer =
+            token(json!({ "iss": "https://other.example", "sub": "bob", "exp": exp }));
+        let resp = auth.authorize(&mut headers(&other_issuer)).unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let mut no_token = HeaderMap::new();
+        let resp = auth.authorize(&mut no_token).unwrap();
+        assert_eq!(resp.headers()[header::WWW_AUTHENTICATE], "Bearer");
+    }
+
+    #[test]
+    fn test_key_algorithm() {
+        let exp = jsonwebtoken::get_current_timestamp() + 60;
+        let auth = jwt_auth(JwtConfig {
+            keys: JwksSource::File("/etc/jwks.json".into()),
+            issuer: None,
+            audience: vec![],
+            claim_headers: vec![],
+        });
+        let claims = json!({ "sub": "alice", "exp": exp });
+
+        // The token can't pick another algorithm than the one of its key
+        let other_alg = signed_token("test", Algorithm::HS512, claims.clone());
+        assert!(auth.validate(&other_alg).is_err());
+
+        // Keys without algorithm verify the algorithms of their type
+        let no_alg = signed_token("no-alg", Algorithm::HS384, claims);
+        assert!(auth.validate(&no_alg).is_ok());
+
+        let jwk: Jwk = serde_json::from_value(json!({
+            "kty": "RSA", "n": "AQAB", "e": "AQAB"
+        }))
+        .unwrap();
+        assert!(key_algorithm(&jwk, Algorithm::RS256).is_ok());
+        assert!(key_algorithm(&jwk, Algorithm::HS256).is_err());
+    }
+}
@@ -0,0 +1,109 @@
+//! Addresses the proxy listens on.
+//!
+//! `WASMTIME_HTTP_PROXY_SOCKET_ADDR` is a comma separated list of addresses, so that the component
+//! is served on several ports or interfaces by the same proxy, e.g.
+//! `0.0.0.0:8080,https://0.0.0.0:8443`. Each address is either:
+//! * a TCP socket address, e.g. `0.0.0.0:8080`, on which TLS is terminated if configured,
+//! * a TCP socket address prefixed with the scheme of its requests, passed to the guest:
+//!   `http://` listeners never terminate TLS, and `https://` listeners pass the `https` scheme
+//!   even in plaintext, e.g. behind a load balancer terminating TLS,
+//! * a Unix domain socket, as `unix://<path>`.
+//!
+//! The scheme forwarded by a trusted proxy, see [`super::forwarded`], still wins.
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{bail, ensure, Context, Result};
+
+const UNIX_SCHEME: &str = "unix://";
+
+/// The scheme of the requests received on a listener, in the extensions of its requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListenScheme {
+    Http,
+    Https,
+}
+
+/// An address the proxy listens on.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenAddr {
+    /// A TCP socket, with the scheme of its requests if set.
+    Tcp(SocketAddr, Option<ListenScheme>),
+    /// A Unix domain socket.
+    Unix(PathBuf),
+}
+
+impl ListenAddr {
+    /// Parse a comma separated list of addresses.
+    pub fn parse_list(value: &str) -> Result<Vec<Self>> {
+        let addrs = value
+            .split(',')
+            .map(str::trim)
+            .filter(|addr| !addr.is_empty())
+            .map(str::parse)
+            .collect::<Result<Vec<Self>>>()?;
+        ensure!(!addrs.is_empty(), "no address to listen on");
+        Ok(addrs)
+    }
+
+    /// The scheme of the requests received on this address, if set.
+    pub fn scheme(&self) -> Option<ListenScheme> {
+        match self {
+            Self::Tcp(_, scheme) => *scheme,
+            Self::Unix(_) => None,
+        }
+    }
+}
+
+impl FromStr for ListenAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(path) = s.strip_prefix(UNIX_SCHEME) {
+            ensure!(!path.is_empty(), "empty unix socket path");
+            return Ok(Self::Unix(path.into()));
+        }
+        let (scheme, addr) = match s.split_once("://") {
+            Some(("http", addr)) => (Some(ListenScheme::Http), addr),
+            Some(("https", addr)) => (Some(ListenScheme::Https), addr),
+            Some((scheme, _)) => bail!("unsupported scheme {scheme:?}"),
+            None => (None, s),
+        };
+        let addr = addr
+            .parse()
+            .with_context(|| format!("invalid socket address {addr:?}"))?;
+        Ok(Self::Tcp(addr, scheme))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_list() -> Result<()> {
+        let addrs = ListenAddr::parse_list(
+            "0.0.0.0:8080, https://0.0.0.0:8443,http://[::1]:9000,unix:///run/http.sock",
+        )?;
+        assert_eq!(
+            addrs,
+            vec![
+                ListenAddr::Tcp("0.0.0.0:8080".parse()?, None),
+                ListenAddr::Tcp("0.0.0.0:8443".parse()?, Some(ListenScheme::Https)),
+                ListenAddr::Tcp("[::1]:9000".parse()?, Some(ListenScheme::Http)),
+                ListenAddr::Unix("/run/http.sock".into()),
+            ]
+        );
+        assert_eq!(addrs[1].scheme(), Some(ListenScheme::Https));
+        assert_eq!(addrs[3].scheme(), None);
+
+        assert!(ListenAddr::parse_list("").is_err());
+        assert!(ListenAddr::parse_list(" , ").is_err());
+        assert!(ListenAddr::parse_list("0.0.0.0:8080,unix://").is_err());
+        assert!(ListenAddr::parse_list("ftp://0.0.0.0:21").is_err());
+        assert!(ListenAddr::parse_list("https://localhost:8443").is_err());
+        Ok(())
+    }
+}
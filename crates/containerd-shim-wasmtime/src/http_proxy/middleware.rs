@@ -0,0 +1,102 @@
+//! Middleware run by the [`ProxyHandler`](super::ProxyHandler) around the guest.
+//!
+//! Middleware are called in registration order before the request is dispatched, and in reverse
+//! order with the response. A middleware can mutate the request, e.g. to add headers, or answer it
+//! directly, e.g. to reject unauthenticated requests. When a middleware answers a request, neither
+//! the guest nor the middleware registered after it see the request, and only the middleware
+//! registered before it see the response.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use hyper::header;
+use hyper::http::request::Parts;
+use wasmtime_wasi_http::body::HyperOutgoingBody;
+
+use super::cors::Cors;
+use super::Request;
+
+pub trait Middleware: Send + Sync {
+    /// Called before the request is dispatched.
+    /// Returning a response answers the request without calling the guest.
+    fn on_request(&self, _req: &mut Request) -> Result<Option<hyper::Response<HyperOutgoingBody>>> {
+        Ok(None)
+    }
+
+    /// Called with the response to the request with head `req`.
+    fn on_response(
+        &self,
+        _req: &Parts,
+        _resp: &mut hyper::Response<HyperOutgoingBody>,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<M: Middleware + ?Sized> Middleware for Arc<M> {
+    fn on_request(&self, req: &mut Request) -> Result<Option<hyper::Response<HyperOutgoingBody>>> {
+        (**self).on_request(req)
+    }
+
+    fn on_response(
+        &self,
+        req: &Parts,
+        resp: &mut hyper::Response<HyperOutgoingBody>,
+    ) -> Result<()> {
+        (**self).on_response(req, resp)
+    }
+}
+
+impl Middleware for Cors {
+    fn on_request(&self, req: &mut Request) -> Result<Option<hyper::Response<HyperOutgoingBody>>> {
+        Ok(Cors::is_preflight(req).then(|| self.preflight(req.headers())))
+    }
+
+    fn on_response(
+        &self,
+        req: &Parts,
+        resp: &mut hyper::Response<HyperOutgoingBody>,
+    ) -> Result<()> {
+        self.apply(req.headers.get(header::ORIGIN), resp);
+        Ok(())
+    }
+}
+
+/// Run `dispatch` with `req` through `middleware`.
+pub(crate) async fn run<F>(
+    middleware: &[Box<dyn Middleware>],
+    mut req: Request,
+    dispatch: impl FnOnce(Request) -> F,
+) -> Result<hyper::Response<HyperOutgoingBody>>
+where
+    F: std::future::Future<Output = Result<hyper::Response<HyperOutgoingBody>>>,
+{
+    if middleware.is_empty() {
+        return dispatch(req).await;
+    }
+
+    let mut answered = None;
+    let mut called = 0;
+    for m in middleware {
+        called += 1;
+        if let Some(resp) = m.on_request(&mut req)? {
+            answered = Some(resp);
+            break;
+        }
+    }
+
+    let (parts, body) = req.into_parts();
+    let head = parts.clone();
+
+    // The middleware that answered the request doesn't see its own response
+    let (mut resp, called) = match answered {
+        Some(resp) => (resp, called - 1),
+        None => (dispatch(Request::from_parts(parts, body)).await?, called),
+    };
+
+    for m in middleware[..called].iter().rev() {
+        m.on_response(&head, &mut resp)?;
+    }
+
+    Ok(resp)
+}
@@ -0,0 +1,238 @@
+//! Mirror a share of the incoming requests to a shadow component.
+//!
+//! * `WASMTIME_HTTP_MIRROR_COMPONENT`: path of the shadow component in the container, e.g. a
+//!   canary build of the served component.
+//! * `WASMTIME_HTTP_MIRROR_PERCENT`: percentage of requests to mirror (default: 100).
+//!
+//! Mirrored requests are buffered in memory so that they can be replayed to the shadow component
+//! after the served component responded. The response of the shadow component is discarded, and
+//! only its status is compared to the status of the served component.
+//!
+//! Shadow requests have their own budget of [`MAX_SHADOW_REQUESTS`], and don't take the
+//! concurrency permits of the served component: a request is not mirrored when the budget is
+//! exhausted, or when its body is larger than [`MAX_MIRROR_BODY_SIZE`].
+//! The counters of the mirror are served as the `runwasi_http_mirror_*` metrics.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use bytes::{Bytes, BytesMut};
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, BodyStream, Full, StreamBody};
+use hyper::body::Frame;
+use hyper::StatusCode;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_stream::StreamExt;
+use wasmtime::component::Component;
+use wasmtime::Engine;
+use wasmtime_wasi_http::bindings::ProxyPre;
+
+use super::config::ComponentRoute;
+use crate::instance::WasiPreview2Ctx;
+use crate::linker::{proxy_linker, proxy_pre};
+use crate::metrics::{self, write_metric, MetricsSource};
+
+/// Maximum number of shadow requests running at the same time.
+pub(crate) const MAX_SHADOW_REQUESTS: usize = 16;
+/// Maximum size of the request bodies buffered to be mirrored.
+pub(crate) const MAX_MIRROR_BODY_SIZE: u64 = 1024 * 1024;
+
+pub(crate) type GuestRequest = hyper::Request<BoxBody<Bytes, hyper::Error>>;
+
+/// Counters comparing the shadow component to the served component.
+#[derive(Default, Debug)]
+pub struct MirrorStats {
+    pub mirrored: AtomicU64,
+    pub status_matched: AtomicU64,
+    pub status_mismatched: AtomicU64,
+    pub errors: AtomicU64,
+    pub skipped: AtomicU64,
+}
+
+impl MetricsSource for MirrorStats {
+    fn write_metrics(&self, metrics: &mut String) {
+        let counters = [
+            (
+                "mirrored",
+                "Requests mirrored to the shadow component.",
+                &self.mirrored,
+            ),
+            (
+                "status_matched",
+                "Shadow responses with the status of the served response.",
+                &self.status_matched,
+            ),
+            (
+                "status_mismatched",
+                "Shadow responses with another status than the served response.",
+                &self.status_mismatched,
+            ),
+            ("errors", "Shadow requests that failed.", &self.errors),
+            (
+                "skipped",
+                "Sampled requests not mirrored, because of the shadow budget or the body size.",
+                &self.skipped,
+            ),
+        ];
+        for (name, help, counter) in counters {
+            let value = counter.load(Ordering::Relaxed) as f64;
+            let name = format!("runwasi_http_mirror_{name}_total");
+            write_metric(metrics, &name, "counter", help, value);
+        }
+    }
+}
+
+pub(crate) struct Mirror {
+    pub instance_pre: ProxyPre<WasiPreview2Ctx>,
+    percent: u64,
+    seq: AtomicU64,
+    budget: Arc<Semaphore>,
+    pub stats: Arc<MirrorStats>,
+}
+
+impl Mirror {
+    /// Load the shadow component.
+    pub fn new(route: &ComponentRoute, engine: &Engine) -> Result<Self> {
+        let ComponentRoute { path, percent } = route;
+        let component = Component::from_file(engine, path)
+            .with_context(|| format!("failed to load mirror component {path:?}"))?;
+        let instance_pre = proxy_pre(&proxy_linker(engine)?, &component)?;
+
+        tracing::info!("mirroring {percent}% of requests to {path:?}");
+
+        let stats = Arc::default();
+        metrics::register(&stats);
+        Ok(Self {
+            instance_pre,
+            percent: *percent,
+            seq: AtomicU64::from(0),
+            budget: Arc::new(Semaphore::new(MAX_SHADOW_REQUESTS)),
+            stats,
+        })
+    }
+
+    /// Whether the next request should be mirrored, and the permit of its shadow request if so.
+    /// Requests are sampled evenly, so that exactly `percent` out of every 100 requests are mirrored,
+    /// as long as the shadow budget isn't exhausted. The permit is held until the shadow request
+    /// completes.
+    pub fn sample(&self) -> Option<OwnedSemaphorePermit> {
+        if !sample_evenly(self.seq.fetch_add(1, Ordering::Relaxed), self.percent) {
+            return None;
+        }
+        let permit = self.budget.clone().try_acquire_owned().ok();
+        if permit.is_none() {
+            self.stats.skipped.fetch_add(1, Ordering::Relaxed);
+        }
+        permit
+    }
+
+    pub fn skip(&self) {
+        self.stats.skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record(&self, primary: StatusCode, shadow: Result<StatusCode>) {
+        self.stats.mirrored.fetch_add(1, Ordering::Relaxed);
+        match shadow {
+            Ok(shadow) if shadow == primary => {
+                self.stats.status_matched.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(shadow) => {
+                tracing::debug!("mirror: status mismatch, served {primary}, shadow {shadow}");
+                self.stats.status_mismatched.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => {
+                tracing::debug!("mirror: shadow request failed: {e:?}");
+                self.stats.errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Whether the `seq`-th request falls within the sampled `percent` of requests.
+pub(super) fn sample_evenly(seq: u64, percent: u64) -> bool {
+    (seq % 100 + 1) * percent / 100 > (seq % 100) * percent / 100
+}
+
+/// Buffer the request body and duplicate the request.
+/// Returns no shadow request if the body is larger than `limit` or has trailers: the request is
+/// rebuilt from the frames already read and the rest of the body, and is only served.
+pub(crate) async fn split(
+    req: GuestRequest,
+    limit: u64,
+) -> Result<(GuestRequest, Option<GuestRequest>)> {
+    let (parts, mut body) = req.into_parts();
+    let mut buffered = BytesMut::new();
+    while let Some(frame) = body.frame().await {
+        let frame = match frame?.into_data() {
+            Ok(data) if (buffered.len() + data.len()) as u64 <= limit => {
+                buffered.extend_from_slice(&data);
+                continue;
+            }
+            Ok(data) => Frame::data(data),
+            Err(frame) => frame,
+        };
+        let frames = [Frame::data(buffered.freeze()), frame].map(Ok);
+        let rest = tokio_stream::iter(frames).chain(BodyStream::new(body));
+        let req = hyper::Request::from_parts(parts, StreamBody::new(rest).boxed());
+        return Ok((req, None));
+    }
+    let body = buffered.freeze();
+
+    let mut shadow = hyper::Request::new(full(body.clone()));
+    *shadow.method_mut() = parts.method.clone();
+    *shadow.uri_mut() = parts.uri.clone();
+    *shadow.version_mut() = parts.version;
+    *shadow.headers_mut() = parts.headers.clone();
+    *shadow.extensions_mut() = parts.extensions.clone();
+
+    Ok((hyper::Request::from_parts(parts, full(body)), Some(shadow)))
+}
+
+fn full(body: Bytes) -> BoxBody<Bytes, hyper::Error> {
+    Full::new(body).map_err(|e| match e {}).boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_evenly() {
+        let count = |percent| (0..1000).filter(|seq| sample_evenly(*seq, percent)).count();
+        assert_eq!(count(0), 0);
+        assert_eq!(count(10), 100);
+        assert_eq!(count(33), 330);
+        assert_eq!(count(100), 1000);
+    }
+
+    #[tokio::test]
+    async fn test_split() {
+        let req = |data: &'static str| hyper::Request::post("/").body(full(data.into())).unwrap();
+
+        let (served, shadow) = split(req("hello"), 16).await.unwrap();
+        let shadow = shadow.unwrap().into_body().collect().await.unwrap();
+        assert_eq!(shadow.to_bytes(), "hello");
+        let body = served.into_body().collect().await.unwrap();
+        assert_eq!(body.to_bytes(), "hello");
+
+        let (req, shadow) = split(req("hello"), 4).await.unwrap();
+        assert!(shadow.is_none());
+        let body = req.into_body().collect().await.unwrap();
+        assert_eq!(body.to_bytes(), "hello");
+    }
+
+    #[test]
+    fn test_metrics() {
+        let stats = MirrorStats::default();
+        stats.mirrored.fetch_add(2, Ordering::Relaxed);
+        stats.skipped.fetch_add(1, Ordering::Relaxed);
+
+        let mut metrics = String::new();
+        stats.write_metrics(&mut metrics);
+        assert!(metrics.contains("# TYPE runwasi_http_mirror_mirrored_total counter\n"));
+        assert!(metrics.contains("runwasi_http_mirror_mirrored_total 2\n"));
+        assert!(metrics.contains("runwasi_http_mirror_skipped_total 1\n"));
+        assert!(metrics.contains("runwasi_http_mirror_errors_total 0\n"));
+    }
+}
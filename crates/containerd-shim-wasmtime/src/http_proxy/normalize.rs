@@ -0,0 +1,175 @@
+//! Canonical normalization of the requests before they reach the guest.
+//!
+//! * `WASMTIME_HTTP_NORMALIZE_PATH`: when `true`, remove dot segments (including their
+//!   percent-encoded forms) and collapse duplicate slashes in the request path.
+//! * `WASMTIME_HTTP_LOWERCASE_HOST`: when `true`, lowercase the request host.
+//! * `WASMTIME_HTTP_DEFAULT_HOST`: the host of the requests without one, e.g. from HTTP/1.0
+//!   clients, which `wasi:http` would otherwise reject.
+//!
+//! This protects naive components routing on raw paths from path-traversal style confusion,
+//! e.g., `/public/../admin` is seen by the guest as `/admin`.
+
+use anyhow::Result;
+use hyper::header::{HeaderValue, HOST};
+use hyper::http::uri::{Authority, PathAndQuery};
+use hyper::{HeaderMap, Uri};
+
+use super::config::Settings;
+
+#[derive(Default)]
+pub struct Normalize {
+    /// Remove dot segments and collapse duplicate slashes in the request path.
+    pub path: bool,
+    /// Lowercase the request host.
+    pub lowercase_host: bool,
+    /// Host of the requests with neither an authority nor a `Host` header.
+    pub default_host: Option<HeaderValue>,
+}
+
+impl Normalize {
+    /// Read the normalization settings.
+    pub(crate) fn from_settings(settings: &Settings) -> Result<Self> {
+        Ok(Self {
+            path: settings.flag("normalize-path")?,
+            lowercase_host: settings.flag("lowercase-host")?,
+            default_host: settings.with_source("default-host", |host| {
+                let host: Authority = host.trim().parse()?;
+                Ok(HeaderValue::from_str(host.as_str())?)
+            })?,
+        })
+    }
+
+    pub fn apply(&self, uri: &mut Uri, headers: &mut HeaderMap) -> Result<()> {
+        if let Some(host) = &self.default_host {
+            if uri.authority().is_none() && !headers.contains_key(HOST) {
+                headers.insert(HOST, host.clone());
+            }
+        }
+
+        if !self.path && !self.lowercase_host {
+            return Ok(());
+        }
+
+        let mut parts = std::mem::take(uri).into_parts();
+
+        if self.path {
+            if let Some(pq) = &parts.path_and_query {
+                let path = normalize_path(pq.path());
+                let pq = match pq.query() {
+                    Some(query) => format!("{path}?{query}"),
+                    None => path,
+                };
+                parts.path_and_query = Some(PathAndQuery::try_from(pq)?);
+            }
+        }
+
+        if self.lowercase_host {
+            if let Some(authority) = &parts.authority {
+                parts.authority = Some(Authority::try_from(authority.as_str().to_lowercase())?);
+            }
+            if let Some(host) = headers.get(HOST).and_then(|h| h.to_str().ok()) {
+                let host = host.to_lowercase().try_into()?;
+                headers.insert(HOST, host);
+            }
+        }
+
+        *uri = Uri::from_parts(parts)?;
+        Ok(())
+    }
+}
+
+fn is_dot(segment: &str) -> bool {
+    segment == "." || segment.eq_ignore_ascii_case("%2e")
+}
+
+fn is_dot_dot(segment: &str) -> bool {
+    segment.len() <= 6 && segment.to_ascii_lowercase().replace("%2e", ".") == ".."
+}
+
+/// Remove dot segments ([RFC 3986, section 5.2.4]) and collapse duplicate slashes.
+///
+/// [RFC 3986, section 5.2.4]: https://www.rfc-editor.org/rfc/rfc3986#section-5.2.4
+fn normalize_path(path: &str) -> String {
+    let mut segments: Vec<&str> = vec![];
+    let mut trailing_slash = false;
+
+    for segment in path.split('/') {
+        trailing_slash = false;
+        match segment {
+            "" => trailing_slash = true,
+            s if is_dot(s) => trailing_slash = true,
+            s if is_dot_dot(s) => {
+                segments.pop();
+                trailing_slash = true;
+            }
+            s => segments.push(s),
+        }
+    }
+
+    let mut normalized = String::with_capacity(path.len());
+    for segment in &segments {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    if trailing_slash || segments.is_empty() {
+        normalized.push('/');
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize_path("/"), "/");
+        assert_eq!(normalize_path("/a/b"), "/a/b");
+        assert_eq!(normalize_path("/a/b/"), "/a/b/");
+        assert_eq!(normalize_path("//a///b"), "/a/b");
+        assert_eq!(normalize_path("/a/./b/../c"), "/a/c");
+        assert_eq!(normalize_path("/a/b/.."), "/a/");
+        assert_eq!(normalize_path("/../../etc/passwd"), "/etc/passwd");
+        assert_eq!(normalize_path("/public/%2e%2E/admin"), "/admin");
+        assert_eq!(normalize_path("/public/.%2e/admin"), "/admin");
+        assert_eq!(normalize_path("/a/...b/"), "/a/...b/");
+    }
+
+    #[test]
+    fn test_apply() -> Result<()> {
+        let normalize = Normalize {
+            path: true,
+            lowercase_host: true,
+            ..Default::default()
+        };
+
+        let mut uri: Uri = "http://Example.COM/a/../b//c?x=/../y".parse()?;
+        let mut headers = HeaderMap::new();
+        headers.insert(HOST, "Example.COM".parse()?);
+        normalize.apply(&mut uri, &mut headers)?;
+
+        assert_eq!(uri, "http://example.com/b/c?x=/../y");
+        assert_eq!(headers[HOST], "example.com");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_host() -> Result<()> {
+        let normalize = Normalize {
+            default_host: Some(HeaderValue::from_static("app.local:8080")),
+            ..Default::default()
+        };
+
+        let mut uri: Uri = "/a".parse()?;
+        let mut headers = HeaderMap::new();
+        normalize.apply(&mut uri, &mut headers)?;
+        assert_eq!(headers[HOST], "app.local:8080");
+
+        headers.insert(HOST, "example.com".parse()?);
+        normalize.apply(&mut uri, &mut headers)?;
+        assert_eq!(headers[HOST], "example.com");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,40 @@
+//! Health and readiness probes answered by the shim, e.g. for the probes of Kubernetes.
+//!
+//! `/healthz` answers `200 OK` as long as the proxy serves, and `/readyz` once a store of the
+//! component can be instantiated, trying in a fresh store on every probe, or else
+//! `503 Service Unavailable` with the error. They are served on the admin endpoint, and on the
+//! proxy itself with `WASMTIME_HTTP_PROBE_PATHS` set to `true`, in front of the middleware, so
+//! that the probes neither reach the guest nor need to be authenticated.
+
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::StatusCode;
+use wasmtime_wasi_http::body::HyperOutgoingBody;
+
+use super::ProxyHandler;
+
+const HEALTHZ: &str = "/healthz";
+const READYZ: &str = "/readyz";
+
+/// The status and body of the probe of `path`, or `None` if it isn't a probe.
+pub(crate) async fn answer(handler: &ProxyHandler, path: &str) -> Option<(StatusCode, String)> {
+    match path {
+        HEALTHZ => Some((StatusCode::OK, "ok".into())),
+        READYZ => Some(match handler.ready().await {
+            Ok(()) => (StatusCode::OK, "ok".into()),
+            Err(e) => {
+                tracing::warn!("not ready: {e:#}");
+                (StatusCode::SERVICE_UNAVAILABLE, format!("{e:#}"))
+            }
+        }),
+        _ => None,
+    }
+}
+
+/// The response of a probe answered by the proxy.
+pub(crate) fn response(status: StatusCode, body: String) -> hyper::Response<HyperOutgoingBody> {
+    let body = Full::new(Bytes::from(body)).map_err(|e| match e {});
+    let mut resp = hyper::Response::new(body.boxed());
+    *resp.status_mut() = status;
+    resp
+}
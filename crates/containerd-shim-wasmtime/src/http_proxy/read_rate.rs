@@ -0,0 +1,331 @@
+//! Minimum read rate of the HTTP/1.1 connections, against slowloris attacks.
+//!
+//! A client sending its requests a few bytes at a time holds a connection, and a slot of the
+//! component, for as long as it likes. With `WASMTIME_HTTP_MIN_READ_RATE` set to a number of bytes
+//! per second, the connections whose requests, headers and body, are received slower than that are
+//! closed, once a grace period of `WASMTIME_HTTP_READ_GRACE_PERIOD` seconds (default: 10).
+//!
+//! Only the time the proxy waits for the client counts, not the time the guest takes to read the
+//! body or to respond, so the connections waiting for their next request are closed after the
+//! grace period as well. HTTP/2 connections are not checked, as they are idle between their
+//! streams by design.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use anyhow::{ensure, Result};
+use hyper::body::{Body, Frame, SizeHint};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+use super::config::Settings;
+
+const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// The minimum rate the requests are received at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReadRate {
+    /// Bytes per second.
+    pub min_rate: u64,
+    /// Time the clients are given before their rate is enforced.
+    pub grace_period: Duration,
+}
+
+impl ReadRate {
+    pub(crate) fn from_settings(settings: &Settings) -> Result<Option<Self>> {
+        let Some(min_rate) = settings.parse::<u64>("min-read-rate")? else {
+            return Ok(None);
+        };
+        ensure!(
+            min_rate > 0,
+            "{} must be positive",
+            settings.source("min-read-rate")
+        );
+        let grace_period = settings
+            .parse("read-grace-period")?
+            .map_or(DEFAULT_GRACE_PERIOD, Duration::from_secs);
+        Ok(Some(Self {
+            min_rate,
+            grace_period,
+        }))
+    }
+
+    /// The time a client sending `bytes` may have been waited for.
+    fn budget(&self, bytes: u64) -> Duration {
+        self.grace_period + Duration::from_secs_f64(bytes as f64 / self.min_rate as f64)
+    }
+}
+
+#[derive(Default)]
+struct WatchState {
+    paused: bool,
+    /// Incremented whenever the reads are resumed, for a new request.
+    generation: u64,
+    /// The reader of a pending read, woken when the reads are resumed.
+    waker: Option<Waker>,
+}
+
+/// Handle to the read rate check of a connection, in the extensions of its requests.
+#[derive(Clone, Default)]
+pub(crate) struct ReadWatch(Arc<Mutex<WatchState>>);
+
+impl ReadWatch {
+    /// Stop counting the time waiting for the client, once its request is read.
+    pub fn pause(&self) {
+        self.0.lock().unwrap().paused = true;
+    }
+
+    /// Count the time waiting for the client again, for its next request.
+    pub fn resume(&self) {
+        let mut state = self.0.lock().unwrap();
+        if state.paused {
+            state.paused = false;
+            state.generation += 1;
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    /// The generation of the reads, unless paused, in which case `waker` is woken on resume.
+    fn generation(&self, waker: &Waker) -> Option<u64> {
+        let mut state = self.0.lock().unwrap();
+        if state.paused {
+            state.waker = Some(waker.clone());
+            return None;
+        }
+        Some(state.generation)
+    }
+
+    /// Wrap a request body, pausing the reads once it is received, or dropped.
+    pub fn request_body<B>(&self, body: B) -> Watched<B> {
+        Watched::new(body, self.clone(), ReadWatch::pause)
+    }
+
+    /// Wrap a response body, resuming the reads once it is sent, or dropped.
+    pub fn response_body<B>(&self, body: B) -> Watched<B> {
+        Watched::new(body, self.clone(), ReadWatch::resume)
+    }
+}
+
+/// A body calling `on_end` on the watch of its connection once it ends, or is dropped.
+pub(crate) struct Watched<B> {
+    body: B,
+    watch: Option<ReadWatch>,
+    on_end: fn(&ReadWatch),
+}
+
+impl<B> Watched<B> {
+    fn new(body: B, watch: ReadWatch, on_end: fn(&ReadWatch)) -> Self {
+        Self {
+            body,
+            watch: Some(watch),
+            on_end,
+        }
+    }
+
+    fn end(&mut self) {
+        if let Some(watch) = self.watch.take() {
+            (self.on_end)(&watch);
+        }
+    }
+}
+
+impl<B> Drop for Watched<B> {
+    fn drop(&mut self) {
+        self.end();
+    }
+}
+
+impl<B: Body + Unpin> Body for Watched<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let frame = Pin::new(&mut self.body).poll_frame(cx);
+        match &frame {
+            Poll::Ready(Some(Ok(_))) if !self.body.is_end_stream() => {}
+            Poll::Ready(_) => self.end(),
+            Poll::Pending => {}
+        }
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+/// A stream failing its reads when the client sends slower than the minimum rate.
+pub(crate) struct MinReadRate<S> {
+    stream: S,
+    rate: ReadRate,
+    watch: ReadWatch,
+    generation: u64,
+    /// Bytes received, and time waited for them, since the reads were last resumed.
+    bytes: u64,
+    waited: Duration,
+    /// Start of the pending read, if any.
+    pending_since: Option<Instant>,
+    timer: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> MinReadRate<S> {
+    pub fn new(stream: S, rate: ReadRate) -> (Self, ReadWatch) {
+        let watch = ReadWatch::default();
+        let stream = Self {
+            stream,
+            rate,
+            watch: watch.clone(),
+            generation: 0,
+            bytes: 0,
+            waited: Duration::ZERO,
+            pending_since: None,
+            timer: None,
+        };
+        (stream, watch)
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MinReadRate<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.stream).poll_read(cx, buf);
+        let now = Instant::now();
+
+        let Some(generation) = self.watch.generation(cx.waker()) else {
+            self.pending_since = None;
+            self.timer = None;
+            return poll;
+        };
+        if generation != self.generation {
+            self.generation = generation;
+            self.bytes = 0;
+            self.waited = Duration::ZERO;
+            self.pending_since = None;
+        }
+
+        if poll.is_ready() {
+            if let Some(since) = self.pending_since.take() {
+                self.waited += now - since;
+            }
+            self.bytes += (buf.filled().len() - filled) as u64;
+            self.timer = None;
+            return poll;
+        }
+
+        let since = *self.pending_since.get_or_insert(now);
+        let waited = self.waited + (now - since);
+        let budget = self.rate.budget(self.bytes);
+        if waited < budget {
+            let deadline = now + (budget - waited);
+            let timer = self
+                .timer
+                .get_or_insert_with(|| Box::pin(tokio::time::sleep_until(deadline)));
+            timer.as_mut().reset(deadline);
+            if timer.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+        Poll::Ready(Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!(
+                "client sent {} bytes in {waited:?}, below the minimum rate",
+                self.bytes
+            ),
+        )))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MinReadRate<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    const RATE: ReadRate = ReadRate {
+        min_rate: 1000,
+        grace_period: Duration::from_millis(50),
+    };
+
+    #[tokio::test]
+    async fn test_min_read_rate() -> Result<()> {
+        let (mut client, server) = tokio::io::duplex(64);
+        let (mut stream, watch) = MinReadRate::new(server, RATE);
+        let mut buf = [0; 64];
+
+        client.write_all(b"GET / HTTP/1.1\r\n").await?;
+        assert_eq!(stream.read(&mut buf).await?, 16);
+
+        // The time the guest takes isn't counted
+        watch.pause();
+        let read = tokio::time::timeout(Duration::from_millis(200), stream.read(&mut buf)).await;
+        assert!(read.is_err());
+
+        // A client sending too slowly is cut off
+        watch.resume();
+        let err = stream.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_watched_body() -> Result<()> {
+        use http_body_util::{BodyExt, Full};
+
+        let watch = ReadWatch::default();
+        let body = watch.request_body(Full::new(bytes::Bytes::from("body")));
+        assert!(!watch.0.lock().unwrap().paused);
+        body.collect().await?;
+        assert!(watch.0.lock().unwrap().paused);
+
+        drop(watch.response_body(Full::new(bytes::Bytes::new())));
+        assert!(!watch.0.lock().unwrap().paused);
+        Ok(())
+    }
+}
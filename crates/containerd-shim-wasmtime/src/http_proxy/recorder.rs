@@ -0,0 +1,413 @@
+//! Record the requests handled by the guest, and their responses, to reproduce failures locally.
+//!
+//! The recorder is enabled by setting `WASMTIME_HTTP_RECORD_SIZE` to the number of exchanges to
+//! keep. The most recent exchanges are kept in memory, and downloaded as JSON from the admin
+//! endpoint (`WASMTIME_HTTP_ADMIN_SOCKET_ADDR`):
+//! * `GET /recordings` returns the recorded exchanges, oldest first.
+//! * `DELETE /recordings` discards them.
+//!
+//! * `WASMTIME_HTTP_RECORD_MAX_BODY_SIZE`: maximum size in bytes of a recorded request or response
+//!   body (default: 64 KiB). Longer bodies are truncated, and marked as such.
+//! * `WASMTIME_HTTP_RECORD_REDACT_HEADERS`: comma separated list of headers whose values are
+//!   redacted, in addition to `Authorization`, `Proxy-Authorization`, `Cookie` and `Set-Cookie`.
+//!
+//! An exchange is recorded once the guest is done with it, including when the guest fails, in
+//! which case the error is recorded instead of the response. Requests answered by the proxy
+//! itself, e.g. static files or cached responses, are not recorded.
+
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use base64::Engine as _;
+use bytes::Bytes;
+use http_body_util::{BodyExt, Empty};
+use hyper::body::{Body, Frame, SizeHint};
+use hyper::header::{self, HeaderMap, HeaderName};
+use hyper::{Method, StatusCode};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use wasmtime_wasi_http::body::HyperOutgoingBody;
+
+use super::config::Settings;
+use super::mirror::GuestRequest;
+
+const DEFAULT_MAX_BODY_SIZE: usize = 64 * 1024;
+
+const REDACTED: &str = "[redacted]";
+
+/// Configuration of the recorder.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecorderConfig {
+    /// Number of exchanges kept.
+    pub size: usize,
+    /// Maximum size of a recorded body, in bytes.
+    pub max_body_size: usize,
+    /// Headers whose values are redacted.
+    pub redact_headers: Vec<HeaderName>,
+}
+
+impl RecorderConfig {
+    /// Read the recorder settings.
+    /// Returns `None` if the recorder is not enabled.
+    pub(crate) fn from_settings(settings: &Settings) -> Result<Option<Self>> {
+        let max_body_size = settings
+            .parse("record-max-body-size")?
+            .unwrap_or(DEFAULT_MAX_BODY_SIZE);
+        let mut redact_headers = vec![
+            header::AUTHORIZATION,
+            header::PROXY_AUTHORIZATION,
+            header::COOKIE,
+            header::SET_COOKIE,
+        ];
+        let extra = settings.with_source("record-redact-headers", |value| {
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(|name| Ok(HeaderName::try_from(name)?))
+                .collect::<Result<Vec<_>>>()
+        })?;
+        redact_headers.extend(extra.unwrap_or_default());
+
+        let size = settings.parse("record-size")?;
+        Ok(size.filter(|size| *size > 0).map(|size| Self {
+            size,
+            max_body_size,
+            redact_headers,
+        }))
+    }
+}
+
+/// A request handled by the guest, and its response.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Exchange {
+    /// The `REQUEST_ID` of the request.
+    pub id: u64,
+    /// When the request was received, in milliseconds since the Unix epoch.
+    pub timestamp_ms: u64,
+    pub method: String,
+    pub uri: String,
+    pub request_headers: Vec<(String, String)>,
+    #[serde(with = "base64_body")]
+    pub request_body: Vec<u8>,
+    pub request_body_truncated: bool,
+    pub status: Option<u16>,
+    pub response_headers: Vec<(String, String)>,
+    #[serde(with = "base64_body")]
+    pub response_body: Vec<u8>,
+    pub response_body_truncated: bool,
+    /// The error of the guest, if it failed to respond.
+    pub error: Option<String>,
+}
+
+/// Bodies are base64 encoded, as they may not be valid UTF-8.
+mod base64_body {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(body: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        base64::engine::general_purpose::STANDARD
+            .encode(body)
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let body = String::deserialize(deserializer)?;
+        base64::engine::general_purpose::STANDARD
+            .decode(body)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+struct Shared {
+    config: RecorderConfig,
+    exchanges: Mutex<VecDeque<Exchange>>,
+}
+
+impl Shared {
+    fn push(&self, exchange: Exchange) {
+        let mut exchanges = self.exchanges.lock().unwrap();
+        if exchanges.len() >= self.config.size {
+            exchanges.pop_front();
+        }
+        exchanges.push_back(exchange);
+    }
+
+    fn redact(&self, headers: &HeaderMap) -> Vec<(String, String)> {
+        headers
+            .iter()
+            .map(|(name, value)| {
+                let value = if self.config.redact_headers.contains(name) {
+                    REDACTED.to_string()
+                } else {
+                    String::from_utf8_lossy(value.as_bytes()).into_owned()
+                };
+                (name.to_string(), value)
+            })
+            .collect()
+    }
+}
+
+/// Recorder of the exchanges of the guest.
+pub(crate) struct Recorder {
+    shared: Arc<Shared>,
+}
+
+impl Recorder {
+    pub fn new(config: RecorderConfig) -> Self {
+        tracing::info!("recording the last {} requests", config.size);
+        Self {
+            shared: Arc::new(Shared {
+                config,
+                exchanges: Default::default(),
+            }),
+        }
+    }
+
+    /// Start recording `req`, wrapping its body to record it as the guest reads it.
+    pub fn start(&self, id: u64, req: GuestRequest) -> (GuestRequest, Arc<Recording>) {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let recording = Arc::new(Recording {
+            shared: self.shared.clone(),
+            exchange: Mutex::new(Exchange {
+                id,
+                timestamp_ms,
+                method: req.method().to_string(),
+                uri: req.uri().to_string(),
+                request_headers: self.shared.redact(req.headers()),
+                ..Default::default()
+            }),
+        });
+
+        let req = req.map(|inner| {
+            RecordingBody {
+                inner,
+                recording: recording.clone(),
+                side: Side::Request,
+            }
+            .boxed()
+        });
+        (req, recording)
+    }
+
+    /// The recorded exchanges, oldest first.
+    pub fn exchanges(&self) -> Vec<Exchange> {
+        self.shared
+            .exchanges
+            .lock()
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Answer a request to `/recordings` on the admin endpoint.
+    pub fn handle_admin_request(&self, method: &Method) -> (StatusCode, String) {
+        match *method {
+            Method::GET => match serde_json::to_string(&self.exchanges()) {
+                Ok(json) => (StatusCode::OK, json),
+                Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{e}\n")),
+            },
+            Method::DELETE => {
+                self.shared.exchanges.lock().unwrap().clear();
+                (StatusCode::NO_CONTENT, String::new())
+            }
+            _ => (StatusCode::METHOD_NOT_ALLOWED, String::new()),
+        }
+    }
+}
+
+/// An exchange being recorded. It is added to the recorder once both bodies are dropped.
+pub(crate) struct Recording {
+    shared: Arc<Shared>,
+    exchange: Mutex<Exchange>,
+}
+
+impl Recording {
+    /// Record the response, wrapping its body to record it as it's sent.
+    pub fn response(self: Arc<Self>, resp: &mut hyper::Response<HyperOutgoingBody>) {
+        {
+            let mut exchange = self.exchange.lock().unwrap();
+            exchange.status = Some(resp.status().as_u16());
+            exchange.response_headers = self.shared.redact(resp.headers());
+        }
+
+        let inner = std::mem::replace(
+            resp.body_mut(),
+            Empty::new().map_err(|e| match e {}).boxed(),
+        );
+        *resp.body_mut() = RecordingBody {
+            inner,
+            recording: self,
+            side: Side::Response,
+        }
+        .boxed();
+    }
+
+    /// Record the failure of the guest.
+    pub fn error(&self, error: &anyhow::Error) {
+        self.exchange.lock().unwrap().error = Some(format!("{error:?}"));
+    }
+
+    fn append(&self, side: Side, data: &[u8]) {
+        let max = self.shared.config.max_body_size;
+        let mut exchange = self.exchange.lock().unwrap();
+        let exchange = &mut *exchange;
+        let (body, truncated) = match side {
+            Side::Request => (
+                &mut exchange.request_body,
+                &mut exchange.request_body_truncated,
+            ),
+            Side::Response => (
+                &mut exchange.response_body,
+                &mut exchange.response_body_truncated,
+            ),
+        };
+        let room = max.saturating_sub(body.len());
+        if data.len() > room {
+            *truncated = true;
+        }
+        body.extend_from_slice(&data[..data.len().min(room)]);
+    }
+}
+
+impl Drop for Recording {
+    fn drop(&mut self) {
+        let exchange = std::mem::take(self.exchange.get_mut().unwrap());
+        self.shared.push(exchange);
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Side {
+    Request,
+    Response,
+}
+
+/// Body recording the data it yields.
+struct RecordingBody<B> {
+    inner: B,
+    recording: Arc<Recording>,
+    side: Side,
+}
+
+impl<B> Body for RecordingBody<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, B::Error>>> {
+        let frame = ready!(Pin::new(&mut self.inner).poll_frame(cx));
+        if let Some(data) = frame.as_ref().and_then(|f| f.as_ref().ok()?.data_ref()) {
+            self.recording.append(self.side, data);
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::Full;
+
+    use super::*;
+
+    fn recorder(size: usize, max_body_size: usize) -> Recorder {
+        let settings = Settings::from_env([
+            ("WASMTIME_HTTP_RECORD_SIZE", size.to_string().as_str()),
+            (
+                "WASMTIME_HTTP_RECORD_MAX_BODY_SIZE",
+                max_body_size.to_string().as_str(),
+            ),
+            ("WASMTIME_HTTP_RECORD_REDACT_HEADERS", "x-api-key"),
+        ]);
+        Recorder::new(RecorderConfig::from_settings(&settings).unwrap().unwrap())
+    }
+
+    fn request(body: &'static str) -> GuestRequest {
+        hyper::Request::post("http://localhost/echo")
+            .header("authorization", "Bearer secret")
+            .header("x-api-key", "secret")
+            .header("x-tenant", "acme")
+            .body(Full::new(Bytes::from(body)).map_err(|e| match e {}).boxed())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_record() -> Result<()> {
+        let recorder = recorder(2, 4);
+
+        let (req, recording) = recorder.start(1, request("hello"));
+        let body = req.into_body().collect().await?.to_bytes();
+        // The guest reads the whole body, only the recording is truncated
+        assert_eq!(body, "hello");
+
+        let mut resp =
+            hyper::Response::new(Full::new(Bytes::from("ok")).map_err(|e| match e {}).boxed());
+        recording.response(&mut resp);
+        // Not recorded until the response is sent
+        assert!(recorder.exchanges().is_empty());
+        resp.into_body().collect().await?;
+
+        let exchanges = recorder.exchanges();
+        assert_eq!(exchanges.len(), 1);
+        let exchange = &exchanges[0];
+        assert_eq!(exchange.method, "POST");
+        assert_eq!(exchange.request_body, b"hell");
+        assert!(exchange.request_body_truncated);
+        assert_eq!(exchange.status, Some(200));
+        assert_eq!(exchange.response_body, b"ok");
+        assert!(!exchange.response_body_truncated);
+        let header = |name: &str| {
+            exchange
+                .request_headers
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.as_str())
+        };
+        assert_eq!(header("authorization"), Some(REDACTED));
+        assert_eq!(header("x-api-key"), Some(REDACTED));
+        assert_eq!(header("x-tenant"), Some("acme"));
+
+        // Round-trips through JSON
+        let json = serde_json::to_string(&exchanges)?;
+        let parsed: Vec<Exchange> = serde_json::from_str(&json)?;
+        assert_eq!(parsed, exchanges);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ring_buffer() -> Result<()> {
+        let recorder = recorder(2, 1024);
+        for id in 0..3 {
+            let (_, recording) = recorder.start(id, request(""));
+            recording.error(&anyhow::anyhow!("trap"));
+        }
+
+        let exchanges = recorder.exchanges();
+        let ids: Vec<_> = exchanges.iter().map(|e| e.id).collect();
+        assert_eq!(ids, [1, 2]);
+        assert!(exchanges[0].error.as_deref().unwrap().contains("trap"));
+        assert_eq!(exchanges[0].status, None);
+
+        Ok(())
+    }
+}
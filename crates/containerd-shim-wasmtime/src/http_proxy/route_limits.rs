@@ -0,0 +1,143 @@
+//! Limits of the requests overridden for some paths.
+//!
+//! An upload endpoint may need larger bodies and a longer timeout than the rest of the service,
+//! and a costly report fewer concurrent requests. `WASMTIME_HTTP_ROUTE_LIMITS` is a semicolon
+//! separated list of `prefix:setting=value,...` routes, e.g.
+//! `/upload:max-request-body-size=104857600,request-timeout=300;/reports:max-concurrency=2`,
+//! where the settings are:
+//! * `request-timeout`: overrides `WASMTIME_HTTP_REQUEST_TIMEOUT`, in seconds,
+//! * `max-request-body-size`: overrides `WASMTIME_HTTP_MAX_REQUEST_BODY_SIZE`, in bytes,
+//! * `max-concurrency`: the requests of the route handled concurrently, in addition to the limit
+//!   of all the requests. The requests beyond wait in their own queue.
+//!
+//! The requests whose path starts with several prefixes get the limits of the longest one.
+
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Context, Result};
+
+use super::concurrency::ConcurrencyLimit;
+use super::config::Settings;
+
+/// The limits of the requests of a path prefix.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RouteLimit {
+    pub prefix: String,
+    pub request_timeout: Option<Duration>,
+    pub max_request_body_size: Option<u64>,
+    pub max_concurrency: Option<usize>,
+}
+
+/// The routes overriding the limits of their requests, longest prefixes first.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RouteLimits(Vec<RouteLimit>);
+
+impl RouteLimits {
+    /// Read the route limits.
+    pub(crate) fn from_settings(settings: &Settings) -> Result<Self> {
+        Ok(settings
+            .with_source("route-limits", Self::parse)?
+            .unwrap_or_default())
+    }
+
+    /// Parse a semicolon separated list of `prefix:setting=value,...` routes.
+    pub fn parse(value: &str) -> Result<Self> {
+        let mut routes = vec![];
+        for route in value.split(';').map(str::trim).filter(|r| !r.is_empty()) {
+            let Some((prefix, settings)) = route.split_once(':') else {
+                bail!("invalid route {route:?}, expected `prefix:setting=value,...`");
+            };
+            let prefix = prefix.trim();
+            ensure!(prefix.starts_with('/'), "invalid path prefix {prefix:?}");
+            let mut limit = RouteLimit {
+                prefix: prefix.to_string(),
+                ..Default::default()
+            };
+            for setting in settings.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+                let Some((key, value)) = setting.split_once('=') else {
+                    bail!("invalid setting {setting:?} of {prefix:?}, expected `setting=value`");
+                };
+                let (key, value) = (key.trim(), value.trim());
+                let context = || format!("invalid {key} {value:?} of {prefix:?}");
+                match key {
+                    "request-timeout" => {
+                        let secs = value.parse().with_context(context)?;
+                        limit.request_timeout = Some(Duration::from_secs(secs));
+                    }
+                    "max-request-body-size" => {
+                        limit.max_request_body_size = Some(value.parse().with_context(context)?);
+                    }
+                    "max-concurrency" => {
+                        let max = value.parse().with_context(context)?;
+                        ensure!(max > 0, "max-concurrency of {prefix:?} must be positive");
+                        limit.max_concurrency = Some(max);
+                    }
+                    _ => bail!("unknown setting {key:?} of {prefix:?}"),
+                }
+            }
+            routes.push(limit);
+        }
+        routes.sort_by(|a, b| b.prefix.len().cmp(&a.prefix.len()));
+        Ok(Self(routes))
+    }
+}
+
+/// A route of the handler, with the request slots of its concurrency limit.
+pub(crate) struct Route {
+    pub limit: RouteLimit,
+    pub concurrency: Option<ConcurrencyLimit>,
+}
+
+/// The routes of the handler.
+pub(crate) struct Routes(Vec<Route>);
+
+impl Routes {
+    pub fn new(limits: &RouteLimits, max_queue: Option<usize>) -> Self {
+        let routes = limits.0.iter().map(|limit| Route {
+            limit: limit.clone(),
+            concurrency: limit
+                .max_concurrency
+                .map(|max| ConcurrencyLimit::new(max, max_queue)),
+        });
+        Self(routes.collect())
+    }
+
+    /// The route of the requests to `path`, if any.
+    pub fn find(&self, path: &str) -> Option<&Route> {
+        self.0
+            .iter()
+            .find(|route| path.starts_with(&route.limit.prefix))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route_limits() -> Result<()> {
+        let limits = RouteLimits::parse(
+            "/upload: max-request-body-size=104857600, request-timeout=300; \
+             /upload/small:max-request-body-size=1024;/reports:max-concurrency=2",
+        )?;
+        let routes = Routes::new(&limits, None);
+
+        let route = routes.find("/upload/small/a.png").unwrap();
+        assert_eq!(route.limit.max_request_body_size, Some(1024));
+        assert_eq!(route.limit.request_timeout, None);
+
+        let route = routes.find("/upload/a.png").unwrap();
+        assert_eq!(route.limit.max_request_body_size, Some(104857600));
+        assert_eq!(route.limit.request_timeout, Some(Duration::from_secs(300)));
+        assert!(route.concurrency.is_none());
+
+        assert!(routes.find("/reports/2024").unwrap().concurrency.is_some());
+        assert!(routes.find("/").is_none());
+
+        assert!(RouteLimits::parse("/upload").is_err());
+        assert!(RouteLimits::parse("upload:request-timeout=1").is_err());
+        assert!(RouteLimits::parse("/upload:timeout=1").is_err());
+        assert!(RouteLimits::parse("/upload:max-concurrency=0").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,398 @@
+//! KEDA external scaler of the proxy.
+//!
+//! With `WASMTIME_HTTP_SCALER_SOCKET_ADDR` set, the proxy serves the `externalscaler.ExternalScaler`
+//! gRPC service of [KEDA](https://keda.sh/docs/latest/concepts/external-scalers/) on that address,
+//! so that wasi-http workloads are scaled on their requests without a service mesh in front of
+//! them. The metric is the load of the container: the requests in flight in the guest, plus the
+//! ones waiting for a slot when their concurrency is limited. KEDA keeps the load of the replicas
+//! around the target, the `targetSize` in the metadata of the trigger, or else
+//! `WASMTIME_HTTP_SCALER_TARGET` (default: 100).
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, StreamBody};
+use hyper::body::{Frame, Incoming};
+use hyper::server::conn::http2;
+use prost::Message;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use wasmtime_wasi_http::io::TokioIo;
+
+use super::concurrency::ConcurrencyLimit;
+use super::grpc::{
+    frame_message, grpc_trailers, MessageReader, DEFAULT_MAX_MESSAGE_SIZE, GRPC_OK,
+    GRPC_RESOURCE_EXHAUSTED, GRPC_UNIMPLEMENTED,
+};
+use super::{bind_listener, tcp_accept, Request, TrackedExecutor, DEFAULT_BACKLOG};
+
+const IS_ACTIVE: &str = "/externalscaler.ExternalScaler/IsActive";
+const STREAM_IS_ACTIVE: &str = "/externalscaler.ExternalScaler/StreamIsActive";
+const GET_METRIC_SPEC: &str = "/externalscaler.ExternalScaler/GetMetricSpec";
+const GET_METRICS: &str = "/externalscaler.ExternalScaler/GetMetrics";
+
+const GRPC_INVALID_ARGUMENT: u32 = 3;
+
+/// The name of the metric, unless KEDA asks for another one.
+const METRIC_NAME: &str = "runwasi-http-requests";
+pub const DEFAULT_TARGET: u64 = 100;
+
+/// Interval at which the activity is checked for `StreamIsActive`.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+type Body = BoxBody<Bytes, Infallible>;
+type Sender = mpsc::Sender<Result<Frame<Bytes>, Infallible>>;
+
+// The messages of `externalscaler.ExternalScaler`
+
+#[derive(Clone, PartialEq, Message)]
+struct ScaledObjectRef {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    namespace: String,
+    #[prost(map = "string, string", tag = "3")]
+    scaler_metadata: HashMap<String, String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct IsActiveResponse {
+    #[prost(bool, tag = "1")]
+    result: bool,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct GetMetricSpecResponse {
+    #[prost(message, repeated, tag = "1")]
+    metric_specs: Vec<MetricSpec>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct MetricSpec {
+    #[prost(string, tag = "1")]
+    metric_name: String,
+    #[prost(int64, tag = "2")]
+    target_size: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct GetMetricsRequest {
+    #[prost(message, optional, tag = "1")]
+    scaled_object_ref: Option<ScaledObjectRef>,
+    #[prost(string, tag = "2")]
+    metric_name: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct GetMetricsResponse {
+    #[prost(message, repeated, tag = "1")]
+    metric_values: Vec<MetricValue>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct MetricValue {
+    #[prost(string, tag = "1")]
+    metric_name: String,
+    #[prost(int64, tag = "2")]
+    metric_value: i64,
+}
+
+pub(crate) struct Scaler {
+    in_flight: Arc<AtomicU64>,
+    concurrency: Option<Arc<ConcurrencyLimit>>,
+    target: u64,
+}
+
+impl Scaler {
+    /// Report the requests counted by `in_flight` and queued by `concurrency`, against `target`
+    /// requests per replica.
+    pub fn new(
+        in_flight: Arc<AtomicU64>,
+        concurrency: Option<Arc<ConcurrencyLimit>>,
+        target: u64,
+    ) -> Self {
+        Self {
+            in_flight,
+            concurrency,
+            target,
+        }
+    }
+
+    /// The requests in flight and waiting for a slot.
+    fn load(&self) -> u64 {
+        let queued = self.concurrency.as_ref().map_or(0, |c| c.queue_depth());
+        self.in_flight.load(Ordering::Relaxed) + queued
+    }
+
+    /// The `GetMetricSpecResponse` to the `ScaledObjectRef` in `req`.
+    fn metric_spec(&self, req: &[u8]) -> Result<Vec<u8>> {
+        let req = ScaledObjectRef::decode(req)?;
+        let target = match req.scaler_metadata.get("targetSize") {
+            Some(target) => target
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid targetSize {target:?}"))?,
+            None => self.target,
+        };
+        let resp = GetMetricSpecResponse {
+            metric_specs: vec![MetricSpec {
+                metric_name: METRIC_NAME.into(),
+                target_size: i64::try_from(target).unwrap_or(i64::MAX),
+            }],
+        };
+        Ok(resp.encode_to_vec())
+    }
+
+    /// The `GetMetricsResponse` to the `GetMetricsRequest` in `req`.
+    fn metrics(&self, req: &[u8]) -> Result<Vec<u8>> {
+        let mut metric_name = GetMetricsRequest::decode(req)?.metric_name;
+        if metric_name.is_empty() {
+            metric_name = METRIC_NAME.into();
+        }
+        let resp = GetMetricsResponse {
+            metric_values: vec![MetricValue {
+                metric_name,
+                metric_value: i64::try_from(self.load()).unwrap_or(i64::MAX),
+            }],
+        };
+        Ok(resp.encode_to_vec())
+    }
+
+    fn handle_request(
+        self: Arc<Self>,
+        req: Request,
+        tracker: &TaskTracker,
+        cancel: CancellationToken,
+    ) -> hyper::Response<Body> {
+        let (tx, rx) = mpsc::channel(4);
+        let path = req.uri().path().to_string();
+
+        tracker.spawn(async move {
+            let status = match self.call(&path, req.into_body(), &tx, cancel).await {
+                Ok(status) => status,
+                Err(e) => {
+                    tracing::debug!("invalid scaler request to {path}: {e:#}");
+                    GRPC_INVALID_ARGUMENT
+                }
+            };
+            let _ = tx.send(Ok(Frame::trailers(grpc_trailers(status)))).await;
+        });
+
+        let body = StreamBody::new(ReceiverStream::new(rx));
+        hyper::Response::builder()
+            .header(hyper::header::CONTENT_TYPE, "application/grpc")
+            .body(body.boxed())
+            .expect("valid response")
+    }
+
+    /// Answer the call to `path`, returning its gRPC status.
+    async fn call(
+        &self,
+        path: &str,
+        mut body: Incoming,
+        tx: &Sender,
+        cancel: CancellationToken,
+    ) -> Result<u32> {
+        // The calls are unary, or server streaming, so only the first message is read
+        let mut reader = MessageReader::new(DEFAULT_MAX_MESSAGE_SIZE);
+        let req = loop {
+            let frame = body.frame().await.context("missing request message")??;
+            let Ok(data) = frame.into_data() else {
+                continue;
+            };
+            reader.push(&data);
+            match reader.next_message() {
+                Ok(Some(req)) => break req,
+                Ok(None) => {}
+                Err(e) => {
+                    tracing::debug!("invalid scaler request to {path}: {e}");
+                    return Ok(GRPC_RESOURCE_EXHAUSTED);
+                }
+            }
+        };
+
+        let resp = match path {
+            IS_ACTIVE => is_active(self.load() > 0),
+            GET_METRIC_SPEC => self.metric_spec(&req)?,
+            GET_METRICS => self.metrics(&req)?,
+            STREAM_IS_ACTIVE => {
+                self.stream_is_active(tx, cancel).await;
+                return Ok(GRPC_OK);
+            }
+            _ => return Ok(GRPC_UNIMPLEMENTED),
+        };
+        let _ = tx.send(Ok(Frame::data(frame_message(&resp)))).await;
+        Ok(GRPC_OK)
+    }
+
+    /// Send the activity of the container whenever it changes, until the client goes away or
+    /// `cancel` is cancelled.
+    async fn stream_is_active(&self, tx: &Sender, cancel: CancellationToken) {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        let mut last = None;
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = tx.closed() => return,
+                _ = cancel.cancelled() => return,
+            }
+            let active = self.load() > 0;
+            if last == Some(active) {
+                continue;
+            }
+            last = Some(active);
+            let msg = frame_message(&is_active(active));
+            if tx.send(Ok(Frame::data(msg))).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// The `IsActiveResponse`.
+fn is_active(active: bool) -> Vec<u8> {
+    IsActiveResponse { result: active }.encode_to_vec()
+}
+
+/// Serve the scaler until `cancel` is triggered.
+pub(crate) async fn serve_scaler(
+    addr: SocketAddr,
+    scaler: Arc<Scaler>,
+    cancel: CancellationToken,
+) -> Result<()> {
+    let listener = bind_listener(addr, DEFAULT_BACKLOG)?;
+    let tracker = TaskTracker::new();
+
+    tracing::info!("Serving KEDA external scaler on {}", listener.local_addr()?);
+
+    loop {
+        let stream = tokio::select! {
+            conn = tcp_accept(&listener) => {
+                match conn {
+                    Some(conn) => conn,
+                    None => continue,
+                }
+            }
+            _ = cancel.cancelled() => {
+                break;
+            }
+        };
+
+        let scaler = scaler.clone();
+        let cancel = cancel.clone();
+        let executor = TrackedExecutor(tracker.clone());
+        let calls = tracker.clone();
+        tracker.spawn(async move {
+            let service = hyper::service::service_fn({
+                let cancel = cancel.clone();
+                move |req| {
+                    let resp = scaler.clone().handle_request(req, &calls, cancel.clone());
+                    async move { anyhow::Ok(resp) }
+                }
+            });
+            // KEDA keeps its connection open, which is closed once its calls complete
+            let conn =
+                http2::Builder::new(executor).serve_connection(TokioIo::new(stream), service);
+            tokio::pin!(conn);
+            let result = tokio::select! {
+                result = conn.as_mut() => result,
+                _ = cancel.cancelled() => {
+                    conn.as_mut().graceful_shutdown();
+                    conn.await
+                }
+            };
+            if let Err(e) = result {
+                tracing::error!("scaler error: {e:?}");
+            }
+        });
+    }
+
+    tracker.close();
+    tracker.wait().await;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scaled_object_ref(metadata: &[(&str, &str)]) -> ScaledObjectRef {
+        ScaledObjectRef {
+            name: "app".into(),
+            namespace: "default".into(),
+            scaler_metadata: metadata
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        }
+    }
+
+    /// The `(name, target)` of the first metric spec of a response.
+    fn first_spec(resp: &[u8]) -> (String, i64) {
+        let spec = GetMetricSpecResponse::decode(resp).unwrap().metric_specs[0].clone();
+        (spec.metric_name, spec.target_size)
+    }
+
+    #[test]
+    fn test_metric_spec() -> Result<()> {
+        let scaler = Scaler::new(Arc::default(), None, DEFAULT_TARGET);
+        let resp = scaler.metric_spec(&scaled_object_ref(&[]).encode_to_vec())?;
+        assert_eq!(
+            first_spec(&resp),
+            (METRIC_NAME.into(), DEFAULT_TARGET as i64)
+        );
+
+        let req = scaled_object_ref(&[("targetSize", "20")]).encode_to_vec();
+        assert_eq!(
+            first_spec(&scaler.metric_spec(&req)?),
+            (METRIC_NAME.into(), 20)
+        );
+
+        let req = scaled_object_ref(&[("targetSize", "many")]).encode_to_vec();
+        assert!(scaler.metric_spec(&req).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_metrics() -> Result<()> {
+        let in_flight = Arc::new(AtomicU64::new(3));
+        let concurrency = Arc::new(ConcurrencyLimit::new(1, None));
+        let scaler = Scaler::new(in_flight, Some(concurrency.clone()), DEFAULT_TARGET);
+
+        let _permit = concurrency.acquire().await?;
+        let waiter = tokio::spawn({
+            let concurrency = concurrency.clone();
+            async move { concurrency.acquire().await.map(|_| ()) }
+        });
+        while concurrency.queue_depth() == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        let req = GetMetricsRequest {
+            scaled_object_ref: Some(scaled_object_ref(&[])),
+            metric_name: "s0-requests".into(),
+        };
+        let resp = GetMetricsResponse::decode(scaler.metrics(&req.encode_to_vec())?.as_slice())?;
+        assert_eq!(
+            resp.metric_values,
+            [MetricValue {
+                metric_name: "s0-requests".into(),
+                metric_value: 4,
+            }]
+        );
+        assert_eq!(is_active(true), [0x08, 0x01]);
+
+        waiter.abort();
+        Ok(())
+    }
+}
@@ -0,0 +1,105 @@
+//! Blue/green component slots with weighted routing.
+//!
+//! The component of the container image is the blue slot. A second component can be loaded in
+//! the green slot, and a configurable share of the requests is routed to it:
+//! * `WASMTIME_HTTP_GREEN_COMPONENT`: path of the green component in the container.
+//! * `WASMTIME_HTTP_GREEN_WEIGHT`: percentage of requests routed to the green slot (default: 0).
+//!
+//! The weight can be adjusted at runtime through the admin endpoint, see [`super::admin`]:
+//! * `GET /slots` returns the current weight, e.g. `{"green_weight":10}`.
+//! * `PUT /slots/green-weight` with the new weight as the request body updates it.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{ensure, Context, Result};
+use http_body_util::BodyExt;
+use hyper::{Method, StatusCode};
+use wasmtime::component::Component;
+use wasmtime::Engine;
+use wasmtime_wasi_http::bindings::ProxyPre;
+
+use super::config::ComponentRoute;
+use super::mirror::sample_evenly;
+use super::Request;
+use crate::instance::WasiPreview2Ctx;
+use crate::linker::{proxy_linker, proxy_pre};
+
+pub(crate) struct Slots {
+    pub green: ProxyPre<WasiPreview2Ctx>,
+    green_weight: AtomicU64,
+    seq: AtomicU64,
+}
+
+impl Slots {
+    /// Load the green component.
+    pub fn new(route: &ComponentRoute, engine: &Engine) -> Result<Self> {
+        let ComponentRoute {
+            path,
+            percent: weight,
+        } = route;
+        let component = Component::from_file(engine, path)
+            .with_context(|| format!("failed to load green component {path:?}"))?;
+        let green = proxy_pre(&proxy_linker(engine)?, &component)?;
+
+        tracing::info!("routing {weight}% of requests to green component {path:?}");
+
+        Ok(Self {
+            green,
+            green_weight: AtomicU64::from(*weight),
+            seq: AtomicU64::from(0),
+        })
+    }
+
+    /// Whether the next request should be routed to the green slot.
+    pub fn route_green(&self) -> bool {
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        sample_evenly(seq, self.green_weight.load(Ordering::Relaxed))
+    }
+
+    pub fn set_green_weight(&self, weight: u64) {
+        tracing::info!("routing {weight}% of requests to green component");
+        self.green_weight.store(weight, Ordering::Relaxed);
+    }
+
+    /// Answer a request to `/slots` on the admin endpoint.
+    pub async fn handle_admin_request(&self, req: Request) -> (StatusCode, String) {
+        match (req.method(), req.uri().path()) {
+            (&Method::GET, "/slots") => {
+                let weight = self.green_weight.load(Ordering::Relaxed);
+                (StatusCode::OK, format!("{{\"green_weight\":{weight}}}"))
+            }
+            (&Method::PUT, "/slots/green-weight") => {
+                let weight = match req.into_body().collect().await {
+                    Ok(body) => parse_weight(&String::from_utf8_lossy(&body.to_bytes())),
+                    Err(e) => Err(e.into()),
+                };
+                match weight {
+                    Ok(weight) => {
+                        self.set_green_weight(weight);
+                        (StatusCode::NO_CONTENT, String::new())
+                    }
+                    Err(e) => (StatusCode::BAD_REQUEST, format!("{e}\n")),
+                }
+            }
+            _ => (StatusCode::NOT_FOUND, String::new()),
+        }
+    }
+}
+
+fn parse_weight(value: &str) -> Result<u64> {
+    let weight = value.trim().parse()?;
+    ensure!(weight <= 100, "weight must be a percentage, got {weight}");
+    Ok(weight)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_weight() {
+        assert_eq!(parse_weight(" 25\n").unwrap(), 25);
+        assert!(parse_weight("101").is_err());
+        assert!(parse_weight("-1").is_err());
+    }
+}
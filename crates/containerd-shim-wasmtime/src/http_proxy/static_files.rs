@@ -0,0 +1,241 @@
+//! Serve static files directly from the container filesystem.
+//!
+//! * `WASMTIME_HTTP_STATIC_DIR`: directory of the container to serve files from.
+//! * `WASMTIME_HTTP_DYNAMIC_PREFIXES`: comma separated list of path prefixes that are always
+//!   handled by the guest, e.g. `/api,/graphql`.
+//!
+//! `GET` and `HEAD` requests outside the dynamic prefixes are served by the shim when a matching
+//! file exists, which avoids a round-trip through the guest for images, stylesheets, etc.
+//! Requests for directories are served the `index.html` file of the directory. Everything else
+//! falls back to the guest.
+//!
+//! Files are streamed in chunks of [`CHUNK_SIZE`] bytes rather than read in memory, and the
+//! responses go through the same middleware as the responses of the guest, e.g. CORS.
+
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context, Result};
+use http_body_util::{BodyExt, Empty, StreamBody};
+use hyper::body::Frame;
+use hyper::header::{self, HeaderValue};
+use hyper::Method;
+use tokio_stream::StreamExt;
+use tokio_util::io::ReaderStream;
+use wasmtime_wasi_http::bindings::http::types::ErrorCode;
+use wasmtime_wasi_http::body::HyperOutgoingBody;
+
+use super::Request;
+
+/// Size of the chunks the static files are read in.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+pub(crate) struct StaticFiles {
+    root: PathBuf,
+    dynamic_prefixes: Vec<String>,
+}
+
+impl StaticFiles {
+    /// Serve the files of the `root` directory, except for the `dynamic_prefixes` paths.
+    pub fn new(root: &Path, dynamic_prefixes: Vec<String>) -> Result<Self> {
+        let root = std::fs::canonicalize(root)
+            .with_context(|| format!("invalid static files directory {root:?}"))?;
+
+        Ok(Self {
+            root,
+            dynamic_prefixes,
+        })
+    }
+
+    /// Serve the request from the static directory.
+    /// Returns the request back if it should be handled by the guest.
+    pub async fn serve(&self, req: Request) -> Result<hyper::Response<HyperOutgoingBody>, Request> {
+        if !matches!(*req.method(), Method::GET | Method::HEAD) {
+            return Err(req);
+        }
+
+        let path = req.uri().path();
+        if self.dynamic_prefixes.iter().any(|p| path.starts_with(p)) {
+            return Err(req);
+        }
+
+        let Some(file) = self.resolve(path).await else {
+            return Err(req);
+        };
+
+        let opened = match tokio::fs::File::open(&file).await {
+            Ok(opened) => opened,
+            Err(e) => {
+                tracing::debug!("failed to open static file {file:?}: {e}");
+                return Err(req);
+            }
+        };
+        let len = match opened.metadata().await {
+            Ok(metadata) => metadata.len(),
+            Err(e) => {
+                tracing::debug!("failed to read static file {file:?}: {e}");
+                return Err(req);
+            }
+        };
+
+        let body = match *req.method() {
+            Method::HEAD => Empty::new().map_err(|e| match e {}).boxed(),
+            _ => stream(opened),
+        };
+
+        let resp = hyper::Response::builder()
+            .header(header::CONTENT_TYPE, content_type(&file))
+            .header(header::CONTENT_LENGTH, len)
+            .body(body)
+            .expect("valid response");
+
+        Ok(resp)
+    }
+
+    /// Map a request path to a file in the static directory.
+    async fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let path = percent_decode(path)?;
+
+        let mut file = self.root.clone();
+        for component in Path::new(&path).components() {
+            match component {
+                Component::RootDir | Component::CurDir => {}
+                Component::Normal(segment) => file.push(segment),
+                // never serve anything outside of the static directory
+                Component::ParentDir | Component::Prefix(_) => return None,
+            }
+        }
+
+        let mut file = tokio::fs::canonicalize(file).await.ok()?;
+        if !file.starts_with(&self.root) {
+            // a symlink pointing outside of the static directory
+            return None;
+        }
+
+        if tokio::fs::metadata(&file).await.ok()?.is_dir() {
+            file.push("index.html");
+        }
+
+        tokio::fs::metadata(&file)
+            .await
+            .ok()?
+            .is_file()
+            .then_some(file)
+    }
+}
+
+/// Stream the contents of `file`.
+fn stream(file: tokio::fs::File) -> HyperOutgoingBody {
+    let chunks = ReaderStream::with_capacity(file, CHUNK_SIZE).map(|chunk| {
+        chunk.map(Frame::data).map_err(|e| {
+            tracing::debug!("failed to read static file: {e}");
+            ErrorCode::InternalError(Some(e.to_string()))
+        })
+    });
+    StreamBody::new(chunks).boxed()
+}
+
+fn percent_decode(path: &str) -> Option<String> {
+    let mut bytes = path.bytes();
+    let mut decoded = Vec::with_capacity(path.len());
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            let hex = std::str::from_utf8(&hex).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            decoded.push(b);
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+fn content_type(file: &Path) -> HeaderValue {
+    let extension = file
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+
+    HeaderValue::from_static(match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        _ => "application/octet-stream",
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_resolve() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::create_dir(dir.path().join("static"))?;
+        std::fs::create_dir(dir.path().join("static/docs"))?;
+        std::fs::write(dir.path().join("static/app.css"), "body {}")?;
+        std::fs::write(dir.path().join("static/docs/index.html"), "<html>")?;
+        std::fs::write(dir.path().join("secret"), "secret")?;
+
+        let files = StaticFiles {
+            root: dir.path().join("static").canonicalize()?,
+            dynamic_prefixes: vec!["/api".into()],
+        };
+
+        let root = &files.root;
+        assert_eq!(files.resolve("/app.css").await, Some(root.join("app.css")));
+        assert_eq!(
+            files.resolve("/app%2Ecss").await,
+            Some(root.join("app.css"))
+        );
+        assert_eq!(
+            files.resolve("/docs").await,
+            Some(root.join("docs/index.html"))
+        );
+        assert_eq!(files.resolve("/missing.css").await, None);
+        assert_eq!(files.resolve("/../secret").await, None);
+        assert_eq!(files.resolve("/%2e%2e/secret").await, None);
+
+        assert_eq!(
+            content_type(Path::new("app.CSS")),
+            "text/css; charset=utf-8"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stream() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let contents = vec![7u8; CHUNK_SIZE * 2 + 1];
+        std::fs::write(dir.path().join("large.bin"), &contents)?;
+
+        let file = tokio::fs::File::open(dir.path().join("large.bin")).await?;
+        let mut body = stream(file);
+        let mut chunks = 0;
+        let mut read = Vec::new();
+        while let Some(frame) = body.frame().await {
+            let data = frame.unwrap().into_data().unwrap();
+            assert!(data.len() <= CHUNK_SIZE);
+            read.extend_from_slice(&data);
+            chunks += 1;
+        }
+        assert_eq!(read, contents);
+        assert_eq!(chunks, 3);
+
+        Ok(())
+    }
+}
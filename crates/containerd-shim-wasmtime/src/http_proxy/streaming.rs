@@ -0,0 +1,106 @@
+//! Streamed responses, e.g. Server-Sent Events.
+//!
+//! Responses are always forwarded as the guest writes them. Responses with a streaming content
+//! type also get `X-Accel-Buffering: no`, so that reverse proxies in front of the shim don't
+//! buffer them, and `Cache-Control: no-cache` unless the guest set it, so that they are not cached.
+//! * `WASMTIME_HTTP_STREAMING_CONTENT_TYPES`: comma separated list of streaming content types
+//!   (default: `text/event-stream`).
+
+use anyhow::Result;
+use hyper::header::{self, HeaderMap, HeaderName, HeaderValue};
+use hyper::http::request::Parts;
+use wasmtime_wasi_http::body::HyperOutgoingBody;
+
+use super::config::Settings;
+use super::middleware::Middleware;
+
+const DEFAULT_CONTENT_TYPES: &str = "text/event-stream";
+
+const X_ACCEL_BUFFERING: HeaderName = HeaderName::from_static("x-accel-buffering");
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Streaming {
+    content_types: Vec<String>,
+}
+
+impl Default for Streaming {
+    fn default() -> Self {
+        Self::parse(DEFAULT_CONTENT_TYPES)
+    }
+}
+
+impl Streaming {
+    /// Read the streaming settings.
+    pub(crate) fn from_settings(settings: &Settings) -> Result<Self> {
+        Ok(settings
+            .get("streaming-content-types")
+            .map(Self::parse)
+            .unwrap_or_default())
+    }
+
+    /// Parse a comma separated list of streaming content types.
+    pub fn parse(value: &str) -> Self {
+        let content_types = value
+            .split(',')
+            .map(|v| v.trim().to_ascii_lowercase())
+            .filter(|v| !v.is_empty())
+            .collect();
+        Self { content_types }
+    }
+
+    /// Whether a response with `headers` is streamed.
+    pub fn is_streaming(&self, headers: &HeaderMap) -> bool {
+        let Some(content_type) = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+        else {
+            return false;
+        };
+        // Ignore the parameters, e.g. `; charset=utf-8`
+        let essence = content_type.split(';').next().unwrap_or_default().trim();
+        self.content_types
+            .iter()
+            .any(|t| t.eq_ignore_ascii_case(essence))
+    }
+}
+
+impl Middleware for Streaming {
+    fn on_response(
+        &self,
+        _req: &Parts,
+        resp: &mut hyper::Response<HyperOutgoingBody>,
+    ) -> Result<()> {
+        if !self.is_streaming(resp.headers()) {
+            return Ok(());
+        }
+        let headers = resp.headers_mut();
+        headers.insert(X_ACCEL_BUFFERING, HeaderValue::from_static("no"));
+        if !headers.contains_key(header::CACHE_CONTROL) {
+            headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_streaming() {
+        let headers = |content_type: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+            headers
+        };
+
+        let streaming = Streaming::default();
+        assert!(streaming.is_streaming(&headers("text/event-stream")));
+        assert!(streaming.is_streaming(&headers("Text/Event-Stream; charset=utf-8")));
+        assert!(!streaming.is_streaming(&headers("application/json")));
+        assert!(!streaming.is_streaming(&HeaderMap::new()));
+
+        let streaming = Streaming::parse("application/x-ndjson, text/event-stream");
+        assert!(streaming.is_streaming(&headers("application/x-ndjson")));
+    }
+}
@@ -0,0 +1,171 @@
+//! Linear memories backed by transparent huge pages.
+//!
+//! Memory-heavy guests spend a noticeable share of their time in TLB misses with 4 KiB pages.
+//! [`HugePageMemoryCreator`] maps the linear memories of the guests itself, aligned to huge
+//! pages and advised with `MADV_HUGEPAGE`, so that the kernel backs them with transparent huge
+//! pages when they are enabled in `madvise` or `always` mode. The memories are unmapped, and
+//! returned to the OS, when their instance is torn down.
+
+use std::ops::Range;
+use std::ptr::NonNull;
+
+use anyhow::{bail, Result};
+use wasmtime::{LinearMemory, MemoryCreator, MemoryType};
+
+const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+/// Reservation of the memories without maximum size or reservation, i.e., the 32-bit space.
+const DEFAULT_RESERVATION: usize = 1 << 32;
+
+pub struct HugePageMemoryCreator;
+
+unsafe impl MemoryCreator for HugePageMemoryCreator {
+    fn new_memory(
+        &self,
+        _ty: MemoryType,
+        minimum: usize,
+        maximum: Option<usize>,
+        reserved_size_in_bytes: Option<usize>,
+        guard_size_in_bytes: usize,
+    ) -> Result<Box<dyn LinearMemory>, String> {
+        let reservation = reserved_size_in_bytes
+            .or(maximum)
+            .unwrap_or(DEFAULT_RESERVATION)
+            .max(minimum);
+        HugePageMemory::new(minimum, maximum, reservation, guard_size_in_bytes)
+            .map(|memory| Box::new(memory) as Box<dyn LinearMemory>)
+            .map_err(|e| format!("{e:?}"))
+    }
+}
+
+struct HugePageMemory {
+    ptr: NonNull<u8>,
+    /// The accessible bytes.
+    len: usize,
+    maximum: Option<usize>,
+    reservation: usize,
+    /// The mapped bytes, including the guard.
+    mapped: usize,
+}
+
+// The memory is only accessed through wasmtime.
+unsafe impl Send for HugePageMemory {}
+unsafe impl Sync for HugePageMemory {}
+
+impl HugePageMemory {
+    fn new(
+        minimum: usize,
+        maximum: Option<usize>,
+        reservation: usize,
+        guard: usize,
+    ) -> Result<Self> {
+        let mapped = reservation.next_multiple_of(HUGE_PAGE_SIZE) + guard;
+
+        // Over-reserve to align the memory to huge pages, and unmap the excess
+        let ptr = mmap_reserve(mapped + HUGE_PAGE_SIZE)?;
+        let offset = ptr.align_offset(HUGE_PAGE_SIZE);
+        unsafe {
+            if offset > 0 {
+                libc::munmap(ptr.cast(), offset);
+            }
+            libc::munmap(ptr.add(offset + mapped).cast(), HUGE_PAGE_SIZE - offset);
+        }
+        let ptr = NonNull::new(unsafe { ptr.add(offset) }).unwrap();
+
+        // The kernel only backs the advised range with huge pages if they are enabled
+        if unsafe { libc::madvise(ptr.as_ptr().cast(), reservation, libc::MADV_HUGEPAGE) } != 0 {
+            tracing::debug!(
+                "madvise(MADV_HUGEPAGE) failed: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+
+        let mut memory = Self {
+            ptr,
+            len: 0,
+            maximum,
+            reservation,
+            mapped,
+        };
+        memory.grow_to(minimum)?;
+        Ok(memory)
+    }
+}
+
+impl Drop for HugePageMemory {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr.as_ptr().cast(), self.mapped) };
+    }
+}
+
+unsafe impl LinearMemory for HugePageMemory {
+    fn byte_size(&self) -> usize {
+        self.len
+    }
+
+    fn maximum_byte_size(&self) -> Option<usize> {
+        self.maximum
+    }
+
+    fn grow_to(&mut self, new_size: usize) -> Result<()> {
+        if new_size > self.reservation {
+            bail!("memory of {new_size} bytes exceeds its reservation");
+        }
+        if new_size > self.len {
+            let start = unsafe { self.ptr.as_ptr().add(self.len) };
+            let prot = libc::PROT_READ | libc::PROT_WRITE;
+            if unsafe { libc::mprotect(start.cast(), new_size - self.len, prot) } != 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            self.len = new_size;
+        }
+        Ok(())
+    }
+
+    fn as_ptr(&self) -> *mut u8 {
+        self.ptr.as_ptr()
+    }
+
+    fn wasm_accessible(&self) -> Range<usize> {
+        let start = self.ptr.as_ptr() as usize;
+        start..start + self.mapped
+    }
+}
+
+/// Reserve `len` bytes of address space, without backing memory.
+fn mmap_reserve(len: usize) -> Result<*mut u8> {
+    let ptr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            len,
+            libc::PROT_NONE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+            -1,
+            0,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(ptr.cast())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_huge_page_memory() -> Result<()> {
+        let page = 64 * 1024;
+        let mut memory = HugePageMemory::new(page, Some(4 * page), 4 * page, page)?;
+        assert_eq!(memory.as_ptr().align_offset(HUGE_PAGE_SIZE), 0);
+        assert_eq!(memory.byte_size(), page);
+
+        memory.grow_to(2 * page)?;
+        let data = unsafe { std::slice::from_raw_parts_mut(memory.as_ptr(), 2 * page) };
+        assert!(data.iter().all(|b| *b == 0));
+        data[2 * page - 1] = 1;
+
+        assert!(memory.grow_to(8 * page).is_err());
+        Ok(())
+    }
+}
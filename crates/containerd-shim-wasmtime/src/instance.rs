@@ -1,62 +1,171 @@
 use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
 use std::marker::PhantomData;
+use std::path::PathBuf;
+use std::sync::{Arc, LazyLock, Mutex};
 
 use anyhow::{bail, Context, Result};
+use containerd_shim_wasm::container::priority::Priority;
+use containerd_shim_wasm::container::socket_policy::{SocketUse, UdpStats};
 use containerd_shim_wasm::container::{
-    Engine, Entrypoint, Instance, RuntimeContext, Stdio, WasmBinaryType,
+    parse_envs, report_phase, resolve_func, Engine, Entrypoint, Instance, Phase, RuntimeContext,
+    Source, Stdio, WasiDescriptor, WasmBinaryType,
 };
 use containerd_shim_wasm::sandbox::WasmLayer;
+use hyper::http::Extensions;
+use tokio::sync::oneshot;
 use tokio_util::sync::CancellationToken;
-use wasi_preview1::WasiP1Ctx;
 use wasi_preview2::bindings::Command;
+use wasmparser::{Parser, Payload};
 use wasmtime::component::types::ComponentItem;
-use wasmtime::component::{self, Component, ResourceTable};
-use wasmtime::{Config, Module, Precompiled, Store};
-use wasmtime_wasi::preview1::{self as wasi_preview1};
+use wasmtime::component::{Component, ResourceTable};
+use wasmtime::{Config, Module, Precompiled, Store, Strategy, UpdateDeadline};
 use wasmtime_wasi::{self as wasi_preview2};
 use wasmtime_wasi_http::bindings::ProxyPre;
-use wasmtime_wasi_http::{WasiHttpCtx, WasiHttpView};
-
-use crate::http_proxy::serve_conn;
+use wasmtime_wasi_http::body::HyperOutgoingBody;
+use wasmtime_wasi_http::types::{HostFutureIncomingResponse, OutgoingRequestConfig};
+use wasmtime_wasi_http::{HttpResult, WasiHttpCtx, WasiHttpView};
+
+use crate::audit::{component_imports, Audit};
+use crate::chaos::Chaos;
+#[cfg(unix)]
+use crate::code_cache::{self, CodeCache};
+#[cfg(feature = "crypto")]
+use crate::crypto;
+use crate::diagnostics::TrapContext;
+#[cfg(unix)]
+use crate::fs_events::FsEvents;
+use crate::guardrails::Guardrails;
+use crate::http_proxy::{serve_conn, ProxyConfig};
+use crate::instantiation::InstantiationConfig;
+use crate::kv_cache::KvCache;
+use crate::linker::{self, explain_link_error, instantiate_pre, Linkers, ModuleCtx};
+use crate::memory::{self, AccountedLimits};
+#[cfg(target_os = "linux")]
+use crate::memory_dump::{MemoryDump, DUMP_DIR_ANNOTATION, DUMP_SIGNAL};
+use crate::outgoing::Outgoing;
+#[cfg(feature = "preinit")]
+use crate::preinit;
+use crate::profile::ResourceProfile;
+use crate::quarantine::{self, Quarantine};
+use crate::snapshot::{Snapshots, RESUME_FUNC, SNAPSHOT_DIR_ANNOTATION};
+use crate::tcp_handler::serve_tcp;
+use crate::timezone::TimeZone;
+#[cfg(unix)]
+use crate::unix_sockets::UnixSockets;
+use crate::write_quota::{ModuleQuotas, WriteQuota, WriteQuotas};
+use crate::{metrics, tenant};
 
 pub type WasmtimeInstance = Instance<WasmtimeEngine<DefaultConfig>>;
 
+/// Number of memories of the pooling allocator of wasmtime by default.
+const DEFAULT_POOL_SIZE: u32 = 1000;
+
+/// Annotation-style key under which the detected target is logged.
+const COMPONENT_TARGET_KEY: &str = "runwasi.io/component-target";
+
+/// Targets of the components started by this process, by layer digest.
+static TARGETS: LazyLock<Mutex<HashMap<String, ComponentTarget>>> = LazyLock::new(Default::default);
+
 /// Represents the WASI API that the component is targeting.
-enum ComponentTarget<'a> {
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ComponentTarget {
     /// A component that targets WASI command-line interface.
     Command,
     /// A component that targets WASI http/proxy  interface.
     HttpProxy,
-    /// Core function. The `&'a str` represents function to call.
-    Core(&'a str),
+    /// A component that exports the `runwasi:tcp/handler` interface.
+    TcpHandler,
+    /// Core function, i.e., the entrypoint function is called.
+    Core,
 }
 
-impl<'a> ComponentTarget<'a> {
-    fn new<'b, I>(exports: I, func: &'a str) -> Self
+impl ComponentTarget {
+    /// Detect the target of `component`, whose layer has `digest`.
+    /// The target of a layer is only detected once.
+    fn detect(engine: &wasmtime::Engine, component: &Component, digest: Option<&str>) -> Self {
+        let Some(digest) = digest else {
+            return Self::new(component.component_type().exports(engine));
+        };
+        let mut targets = TARGETS.lock().unwrap();
+        *targets
+            .entry(digest.to_string())
+            .or_insert_with(|| Self::new(component.component_type().exports(engine)))
+    }
+
+    /// Detect the target of the component `bytes` from its exports, without compiling it.
+    fn from_binary(bytes: &[u8]) -> Self {
+        let mut depth = 0usize;
+        let mut names = vec![];
+        for payload in Parser::new(0).parse_all(bytes) {
+            match payload {
+                Ok(Payload::ModuleSection { .. } | Payload::ComponentSection { .. }) => depth += 1,
+                Ok(Payload::End(_)) => depth = depth.saturating_sub(1),
+                // Only the exports of the outer component tell its target
+                Ok(Payload::ComponentExportSection(exports)) if depth == 0 => {
+                    names.extend(exports.into_iter().flatten().map(|export| export.name.0));
+                }
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+        Self::from_names(names)
+    }
+
+    fn new<'b, I>(exports: I) -> Self
     where
         I: IntoIterator<Item = (&'b str, ComponentItem)> + 'b,
     {
+        Self::from_names(exports.into_iter().map(|(name, _)| name))
+    }
+
+    fn from_names<'b>(names: impl IntoIterator<Item = &'b str>) -> Self {
         // This is heuristic but seems to work
-        exports
+        names
             .into_iter()
-            .find_map(|(name, _)| {
+            .find_map(|name| {
                 if name.starts_with("wasi:http/incoming-handler") {
                     Some(Self::HttpProxy)
+                } else if name.starts_with("runwasi:tcp/handler") {
+                    Some(Self::TcpHandler)
                 } else if name.starts_with("wasi:cli/run") {
                     Some(Self::Command)
                 } else {
                     None
                 }
             })
-            .unwrap_or(Self::Core(func))
+            .unwrap_or(Self::Core)
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Command => "command",
+            Self::HttpProxy => "http-proxy",
+            Self::TcpHandler => "tcp-handler",
+            Self::Core => "core",
+        }
+    }
+}
+
+/// The digest of the layer of the entrypoint, if it's an OCI layer.
+fn layer_digest(ctx: &impl RuntimeContext) -> Option<String> {
+    match ctx.entrypoint().source {
+        Source::Oci([layer]) => Some(layer.config.digest().to_string()),
+        _ => None,
     }
 }
 
 #[derive(Clone)]
 pub struct WasmtimeEngine<T: WasiConfig> {
     engine: wasmtime::Engine,
+    linkers: Arc<Linkers>,
     cancel: CancellationToken,
+    /// The tenant of the engine, when the containers are isolated by tenant.
+    tenant: Option<String>,
+    /// The code cache of the shim, opened before the containers are created.
+    #[cfg(unix)]
+    code_cache: Option<Arc<CodeCache>>,
     config_type: PhantomData<T>,
 }
 
@@ -77,37 +186,326 @@ pub trait WasiConfig: Clone + Sync + Send + 'static {
 
 impl<T: WasiConfig> Default for WasmtimeEngine<T> {
     fn default() -> Self {
-        let mut config = T::new_config();
-        config.async_support(true); // must be on
-
-        if use_pooling_allocator_by_default().unwrap_or_default() {
-            let cfg = wasmtime::PoolingAllocationConfig::default();
-            config.allocation_strategy(wasmtime::InstanceAllocationStrategy::Pooling(cfg));
-        }
-
+        let engine = new_engine::<T>(&ResourceProfile::default(), None).unwrap();
         Self {
-            engine: wasmtime::Engine::new(&config)
-                .context("failed to create wasmtime engine")
-                .unwrap(),
+            linkers: Arc::new(Linkers::new(&engine).unwrap()),
+            engine,
             cancel: CancellationToken::new(),
+            tenant: None,
+            #[cfg(unix)]
+            code_cache: CodeCache::shared(),
             config_type: PhantomData,
         }
     }
 }
 
+impl<T: WasiConfig> WasmtimeEngine<T> {
+    /// The engine of the tenant of the container, when the containers are isolated by tenant.
+    fn for_tenant(&self, ctx: &impl RuntimeContext) -> Result<Self> {
+        let Some(tenant) = tenant::from_ctx(ctx)? else {
+            return Ok(self.clone());
+        };
+        let (engine, linkers) = tenant::engine(&tenant, || {
+            let engine = new_engine::<T>(&ResourceProfile::default(), None)?;
+            let linkers = Arc::new(Linkers::new(&engine)?);
+            Ok((engine, linkers))
+        })?;
+        Ok(Self {
+            engine,
+            linkers,
+            tenant: Some(tenant),
+            ..self.clone()
+        })
+    }
+
+    /// The engine to run a container with `profile`.
+    /// Profiles with a pool size get their own engine, compatible with the precompiled artifacts.
+    fn for_profile(&self, profile: &ResourceProfile) -> Result<Self> {
+        if profile.pool_size.is_none() {
+            return Ok(self.clone());
+        }
+        let engine = new_engine::<T>(profile, None)?;
+        Ok(Self {
+            linkers: Arc::new(Linkers::new(&engine)?),
+            engine,
+            ..self.clone()
+        })
+    }
+
+    /// The engine to run a container with memory dumps, which tracks the memories of the stores.
+    #[cfg(target_os = "linux")]
+    fn for_memory_dump(
+        &self,
+        ctx: &impl RuntimeContext,
+        profile: &ResourceProfile,
+    ) -> Result<Self> {
+        let Some(dump) = MemoryDump::enable(ctx)? else {
+            return Ok(self.clone());
+        };
+        let engine = new_engine::<T>(profile, None)?;
+        dump.listen(&engine)?;
+        Ok(Self {
+            linkers: Arc::new(Linkers::new(&engine)?),
+            engine,
+            ..self.clone()
+        })
+    }
+
+    /// The engine to start the container with baseline code, if it opted in to tiered start.
+    fn baseline(&self, ctx: &impl RuntimeContext) -> Result<Option<Self>> {
+        if !ProxyConfig::from_ctx(ctx)?.tiered_start {
+            return Ok(None);
+        }
+        let profile = ResourceProfile::from_ctx(ctx)?;
+        let engine = match new_engine::<T>(&profile, Some(Strategy::Winch)) {
+            Ok(engine) => engine,
+            Err(e) => {
+                tracing::warn!("tiered start is not available: {e:?}");
+                return Ok(None);
+            }
+        };
+        Ok(Some(Self {
+            linkers: Arc::new(Linkers::new(&engine)?),
+            engine,
+            ..self.clone()
+        }))
+    }
+
+    /// Compile the optimized code of the HTTP proxy component `bytes` on a background thread,
+    /// storing it in `cache`, if any.
+    fn compile_in_background(
+        &self,
+        bytes: Vec<u8>,
+        #[cfg(unix)] cache: Option<code_cache::Entry>,
+    ) -> OptimizedProxy {
+        let (sender, receiver) = oneshot::channel();
+        let this = self.clone();
+        std::thread::spawn(move || {
+            #[cfg(unix)]
+            let cached = cache
+                .and_then(|entry| this.cached_code(&entry, &bytes))
+                // SAFETY: the code was compiled by the shim, and authenticated
+                .map(|code| unsafe { Component::deserialize_file(&this.engine, code.path()) });
+            #[cfg(not(unix))]
+            let cached = None;
+            let instance = cached
+                .unwrap_or_else(|| Component::from_binary(&this.engine, &bytes))
+                .and_then(|component| this.linkers.proxy_pre(&component));
+            let _ = sender.send(instance);
+        });
+        receiver
+    }
+}
+
+/// The optimized HTTP proxy component, once compiled.
+type OptimizedProxy = oneshot::Receiver<Result<ProxyPre<WasiPreview2Ctx>>>;
+
+fn new_engine<T: WasiConfig>(
+    profile: &ResourceProfile,
+    strategy: Option<Strategy>,
+) -> Result<wasmtime::Engine> {
+    let mut config = T::new_config();
+    config.async_support(true); // must be on
+    config.epoch_interruption(true); // stores yield at every tick of the epoch ticker
+
+    if let Some(strategy) = strategy {
+        config.strategy(strategy);
+    }
+
+    // Invalid profiles are reported when the container is created
+    if ResourceProfile::fuel_metering().unwrap_or_default() {
+        config.consume_fuel(true);
+    }
+
+    let instantiation = InstantiationConfig::from_env().unwrap_or_default();
+    instantiation.configure(&mut config);
+    #[cfg(target_os = "linux")]
+    let dumped = MemoryDump::configure(&mut config);
+    #[cfg(not(target_os = "linux"))]
+    let dumped = false;
+
+    // The memories backed by huge pages, or dumped, are allocated on demand
+    let pooling = !instantiation.huge_pages
+        && !dumped
+        && use_pooling_allocator_by_default().unwrap_or_default();
+    if pooling {
+        let mut cfg = wasmtime::PoolingAllocationConfig::default();
+        instantiation.configure_pooling(&mut cfg);
+        profile.configure_pooling(&mut cfg);
+        config.allocation_strategy(wasmtime::InstanceAllocationStrategy::Pooling(cfg));
+    }
+
+    let engine = wasmtime::Engine::new(&config).context("failed to create wasmtime engine")?;
+    if pooling {
+        instantiation.prefault(&engine, profile.pool_size.unwrap_or(DEFAULT_POOL_SIZE));
+    }
+    Ok(engine)
+}
+
+/// The data of the stores of the components.
+///
+/// Embedders build it with a [`WasiPreview2CtxBuilder`], e.g. to preopen other directories, or
+/// to attach the state of their own host interfaces as [extensions](Self::extension).
 pub struct WasiPreview2Ctx {
     pub(crate) wasi_ctx: wasi_preview2::WasiCtx,
     pub(crate) wasi_http: WasiHttpCtx,
     pub(crate) resource_table: ResourceTable,
+    #[cfg(unix)]
+    pub(crate) unix_sockets: UnixSockets,
+    #[cfg(unix)]
+    pub(crate) fs_events: Option<Arc<FsEvents>>,
+    pub(crate) outgoing: Arc<Outgoing>,
+    pub(crate) timezone: Arc<TimeZone>,
+    pub(crate) kv_cache: Arc<KvCache>,
+    pub(crate) write_quotas: Option<Arc<WriteQuotas>>,
+    pub(crate) audit: Option<Arc<Audit>>,
+    pub(crate) udp_stats: Arc<UdpStats>,
+    /// The guest paths of the descriptors opened by the guest, tracked for the audit log and the
+    /// write quotas.
+    pub(crate) descriptor_paths: HashMap<u32, PathBuf>,
+    /// The write quotas of the descriptors, the ones of the guest paths they were opened at.
+    pub(crate) descriptor_quotas: HashMap<u32, Arc<WriteQuota>>,
+    pub(crate) limits: AccountedLimits,
+    pub(crate) priority: Priority,
+    pub(crate) extensions: Extensions,
 }
 
 impl WasiPreview2Ctx {
     pub fn new(ctx: &impl RuntimeContext) -> Result<Self> {
+        Ok(WasiPreview2CtxBuilder::new(ctx)?.build())
+    }
+
+    /// The extension of type `T` attached by the embedder, if any.
+    pub fn extension<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.extensions.get()
+    }
+
+    pub fn extension_mut<T: Send + Sync + 'static>(&mut self) -> Option<&mut T> {
+        self.extensions.get_mut()
+    }
+}
+
+/// Builder of the [`WasiPreview2Ctx`] of a container.
+///
+/// The settings of the container are read from its annotations, and the WASI context from its
+/// [`WasiDescriptor`], which embedders can change before building the context.
+pub struct WasiPreview2CtxBuilder {
+    wasi: wasi_preview2::WasiCtxBuilder,
+    #[cfg(unix)]
+    unix_sockets: UnixSockets,
+    #[cfg(unix)]
+    fs_events: Option<Arc<FsEvents>>,
+    outgoing: Arc<Outgoing>,
+    timezone: Arc<TimeZone>,
+    kv_cache: Arc<KvCache>,
+    write_quotas: Option<Arc<WriteQuotas>>,
+    audit: Option<Arc<Audit>>,
+    udp_stats: Arc<UdpStats>,
+    limits: AccountedLimits,
+    priority: Priority,
+    extensions: Extensions,
+}
+
+impl WasiPreview2CtxBuilder {
+    /// The builder of the context of the container `ctx`.
+    pub fn new(ctx: &impl RuntimeContext) -> Result<Self> {
+        Self::with_descriptor(ctx, WasiDescriptor::from_ctx(ctx)?)
+    }
+
+    /// The builder of the context of the container `ctx`, with the args, envs, preopens and
+    /// socket policy of `descriptor`.
+    pub fn with_descriptor(ctx: &impl RuntimeContext, descriptor: WasiDescriptor) -> Result<Self> {
+        let audit = Audit::from_ctx(ctx)?;
+        let udp_stats = descriptor.socket_policy.udp_stats();
+        metrics::register(&udp_stats);
         Ok(Self {
-            wasi_ctx: wasi_builder(ctx)?.build(),
+            wasi: wasi_builder(ctx, descriptor, audit.clone())?,
+            #[cfg(unix)]
+            unix_sockets: UnixSockets::connect(ctx)?,
+            #[cfg(unix)]
+            fs_events: FsEvents::watch(ctx)?,
+            outgoing: Outgoing::from_ctx(ctx)?,
+            timezone: TimeZone::from_ctx(ctx),
+            kv_cache: KvCache::from_ctx(ctx)?,
+            write_quotas: WriteQuotas::from_ctx(ctx)?,
+            audit,
+            udp_stats,
+            limits: ResourceProfile::from_ctx(ctx)?.store_limits(),
+            priority: Priority::from_ctx(ctx)?,
+            extensions: Extensions::new(),
+        })
+    }
+
+    /// The WASI context being built, e.g. to add preopens, envs, or redirect the stdio.
+    pub fn wasi(&mut self) -> &mut wasi_preview2::WasiCtxBuilder {
+        &mut self.wasi
+    }
+
+    /// Attach `extension` to the context, replacing the extension of the same type.
+    pub fn extension<T: Clone + Send + Sync + 'static>(&mut self, extension: T) -> &mut Self {
+        self.extensions.insert(extension);
+        self
+    }
+
+    pub fn build(mut self) -> WasiPreview2Ctx {
+        WasiPreview2Ctx {
+            wasi_ctx: self.wasi.build(),
             wasi_http: WasiHttpCtx::new(),
             resource_table: ResourceTable::default(),
-        })
+            #[cfg(unix)]
+            unix_sockets: self.unix_sockets,
+            #[cfg(unix)]
+            fs_events: self.fs_events,
+            outgoing: self.outgoing,
+            timezone: self.timezone,
+            kv_cache: self.kv_cache,
+            write_quotas: self.write_quotas,
+            audit: self.audit,
+            udp_stats: self.udp_stats,
+            descriptor_paths: Default::default(),
+            descriptor_quotas: Default::default(),
+            limits: self.limits,
+            priority: self.priority,
+            extensions: self.extensions,
+        }
+    }
+}
+
+/// Create a store for `ctx`, limited by `profile`.
+pub fn new_store(
+    engine: &wasmtime::Engine,
+    ctx: WasiPreview2Ctx,
+    profile: &ResourceProfile,
+) -> Result<Store<WasiPreview2Ctx>> {
+    let time_slices = ctx.priority.time_slices();
+    let mut store = Store::new(engine, ctx);
+    if memory_dumps() {
+        store.set_epoch_deadline(time_slices);
+        store.epoch_deadline_callback(move |store| {
+            dump_memory(store.data().limits.store_id());
+            Ok(UpdateDeadline::Yield(time_slices))
+        });
+    } else {
+        store.epoch_deadline_async_yield_and_update(time_slices);
+    }
+    profile.limit_store(&mut store, |ctx| &mut ctx.limits)?;
+    Ok(store)
+}
+
+/// Whether the memories of the container are dumped on request.
+fn memory_dumps() -> bool {
+    #[cfg(target_os = "linux")]
+    let enabled = MemoryDump::current().is_some();
+    #[cfg(not(target_os = "linux"))]
+    let enabled = false;
+    enabled
+}
+
+/// Take the requested memory dump of the store `store_id`, at its epoch check.
+fn dump_memory(store_id: u64) {
+    #[cfg(target_os = "linux")]
+    if let Some(dump) = MemoryDump::current() {
+        dump.on_epoch(store_id);
     }
 }
 
@@ -130,6 +528,17 @@ impl WasiHttpView for WasiPreview2Ctx {
     fn ctx(&mut self) -> &mut wasmtime_wasi_http::WasiHttpCtx {
         &mut self.wasi_http
     }
+
+    fn send_request(
+        &mut self,
+        request: hyper::Request<HyperOutgoingBody>,
+        config: OutgoingRequestConfig,
+    ) -> HttpResult<HostFutureIncomingResponse> {
+        if let Some(audit) = &self.audit {
+            audit.http_request(request.method().as_str(), request.uri());
+        }
+        Ok(self.outgoing.send_request(request, config))
+    }
 }
 
 impl<T: WasiConfig> Engine for WasmtimeEngine<T> {
@@ -137,8 +546,17 @@ impl<T: WasiConfig> Engine for WasmtimeEngine<T> {
         "wasmtime"
     }
 
-    fn run_wasi(&self, ctx: &impl RuntimeContext, stdio: Stdio) -> Result<i32> {
-        log::info!("setting up wasi");
+    fn run_wasi(&self, ctx: &(impl RuntimeContext + Sync), stdio: Stdio) -> Result<i32> {
+        wasmtime_wasi::runtime::in_tokio(self.run_wasi_async(ctx, stdio))
+    }
+
+    #[tracing::instrument(skip_all, level = "info")]
+    async fn run_wasi_async(
+        &self,
+        ctx: &(impl RuntimeContext + Sync),
+        stdio: Stdio,
+    ) -> Result<i32> {
+        tracing::info!("setting up wasi");
         let Entrypoint {
             source,
             func,
@@ -146,8 +564,24 @@ impl<T: WasiConfig> Engine for WasmtimeEngine<T> {
             name: _,
         } = ctx.entrypoint();
 
+        let profile = ResourceProfile::from_ctx(ctx)?;
+        let engine = self.for_tenant(ctx)?.for_profile(&profile)?;
+        #[cfg(target_os = "linux")]
+        let engine = engine.for_memory_dump(ctx, &profile)?;
+
+        if let (Some(quarantine), Some(digest)) = (Quarantine::from_env()?, layer_digest(ctx)) {
+            quarantine::track(quarantine, digest)?;
+        }
+
+        let _metrics = metrics::serve_from_ctx(ctx)?;
         let wasm_bytes = &source.as_bytes()?;
-        self.execute(ctx, wasm_bytes, func, stdio).into_error_code()
+        let status = engine.execute(ctx, wasm_bytes, func, stdio).await;
+        if let Err(e) = &status {
+            quarantine::failed(e);
+        }
+        let status = status.into_error_code();
+        memory::stats().log_summary();
+        status
     }
 
     fn precompile(&self, layers: &[WasmLayer]) -> Result<Vec<Option<Vec<u8>>>> {
@@ -155,18 +589,26 @@ impl<T: WasiConfig> Engine for WasmtimeEngine<T> {
 
         for layer in layers {
             if self.engine.detect_precompiled(&layer.layer).is_some() {
-                log::info!("Already precompiled");
+                tracing::info!("Already precompiled");
                 compiled_layers.push(None);
                 continue;
             }
 
             use WasmBinaryType::*;
 
-            let compiled_layer = match WasmBinaryType::from_bytes(&layer.layer) {
-                Some(Module) => self.engine.precompile_module(&layer.layer)?,
-                Some(Component) => self.engine.precompile_component(&layer.layer)?,
+            #[cfg(feature = "preinit")]
+            let initialized = preinit::enabled()
+                .then(|| preinit::preinit(&layer.layer))
+                .flatten();
+            #[cfg(not(feature = "preinit"))]
+            let initialized: Option<Vec<u8>> = None;
+            let wasm = initialized.as_deref().unwrap_or(&layer.layer);
+
+            let compiled_layer = match WasmBinaryType::from_bytes(wasm) {
+                Some(Module) => self.engine.precompile_module(wasm)?,
+                Some(Component) => self.engine.precompile_component(wasm)?,
                 None => {
-                    log::warn!("Unknow WASM binary type");
+                    tracing::warn!("Unknow WASM binary type");
                     continue;
                 }
             };
@@ -182,8 +624,52 @@ impl<T: WasiConfig> Engine for WasmtimeEngine<T> {
         self.engine
             .precompile_compatibility_hash()
             .hash(&mut hasher);
+        // Pre-initialized modules must not be mixed up with the original ones
+        #[cfg(feature = "preinit")]
+        if preinit::enabled() {
+            preinit::PREINIT_ENV.hash(&mut hasher);
+        }
         Some(hasher.finish().to_string())
     }
+
+    fn info(&self) -> serde_json::Map<String, serde_json::Value> {
+        let features: Vec<&str> = [
+            ("chaos", cfg!(feature = "chaos")),
+            ("crypto", cfg!(feature = "crypto")),
+            ("preinit", cfg!(feature = "preinit")),
+        ]
+        .into_iter()
+        .filter_map(|(feature, enabled)| enabled.then_some(feature))
+        .collect();
+
+        let mut info = serde_json::Map::new();
+        // The version of wasmtime in the Cargo.lock of the build, see `build.rs`
+        info.insert("wasmtime".into(), env!("WASMTIME_VERSION").into());
+        info.insert("features".into(), features.into());
+        info.insert("interfaces".into(), linker::interfaces().into());
+        info
+    }
+
+    fn validate(&self, ctx: &impl RuntimeContext) -> Result<()> {
+        ResourceProfile::from_ctx(ctx)?;
+        #[cfg(target_os = "linux")]
+        MemoryDump::from_ctx(ctx)?;
+        Snapshots::from_ctx(ctx, layer_digest(ctx).as_deref())?;
+        InstantiationConfig::from_env()?;
+        ProxyConfig::from_ctx(ctx).context("invalid http proxy configuration")?;
+        tenant::from_ctx(ctx)?;
+        Guardrails::from_env()?;
+        Quarantine::from_env()?;
+        metrics::metrics_addr(ctx)?;
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn memory_dump_signal(&self, ctx: &impl RuntimeContext) -> Option<i32> {
+        ctx.annotations()
+            .contains_key(DUMP_DIR_ANNOTATION)
+            .then_some(DUMP_SIGNAL)
+    }
 }
 
 impl<T> WasmtimeEngine<T>
@@ -194,109 +680,205 @@ where
     ///
     /// This function adds wasi_preview1 to the linker and can be utilized
     /// to execute a wasm module that uses wasi_preview1.
-    fn execute_module(
+    #[tracing::instrument(skip_all, level = "info")]
+    async fn execute_module(
         &self,
         ctx: &impl RuntimeContext,
         module: Module,
         func: &String,
         stdio: Stdio,
     ) -> Result<i32> {
-        log::debug!("execute module");
-
-        let ctx = wasi_builder(ctx)?.build_p1();
-        let mut store = Store::new(&self.engine, ctx);
-        let mut module_linker = wasmtime::Linker::new(&self.engine);
-
-        log::debug!("init linker");
-        wasi_preview1::add_to_linker_async(&mut module_linker, |wasi_ctx: &mut WasiP1Ctx| {
-            wasi_ctx
-        })?;
-
-        wasmtime_wasi::runtime::in_tokio(async move {
-            log::info!("instantiating instance");
-            let instance: wasmtime::Instance =
-                module_linker.instantiate_async(&mut store, &module).await?;
+        tracing::debug!("execute module");
+
+        let profile = ResourceProfile::from_ctx(ctx)?;
+        let trap_ctx = TrapContext::from_ctx(ctx);
+        let snapshots = Snapshots::from_ctx(ctx, layer_digest(ctx).as_deref())?;
+        let time_slices = Priority::from_ctx(ctx)?.time_slices();
+        let audit = Audit::from_ctx(ctx)?;
+        if let Some(audit) = &audit {
+            let imports = module.imports();
+            audit.imports(imports.map(|import| format!("{}#{}", import.module(), import.name())));
+        }
+        let descriptor = WasiDescriptor::from_ctx(ctx)?;
+        let write_quotas = WriteQuotas::from_ctx(ctx)?;
+        let write_quotas = ModuleQuotas::new(write_quotas, &descriptor.preopens);
+        let wasi = wasi_builder(ctx, descriptor, audit)?;
+        let data = ModuleCtx {
+            wasi: wasi.build_p1(),
+            limits: profile.store_limits(),
+            write_quotas,
+            #[cfg(feature = "crypto")]
+            crypto: crypto::WasiCrypto::new(crypto::Crypto::from_ctx(ctx)?),
+        };
+        let mut store = Store::new(&self.engine, data);
+        match (memory_dumps(), &snapshots) {
+            (false, None) => store.epoch_deadline_async_yield_and_update(time_slices),
+            (_, snapshots) => {
+                let snapshots = snapshots.clone();
+                store.set_epoch_deadline(time_slices);
+                store.epoch_deadline_callback(move |store| {
+                    dump_memory(store.data().limits.store_id());
+                    if let Some(snapshots) = &snapshots {
+                        snapshots.on_epoch(&store);
+                    }
+                    Ok(UpdateDeadline::Yield(time_slices))
+                });
+            }
+        }
+        profile.limit_store(&mut store, |ctx| &mut ctx.limits)?;
+
+        tracing::info!("instantiating instance");
+        report_phase(Phase::Instantiating);
+        let instance: wasmtime::Instance = self
+            .linkers
+            .module
+            .instantiate_async(&mut store, &module)
+            .await?;
+
+        // The snapshots are only useful to the modules that can resume from them
+        let snapshots = snapshots.filter(|_| {
+            let resumable = instance.get_func(&mut store, RESUME_FUNC).is_some();
+            if !resumable {
+                tracing::warn!("snapshots are disabled, the module doesn't export {RESUME_FUNC:?}");
+            }
+            resumable
+        });
+        let mut restored = false;
+        if let Some(snapshots) = &snapshots {
+            let memory = instance
+                .get_memory(&mut store, "memory")
+                .context("module with snapshots does not export `memory`")?;
+            match snapshots.restore(&mut store, memory) {
+                Ok(Some(path)) => {
+                    tracing::info!("restored the memory snapshot {path:?}");
+                    restored = true;
+                }
+                Ok(None) => {}
+                Err(e) => tracing::warn!("cold-starting, failed to restore the memory: {e:?}"),
+            }
+            snapshots.set_memory(memory);
+            snapshots.schedule(&self.engine);
+        }
 
-            log::info!("getting start function");
-            let start_func = instance
-                .get_func(&mut store, func)
-                .context("module does not have a WASI start function")?;
+        tracing::info!("getting start function");
+        let func = if restored {
+            RESUME_FUNC.to_string()
+        } else {
+            resolve_func(ctx, func, |name| {
+                instance.get_func(&mut store, name).is_some()
+            })
+        };
+        let start_func = instance
+            .get_func(&mut store, &func)
+            .context("module does not have a WASI start function")?;
 
-            log::debug!("running start function {func:?}");
+        tracing::debug!("running start function {func:?}");
 
-            stdio.redirect()?;
+        stdio.redirect()?;
 
-            start_func
-                .call_async(&mut store, &[], &mut [])
-                .await
-                .into_error_code()
-        })
+        quarantine::instantiated();
+        report_phase(Phase::Serving);
+        let result = start_func.call_async(&mut store, &[], &mut []).await;
+        if let (Err(e), Some(trap_ctx)) = (&result, trap_ctx) {
+            trap_ctx.capture(e, None, profile.fuel_consumed(&store));
+        }
+        let status = result.into_error_code();
+        // The next start after a successful exit is a cold one
+        if let (Ok(0), Some(snapshots)) = (&status, &snapshots) {
+            snapshots.clear();
+        }
+        status
     }
 
+    #[tracing::instrument(skip_all, level = "info")]
     async fn execute_component_async(
         &self,
         ctx: &impl RuntimeContext,
         component: Component,
         func: String,
         stdio: Stdio,
+        optimized: Option<OptimizedProxy>,
     ) -> Result<i32> {
-        log::info!("instantiating component");
-
-        let target = ComponentTarget::new(
-            component.component_type().exports(&self.engine),
-            func.as_str(),
-        );
+        tracing::info!("instantiating component");
+        report_phase(Phase::Instantiating);
+
+        let digest = layer_digest(ctx);
+        let target = ComponentTarget::detect(&self.engine, &component, digest.as_deref());
+        tracing::info!("{COMPONENT_TARGET_KEY}={}", target.name());
+        if ctx.annotations().contains_key(SNAPSHOT_DIR_ANNOTATION) {
+            tracing::warn!("memory snapshots are not supported for components");
+        }
 
         stdio.redirect()?;
 
         // This is a adapter logic that converts wasip1 `_start` function to wasip2 `run` function.
         let status = match target {
             ComponentTarget::HttpProxy => {
-                log::info!("Found HTTP proxy target");
-                let mut linker = component::Linker::new(&self.engine);
-                wasmtime_wasi::add_to_linker_async(&mut linker)?;
-                wasmtime_wasi_http::add_only_http_to_linker_async(&mut linker)?;
+                tracing::info!("Found HTTP proxy target");
+                let instance = self.linkers.proxy_pre(&component)?;
 
-                let pre = linker.instantiate_pre(&component)?;
-                log::info!("pre-instantiate_pre");
-                let instance = ProxyPre::new(pre)?;
+                tracing::info!("starting HTTP server");
+                let cancel = self.cancel.clone();
+                serve_conn(ctx, instance, optimized, cancel).await
+            }
+            ComponentTarget::TcpHandler => {
+                tracing::info!("Found TCP handler target");
+                let pre = instantiate_pre(&self.linkers.wasi, &component)?;
 
-                log::info!("starting HTTP server");
+                tracing::info!("starting TCP server");
                 let cancel = self.cancel.clone();
-                serve_conn(ctx, instance, cancel).await
+                serve_tcp(ctx, &component, pre, cancel).await
             }
             ComponentTarget::Command => {
-                log::info!("Found command target");
+                tracing::info!("Found command target");
                 let wasi_ctx = WasiPreview2Ctx::new(ctx)?;
-                let (mut store, linker) = store_for_context(&self.engine, wasi_ctx)?;
-
-                let command = Command::instantiate_async(&mut store, &component, &linker).await?;
-
-                command
-                    .wasi_cli_run()
-                    .call_run(&mut store)
-                    .await?
-                    .map_err(|_| {
-                        anyhow::anyhow!(
-                            "failed to run component targeting `wasi:cli/command` world"
-                        )
-                    })
+                if let Some(audit) = &wasi_ctx.audit {
+                    audit.imports(component_imports(&self.engine, &component));
+                }
+                let profile = ResourceProfile::from_ctx(ctx)?;
+                let mut store = new_store(&self.engine, wasi_ctx, &profile)?;
+
+                let command =
+                    Command::instantiate_async(&mut store, &component, &self.linkers.command)
+                        .await
+                        .map_err(|e| explain_link_error(&self.engine, &component, e))?;
+                quarantine::instantiated();
+                report_phase(Phase::Serving);
+
+                let result = command.wasi_cli_run().call_run(&mut store).await;
+                if let (Err(e), Some(trap_ctx)) = (&result, TrapContext::from_ctx(ctx)) {
+                    trap_ctx.capture(e, None, profile.fuel_consumed(&store));
+                }
+                result?.map_err(|_| {
+                    anyhow::anyhow!("failed to run component targeting `wasi:cli/command` world")
+                })
             }
-            ComponentTarget::Core(func) => {
-                log::info!("Found Core target");
+            ComponentTarget::Core => {
+                tracing::info!("Found Core target");
+                let func = func.as_str();
                 let wasi_ctx = WasiPreview2Ctx::new(ctx)?;
-                let (mut store, linker) = store_for_context(&self.engine, wasi_ctx)?;
+                if let Some(audit) = &wasi_ctx.audit {
+                    audit.imports(component_imports(&self.engine, &component));
+                }
+                let profile = ResourceProfile::from_ctx(ctx)?;
+                let mut store = new_store(&self.engine, wasi_ctx, &profile)?;
 
-                let pre = linker.instantiate_pre(&component)?;
+                let pre = instantiate_pre(&self.linkers.command, &component)?;
                 let instance = pre.instantiate_async(&mut store).await?;
 
-                log::info!("getting component exported function {func:?}");
+                tracing::info!("getting component exported function {func:?}");
                 let start_func = instance.get_func(&mut store, func).context(format!(
                     "component does not have exported function {func:?}"
                 ))?;
 
-                log::debug!("running exported function {func:?} {start_func:?}");
-                start_func.call_async(&mut store, &[], &mut []).await
+                tracing::debug!("running exported function {func:?} {start_func:?}");
+                quarantine::instantiated();
+                report_phase(Phase::Serving);
+                let result = start_func.call_async(&mut store, &[], &mut []).await;
+                if let (Err(e), Some(trap_ctx)) = (&result, TrapContext::from_ctx(ctx)) {
+                    trap_ctx.capture(e, None, profile.fuel_consumed(&store));
+                }
+                result
             }
         };
 
@@ -307,31 +889,102 @@ where
     ///
     /// This function adds wasi_preview2 to the linker and can be utilized
     /// to execute a wasm component that uses wasi_preview2.
-    fn execute_component(
+    async fn execute_component(
         &self,
         ctx: &impl RuntimeContext,
         component: Component,
         func: String,
         stdio: Stdio,
+        optimized: Option<OptimizedProxy>,
     ) -> Result<i32> {
-        log::debug!("loading wasm component");
+        tracing::debug!("loading wasm component");
 
-        wasmtime_wasi::runtime::in_tokio(async move {
-            tokio::select! {
-                status = self.execute_component_async(ctx, component, func, stdio) => {
-                    status
-                }
-                status = self.handle_signals() => {
-                    status
-                }
+        tokio::select! {
+            status = self.execute_component_async(ctx, component, func, stdio, optimized) => {
+                status
             }
-        })
+            status = self.handle_signals() => {
+                status
+            }
+        }
+    }
+
+    /// The entry of the container in the code cache, if any.
+    #[cfg(unix)]
+    fn cache_entry(&self, ctx: &impl RuntimeContext) -> Option<code_cache::Entry> {
+        let cache = self.code_cache.as_ref()?;
+        let digest = layer_digest(ctx)?;
+        let mut compat = self.can_precompile()?;
+        // The tenants don't share their compiled code
+        if let Some(tenant) = &self.tenant {
+            compat = format!("{compat}/{tenant}");
+        }
+        Some(cache.entry(&compat, &digest))
+    }
+
+    /// The compiled code of the container in the code cache, compiling it on a miss.
+    #[cfg(unix)]
+    fn cached_code(
+        &self,
+        entry: &code_cache::Entry,
+        wasm_binary: &[u8],
+    ) -> Option<code_cache::CachedCode> {
+        let compile = || {
+            if self.engine.detect_precompiled(wasm_binary).is_some() {
+                return Ok(wasm_binary.to_vec());
+            }
+            report_phase(Phase::Compiling);
+            let compiled = match WasmBinaryType::from_bytes(wasm_binary) {
+                Some(WasmBinaryType::Module) => self.engine.precompile_module(wasm_binary)?,
+                Some(WasmBinaryType::Component) => self.engine.precompile_component(wasm_binary)?,
+                None => bail!("invalid wasm binary"),
+            };
+            report_phase(Phase::Compiled);
+            Ok(compiled)
+        };
+
+        match entry.get_or_compile(compile) {
+            Ok(code) => Some(code),
+            Err(e) => {
+                tracing::warn!("code cache is not available: {e:?}");
+                None
+            }
+        }
+    }
+
+    /// Execute the authenticated `code` of the code cache.
+    #[cfg(unix)]
+    async fn execute_cached(
+        &self,
+        ctx: &impl RuntimeContext,
+        code: code_cache::CachedCode,
+        func: String,
+        stdio: Stdio,
+    ) -> Result<i32> {
+        let path = code.path();
+        match self.engine.detect_precompiled_file(&path)? {
+            Some(Precompiled::Module) => {
+                // SAFETY: the code was compiled by the shim, and authenticated
+                let module = unsafe { Module::deserialize_file(&self.engine, &path) }?;
+                drop(code);
+                self.execute_module(ctx, module, &func, stdio).await
+            }
+            Some(Precompiled::Component) => {
+                // SAFETY: the code was compiled by the shim, and authenticated
+                let component = unsafe { Component::deserialize_file(&self.engine, &path) }?;
+                drop(code);
+                self.execute_component(ctx, component, func, stdio, None)
+                    .await
+            }
+            None => bail!("invalid cached code {path:?}"),
+        }
     }
 
     async fn handle_signals(&self) -> Result<i32> {
         match wait_for_signal().await? {
             libc::SIGINT => {
                 // Request graceful shutdown;
+                report_phase(Phase::Draining);
                 self.cancel.cancel();
             }
             sig => {
@@ -344,33 +997,92 @@ where
         wait_for_signal().await
     }
 
-    fn execute(
+    #[tracing::instrument(skip_all, level = "info")]
+    async fn execute(
         &self,
         ctx: &impl RuntimeContext,
         wasm_binary: &[u8],
         func: String,
         stdio: Stdio,
     ) -> Result<i32> {
+        Chaos::from_ctx(ctx)?.compile()?;
+
+        #[cfg(unix)]
+        let cache = self.cache_entry(ctx);
+        #[cfg(unix)]
+        let cached = match cache.as_ref().map(code_cache::Entry::get) {
+            Some(Ok(code)) => code,
+            Some(Err(e)) => {
+                tracing::warn!("ignoring cached code: {e:?}");
+                None
+            }
+            None => None,
+        };
+        #[cfg(not(unix))]
+        let cached: Option<std::convert::Infallible> = None;
+
+        let component = matches!(
+            WasmBinaryType::from_bytes(wasm_binary),
+            Some(WasmBinaryType::Component)
+        );
+        if component && ProxyConfig::from_ctx(ctx)?.tiered_start {
+            if ComponentTarget::from_binary(wasm_binary) != ComponentTarget::HttpProxy {
+                // Only HTTP proxies instantiate the component again, and can switch code
+                tracing::info!("tiered start only applies to HTTP proxies");
+            } else if cached.is_some() {
+                // The optimized code is ready, there is nothing to wait for
+                tracing::info!("starting with cached code instead of baseline code");
+            } else if let Some(baseline) = self.baseline(ctx)? {
+                report_phase(Phase::Compiling);
+                match Component::from_binary(&baseline.engine, wasm_binary) {
+                    Ok(component) => {
+                        tracing::info!("starting with baseline code");
+                        report_phase(Phase::Compiled);
+                        #[cfg(unix)]
+                        let optimized = self.compile_in_background(wasm_binary.to_vec(), cache);
+                        #[cfg(not(unix))]
+                        let optimized = self.compile_in_background(wasm_binary.to_vec());
+                        return baseline
+                            .execute_component(ctx, component, func, stdio, Some(optimized))
+                            .await;
+                    }
+                    // e.g. the component uses a proposal Winch doesn't support
+                    Err(e) => tracing::warn!("starting with optimized code: {e:?}"),
+                }
+            }
+        }
+
+        #[cfg(unix)]
+        if let Some(code) = cached.or_else(|| self.cached_code(cache.as_ref()?, wasm_binary)) {
+            return self.execute_cached(ctx, code, func, stdio).await;
+        }
+
         match WasmBinaryType::from_bytes(wasm_binary) {
             Some(WasmBinaryType::Module) => {
-                log::debug!("loading wasm module");
+                tracing::debug!("loading wasm module");
+                report_phase(Phase::Compiling);
                 let module = Module::from_binary(&self.engine, wasm_binary)?;
-                self.execute_module(ctx, module, &func, stdio)
+                report_phase(Phase::Compiled);
+                self.execute_module(ctx, module, &func, stdio).await
             }
             Some(WasmBinaryType::Component) => {
+                report_phase(Phase::Compiling);
                 let component = Component::from_binary(&self.engine, wasm_binary)?;
-                self.execute_component(ctx, component, func, stdio)
+                report_phase(Phase::Compiled);
+                self.execute_component(ctx, component, func, stdio, None)
+                    .await
             }
             None => match &self.engine.detect_precompiled(wasm_binary) {
                 Some(Precompiled::Module) => {
-                    log::info!("using precompiled module");
+                    tracing::info!("using precompiled module");
                     let module = unsafe { Module::deserialize(&self.engine, wasm_binary) }?;
-                    self.execute_module(ctx, module, &func, stdio)
+                    self.execute_module(ctx, module, &func, stdio).await
                 }
                 Some(Precompiled::Component) => {
-                    log::info!("using precompiled component");
+                    tracing::info!("using precompiled component");
                     let component = unsafe { Component::deserialize(&self.engine, wasm_binary) }?;
-                    self.execute_component(ctx, component, func, stdio)
+                    self.execute_component(ctx, component, func, stdio, None)
+                        .await
                 }
                 None => {
                     bail!("invalid precompiled module")
@@ -381,49 +1093,64 @@ where
 }
 
 pub(crate) fn envs_from_ctx(ctx: &impl RuntimeContext) -> Vec<(String, String)> {
-    ctx.envs()
-        .iter()
-        .map(|v| {
-            let (key, value) = v.split_once('=').unwrap_or((v.as_str(), ""));
-            (key.to_string(), value.to_string())
-        })
-        .collect()
+    parse_envs(ctx.envs())
 }
 
-fn store_for_context<T: wasi_preview2::WasiView>(
-    engine: &wasmtime::Engine,
-    ctx: T,
-) -> Result<(Store<T>, component::Linker<T>)> {
-    let store = Store::new(engine, ctx);
-
-    log::debug!("init linker");
-    let mut linker = component::Linker::new(engine);
-    wasi_preview2::add_to_linker_async(&mut linker)?;
-
-    Ok((store, linker))
-}
-
-fn wasi_builder(ctx: &impl RuntimeContext) -> Result<wasi_preview2::WasiCtxBuilder, anyhow::Error> {
-    // TODO: make this more configurable (e.g. allow the user to specify the
-    // preopened directories and their permissions)
-    // https://github.com/containerd/runwasi/issues/413
-    let file_perms = wasi_preview2::FilePerms::all();
-    let dir_perms = wasi_preview2::DirPerms::all();
-    let envs = envs_from_ctx(ctx);
+/// The builder of the WASI context of the container `ctx` described by `descriptor`, auditing
+/// its connections with `audit`.
+pub fn wasi_builder(
+    ctx: &impl RuntimeContext,
+    descriptor: WasiDescriptor,
+    audit: Option<Arc<Audit>>,
+) -> Result<wasi_preview2::WasiCtxBuilder, anyhow::Error> {
+    let WasiDescriptor {
+        args,
+        envs,
+        preopens,
+        socket_policy: policy,
+    } = descriptor;
 
     let mut builder = wasi_preview2::WasiCtxBuilder::new();
     builder
-        .args(ctx.args())
+        .args(&args)
         .envs(&envs)
         .inherit_stdio()
         .inherit_network()
         .allow_tcp(true)
         .allow_udp(true)
         .allow_ip_name_lookup(true)
-        .preopened_dir("/", "/", dir_perms, file_perms)?;
+        .socket_addr_check(move |addr, addr_use| {
+            let allowed = policy.check(addr, socket_use(addr_use));
+            if let Some(audit) = &audit {
+                audit.connect(addr, addr_use, allowed);
+            }
+            Box::pin(async move { allowed })
+        });
+    for preopen in preopens {
+        builder.preopened_dir(
+            preopen.host,
+            preopen.guest,
+            wasi_preview2::DirPerms::all(),
+            wasi_preview2::FilePerms::all(),
+        )?;
+    }
+    if let Some(trap_ctx) = TrapContext::from_ctx(ctx) {
+        builder.stderr(trap_ctx.stderr());
+    }
     Ok(builder)
 }
 
+fn socket_use(addr_use: wasi_preview2::SocketAddrUse) -> SocketUse {
+    use wasi_preview2::SocketAddrUse::*;
+    match addr_use {
+        TcpBind => SocketUse::TcpBind,
+        TcpConnect => SocketUse::TcpConnect,
+        UdpBind => SocketUse::UdpBind,
+        UdpConnect => SocketUse::UdpConnect,
+        UdpOutgoingDatagram => SocketUse::UdpOutgoingDatagram,
+    }
+}
+
 async fn wait_for_signal() -> Result<i32> {
     #[cfg(unix)]
     {
@@ -476,3 +1203,35 @@ impl IntoErrorCode for Result<()> {
         self.map(|_| 0).into_error_code()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target_from_binary() -> Result<()> {
+        let component = |export: &str| {
+            wat::parse_str(format!(
+                r#"(component
+                    (component (instance (export "wasi:cli/run@0.2.0")))
+                    (instance $handler)
+                    (export "{export}" (instance $handler))
+                )"#
+            ))
+        };
+        assert_eq!(
+            ComponentTarget::from_binary(&component("wasi:http/incoming-handler@0.2.0")?),
+            ComponentTarget::HttpProxy
+        );
+        assert_eq!(
+            ComponentTarget::from_binary(&component("wasi:cli/run@0.2.0")?),
+            ComponentTarget::Command
+        );
+        // The exports of the nested components don't count
+        assert_eq!(
+            ComponentTarget::from_binary(&component("other")?),
+            ComponentTarget::Core
+        );
+        Ok(())
+    }
+}
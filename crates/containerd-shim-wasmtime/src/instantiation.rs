@@ -0,0 +1,247 @@
+//! Instantiation cost knobs of the engine.
+//!
+//! The knobs are set with environment variables of the shim, and apply to all its containers:
+//! * `RUNWASI_WASMTIME_TABLE_LAZY_INIT`: initialize the function tables lazily, on first use
+//!   (default: true). Components with large element segments instantiate faster, which matters
+//!   most for HTTP proxies, where every request instantiates the component.
+//! * `RUNWASI_WASMTIME_MEMORY_INIT_COW`: map the initial linear memories copy-on-write from the
+//!   compiled artifact instead of copying the data segments (default: true).
+//! * `RUNWASI_WASMTIME_MEMORY_DENSE_IMAGE_SIZE`: size in bytes up to which sparse data segments are
+//!   still turned into a copy-on-write image (default: wasmtime default).
+//! * `RUNWASI_WASMTIME_KEEP_RESIDENT`: bytes of each memory and table of the pooling allocator
+//!   reset with `memset` rather than `madvise`, keeping them resident between instances
+//!   (default: 0).
+//! * `RUNWASI_WASMTIME_PREFAULT`: touch the memories of the pooling allocator up to the
+//!   keep-resident size when the engine is created (default: false), so that latency-sensitive
+//!   deployments pay the page faults at startup rather than when serving requests. This needs
+//!   `RUNWASI_WASMTIME_KEEP_RESIDENT` of at least one wasm page: the pages past the keep-resident
+//!   size are decommitted when an instance is torn down, so they would fault again anyway. The
+//!   address space of the pool is reserved by wasmtime with `MAP_NORESERVE`, which can't be
+//!   turned off, so the rest of the memories is not committed.
+//! * `RUNWASI_WASMTIME_DECOMMIT_BATCH_SIZE`: number of memories of the pooling allocator freed
+//!   with `madvise(MADV_DONTNEED)` together (default: 1, i.e., as soon as they are torn down).
+//! * `RUNWASI_WASMTIME_MAX_UNUSED_WARM_SLOTS`: number of unused slots of the pooling allocator
+//!   kept warm for the next instances (default: 100). Lower values return the memory of
+//!   high-churn HTTP proxies to the OS faster.
+//! * `RUNWASI_WASMTIME_HUGE_PAGES`: back the linear memories with transparent huge pages
+//!   (default: false), see [`HugePageMemoryCreator`](crate::huge_pages::HugePageMemoryCreator).
+//!   This replaces the pooling allocator, and is only available on Linux.
+//!
+//! Changing these knobs changes the precompilation hash, so precompiled images are recompiled.
+
+use std::fmt::Display;
+use std::future::Future;
+use std::pin::pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+
+use anyhow::{bail, ensure, Result};
+use wasmtime::{Config, Engine, Instance, Memory, Module, PoolingAllocationConfig, Store};
+
+#[cfg(target_os = "linux")]
+use crate::huge_pages::HugePageMemoryCreator;
+
+pub const TABLE_LAZY_INIT_ENV: &str = "RUNWASI_WASMTIME_TABLE_LAZY_INIT";
+pub const MEMORY_INIT_COW_ENV: &str = "RUNWASI_WASMTIME_MEMORY_INIT_COW";
+pub const MEMORY_DENSE_IMAGE_SIZE_ENV: &str = "RUNWASI_WASMTIME_MEMORY_DENSE_IMAGE_SIZE";
+pub const KEEP_RESIDENT_ENV: &str = "RUNWASI_WASMTIME_KEEP_RESIDENT";
+pub const PREFAULT_ENV: &str = "RUNWASI_WASMTIME_PREFAULT";
+pub const DECOMMIT_BATCH_SIZE_ENV: &str = "RUNWASI_WASMTIME_DECOMMIT_BATCH_SIZE";
+pub const MAX_UNUSED_WARM_SLOTS_ENV: &str = "RUNWASI_WASMTIME_MAX_UNUSED_WARM_SLOTS";
+pub const HUGE_PAGES_ENV: &str = "RUNWASI_WASMTIME_HUGE_PAGES";
+
+const WASM_PAGE_SIZE: usize = 64 * 1024;
+const OS_PAGE_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstantiationConfig {
+    pub table_lazy_init: bool,
+    pub memory_init_cow: bool,
+    pub memory_dense_image_size: Option<u64>,
+    pub keep_resident: Option<usize>,
+    pub prefault: bool,
+    pub decommit_batch_size: Option<usize>,
+    pub max_unused_warm_slots: Option<u32>,
+    pub huge_pages: bool,
+}
+
+impl Default for InstantiationConfig {
+    fn default() -> Self {
+        Self {
+            table_lazy_init: true,
+            memory_init_cow: true,
+            memory_dense_image_size: None,
+            keep_resident: None,
+            prefault: false,
+            decommit_batch_size: None,
+            max_unused_warm_slots: None,
+            huge_pages: false,
+        }
+    }
+}
+
+impl InstantiationConfig {
+    /// Read the knobs from the environment of the shim.
+    pub fn from_env() -> Result<Self> {
+        Self::from_lookup(|name| std::env::var(name).ok())
+    }
+
+    fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let defaults = Self::default();
+        let config = Self {
+            table_lazy_init: parse(&lookup, TABLE_LAZY_INIT_ENV)?
+                .unwrap_or(defaults.table_lazy_init),
+            memory_init_cow: parse(&lookup, MEMORY_INIT_COW_ENV)?
+                .unwrap_or(defaults.memory_init_cow),
+            memory_dense_image_size: parse(&lookup, MEMORY_DENSE_IMAGE_SIZE_ENV)?,
+            keep_resident: parse(&lookup, KEEP_RESIDENT_ENV)?,
+            prefault: parse(&lookup, PREFAULT_ENV)?.unwrap_or(defaults.prefault),
+            decommit_batch_size: parse(&lookup, DECOMMIT_BATCH_SIZE_ENV)?,
+            max_unused_warm_slots: parse(&lookup, MAX_UNUSED_WARM_SLOTS_ENV)?,
+            huge_pages: parse(&lookup, HUGE_PAGES_ENV)?.unwrap_or(defaults.huge_pages),
+        };
+        ensure!(
+            !config.prefault || config.keep_resident.unwrap_or_default() >= WASM_PAGE_SIZE,
+            "{PREFAULT_ENV} needs {KEEP_RESIDENT_ENV} of at least one wasm page"
+        );
+        Ok(config)
+    }
+
+    pub fn configure(&self, config: &mut Config) {
+        config.table_lazy_init(self.table_lazy_init);
+        config.memory_init_cow(self.memory_init_cow);
+        if let Some(size) = self.memory_dense_image_size {
+            config.memory_guaranteed_dense_image_size(size);
+        }
+        if self.huge_pages {
+            #[cfg(target_os = "linux")]
+            config.with_host_memory(Arc::new(HugePageMemoryCreator));
+            #[cfg(not(target_os = "linux"))]
+            tracing::warn!("{HUGE_PAGES_ENV} is only available on Linux");
+        }
+    }
+
+    pub fn configure_pooling(&self, config: &mut PoolingAllocationConfig) {
+        if let Some(size) = self.keep_resident {
+            config
+                .linear_memory_keep_resident(size)
+                .table_keep_resident(size);
+        }
+        if let Some(size) = self.decommit_batch_size {
+            config.decommit_batch_size(size);
+        }
+        if let Some(slots) = self.max_unused_warm_slots {
+            config.max_unused_warm_slots(slots);
+        }
+    }
+
+    /// Touch the kept resident pages of up to `slots` memories of the pooling allocator of
+    /// `engine`, if pre-faulting is enabled.
+    pub fn prefault(&self, engine: &Engine, slots: u32) {
+        let (true, Some(size)) = (self.prefault, self.keep_resident) else {
+            return;
+        };
+        match touch_memories(engine, size / WASM_PAGE_SIZE, slots) {
+            Ok(touched) => tracing::info!("prefaulted {touched} memories of {size} bytes"),
+            Err(e) => tracing::warn!("failed to prefault the memories: {e:?}"),
+        }
+    }
+}
+
+/// Instantiate `slots` memories of `pages` together, each in a different slot of the pool, and
+/// write to every OS page of them. Returns the number of memories touched, which is less than
+/// `slots` when the pool is smaller.
+///
+/// The memories are the ones of a module without imports nor start function, as the memories of
+/// the pool are only allocated to instances. Their instantiation doesn't run any wasm, so it
+/// completes without yielding, without a runtime for the async engine.
+fn touch_memories(engine: &Engine, pages: usize, slots: u32) -> Result<u32> {
+    let module = Module::new(
+        engine,
+        format!("(module (memory (export \"memory\") {pages}))"),
+    )?;
+    let mut stores = vec![];
+    for _ in 0..slots {
+        let mut store = Store::new(engine, ());
+        let Ok(instance) = poll_once(Instance::new_async(&mut store, &module, &[]))? else {
+            break;
+        };
+        let memory: Memory = instance
+            .get_memory(&mut store, "memory")
+            .expect("memory is exported");
+        for byte in memory.data_mut(&mut store).iter_mut().step_by(OS_PAGE_SIZE) {
+            *byte = 1;
+        }
+        stores.push(store);
+    }
+    Ok(stores.len() as u32)
+}
+
+/// Poll `future` once, for futures which complete without yielding.
+fn poll_once<F: Future>(future: F) -> Result<F::Output> {
+    struct NoopWake;
+    impl Wake for NoopWake {
+        fn wake(self: Arc<Self>) {}
+    }
+    let waker = Waker::from(Arc::new(NoopWake));
+    match pin!(future).poll(&mut Context::from_waker(&waker)) {
+        Poll::Ready(output) => Ok(output),
+        Poll::Pending => bail!("the future yielded"),
+    }
+}
+
+fn parse<T>(lookup: &impl Fn(&str) -> Option<String>, name: &str) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let Some(value) = lookup(name) else {
+        return Ok(None);
+    };
+    match value.trim().parse() {
+        Ok(value) => Ok(Some(value)),
+        Err(e) => bail!("invalid {name} {value:?}: {e}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_lookup() -> Result<()> {
+        let config = InstantiationConfig::from_lookup(|_| None)?;
+        assert_eq!(config, InstantiationConfig::default());
+
+        let config = InstantiationConfig::from_lookup(|name| match name {
+            TABLE_LAZY_INIT_ENV => Some("false".to_string()),
+            KEEP_RESIDENT_ENV => Some("65536".to_string()),
+            PREFAULT_ENV => Some("true".to_string()),
+            _ => None,
+        })?;
+        assert!(!config.table_lazy_init);
+        assert!(config.memory_init_cow);
+        assert_eq!(config.keep_resident, Some(65536));
+        assert!(config.prefault);
+
+        let invalid = InstantiationConfig::from_lookup(|name| {
+            (name == MEMORY_INIT_COW_ENV).then(|| "yes".to_string())
+        });
+        assert!(invalid.is_err());
+
+        // Only the kept resident pages are pre-faulted
+        let invalid = InstantiationConfig::from_lookup(|name| {
+            (name == PREFAULT_ENV).then(|| "true".to_string())
+        });
+        assert!(invalid.is_err());
+        let invalid = InstantiationConfig::from_lookup(|name| match name {
+            KEEP_RESIDENT_ENV => Some("4096".to_string()),
+            PREFAULT_ENV => Some("true".to_string()),
+            _ => None,
+        });
+        assert!(invalid.is_err());
+
+        Ok(())
+    }
+}
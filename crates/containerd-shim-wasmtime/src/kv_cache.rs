@@ -0,0 +1,245 @@
+//! In-memory key-value cache shared by the instances of a container.
+//!
+//! Unlike `wasi:keyvalue`, nothing is persisted: the cache lives in the shim and is lost when the
+//! container stops. It lets the instances of a `wasi:http/proxy` component, created for each
+//! request, memoize results across requests without an external service. The guest imports the
+//! `runwasi:cache/cache` host interface.
+//!
+//! The cache holds up to 16 MiB of keys and values by default, which the
+//! `runwasi.io/kv-cache-size` annotation changes, in bytes. When the cache is full, the expired
+//! entries are evicted first, then the least recently used ones.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use containerd_shim_wasm::container::RuntimeContext;
+use wasmtime::component::Linker;
+use wasmtime::StoreContextMut;
+
+use crate::instance::WasiPreview2Ctx;
+
+pub const KV_CACHE_SIZE_ANNOTATION: &str = "runwasi.io/kv-cache-size";
+
+pub(crate) const INTERFACE: &str = "runwasi:cache/cache@0.1.0";
+
+const DEFAULT_SIZE: usize = 16 * 1024 * 1024;
+
+struct Entry {
+    value: Vec<u8>,
+    expires: Option<Instant>,
+    last_used: u64,
+}
+
+impl Entry {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+#[derive(Default)]
+struct Entries {
+    entries: HashMap<String, Entry>,
+    size: usize,
+    tick: u64,
+}
+
+impl Entries {
+    fn remove(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        self.size -= key.len() + entry.value.len();
+        Some(entry)
+    }
+}
+
+/// The key-value cache of a container.
+pub struct KvCache {
+    size: usize,
+    entries: Mutex<Entries>,
+}
+
+impl Default for KvCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_SIZE)
+    }
+}
+
+impl KvCache {
+    /// A cache of up to `size` bytes of keys and values.
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            entries: Default::default(),
+        }
+    }
+
+    /// The cache of the container, sized by its annotations.
+    pub fn from_ctx(ctx: &impl RuntimeContext) -> Result<Arc<Self>> {
+        let size = ctx
+            .annotations()
+            .get(KV_CACHE_SIZE_ANNOTATION)
+            .map(|v| v.trim().parse())
+            .transpose()
+            .with_context(|| format!("invalid {KV_CACHE_SIZE_ANNOTATION}"))?
+            .unwrap_or(DEFAULT_SIZE);
+        Ok(Arc::new(Self::new(size)))
+    }
+
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        self.get_at(key, Instant::now())
+    }
+
+    fn get_at(&self, key: &str, now: Instant) -> Option<Vec<u8>> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.tick += 1;
+        let tick = entries.tick;
+
+        let entry = entries.entries.get_mut(key)?;
+        if entry.is_expired(now) {
+            entries.remove(key);
+            return None;
+        }
+        entry.last_used = tick;
+        Some(entry.value.clone())
+    }
+
+    /// Set `key` to `value`, for `ttl` or until evicted.
+    pub fn set(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>) -> Result<(), String> {
+        self.set_at(key, value, ttl, Instant::now())
+    }
+
+    fn set_at(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+        now: Instant,
+    ) -> Result<(), String> {
+        let size = key.len() + value.len();
+        if size > self.size {
+            return Err(format!(
+                "entry of {size} bytes is larger than the cache of {} bytes",
+                self.size
+            ));
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.tick += 1;
+        let entry = Entry {
+            value,
+            expires: ttl.and_then(|ttl| now.checked_add(ttl)),
+            last_used: entries.tick,
+        };
+        entries.remove(key);
+        entries.entries.insert(key.to_string(), entry);
+        entries.size += size;
+
+        while entries.size > self.size {
+            // Evict an expired entry, or the least recently used one
+            let Some(key) = entries
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| (!entry.is_expired(now), entry.last_used))
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            entries.remove(&key);
+        }
+        Ok(())
+    }
+
+    pub fn delete(&self, key: &str) {
+        self.entries.lock().unwrap().remove(key);
+    }
+}
+
+/// Add the `runwasi:cache/cache` interface to the linker.
+///
+/// The interface exports three functions, a `ttl-ms` of 0 never expiring:
+/// `get: func(key: string) -> option<list<u8>>`
+/// `set: func(key: string, value: list<u8>, ttl-ms: u64) -> result<_, string>`
+/// `delete: func(key: string)`
+pub(crate) fn add_to_linker(linker: &mut Linker<WasiPreview2Ctx>) -> Result<()> {
+    let mut instance = linker.instance(INTERFACE)?;
+    instance.func_wrap(
+        "get",
+        |store: StoreContextMut<'_, WasiPreview2Ctx>,
+         (key,): (String,)|
+         -> Result<(Option<Vec<u8>>,)> { Ok((store.data().kv_cache.get(&key),)) },
+    )?;
+    instance.func_wrap(
+        "set",
+        |store: StoreContextMut<'_, WasiPreview2Ctx>,
+         (key, value, ttl_ms): (String, Vec<u8>, u64)|
+         -> Result<(Result<(), String>,)> {
+            let ttl = (ttl_ms > 0).then(|| Duration::from_millis(ttl_ms));
+            Ok((store.data().kv_cache.set(&key, value, ttl),))
+        },
+    )?;
+    instance.func_wrap(
+        "delete",
+        |store: StoreContextMut<'_, WasiPreview2Ctx>, (key,): (String,)| -> Result<()> {
+            store.data().kv_cache.delete(&key);
+            Ok(())
+        },
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ttl() {
+        let cache = KvCache::default();
+        let now = Instant::now();
+        let ttl = Some(Duration::from_secs(10));
+
+        cache.set_at("a", b"1".to_vec(), ttl, now).unwrap();
+        cache.set_at("b", b"2".to_vec(), None, now).unwrap();
+        assert_eq!(cache.get_at("a", now), Some(b"1".to_vec()));
+
+        let later = now + Duration::from_secs(11);
+        assert_eq!(cache.get_at("a", later), None);
+        assert_eq!(cache.get_at("b", later), Some(b"2".to_vec()));
+
+        cache.delete("b");
+        assert_eq!(cache.get_at("b", later), None);
+        assert_eq!(cache.entries.lock().unwrap().size, 0);
+    }
+
+    #[test]
+    fn test_eviction() {
+        // Room for three entries of 4 bytes
+        let cache = KvCache::new(12);
+        let now = Instant::now();
+
+        cache.set_at("a", b"aaa".to_vec(), None, now).unwrap();
+        cache.set_at("b", b"bbb".to_vec(), None, now).unwrap();
+        cache
+            .set_at("c", b"ccc".to_vec(), Some(Duration::from_secs(1)), now)
+            .unwrap();
+        cache.get_at("a", now).unwrap();
+
+        // The expired entry is evicted first
+        let later = now + Duration::from_secs(2);
+        cache.set_at("d", b"ddd".to_vec(), None, later).unwrap();
+        assert!(cache.get_at("a", later).is_some());
+        assert!(cache.get_at("b", later).is_some());
+
+        // Then the least recently used one
+        cache.set_at("e", b"eee".to_vec(), None, later).unwrap();
+        assert!(cache.get_at("d", later).is_none());
+        assert!(cache.get_at("a", later).is_some());
+
+        // Replacing an entry doesn't count it twice
+        cache.set_at("a", b"AAA".to_vec(), None, later).unwrap();
+        assert_eq!(cache.entries.lock().unwrap().size, 12);
+
+        assert!(cache.set_at("f", vec![0; 12], None, later).is_err());
+    }
+}
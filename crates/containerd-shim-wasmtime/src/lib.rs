@@ -1,7 +1,46 @@
-mod http_proxy;
+pub mod audit;
+pub mod chaos;
+#[cfg(unix)]
+mod code_cache;
+#[cfg(feature = "crypto")]
+pub mod crypto;
+mod diagnostics;
+mod epoch;
+mod filesystem;
+#[cfg(unix)]
+pub mod fs_events;
+pub mod guardrails;
+pub mod http_proxy;
+#[cfg(target_os = "linux")]
+pub mod huge_pages;
 pub mod instance;
+pub mod instantiation;
+pub mod kv_cache;
+mod linker;
+pub mod memory;
+#[cfg(target_os = "linux")]
+mod memory_dump;
+mod metrics;
+pub mod options;
+pub mod outgoing;
+#[cfg(feature = "preinit")]
+mod preinit;
+pub mod profile;
+pub mod quarantine;
+#[cfg(unix)]
+mod reload;
+mod snapshot;
+mod sockets;
+mod tcp_handler;
+pub mod tenant;
+pub mod timezone;
+#[cfg(unix)]
+pub mod unix_sockets;
+pub mod write_quota;
 
-pub use instance::WasmtimeInstance;
+pub use http_proxy::{ComponentRoute, ProxyConfig};
+pub use instance::{WasiPreview2Ctx, WasiPreview2CtxBuilder, WasmtimeInstance};
+pub use options::WasmtimeOptions;
 
 #[cfg(unix)]
 #[cfg(test)]
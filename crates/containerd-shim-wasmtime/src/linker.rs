@@ -0,0 +1,372 @@
+//! Linkers shared by the containers of an engine.
+//!
+//! Adding the WASI and WASI/HTTP host functions to a linker defines hundreds of functions. Instead
+//! of doing so on every container start, the linkers are built once with the engine, and only the
+//! components are resolved against them when a container starts.
+//!
+//! The linkers define the interfaces of the WASI release of wasmtime, [`WASI_LINKED`]. The worlds
+//! of the previous releases of WASI 0.2, [`WASI_PREVIOUS`], are linked onto the same bindings: the
+//! imports of a component built against them, e.g. `wasi:http/types@0.2.0`, resolve to the semver
+//! compatible interfaces of the linker, which only add functions across patch releases. The images
+//! built against slightly older worlds keep running across shim upgrades.
+//!
+//! The components that fail to link get a diagnostic listing their unresolved imports, with the
+//! missing function or resource when the interface is only partially defined, and the closest
+//! interface defined by the shim, e.g.
+//! `{"interface":"wasi:http/types@0.3.0","item":null,"reason":"...","closest":"wasi:http/types@0.2.2"}`.
+//! It is logged, and added to the error the container fails with.
+
+use std::sync::OnceLock;
+
+use anyhow::Result;
+use serde::Serialize;
+use wasmtime::component::{self, Component, InstancePre};
+use wasmtime::Engine;
+use wasmtime_wasi::preview1::{self as wasi_preview1, WasiP1Ctx};
+use wasmtime_wasi_http::bindings::ProxyPre;
+
+use crate::audit::component_imports;
+#[cfg(feature = "crypto")]
+use crate::crypto;
+use crate::instance::WasiPreview2Ctx;
+use crate::memory::AccountedLimits;
+use crate::tcp_handler::TCP_HANDLER_INTERFACE;
+use crate::write_quota::ModuleQuotas;
+use crate::{filesystem, kv_cache, sockets, timezone, write_quota};
+#[cfg(unix)]
+use crate::{fs_events, unix_sockets};
+
+/// The WASI release whose interfaces are linked, compatible with its other patch releases.
+const WASI_RELEASE: &str = "0.2";
+/// The WASI release whose bindings are linked, the one of wasmtime.
+const WASI_LINKED: &str = "0.2.2";
+/// The previous WASI releases whose worlds are linked onto the bindings of [`WASI_LINKED`].
+const WASI_PREVIOUS: &[&str] = &["0.2.0", "0.2.1"];
+/// The WASI worlds linked at every release.
+const WASI_WORLDS: &[&str] = &["wasi:cli/command", "wasi:http/proxy"];
+
+/// The worlds and interfaces defined by the linkers, as reported in the info of the shim.
+pub(crate) fn interfaces() -> Vec<&'static str> {
+    let mut interfaces = wasi_worlds();
+    interfaces.extend([
+        TCP_HANDLER_INTERFACE,
+        timezone::INTERFACE,
+        kv_cache::INTERFACE,
+    ]);
+    #[cfg(unix)]
+    interfaces.extend([unix_sockets::INTERFACE, fs_events::INTERFACE]);
+    interfaces
+}
+
+/// The WASI worlds linked, at the previous releases and at the linked one, e.g.
+/// `wasi:http/proxy@0.2.0`.
+fn wasi_worlds() -> Vec<&'static str> {
+    static WORLDS: OnceLock<Vec<String>> = OnceLock::new();
+    let worlds = WORLDS.get_or_init(|| {
+        WASI_WORLDS
+            .iter()
+            .flat_map(|world| {
+                let releases = WASI_PREVIOUS.iter().chain([&WASI_LINKED]);
+                releases.map(move |release| format!("{world}@{release}"))
+            })
+            .collect()
+    });
+    worlds.iter().map(String::as_str).collect()
+}
+
+/// The store data of wasm modules.
+pub(crate) struct ModuleCtx {
+    pub wasi: WasiP1Ctx,
+    pub limits: AccountedLimits,
+    pub write_quotas: ModuleQuotas,
+    /// The wasi-crypto handles of the module.
+    #[cfg(feature = "crypto")]
+    pub crypto: crypto::WasiCrypto,
+}
+
+pub(crate) struct Linkers {
+    /// WASI preview 1, and wasi-crypto with the `crypto` feature, for modules.
+    pub module: wasmtime::Linker<ModuleCtx>,
+    /// WASI preview 2, for TCP handlers.
+    pub wasi: component::Linker<WasiPreview2Ctx>,
+    /// WASI preview 2, unix sockets and filesystem events, for commands and core functions.
+    pub command: component::Linker<WasiPreview2Ctx>,
+    /// WASI preview 2, WASI/HTTP and filesystem events, for HTTP proxies.
+    pub proxy: component::Linker<WasiPreview2Ctx>,
+}
+
+impl Linkers {
+    #[tracing::instrument(name = "linker_setup", skip_all, level = "info")]
+    pub fn new(engine: &Engine) -> Result<Self> {
+        let mut module = wasmtime::Linker::new(engine);
+        wasi_preview1::add_to_linker_async(&mut module, |ctx: &mut ModuleCtx| &mut ctx.wasi)?;
+        write_quota::add_to_linker(&mut module)?;
+        #[cfg(feature = "crypto")]
+        crypto::add_to_linker(&mut module)?;
+
+        let mut wasi = component::Linker::new(engine);
+        wasmtime_wasi::add_to_linker_async(&mut wasi)?;
+        filesystem::add_to_linker(&mut wasi)?;
+        sockets::add_to_linker(&mut wasi)?;
+        timezone::add_to_linker(&mut wasi)?;
+        kv_cache::add_to_linker(&mut wasi)?;
+
+        #[allow(unused_mut)]
+        let mut command = wasi.clone();
+        #[cfg(unix)]
+        {
+            unix_sockets::add_to_linker(&mut command)?;
+            fs_events::add_to_linker(&mut command)?;
+        }
+
+        Ok(Self {
+            module,
+            proxy: proxy_linker(engine)?,
+            wasi,
+            command,
+        })
+    }
+
+    /// Pre-instantiate the HTTP proxy `component`.
+    pub fn proxy_pre(&self, component: &Component) -> Result<ProxyPre<WasiPreview2Ctx>> {
+        proxy_pre(&self.proxy, component)
+    }
+}
+
+/// A linker for HTTP proxies of `engine`.
+pub(crate) fn proxy_linker(engine: &Engine) -> Result<component::Linker<WasiPreview2Ctx>> {
+    let mut linker = component::Linker::new(engine);
+    wasmtime_wasi::add_to_linker_async(&mut linker)?;
+    wasmtime_wasi_http::add_only_http_to_linker_async(&mut linker)?;
+    timezone::add_to_linker(&mut linker)?;
+    kv_cache::add_to_linker(&mut linker)?;
+    #[cfg(unix)]
+    fs_events::add_to_linker(&mut linker)?;
+    Ok(linker)
+}
+
+/// Pre-instantiate the HTTP proxy `component` with `linker`.
+pub(crate) fn proxy_pre(
+    linker: &component::Linker<WasiPreview2Ctx>,
+    component: &Component,
+) -> Result<ProxyPre<WasiPreview2Ctx>> {
+    let pre = instantiate_pre(linker, component)?;
+    tracing::info!("pre-instantiate_pre");
+    ProxyPre::new(pre)
+}
+
+/// Pre-instantiate `component` with `linker`.
+pub(crate) fn instantiate_pre<T>(
+    linker: &component::Linker<T>,
+    component: &Component,
+) -> Result<InstancePre<T>> {
+    linker
+        .instantiate_pre(component)
+        .map_err(|e| explain_link_error(linker.engine(), component, e))
+}
+
+/// An import of a component that couldn't be resolved by the linker.
+#[derive(Debug, PartialEq, Serialize)]
+struct UnresolvedImport {
+    /// The imported interface, e.g. `wasi:http/types@0.2.0`.
+    interface: String,
+    /// The missing function or resource, when the interface is defined.
+    item: Option<String>,
+    reason: String,
+    /// The closest interface defined by the shim, if any.
+    closest: Option<String>,
+}
+
+/// `error` of linking `component`, with the diagnostic of its unresolved imports, if any.
+pub(crate) fn explain_link_error(
+    engine: &Engine,
+    component: &Component,
+    error: anyhow::Error,
+) -> anyhow::Error {
+    let unresolved = unresolved_imports(&component_imports(engine, component), &error);
+    if unresolved.is_empty() {
+        return error;
+    }
+    match serde_json::to_string(&unresolved) {
+        Ok(diagnostic) => tracing::error!("unresolved imports: {diagnostic}"),
+        Err(e) => tracing::warn!("failed to serialize the unresolved imports: {e}"),
+    }
+    let lines: Vec<String> = unresolved
+        .iter()
+        .map(|import| {
+            let item = import
+                .item
+                .as_ref()
+                .map(|item| format!(" `{item}`"))
+                .unwrap_or_default();
+            let closest = import
+                .closest
+                .as_ref()
+                .map(|closest| format!(", the shim defines `{closest}`"))
+                .unwrap_or_default();
+            format!("`{}`{item}: {}{closest}", import.interface, import.reason)
+        })
+        .collect();
+    error.context(format!("unresolved imports: {}", lines.join("; ")))
+}
+
+/// The unresolved imports among `imports`: the one reported by wasmtime in `error`, which stops at
+/// the first, and the WASI imports of an incompatible version.
+fn unresolved_imports(imports: &[String], error: &anyhow::Error) -> Vec<UnresolvedImport> {
+    let mut unresolved = vec![];
+    let (mut interface, mut item) = (None, None);
+    for cause in error.chain().map(ToString::to_string) {
+        if cause.starts_with("component imports") && interface.is_none() {
+            interface = quoted(&cause);
+        } else if cause.starts_with("instance export") && item.is_none() {
+            item = quoted(&cause);
+        }
+    }
+    if let Some(interface) = interface {
+        unresolved.push(UnresolvedImport {
+            closest: closest(&interface),
+            reason: error.root_cause().to_string(),
+            interface,
+            item,
+        });
+    }
+
+    for import in imports {
+        if !is_compatible(import) && unresolved.iter().all(|u| &u.interface != import) {
+            unresolved.push(UnresolvedImport {
+                interface: import.clone(),
+                item: None,
+                reason: format!("incompatible with the WASI {WASI_RELEASE} interfaces"),
+                closest: closest(import),
+            });
+        }
+    }
+    unresolved
+}
+
+/// The first name quoted with backticks in `message`.
+fn quoted(message: &str) -> Option<String> {
+    let (_, rest) = message.split_once('`')?;
+    let (name, _) = rest.split_once('`')?;
+    Some(name.to_string())
+}
+
+/// The interface of the shim closest to `import`: the same interface of the version of its
+/// package linked by the shim, e.g. `wasi:http/types@0.2.2` for `wasi:http/types@0.3.0`.
+fn closest(import: &str) -> Option<String> {
+    let (name, _) = import.split_once('@')?;
+    let (package, _) = name.split_once('/')?;
+    // The WASI packages of a release share its version
+    if package.starts_with("wasi:") {
+        return Some(format!("{name}@{WASI_LINKED}"));
+    }
+    let interfaces = interfaces();
+    let version = interfaces
+        .iter()
+        .find(|interface| interface.starts_with(&format!("{package}/")))?
+        .split_once('@')?
+        .1;
+    Some(format!("{name}@{version}"))
+}
+
+/// Whether the WASI interface `import`, e.g. `wasi:http/types@0.2.0`, can be linked.
+fn is_compatible(import: &str) -> bool {
+    let Some((package, version)) = import.split_once('@') else {
+        return true;
+    };
+    if !package.starts_with("wasi:") {
+        return true;
+    }
+    // The pre-releases aren't compatible with any other version
+    version
+        .strip_prefix(WASI_RELEASE)
+        .is_some_and(|patch| patch.starts_with('.') && !patch.contains('-'))
+}
+
+#[cfg(test)]
+mod tests {
+    use containerd_shim_wasm::testing::modules::{
+        COMPONENT_HELLO_WORLD, HELLO_WASI_HTTP, HELLO_WASI_HTTP_CSHARP,
+    };
+    use wasmtime::Config;
+
+    use super::*;
+
+    #[test]
+    fn test_is_compatible() {
+        assert!(is_compatible("wasi:http/types@0.2.0"));
+        assert!(is_compatible("wasi:cli/environment@0.2.3"));
+        assert!(is_compatible("runwasi:cache/cache@0.1.0"));
+        assert!(!is_compatible("wasi:http/types@0.2.0-rc-2023-12-05"));
+        assert!(!is_compatible("wasi:http/types@0.3.0"));
+        assert!(!is_compatible("wasi:io/streams@0.20.0"));
+    }
+
+    #[test]
+    fn test_unresolved_imports() {
+        let error = anyhow::anyhow!("function implementation is missing")
+            .context("instance export `[method]fields.clone` has the wrong type")
+            .context(
+                "component imports instance `wasi:http/types@0.2.9`, but a matching \
+                 implementation was not found in the linker",
+            );
+        let imports = [
+            "wasi:http/types@0.2.9".to_string(),
+            "wasi:io/streams@0.3.0".to_string(),
+            "wasi:cli/environment@0.2.0".to_string(),
+        ];
+        assert_eq!(
+            unresolved_imports(&imports, &error),
+            [
+                UnresolvedImport {
+                    interface: "wasi:http/types@0.2.9".into(),
+                    item: Some("[method]fields.clone".into()),
+                    reason: "function implementation is missing".into(),
+                    closest: Some("wasi:http/types@0.2.2".into()),
+                },
+                UnresolvedImport {
+                    interface: "wasi:io/streams@0.3.0".into(),
+                    item: None,
+                    reason: "incompatible with the WASI 0.2 interfaces".into(),
+                    closest: Some("wasi:io/streams@0.2.2".into()),
+                },
+            ]
+        );
+        assert_eq!(closest("acme:kv/store@1.0.0"), None);
+    }
+
+    #[test]
+    fn test_wasi_worlds() {
+        let worlds = wasi_worlds();
+        assert!(worlds.contains(&"wasi:http/proxy@0.2.0"));
+        assert!(worlds.contains(&"wasi:http/proxy@0.2.1"));
+        assert!(worlds.contains(&"wasi:cli/command@0.2.0"));
+        assert!(worlds.contains(&"wasi:cli/command@0.2.2"));
+    }
+
+    fn linkers() -> Result<Linkers> {
+        let mut config = Config::new();
+        config.async_support(true);
+        Linkers::new(&Engine::new(&config)?)
+    }
+
+    // The components built against the previous WASI releases resolve to the linked bindings
+    #[test]
+    fn test_link_previous_releases() -> Result<()> {
+        let linkers = linkers()?;
+        let engine = linkers.proxy.engine().clone();
+
+        // wasi:http/proxy@0.2.0
+        let component = Component::new(&engine, HELLO_WASI_HTTP_CSHARP)?;
+        linkers.proxy_pre(&component)?;
+
+        // wasi:http/proxy@0.2.1
+        let component = Component::new(&engine, HELLO_WASI_HTTP)?;
+        linkers.proxy_pre(&component)?;
+
+        // wasi:cli/command@0.2.0
+        let component = Component::new(&engine, COMPONENT_HELLO_WORLD)?;
+        instantiate_pre(&linkers.command, &component)?;
+        Ok(())
+    }
+}
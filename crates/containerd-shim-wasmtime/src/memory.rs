@@ -0,0 +1,245 @@
+//! Memory accounting of the stores of a container.
+//!
+//! Every store is limited by an [`AccountedLimits`], which enforces the limits of the
+//! [`ResourceProfile`](crate::profile::ResourceProfile) and accounts the linear memories and
+//! tables of the store in the [`MemoryStats`] of the container. The current and peak values help
+//! right-sizing the memory limits of a service, e.g. the size of the linear memories of all the
+//! in-flight requests of an HTTP proxy.
+//!
+//! The stats are served as [metrics](crate::metrics), and logged when the container exits.
+
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::LazyLock;
+
+use anyhow::Result;
+use wasmtime::{ResourceLimiter, StoreLimits};
+
+use crate::metrics::{write_metric, MetricsSource};
+
+static STATS: LazyLock<MemoryStats> = LazyLock::new(MemoryStats::default);
+
+/// The id of the next store.
+static NEXT_STORE: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    /// The store whose limiter allowed a new memory on this thread, which is created right after.
+    static CREATING_STORE: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+/// The id of the store creating a memory on this thread, if any.
+pub(crate) fn creating_store() -> Option<u64> {
+    CREATING_STORE.take()
+}
+
+#[cfg(test)]
+pub(crate) fn set_creating_store(store: u64) {
+    CREATING_STORE.set(Some(store));
+}
+
+/// The memory stats of the container.
+pub fn stats() -> &'static MemoryStats {
+    &STATS
+}
+
+/// Current and peak memory of the stores of the container.
+#[derive(Default, Debug)]
+pub struct MemoryStats {
+    pub stores: Gauge,
+    pub memory_bytes: Gauge,
+    pub table_elements: Gauge,
+}
+
+impl MemoryStats {
+    pub fn log_summary(&self) {
+        tracing::info!(
+            "memory: {} stores ({} peak), {} bytes of linear memory ({} peak), {} table elements ({} peak)",
+            self.stores.current(),
+            self.stores.peak(),
+            self.memory_bytes.current(),
+            self.memory_bytes.peak(),
+            self.table_elements.current(),
+            self.table_elements.peak(),
+        );
+    }
+}
+
+impl MetricsSource for MemoryStats {
+    fn write_metrics(&self, metrics: &mut String) {
+        let gauges = [
+            ("stores", "Stores of the container", &self.stores),
+            (
+                "memory_bytes",
+                "Bytes of linear memory of the stores",
+                &self.memory_bytes,
+            ),
+            (
+                "table_elements",
+                "Elements of the tables of the stores",
+                &self.table_elements,
+            ),
+        ];
+        for (name, help, gauge) in gauges {
+            write_metric(
+                metrics,
+                &format!("runwasi_wasm_{name}"),
+                "gauge",
+                &format!("{help}."),
+                gauge.current() as f64,
+            );
+            write_metric(
+                metrics,
+                &format!("runwasi_wasm_{name}_peak"),
+                "gauge",
+                &format!("{help}, at their peak."),
+                gauge.peak() as f64,
+            );
+        }
+    }
+}
+
+/// A value with its peak.
+#[derive(Default, Debug)]
+pub struct Gauge {
+    current: AtomicU64,
+    peak: AtomicU64,
+}
+
+impl Gauge {
+    pub fn current(&self) -> u64 {
+        self.current.load(Ordering::Relaxed)
+    }
+
+    pub fn peak(&self) -> u64 {
+        self.peak.load(Ordering::Relaxed)
+    }
+
+    fn add(&self, value: usize) {
+        let current = self.current.fetch_add(value as u64, Ordering::Relaxed) + value as u64;
+        self.peak.fetch_max(current, Ordering::Relaxed);
+    }
+
+    fn sub(&self, value: usize) {
+        self.current.fetch_sub(value as u64, Ordering::Relaxed);
+    }
+}
+
+/// The limits of a store, accounting its memory in the stats of the container.
+pub struct AccountedLimits {
+    /// The id of the store.
+    id: u64,
+    limits: StoreLimits,
+    stats: &'static MemoryStats,
+    memory_bytes: usize,
+    table_elements: usize,
+}
+
+impl AccountedLimits {
+    pub fn new(limits: StoreLimits) -> Self {
+        Self::with_stats(limits, stats())
+    }
+
+    fn with_stats(limits: StoreLimits, stats: &'static MemoryStats) -> Self {
+        stats.stores.add(1);
+        Self {
+            id: NEXT_STORE.fetch_add(1, Ordering::Relaxed),
+            limits,
+            stats,
+            memory_bytes: 0,
+            table_elements: 0,
+        }
+    }
+
+    /// The id of the store, unique in the process.
+    pub fn store_id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Drop for AccountedLimits {
+    fn drop(&mut self) {
+        self.stats.stores.sub(1);
+        self.stats.memory_bytes.sub(self.memory_bytes);
+        self.stats.table_elements.sub(self.table_elements);
+    }
+}
+
+impl ResourceLimiter for AccountedLimits {
+    fn memory_growing(
+        &mut self,
+        current: usize,
+        desired: usize,
+        maximum: Option<usize>,
+    ) -> Result<bool> {
+        let allowed = self.limits.memory_growing(current, desired, maximum)?;
+        if allowed {
+            if current == 0 {
+                CREATING_STORE.set(Some(self.id));
+            }
+            self.memory_bytes += desired - current;
+            self.stats.memory_bytes.add(desired - current);
+        }
+        Ok(allowed)
+    }
+
+    fn table_growing(
+        &mut self,
+        current: usize,
+        desired: usize,
+        maximum: Option<usize>,
+    ) -> Result<bool> {
+        let allowed = self.limits.table_growing(current, desired, maximum)?;
+        if allowed {
+            self.table_elements += desired - current;
+            self.stats.table_elements.add(desired - current);
+        }
+        Ok(allowed)
+    }
+
+    fn instances(&self) -> usize {
+        self.limits.instances()
+    }
+
+    fn tables(&self) -> usize {
+        self.limits.tables()
+    }
+
+    fn memories(&self) -> usize {
+        self.limits.memories()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accounting() -> Result<()> {
+        let stats: &'static MemoryStats = Box::leak(Box::default());
+        let limits = || {
+            wasmtime::StoreLimitsBuilder::new()
+                .memory_size(1 << 20)
+                .build()
+        };
+
+        let mut a = AccountedLimits::with_stats(limits(), stats);
+        let mut b = AccountedLimits::with_stats(limits(), stats);
+        assert!(a.memory_growing(0, 65536, None)?);
+        assert!(b.memory_growing(0, 65536, None)?);
+        assert!(b.memory_growing(65536, 131072, None)?);
+        // Denied by the limits, not accounted
+        assert!(!b.memory_growing(131072, 2 << 20, None)?);
+        assert_eq!(stats.stores.current(), 2);
+        assert_eq!(stats.memory_bytes.current(), 196608);
+
+        drop(b);
+        assert_eq!(stats.stores.current(), 1);
+        assert_eq!(stats.memory_bytes.current(), 65536);
+        assert_eq!(stats.memory_bytes.peak(), 196608);
+
+        drop(a);
+        assert_eq!(stats.memory_bytes.current(), 0);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,376 @@
+//! Dump the linear memories of a running guest on demand.
+//!
+//! When the `runwasi.io/debug-memory-dump-dir` annotation is set to a directory of the container,
+//! an `Update` ttrpc request of the task carrying the `runwasi.io/debug-memory-dump` annotation
+//! writes a snapshot of the linear memories of the guest to that directory, named
+//! `memory-<timestamp in ms>-<pid>-<store>-<index>.bin`, with one file per memory of every store
+//! of the container. The shim forwards the request to the process of the container with `SIGUSR1`.
+//! * `runwasi.io/debug-memory-dump-max-size`: size in bytes the snapshots are capped to, from the
+//!   start of the memories (default: the whole memories).
+//! * `runwasi.io/debug-memory-dump-compress`: when `true`, the snapshots are gzip compressed and
+//!   named `.bin.gz`.
+//!
+//! The memories of modules and components alike are dumped: the memories of the containers with
+//! memory dumps are created by a [`DumpMemoryCreator`], which keeps track of them, rather than by
+//! the pooling allocator. They are allocated on demand, aligned to huge pages as with
+//! `RUNWASI_WASMTIME_HUGE_PAGES`.
+//!
+//! Every store takes the snapshot of its own memories at its next epoch check, so that the
+//! memories are consistent. A guest blocked in a host call, e.g. waiting for a connection, is
+//! dumped once it resumes, and the idle stores, e.g. the pooled instances of an HTTP proxy, once
+//! they serve a request.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use containerd_shim_wasm::container::RuntimeContext;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use wasmtime::{Config, Engine, LinearMemory, MemoryCreator, MemoryType};
+
+use crate::huge_pages::HugePageMemoryCreator;
+use crate::memory;
+
+pub const DUMP_DIR_ANNOTATION: &str = "runwasi.io/debug-memory-dump-dir";
+pub const DUMP_MAX_SIZE_ANNOTATION: &str = "runwasi.io/debug-memory-dump-max-size";
+pub const DUMP_COMPRESS_ANNOTATION: &str = "runwasi.io/debug-memory-dump-compress";
+
+/// The signal forwarding the memory dump requests to the process of the container.
+pub const DUMP_SIGNAL: i32 = libc::SIGUSR1;
+
+/// The memory dumps of the container of the process, once enabled.
+static DUMP: OnceLock<Arc<MemoryDump>> = OnceLock::new();
+
+pub(crate) struct MemoryDump {
+    dir: PathBuf,
+    max_size: Option<usize>,
+    compress: bool,
+    /// The timestamp of the last request, in ms since the UNIX epoch, 0 before the first one.
+    requested: AtomicU64,
+    stores: Mutex<HashMap<u64, StoreMemories>>,
+}
+
+/// The memories of a store.
+#[derive(Default)]
+struct StoreMemories {
+    memories: Vec<Weak<MemoryView>>,
+    /// The timestamp of the last request served by the store.
+    dumped: u64,
+}
+
+/// The location of a memory, which moves when it grows.
+struct MemoryView {
+    ptr: AtomicPtr<u8>,
+    len: AtomicUsize,
+}
+
+impl MemoryDump {
+    /// The memory dump settings of the container, `None` when dumps are not enabled.
+    pub fn from_ctx(ctx: &impl RuntimeContext) -> Result<Option<Self>> {
+        let annotations = ctx.annotations();
+        let Some(dir) = annotations.get(DUMP_DIR_ANNOTATION) else {
+            return Ok(None);
+        };
+        let max_size = annotations
+            .get(DUMP_MAX_SIZE_ANNOTATION)
+            .map(|v| v.trim().parse())
+            .transpose()
+            .with_context(|| format!("invalid {DUMP_MAX_SIZE_ANNOTATION}"))?;
+        let compress = annotations
+            .get(DUMP_COMPRESS_ANNOTATION)
+            .map(|v| v.trim().parse())
+            .transpose()
+            .with_context(|| format!("invalid {DUMP_COMPRESS_ANNOTATION}"))?
+            .unwrap_or(false);
+
+        Ok(Some(Self {
+            dir: dir.into(),
+            max_size,
+            compress,
+            requested: AtomicU64::new(0),
+            stores: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    /// Enable the memory dumps of the container `ctx` in this process, if requested.
+    /// The engines created afterwards track their memories.
+    pub fn enable(ctx: &impl RuntimeContext) -> Result<Option<Arc<Self>>> {
+        let Some(dump) = Self::from_ctx(ctx)? else {
+            return Ok(None);
+        };
+        Ok(Some(DUMP.get_or_init(|| Arc::new(dump)).clone()))
+    }
+
+    /// The memory dumps of the container of the process, if enabled.
+    pub fn current() -> Option<&'static Arc<Self>> {
+        DUMP.get()
+    }
+
+    /// Track the memories created by engines configured with `config`, when the memory dumps
+    /// are enabled. Returns whether they are.
+    pub fn configure(config: &mut Config) -> bool {
+        let Some(dump) = Self::current() else {
+            return false;
+        };
+        config.with_host_memory(Arc::new(DumpMemoryCreator {
+            dump: dump.clone(),
+            inner: HugePageMemoryCreator,
+        }));
+        true
+    }
+
+    /// Request a dump on every `DUMP_SIGNAL`, and interrupt the guests running on `engine` to
+    /// take it.
+    pub fn listen(self: &Arc<Self>, engine: &Engine) -> Result<()> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut requests = signal(SignalKind::from_raw(DUMP_SIGNAL))?;
+        let (dump, engine) = (self.clone(), engine.clone());
+        tokio::spawn(async move {
+            while requests.recv().await.is_some() {
+                tracing::info!("memory dump requested");
+                dump.requested.store(now_ms(), Ordering::Relaxed);
+                engine.increment_epoch();
+            }
+        });
+        Ok(())
+    }
+
+    fn track(&self, store: Option<u64>, memory: &Arc<MemoryView>) {
+        let Some(store) = store else {
+            tracing::warn!("untracked memory, it won't be dumped");
+            return;
+        };
+        let mut stores = self.stores.lock().unwrap();
+        stores.retain(|_, store| store.memories.iter().any(|m| m.strong_count() > 0));
+        let memories = &mut stores.entry(store).or_default().memories;
+        memories.push(Arc::downgrade(memory));
+    }
+
+    /// Called on the epoch checks of `store`, takes the requested dump of its memories.
+    pub fn on_epoch(&self, store: u64) {
+        let requested = self.requested.load(Ordering::Relaxed);
+        if requested == 0 {
+            return;
+        }
+        let memories: Vec<_> = {
+            let mut stores = self.stores.lock().unwrap();
+            let Some(memories) = stores.get_mut(&store) else {
+                return;
+            };
+            if memories.dumped >= requested {
+                return;
+            }
+            memories.dumped = requested;
+            memories.memories.iter().filter_map(Weak::upgrade).collect()
+        };
+
+        for (index, memory) in memories.iter().enumerate() {
+            let ptr = memory.ptr.load(Ordering::Relaxed);
+            let len = memory.len.load(Ordering::Relaxed);
+            // SAFETY: the store owning the memory is paused in its epoch check, so the memory is
+            // neither growing nor written to while it's dumped
+            let data = unsafe { std::slice::from_raw_parts(ptr, len) };
+            let name = format!("{requested}-{}-{store}-{index}", std::process::id());
+            match self.write(&name, data) {
+                Ok(path) => tracing::info!("dumped memory to {path:?}"),
+                Err(e) => tracing::warn!("failed to dump memory: {e:?}"),
+            }
+        }
+    }
+
+    fn write(&self, name: &str, data: &[u8]) -> Result<PathBuf> {
+        let len = self.max_size.unwrap_or(data.len()).min(data.len());
+        let ext = if self.compress { "bin.gz" } else { "bin" };
+        let path = self.dir.join(format!("memory-{name}.{ext}"));
+
+        std::fs::create_dir_all(&self.dir)?;
+        write_file(&path, &data[..len], self.compress)
+            .with_context(|| format!("failed to write {path:?}"))?;
+        Ok(path)
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn write_file(path: &Path, data: &[u8], compress: bool) -> Result<()> {
+    let file = File::create(path)?;
+    if compress {
+        let mut encoder = GzEncoder::new(file, Compression::fast());
+        encoder.write_all(data)?;
+        encoder.finish()?.sync_all()?;
+    } else {
+        let mut file = file;
+        file.write_all(data)?;
+        file.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Creates the memories of the containers with memory dumps, keeping track of the memories of
+/// every store.
+pub(crate) struct DumpMemoryCreator {
+    dump: Arc<MemoryDump>,
+    inner: HugePageMemoryCreator,
+}
+
+unsafe impl MemoryCreator for DumpMemoryCreator {
+    fn new_memory(
+        &self,
+        ty: MemoryType,
+        minimum: usize,
+        maximum: Option<usize>,
+        reserved_size_in_bytes: Option<usize>,
+        guard_size_in_bytes: usize,
+    ) -> Result<Box<dyn LinearMemory>, String> {
+        let inner = self.inner.new_memory(
+            ty,
+            minimum,
+            maximum,
+            reserved_size_in_bytes,
+            guard_size_in_bytes,
+        )?;
+        let view = Arc::new(MemoryView {
+            ptr: AtomicPtr::new(inner.as_ptr()),
+            len: AtomicUsize::new(inner.byte_size()),
+        });
+        // The limiter of the store allowed the memory right before it's created
+        self.dump.track(memory::creating_store(), &view);
+        Ok(Box::new(TrackedMemory { inner, view }))
+    }
+}
+
+struct TrackedMemory {
+    inner: Box<dyn LinearMemory>,
+    view: Arc<MemoryView>,
+}
+
+unsafe impl LinearMemory for TrackedMemory {
+    fn byte_size(&self) -> usize {
+        self.inner.byte_size()
+    }
+
+    fn maximum_byte_size(&self) -> Option<usize> {
+        self.inner.maximum_byte_size()
+    }
+
+    fn grow_to(&mut self, new_size: usize) -> Result<()> {
+        self.inner.grow_to(new_size)?;
+        self.view.ptr.store(self.inner.as_ptr(), Ordering::Relaxed);
+        self.view
+            .len
+            .store(self.inner.byte_size(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn as_ptr(&self) -> *mut u8 {
+        self.inner.as_ptr()
+    }
+
+    fn wasm_accessible(&self) -> Range<usize> {
+        self.inner.wasm_accessible()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+
+    fn dump(dir: &Path) -> Arc<MemoryDump> {
+        Arc::new(MemoryDump {
+            dir: dir.join("dumps"),
+            max_size: None,
+            compress: false,
+            requested: AtomicU64::new(0),
+            stores: Mutex::new(HashMap::new()),
+        })
+    }
+
+    #[test]
+    fn test_write() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut dump = MemoryDump {
+            max_size: Some(4),
+            ..Arc::into_inner(dump(dir.path())).unwrap()
+        };
+
+        let path = dump.write("1", b"memory")?;
+        assert_eq!(std::fs::read(&path)?, b"memo");
+
+        dump.compress = true;
+        dump.max_size = None;
+        let path = dump.write("2", b"memory")?;
+        assert!(path.to_string_lossy().ends_with(".bin.gz"));
+        let mut data = vec![];
+        GzDecoder::new(File::open(&path)?).read_to_end(&mut data)?;
+        assert_eq!(data, b"memory");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dump_stores() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dump = dump(dir.path());
+        let creator = DumpMemoryCreator {
+            dump: dump.clone(),
+            inner: HugePageMemoryCreator,
+        };
+        let page = 64 * 1024;
+        let ty = MemoryType::new(1, Some(4));
+        let new_memory = |store| {
+            memory::set_creating_store(store);
+            creator
+                .new_memory(ty.clone(), page, Some(4 * page), None, page)
+                .unwrap()
+        };
+
+        let mut first = new_memory(1);
+        let second = new_memory(2);
+        first.grow_to(2 * page)?;
+        unsafe { *first.as_ptr() = 1 };
+
+        // Nothing is dumped until requested
+        dump.on_epoch(1);
+        assert!(!dir.path().join("dumps").exists());
+
+        dump.requested.store(1234, Ordering::Relaxed);
+        dump.on_epoch(1);
+        let path = dir
+            .path()
+            .join(format!("dumps/memory-1234-{}-1-0.bin", std::process::id()));
+        let data = std::fs::read(&path)?;
+        assert_eq!(data.len(), 2 * page);
+        assert_eq!(data[0], 1);
+
+        // Every store dumps its memories once per request
+        std::fs::remove_file(&path)?;
+        dump.on_epoch(1);
+        assert!(!path.exists());
+        dump.on_epoch(2);
+        assert_eq!(std::fs::read_dir(dir.path().join("dumps"))?.count(), 1);
+
+        // The memories of the dropped stores are forgotten
+        drop(second);
+        let _third = new_memory(3);
+        assert!(!dump.stores.lock().unwrap().contains_key(&2));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,164 @@
+//! Metrics of the container, in the Prometheus text format.
+//!
+//! The parts of the shim keeping counters register them as a [`MetricsSource`], and the metrics
+//! of all the sources are served on `/metrics` of the admin endpoint of the HTTP proxy. The
+//! memory stats of the stores, see [`memory`](crate::memory), are always served.
+//!
+//! The containers that aren't HTTP proxies, e.g. the commands using `wasi:sockets`, serve them on
+//! `/metrics` of the socket address of the `runwasi.io/metrics-addr` annotation, e.g.
+//! `0.0.0.0:9090`.
+//!
+//! The names of the metrics start with `runwasi_`.
+
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, Weak};
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use containerd_shim_wasm::container::RuntimeContext;
+use http_body_util::Full;
+use hyper::server::conn::http1;
+use hyper::StatusCode;
+use tokio::net::TcpListener;
+use tokio_util::sync::{CancellationToken, DropGuard};
+use wasmtime_wasi_http::io::TokioIo;
+
+use crate::http_proxy::{bind_listener, tcp_accept, DEFAULT_BACKLOG};
+use crate::memory;
+
+pub const METRICS_ADDR_ANNOTATION: &str = "runwasi.io/metrics-addr";
+
+/// Counters served as metrics.
+pub(crate) trait MetricsSource: Send + Sync {
+    /// Append the metrics of the source to `metrics`.
+    fn write_metrics(&self, metrics: &mut String);
+}
+
+static SOURCES: Mutex<Vec<Weak<dyn MetricsSource>>> = Mutex::new(Vec::new());
+
+/// Serve the metrics of `source` as long as it is alive.
+pub(crate) fn register<S: MetricsSource + 'static>(source: &Arc<S>) {
+    let source: Arc<dyn MetricsSource> = source.clone();
+    let mut sources = SOURCES.lock().unwrap();
+    sources.retain(|source| source.strong_count() > 0);
+    sources.push(Arc::downgrade(&source));
+}
+
+/// The metrics of all the sources of the container.
+pub(crate) fn render() -> String {
+    let mut metrics = String::new();
+    memory::stats().write_metrics(&mut metrics);
+    let sources: Vec<_> = SOURCES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(Weak::upgrade)
+        .collect();
+    for source in sources {
+        source.write_metrics(&mut metrics);
+    }
+    metrics
+}
+
+/// The address the metrics of the container `ctx` are served on, if any.
+pub(crate) fn metrics_addr(ctx: &impl RuntimeContext) -> Result<Option<SocketAddr>> {
+    ctx.annotations()
+        .get(METRICS_ADDR_ANNOTATION)
+        .map(|addr| addr.parse())
+        .transpose()
+        .context(METRICS_ADDR_ANNOTATION)
+}
+
+/// Serve the metrics of the container `ctx` on its metrics address, if any, until the returned
+/// guard is dropped.
+pub(crate) fn serve_from_ctx(ctx: &impl RuntimeContext) -> Result<Option<DropGuard>> {
+    let Some(addr) = metrics_addr(ctx)? else {
+        return Ok(None);
+    };
+    let listener = bind_listener(addr, DEFAULT_BACKLOG)?;
+    tracing::info!(
+        "Serving metrics on http://{}/metrics",
+        listener.local_addr()?
+    );
+
+    let cancel = CancellationToken::new();
+    tokio::spawn(serve(listener, cancel.clone()));
+    Ok(Some(cancel.drop_guard()))
+}
+
+async fn serve(listener: TcpListener, cancel: CancellationToken) {
+    loop {
+        let stream = tokio::select! {
+            conn = tcp_accept(&listener) => {
+                match conn {
+                    Some(conn) => conn,
+                    None => continue,
+                }
+            }
+            _ = cancel.cancelled() => {
+                return;
+            }
+        };
+
+        tokio::spawn(async move {
+            let service = hyper::service::service_fn(|req| async move {
+                let (status, body) = match req.uri().path() {
+                    "/metrics" => (StatusCode::OK, render()),
+                    _ => (StatusCode::NOT_FOUND, String::new()),
+                };
+                let mut resp = hyper::Response::new(Full::new(Bytes::from(body)));
+                *resp.status_mut() = status;
+                anyhow::Ok(resp)
+            });
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                tracing::error!("metrics error: {e:?}");
+            }
+        });
+    }
+}
+
+/// Append the metric `name` of type `kind`, e.g. `gauge` or `counter`, to `metrics`.
+pub(crate) fn write_metric(metrics: &mut String, name: &str, kind: &str, help: &str, value: f64) {
+    let _ = writeln!(metrics, "# HELP {name} {help}");
+    let _ = writeln!(metrics, "# TYPE {name} {kind}");
+    let _ = writeln!(metrics, "{name} {value}");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use super::*;
+
+    struct Requests(AtomicU64);
+
+    impl MetricsSource for Requests {
+        fn write_metrics(&self, metrics: &mut String) {
+            let value = self.0.load(Ordering::Relaxed) as f64;
+            write_metric(
+                metrics,
+                "runwasi_test_requests_total",
+                "counter",
+                "Requests.",
+                value,
+            );
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let requests = Arc::new(Requests(AtomicU64::new(3)));
+        register(&requests);
+        let metrics = render();
+        assert!(metrics.contains("# TYPE runwasi_wasm_stores gauge\n"));
+        assert!(metrics.contains("runwasi_test_requests_total 3\n"));
+
+        // The metrics of a source are served while it is alive
+        drop(requests);
+        assert!(!render().contains("runwasi_test_requests_total"));
+    }
+}
@@ -0,0 +1,70 @@
+//! Options of the wasmtime engine, from the runtime options of containerd.
+//!
+//! The options are a JSON object, read from the file set as `ConfigPath` in the options of the
+//! runtime, e.g. in the CRI config of containerd:
+//!
+//! ```toml
+//! [plugins."io.containerd.grpc.v1.cri".containerd.runtimes.wasmtime.options]
+//! ConfigPath = "/etc/runwasi/wasmtime.json"
+//! ```
+//!
+//! ```json
+//! { "http": { "backlog": 1024, "cors-allowed-origins": "*" } }
+//! ```
+//!
+//! * `http`: settings of the HTTP proxy by key, see [`ProxyConfig`](crate::ProxyConfig).
+//!
+//! The fields of the `runwasi.io/engine-options` annotation of a container override these.
+
+use std::collections::HashMap;
+
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WasmtimeOptions {
+    #[serde(deserialize_with = "deserialize_settings")]
+    pub http: HashMap<String, String>,
+}
+
+/// Settings are strings, as when they are set with annotations, but numbers and booleans are
+/// accepted as well.
+fn deserialize_settings<'de, D>(deserializer: D) -> Result<HashMap<String, String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    HashMap::<String, Value>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(key, value)| match value {
+            Value::String(value) => Ok((key, value)),
+            Value::Bool(_) | Value::Number(_) => Ok((key, value.to_string())),
+            _ => Err(D::Error::custom(format!(
+                "invalid {key:?}: expected a string, a number or a boolean"
+            ))),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deserialize() -> anyhow::Result<()> {
+        let options: WasmtimeOptions = serde_json::from_str(
+            r#"{ "http": { "backlog": 1024, "grpc-health": true, "static-dir": "/www" } }"#,
+        )?;
+        assert_eq!(options.http["backlog"], "1024");
+        assert_eq!(options.http["grpc-health"], "true");
+        assert_eq!(options.http["static-dir"], "/www");
+
+        assert!(
+            serde_json::from_str::<WasmtimeOptions>(r#"{ "http": { "backlog": [] } }"#).is_err()
+        );
+        assert!(serde_json::from_str::<WasmtimeOptions>(r#"{ "htpp": {} }"#).is_err());
+
+        Ok(())
+    }
+}
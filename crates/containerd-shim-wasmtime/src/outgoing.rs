@@ -0,0 +1,432 @@
+//! Outgoing `wasi:http` requests of the guest.
+//!
+//! By default requests are sent with the wasmtime client. When any of the outgoing features is
+//! configured for the container, the connections are established by the shim instead, so that
+//! they go through the egress proxy and the DNS cache, and race the resolved addresses with
+//! Happy Eyeballs. Their traffic can also be captured for debugging, the requests to failing
+//! hosts cut short by a circuit breaker, and the failed requests retried.
+
+pub mod capture;
+pub mod circuit_breaker;
+pub mod dns;
+pub mod egress_proxy;
+pub mod happy_eyeballs;
+pub mod retry;
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, ensure, Result};
+use bytes::Bytes;
+use containerd_shim_wasm::container::RuntimeContext;
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::header::{HeaderValue, HOST, PROXY_AUTHORIZATION};
+use hyper::Uri;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+use wasmtime_wasi_http::bindings::http::types::{DnsErrorPayload, ErrorCode};
+use wasmtime_wasi_http::body::HyperOutgoingBody;
+use wasmtime_wasi_http::io::TokioIo;
+use wasmtime_wasi_http::types::{
+    default_send_request, HostFutureIncomingResponse, IncomingResponse, OutgoingRequestConfig,
+};
+use wasmtime_wasi_http::{hyper_request_error, hyper_response_error};
+
+use self::capture::{CaptureStream, KeyLogFile, Pcap};
+use self::circuit_breaker::CircuitBreaker;
+use self::dns::DnsCache;
+use self::egress_proxy::{connect_tunnel, EgressProxy, ProxyUrl};
+use self::happy_eyeballs::HappyEyeballs;
+use self::retry::{RetryPolicy, MAX_REPLAY_SIZE};
+use crate::chaos::Chaos;
+use crate::instance::envs_from_ctx;
+
+// Timeout of the requests made by the shim itself, see `Outgoing::get`.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct Outgoing {
+    proxy: Option<EgressProxy>,
+    dns: Option<Arc<DnsCache>>,
+    happy_eyeballs: Option<HappyEyeballs>,
+    tls: Arc<ClientConfig>,
+    key_log: bool,
+    pcap: Option<Arc<Pcap>>,
+    breaker: Option<CircuitBreaker>,
+    retry: Option<RetryPolicy>,
+    chaos: Arc<Chaos>,
+}
+
+impl Outgoing {
+    /// Read the outgoing settings of the container.
+    pub fn from_ctx(ctx: &impl RuntimeContext) -> Result<Arc<Self>> {
+        let envs = envs_from_ctx(ctx);
+        let proxy = EgressProxy::from_env(envs.iter().map(|(k, v)| (k.as_str(), v.as_str())))?;
+        let dns = DnsCache::from_ctx(ctx)?;
+        let happy_eyeballs = HappyEyeballs::from_ctx(ctx)?;
+        let pcap = Pcap::from_ctx(ctx)?;
+
+        let mut tls = tls_config();
+        let key_log = KeyLogFile::from_ctx(ctx)?;
+        if let Some(key_log) = &key_log {
+            tls.key_log = key_log.clone();
+        }
+
+        Ok(Arc::new(Self {
+            proxy,
+            dns,
+            happy_eyeballs,
+            tls: Arc::new(tls),
+            key_log: key_log.is_some(),
+            pcap,
+            breaker: CircuitBreaker::from_ctx(ctx)?,
+            retry: RetryPolicy::from_ctx(ctx)?,
+            chaos: Chaos::from_ctx(ctx)?,
+        }))
+    }
+
+    /// Connect directly to the requested hosts, without egress proxy, DNS cache or capture.
+    pub fn direct() -> Arc<Self> {
+        Arc::new(Self {
+            proxy: None,
+            dns: None,
+            happy_eyeballs: None,
+            tls: Arc::new(tls_config()),
+            key_log: false,
+            pcap: None,
+            breaker: None,
+            retry: None,
+            chaos: Arc::default(),
+        })
+    }
+
+    /// The DNS cache of the container, if enabled.
+    pub fn dns(&self) -> Option<&Arc<DnsCache>> {
+        self.dns.as_ref()
+    }
+
+    /// The Happy Eyeballs settings of the container, if enabled.
+    pub fn happy_eyeballs(&self) -> Option<&HappyEyeballs> {
+        self.happy_eyeballs.as_ref()
+    }
+
+    pub fn send_request(
+        self: &Arc<Self>,
+        request: hyper::Request<HyperOutgoingBody>,
+        config: OutgoingRequestConfig,
+    ) -> HostFutureIncomingResponse {
+        let proxy = self
+            .proxy
+            .as_ref()
+            .and_then(|proxy| proxy.proxy_for(request.uri(), config.use_tls))
+            .cloned();
+        if proxy.is_none() && !self.intercepts() {
+            return default_send_request(request, config);
+        }
+
+        let outgoing = self.clone();
+        let handle = wasmtime_wasi::runtime::spawn(async move {
+            let host = request.uri().authority().map(|a| a.to_string());
+            let Some((breaker, host)) = outgoing.breaker.as_ref().zip(host) else {
+                return Ok(outgoing.send_with_retries(request, config, proxy).await);
+            };
+            if !breaker.admit(&host, Instant::now()) {
+                tracing::debug!("circuit breaker of {host:?} open, failing the request");
+                return Ok(Err(ErrorCode::ConnectionRefused));
+            }
+            let result = outgoing.send_with_retries(request, config, proxy).await;
+            let ok = matches!(&result, Ok(r) if !r.resp.status().is_server_error());
+            breaker.record(&host, ok, Instant::now());
+            Ok(result)
+        });
+        HostFutureIncomingResponse::pending(handle)
+    }
+
+    /// Fetch `uri` on behalf of the shim, e.g. a document needed to configure the proxy.
+    /// The request goes through the same egress proxy and DNS cache as the requests of the guest.
+    pub async fn get(&self, uri: &Uri) -> Result<Bytes> {
+        let use_tls = uri.scheme_str() == Some("https");
+        let proxy = self
+            .proxy
+            .as_ref()
+            .and_then(|proxy| proxy.proxy_for(uri, use_tls))
+            .cloned();
+        let config = OutgoingRequestConfig {
+            use_tls,
+            connect_timeout: FETCH_TIMEOUT,
+            first_byte_timeout: FETCH_TIMEOUT,
+            between_bytes_timeout: FETCH_TIMEOUT,
+        };
+        let request =
+            hyper::Request::get(uri.clone()).body(Empty::new().map_err(|e| match e {}).boxed())?;
+
+        // The worker drives the connection, and must be kept until the body is read
+        let IncomingResponse { resp, worker, .. } = self
+            .send(request, config, proxy)
+            .await
+            .map_err(|e| anyhow!("failed to fetch {uri}: {e:?}"))?;
+
+        let status = resp.status();
+        ensure!(status.is_success(), "failed to fetch {uri}: {status}");
+        let body = timeout(FETCH_TIMEOUT, resp.into_body().collect())
+            .await
+            .map_err(|_| anyhow!("failed to fetch {uri}: timed out"))?
+            .map_err(|e| anyhow!("failed to fetch {uri}: {e:?}"))?
+            .to_bytes();
+        drop(worker);
+
+        Ok(body)
+    }
+}
+
+impl Outgoing {
+    /// Whether the requests are sent by the shim even when no egress proxy applies.
+    fn intercepts(&self) -> bool {
+        self.dns.is_some()
+            || self.happy_eyeballs.is_some()
+            || self.key_log
+            || self.pcap.is_some()
+            || self.breaker.is_some()
+            || self.retry.is_some()
+            || self.chaos.refuses_connections()
+    }
+
+    /// Send `request`, and retry it if it fails according to the retry policy of the container.
+    async fn send_with_retries(
+        &self,
+        request: hyper::Request<HyperOutgoingBody>,
+        config: OutgoingRequestConfig,
+        proxy: Option<ProxyUrl>,
+    ) -> Result<IncomingResponse, ErrorCode> {
+        let Some(policy) = self
+            .retry
+            .as_ref()
+            .filter(|_| retry::is_retryable(&request))
+        else {
+            return self.send(request, config, proxy).await;
+        };
+        let (parts, body) = request.into_parts();
+        let body = match retry::buffer(body, MAX_REPLAY_SIZE).await? {
+            Ok(body) => body,
+            Err(body) => {
+                let request = hyper::Request::from_parts(parts, body);
+                return self.send(request, config, proxy).await;
+            }
+        };
+
+        let OutgoingRequestConfig {
+            use_tls,
+            connect_timeout,
+            first_byte_timeout,
+            between_bytes_timeout,
+        } = config;
+        let mut attempt = 0;
+        loop {
+            let mut request =
+                hyper::Request::new(Full::new(body.clone()).map_err(|e| match e {}).boxed());
+            *request.method_mut() = parts.method.clone();
+            *request.uri_mut() = parts.uri.clone();
+            *request.version_mut() = parts.version;
+            *request.headers_mut() = parts.headers.clone();
+            let config = OutgoingRequestConfig {
+                use_tls,
+                connect_timeout,
+                first_byte_timeout,
+                between_bytes_timeout,
+            };
+
+            let result = self.send(request, config, proxy.clone()).await;
+            if attempt == policy.retries() || !retry::should_retry(&result) {
+                return result;
+            }
+            // The failed response, and its connection, are dropped before the retry
+            drop(result);
+            let backoff = policy.backoff(attempt);
+            tracing::debug!("retrying request to {} in {backoff:?}", parts.uri);
+            tokio::time::sleep(backoff).await;
+            attempt += 1;
+        }
+    }
+
+    async fn send(
+        &self,
+        mut request: hyper::Request<HyperOutgoingBody>,
+        config: OutgoingRequestConfig,
+        proxy: Option<ProxyUrl>,
+    ) -> Result<IncomingResponse, ErrorCode> {
+        let OutgoingRequestConfig {
+            use_tls,
+            connect_timeout,
+            first_byte_timeout,
+            between_bytes_timeout,
+        } = config;
+
+        let authority = request
+            .uri()
+            .authority()
+            .ok_or(ErrorCode::HttpRequestUriInvalid)?
+            .clone();
+        let port = authority
+            .port_u16()
+            .unwrap_or(if use_tls { 443 } else { 80 });
+
+        let (host, port) = match &proxy {
+            Some(proxy) => (proxy.host.as_str(), proxy.port),
+            None => (authority.host(), port),
+        };
+        let mut stream = timeout(connect_timeout, self.connect(host, port))
+            .await
+            .map_err(|_| ErrorCode::ConnectionTimeout)??;
+
+        if let (Some(proxy), true) = (&proxy, use_tls) {
+            let target = format!("{}:{port}", authority.host());
+            timeout(connect_timeout, connect_tunnel(&mut stream, &target, proxy))
+                .await
+                .map_err(|_| ErrorCode::ConnectionTimeout)??;
+        }
+
+        match &proxy {
+            // Plain requests are sent to the proxy in absolute-form
+            Some(proxy) if !use_tls => {
+                if let Some(authorization) = &proxy.authorization {
+                    request
+                        .headers_mut()
+                        .insert(PROXY_AUTHORIZATION, authorization.clone());
+                }
+            }
+            _ => {
+                let path = request
+                    .uri()
+                    .path_and_query()
+                    .map(|p| p.as_str())
+                    .unwrap_or("/");
+                *request.uri_mut() = path.parse().map_err(|_| ErrorCode::HttpRequestUriInvalid)?;
+            }
+        }
+
+        let (mut sender, worker) = if use_tls {
+            let stream = self.tls_connect(authority.host(), stream).await?;
+            handshake(TokioIo::new(stream)).await?
+        } else {
+            handshake(TokioIo::new(stream)).await?
+        };
+
+        if !request.headers().contains_key(HOST) {
+            let host = HeaderValue::try_from(authority.as_str())
+                .map_err(|_| ErrorCode::HttpRequestUriInvalid)?;
+            request.headers_mut().insert(HOST, host);
+        }
+
+        let resp = timeout(first_byte_timeout, sender.send_request(request))
+            .await
+            .map_err(|_| ErrorCode::ConnectionReadTimeout)?
+            .map_err(hyper_request_error)?
+            .map(|body| body.map_err(hyper_response_error).boxed());
+
+        Ok(IncomingResponse {
+            resp,
+            worker: Some(worker),
+            between_bytes_timeout,
+        })
+    }
+
+    /// Resolve `host` and connect to the first reachable address.
+    async fn connect(&self, host: &str, port: u16) -> Result<CaptureStream, ErrorCode> {
+        if self.chaos.refuse_connection() {
+            tracing::debug!("injected connection error to {host:?}");
+            return Err(ErrorCode::ConnectionRefused);
+        }
+
+        let addrs = self.resolve(host, port).await.map_err(|e| {
+            tracing::debug!("failed to resolve {host:?}: {e}");
+            ErrorCode::DnsError(DnsErrorPayload {
+                rcode: Some("address not available".to_string()),
+                info_code: Some(0),
+            })
+        })?;
+
+        let stream = match &self.happy_eyeballs {
+            Some(happy_eyeballs) => happy_eyeballs.connect(addrs).await,
+            None => TcpStream::connect(&addrs[..]).await,
+        };
+        let stream = stream.map_err(|e| {
+            tracing::debug!("failed to connect to {host:?}: {e}");
+            ErrorCode::ConnectionRefused
+        })?;
+
+        Ok(CaptureStream::new(stream, self.pcap.as_ref()))
+    }
+
+    /// Resolve `host` with the DNS cache, if enabled, or the system resolver.
+    pub async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let ip_literal = host.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = ip_literal.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+        match &self.dns {
+            Some(dns) => Ok(dns
+                .lookup(host)
+                .await?
+                .iter()
+                .map(|ip| SocketAddr::new(*ip, port))
+                .collect()),
+            None => Ok(tokio::net::lookup_host((host, port)).await?.collect()),
+        }
+    }
+
+    async fn tls_connect(
+        &self,
+        host: &str,
+        stream: CaptureStream,
+    ) -> Result<tokio_rustls::client::TlsStream<CaptureStream>, ErrorCode> {
+        let connector = TlsConnector::from(self.tls.clone());
+
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let domain = ServerName::try_from(host)
+            .map_err(|_| ErrorCode::InternalError(Some(format!("invalid dns name {host:?}"))))?
+            .to_owned();
+
+        connector.connect(domain, stream).await.map_err(|e| {
+            tracing::warn!("tls protocol error: {e:?}");
+            ErrorCode::TlsProtocolError
+        })
+    }
+}
+
+fn tls_config() -> ClientConfig {
+    let root_cert_store = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.into(),
+    };
+    ClientConfig::builder()
+        .with_root_certificates(root_cert_store)
+        .with_no_client_auth()
+}
+
+async fn handshake<T>(
+    io: TokioIo<T>,
+) -> Result<
+    (
+        hyper::client::conn::http1::SendRequest<HyperOutgoingBody>,
+        wasmtime_wasi::runtime::AbortOnDropJoinHandle<()>,
+    ),
+    ErrorCode,
+>
+where
+    T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    let (sender, conn) = hyper::client::conn::http1::handshake(io)
+        .await
+        .map_err(hyper_request_error)?;
+
+    let worker = wasmtime_wasi::runtime::spawn(async move {
+        if let Err(e) = conn.await {
+            tracing::debug!("outgoing connection error: {e}");
+        }
+    });
+
+    Ok((sender, worker))
+}
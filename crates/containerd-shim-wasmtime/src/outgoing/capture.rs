@@ -0,0 +1,351 @@
+//! Debug capture of the outgoing connections of the guest.
+//!
+//! Both captures are opt-in and meant for debugging protocol issues, paths are in the container:
+//! * `runwasi.io/debug-tls-keylog`: file the TLS session secrets are appended to, in the
+//!   `SSLKEYLOGFILE` format understood by Wireshark.
+//! * `runwasi.io/debug-pcap`: file the traffic of the outgoing connections is written to, in the
+//!   pcap format. The traffic is captured as seen by the shim, i.e., encrypted for TLS connections
+//!   and including the exchanges with the egress proxy.
+//! * `runwasi.io/debug-pcap-max-size`: size in bytes the pcap file is bounded to (default: 64 MiB).
+//!   Packets are dropped once the limit is reached.
+//!
+//! The TCP and IP headers of the captured packets are synthesized from the connection addresses.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context as TaskContext, Poll};
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use containerd_shim_wasm::container::RuntimeContext;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::KeyLog;
+
+pub const TLS_KEYLOG_ANNOTATION: &str = "runwasi.io/debug-tls-keylog";
+pub const PCAP_ANNOTATION: &str = "runwasi.io/debug-pcap";
+pub const PCAP_MAX_SIZE_ANNOTATION: &str = "runwasi.io/debug-pcap-max-size";
+
+const DEFAULT_PCAP_MAX_SIZE: u64 = 64 * 1024 * 1024;
+
+// Raw IPv4 or IPv6 packets, without link layer.
+const LINKTYPE_RAW: u32 = 101;
+
+// Payload size of the synthesized TCP segments.
+const MAX_SEGMENT_SIZE: usize = 32 * 1024;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+
+/// Append the TLS session secrets to a file, in the `SSLKEYLOGFILE` format.
+#[derive(Debug)]
+pub struct KeyLogFile(Mutex<File>);
+
+impl KeyLogFile {
+    pub fn from_ctx(ctx: &impl RuntimeContext) -> Result<Option<Arc<Self>>> {
+        let Some(path) = ctx.annotations().get(TLS_KEYLOG_ANNOTATION) else {
+            return Ok(None);
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("failed to open tls keylog file {path:?}"))?;
+
+        tracing::warn!("writing the tls session secrets to {path:?}");
+        Ok(Some(Arc::new(Self(Mutex::new(file)))))
+    }
+}
+
+impl KeyLog for KeyLogFile {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        let line = format!("{label} {} {}\n", hex(client_random), hex(secret));
+        if let Err(e) = self.0.lock().unwrap().write_all(line.as_bytes()) {
+            tracing::warn!("failed to write tls keylog: {e}");
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+struct PcapFile {
+    file: File,
+    size: u64,
+    truncated: bool,
+}
+
+/// Write the traffic of the outgoing connections to a bounded pcap file.
+pub struct Pcap {
+    file: Mutex<PcapFile>,
+    max_size: u64,
+}
+
+impl Pcap {
+    pub fn from_ctx(ctx: &impl RuntimeContext) -> Result<Option<Arc<Self>>> {
+        let annotations = ctx.annotations();
+        let Some(path) = annotations.get(PCAP_ANNOTATION) else {
+            return Ok(None);
+        };
+        let max_size = annotations
+            .get(PCAP_MAX_SIZE_ANNOTATION)
+            .map(|v| v.trim().parse())
+            .transpose()
+            .context(PCAP_MAX_SIZE_ANNOTATION)?
+            .unwrap_or(DEFAULT_PCAP_MAX_SIZE);
+
+        let mut file =
+            File::create(path).with_context(|| format!("failed to create pcap file {path:?}"))?;
+        let header = pcap_header();
+        file.write_all(&header)?;
+
+        tracing::warn!("capturing outgoing traffic to {path:?}");
+        Ok(Some(Arc::new(Self {
+            file: Mutex::new(PcapFile {
+                file,
+                size: header.len() as u64,
+                truncated: false,
+            }),
+            max_size,
+        })))
+    }
+
+    fn write_packet(&self, packet: &[u8]) {
+        let mut pcap = self.file.lock().unwrap();
+        let record_size = 16 + packet.len() as u64;
+        if pcap.size + record_size > self.max_size {
+            if !pcap.truncated {
+                tracing::warn!("pcap size limit reached, dropping packets");
+                pcap.truncated = true;
+            }
+            return;
+        }
+
+        let mut record = record_header(SystemTime::now(), packet.len());
+        record.extend_from_slice(packet);
+        match pcap.file.write_all(&record) {
+            Ok(()) => pcap.size += record_size,
+            Err(e) => tracing::warn!("failed to write pcap: {e}"),
+        }
+    }
+}
+
+// State of a captured TCP connection.
+struct Flow {
+    pcap: Arc<Pcap>,
+    local: SocketAddr,
+    peer: SocketAddr,
+    // Next sequence numbers of the local and the peer ends.
+    local_seq: u32,
+    peer_seq: u32,
+}
+
+impl Flow {
+    fn new(pcap: Arc<Pcap>, local: SocketAddr, peer: SocketAddr) -> Self {
+        let mut flow = Self {
+            pcap,
+            local,
+            peer,
+            local_seq: 0,
+            peer_seq: 0,
+        };
+        flow.segment(true, TCP_SYN, &[]);
+        flow.segment(false, TCP_SYN | TCP_ACK, &[]);
+        flow
+    }
+
+    fn record(&mut self, outbound: bool, data: &[u8]) {
+        for chunk in data.chunks(MAX_SEGMENT_SIZE) {
+            self.segment(outbound, TCP_PSH | TCP_ACK, chunk);
+        }
+    }
+
+    fn segment(&mut self, outbound: bool, flags: u8, payload: &[u8]) {
+        let (src, dst, seq, ack) = match outbound {
+            true => (self.local, self.peer, self.local_seq, self.peer_seq),
+            false => (self.peer, self.local, self.peer_seq, self.local_seq),
+        };
+        self.pcap
+            .write_packet(&tcp_packet(src, dst, seq, ack, flags, payload));
+
+        // SYN and FIN consume a sequence number
+        let len = payload.len() as u32 + (flags & (TCP_SYN | TCP_FIN) != 0) as u32;
+        match outbound {
+            true => self.local_seq = self.local_seq.wrapping_add(len),
+            false => self.peer_seq = self.peer_seq.wrapping_add(len),
+        }
+    }
+}
+
+impl Drop for Flow {
+    fn drop(&mut self) {
+        self.segment(true, TCP_FIN | TCP_ACK, &[]);
+    }
+}
+
+/// A TCP stream whose traffic is optionally written to a pcap file.
+pub struct CaptureStream {
+    inner: TcpStream,
+    flow: Option<Flow>,
+}
+
+impl CaptureStream {
+    pub fn new(inner: TcpStream, pcap: Option<&Arc<Pcap>>) -> Self {
+        let flow = pcap.and_then(|pcap| {
+            let local = inner.local_addr().ok()?;
+            let peer = inner.peer_addr().ok()?;
+            Some(Flow::new(pcap.clone(), local, peer))
+        });
+        Self { inner, flow }
+    }
+}
+
+impl AsyncRead for CaptureStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let this = &mut *self;
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(())), Some(flow)) = (&result, &mut this.flow) {
+            flow.record(false, &buf.filled()[filled..]);
+        }
+        result
+    }
+}
+
+impl AsyncWrite for CaptureStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let (Poll::Ready(Ok(n)), Some(flow)) = (&result, &mut this.flow) {
+            flow.record(true, &buf[..*n]);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+fn pcap_header() -> Vec<u8> {
+    let mut header = Vec::with_capacity(24);
+    header.extend_from_slice(&0xa1b2c3d4u32.to_le_bytes());
+    header.extend_from_slice(&2u16.to_le_bytes());
+    header.extend_from_slice(&4u16.to_le_bytes());
+    header.extend_from_slice(&0i32.to_le_bytes()); // thiszone
+    header.extend_from_slice(&0u32.to_le_bytes()); // sigfigs
+    header.extend_from_slice(&65535u32.to_le_bytes()); // snaplen
+    header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    header
+}
+
+fn record_header(time: SystemTime, len: usize) -> Vec<u8> {
+    let time = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut header = Vec::with_capacity(16 + len);
+    header.extend_from_slice(&(time.as_secs() as u32).to_le_bytes());
+    header.extend_from_slice(&time.subsec_micros().to_le_bytes());
+    header.extend_from_slice(&(len as u32).to_le_bytes());
+    header.extend_from_slice(&(len as u32).to_le_bytes());
+    header
+}
+
+fn tcp_packet(
+    src: SocketAddr,
+    dst: SocketAddr,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    payload: &[u8],
+) -> Vec<u8> {
+    let tcp_len = 20 + payload.len();
+    let mut packet = Vec::with_capacity(40 + tcp_len);
+
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let start = packet.len();
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.extend_from_slice(&((20 + tcp_len) as u16).to_be_bytes());
+            packet.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]);
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&dst.octets());
+            let checksum = ipv4_checksum(&packet[start..]);
+            packet[start + 10..start + 12].copy_from_slice(&checksum.to_be_bytes());
+        }
+        (src, dst) => {
+            let to_ipv6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&(tcp_len as u16).to_be_bytes());
+            packet.extend_from_slice(&[6, 64]);
+            packet.extend_from_slice(&to_ipv6(src).octets());
+            packet.extend_from_slice(&to_ipv6(dst).octets());
+        }
+    }
+
+    packet.extend_from_slice(&src.port().to_be_bytes());
+    packet.extend_from_slice(&dst.port().to_be_bytes());
+    packet.extend_from_slice(&seq.to_be_bytes());
+    packet.extend_from_slice(&ack.to_be_bytes());
+    // Data offset of 5 words, flags, window, and a zero checksum and urgent pointer
+    packet.extend_from_slice(&[5 << 4, flags, 0xff, 0xff, 0, 0, 0, 0]);
+    packet.extend_from_slice(payload);
+    packet
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let sum = header
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+        .sum::<u32>();
+    let sum = (sum & 0xffff) + (sum >> 16);
+    !((sum & 0xffff) + (sum >> 16)) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tcp_packet() {
+        let src = SocketAddr::from(([10, 0, 0, 1], 40000));
+        let dst = SocketAddr::from(([10, 0, 0, 2], 443));
+        let packet = tcp_packet(src, dst, 1, 2, TCP_PSH | TCP_ACK, b"hello");
+
+        assert_eq!(packet.len(), 20 + 20 + 5);
+        assert_eq!(&packet[2..4], &45u16.to_be_bytes());
+        assert_eq!(ipv4_checksum(&packet[..20]), 0);
+        assert_eq!(&packet[20..22], &40000u16.to_be_bytes());
+        assert_eq!(&packet[24..28], &1u32.to_be_bytes());
+        assert_eq!(packet[33], TCP_PSH | TCP_ACK);
+        assert_eq!(&packet[40..], b"hello");
+
+        let src = SocketAddr::from(([0xfd00, 0, 0, 0, 0, 0, 0, 1], 40000));
+        let dst = SocketAddr::from(([0xfd00, 0, 0, 0, 0, 0, 0, 2], 443));
+        let packet = tcp_packet(src, dst, 1, 2, TCP_SYN, &[]);
+        assert_eq!(packet.len(), 40 + 20);
+        assert_eq!(packet[0] >> 4, 6);
+        assert_eq!(&packet[4..6], &20u16.to_be_bytes());
+    }
+}
@@ -0,0 +1,192 @@
+//! Circuit breaker for the outgoing requests of the guest.
+//!
+//! When an upstream host fails, every request of the guest to it would otherwise wait for its
+//! full timeout. With the `runwasi.io/circuit-breaker-threshold` annotation set, the breaker of a
+//! host opens after that many consecutive failures, i.e., connection errors, timeouts and `5xx`
+//! responses, and the requests to the host then fail immediately.
+//! * `runwasi.io/circuit-breaker-open-duration`: time in milliseconds the breaker stays open
+//!   (default: 30000). A single probe request is then let through: the breaker closes if it
+//!   succeeds, and opens again for twice as long if it fails, up to 16 times the open duration.
+//!
+//! The breakers are per host and port, and shared by all the requests served by the container.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{ensure, Context, Result};
+use containerd_shim_wasm::container::RuntimeContext;
+
+pub const CIRCUIT_BREAKER_THRESHOLD_ANNOTATION: &str = "runwasi.io/circuit-breaker-threshold";
+pub const CIRCUIT_BREAKER_OPEN_DURATION_ANNOTATION: &str =
+    "runwasi.io/circuit-breaker-open-duration";
+
+const DEFAULT_OPEN_DURATION: Duration = Duration::from_secs(30);
+const MAX_BACKOFF: u32 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+        backoff: u32,
+    },
+    /// A probe request was let through at `since`.
+    HalfOpen {
+        since: Instant,
+        backoff: u32,
+    },
+}
+
+pub struct CircuitBreaker {
+    threshold: u32,
+    open_duration: Duration,
+    hosts: Mutex<HashMap<String, State>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, open_duration: Duration) -> Self {
+        Self {
+            threshold,
+            open_duration,
+            hosts: Default::default(),
+        }
+    }
+
+    /// The circuit breaker of the container, if its annotations enable it.
+    pub fn from_ctx(ctx: &impl RuntimeContext) -> Result<Option<Self>> {
+        let annotations = ctx.annotations();
+        let Some(threshold) = annotations.get(CIRCUIT_BREAKER_THRESHOLD_ANNOTATION) else {
+            return Ok(None);
+        };
+        let threshold: u32 = threshold
+            .trim()
+            .parse()
+            .context(CIRCUIT_BREAKER_THRESHOLD_ANNOTATION)?;
+        ensure!(
+            threshold > 0,
+            "{CIRCUIT_BREAKER_THRESHOLD_ANNOTATION} must be positive"
+        );
+        let open_duration = annotations
+            .get(CIRCUIT_BREAKER_OPEN_DURATION_ANNOTATION)
+            .map(|v| v.trim().parse().map(Duration::from_millis))
+            .transpose()
+            .context(CIRCUIT_BREAKER_OPEN_DURATION_ANNOTATION)?
+            .unwrap_or(DEFAULT_OPEN_DURATION);
+        Ok(Some(Self::new(threshold, open_duration)))
+    }
+
+    /// Whether a request to `host` may be sent at `now`.
+    pub fn admit(&self, host: &str, now: Instant) -> bool {
+        let mut hosts = self.hosts.lock().unwrap();
+        let Some(state) = hosts.get_mut(host) else {
+            return true;
+        };
+        match *state {
+            State::Closed { .. } => true,
+            State::Open { until, backoff } if now >= until => {
+                tracing::debug!("circuit breaker of {host:?} half-open, probing");
+                *state = State::HalfOpen {
+                    since: now,
+                    backoff,
+                };
+                true
+            }
+            State::Open { .. } => false,
+            // The outcome of a probe which didn't complete is never recorded, probe again
+            State::HalfOpen { since, backoff } if now >= since + self.open_duration => {
+                *state = State::HalfOpen {
+                    since: now,
+                    backoff,
+                };
+                true
+            }
+            State::HalfOpen { .. } => false,
+        }
+    }
+
+    /// Record the outcome of a request to `host` completed at `now`.
+    pub fn record(&self, host: &str, ok: bool, now: Instant) {
+        let mut hosts = self.hosts.lock().unwrap();
+        let state = hosts
+            .entry(host.to_string())
+            .or_insert(State::Closed { failures: 0 });
+        *state = match (*state, ok) {
+            (State::Closed { .. }, true) => State::Closed { failures: 0 },
+            (State::Closed { failures }, false) if failures + 1 < self.threshold => State::Closed {
+                failures: failures + 1,
+            },
+            (State::Closed { failures }, false) => {
+                tracing::warn!(
+                    "circuit breaker of {host:?} open after {} failures",
+                    failures + 1
+                );
+                self.open(now, 1)
+            }
+            (State::HalfOpen { .. }, true) => {
+                tracing::info!("circuit breaker of {host:?} closed");
+                State::Closed { failures: 0 }
+            }
+            (State::HalfOpen { backoff, .. }, false) => {
+                tracing::warn!("circuit breaker of {host:?} probe failed");
+                self.open(now, (backoff * 2).min(MAX_BACKOFF))
+            }
+            // Requests sent before the breaker opened
+            (state @ State::Open { .. }, _) => state,
+        };
+    }
+
+    fn open(&self, now: Instant, backoff: u32) -> State {
+        State::Open {
+            until: now + self.open_duration * backoff,
+            backoff,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_circuit_breaker() {
+        let open_duration = Duration::from_secs(1);
+        let breaker = CircuitBreaker::new(2, open_duration);
+        let now = Instant::now();
+
+        breaker.record("a:80", false, now);
+        assert!(breaker.admit("a:80", now));
+        breaker.record("a:80", false, now);
+        assert!(!breaker.admit("a:80", now));
+        assert!(breaker.admit("b:80", now));
+
+        // A single probe once open, which fails and backs off
+        let now = now + open_duration;
+        assert!(breaker.admit("a:80", now));
+        assert!(!breaker.admit("a:80", now));
+        breaker.record("a:80", false, now);
+        assert!(!breaker.admit("a:80", now + open_duration));
+
+        // The probe succeeds
+        let now = now + open_duration * 2;
+        assert!(breaker.admit("a:80", now));
+        breaker.record("a:80", true, now);
+        assert!(breaker.admit("a:80", now));
+        breaker.record("a:80", false, now);
+        assert!(breaker.admit("a:80", now));
+    }
+
+    #[test]
+    fn test_stalled_probe() {
+        let open_duration = Duration::from_secs(1);
+        let breaker = CircuitBreaker::new(1, open_duration);
+        let now = Instant::now();
+
+        breaker.record("a:80", false, now);
+        assert!(breaker.admit("a:80", now + open_duration));
+        assert!(!breaker.admit("a:80", now + open_duration));
+        assert!(breaker.admit("a:80", now + open_duration * 2));
+    }
+}
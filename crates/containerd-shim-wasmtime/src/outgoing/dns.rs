@@ -0,0 +1,143 @@
+//! Shared DNS cache for the outgoing connections of the guest.
+//!
+//! The cache is enabled with the `runwasi.io/dns-cache` annotation set to `true`. Names are
+//! resolved with the nameservers of the container's `/etc/resolv.conf`, and the answers are
+//! cached for their TTL, capped with `runwasi.io/dns-cache-max-ttl` (in seconds, default: 300).
+//! The cache is shared by all the requests served by the container.
+//!
+//! The hosts of the outgoing `wasi:http` requests, and the names resolved by the guest with
+//! `wasi:sockets/ip-name-lookup`, see [`sockets`](crate::sockets), go through the cache. Its hits,
+//! misses and errors are served as the `runwasi_dns_cache_*` metrics.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use containerd_shim_wasm::container::RuntimeContext;
+use hickory_resolver::config::LookupIpStrategy;
+use hickory_resolver::TokioAsyncResolver;
+
+use crate::metrics::{self, write_metric, MetricsSource};
+
+pub const DNS_CACHE_ANNOTATION: &str = "runwasi.io/dns-cache";
+pub const DNS_CACHE_MAX_TTL_ANNOTATION: &str = "runwasi.io/dns-cache-max-ttl";
+
+const DEFAULT_MAX_TTL: Duration = Duration::from_secs(300);
+
+// Expired entries are evicted when the cache grows past this size.
+const EVICTION_THRESHOLD: usize = 1024;
+
+/// Counters for the DNS cache.
+#[derive(Default, Debug)]
+pub struct DnsStats {
+    pub hits: AtomicU64,
+    pub misses: AtomicU64,
+    pub errors: AtomicU64,
+}
+
+impl MetricsSource for DnsStats {
+    fn write_metrics(&self, metrics: &mut String) {
+        let counters = [
+            ("hits", "Names resolved from the DNS cache.", &self.hits),
+            ("misses", "Names resolved by the nameservers.", &self.misses),
+            ("errors", "Names that failed to resolve.", &self.errors),
+        ];
+        for (name, help, counter) in counters {
+            let value = counter.load(Ordering::Relaxed) as f64;
+            let name = format!("runwasi_dns_cache_{name}_total");
+            write_metric(metrics, &name, "counter", help, value);
+        }
+    }
+}
+
+struct Entry {
+    addrs: Arc<[IpAddr]>,
+    expires: Instant,
+}
+
+pub struct DnsCache {
+    resolver: TokioAsyncResolver,
+    entries: Mutex<HashMap<String, Entry>>,
+    max_ttl: Duration,
+    stats: Arc<DnsStats>,
+}
+
+impl DnsCache {
+    /// Create the DNS cache if it's enabled in the container annotations.
+    pub fn from_ctx(ctx: &impl RuntimeContext) -> Result<Option<Arc<Self>>> {
+        let annotations = ctx.annotations();
+
+        let enabled = annotations
+            .get(DNS_CACHE_ANNOTATION)
+            .is_some_and(|v| v.trim() == "true");
+        if !enabled {
+            return Ok(None);
+        }
+
+        let max_ttl = annotations
+            .get(DNS_CACHE_MAX_TTL_ANNOTATION)
+            .map(|v| v.trim().parse().map(Duration::from_secs))
+            .transpose()
+            .context(DNS_CACHE_MAX_TTL_ANNOTATION)?
+            .unwrap_or(DEFAULT_MAX_TTL);
+
+        let (config, mut opts) = hickory_resolver::system_conf::read_system_conf()
+            .context("failed to read the resolver configuration of the container")?;
+        // Caching is done by `DnsCache` itself
+        opts.cache_size = 0;
+        // Both address families are needed to race the connections with Happy Eyeballs
+        opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+
+        let stats = Arc::default();
+        metrics::register(&stats);
+
+        Ok(Some(Arc::new(Self {
+            resolver: TokioAsyncResolver::tokio(config, opts),
+            entries: Default::default(),
+            max_ttl,
+            stats,
+        })))
+    }
+
+    pub fn stats(&self) -> &DnsStats {
+        &self.stats
+    }
+
+    pub async fn lookup(&self, host: &str) -> io::Result<Arc<[IpAddr]>> {
+        let now = Instant::now();
+        if let Some(addrs) = self.cached(host, now) {
+            self.stats.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(addrs);
+        }
+        self.stats.misses.fetch_add(1, Ordering::Relaxed);
+
+        let lookup = self.resolver.lookup_ip(host).await.map_err(|e| {
+            self.stats.errors.fetch_add(1, Ordering::Relaxed);
+            io::Error::new(io::ErrorKind::Other, e)
+        })?;
+
+        let addrs: Arc<[IpAddr]> = lookup.iter().collect();
+        let expires = lookup.valid_until().min(now + self.max_ttl);
+        self.insert(host, addrs.clone(), expires, now);
+
+        Ok(addrs)
+    }
+
+    fn cached(&self, host: &str, now: Instant) -> Option<Arc<[IpAddr]>> {
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.get(host)?;
+        (entry.expires > now).then(|| entry.addrs.clone())
+    }
+
+    fn insert(&self, host: &str, addrs: Arc<[IpAddr]>, expires: Instant, now: Instant) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= EVICTION_THRESHOLD {
+            entries.retain(|_, entry| entry.expires > now);
+        }
+        entries.insert(host.to_string(), Entry { addrs, expires });
+    }
+}
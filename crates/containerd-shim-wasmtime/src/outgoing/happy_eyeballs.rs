@@ -0,0 +1,162 @@
+//! Dual-stack connection racing for the outgoing connections of the guest ([RFC 8305]).
+//!
+//! The resolved addresses are interleaved by address family, starting with the preferred family,
+//! and a new connection attempt is started every connection attempt delay until one succeeds.
+//! A broken address family thus only delays the connection instead of failing it.
+//! Racing is enabled by setting any of the annotations:
+//! * `runwasi.io/ip-family-preference`: `ipv6` (default) or `ipv4`.
+//! * `runwasi.io/connection-attempt-delay`: delay in milliseconds between the connection
+//!   attempts (default: 250).
+//!
+//! [RFC 8305]: https://www.rfc-editor.org/rfc/rfc8305
+
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use containerd_shim_wasm::container::RuntimeContext;
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+
+pub const IP_FAMILY_PREFERENCE_ANNOTATION: &str = "runwasi.io/ip-family-preference";
+pub const CONNECTION_ATTEMPT_DELAY_ANNOTATION: &str = "runwasi.io/connection-attempt-delay";
+
+// Recommended value of the "Connection Attempt Delay" in RFC 8305.
+const DEFAULT_CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum IpFamily {
+    #[default]
+    Ipv6,
+    Ipv4,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HappyEyeballs {
+    preference: IpFamily,
+    delay: Duration,
+}
+
+impl Default for HappyEyeballs {
+    fn default() -> Self {
+        Self {
+            preference: IpFamily::default(),
+            delay: DEFAULT_CONNECTION_ATTEMPT_DELAY,
+        }
+    }
+}
+
+impl HappyEyeballs {
+    /// Read the Happy Eyeballs settings of the container.
+    /// Returns `None` if none of the annotations is set.
+    pub fn from_ctx(ctx: &impl RuntimeContext) -> Result<Option<Self>> {
+        let annotations = ctx.annotations();
+        if !annotations.contains_key(IP_FAMILY_PREFERENCE_ANNOTATION)
+            && !annotations.contains_key(CONNECTION_ATTEMPT_DELAY_ANNOTATION)
+        {
+            return Ok(None);
+        }
+
+        let preference = match annotations
+            .get(IP_FAMILY_PREFERENCE_ANNOTATION)
+            .map(|v| v.trim())
+        {
+            None | Some("ipv6") => IpFamily::Ipv6,
+            Some("ipv4") => IpFamily::Ipv4,
+            Some(v) => {
+                bail!("invalid {IP_FAMILY_PREFERENCE_ANNOTATION} {v:?}, expected ipv6 or ipv4")
+            }
+        };
+
+        let delay = annotations
+            .get(CONNECTION_ATTEMPT_DELAY_ANNOTATION)
+            .map(|v| v.trim().parse().map(Duration::from_millis))
+            .transpose()
+            .context(CONNECTION_ATTEMPT_DELAY_ANNOTATION)?
+            .unwrap_or(DEFAULT_CONNECTION_ATTEMPT_DELAY);
+
+        Ok(Some(Self { preference, delay }))
+    }
+
+    /// Interleave the addresses by family, starting with the preferred family.
+    pub fn sort(&self, addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        let (preferred, other): (Vec<_>, Vec<_>) =
+            addrs.into_iter().partition(|addr| match self.preference {
+                IpFamily::Ipv6 => addr.is_ipv6(),
+                IpFamily::Ipv4 => addr.is_ipv4(),
+            });
+
+        let mut sorted = Vec::with_capacity(preferred.len() + other.len());
+        let (mut preferred, mut other) = (preferred.into_iter(), other.into_iter());
+        loop {
+            match (preferred.next(), other.next()) {
+                (None, None) => break,
+                (a, b) => sorted.extend(a.into_iter().chain(b)),
+            }
+        }
+        sorted
+    }
+
+    /// Race connection attempts to `addrs`, and return the first established connection.
+    pub async fn connect(&self, addrs: Vec<SocketAddr>) -> io::Result<TcpStream> {
+        let mut addrs = self.sort(addrs).into_iter();
+        let mut attempts = JoinSet::new();
+        let mut last_error = None;
+
+        loop {
+            if let Some(addr) = addrs.next() {
+                attempts
+                    .spawn(async move { TcpStream::connect(addr).await.map_err(|e| (addr, e)) });
+            }
+            if attempts.is_empty() {
+                break;
+            }
+
+            // Wait for an attempt to complete, or start the next one after the delay.
+            // A failed attempt starts the next one right away.
+            tokio::select! {
+                Some(result) = attempts.join_next() => match result {
+                    Ok(Ok(stream)) => return Ok(stream),
+                    Ok(Err((addr, e))) => {
+                        tracing::debug!("failed to connect to {addr}: {e}");
+                        last_error = Some(e);
+                    }
+                    Err(e) => last_error = Some(io::Error::other(e)),
+                },
+                _ = tokio::time::sleep(self.delay) => {}
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::AddrNotAvailable, "no address to connect to")
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sort() {
+        let v4 = |i| SocketAddr::from(([10, 0, 0, i], 80));
+        let v6 = |i| SocketAddr::from(([0xfd00, 0, 0, 0, 0, 0, 0, i], 80));
+        let addrs = vec![v4(1), v4(2), v4(3), v6(1), v6(2)];
+
+        let happy_eyeballs = HappyEyeballs::default();
+        assert_eq!(
+            happy_eyeballs.sort(addrs.clone()),
+            vec![v6(1), v4(1), v6(2), v4(2), v4(3)]
+        );
+
+        let happy_eyeballs = HappyEyeballs {
+            preference: IpFamily::Ipv4,
+            ..Default::default()
+        };
+        assert_eq!(
+            happy_eyeballs.sort(addrs),
+            vec![v4(1), v6(1), v4(2), v6(2), v4(3)]
+        );
+    }
+}
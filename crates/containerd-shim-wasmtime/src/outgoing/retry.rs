@@ -0,0 +1,184 @@
+//! Retries of the outgoing requests of the guest.
+//!
+//! With the `runwasi.io/outgoing-retries` annotation set to a number of retries, the requests
+//! failing with a connection error or a `429`, `502`, `503` or `504` response are retried by the
+//! shim, so that the guests don't each implement their own retries. Only the requests with an
+//! idempotent method, or an `Idempotency-Key` header, are retried.
+//! * `runwasi.io/outgoing-retry-backoff`: base backoff in milliseconds (default: 100). The
+//!   backoff doubles at every retry, up to 10 seconds, and is jittered.
+//!
+//! The body of a retried request is buffered to be sent again, unless it is larger than 1 MiB,
+//! in which case the request is sent once.
+
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::time::Duration;
+
+use anyhow::{ensure, Context, Result};
+use bytes::{Bytes, BytesMut};
+use containerd_shim_wasm::container::RuntimeContext;
+use http_body_util::{BodyExt, BodyStream, StreamBody};
+use hyper::body::Frame;
+use hyper::{Method, StatusCode};
+use tokio_stream::StreamExt;
+use wasmtime_wasi_http::bindings::http::types::ErrorCode;
+use wasmtime_wasi_http::body::HyperOutgoingBody;
+use wasmtime_wasi_http::types::IncomingResponse;
+
+pub const OUTGOING_RETRIES_ANNOTATION: &str = "runwasi.io/outgoing-retries";
+pub const OUTGOING_RETRY_BACKOFF_ANNOTATION: &str = "runwasi.io/outgoing-retry-backoff";
+
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+const DEFAULT_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+/// Size of the bodies buffered to be sent again.
+pub(crate) const MAX_REPLAY_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    retries: u32,
+    backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// The retry policy of the container, if its annotations enable the retries.
+    pub fn from_ctx(ctx: &impl RuntimeContext) -> Result<Option<Self>> {
+        let annotations = ctx.annotations();
+        let Some(retries) = annotations.get(OUTGOING_RETRIES_ANNOTATION) else {
+            return Ok(None);
+        };
+        let retries: u32 = retries
+            .trim()
+            .parse()
+            .context(OUTGOING_RETRIES_ANNOTATION)?;
+        ensure!(
+            retries <= 10,
+            "{OUTGOING_RETRIES_ANNOTATION} must be at most 10"
+        );
+        let backoff = annotations
+            .get(OUTGOING_RETRY_BACKOFF_ANNOTATION)
+            .map(|v| v.trim().parse().map(Duration::from_millis))
+            .transpose()
+            .context(OUTGOING_RETRY_BACKOFF_ANNOTATION)?
+            .unwrap_or(DEFAULT_BACKOFF);
+        Ok((retries > 0).then_some(Self { retries, backoff }))
+    }
+
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    /// The backoff before the `retry`-th retry, starting at 0, with full jitter.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let max = self
+            .backoff
+            .saturating_mul(1 << retry.min(16))
+            .min(MAX_BACKOFF);
+        max.mul_f64(jitter())
+    }
+}
+
+/// Whether `request` may be sent again.
+pub fn is_retryable<B>(request: &hyper::Request<B>) -> bool {
+    let idempotent = matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE
+    );
+    idempotent || request.headers().contains_key(IDEMPOTENCY_KEY)
+}
+
+/// Whether the request that resulted in `result` should be retried.
+pub fn should_retry(result: &Result<IncomingResponse, ErrorCode>) -> bool {
+    match result {
+        Ok(response) => matches!(
+            response.resp.status(),
+            StatusCode::TOO_MANY_REQUESTS
+                | StatusCode::BAD_GATEWAY
+                | StatusCode::SERVICE_UNAVAILABLE
+                | StatusCode::GATEWAY_TIMEOUT
+        ),
+        Err(code) => matches!(
+            code,
+            ErrorCode::DnsTimeout
+                | ErrorCode::DnsError(_)
+                | ErrorCode::ConnectionRefused
+                | ErrorCode::ConnectionTerminated
+                | ErrorCode::ConnectionTimeout
+                | ErrorCode::ConnectionReadTimeout
+                | ErrorCode::ConnectionWriteTimeout
+                | ErrorCode::ConnectionLimitReached
+        ),
+    }
+}
+
+/// Read `body` to send it again, or, if it is larger than `limit` or has trailers, rebuild it
+/// from the frames already read and the rest of it to send it once.
+pub async fn buffer(
+    mut body: HyperOutgoingBody,
+    limit: usize,
+) -> Result<Result<Bytes, HyperOutgoingBody>, ErrorCode> {
+    let mut buffered = BytesMut::new();
+    while let Some(frame) = body.frame().await {
+        let frame = match frame?.into_data() {
+            Ok(data) if buffered.len() + data.len() <= limit => {
+                buffered.extend_from_slice(&data);
+                continue;
+            }
+            Ok(data) => Frame::data(data),
+            Err(frame) => frame,
+        };
+        let frames = [Frame::data(buffered.freeze()), frame].map(Ok);
+        let rest = tokio_stream::iter(frames).chain(BodyStream::new(body));
+        return Ok(Err(StreamBody::new(rest).boxed()));
+    }
+    Ok(Ok(buffered.freeze()))
+}
+
+/// A random factor in `[0, 1)`.
+fn jitter() -> f64 {
+    let random = RandomState::new().hash_one(std::time::Instant::now());
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::Full;
+
+    use super::*;
+
+    #[test]
+    fn test_retryable() {
+        let request = |method| hyper::Request::builder().method(method).body(()).unwrap();
+        assert!(is_retryable(&request(Method::GET)));
+        assert!(is_retryable(&request(Method::PUT)));
+        assert!(!is_retryable(&request(Method::POST)));
+
+        let mut post = request(Method::POST);
+        post.headers_mut()
+            .insert(IDEMPOTENCY_KEY, "a1".parse().unwrap());
+        assert!(is_retryable(&post));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            retries: 3,
+            backoff: Duration::from_millis(100),
+        };
+        assert!(policy.backoff(0) < Duration::from_millis(100));
+        assert!(policy.backoff(2) < Duration::from_millis(400));
+        assert!(policy.backoff(30) < MAX_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn test_buffer() {
+        let body =
+            |data: &'static str| Full::new(Bytes::from(data)).map_err(|e| match e {}).boxed();
+        let buffered = buffer(body("hello"), 16).await.unwrap().unwrap();
+        assert_eq!(buffered, "hello");
+
+        let rest = buffer(body("hello"), 4).await.unwrap().unwrap_err();
+        let bytes = rest.collect().await.unwrap().to_bytes();
+        assert_eq!(bytes, "hello");
+    }
+}
@@ -0,0 +1,634 @@
+//! Pre-initialization of modules at precompile time.
+//!
+//! When `RUNWASI_WASMTIME_PREINIT` is `true` in the environment of the shim, modules exporting a
+//! `wizer.initialize` function are pre-initialized before being precompiled, as
+//! [Wizer](https://github.com/bytecodealliance/wizer) does: the function is run once, and the
+//! resulting memories and globals are snapshotted into the module. Containers then start from the
+//! initialized state, skipping e.g. the initialization of a language runtime compiled to wasm.
+//!
+//! The core modules of a component exporting the function are pre-initialized the same way, and
+//! the component is rebuilt with them.
+//!
+//! The initialization function is untrusted code running in the shim, so it runs:
+//! * with an empty WASI context: no preopened directory, environment variable, argument or stdio,
+//!   and the other imports of the module trap,
+//! * with a fuel budget, `RUNWASI_WASMTIME_PREINIT_FUEL` (10 billion units by default, roughly as
+//!   many instructions), and at most [`MAX_MEMORY`] of memory.
+//!
+//! A module that can't be snapshotted, e.g. because it mutates its tables or imports its memory,
+//! is precompiled as is.
+
+use std::ops::Range;
+
+use anyhow::{bail, ensure, Context, Result};
+use wasm_encoder::{ConstExpr, DataSection, Encode, ExportKind, ExportSection, MemorySection};
+use wasmparser::{Encoding, ExternalKind, Operator, Parser, Payload, TypeRef, ValType};
+use wasmtime::{Config, Engine, Linker, Module, Store, StoreLimits, StoreLimitsBuilder, Val};
+use wasmtime_wasi::preview1::{self as wasi_preview1, WasiP1Ctx};
+use wasmtime_wasi::WasiCtxBuilder;
+
+pub const PREINIT_ENV: &str = "RUNWASI_WASMTIME_PREINIT";
+const FUEL_ENV: &str = "RUNWASI_WASMTIME_PREINIT_FUEL";
+
+/// The export marking the initialization of a module.
+const INIT_FUNC: &str = "wizer.initialize";
+/// The fuel given to the initialization function by default.
+const DEFAULT_FUEL: u64 = 10_000_000_000;
+/// The most memory the initialization function can grow its memories to.
+const MAX_MEMORY: usize = 2 << 30;
+/// The prefix of the exports added to read the state of a module after its initialization.
+const STATE_EXPORT: &str = "runwasi:preinit/";
+/// The zero bytes between two non-zero ranges of a memory below which they are snapshotted as
+/// one data segment.
+const MERGE_GAP: usize = 32;
+
+const SECTION_MEMORY: u8 = 5;
+const SECTION_GLOBAL: u8 = 6;
+const SECTION_EXPORT: u8 = 7;
+const SECTION_START: u8 = 8;
+const SECTION_DATA: u8 = 11;
+const SECTION_DATA_COUNT: u8 = 12;
+const SECTION_CORE_MODULE: u8 = 1;
+
+/// Whether pre-initialization is enabled in the shim.
+pub fn enabled() -> bool {
+    std::env::var(PREINIT_ENV).is_ok_and(|v| v.trim().eq_ignore_ascii_case("true"))
+}
+
+/// Pre-initialize the module or component `wasm`, if it exports an initialization function.
+///
+/// Returns `None` when it is not pre-initialized, in which case it is precompiled as is.
+pub fn preinit(wasm: &[u8]) -> Option<Vec<u8>> {
+    // Cheap check before parsing the binary
+    if !contains(wasm, INIT_FUNC.as_bytes()) {
+        return None;
+    }
+
+    let result = match Parser::is_component(wasm) {
+        true => preinit_component(wasm),
+        false => Preinit::new().and_then(|preinit| preinit.module(wasm, false)),
+    };
+    match result {
+        Ok(Some(initialized)) => {
+            tracing::info!("pre-initialized with {INIT_FUNC}");
+            Some(initialized)
+        }
+        Ok(None) => None,
+        Err(e) => {
+            tracing::warn!("failed to pre-initialize, precompiling as is: {e:?}");
+            None
+        }
+    }
+}
+
+/// Pre-initialize the core modules of the component `component` exporting the initialization
+/// function, keeping it so that the component still links.
+fn preinit_component(component: &[u8]) -> Result<Option<Vec<u8>>> {
+    let preinit = Preinit::new()?;
+    let mut initialized = false;
+    let mut out = component[..8].to_vec();
+    for (id, range) in sections(component)? {
+        let section = &component[range];
+        if id != SECTION_CORE_MODULE || !contains(section, INIT_FUNC.as_bytes()) {
+            raw_section(&mut out, id, section);
+            continue;
+        }
+        match preinit.module(section, true) {
+            Ok(Some(module)) => {
+                raw_section(&mut out, id, &module);
+                initialized = true;
+            }
+            Ok(None) => raw_section(&mut out, id, section),
+            Err(e) => {
+                tracing::warn!("failed to pre-initialize a module of the component: {e:?}");
+                raw_section(&mut out, id, section);
+            }
+        }
+    }
+    Ok(initialized.then_some(out))
+}
+
+/// The engine running the initialization functions.
+struct Preinit {
+    engine: Engine,
+    fuel: u64,
+}
+
+/// The store data of the initialization functions.
+struct PreinitCtx {
+    wasi: WasiP1Ctx,
+    limits: StoreLimits,
+}
+
+impl Preinit {
+    fn new() -> Result<Self> {
+        let fuel = match std::env::var(FUEL_ENV) {
+            Ok(fuel) => fuel
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid {FUEL_ENV}: {fuel:?}"))?,
+            Err(_) => DEFAULT_FUEL,
+        };
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config)?;
+        Ok(Self { engine, fuel })
+    }
+
+    /// Pre-initialize the core `module`, returning `None` if it doesn't export the initialization
+    /// function. The function is kept exported with `keep_init`.
+    fn module(&self, module: &[u8], keep_init: bool) -> Result<Option<Vec<u8>>> {
+        let info = ModuleInfo::parse(module)?;
+        if !info
+            .exports
+            .iter()
+            .any(|(name, kind, _)| name == INIT_FUNC && *kind == ExternalKind::Func)
+        {
+            return Ok(None);
+        }
+        let state = self.run(&info.instrument(module)?, &info)?;
+        info.snapshot(module, &state, keep_init).map(Some)
+    }
+
+    /// Run the initialization function of the `instrumented` module, and read its state.
+    fn run(&self, instrumented: &[u8], info: &ModuleInfo) -> Result<State> {
+        let module = Module::new(&self.engine, instrumented)?;
+
+        let mut linker = Linker::<PreinitCtx>::new(&self.engine);
+        wasi_preview1::add_to_linker_sync(&mut linker, |ctx| &mut ctx.wasi)?;
+        linker.define_unknown_imports_as_traps(&module)?;
+
+        let ctx = PreinitCtx {
+            wasi: WasiCtxBuilder::new().build_p1(),
+            limits: StoreLimitsBuilder::new().memory_size(MAX_MEMORY).build(),
+        };
+        let mut store = Store::new(&self.engine, ctx);
+        store.limiter(|ctx| &mut ctx.limits);
+        store.set_fuel(self.fuel)?;
+
+        let instance = linker.instantiate(&mut store, &module)?;
+        instance
+            .get_typed_func::<(), ()>(&mut store, INIT_FUNC)?
+            .call(&mut store, ())
+            .with_context(|| format!("{INIT_FUNC} failed"))?;
+
+        let globals = (0..info.globals.len())
+            .map(|i| {
+                let name = format!("{STATE_EXPORT}global{i}");
+                let global = instance.get_global(&mut store, &name);
+                Ok(global.context("missing global")?.get(&mut store))
+            })
+            .collect::<Result<_>>()?;
+        let memories = (0..info.memories.len())
+            .map(|i| {
+                let name = format!("{STATE_EXPORT}memory{i}");
+                let memory = instance.get_memory(&mut store, &name);
+                let memory = memory.context("missing memory")?;
+                Ok((memory.size(&store), memory.data(&store).to_vec()))
+            })
+            .collect::<Result<_>>()?;
+        Ok(State { globals, memories })
+    }
+}
+
+/// The state of an instance after its initialization.
+struct State {
+    globals: Vec<Val>,
+    /// The size in pages and the content of the memories.
+    memories: Vec<(u64, Vec<u8>)>,
+}
+
+/// What is snapshotted of a module.
+struct ModuleInfo {
+    /// The globals defined by the module, with the range of their type and initializer.
+    globals: Vec<(ValType, bool, Range<usize>)>,
+    /// The memories defined by the module.
+    memories: Vec<wasmparser::MemoryType>,
+    exports: Vec<(String, ExternalKind, u32)>,
+    /// The number of imported globals and memories, which come first in their index spaces.
+    imported_globals: u32,
+    imported_memories: u32,
+}
+
+impl ModuleInfo {
+    fn parse(module: &[u8]) -> Result<Self> {
+        let mut info = Self {
+            globals: vec![],
+            memories: vec![],
+            exports: vec![],
+            imported_globals: 0,
+            imported_memories: 0,
+        };
+        for payload in Parser::new(0).parse_all(module) {
+            match payload? {
+                Payload::Version { encoding, .. } => {
+                    ensure!(encoding == Encoding::Module, "not a core module")
+                }
+                Payload::ImportSection(reader) => {
+                    for import in reader {
+                        match import?.ty {
+                            TypeRef::Memory(_) => bail!("imported memories can't be snapshotted"),
+                            TypeRef::Global(ty) if ty.mutable => {
+                                bail!("imported mutable globals can't be snapshotted")
+                            }
+                            TypeRef::Global(_) => info.imported_globals += 1,
+                            _ => {}
+                        }
+                    }
+                }
+                Payload::MemorySection(reader) => {
+                    for memory in reader {
+                        let memory = memory?;
+                        ensure!(!memory.shared, "shared memories can't be snapshotted");
+                        ensure!(
+                            memory.page_size_log2.is_none(),
+                            "custom page sizes can't be snapshotted"
+                        );
+                        info.memories.push(memory);
+                    }
+                }
+                Payload::GlobalSection(reader) => {
+                    let end = reader.range().end;
+                    let mut globals = reader.into_iter_with_offsets().peekable();
+                    while let Some(global) = globals.next() {
+                        let (start, global) = global?;
+                        let next = match globals.peek() {
+                            Some(Ok((next, _))) => *next,
+                            _ => end,
+                        };
+                        let ty = global.ty;
+                        ensure!(!ty.shared, "shared globals can't be snapshotted");
+                        let numeric = !matches!(ty.content_type, ValType::Ref(_));
+                        ensure!(
+                            numeric || !ty.mutable,
+                            "mutable reference globals can't be snapshotted"
+                        );
+                        info.globals
+                            .push((ty.content_type, ty.mutable, start..next));
+                    }
+                }
+                Payload::ExportSection(reader) => {
+                    for export in reader {
+                        let export = export?;
+                        info.exports
+                            .push((export.name.to_string(), export.kind, export.index));
+                    }
+                }
+                Payload::CodeSectionEntry(body) => {
+                    for op in body.get_operators_reader()? {
+                        match op? {
+                            Operator::TableSet { .. }
+                            | Operator::TableGrow { .. }
+                            | Operator::TableFill { .. }
+                            | Operator::TableCopy { .. }
+                            | Operator::TableInit { .. }
+                            | Operator::ElemDrop { .. } => {
+                                bail!("modules mutating their tables can't be snapshotted")
+                            }
+                            Operator::MemoryInit { .. } | Operator::DataDrop { .. } => {
+                                bail!("passive data segments can't be snapshotted")
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(info)
+    }
+
+    /// `module` exporting its globals and memories, to read them after the initialization.
+    fn instrument(&self, module: &[u8]) -> Result<Vec<u8>> {
+        let mut exports = self.export_section(&|_| true);
+        for i in 0..self.globals.len() as u32 {
+            let name = format!("{STATE_EXPORT}global{i}");
+            exports.export(&name, ExportKind::Global, self.imported_globals + i);
+        }
+        for i in 0..self.memories.len() as u32 {
+            let name = format!("{STATE_EXPORT}memory{i}");
+            exports.export(&name, ExportKind::Memory, self.imported_memories + i);
+        }
+        rewrite(module, |id, _| match id {
+            SECTION_EXPORT => Some(Some(encoded(&exports))),
+            _ => None,
+        })
+    }
+
+    /// `module` with the `state` of its instance after the initialization.
+    fn snapshot(&self, module: &[u8], state: &State, keep_init: bool) -> Result<Vec<u8>> {
+        let mut globals = vec![];
+        let mut count = 0u32;
+        for ((ty, mutable, range), value) in self.globals.iter().zip(&state.globals) {
+            count += 1;
+            if matches!(ty, ValType::Ref(_)) {
+                globals.extend_from_slice(&module[range.clone()]);
+                continue;
+            }
+            globals.extend_from_slice(&[valtype(*ty)?, u8::from(*mutable)]);
+            const_expr(value)?.encode(&mut globals);
+        }
+        let mut global_section = vec![];
+        count.encode(&mut global_section);
+        global_section.extend(globals);
+
+        let mut memories = MemorySection::new();
+        let mut data = DataSection::new();
+        for (i, (ty, (pages, bytes))) in self.memories.iter().zip(&state.memories).enumerate() {
+            memories.memory(wasm_encoder::MemoryType {
+                minimum: *pages,
+                maximum: ty.maximum,
+                memory64: ty.memory64,
+                shared: false,
+                page_size_log2: None,
+            });
+            let index = self.imported_memories + i as u32;
+            for range in non_zero_ranges(bytes) {
+                let offset = match ty.memory64 {
+                    true => ConstExpr::i64_const(range.start as i64),
+                    false => ConstExpr::i32_const(range.start as i32),
+                };
+                data.active(index, &offset, bytes[range].iter().copied());
+            }
+        }
+
+        let exports = self.export_section(&|name| keep_init || name != INIT_FUNC);
+        let has_data = sections(module)?.iter().any(|(id, _)| *id == SECTION_DATA);
+        let mut module = rewrite(module, |id, _| match id {
+            SECTION_GLOBAL => Some(Some(global_section.clone())),
+            SECTION_MEMORY => Some(Some(encoded(&memories))),
+            SECTION_EXPORT => Some(Some(encoded(&exports))),
+            SECTION_DATA => Some(Some(encoded(&data))),
+            // The start function ran before the initialization, and no code uses passive data
+            SECTION_START | SECTION_DATA_COUNT => Some(None),
+            _ => None,
+        })?;
+        // The data section comes last, custom sections aside
+        if !has_data && !data.is_empty() {
+            raw_section(&mut module, SECTION_DATA, &encoded(&data));
+        }
+        Ok(module)
+    }
+
+    /// The exports of the module whose name matches `filter`.
+    fn export_section(&self, filter: &dyn Fn(&str) -> bool) -> ExportSection {
+        let mut exports = ExportSection::new();
+        for (name, kind, index) in self.exports.iter().filter(|(name, ..)| filter(name)) {
+            let kind = match kind {
+                ExternalKind::Func => ExportKind::Func,
+                ExternalKind::Table => ExportKind::Table,
+                ExternalKind::Memory => ExportKind::Memory,
+                ExternalKind::Global => ExportKind::Global,
+                ExternalKind::Tag => ExportKind::Tag,
+            };
+            exports.export(name, kind, *index);
+        }
+        exports
+    }
+}
+
+/// The sections of the module or component `wasm`, with the range of their content.
+fn sections(wasm: &[u8]) -> Result<Vec<(u8, Range<usize>)>> {
+    let mut sections = vec![];
+    let mut pos = 8;
+    while pos < wasm.len() {
+        let id = wasm[pos];
+        let (size, len) = read_u32(&wasm[pos + 1..])?;
+        let start = pos + 1 + len;
+        let end = start + size as usize;
+        ensure!(end <= wasm.len(), "truncated section");
+        sections.push((id, start..end));
+        pos = end;
+    }
+    Ok(sections)
+}
+
+/// Rewrite the sections of `module` with `f`, which returns `None` to keep a section as is,
+/// `Some(None)` to remove it, or `Some(Some(content))` to replace it. The export section is added
+/// if the module has none.
+fn rewrite(
+    module: &[u8],
+    mut f: impl FnMut(u8, &[u8]) -> Option<Option<Vec<u8>>>,
+) -> Result<Vec<u8>> {
+    let mut out = module[..8].to_vec();
+    let mut exported = false;
+    for (id, range) in sections(module)? {
+        // The export section comes after the sections of ids 1 to 6 and 13, the tags
+        if !exported && id != 0 && id != 13 && id > SECTION_GLOBAL {
+            if id != SECTION_EXPORT {
+                if let Some(Some(content)) = f(SECTION_EXPORT, &[]) {
+                    raw_section(&mut out, SECTION_EXPORT, &content);
+                }
+            }
+            exported = true;
+        }
+        let content = &module[range];
+        match f(id, content) {
+            None => raw_section(&mut out, id, content),
+            Some(Some(content)) => raw_section(&mut out, id, &content),
+            Some(None) => {}
+        }
+    }
+    if !exported {
+        if let Some(Some(content)) = f(SECTION_EXPORT, &[]) {
+            raw_section(&mut out, SECTION_EXPORT, &content);
+        }
+    }
+    Ok(out)
+}
+
+fn raw_section(out: &mut Vec<u8>, id: u8, content: &[u8]) {
+    out.push(id);
+    (content.len() as u32).encode(out);
+    out.extend_from_slice(content);
+}
+
+/// The content of `section`, without its size.
+fn encoded(section: &impl Encode) -> Vec<u8> {
+    let mut bytes = vec![];
+    section.encode(&mut bytes);
+    let (_, len) = read_u32(&bytes).expect("valid section size");
+    bytes.split_off(len)
+}
+
+/// Read an unsigned LEB128 `u32`, returning it with its encoded length.
+fn read_u32(bytes: &[u8]) -> Result<(u32, usize)> {
+    let mut value = 0u32;
+    for (i, byte) in bytes.iter().take(5).enumerate() {
+        value |= u32::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok((value, i + 1));
+        }
+    }
+    bail!("invalid LEB128 integer")
+}
+
+fn valtype(ty: ValType) -> Result<u8> {
+    Ok(match ty {
+        ValType::I32 => 0x7f,
+        ValType::I64 => 0x7e,
+        ValType::F32 => 0x7d,
+        ValType::F64 => 0x7c,
+        ValType::V128 => 0x7b,
+        ValType::Ref(_) => bail!("not a numeric type"),
+    })
+}
+
+fn const_expr(value: &Val) -> Result<ConstExpr> {
+    Ok(match value {
+        Val::I32(v) => ConstExpr::i32_const(*v),
+        Val::I64(v) => ConstExpr::i64_const(*v),
+        // The floats are encoded raw to keep their bits, e.g. of NaNs
+        Val::F32(bits) => ConstExpr::raw([0x43].into_iter().chain(bits.to_le_bytes())),
+        Val::F64(bits) => ConstExpr::raw([0x44].into_iter().chain(bits.to_le_bytes())),
+        Val::V128(v) => {
+            let bits = u128::from(*v).to_le_bytes();
+            ConstExpr::raw([0xfd, 0x0c].into_iter().chain(bits))
+        }
+        _ => bail!("not a numeric value"),
+    })
+}
+
+/// The ranges of `bytes` that aren't zero, merging those separated by fewer than [`MERGE_GAP`]
+/// zero bytes.
+fn non_zero_ranges(bytes: &[u8]) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = vec![];
+    let mut pos = 0;
+    while let Some(start) = bytes[pos..].iter().position(|b| *b != 0) {
+        let start = pos + start;
+        let end = bytes[start..]
+            .iter()
+            .position(|b| *b == 0)
+            .map_or(bytes.len(), |len| start + len);
+        match ranges.last_mut() {
+            Some(last) if start - last.end < MERGE_GAP => last.end = end,
+            _ => ranges.push(start..end),
+        }
+        pos = end;
+    }
+    ranges
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The fields of a module whose initialization sets a global and writes to its memory
+    const FIELDS: &str = r#"
+        (memory (export "memory") 2)
+        (global $initialized (mut i32) (i32.const 0))
+        (global $pi (mut f64) (f64.const 0))
+        (func (export "wizer.initialize")
+            (global.set $initialized (i32.const 42))
+            (global.set $pi (f64.const 3.14))
+            (i32.store (i32.const 1024) (i32.const 0x01020304))
+            (drop (memory.grow (i32.const 1)))
+            (i32.store (i32.const 70000) (i32.const 7)))
+        (func (export "get") (result i32)
+            (i32.add (global.get $initialized)
+                (i32.add (i32.load (i32.const 1024)) (i32.load (i32.const 70000)))))"#;
+    const INITIALIZED: i32 = 42 + 0x01020304 + 7;
+
+    fn get(module: &[u8]) -> Result<i32> {
+        let engine = Engine::default();
+        let module = Module::new(&engine, module)?;
+        let mut linker = Linker::new(&engine);
+        linker.define_unknown_imports_as_traps(&module)?;
+        let mut store = Store::new(&engine, ());
+        let instance = linker.instantiate(&mut store, &module)?;
+        let get = instance.get_typed_func::<(), i32>(&mut store, "get")?;
+        get.call(&mut store, ())
+    }
+
+    #[test]
+    fn test_preinit_module() -> Result<()> {
+        // The other imports of the module trap
+        let module = wat::parse_str(format!(
+            r#"(module
+                (import "env" "abort" (func))
+                (import "wasi_snapshot_preview1" "sched_yield" (func (result i32)))
+                {FIELDS})"#
+        ))?;
+        assert_eq!(get(&module)?, 0);
+
+        let initialized = preinit(&module).context("not pre-initialized")?;
+        assert_eq!(get(&initialized)?, INITIALIZED);
+
+        let info = ModuleInfo::parse(&initialized)?;
+        assert!(info.exports.iter().all(|(name, ..)| name != INIT_FUNC));
+        assert!(info
+            .exports
+            .iter()
+            .all(|(name, ..)| !name.starts_with(STATE_EXPORT)));
+        assert_eq!(info.memories[0].initial, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_preinit_without_init() -> Result<()> {
+        let module = wat::parse_str("(module (func (export \"_start\")))")?;
+        assert_eq!(preinit(&module), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_preinit_infinite_loop() -> Result<()> {
+        let module =
+            wat::parse_str(r#"(module (func (export "wizer.initialize") (loop $l (br $l))))"#)?;
+        let preinit = Preinit {
+            fuel: 1_000_000,
+            ..Preinit::new()?
+        };
+        let err = preinit.module(&module, false).err();
+        let err = err.context("the loop was not interrupted")?;
+        assert!(format!("{err:?}").contains("fuel"), "{err:?}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_preinit_rejects_table_mutations() -> Result<()> {
+        let module = wat::parse_str(
+            r#"(module
+                (table 1 funcref)
+                (func (export "wizer.initialize") (drop (table.grow (ref.null func) (i32.const 1)))))"#,
+        )?;
+        assert!(ModuleInfo::parse(&module).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_preinit_component() -> Result<()> {
+        let component = wat::parse_str(format!(
+            r#"(component
+                (core module $m {FIELDS})
+                (core instance $i (instantiate $m))
+                (func (export "get") (result s32) (canon lift (core func $i "get"))))"#
+        ))?;
+        let initialized = preinit(&component).context("not pre-initialized")?;
+
+        let engine = Engine::default();
+        let component = wasmtime::component::Component::new(&engine, &initialized)?;
+        let mut linker = wasmtime::component::Linker::<()>::new(&engine);
+        linker.define_unknown_imports_as_traps(&component)?;
+        let mut store = Store::new(&engine, ());
+        let instance = linker.instantiate(&mut store, &component)?;
+        let get = instance.get_typed_func::<(), (i32,)>(&mut store, "get")?;
+        assert_eq!(get.call(&mut store, ())?, (INITIALIZED,));
+        Ok(())
+    }
+
+    #[test]
+    fn test_non_zero_ranges() {
+        let mut bytes = vec![0u8; 200];
+        bytes[1] = 1;
+        bytes[10] = 1;
+        bytes[100] = 1;
+        bytes[199] = 1;
+        assert_eq!(non_zero_ranges(&bytes), [1..11, 100..101, 199..200]);
+        assert_eq!(non_zero_ranges(&[0; 10]), []);
+    }
+}
@@ -0,0 +1,180 @@
+//! Named resource profiles.
+//!
+//! A profile bundles the resource limits of a container, and is selected with the
+//! `runwasi.io/resource-profile` annotation. The shim has the built-in `small`, `medium` and
+//! `large` profiles. More profiles can be defined, and the built-in ones overridden, in a JSON
+//! file set with the `RUNWASI_WASMTIME_PROFILES` environment variable of the shim, e.g.:
+//!
+//! ```json
+//! {
+//!     "tiny": { "max_memory_size": 16777216, "max_concurrency": 4, "fuel": 100000000 },
+//!     "small": { "max_memory_size": 33554432 }
+//! }
+//! ```
+//!
+//! Containers without the annotation are not limited.
+
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use containerd_shim_wasm::container::RuntimeContext;
+use serde::Deserialize;
+use wasmtime::{PoolingAllocationConfig, Store, StoreLimitsBuilder};
+
+use crate::memory::AccountedLimits;
+
+pub const RESOURCE_PROFILE_ANNOTATION: &str = "runwasi.io/resource-profile";
+pub const PROFILES_ENV: &str = "RUNWASI_WASMTIME_PROFILES";
+
+const MIB: usize = 1024 * 1024;
+
+/// Resource limits of a container.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResourceProfile {
+    /// Fuel of each store, e.g. of each HTTP request.
+    /// Any profile with fuel enables fuel metering for all the containers of the shim.
+    pub fuel: Option<u64>,
+    /// Maximum size in bytes of each linear memory.
+    pub max_memory_size: Option<usize>,
+    /// Maximum number of elements of each table.
+    pub max_table_elements: Option<usize>,
+    /// Maximum number of instances of each store.
+    pub max_instances: Option<usize>,
+    /// Number of instances, memories and tables of the pooling allocator.
+    pub pool_size: Option<u32>,
+    /// Maximum number of HTTP requests handled concurrently.
+    pub max_concurrency: Option<usize>,
+}
+
+impl ResourceProfile {
+    /// The profile selected by the container annotations.
+    pub fn from_ctx(ctx: &impl RuntimeContext) -> Result<Self> {
+        let Some(name) = ctx.annotations().get(RESOURCE_PROFILE_ANNOTATION) else {
+            return Ok(Self::default());
+        };
+        let name = name.trim();
+        let mut profiles = profiles()?;
+        let profile = profiles.remove(name).with_context(|| {
+            let mut names = profiles.into_keys().collect::<Vec<_>>();
+            names.sort();
+            format!(
+                "unknown {RESOURCE_PROFILE_ANNOTATION} {name:?}, expected one of {}",
+                names.join(", ")
+            )
+        })?;
+        tracing::info!("using resource profile {name:?}: {profile:?}");
+        Ok(profile)
+    }
+
+    /// Whether any profile meters fuel, in which case the engine must be created with fuel
+    /// metering.
+    pub fn fuel_metering() -> Result<bool> {
+        Ok(profiles()?.values().any(|profile| profile.fuel.is_some()))
+    }
+
+    /// The store limits of the profile.
+    pub fn store_limits(&self) -> AccountedLimits {
+        let mut limits = StoreLimitsBuilder::new();
+        if let Some(size) = self.max_memory_size {
+            limits = limits.memory_size(size);
+        }
+        if let Some(elements) = self.max_table_elements {
+            limits = limits.table_elements(elements);
+        }
+        if let Some(instances) = self.max_instances {
+            limits = limits.instances(instances);
+        }
+        AccountedLimits::new(limits.build())
+    }
+
+    /// Apply the limits of the profile to `store`, whose limits are at `limits`.
+    pub fn limit_store<T>(
+        &self,
+        store: &mut Store<T>,
+        limits: fn(&mut T) -> &mut AccountedLimits,
+    ) -> Result<()> {
+        store.limiter(move |data| limits(data));
+        // Setting the fuel fails if fuel metering is disabled
+        if store.get_fuel().is_ok() {
+            store.set_fuel(self.fuel.unwrap_or(u64::MAX))?;
+        }
+        Ok(())
+    }
+
+    /// Fuel consumed by `store`, when fuel is metered.
+    pub fn fuel_consumed<T>(&self, store: &Store<T>) -> Option<u64> {
+        let remaining = store.get_fuel().ok()?;
+        Some(self.fuel.unwrap_or(u64::MAX) - remaining)
+    }
+
+    /// Apply the pool size of the profile to the pooling allocator.
+    pub fn configure_pooling(&self, config: &mut PoolingAllocationConfig) {
+        if let Some(size) = self.pool_size {
+            config
+                .total_component_instances(size)
+                .total_core_instances(size)
+                .total_memories(size)
+                .total_tables(size);
+        }
+        if let Some(size) = self.max_memory_size {
+            config.max_memory_size(size);
+        }
+    }
+}
+
+fn builtin_profiles() -> HashMap<String, ResourceProfile> {
+    let profile = |memory_mib, tables, pool_size, concurrency| ResourceProfile {
+        max_memory_size: Some(memory_mib * MIB),
+        max_table_elements: Some(tables),
+        pool_size: Some(pool_size),
+        max_concurrency: Some(concurrency),
+        ..Default::default()
+    };
+    HashMap::from([
+        ("small".to_string(), profile(64, 10_000, 64, 16)),
+        ("medium".to_string(), profile(256, 20_000, 256, 64)),
+        ("large".to_string(), profile(1024, 50_000, 1000, 256)),
+    ])
+}
+
+/// The built-in profiles, and the profiles of the shim configuration.
+fn profiles() -> Result<HashMap<String, ResourceProfile>> {
+    let mut profiles = builtin_profiles();
+    if let Some(path) = std::env::var_os(PROFILES_ENV) {
+        let file = std::fs::read(&path)
+            .with_context(|| format!("failed to read resource profiles {path:?}"))?;
+        let custom: HashMap<String, ResourceProfile> = serde_json::from_slice(&file)
+            .with_context(|| format!("invalid resource profiles {path:?}"))?;
+        profiles.extend(custom);
+    }
+    Ok(profiles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_custom_profiles() -> Result<()> {
+        let custom: HashMap<String, ResourceProfile> =
+            serde_json::from_str(r#"{ "tiny": { "max_memory_size": 1048576, "fuel": 1000 } }"#)?;
+        assert_eq!(
+            custom["tiny"],
+            ResourceProfile {
+                fuel: Some(1000),
+                max_memory_size: Some(MIB),
+                ..Default::default()
+            }
+        );
+
+        let unknown = serde_json::from_str::<HashMap<String, ResourceProfile>>(
+            r#"{ "tiny": { "max_memory": 1048576 } }"#,
+        );
+        assert!(unknown.is_err());
+
+        assert!(builtin_profiles().values().all(|p| p.fuel.is_none()));
+
+        Ok(())
+    }
+}
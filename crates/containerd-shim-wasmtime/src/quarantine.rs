@@ -0,0 +1,237 @@
+//! Quarantine of the artifacts which repeatedly fail to compile or instantiate.
+//!
+//! A container whose component can't be compiled or instantiated is restarted by its pod in a
+//! loop, and every restart compiles the component again. When `RUNWASI_WASMTIME_QUARANTINE_DIR`
+//! is set in the environment of the shim, the failures of the OCI layers are recorded in that
+//! node-local directory, and a layer which failed too often is quarantined: the containers
+//! running it fail fast with the last error instead of compiling it again, until the quarantine
+//! expires. The shim is configured with:
+//! * `RUNWASI_WASMTIME_QUARANTINE_THRESHOLD`: the consecutive failures quarantining a layer,
+//!   3 by default,
+//! * `RUNWASI_WASMTIME_QUARANTINE_TTL`: the duration of the quarantine in seconds, 600 by
+//!   default.
+//!
+//! A layer whose guest is instantiated is cleared of its failures. The guests failing once they
+//! are running, e.g. with a trap, are not quarantined.
+
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+pub const QUARANTINE_DIR_ENV: &str = "RUNWASI_WASMTIME_QUARANTINE_DIR";
+pub const QUARANTINE_THRESHOLD_ENV: &str = "RUNWASI_WASMTIME_QUARANTINE_THRESHOLD";
+pub const QUARANTINE_TTL_ENV: &str = "RUNWASI_WASMTIME_QUARANTINE_TTL";
+
+const DEFAULT_THRESHOLD: u32 = 3;
+const DEFAULT_TTL: Duration = Duration::from_secs(600);
+
+/// The layer tracked by the container of the shim, with whether its guest was instantiated.
+static TRACKED: Mutex<Option<(Quarantine, String, bool)>> = Mutex::new(None);
+
+/// The failures of a layer.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Entry {
+    failures: u32,
+    /// The time of the last failure, in seconds since the UNIX epoch.
+    last_failure: u64,
+    error: String,
+}
+
+impl Entry {
+    /// Whether the failures recorded at `last_failure` still count at `now`.
+    fn is_live(&self, ttl: Duration, now: u64) -> bool {
+        now < self.last_failure.saturating_add(ttl.as_secs())
+    }
+
+    fn is_quarantined(&self, threshold: u32, ttl: Duration, now: u64) -> bool {
+        self.failures >= threshold && self.is_live(ttl, now)
+    }
+
+    /// Record a failure with `error` at `now`, the failures before the TTL being forgotten.
+    fn fail(&mut self, error: String, ttl: Duration, now: u64) {
+        if !self.is_live(ttl, now) {
+            self.failures = 0;
+        }
+        self.failures += 1;
+        self.last_failure = now;
+        self.error = error;
+    }
+}
+
+pub struct Quarantine {
+    dir: PathBuf,
+    threshold: u32,
+    ttl: Duration,
+}
+
+impl Quarantine {
+    /// The quarantine of the shim, if any.
+    pub fn from_env() -> Result<Option<Self>> {
+        let Some(dir) = std::env::var_os(QUARANTINE_DIR_ENV) else {
+            return Ok(None);
+        };
+        let threshold = std::env::var(QUARANTINE_THRESHOLD_ENV)
+            .ok()
+            .map(|v| v.trim().parse())
+            .transpose()
+            .context(QUARANTINE_THRESHOLD_ENV)?
+            .unwrap_or(DEFAULT_THRESHOLD)
+            .max(1);
+        let ttl = std::env::var(QUARANTINE_TTL_ENV)
+            .ok()
+            .map(|v| v.trim().parse().map(Duration::from_secs))
+            .transpose()
+            .context(QUARANTINE_TTL_ENV)?
+            .unwrap_or(DEFAULT_TTL);
+        Ok(Some(Self {
+            dir: dir.into(),
+            threshold,
+            ttl,
+        }))
+    }
+
+    fn path(&self, digest: &str) -> PathBuf {
+        let name = digest.replace([':', '/'], "-");
+        self.dir.join(format!("{name}.json"))
+    }
+
+    fn entry(&self, digest: &str) -> Entry {
+        std::fs::read(self.path(digest))
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default()
+    }
+
+    /// Fail if the layer with `digest` is quarantined.
+    pub fn check(&self, digest: &str) -> Result<()> {
+        let (entry, now) = (self.entry(digest), now());
+        if entry.is_quarantined(self.threshold, self.ttl, now) {
+            let remaining = entry.last_failure + self.ttl.as_secs() - now;
+            bail!(
+                "layer {digest} is quarantined for {remaining}s after failing {} times, last with: {}",
+                entry.failures,
+                entry.error
+            );
+        }
+        Ok(())
+    }
+
+    /// Record a failure of the layer with `digest` to compile or instantiate.
+    pub fn record_failure(&self, digest: &str, error: &anyhow::Error) -> Result<()> {
+        let mut entry = self.entry(digest);
+        entry.fail(format!("{error:#}"), self.ttl, now());
+        if entry.failures >= self.threshold {
+            tracing::warn!(
+                "quarantining layer {digest} for {}s after {} failures",
+                self.ttl.as_secs(),
+                entry.failures
+            );
+        }
+        write_atomic(&self.path(digest), &serde_json::to_vec(&entry)?)
+    }
+
+    /// Forget the failures of the layer with `digest`.
+    pub fn clear(&self, digest: &str) -> Result<()> {
+        match std::fs::remove_file(self.path(digest)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Write `path` so that concurrent readers either see the whole file or no file.
+fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let dir = path.parent().context("no parent directory")?;
+    std::fs::create_dir_all(dir)?;
+    let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+    std::fs::write(&tmp, contents)?;
+    if let Err(e) = std::fs::rename(&tmp, path) {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Fail fast if the layer with `digest` is quarantined, or else track it until its guest is
+/// [`instantiated`] or it [`failed`].
+pub(crate) fn track(quarantine: Quarantine, digest: String) -> Result<()> {
+    quarantine.check(&digest)?;
+    *TRACKED.lock().unwrap() = Some((quarantine, digest, false));
+    Ok(())
+}
+
+/// The guest of the tracked layer was instantiated: the layer is not a bad artifact.
+pub(crate) fn instantiated() {
+    let mut tracked = TRACKED.lock().unwrap();
+    if let Some((quarantine, digest, instantiated @ false)) = &mut *tracked {
+        *instantiated = true;
+        if let Err(e) = quarantine.clear(digest) {
+            tracing::warn!("failed to clear the failures of layer {digest}: {e:#}");
+        }
+    }
+}
+
+/// The container failed with `error`, which counts against the tracked layer unless its guest
+/// was instantiated.
+pub(crate) fn failed(error: &anyhow::Error) {
+    if let Some((quarantine, digest, false)) = &*TRACKED.lock().unwrap() {
+        if let Err(e) = quarantine.record_failure(digest, error) {
+            tracing::warn!("failed to record the failure of layer {digest}: {e:#}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry() {
+        let ttl = Duration::from_secs(600);
+        let mut entry = Entry::default();
+        entry.fail("invalid component".into(), ttl, 1000);
+        entry.fail("invalid component".into(), ttl, 1010);
+        assert!(!entry.is_quarantined(3, ttl, 1010));
+
+        entry.fail("invalid component".into(), ttl, 1020);
+        assert!(entry.is_quarantined(3, ttl, 1020));
+        assert!(entry.is_quarantined(3, ttl, 1619));
+        assert!(!entry.is_quarantined(3, ttl, 1620));
+
+        // The failures before the TTL are forgotten
+        entry.fail("invalid component".into(), ttl, 1620);
+        assert_eq!(entry.failures, 1);
+    }
+
+    #[test]
+    fn test_quarantine() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let quarantine = Quarantine {
+            dir: dir.path().to_path_buf(),
+            threshold: 2,
+            ttl: DEFAULT_TTL,
+        };
+        let error = anyhow::anyhow!("failed to parse component");
+
+        quarantine.record_failure("sha256:abcd", &error)?;
+        quarantine.check("sha256:abcd")?;
+        quarantine.record_failure("sha256:abcd", &error)?;
+        let err = quarantine.check("sha256:abcd").unwrap_err().to_string();
+        assert!(err.contains("failed to parse component"), "{err}");
+        quarantine.check("sha256:1234")?;
+
+        quarantine.clear("sha256:abcd")?;
+        quarantine.check("sha256:abcd")?;
+        quarantine.clear("sha256:abcd")?;
+        Ok(())
+    }
+}
@@ -0,0 +1,239 @@
+//! Live configuration of a running container.
+//!
+//! When the `runwasi.io/config-file` annotation is set to a JSON file of the container, e.g. a
+//! mounted ConfigMap, the file is read when the container starts, and again when it changes or
+//! when the task receives `SIGHUP`, e.g. with `ctr task kill --signal SIGHUP <id>`:
+//!
+//! ```json
+//! { "log_level": "debug", "max_concurrency": 32, "request_timeout": 30, "instance_pool_size": 8 }
+//! ```
+//!
+//! * `log_level`: maximum level of the logs of the container, e.g. `info` or `trace`.
+//! * `max_concurrency`: maximum number of HTTP requests handled concurrently, for containers
+//!   whose concurrency is limited, by `WASMTIME_HTTP_MAX_CONCURRENCY` or the resource profile.
+//! * `green_weight`: percentage of HTTP requests routed to the green component, if any.
+//! * `request_timeout`, `drain_timeout`: the timeouts of `WASMTIME_HTTP_REQUEST_TIMEOUT` and
+//!   `WASMTIME_HTTP_DRAIN_TIMEOUT`, in seconds, `0` for no timeout.
+//! * `instance_pool_size`: the number of pre-instantiated instances, or of stateful workers, for
+//!   containers with an instance pool.
+//!
+//! All the fields are optional, and removing a field keeps its current value. An invalid file
+//! is logged and ignored, so that a typo doesn't take the container down. Settings fixed when the
+//! engine is created, e.g. the pool size of a resource profile, can't be changed live.
+
+use std::ffi::CString;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{ensure, Context, Result};
+use containerd_shim_wasm::container::RuntimeContext;
+use log::LevelFilter;
+use serde::Deserialize;
+use tokio::io::unix::AsyncFd;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_util::sync::CancellationToken;
+
+pub const CONFIG_FILE_ANNOTATION: &str = "runwasi.io/config-file";
+
+/// The settings that can be changed without restarting the container.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LiveConfig {
+    #[serde(deserialize_with = "deserialize_level")]
+    pub log_level: Option<LevelFilter>,
+    pub max_concurrency: Option<usize>,
+    pub green_weight: Option<u64>,
+    pub request_timeout: Option<u64>,
+    pub drain_timeout: Option<u64>,
+    pub instance_pool_size: Option<usize>,
+}
+
+fn deserialize_level<'de, D>(deserializer: D) -> Result<Option<LevelFilter>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let level = String::deserialize(deserializer)?;
+    level.parse().map(Some).map_err(serde::de::Error::custom)
+}
+
+impl LiveConfig {
+    fn parse(contents: &str) -> Result<Self> {
+        let config: Self = serde_json::from_str(contents)?;
+        if let Some(weight) = config.green_weight {
+            ensure!(weight <= 100, "green_weight must be at most 100");
+        }
+        ensure!(
+            config.instance_pool_size != Some(0),
+            "instance_pool_size must be positive"
+        );
+        Ok(config)
+    }
+
+    fn read(path: &Path) -> Result<Self> {
+        let contents =
+            std::fs::read_to_string(path).with_context(|| format!("failed to read {path:?}"))?;
+        Self::parse(&contents).with_context(|| format!("invalid {path:?}"))
+    }
+}
+
+/// The live config file of the container, if any.
+pub fn config_file(ctx: &impl RuntimeContext) -> Option<PathBuf> {
+    ctx.annotations()
+        .get(CONFIG_FILE_ANNOTATION)
+        .map(PathBuf::from)
+}
+
+/// Call `apply` with the config in `path`, and again every time it changes, until `cancel` is
+/// triggered.
+pub async fn watch(path: PathBuf, apply: impl Fn(&LiveConfig), cancel: CancellationToken) {
+    let mut current = LiveConfig::default();
+    let reload = || match LiveConfig::read(&path) {
+        Ok(config) if config != current => {
+            tracing::info!("applying live config {config:?}");
+            apply(&config);
+            current = config;
+        }
+        Ok(_) => {}
+        Err(e) => tracing::warn!("ignoring live config: {e:?}"),
+    };
+    watch_files(std::slice::from_ref(&path), reload, cancel).await
+}
+
+/// Call `reload` now, and again every time the files in `paths` may have changed or the task
+/// receives `SIGHUP`, until `cancel` is triggered.
+pub async fn watch_files(paths: &[PathBuf], mut reload: impl FnMut(), cancel: CancellationToken) {
+    reload();
+
+    let mut sighup = match signal(SignalKind::hangup()) {
+        Ok(sighup) => sighup,
+        Err(e) => {
+            tracing::warn!("failed to watch SIGHUP: {e}");
+            return;
+        }
+    };
+    // ConfigMaps and Secrets are updated by swapping a symlink in their directory, so the
+    // directories are watched rather than the files.
+    let mut dirs: Vec<&Path> = paths
+        .iter()
+        .map(|path| match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        })
+        .collect();
+    dirs.sort();
+    dirs.dedup();
+    let inotify = inotify(&dirs)
+        .inspect_err(|e| tracing::warn!("failed to watch {dirs:?}, only reloading on SIGHUP: {e}"))
+        .ok();
+
+    loop {
+        tokio::select! {
+            _ = sighup.recv() => tracing::info!("reloading {paths:?} on SIGHUP"),
+            result = changed(inotify.as_ref()) => {
+                if let Err(e) = result {
+                    tracing::warn!("failed to watch {dirs:?}: {e}");
+                    return;
+                }
+            }
+            _ = cancel.cancelled() => return,
+        }
+        reload();
+    }
+}
+
+fn inotify(dirs: &[&Path]) -> io::Result<AsyncFd<OwnedFd>> {
+    let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let mask = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_CREATE | libc::IN_DELETE;
+    for dir in dirs {
+        let dir = CString::new(dir.as_os_str().as_bytes())?;
+        if unsafe { libc::inotify_add_watch(fd.as_raw_fd(), dir.as_ptr(), mask) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    AsyncFd::new(fd)
+}
+
+/// Wait for a change in the watched directory, forever when not watched.
+async fn changed(inotify: Option<&AsyncFd<OwnedFd>>) -> io::Result<()> {
+    let Some(inotify) = inotify else {
+        return std::future::pending().await;
+    };
+    loop {
+        let mut guard = inotify.readable().await?;
+        // The events are not parsed, any change reloads the file
+        let mut events = [0u8; 4096];
+        let read = guard.try_io(|fd| {
+            let n = unsafe { libc::read(fd.as_raw_fd(), events.as_mut_ptr().cast(), events.len()) };
+            if n < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+        match read {
+            Ok(result) => return result,
+            Err(_would_block) => continue,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let config = LiveConfig::parse(r#"{ "log_level": "debug", "max_concurrency": 8 }"#)?;
+        assert_eq!(
+            config,
+            LiveConfig {
+                log_level: Some(LevelFilter::Debug),
+                max_concurrency: Some(8),
+                ..Default::default()
+            }
+        );
+        let config = LiveConfig::parse(r#"{ "request_timeout": 0, "instance_pool_size": 2 }"#)?;
+        assert_eq!(config.request_timeout, Some(0));
+        assert_eq!(config.instance_pool_size, Some(2));
+
+        assert_eq!(LiveConfig::parse("{}")?, LiveConfig::default());
+        assert!(LiveConfig::parse(r#"{ "log_level": "loud" }"#).is_err());
+        assert!(LiveConfig::parse(r#"{ "green_weight": 101 }"#).is_err());
+        assert!(LiveConfig::parse(r#"{ "instance_pool_size": 0 }"#).is_err());
+        assert!(LiveConfig::parse(r#"{ "pool_size": 10 }"#).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_watch() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("config.json");
+        std::fs::write(&path, r#"{ "max_concurrency": 1 }"#)?;
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let cancel = CancellationToken::new();
+        let task = tokio::spawn(watch(
+            path.clone(),
+            move |config| tx.send(config.clone()).unwrap(),
+            cancel.clone(),
+        ));
+
+        let config = rx.recv().await.unwrap();
+        assert_eq!(config.max_concurrency, Some(1));
+
+        std::fs::write(&path, r#"{ "max_concurrency": 2 }"#)?;
+        let config = rx.recv().await.unwrap();
+        assert_eq!(config.max_concurrency, Some(2));
+
+        cancel.cancel();
+        task.await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,243 @@
+//! Scheduled snapshots of the memory of a long-running module, restored after a crash.
+//!
+//! When the `runwasi.io/snapshot-dir` annotation is set to a directory of the container, e.g. a
+//! volume outliving the container, the exported `memory` of the module is written to that
+//! directory periodically, named `snapshot-<timestamp in ms>.bin`. When the container is
+//! restarted after a crash, the module is instantiated with the memory of the last snapshot and
+//! resumed with its `runwasi.resume` export, instead of cold-starting with its start function.
+//! * `runwasi.io/snapshot-interval`: seconds between the snapshots (default: 60).
+//! * `runwasi.io/snapshot-retention`: snapshots kept, the older ones being removed (default: 3).
+//!
+//! Only the memory is restored: the call stack, the globals, and the host resources, e.g. open
+//! files or sockets, start afresh, so the module opts in by exporting `runwasi.resume`, which
+//! reopens its resources from the state in memory. The snapshots are only restored for the module
+//! they were taken from, identified by the digest of its layer, and removed when the module exits
+//! successfully, so that the next start is a cold one.
+//!
+//! Like the memory dumps, the snapshots are taken at the epoch checks of the guest, so that the
+//! memory is consistent. Components are not supported, as their memories are not reachable from
+//! the host.
+
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{ensure, Context, Result};
+use containerd_shim_wasm::container::RuntimeContext;
+use wasmtime::{AsContext, AsContextMut, Engine, Memory};
+
+pub const SNAPSHOT_DIR_ANNOTATION: &str = "runwasi.io/snapshot-dir";
+pub const SNAPSHOT_INTERVAL_ANNOTATION: &str = "runwasi.io/snapshot-interval";
+pub const SNAPSHOT_RETENTION_ANNOTATION: &str = "runwasi.io/snapshot-retention";
+
+/// The export resuming a module from the memory of a snapshot.
+pub const RESUME_FUNC: &str = "runwasi.resume";
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+const DEFAULT_RETENTION: usize = 3;
+const WASM_PAGE_SIZE: u64 = 65536;
+
+pub(crate) struct Snapshots {
+    /// The directory of the snapshots of the module.
+    dir: PathBuf,
+    interval: Duration,
+    retention: usize,
+    requested: AtomicBool,
+    /// Whether a snapshot is being written, the next ones being skipped until it is done.
+    writing: Arc<AtomicBool>,
+    memory: Mutex<Option<Memory>>,
+}
+
+impl Snapshots {
+    /// The snapshot settings of the container, `None` when snapshots are not enabled.
+    pub fn from_ctx(ctx: &impl RuntimeContext, digest: Option<&str>) -> Result<Option<Arc<Self>>> {
+        let annotations = ctx.annotations();
+        let Some(dir) = annotations.get(SNAPSHOT_DIR_ANNOTATION) else {
+            return Ok(None);
+        };
+        let interval = annotations
+            .get(SNAPSHOT_INTERVAL_ANNOTATION)
+            .map(|v| v.trim().parse())
+            .transpose()
+            .with_context(|| format!("invalid {SNAPSHOT_INTERVAL_ANNOTATION}"))?
+            .map_or(DEFAULT_INTERVAL, Duration::from_secs);
+        ensure!(
+            !interval.is_zero(),
+            "{SNAPSHOT_INTERVAL_ANNOTATION} must be positive"
+        );
+        let retention = annotations
+            .get(SNAPSHOT_RETENTION_ANNOTATION)
+            .map(|v| v.trim().parse())
+            .transpose()
+            .with_context(|| format!("invalid {SNAPSHOT_RETENTION_ANNOTATION}"))?
+            .unwrap_or(DEFAULT_RETENTION);
+        ensure!(
+            retention > 0,
+            "{SNAPSHOT_RETENTION_ANNOTATION} must be positive"
+        );
+
+        // The memory of another module must never be restored
+        let Some(digest) = digest else {
+            tracing::warn!("snapshots are disabled, the module has no layer digest");
+            return Ok(None);
+        };
+
+        Ok(Some(Arc::new(Self {
+            dir: Path::new(dir).join(digest.replace(':', "-")),
+            interval,
+            retention,
+            requested: AtomicBool::new(false),
+            writing: Arc::default(),
+            memory: Mutex::new(None),
+        })))
+    }
+
+    /// Take snapshots of `memory` from now on.
+    pub fn set_memory(&self, memory: Memory) {
+        *self.memory.lock().unwrap() = Some(memory);
+    }
+
+    /// Request a snapshot every interval, and interrupt the guest running on `engine` to take it.
+    pub fn schedule(self: &Arc<Self>, engine: &Engine) {
+        let (snapshots, engine) = (self.clone(), engine.clone());
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(snapshots.interval);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                snapshots.requested.store(true, Ordering::Relaxed);
+                engine.increment_epoch();
+            }
+        });
+    }
+
+    /// Called on the epoch checks of the guest, takes the requested snapshot.
+    pub fn on_epoch(self: &Arc<Self>, store: impl AsContext) {
+        if !self.requested.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        let Some(memory) = *self.memory.lock().unwrap() else {
+            return;
+        };
+        if self.writing.swap(true, Ordering::Acquire) {
+            tracing::debug!("skipping a snapshot, the previous one is still being written");
+            return;
+        }
+        // Copied so that the guest resumes while the snapshot is written
+        let data = memory.data(&store).to_vec();
+        let snapshots = self.clone();
+        tokio::task::spawn_blocking(move || {
+            match snapshots.write(&data) {
+                Ok(path) => tracing::debug!("wrote memory snapshot {path:?}"),
+                Err(e) => tracing::warn!("failed to write memory snapshot: {e:?}"),
+            }
+            snapshots.writing.store(false, Ordering::Release);
+        });
+    }
+
+    /// Restore the memory of the last snapshot to `memory`, returning its path, or `None` when
+    /// there is no snapshot.
+    pub fn restore(&self, mut store: impl AsContextMut, memory: Memory) -> Result<Option<PathBuf>> {
+        let Some(path) = self.snapshots()?.pop() else {
+            return Ok(None);
+        };
+        let data = std::fs::read(&path).with_context(|| format!("failed to read {path:?}"))?;
+        let size = memory.data_size(&store) as u64;
+        let missing = (data.len() as u64).saturating_sub(size);
+        memory
+            .grow(&mut store, missing.div_ceil(WASM_PAGE_SIZE))
+            .with_context(|| format!("failed to grow the memory to the size of {path:?}"))?;
+        memory.data_mut(&mut store)[..data.len()].copy_from_slice(&data);
+        Ok(Some(path))
+    }
+
+    /// Remove the snapshots, once the module exited successfully.
+    pub fn clear(&self) {
+        match std::fs::remove_dir_all(&self.dir) {
+            Ok(()) => tracing::info!("removed the memory snapshots"),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => tracing::warn!("failed to remove the memory snapshots: {e}"),
+        }
+    }
+
+    fn write(&self, data: &[u8]) -> Result<PathBuf> {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let path = self.dir.join(format!("snapshot-{timestamp_ms}.bin"));
+
+        // Written aside and renamed, so that a crash never leaves a truncated snapshot
+        std::fs::create_dir_all(&self.dir)?;
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, &path).with_context(|| format!("failed to write {path:?}"))?;
+
+        let snapshots = self.snapshots()?;
+        let expired = snapshots.len().saturating_sub(self.retention);
+        for old in &snapshots[..expired] {
+            std::fs::remove_file(old)?;
+        }
+        Ok(path)
+    }
+
+    /// The snapshots of the module, oldest first.
+    fn snapshots(&self) -> Result<Vec<PathBuf>> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {:?}", self.dir)),
+        };
+        let mut snapshots = vec![];
+        for entry in entries {
+            let path = entry?.path();
+            let timestamp = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix("snapshot-"))
+                .and_then(|name| name.strip_suffix(".bin"))
+                .and_then(|timestamp| timestamp.parse::<u64>().ok());
+            if let Some(timestamp) = timestamp {
+                snapshots.push((timestamp, path));
+            }
+        }
+        snapshots.sort();
+        Ok(snapshots.into_iter().map(|(_, path)| path).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retention() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let snapshots = Snapshots {
+            dir: dir.path().join("sha256-1234"),
+            interval: DEFAULT_INTERVAL,
+            retention: 2,
+            requested: AtomicBool::new(false),
+            writing: Arc::default(),
+            memory: Mutex::new(None),
+        };
+        assert!(snapshots.snapshots()?.is_empty());
+
+        for data in [b"first", b"secnd", b"third"] {
+            snapshots.write(data)?;
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        let kept = snapshots.snapshots()?;
+        assert_eq!(kept.len(), 2);
+        assert_eq!(std::fs::read(&kept[1])?, b"third");
+
+        snapshots.clear();
+        assert!(snapshots.snapshots()?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,314 @@
+//! The `wasi:sockets` interfaces of the components.
+//!
+//! The interfaces are implemented by wasmtime, and the socket policy of the container is applied
+//! through its `socket_addr_check`. But the shim also sees what the check doesn't: the datagrams
+//! received by the guest are counted, and the names resolved by the guest go through the
+//! [DNS cache](crate::outgoing::dns) when it's enabled. With
+//! [Happy Eyeballs](crate::outgoing::happy_eyeballs), the resolved addresses are interleaved by
+//! family starting with the preferred one, so that a guest connecting to them in order follows
+//! the same policy as the outgoing `wasi:http` requests. The implementations of
+//! `wasi:sockets/udp` and `wasi:sockets/ip-name-lookup` of wasmtime are wrapped, and replace them
+//! in the linkers. The UDP counters of the container are served as metrics.
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::Ordering;
+
+use anyhow::Result;
+use containerd_shim_wasm::container::socket_policy::UdpStats;
+use wasmtime::component::{Linker, Resource};
+use wasmtime_wasi::bindings::io::poll::Pollable;
+use wasmtime_wasi::bindings::sockets::ip_name_lookup::{self, ResolveAddressStream};
+use wasmtime_wasi::bindings::sockets::network::{
+    self, ErrorCode, IpAddress, IpAddressFamily, IpSocketAddress, Network,
+};
+use wasmtime_wasi::bindings::sockets::udp::{
+    self, IncomingDatagram, IncomingDatagramStream, OutgoingDatagram, OutgoingDatagramStream,
+    UdpSocket,
+};
+use wasmtime_wasi::{async_trait, SocketError, SocketResult, WasiImpl, WasiView};
+
+use crate::instance::WasiPreview2Ctx;
+use crate::metrics::{write_metric, MetricsSource};
+
+/// Replace the `wasi:sockets/udp` and `wasi:sockets/ip-name-lookup` interfaces of the linker
+/// with [`Sockets`].
+pub(crate) fn add_to_linker(linker: &mut Linker<WasiPreview2Ctx>) -> Result<()> {
+    linker.allow_shadowing(true);
+    let res = udp::add_to_linker_get_host(linker, sockets)
+        .and_then(|_| ip_name_lookup::add_to_linker_get_host(linker, sockets));
+    linker.allow_shadowing(false);
+    res
+}
+
+fn sockets(ctx: &mut WasiPreview2Ctx) -> Sockets<'_> {
+    Sockets {
+        wasi: WasiImpl(ctx),
+    }
+}
+
+impl MetricsSource for UdpStats {
+    fn write_metrics(&self, metrics: &mut String) {
+        let counters = [
+            ("binds", "UDP sockets bound by the guest.", &self.binds),
+            (
+                "datagrams_sent",
+                "UDP datagrams sent by the guest.",
+                &self.datagrams_sent,
+            ),
+            (
+                "datagrams_received",
+                "UDP datagrams received by the guest.",
+                &self.datagrams_received,
+            ),
+            (
+                "denied",
+                "UDP operations of the guest denied by the socket policy.",
+                &self.denied,
+            ),
+        ];
+        for (name, help, counter) in counters {
+            let value = counter.load(Ordering::Relaxed) as f64;
+            let name = format!("runwasi_udp_{name}_total");
+            write_metric(metrics, &name, "counter", help, value);
+        }
+    }
+}
+
+/// The `wasi:sockets` implementation of wasmtime, with the received datagrams counted and the
+/// names resolved with the DNS cache and ordered by the family preference.
+struct Sockets<'a> {
+    wasi: WasiImpl<&'a mut WasiPreview2Ctx>,
+}
+
+#[async_trait]
+impl network::Host for Sockets<'_> {
+    fn convert_error_code(&mut self, err: SocketError) -> Result<ErrorCode> {
+        network::Host::convert_error_code(&mut self.wasi, err)
+    }
+
+    fn network_error_code(&mut self, err: Resource<anyhow::Error>) -> Result<Option<ErrorCode>> {
+        network::Host::network_error_code(&mut self.wasi, err)
+    }
+}
+
+#[async_trait]
+impl network::HostNetwork for Sockets<'_> {
+    fn drop(&mut self, this: Resource<Network>) -> Result<()> {
+        network::HostNetwork::drop(&mut self.wasi, this)
+    }
+}
+
+impl udp::Host for Sockets<'_> {}
+
+#[async_trait]
+impl udp::HostUdpSocket for Sockets<'_> {
+    async fn start_bind(
+        &mut self,
+        this: Resource<UdpSocket>,
+        network: Resource<Network>,
+        local_address: IpSocketAddress,
+    ) -> SocketResult<()> {
+        udp::HostUdpSocket::start_bind(&mut self.wasi, this, network, local_address).await
+    }
+
+    fn finish_bind(&mut self, this: Resource<UdpSocket>) -> SocketResult<()> {
+        udp::HostUdpSocket::finish_bind(&mut self.wasi, this)
+    }
+
+    async fn stream(
+        &mut self,
+        this: Resource<UdpSocket>,
+        remote_address: Option<IpSocketAddress>,
+    ) -> SocketResult<(
+        Resource<IncomingDatagramStream>,
+        Resource<OutgoingDatagramStream>,
+    )> {
+        udp::HostUdpSocket::stream(&mut self.wasi, this, remote_address).await
+    }
+
+    fn local_address(&mut self, this: Resource<UdpSocket>) -> SocketResult<IpSocketAddress> {
+        udp::HostUdpSocket::local_address(&mut self.wasi, this)
+    }
+
+    fn remote_address(&mut self, this: Resource<UdpSocket>) -> SocketResult<IpSocketAddress> {
+        udp::HostUdpSocket::remote_address(&mut self.wasi, this)
+    }
+
+    fn address_family(&mut self, this: Resource<UdpSocket>) -> Result<IpAddressFamily> {
+        udp::HostUdpSocket::address_family(&mut self.wasi, this)
+    }
+
+    fn unicast_hop_limit(&mut self, this: Resource<UdpSocket>) -> SocketResult<u8> {
+        udp::HostUdpSocket::unicast_hop_limit(&mut self.wasi, this)
+    }
+
+    fn set_unicast_hop_limit(&mut self, this: Resource<UdpSocket>, value: u8) -> SocketResult<()> {
+        udp::HostUdpSocket::set_unicast_hop_limit(&mut self.wasi, this, value)
+    }
+
+    fn receive_buffer_size(&mut self, this: Resource<UdpSocket>) -> SocketResult<u64> {
+        udp::HostUdpSocket::receive_buffer_size(&mut self.wasi, this)
+    }
+
+    fn set_receive_buffer_size(
+        &mut self,
+        this: Resource<UdpSocket>,
+        value: u64,
+    ) -> SocketResult<()> {
+        udp::HostUdpSocket::set_receive_buffer_size(&mut self.wasi, this, value)
+    }
+
+    fn send_buffer_size(&mut self, this: Resource<UdpSocket>) -> SocketResult<u64> {
+        udp::HostUdpSocket::send_buffer_size(&mut self.wasi, this)
+    }
+
+    fn set_send_buffer_size(&mut self, this: Resource<UdpSocket>, value: u64) -> SocketResult<()> {
+        udp::HostUdpSocket::set_send_buffer_size(&mut self.wasi, this, value)
+    }
+
+    fn subscribe(&mut self, this: Resource<UdpSocket>) -> Result<Resource<Pollable>> {
+        udp::HostUdpSocket::subscribe(&mut self.wasi, this)
+    }
+
+    fn drop(&mut self, this: Resource<UdpSocket>) -> Result<()> {
+        udp::HostUdpSocket::drop(&mut self.wasi, this)
+    }
+}
+
+#[async_trait]
+impl udp::HostIncomingDatagramStream for Sockets<'_> {
+    fn receive(
+        &mut self,
+        this: Resource<IncomingDatagramStream>,
+        max_results: u64,
+    ) -> SocketResult<Vec<IncomingDatagram>> {
+        let datagrams =
+            udp::HostIncomingDatagramStream::receive(&mut self.wasi, this, max_results)?;
+        self.wasi
+            .0
+            .udp_stats
+            .datagrams_received
+            .fetch_add(datagrams.len() as u64, Ordering::Relaxed);
+        Ok(datagrams)
+    }
+
+    fn subscribe(&mut self, this: Resource<IncomingDatagramStream>) -> Result<Resource<Pollable>> {
+        udp::HostIncomingDatagramStream::subscribe(&mut self.wasi, this)
+    }
+
+    fn drop(&mut self, this: Resource<IncomingDatagramStream>) -> Result<()> {
+        udp::HostIncomingDatagramStream::drop(&mut self.wasi, this)
+    }
+}
+
+#[async_trait]
+impl udp::HostOutgoingDatagramStream for Sockets<'_> {
+    fn check_send(&mut self, this: Resource<OutgoingDatagramStream>) -> SocketResult<u64> {
+        udp::HostOutgoingDatagramStream::check_send(&mut self.wasi, this)
+    }
+
+    async fn send(
+        &mut self,
+        this: Resource<OutgoingDatagramStream>,
+        datagrams: Vec<OutgoingDatagram>,
+    ) -> SocketResult<u64> {
+        udp::HostOutgoingDatagramStream::send(&mut self.wasi, this, datagrams).await
+    }
+
+    fn subscribe(&mut self, this: Resource<OutgoingDatagramStream>) -> Result<Resource<Pollable>> {
+        udp::HostOutgoingDatagramStream::subscribe(&mut self.wasi, this)
+    }
+
+    fn drop(&mut self, this: Resource<OutgoingDatagramStream>) -> Result<()> {
+        udp::HostOutgoingDatagramStream::drop(&mut self.wasi, this)
+    }
+}
+
+#[async_trait]
+impl ip_name_lookup::Host for Sockets<'_> {
+    fn resolve_addresses(
+        &mut self,
+        network: Resource<Network>,
+        name: String,
+    ) -> SocketResult<Resource<ResolveAddressStream>> {
+        // IP addresses and international names are left to wasmtime, which parses them
+        let outgoing = &self.wasi.0.outgoing;
+        let intercept = outgoing.dns().is_some() || outgoing.happy_eyeballs().is_some();
+        if !intercept || !is_ascii_name(&name) {
+            return ip_name_lookup::Host::resolve_addresses(&mut self.wasi, network, name);
+        }
+        let outgoing = outgoing.clone();
+        if !self.wasi.table().get(&network)?.allow_ip_name_lookup {
+            return Err(ErrorCode::PermanentResolverFailure.into());
+        }
+
+        let task = wasmtime_wasi::runtime::spawn(async move {
+            let mut addrs = outgoing.resolve(&name, 0).await.map_err(|e| {
+                tracing::debug!("failed to resolve {name:?}: {e}");
+                SocketError::from(ErrorCode::NameUnresolvable)
+            })?;
+            if let Some(happy_eyeballs) = outgoing.happy_eyeballs() {
+                addrs = happy_eyeballs.sort(addrs);
+            }
+            Ok(addrs
+                .iter()
+                .map(SocketAddr::ip)
+                .map(IpAddress::from)
+                .collect())
+        });
+        let stream = ResolveAddressStream::Waiting(task);
+        Ok(self.wasi.table().push(stream)?)
+    }
+}
+
+#[async_trait]
+impl ip_name_lookup::HostResolveAddressStream for Sockets<'_> {
+    fn resolve_next_address(
+        &mut self,
+        this: Resource<ResolveAddressStream>,
+    ) -> SocketResult<Option<IpAddress>> {
+        ip_name_lookup::HostResolveAddressStream::resolve_next_address(&mut self.wasi, this)
+    }
+
+    fn subscribe(&mut self, this: Resource<ResolveAddressStream>) -> Result<Resource<Pollable>> {
+        ip_name_lookup::HostResolveAddressStream::subscribe(&mut self.wasi, this)
+    }
+
+    fn drop(&mut self, this: Resource<ResolveAddressStream>) -> Result<()> {
+        ip_name_lookup::HostResolveAddressStream::drop(&mut self.wasi, this)
+    }
+}
+
+/// Whether `name` is a domain name, rather than an IP address, that needs no IDNA conversion.
+fn is_ascii_name(name: &str) -> bool {
+    let literal = name.trim_start_matches('[').trim_end_matches(']');
+    name.is_ascii() && literal.parse::<IpAddr>().is_err()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_ascii_name() {
+        assert!(is_ascii_name("example.com"));
+        assert!(!is_ascii_name("127.0.0.1"));
+        assert!(!is_ascii_name("::1"));
+        assert!(!is_ascii_name("[::1]"));
+        assert!(!is_ascii_name("bücher.example"));
+    }
+
+    #[test]
+    fn test_udp_metrics() {
+        let stats = UdpStats::default();
+        stats.binds.fetch_add(1, Ordering::Relaxed);
+        stats.datagrams_received.fetch_add(3, Ordering::Relaxed);
+
+        let mut metrics = String::new();
+        stats.write_metrics(&mut metrics);
+        assert!(metrics.contains("# TYPE runwasi_udp_binds_total counter\n"));
+        assert!(metrics.contains("runwasi_udp_binds_total 1\n"));
+        assert!(metrics.contains("runwasi_udp_datagrams_received_total 3\n"));
+        assert!(metrics.contains("runwasi_udp_denied_total 0\n"));
+    }
+}
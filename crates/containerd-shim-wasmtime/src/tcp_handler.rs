@@ -0,0 +1,218 @@
+//! Serve raw TCP connections with a component.
+//!
+//! Components exporting the `runwasi:tcp/handler` interface are served by a TCP listener
+//! bound by the shim. Every accepted connection gets a fresh instance of the component, and
+//! the connection is handed to the guest as a pair of `wasi:io` streams:
+//!
+//! ```wit
+//! interface handler {
+//!   use wasi:io/streams@0.2.0.{input-stream, output-stream};
+//!
+//!   handle: func(input: input-stream, output: output-stream);
+//! }
+//! ```
+//!
+//! This allows components implementing custom protocols to be served without `wasi:http`.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use containerd_shim_wasm::container::priority::Priority;
+use containerd_shim_wasm::container::{report_phase, Phase, RuntimeContext};
+use tokio::net::TcpStream;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use wasmtime::component::{Component, ComponentExportIndex, InstancePre, Resource, ResourceTable};
+use wasmtime::Store;
+use wasmtime_wasi::pipe::{AsyncReadStream, AsyncWriteStream};
+use wasmtime_wasi::{InputStream, OutputStream};
+use wasmtime_wasi_http::WasiHttpCtx;
+
+use crate::audit::{component_imports, Audit};
+use crate::chaos::Chaos;
+use crate::diagnostics::TrapContext;
+use crate::http_proxy::{bind_listener, tcp_accept, DEFAULT_BACKLOG};
+use crate::instance::{envs_from_ctx, new_store, WasiPreview2Ctx};
+use crate::kv_cache::KvCache;
+use crate::outgoing::Outgoing;
+use crate::profile::ResourceProfile;
+use crate::timezone::TimeZone;
+use crate::{guardrails, quarantine};
+
+pub const TCP_HANDLER_INTERFACE: &str = "runwasi:tcp/handler@0.1.0";
+
+const DEFAULT_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 9000);
+
+// Matches the write budget wasmtime uses for its own stdio streams.
+const WRITE_BUDGET: usize = 1024 * 1024;
+
+pub(crate) async fn serve_tcp(
+    ctx: &impl RuntimeContext,
+    component: &Component,
+    instance_pre: InstancePre<WasiPreview2Ctx>,
+    cancel: CancellationToken,
+) -> Result<()> {
+    let mut env = envs_from_ctx(ctx).into_iter().collect::<HashMap<_, _>>();
+
+    // Consume env variables for TCP server settings before passing it to handler
+    let addr = env
+        .remove("WASMTIME_TCP_SOCKET_ADDR")
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_ADDR);
+    let backlog = env
+        .remove("WASMTIME_TCP_BACKLOG")
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_BACKLOG);
+
+    let handle = handle_export_index(component)?;
+
+    let listener = bind_listener(addr, backlog)?;
+    let tracker = TaskTracker::new();
+
+    tracing::info!("Serving TCP on {}", listener.local_addr()?);
+    quarantine::instantiated();
+    report_phase(Phase::Serving);
+
+    let handler = Arc::new(TcpHandler {
+        instance_pre,
+        handle,
+        env: env.into_iter().collect(),
+        outgoing: Outgoing::from_ctx(ctx)?,
+        timezone: TimeZone::from_ctx(ctx),
+        kv_cache: KvCache::from_ctx(ctx)?,
+        audit: Audit::from_ctx(ctx)?,
+        profile: ResourceProfile::from_ctx(ctx)?,
+        priority: Priority::from_ctx(ctx)?,
+        chaos: Chaos::from_ctx(ctx)?,
+        trap_ctx: TrapContext::from_ctx(ctx),
+        next_id: AtomicU64::from(0),
+    });
+    if let Some(audit) = &handler.audit {
+        audit.imports(component_imports(handler.instance_pre.engine(), component));
+    }
+
+    loop {
+        let stream = tokio::select! {
+            conn = tcp_accept(&listener) => {
+                match conn {
+                    Some(conn) => conn,
+                    None => continue,
+                }
+            }
+            _ = cancel.cancelled() => {
+                break;
+            }
+        };
+
+        if guardrails::should_shed() {
+            tracing::debug!("closing connection, shedding load");
+            continue;
+        }
+
+        let h = handler.clone();
+        tracker.spawn(async move {
+            let conn_id = h.next_id.fetch_add(1, Ordering::Relaxed);
+            if let Err(e) = h.handle_connection(conn_id, stream).await {
+                tracing::error!("[{conn_id}] :: {e:?}");
+            }
+        });
+    }
+
+    tracker.close();
+    tracker.wait().await;
+
+    Ok(())
+}
+
+fn handle_export_index(component: &Component) -> Result<ComponentExportIndex> {
+    let (_, interface) = component
+        .export_index(None, TCP_HANDLER_INTERFACE)
+        .with_context(|| format!("component does not export {TCP_HANDLER_INTERFACE}"))?;
+    let (_, handle) = component
+        .export_index(Some(&interface), "handle")
+        .with_context(|| format!("{TCP_HANDLER_INTERFACE} does not export `handle`"))?;
+    Ok(handle)
+}
+
+struct TcpHandler {
+    instance_pre: InstancePre<WasiPreview2Ctx>,
+    handle: ComponentExportIndex,
+    env: Vec<(String, String)>,
+    outgoing: Arc<Outgoing>,
+    timezone: Arc<TimeZone>,
+    kv_cache: Arc<KvCache>,
+    audit: Option<Arc<Audit>>,
+    profile: ResourceProfile,
+    priority: Priority,
+    chaos: Arc<Chaos>,
+    trap_ctx: Option<Arc<TrapContext>>,
+    next_id: AtomicU64,
+}
+
+impl TcpHandler {
+    fn wasi_store_for_connection(&self, conn_id: u64) -> Result<Store<WasiPreview2Ctx>> {
+        let engine = self.instance_pre.engine();
+        let mut builder = wasmtime_wasi::WasiCtxBuilder::new();
+
+        builder.envs(&self.env);
+        builder.env("CONNECTION_ID", conn_id.to_string());
+        if let Some(trap_ctx) = &self.trap_ctx {
+            builder.stderr(trap_ctx.stderr());
+        }
+
+        let ctx = WasiPreview2Ctx {
+            wasi_ctx: builder.build(),
+            wasi_http: WasiHttpCtx::new(),
+            resource_table: ResourceTable::default(),
+            #[cfg(unix)]
+            unix_sockets: Default::default(),
+            #[cfg(unix)]
+            fs_events: None,
+            outgoing: self.outgoing.clone(),
+            timezone: self.timezone.clone(),
+            kv_cache: self.kv_cache.clone(),
+            write_quotas: None,
+            audit: self.audit.clone(),
+            udp_stats: Default::default(),
+            descriptor_paths: Default::default(),
+            descriptor_quotas: Default::default(),
+            limits: self.profile.store_limits(),
+            priority: self.priority,
+            extensions: Default::default(),
+        };
+
+        new_store(engine, ctx, &self.profile)
+    }
+
+    async fn handle_connection(&self, conn_id: u64, stream: TcpStream) -> Result<()> {
+        tracing::trace!("Connection {conn_id} from {:?}", stream.peer_addr());
+
+        let mut store = self.wasi_store_for_connection(conn_id)?;
+
+        let (reader, writer) = stream.into_split();
+        let input: InputStream = Box::new(AsyncReadStream::new(reader));
+        let output: OutputStream = Box::new(AsyncWriteStream::new(WRITE_BUDGET, writer));
+        let input = store.data_mut().resource_table.push(input)?;
+        let output = store.data_mut().resource_table.push(output)?;
+
+        self.chaos.instantiate().await;
+        let instance = self.instance_pre.instantiate_async(&mut store).await?;
+        let handle = instance
+            .get_typed_func::<(Resource<InputStream>, Resource<OutputStream>), ()>(
+                &mut store,
+                &self.handle,
+            )?;
+
+        let result = handle.call_async(&mut store, (input, output)).await;
+        if let (Err(e), Some(trap_ctx)) = (&result, &self.trap_ctx) {
+            trap_ctx.capture(e, None, self.profile.fuel_consumed(&store));
+        }
+        result?;
+        handle.post_return_async(&mut store).await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,80 @@
+//! Per-tenant isolation of the engines.
+//!
+//! The containers of a shim share a wasmtime engine, with its pooling allocator, and the code
+//! cache. When `RUNWASI_WASMTIME_TENANT_ISOLATION` is `true` in the environment of the shim, the
+//! containers of each tenant, set with the `runwasi.io/tenant` annotation, get their own engine
+//! and their own directory in the code cache instead, trading memory for a smaller blast radius
+//! on multi-tenant platforms. Containers without the annotation share the default engine.
+
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, Mutex};
+
+use anyhow::{ensure, Result};
+use containerd_shim_wasm::container::RuntimeContext;
+use wasmtime::Engine;
+
+use crate::linker::Linkers;
+
+pub const TENANT_ANNOTATION: &str = "runwasi.io/tenant";
+pub const TENANT_ISOLATION_ENV: &str = "RUNWASI_WASMTIME_TENANT_ISOLATION";
+
+/// The engines of the tenants, created for their first container.
+static ENGINES: LazyLock<Mutex<HashMap<String, (Engine, Arc<Linkers>)>>> =
+    LazyLock::new(Default::default);
+
+/// Whether the containers are isolated by tenant.
+pub fn enabled() -> bool {
+    std::env::var(TENANT_ISOLATION_ENV).is_ok_and(|v| v.trim().eq_ignore_ascii_case("true"))
+}
+
+/// The tenant of the container, if the containers are isolated by tenant.
+pub fn from_ctx(ctx: &impl RuntimeContext) -> Result<Option<String>> {
+    if !enabled() {
+        return Ok(None);
+    }
+    let Some(tenant) = ctx.annotations().get(TENANT_ANNOTATION) else {
+        return Ok(None);
+    };
+    let tenant = tenant.trim();
+    ensure!(is_valid(tenant), "invalid {TENANT_ANNOTATION} {tenant:?}");
+    Ok(Some(tenant.to_string()))
+}
+
+/// The engine of `tenant`, created with `new` for its first container.
+pub(crate) fn engine(
+    tenant: &str,
+    new: impl FnOnce() -> Result<(Engine, Arc<Linkers>)>,
+) -> Result<(Engine, Arc<Linkers>)> {
+    let mut engines = ENGINES.lock().unwrap();
+    if let Some(engine) = engines.get(tenant) {
+        return Ok(engine.clone());
+    }
+    tracing::info!("creating the engine of tenant {tenant:?}");
+    let engine = new()?;
+    engines.insert(tenant.to_string(), engine.clone());
+    Ok(engine)
+}
+
+/// Whether `tenant` can be used as the name of its directory in the code cache.
+fn is_valid(tenant: &str) -> bool {
+    !tenant.is_empty()
+        && tenant.len() <= 253
+        && !tenant.starts_with('.')
+        && tenant
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_valid() {
+        assert!(is_valid("team-a"));
+        assert!(is_valid("org_1.prod"));
+        assert!(!is_valid(""));
+        assert!(!is_valid(".."));
+        assert!(!is_valid("a/b"));
+    }
+}
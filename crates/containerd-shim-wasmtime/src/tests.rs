@@ -1,8 +1,12 @@
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
 use std::time::Duration;
 
+use containerd_shim_wasm::container::fallback::FALLBACK_FUNCTIONS_ANNOTATION;
 use containerd_shim_wasm::container::Instance;
+use containerd_shim_wasm::testing::conformance::Conformance;
 use containerd_shim_wasm::testing::modules::*;
-use containerd_shim_wasm::testing::{oci_helpers, WasiTest};
+use containerd_shim_wasm::testing::{http_helpers, oci_helpers, WasiTest};
 use serial_test::serial;
 use wasmtime::Config;
 use WasmtimeTestInstance as WasiInstance;
@@ -158,6 +162,22 @@ fn test_custom_entrypoint() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[test]
+#[serial]
+fn test_fallback_function() -> anyhow::Result<()> {
+    let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
+        .with_annotation(FALLBACK_FUNCTIONS_ANNOTATION, "main,foo")
+        .with_wasm(CUSTOM_ENTRYPOINT)?
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "hello world\n");
+
+    Ok(())
+}
+
 #[test]
 #[serial]
 fn test_unreachable() -> anyhow::Result<()> {
@@ -274,16 +294,16 @@ fn test_wasip2_component_http_proxy() -> anyhow::Result<()> {
         .build()?;
 
     let srv = srv.start()?;
-    let response = http_get();
-
-    let response = response.expect("Server did not start in time");
-    assert!(response.status().is_success());
+    let response = http_get()?;
 
-    let body = response.text().unwrap();
-    assert_eq!(body, "Hello, this is your first wasi:http/proxy world!\n");
+    assert_eq!(response.status, 200);
+    assert_eq!(
+        response.body,
+        "Hello, this is your first wasi:http/proxy world!\n"
+    );
 
-    let (exit_code, _, _) = srv.ctrl_c()?.wait(Duration::from_secs(5))?;
-    assert_eq!(exit_code, 0);
+    srv.ctrl_c()?
+        .wait_for_exit_code(Duration::from_secs(5), 0)?;
 
     Ok(())
 }
@@ -303,16 +323,13 @@ fn test_wasip2_component_http_proxy_csharp() -> anyhow::Result<()> {
 
     // dotnet takes a bit longer to start up
     // Todo: find out why this doesn't happen in wasmtime directly
-    let response = http_get_with_backoff_secs(2);
+    let response = http_get_with_backoff_secs(2)?;
 
-    let response = response.expect("Server did not start in time");
-    assert!(response.status().is_success());
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, "Hello, from C#!");
 
-    let body = response.text().unwrap();
-    assert_eq!(body, "Hello, from C#!");
-
-    let (exit_code, _, _) = srv.ctrl_c()?.wait(Duration::from_secs(5))?;
-    assert_eq!(exit_code, 0);
+    srv.ctrl_c()?
+        .wait_for_exit_code(Duration::from_secs(5), 0)?;
 
     Ok(())
 }
@@ -327,7 +344,7 @@ fn test_wasip2_component_http_proxy_force_shutdown() -> anyhow::Result<()> {
         .build()?;
 
     let srv = srv.start()?;
-    assert!(http_get().unwrap().status().is_success());
+    assert_eq!(http_get()?.status, 200);
 
     // Send SIGTERM
     let (exit_code, _, _) = srv.terminate()?.wait(Duration::from_secs(5))?;
@@ -337,25 +354,83 @@ fn test_wasip2_component_http_proxy_force_shutdown() -> anyhow::Result<()> {
     Ok(())
 }
 
-fn http_get() -> reqwest::Result<reqwest::blocking::Response> {
+// The component exports `runwasi:tcp/handler`, and echoes the connections back.
+#[test]
+#[serial]
+fn test_wasip2_component_tcp_handler() -> anyhow::Result<()> {
+    let srv = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(TCP_ECHO)?
+        .with_host_network()
+        .build()?;
+
+    let srv = srv.start()?;
+
+    // Every connection is handled by its own instance
+    assert_eq!(tcp_echo("127.0.0.1:9000", b"hello")?, b"hello");
+    assert_eq!(tcp_echo("127.0.0.1:9000", b"world")?, b"world");
+
+    srv.ctrl_c()?
+        .wait_for_exit_code(Duration::from_secs(5), 0)?;
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_wasip2_component_tcp_handler_socket_addr() -> anyhow::Result<()> {
+    let srv = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(TCP_ECHO)?
+        .with_host_network()
+        .with_env("WASMTIME_TCP_SOCKET_ADDR", "127.0.0.1:9001")
+        .build()?;
+
+    let srv = srv.start()?;
+    assert_eq!(tcp_echo("127.0.0.1:9001", b"hello")?, b"hello");
+
+    srv.ctrl_c()?
+        .wait_for_exit_code(Duration::from_secs(5), 0)?;
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_conformance() -> anyhow::Result<()> {
+    Conformance::new()
+        .with_components()
+        .with_http_proxy()
+        .run::<WasiInstance>()
+}
+
+fn http_get() -> anyhow::Result<http_helpers::Response> {
     http_get_with_backoff_secs(1)
 }
 
-// Helper method to make a `GET` request
-fn http_get_with_backoff_secs(backoff: u64) -> reqwest::Result<reqwest::blocking::Response> {
-    const MAX_ATTEMPTS: u32 = 10;
-    let backoff_duration: Duration = Duration::from_secs(backoff);
-
-    let mut attempts = 0;
-
-    loop {
-        match reqwest::blocking::get("http://127.0.0.1:8080") {
-            Ok(resp) => break Ok(resp),
-            Err(err) if attempts == MAX_ATTEMPTS => break Err(err),
-            Err(_) => {
-                std::thread::sleep(backoff_duration);
-                attempts += 1;
-            }
+fn http_get_with_backoff_secs(backoff: u64) -> anyhow::Result<http_helpers::Response> {
+    http_helpers::get_with_retry("http://127.0.0.1:8080", 11, Duration::from_secs(backoff))
+}
+
+/// Send `data` to the TCP server at `addr`, and read what it sends back until it closes the
+/// connection.
+fn tcp_echo(addr: &str, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut stream = tcp_connect_with_retry(addr, 10, Duration::from_secs(1))?;
+    stream.write_all(data)?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut echoed = vec![];
+    stream.read_to_end(&mut echoed)?;
+    Ok(echoed)
+}
+
+fn tcp_connect_with_retry(
+    addr: &str,
+    retries: u32,
+    backoff: Duration,
+) -> anyhow::Result<TcpStream> {
+    for _ in 0..retries {
+        if let Ok(stream) = TcpStream::connect(addr) {
+            return Ok(stream);
         }
+        std::thread::sleep(backoff);
     }
+    Ok(TcpStream::connect(addr)?)
 }
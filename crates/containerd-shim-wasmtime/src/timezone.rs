@@ -0,0 +1,306 @@
+//! The `wasi:clocks/timezone` interface, from the `TZ` environment variable of the container.
+//!
+//! `wasmtime-wasi` doesn't implement the timezone of `wasi:clocks`, so without it every guest
+//! formats local times in UTC. The time zone of the container is resolved like glibc does:
+//!
+//! * `TZ` names a zone of the tz database, e.g. `Europe/Paris`, read from `$TZDIR` or
+//!   `/usr/share/zoneinfo` in the container, either bundled in the image or mounted from the node.
+//! * `TZ` is a POSIX time zone, e.g. `CET-1CEST,M3.5.0,M10.5.0/3`, which needs no tz database.
+//! * Without `TZ`, `/etc/localtime` of the container is used if it exists, and UTC otherwise.
+//!
+//! The TZif files and the POSIX time zones are parsed with `tz-rs`. A time zone that can't be
+//! resolved is logged, and UTC is used.
+
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use containerd_shim_wasm::container::RuntimeContext;
+use wasmtime::component::{ComponentType, Lift, Linker, Lower};
+use wasmtime::StoreContextMut;
+use wasmtime_wasi::bindings::clocks::wall_clock::Datetime;
+
+use crate::instance::{envs_from_ctx, WasiPreview2Ctx};
+
+pub(crate) const INTERFACE: &str = "wasi:clocks/timezone@0.2.0";
+
+const DEFAULT_TZDIR: &str = "/usr/share/zoneinfo";
+const LOCALTIME: &str = "/etc/localtime";
+
+/// The transitions of POSIX time zones without rules, as glibc does.
+const DEFAULT_RULE: &str = "M3.2.0,M11.1.0";
+
+/// The `timezone-display` record of `wasi:clocks/timezone`.
+#[derive(ComponentType, Lift, Lower, Debug)]
+#[component(record)]
+struct TimezoneDisplay {
+    #[component(name = "utc-offset")]
+    utc_offset: i32,
+    name: String,
+    #[component(name = "in-daylight-saving-time")]
+    in_daylight_saving_time: bool,
+}
+
+/// A local time type: the offset from UTC in seconds, east of UTC being positive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalType {
+    pub offset: i32,
+    pub dst: bool,
+    pub name: String,
+}
+
+impl LocalType {
+    fn utc() -> Self {
+        Self {
+            offset: 0,
+            dst: false,
+            name: "UTC".to_string(),
+        }
+    }
+}
+
+/// A time zone, from a TZif file or a POSIX time zone.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimeZone(tz::TimeZone);
+
+impl Default for TimeZone {
+    fn default() -> Self {
+        Self::utc()
+    }
+}
+
+impl TimeZone {
+    pub fn utc() -> Self {
+        Self(tz::TimeZone::utc())
+    }
+
+    /// The time zone of the container.
+    pub fn from_ctx(ctx: &impl RuntimeContext) -> Arc<Self> {
+        let envs = envs_from_ctx(ctx);
+        let env = |key: &str| envs.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str());
+        let tzdir = Path::new(env("TZDIR").unwrap_or(DEFAULT_TZDIR));
+
+        let tz = match env("TZ") {
+            Some(tz) => Self::resolve(tz, tzdir).with_context(|| format!("invalid TZ {tz:?}")),
+            None if Path::new(LOCALTIME).exists() => Self::read(Path::new(LOCALTIME)),
+            None => Ok(Self::utc()),
+        };
+        let tz = tz.unwrap_or_else(|err| {
+            tracing::warn!("using UTC: {err:?}");
+            Self::utc()
+        });
+        Arc::new(tz)
+    }
+
+    fn resolve(tz: &str, tzdir: &Path) -> Result<Self> {
+        let tz = tz.strip_prefix(':').unwrap_or(tz);
+        if tz.is_empty() {
+            return Ok(Self::utc());
+        }
+        if let Some(path) = zone_path(tz, tzdir) {
+            if path.is_file() {
+                return Self::read(&path);
+            }
+        }
+        Self::parse_posix(tz)
+    }
+
+    fn read(path: &Path) -> Result<Self> {
+        let data = std::fs::read(path).with_context(|| format!("failed to read {path:?}"))?;
+        Self::parse_tzif(&data).with_context(|| format!("invalid tz file {path:?}"))
+    }
+
+    /// Parse a POSIX time zone, e.g. `CET-1CEST,M3.5.0,M10.5.0/3`.
+    pub fn parse_posix(tz: &str) -> Result<Self> {
+        match tz::TimeZone::from_posix_tz(tz) {
+            Ok(tz) => Ok(Self(tz)),
+            // The daylight saving time without rules uses the rules of glibc
+            Err(_) if !tz.contains(',') => {
+                let tz = tz::TimeZone::from_posix_tz(&format!("{tz},{DEFAULT_RULE}"))?;
+                Ok(Self(tz))
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Parse a TZif file, as described in RFC 8536.
+    pub fn parse_tzif(data: &[u8]) -> Result<Self> {
+        Ok(Self(tz::TimeZone::from_tz_data(data)?))
+    }
+
+    /// The local time type at `time`, in seconds since the epoch.
+    pub fn local_type(&self, time: i64) -> LocalType {
+        let tz = self.0.as_ref();
+        let local = tz.find_local_time_type(time).ok().or_else(|| {
+            // Without a rule, the last local time type lasts after the last transition
+            let last = tz.transitions().last()?;
+            tz.local_time_types().get(last.local_time_type_index())
+        });
+        match local {
+            Some(local) => LocalType {
+                offset: local.ut_offset(),
+                dst: local.is_dst(),
+                name: local.time_zone_designation().to_string(),
+            },
+            None => LocalType::utc(),
+        }
+    }
+}
+
+/// The path of the zone named `tz` in `tzdir`, unless it escapes `tzdir`.
+fn zone_path(tz: &str, tzdir: &Path) -> Option<PathBuf> {
+    let path = Path::new(tz);
+    if path.is_absolute() {
+        return Some(path.to_path_buf());
+    }
+    path.components()
+        .all(|c| matches!(c, Component::Normal(_)))
+        .then(|| tzdir.join(path))
+}
+
+/// Add the `wasi:clocks/timezone` interface to the linker.
+pub(crate) fn add_to_linker(linker: &mut Linker<WasiPreview2Ctx>) -> Result<()> {
+    let mut instance = linker.instance(INTERFACE)?;
+    instance.func_wrap(
+        "display",
+        |store: StoreContextMut<'_, WasiPreview2Ctx>,
+         (when,): (Datetime,)|
+         -> Result<(TimezoneDisplay,)> {
+            let local = store.data().timezone.local_type(when.seconds as i64);
+            Ok((TimezoneDisplay {
+                utc_offset: local.offset,
+                name: local.name,
+                in_daylight_saving_time: local.dst,
+            },))
+        },
+    )?;
+    instance.func_wrap(
+        "utc-offset",
+        |store: StoreContextMut<'_, WasiPreview2Ctx>, (when,): (Datetime,)| -> Result<(i32,)> {
+            Ok((store.data().timezone.local_type(when.seconds as i64).offset,))
+        },
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2024-01-15T12:00:00Z and 2024-07-15T12:00:00Z
+    const WINTER: i64 = 1705320000;
+    const SUMMER: i64 = 1721044800;
+
+    fn offset(tz: &TimeZone, time: i64) -> (i32, bool, String) {
+        let local = tz.local_type(time);
+        (local.offset, local.dst, local.name)
+    }
+
+    #[test]
+    fn test_posix() -> Result<()> {
+        let paris = TimeZone::parse_posix("CET-1CEST,M3.5.0,M10.5.0/3")?;
+        assert_eq!(offset(&paris, WINTER), (3600, false, "CET".into()));
+        assert_eq!(offset(&paris, SUMMER), (7200, true, "CEST".into()));
+        // 2024-03-31T01:00:00Z, the last Sunday of March at 02:00 CET
+        assert_eq!(offset(&paris, 1711846800 - 1).0, 3600);
+        assert_eq!(offset(&paris, 1711846800).0, 7200);
+        // 2024-10-27T01:00:00Z, the last Sunday of October at 03:00 CEST
+        assert_eq!(offset(&paris, 1729990800 - 1).0, 7200);
+        assert_eq!(offset(&paris, 1729990800).0, 3600);
+
+        // Southern hemisphere
+        let sydney = TimeZone::parse_posix("AEST-10AEDT,M10.1.0,M4.1.0/3")?;
+        assert_eq!(offset(&sydney, WINTER), (39600, true, "AEDT".into()));
+        assert_eq!(offset(&sydney, SUMMER), (36000, false, "AEST".into()));
+
+        let new_york = TimeZone::parse_posix("XST5XDT")?;
+        assert_eq!(offset(&new_york, WINTER).0, -5 * 3600);
+        assert_eq!(offset(&new_york, SUMMER).0, -4 * 3600);
+
+        let kolkata = TimeZone::parse_posix("<+0530>-5:30")?;
+        assert_eq!(offset(&kolkata, SUMMER), (19800, false, "+0530".into()));
+
+        assert!(TimeZone::parse_posix("Europe-Paris").is_err());
+        assert!(TimeZone::parse_posix("CET-1CEST,M13.5.0,M10.5.0").is_err());
+        Ok(())
+    }
+
+    fn tzif(version: u8, times: &[i64], types: &[(i32, bool, usize)], chars: &[u8]) -> Vec<u8> {
+        let header = |version: u8| {
+            let mut header = b"TZif".to_vec();
+            header.push(version);
+            header.extend([0; 15]);
+            for count in [0, 0, 0, times.len(), types.len(), chars.len()] {
+                header.extend((count as u32).to_be_bytes());
+            }
+            header
+        };
+        let data = |wide: bool| {
+            let mut data = vec![];
+            for time in times {
+                if wide {
+                    data.extend(time.to_be_bytes());
+                } else {
+                    data.extend((*time as i32).to_be_bytes());
+                }
+            }
+            data.extend((0..times.len()).map(|i| ((i + 1) % types.len()) as u8));
+            for (offset, dst, name) in types {
+                data.extend(offset.to_be_bytes());
+                data.extend([*dst as u8, *name as u8]);
+            }
+            data.extend(chars);
+            data
+        };
+
+        let mut file = header(version);
+        file.extend(data(false));
+        if version != 0 {
+            file.extend(header(version));
+            file.extend(data(true));
+            file.extend(b"\nCET-1CEST,M3.5.0,M10.5.0/3\n");
+        }
+        file
+    }
+
+    #[test]
+    fn test_tzif() -> Result<()> {
+        let types = [(3600, false, 0), (7200, true, 4)];
+        // 2023-03-26T01:00:00Z and 2023-10-29T01:00:00Z
+        let times = [1679792400, 1698541200];
+
+        let tz = TimeZone::parse_tzif(&tzif(0, &times, &types, b"CET\0CEST\0"))?;
+        assert_eq!(offset(&tz, times[0] - 1), (3600, false, "CET".into()));
+        assert_eq!(offset(&tz, times[0]), (7200, true, "CEST".into()));
+        assert_eq!(offset(&tz, SUMMER), (3600, false, "CET".into()));
+
+        // The footer applies after the last transition
+        let tz = TimeZone::parse_tzif(&tzif(b'2', &times, &types, b"CET\0CEST\0"))?;
+        assert_eq!(offset(&tz, times[0]), (7200, true, "CEST".into()));
+        assert_eq!(offset(&tz, SUMMER), (7200, true, "CEST".into()));
+        assert_eq!(offset(&tz, WINTER), (3600, false, "CET".into()));
+
+        assert!(TimeZone::parse_tzif(b"TZif2").is_err());
+        assert!(TimeZone::parse_tzif(&tzif(0, &times, &types, b"")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve() -> Result<()> {
+        let tzdir = tempfile::tempdir()?;
+        std::fs::create_dir(tzdir.path().join("Europe"))?;
+        std::fs::write(
+            tzdir.path().join("Europe/Paris"),
+            tzif(b'2', &[], &[(3600, false, 0)], b"CET\0"),
+        )?;
+
+        let tz = TimeZone::resolve(":Europe/Paris", tzdir.path())?;
+        assert_eq!(offset(&tz, SUMMER), (7200, true, "CEST".into()));
+        let tz = TimeZone::resolve("EST5", tzdir.path())?;
+        assert_eq!(offset(&tz, SUMMER), (-5 * 3600, false, "EST".into()));
+        assert_eq!(TimeZone::resolve("", tzdir.path())?, TimeZone::utc());
+        assert!(TimeZone::resolve("../Europe/Paris", tzdir.path()).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,140 @@
+//! Pre-connected unix sockets handed to the guest.
+//!
+//! Sockets are declared with the `runwasi.io/unix-sockets` annotation as a comma separated
+//! list of `name=path` pairs, e.g. `agent=/run/agent/agent.sock`. The shim connects to every
+//! declared socket before the guest starts, and the guest claims a connection by name through
+//! the `runwasi:unix/sockets` host interface, which hands it back as a pair of `wasi:io` streams.
+//!
+//! This allows a component to talk to a node-local agent without granting it network access.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+use containerd_shim_wasm::container::RuntimeContext;
+use tokio::net::UnixStream;
+use wasmtime::component::{Linker, Resource};
+use wasmtime::StoreContextMut;
+use wasmtime_wasi::pipe::{AsyncReadStream, AsyncWriteStream};
+use wasmtime_wasi::{InputStream, OutputStream};
+
+use crate::instance::WasiPreview2Ctx;
+
+pub const UNIX_SOCKETS_ANNOTATION: &str = "runwasi.io/unix-sockets";
+
+pub(crate) const INTERFACE: &str = "runwasi:unix/sockets@0.1.0";
+
+// Matches the write budget wasmtime uses for its own stdio streams.
+const WRITE_BUDGET: usize = 1024 * 1024;
+
+type TakeResult = Result<(Resource<InputStream>, Resource<OutputStream>), String>;
+
+/// Connections to the unix sockets declared for a container, keyed by name.
+#[derive(Default)]
+pub struct UnixSockets {
+    sockets: HashMap<String, UnixStream>,
+}
+
+impl UnixSockets {
+    /// Connect to all the sockets declared in the container annotations.
+    ///
+    /// This must be called from within a tokio runtime.
+    pub fn connect(ctx: &impl RuntimeContext) -> Result<Self> {
+        let Some(value) = ctx.annotations().get(UNIX_SOCKETS_ANNOTATION) else {
+            return Ok(Self::default());
+        };
+
+        let mut sockets = HashMap::new();
+        for (name, path) in parse_socket_map(value)? {
+            let stream = std::os::unix::net::UnixStream::connect(&path)
+                .with_context(|| format!("failed to connect to unix socket {path:?}"))?;
+            stream.set_nonblocking(true)?;
+
+            tracing::info!("connected unix socket {name:?} at {path:?}");
+            sockets.insert(name, UnixStream::from_std(stream)?);
+        }
+
+        Ok(Self { sockets })
+    }
+
+    fn take(&mut self, name: &str) -> Option<UnixStream> {
+        self.sockets.remove(name)
+    }
+}
+
+/// Parse a `name=path[,name=path]` socket declaration.
+pub(crate) fn parse_socket_map(value: &str) -> Result<Vec<(String, PathBuf)>> {
+    let mut sockets: Vec<(String, PathBuf)> = vec![];
+
+    for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let Some((name, path)) = entry.split_once('=') else {
+            bail!("invalid unix socket declaration {entry:?}, expected `name=path`");
+        };
+
+        let (name, path) = (name.trim(), PathBuf::from(path.trim()));
+        if name.is_empty() || !path.is_absolute() {
+            bail!("invalid unix socket declaration {entry:?}, expected `name=/absolute/path`");
+        }
+        if sockets.iter().any(|(n, _)| n == name) {
+            bail!("unix socket {name:?} is declared more than once");
+        }
+
+        sockets.push((name.to_string(), path));
+    }
+
+    Ok(sockets)
+}
+
+/// Add the `runwasi:unix/sockets` interface to the linker.
+///
+/// The interface exports a single function:
+/// `take: func(name: string) -> result<tuple<input-stream, output-stream>, string>`
+/// Each socket can be taken only once.
+pub(crate) fn add_to_linker(linker: &mut Linker<WasiPreview2Ctx>) -> Result<()> {
+    linker.instance(INTERFACE)?.func_wrap(
+        "take",
+        |mut store: StoreContextMut<'_, WasiPreview2Ctx>,
+         (name,): (String,)|
+         -> Result<(TakeResult,)> {
+            let ctx = store.data_mut();
+            let Some(stream) = ctx.unix_sockets.take(&name) else {
+                return Ok((Err(format!("unix socket {name:?} is not available")),));
+            };
+
+            let (reader, writer) = stream.into_split();
+            let input: InputStream = Box::new(AsyncReadStream::new(reader));
+            let output: OutputStream = Box::new(AsyncWriteStream::new(WRITE_BUDGET, writer));
+
+            let input = ctx.resource_table.push(input)?;
+            let output = ctx.resource_table.push(output)?;
+
+            Ok((Ok((input, output)),))
+        },
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_socket_map() -> Result<()> {
+        let sockets = parse_socket_map("agent=/run/agent.sock, metrics = /run/metrics.sock")?;
+        assert_eq!(
+            sockets,
+            vec![
+                ("agent".to_string(), PathBuf::from("/run/agent.sock")),
+                ("metrics".to_string(), PathBuf::from("/run/metrics.sock")),
+            ]
+        );
+
+        assert!(parse_socket_map("")?.is_empty());
+        assert!(parse_socket_map("agent").is_err());
+        assert!(parse_socket_map("agent=relative.sock").is_err());
+        assert!(parse_socket_map("a=/run/a.sock,a=/run/b.sock").is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,537 @@
+//! Quotas of the bytes written by the guest to its filesystem.
+//!
+//! The containers preopen their root with write permissions, which includes the volumes mounted
+//! in the container. A guest can fill a volume shared with other containers, or the disk of the
+//! node, long before its memory limit is reached. The `runwasi.io/write-quota` annotation sets
+//! the number of bytes the guest may write under a guest path, after which the writes fail with
+//! `ENOSPC`. The quota of the whole filesystem is set with a number of bytes, and the quota of
+//! the files under a guest path with `path=bytes`, e.g. `1048576,/data=1073741824`.
+//!
+//! The files opened by the guest take the quota of the longest path with a quota they are opened
+//! under, the guest paths being resolved without following symlinks. Components are accounted
+//! through `wasi:filesystem`, see [`crate::filesystem`]. Wasm modules are accounted by wrapping
+//! the WASI preview 1 functions of wasmtime which write to, open and close files, the guest paths
+//! of the file descriptors being tracked from the preopens following the stdio.
+//!
+//! The bytes written are counted, not the size of the files: rewriting a file counts again, and
+//! removing it doesn't give back the quota.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, ensure, Context, Result};
+use bytes::Bytes;
+use containerd_shim_wasm::container::{Preopen, RuntimeContext};
+use wasmtime::{Caller, Extern, Linker};
+use wasmtime_wasi::bindings::filesystem::types::ErrorCode;
+use wasmtime_wasi::bindings::io::streams::OutputStream;
+use wasmtime_wasi::preview1::wasi_snapshot_preview1 as p1;
+use wasmtime_wasi::{async_trait, HostOutputStream, StreamError, StreamResult, Subscribe};
+use wiggle::{GuestMemory, GuestPtr};
+
+use crate::filesystem::normalize;
+use crate::linker::ModuleCtx;
+
+pub const WRITE_QUOTA_ANNOTATION: &str = "runwasi.io/write-quota";
+
+/// The module of the WASI preview 1 functions.
+const WASI_MODULE: &str = "wasi_snapshot_preview1";
+/// The `nospc` errno of WASI preview 1.
+const ERRNO_NOSPC: i32 = 51;
+/// The file descriptor of the first preopen, after the stdio.
+const FIRST_PREOPEN_FD: u32 = 3;
+
+/// The bytes the guest may still write under a guest path.
+pub struct WriteQuota {
+    limit: u64,
+    used: AtomicU64,
+}
+
+impl WriteQuota {
+    pub fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            used: AtomicU64::new(0),
+        }
+    }
+
+    /// The bytes written so far.
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Account for `len` bytes about to be written.
+    /// Fails without accounting for anything if they don't fit in the quota.
+    pub(crate) fn reserve(&self, len: u64) -> Result<(), ErrorCode> {
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(len).filter(|used| *used <= self.limit)
+            })
+            .map(|_| ())
+            .map_err(|_| ErrorCode::InsufficientSpace)
+    }
+
+    /// Give back `len` bytes reserved but not written.
+    pub(crate) fn release(&self, len: u64) {
+        self.used.fetch_sub(len, Ordering::Relaxed);
+    }
+}
+
+/// The write quotas of a container, by guest path.
+///
+/// The quota of a path is shared by all the instances of the container.
+pub struct WriteQuotas {
+    /// The limit of the files under none of the paths with their own.
+    default: Option<u64>,
+    limits: HashMap<PathBuf, u64>,
+    quotas: Mutex<HashMap<PathBuf, Arc<WriteQuota>>>,
+}
+
+impl WriteQuotas {
+    /// The quotas of the container, if its annotations set some.
+    pub fn from_ctx(ctx: &impl RuntimeContext) -> Result<Option<Arc<Self>>> {
+        let Some(value) = ctx.annotations().get(WRITE_QUOTA_ANNOTATION) else {
+            return Ok(None);
+        };
+        let quotas =
+            Self::parse(value).with_context(|| format!("invalid {WRITE_QUOTA_ANNOTATION}"))?;
+        Ok(Some(Arc::new(quotas)))
+    }
+
+    /// Parse a `[bytes][,path=bytes]` list of quotas.
+    pub fn parse(value: &str) -> Result<Self> {
+        let mut default = None;
+        let mut limits = HashMap::new();
+        for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            match entry.split_once('=') {
+                Some((path, limit)) => {
+                    let path = path.trim();
+                    ensure!(path.starts_with('/'), "{path:?} is not an absolute path");
+                    let limit = limit
+                        .trim()
+                        .parse()
+                        .with_context(|| format!("invalid {entry:?}"))?;
+                    limits.insert(normalize(Path::new(path)), limit);
+                }
+                None => {
+                    default = Some(
+                        entry
+                            .parse()
+                            .with_context(|| format!("invalid {entry:?}"))?,
+                    )
+                }
+            }
+        }
+        Ok(Self {
+            default,
+            limits,
+            quotas: Default::default(),
+        })
+    }
+
+    /// The quota of the file at the absolute guest path `path`, the one of the longest path with
+    /// a quota it is under, if any.
+    pub fn quota(&self, path: &Path) -> Option<Arc<WriteQuota>> {
+        let longest = self
+            .limits
+            .iter()
+            .filter(|(root, _)| path.starts_with(root))
+            .max_by_key(|(root, _)| root.components().count());
+        let (root, limit) = match longest {
+            Some((root, limit)) => (root.clone(), *limit),
+            None => (PathBuf::from("/"), self.default?),
+        };
+        let mut quotas = self.quotas.lock().unwrap();
+        let quota = quotas
+            .entry(root)
+            .or_insert_with(|| Arc::new(WriteQuota::new(limit)));
+        Some(quota.clone())
+    }
+}
+
+/// A file output stream, with the writes accounted for.
+pub(crate) struct QuotaStream {
+    inner: OutputStream,
+    quota: Arc<WriteQuota>,
+}
+
+impl QuotaStream {
+    pub(crate) fn new(inner: OutputStream, quota: Arc<WriteQuota>) -> Self {
+        Self { inner, quota }
+    }
+}
+
+#[async_trait]
+impl Subscribe for QuotaStream {
+    async fn ready(&mut self) {
+        self.inner.ready().await
+    }
+}
+
+#[async_trait]
+impl HostOutputStream for QuotaStream {
+    fn write(&mut self, bytes: Bytes) -> StreamResult<()> {
+        let len = bytes.len() as u64;
+        if self.quota.reserve(len).is_err() {
+            let err = io::Error::from_raw_os_error(libc::ENOSPC);
+            return Err(StreamError::LastOperationFailed(err.into()));
+        }
+        self.inner
+            .write(bytes)
+            .inspect_err(|_| self.quota.release(len))
+    }
+
+    fn flush(&mut self) -> StreamResult<()> {
+        self.inner.flush()
+    }
+
+    fn check_write(&mut self) -> StreamResult<usize> {
+        self.inner.check_write()
+    }
+}
+
+/// The write quotas of a wasm module, by WASI preview 1 file descriptor.
+#[derive(Default)]
+pub(crate) struct ModuleQuotas {
+    quotas: Option<Arc<WriteQuotas>>,
+    /// The guest paths of the file descriptors.
+    paths: HashMap<u32, PathBuf>,
+    fds: HashMap<u32, Arc<WriteQuota>>,
+}
+
+impl ModuleQuotas {
+    /// The quotas of a module with `preopens`, which get the file descriptors following the
+    /// stdio in order.
+    pub(crate) fn new(quotas: Option<Arc<WriteQuotas>>, preopens: &[Preopen]) -> Self {
+        let mut module = Self {
+            quotas,
+            ..Default::default()
+        };
+        if module.quotas.is_some() {
+            for (fd, preopen) in (FIRST_PREOPEN_FD..).zip(preopens) {
+                module.track(fd, normalize(Path::new(&preopen.guest)));
+            }
+        }
+        module
+    }
+
+    /// Reserve the bytes of the `iovs_len` iovecs at `iovs` written to `fd`, if it has a quota.
+    /// Fails with the errno of the write if they don't fit in the quota.
+    fn reserve(
+        &self,
+        memory: &GuestMemory,
+        fd: i32,
+        iovs: i32,
+        iovs_len: i32,
+    ) -> Result<Option<Reserved>, i32> {
+        let Some(quota) = self.fds.get(&(fd as u32)) else {
+            return Ok(None);
+        };
+        // Invalid iovecs fail the write without writing anything
+        let Some(len) = iovs_total_len(memory, iovs, iovs_len) else {
+            return Ok(None);
+        };
+        quota.reserve(len).map_err(|_| ERRNO_NOSPC)?;
+        Ok(Some(Reserved {
+            quota: quota.clone(),
+            len,
+        }))
+    }
+
+    /// Let the file descriptor `fd` take the quota of the guest path `path`.
+    fn track(&mut self, fd: u32, path: PathBuf) {
+        let quota = self.quotas.as_ref().and_then(|quotas| quotas.quota(&path));
+        match quota {
+            Some(quota) => self.fds.insert(fd, quota),
+            None => self.fds.remove(&fd),
+        };
+        self.paths.insert(fd, path);
+    }
+
+    /// Let the file descriptor `fd` opened at `path` from the directory `dir` take the quota of
+    /// its guest path.
+    fn opened(&mut self, dir: i32, path: &str, fd: u32) {
+        match self.paths.get(&(dir as u32)) {
+            Some(dir) => self.track(fd, normalize(&dir.join(path))),
+            None => self.closed(fd),
+        }
+    }
+
+    fn closed(&mut self, fd: u32) {
+        self.paths.remove(&fd);
+        self.fds.remove(&fd);
+    }
+
+    fn renumbered(&mut self, from: u32, to: u32) {
+        match self.paths.remove(&from) {
+            Some(path) => self.paths.insert(to, path),
+            None => self.paths.remove(&to),
+        };
+        match self.fds.remove(&from) {
+            Some(quota) => self.fds.insert(to, quota),
+            None => self.fds.remove(&to),
+        };
+    }
+}
+
+/// Bytes reserved for a write.
+struct Reserved {
+    quota: Arc<WriteQuota>,
+    len: u64,
+}
+
+impl Reserved {
+    /// Give back the bytes not written, `nwritten` being written to if the write succeeded.
+    fn release(self, memory: &GuestMemory, res: &Result<i32>, nwritten: i32) {
+        let written = match res {
+            Ok(0) => read_u32(memory, nwritten).unwrap_or_default() as u64,
+            _ => 0,
+        };
+        self.quota.release(self.len - written.min(self.len));
+    }
+}
+
+/// Replace the WASI preview 1 functions of the linker which write to, open and close files with
+/// wrappers accounting for the writes, around the functions of wasmtime.
+pub(crate) fn add_to_linker(linker: &mut Linker<ModuleCtx>) -> Result<()> {
+    linker.allow_shadowing(true);
+    let res = wrap(linker);
+    linker.allow_shadowing(false);
+    res
+}
+
+fn wrap(linker: &mut Linker<ModuleCtx>) -> Result<()> {
+    linker.func_wrap_async(
+        WASI_MODULE,
+        "fd_write",
+        |mut caller: Caller<'_, ModuleCtx>,
+         (fd, iovs, iovs_len, nwritten): (i32, i32, i32, i32)| {
+            Box::new(async move {
+                let export = caller.get_export("memory");
+                let (mut memory, ctx) = guest_memory(&export, &mut caller)?;
+                let reserved = match ctx.write_quotas.reserve(&memory, fd, iovs, iovs_len) {
+                    Ok(reserved) => reserved,
+                    Err(errno) => return Ok(errno),
+                };
+                let wasi = &mut ctx.wasi;
+                let res = p1::fd_write(wasi, &mut memory, fd, iovs, iovs_len, nwritten).await;
+                if let Some(reserved) = reserved {
+                    reserved.release(&memory, &res, nwritten);
+                }
+                res
+            })
+        },
+    )?;
+    linker.func_wrap_async(
+        WASI_MODULE,
+        "fd_pwrite",
+        |mut caller: Caller<'_, ModuleCtx>,
+         (fd, iovs, iovs_len, offset, nwritten): (i32, i32, i32, i64, i32)| {
+            Box::new(async move {
+                let export = caller.get_export("memory");
+                let (mut memory, ctx) = guest_memory(&export, &mut caller)?;
+                let reserved = match ctx.write_quotas.reserve(&memory, fd, iovs, iovs_len) {
+                    Ok(reserved) => reserved,
+                    Err(errno) => return Ok(errno),
+                };
+                let wasi = &mut ctx.wasi;
+                let res =
+                    p1::fd_pwrite(wasi, &mut memory, fd, iovs, iovs_len, offset, nwritten).await;
+                if let Some(reserved) = reserved {
+                    reserved.release(&memory, &res, nwritten);
+                }
+                res
+            })
+        },
+    )?;
+    linker.func_wrap_async(
+        WASI_MODULE,
+        "path_open",
+        |mut caller: Caller<'_, ModuleCtx>,
+         (fd, dirflags, path, path_len, oflags, base, inheriting, fdflags, opened): (
+            i32,
+            i32,
+            i32,
+            i32,
+            i32,
+            i64,
+            i64,
+            i32,
+            i32,
+        )| {
+            Box::new(async move {
+                let export = caller.get_export("memory");
+                let (mut memory, ctx) = guest_memory(&export, &mut caller)?;
+                let res = p1::path_open(
+                    &mut ctx.wasi,
+                    &mut memory,
+                    fd,
+                    dirflags,
+                    path,
+                    path_len,
+                    oflags,
+                    base,
+                    inheriting,
+                    fdflags,
+                    opened,
+                )
+                .await;
+                if let Ok(0) = res {
+                    // The file takes the quota of its guest path
+                    let opened = read_u32(&memory, opened).context("invalid opened fd")?;
+                    let path = read_str(&memory, path, path_len).context("invalid path")?;
+                    ctx.write_quotas.opened(fd, &path, opened);
+                }
+                res
+            })
+        },
+    )?;
+    linker.func_wrap_async(
+        WASI_MODULE,
+        "fd_close",
+        |mut caller: Caller<'_, ModuleCtx>, (fd,): (i32,)| {
+            Box::new(async move {
+                let export = caller.get_export("memory");
+                let (mut memory, ctx) = guest_memory(&export, &mut caller)?;
+                let res = p1::fd_close(&mut ctx.wasi, &mut memory, fd).await;
+                if let Ok(0) = res {
+                    ctx.write_quotas.closed(fd as u32);
+                }
+                res
+            })
+        },
+    )?;
+    linker.func_wrap(
+        WASI_MODULE,
+        "fd_renumber",
+        |mut caller: Caller<'_, ModuleCtx>, from: i32, to: i32| {
+            let export = caller.get_export("memory");
+            let (mut memory, ctx) = guest_memory(&export, &mut caller)?;
+            let res = p1::fd_renumber(&mut ctx.wasi, &mut memory, from, to);
+            if let Ok(0) = res {
+                ctx.write_quotas.renumbered(from as u32, to as u32);
+            }
+            res
+        },
+    )?;
+    Ok(())
+}
+
+/// The memory of the calling module and the store data, as the WASI preview 1 functions of
+/// wasmtime get them.
+fn guest_memory<'a>(
+    export: &'a Option<Extern>,
+    caller: &'a mut Caller<'_, ModuleCtx>,
+) -> Result<(GuestMemory<'a>, &'a mut ModuleCtx)> {
+    match export {
+        Some(Extern::Memory(memory)) => {
+            let (memory, ctx) = memory.data_and_store_mut(caller);
+            Ok((GuestMemory::Unshared(memory), ctx))
+        }
+        Some(Extern::SharedMemory(memory)) => {
+            Ok((GuestMemory::Shared(memory.data()), caller.data_mut()))
+        }
+        _ => bail!("missing required memory export"),
+    }
+}
+
+fn read_u32(memory: &GuestMemory, ptr: i32) -> Option<u32> {
+    memory.read(GuestPtr::<u32>::new(ptr as u32)).ok()
+}
+
+fn read_str(memory: &GuestMemory, ptr: i32, len: i32) -> Option<String> {
+    let ptr = GuestPtr::<str>::new((ptr as u32, len as u32));
+    memory.as_cow_str(ptr).ok().map(Into::into)
+}
+
+/// The total length of the `iovs_len` iovecs at `iovs`, each a pointer and a length.
+fn iovs_total_len(memory: &GuestMemory, iovs: i32, iovs_len: i32) -> Option<u64> {
+    (0..iovs_len as u32).try_fold(0, |total: u64, i| {
+        let len = i.checked_mul(8)?.checked_add(4)?.checked_add(iovs as u32)?;
+        Some(total + read_u32(memory, len as i32)? as u64)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(quotas: &WriteQuotas, path: &str) -> Option<u64> {
+        quotas.quota(Path::new(path)).map(|quota| quota.limit)
+    }
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let quotas = WriteQuotas::parse("1024, /data=4096, /data/logs/=512")?;
+        assert_eq!(limit(&quotas, "/"), Some(1024));
+        assert_eq!(limit(&quotas, "/etc/passwd"), Some(1024));
+        assert_eq!(limit(&quotas, "/data"), Some(4096));
+        assert_eq!(limit(&quotas, "/data/db"), Some(4096));
+        assert_eq!(limit(&quotas, "/database"), Some(1024));
+        assert_eq!(limit(&quotas, "/data/logs/today"), Some(512));
+        // The quota of a path is shared by the files under it
+        quotas
+            .quota(Path::new("/data/a"))
+            .unwrap()
+            .reserve(1000)
+            .unwrap();
+        assert_eq!(quotas.quota(Path::new("/data/b")).unwrap().used(), 1000);
+        assert_eq!(quotas.quota(Path::new("/")).unwrap().used(), 0);
+
+        let quotas = WriteQuotas::parse("/data=4096")?;
+        assert!(quotas.quota(Path::new("/etc")).is_none());
+        assert!(WriteQuotas::parse("1G").is_err());
+        assert!(WriteQuotas::parse("/data=").is_err());
+        assert!(WriteQuotas::parse("data=4096").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_module_fds() -> Result<()> {
+        let quotas = Arc::new(WriteQuotas::parse("/data=4096")?);
+        let preopens = [Preopen {
+            host: "/".into(),
+            guest: "/".to_string(),
+        }];
+        let mut module = ModuleQuotas::new(Some(quotas), &preopens);
+        assert!(!module.fds.contains_key(&3));
+
+        // The files take the quota of the path they are opened at
+        module.opened(3, "data/../data/db", 4);
+        assert_eq!(module.fds[&4].limit, 4096);
+        module.opened(4, "../../etc", 5);
+        assert!(!module.fds.contains_key(&5));
+        module.opened(4, "wal", 5);
+        assert_eq!(module.paths[&5], Path::new("/data/db/wal"));
+
+        module.renumbered(5, 6);
+        assert!(!module.paths.contains_key(&5));
+        assert_eq!(module.fds[&6].limit, 4096);
+        module.closed(6);
+        assert!(!module.fds.contains_key(&6));
+
+        assert!(ModuleQuotas::new(None, &preopens).fds.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_reserve() {
+        let quota = WriteQuota::new(10);
+        quota.reserve(4).unwrap();
+        quota.reserve(6).unwrap();
+        assert!(matches!(
+            quota.reserve(1),
+            Err(ErrorCode::InsufficientSpace)
+        ));
+        assert_eq!(quota.used(), 10);
+
+        quota.release(3);
+        assert!(quota.reserve(4).is_err());
+        quota.reserve(3).unwrap();
+        assert!(quota.reserve(u64::MAX).is_err());
+        assert_eq!(quota.used(), 10);
+    }
+}